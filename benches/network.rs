@@ -0,0 +1,249 @@
+//! Throughput, handshake latency and connection churn between two managers over loopback, for
+//! each transport. Run with `cargo bench --bench network`, the benchmarks of a transport can be
+//! selected with a filter, e.g. `cargo bench --bench network -- tcp`.
+
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use peernet::config::{PeerNetCategoryInfo, PeerNetConfigurationBuilder, QuicSettings};
+use peernet::defaults::{DefaultContext, DefaultInitConnection, DefaultPeerId};
+use peernet::error::PeerNetResult;
+use peernet::messages::{Bytes, MessagesHandler, RawSerializer};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::SendChannels;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+
+const TRANSPORTS: [(&str, TransportType); 2] =
+    [("tcp", TransportType::Tcp), ("quic", TransportType::Quic)];
+// clients connecting and disconnecting at the same time in the churn benchmark
+const NB_CHURN_CLIENTS: usize = 8;
+const MAX_CONNECTIONS: usize = 1000;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Default)]
+struct CountingHandler {
+    received: Arc<AtomicUsize>,
+}
+
+impl MessagesHandler<DefaultPeerId> for CountingHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+type Manager =
+    PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, CountingHandler>;
+
+/// Free local address for `transport_type`
+fn free_addr(transport_type: TransportType) -> SocketAddr {
+    let port = (10000..u16::MAX)
+        .find(|port| match transport_type {
+            TransportType::Tcp => TcpListener::bind(("127.0.0.1", *port)).is_ok(),
+            TransportType::Quic => UdpSocket::bind(("127.0.0.1", *port)).is_ok(),
+            // in memory, any address not taken by a listener of the process
+            #[cfg(feature = "testing")]
+            TransportType::Mock => true,
+        })
+        .expect("No ports available");
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn manager(transport_type: TransportType, handler: CountingHandler) -> Manager {
+    let limits = PeerNetCategoryInfo {
+        max_in_connections: Some(MAX_CONNECTIONS),
+        max_in_connections_per_ip: Some(MAX_CONNECTIONS),
+        max_out_connections: Some(MAX_CONNECTIONS),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    };
+    let mut builder = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        handler,
+    )
+    .set_max_in_connections(Some(MAX_CONNECTIONS))
+    .set_default_category_info(limits)
+    .set_send_data_channel_size(10_000);
+    if transport_type == TransportType::Quic {
+        // the out connections of each manager need a socket of their own
+        builder = builder.set_quic_settings(QuicSettings {
+            local_addr: free_addr(TransportType::Quic),
+            ..Default::default()
+        });
+    }
+    PeerNetManager::new(builder.build().unwrap()).unwrap()
+}
+
+fn wait_until(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        assert!(Instant::now() < deadline, "timeout");
+        std::thread::yield_now();
+    }
+}
+
+/// Connect `client` to `addr` and wait for the end of the handshake
+fn connect(client: &mut Manager, transport_type: TransportType, addr: SocketAddr) -> DefaultPeerId {
+    client.try_connect(transport_type, addr, TIMEOUT).unwrap();
+    wait_until(|| !client.active_connections.read().connections.is_empty());
+    let active_connections = client.active_connections.read();
+    active_connections
+        .connections
+        .keys()
+        .next()
+        .unwrap()
+        .clone()
+}
+
+fn disconnect(client: &Manager, peer_id: &DefaultPeerId) {
+    client.active_connections.write().remove_connection(peer_id);
+}
+
+/// A server listening on `addr` and a client with no connection
+struct Pair {
+    transport_type: TransportType,
+    server: Manager,
+    client: Manager,
+    addr: SocketAddr,
+    received: Arc<AtomicUsize>,
+}
+
+impl Pair {
+    fn new(transport_type: TransportType) -> Pair {
+        let handler = CountingHandler::default();
+        let received = handler.received.clone();
+        let mut server = manager(transport_type, handler);
+        let addr = free_addr(transport_type);
+        server.start_listener(transport_type, addr).unwrap();
+        Pair {
+            transport_type,
+            server,
+            client: manager(transport_type, CountingHandler::default()),
+            addr,
+            received,
+        }
+    }
+
+    fn connected(transport_type: TransportType) -> (Pair, SendChannels) {
+        let mut pair = Pair::new(transport_type);
+        let peer_id = connect(&mut pair.client, transport_type, pair.addr);
+        let send_channels = pair.client.active_connections.read().connections[&peer_id]
+            .send_channels
+            .clone();
+        (pair, send_channels)
+    }
+}
+
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let _ = self.server.stop_listener(self.transport_type, self.addr);
+    }
+}
+
+/// Messages per second with small messages, bytes per second with big ones
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput");
+    for (name, transport_type) in TRANSPORTS {
+        for (size_name, size, throughput) in [
+            ("64B", 64, Throughput::Elements(1)),
+            ("64KiB", 64 * 1024, Throughput::Bytes(64 * 1024)),
+        ] {
+            group.throughput(throughput);
+            // set up only if the benchmark is selected
+            let mut connected = None;
+            group.bench_function(format!("{name}/{size_name}"), |b| {
+                let (pair, send_channels) =
+                    connected.get_or_insert_with(|| Pair::connected(transport_type));
+                b.iter_custom(|iters| {
+                    let target = pair.received.load(Ordering::Relaxed) + iters as usize;
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        send_channels
+                            .send(&RawSerializer, vec![0; size], false)
+                            .unwrap();
+                    }
+                    wait_until(|| pair.received.load(Ordering::Relaxed) >= target);
+                    start.elapsed()
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Time from `try_connect` to the end of the handshake on the client side
+fn handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    for (name, transport_type) in TRANSPORTS {
+        let mut pair = None;
+        group.bench_function(name, |b| {
+            let pair = pair.get_or_insert_with(|| Pair::new(transport_type));
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    let peer_id = connect(&mut pair.client, transport_type, pair.addr);
+                    total += start.elapsed();
+                    disconnect(&pair.client, &peer_id);
+                    wait_until(|| pair.server.nb_in_connections() == 0);
+                }
+                total
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Connections accepted per second while several clients connect and disconnect in a loop
+fn churn(c: &mut Criterion) {
+    let mut group = c.benchmark_group("churn");
+    group.throughput(Throughput::Elements(1));
+    for (name, transport_type) in TRANSPORTS {
+        let mut setup = None;
+        group.bench_function(name, |b| {
+            let (pair, clients) = setup.get_or_insert_with(|| {
+                let clients: Vec<Manager> = (0..NB_CHURN_CLIENTS)
+                    .map(|_| manager(transport_type, CountingHandler::default()))
+                    .collect();
+                (Pair::new(transport_type), clients)
+            });
+            let addr = pair.addr;
+            b.iter_custom(|iters| {
+                let start = Instant::now();
+                std::thread::scope(|scope| {
+                    for (index, client) in clients.iter_mut().enumerate() {
+                        // the connections are shared between the clients
+                        let nb_connections =
+                            (iters as usize + NB_CHURN_CLIENTS - 1 - index) / NB_CHURN_CLIENTS;
+                        scope.spawn(move || {
+                            for _ in 0..nb_connections {
+                                let peer_id = connect(client, transport_type, addr);
+                                disconnect(client, &peer_id);
+                            }
+                        });
+                    }
+                });
+                start.elapsed()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(Duration::from_secs(1))
+        .measurement_time(Duration::from_secs(3));
+    targets = throughput, handshake, churn
+}
+criterion_main!(benches);
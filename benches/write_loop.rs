@@ -0,0 +1,154 @@
+//! CPU used by the write threads of the peers, idle and under load.
+//! Run with `cargo bench --bench write_loop`, Linux only as the CPU time is read from `/proc`.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use peernet::config::{PeerNetCategoryInfo, PeerNetConfigurationBuilder};
+use peernet::defaults::{DefaultContext, DefaultInitConnection, DefaultPeerId};
+use peernet::error::PeerNetResult;
+use peernet::messages::{Bytes, MessagesHandler, RawSerializer};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+
+const NB_IDLE_PEERS: usize = 20;
+const IDLE_DURATION: Duration = Duration::from_secs(3);
+const NB_MESSAGES: usize = 200_000;
+const MESSAGE_SIZE: usize = 64;
+
+#[derive(Clone, Default)]
+struct CountingHandler {
+    received: Arc<AtomicUsize>,
+}
+
+impl MessagesHandler<DefaultPeerId> for CountingHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+type Manager =
+    PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, CountingHandler>;
+
+fn manager(handler: CountingHandler) -> Manager {
+    let limits = PeerNetCategoryInfo {
+        max_in_connections: Some(NB_IDLE_PEERS),
+        max_in_connections_per_ip: Some(NB_IDLE_PEERS),
+        max_out_connections: Some(NB_IDLE_PEERS),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    };
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        handler,
+    )
+    .set_max_in_connections(Some(NB_IDLE_PEERS))
+    .set_default_category_info(limits)
+    .set_send_data_channel_size(10_000)
+    .build()
+    .unwrap();
+    PeerNetManager::new(config).unwrap()
+}
+
+fn listener(manager: &mut Manager) -> SocketAddr {
+    let port = (10000..u16::MAX)
+        .find(|port| std::net::TcpListener::bind(("127.0.0.1", *port)).is_ok())
+        .expect("No TCP ports available");
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+    addr
+}
+
+/// CPU time used by all the threads of the process
+fn cpu_time() -> Duration {
+    let nanos: u64 = std::fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter_map(|task| {
+            // first field: time spent running, in nanoseconds
+            let schedstat = std::fs::read_to_string(task.ok()?.path().join("schedstat")).ok()?;
+            schedstat.split_whitespace().next()?.parse::<u64>().ok()
+        })
+        .sum();
+    Duration::from_nanos(nanos)
+}
+
+fn idle() {
+    let mut server = manager(CountingHandler::default());
+    let addr = listener(&mut server);
+    let clients: Vec<Manager> = (0..NB_IDLE_PEERS)
+        .map(|_| {
+            let mut client = manager(CountingHandler::default());
+            client
+                .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+                .unwrap();
+            client
+        })
+        .collect();
+    sleep(Duration::from_secs(1));
+    assert_eq!(server.nb_in_connections(), NB_IDLE_PEERS);
+
+    let start = cpu_time();
+    sleep(IDLE_DURATION);
+    let used = cpu_time() - start;
+    println!(
+        "idle: {} peers, {:?} of CPU in {:?}",
+        NB_IDLE_PEERS * 2,
+        used,
+        IDLE_DURATION
+    );
+    drop(clients);
+    server.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+fn load() {
+    let handler = CountingHandler::default();
+    let received = handler.received.clone();
+    let mut server = manager(handler);
+    let addr = listener(&mut server);
+    let mut client = manager(CountingHandler::default());
+    client
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    sleep(Duration::from_secs(1));
+    let send_channels = {
+        let active_connections = client.active_connections.read();
+        let (_, connection) = active_connections.connections.iter().next().unwrap();
+        connection.send_channels.clone()
+    };
+
+    let start = Instant::now();
+    let start_cpu = cpu_time();
+    for index in 0..NB_MESSAGES {
+        // one message out of ten is high priority, the write thread goes between the two channels
+        send_channels
+            .send(&RawSerializer, vec![0; MESSAGE_SIZE], index % 10 == 0)
+            .unwrap();
+    }
+    while received.load(Ordering::Relaxed) < NB_MESSAGES {
+        sleep(Duration::from_millis(1));
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "load: {} messages of {} bytes in {:?} ({:.0} messages/s), {:?} of CPU",
+        NB_MESSAGES,
+        MESSAGE_SIZE,
+        elapsed,
+        NB_MESSAGES as f64 / elapsed.as_secs_f64(),
+        cpu_time() - start_cpu
+    );
+    server.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+fn main() {
+    idle();
+    load();
+}
@@ -0,0 +1,322 @@
+//! Soak test: a server and a client manager keep hundreds of TCP connections over loopback,
+//! exchange timestamped messages on all of them and replace some of the connections every
+//! interval, for hours if asked. Every report prints the latency of the messages along with the
+//! memory, threads and file descriptors of the process, so that leaks of the connections show
+//! as a steady growth. At the end all the connections are closed and the process must be back
+//! to the threads and file descriptors it had before connecting, otherwise it exits with an
+//! error.
+//!
+//! Run with `cargo run --release --example soak -- --duration 3600`, the options are:
+//! - `--connections <n>`: connections kept open (default 200)
+//! - `--duration <seconds>`: length of the run (default 60)
+//! - `--rate <n>`: messages per second sent by each side of a connection (default 10)
+//! - `--churn <percent>`: part of the connections replaced every report (default 10)
+//! - `--report <seconds>`: interval between the reports (default 10)
+//!
+//! The memory, threads and file descriptors are read from `/proc`, they are only reported on
+//! Linux.
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use peernet::config::{PeerNetCategoryInfo, PeerNetConfigurationBuilder};
+use peernet::defaults::{DefaultContext, DefaultInitConnection, DefaultPeerId};
+use peernet::error::PeerNetResult;
+use peernet::messages::{Bytes, MessagesHandler, RawSerializer};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::SendChannels;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+use rand::seq::SliceRandom;
+
+const TICK: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(10);
+// padding after the timestamp of the messages
+const PAYLOAD_SIZE: usize = 256;
+
+struct Options {
+    connections: usize,
+    duration: Duration,
+    rate: usize,
+    churn: usize,
+    report: Duration,
+}
+
+impl Options {
+    fn from_args() -> Options {
+        let mut options = Options {
+            connections: 200,
+            duration: Duration::from_secs(60),
+            rate: 10,
+            churn: 10,
+            report: Duration::from_secs(10),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(name) = args.next() {
+            let value: u64 = args
+                .next()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_else(|| panic!("{} expects a number", name));
+            match name.as_str() {
+                "--connections" => options.connections = value as usize,
+                "--duration" => options.duration = Duration::from_secs(value),
+                "--rate" => options.rate = value as usize,
+                "--churn" => options.churn = (value as usize).min(100),
+                "--report" => options.report = Duration::from_secs(value.max(1)),
+                _ => panic!("unknown option {}", name),
+            }
+        }
+        options
+    }
+}
+
+/// Latency of the messages received since the last report
+#[derive(Default)]
+struct Latency {
+    received: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+#[derive(Clone)]
+struct LatencyHandler {
+    start: Instant,
+    latency: Arc<Latency>,
+}
+
+impl MessagesHandler<DefaultPeerId> for LatencyHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        let sent_at = u64::from_be_bytes(data[..8].try_into().unwrap());
+        let micros = (self.start.elapsed().as_micros() as u64).saturating_sub(sent_at);
+        self.latency.received.fetch_add(1, Ordering::Relaxed);
+        self.latency
+            .total_micros
+            .fetch_add(micros, Ordering::Relaxed);
+        self.latency.max_micros.fetch_max(micros, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+type Manager = PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, LatencyHandler>;
+
+fn manager(max_connections: usize, handler: LatencyHandler) -> Manager {
+    let limits = PeerNetCategoryInfo {
+        max_in_connections: Some(max_connections),
+        max_in_connections_per_ip: Some(max_connections),
+        max_out_connections: Some(max_connections),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    };
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        handler,
+    )
+    .set_max_in_connections(Some(max_connections))
+    .set_default_category_info(limits)
+    .build()
+    .unwrap();
+    PeerNetManager::new(config).unwrap()
+}
+
+fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Resources of the process, from `/proc` on Linux
+#[derive(Clone, Copy, Debug, Default)]
+struct Resources {
+    rss_kib: Option<u64>,
+    threads: Option<u64>,
+    fds: Option<u64>,
+}
+
+impl Resources {
+    fn current() -> Resources {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let field = |name: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|value| value.split_whitespace().next())
+                .and_then(|value| value.parse().ok())
+        };
+        Resources {
+            rss_kib: field("VmRSS:"),
+            threads: field("Threads:"),
+            fds: std::fs::read_dir("/proc/self/fd")
+                .ok()
+                .map(|dir| dir.count() as u64),
+        }
+    }
+}
+
+fn format_optional(value: Option<u64>) -> String {
+    value.map_or("n/a".to_string(), |value| value.to_string())
+}
+
+fn send_channels(manager: &Manager) -> Vec<SendChannels> {
+    manager
+        .active_connections
+        .read()
+        .connections
+        .values()
+        .map(|connection| connection.send_channels.clone())
+        .collect()
+}
+
+/// Open connections from the client until it has `target`, returns false on timeout
+fn connect_to(client: &mut Manager, addr: SocketAddr, target: usize) -> bool {
+    let missing = target.saturating_sub(client.active_connections.read().connections.len());
+    for _ in 0..missing {
+        // the failed attempts are retried by the next call
+        let _ = client.try_connect(TransportType::Tcp, addr, TIMEOUT);
+    }
+    wait_until(|| client.active_connections.read().connections.len() >= target)
+}
+
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+fn main() {
+    let options = Options::from_args();
+    let start = Instant::now();
+    let latency = Arc::new(Latency::default());
+    let handler = LatencyHandler {
+        start,
+        latency: latency.clone(),
+    };
+    // room for the connections being replaced
+    let max_connections = options.connections * 2;
+    let mut server = manager(max_connections, handler.clone());
+    let mut client = manager(max_connections, handler);
+    let addr = free_addr();
+    server.start_listener(TransportType::Tcp, addr).unwrap();
+    let idle = Resources::current();
+    println!(
+        "soak: {} connections on {} for {:?}, {} messages/s per side, {}% replaced every {:?}",
+        options.connections, addr, options.duration, options.rate, options.churn, options.report
+    );
+
+    if !connect_to(&mut client, addr, options.connections) {
+        eprintln!("soak: could not open the connections");
+        std::process::exit(1);
+    }
+    let connected = Resources::current();
+    let mut rng = rand::thread_rng();
+    let mut nb_failed_sends = 0u64;
+    let mut nb_replaced = 0usize;
+    let mut next_report = Instant::now() + options.report;
+    // messages sent by each side of a connection every tick, the remainder is carried over
+    let mut credit = 0.0;
+    while start.elapsed() < options.duration {
+        credit += options.rate as f64 * TICK.as_secs_f64();
+        let nb_messages = credit as usize;
+        credit -= nb_messages as f64;
+        for send_channels in send_channels(&client)
+            .into_iter()
+            .chain(send_channels(&server))
+        {
+            for _ in 0..nb_messages {
+                let mut message = (start.elapsed().as_micros() as u64).to_be_bytes().to_vec();
+                message.resize(8 + PAYLOAD_SIZE, 0);
+                if send_channels.send(&RawSerializer, message, false).is_err() {
+                    nb_failed_sends += 1;
+                }
+            }
+        }
+        std::thread::sleep(TICK);
+
+        if Instant::now() < next_report {
+            continue;
+        }
+        next_report += options.report;
+        let received = latency.received.swap(0, Ordering::Relaxed);
+        let total_micros = latency.total_micros.swap(0, Ordering::Relaxed);
+        let max_micros = latency.max_micros.swap(0, Ordering::Relaxed);
+        let resources = Resources::current();
+        println!(
+            "[{:>6}s] connections: {} out, {} in, replaced {} | messages: {} received, {} failed \
+             sends | latency: avg {}us, max {}us | rss: {} KiB, threads: {}, fds: {}",
+            start.elapsed().as_secs(),
+            client.active_connections.read().connections.len(),
+            server.nb_in_connections(),
+            nb_replaced,
+            received,
+            nb_failed_sends,
+            total_micros.checked_div(received).unwrap_or(0),
+            max_micros,
+            format_optional(resources.rss_kib),
+            format_optional(resources.threads),
+            format_optional(resources.fds),
+        );
+
+        // replace some connections, their threads and sockets must be released
+        let mut peer_ids: Vec<DefaultPeerId> = client
+            .active_connections
+            .read()
+            .connections
+            .keys()
+            .cloned()
+            .collect();
+        peer_ids.shuffle(&mut rng);
+        peer_ids.truncate(options.connections * options.churn / 100);
+        for peer_id in &peer_ids {
+            client.active_connections.write().remove_connection(peer_id);
+        }
+        nb_replaced += peer_ids.len();
+        if !connect_to(&mut client, addr, options.connections) {
+            eprintln!("soak: could not replace the connections");
+        }
+    }
+
+    let steady = Resources::current();
+    let peer_ids: Vec<DefaultPeerId> = client
+        .active_connections
+        .read()
+        .connections
+        .keys()
+        .cloned()
+        .collect();
+    for peer_id in &peer_ids {
+        client.active_connections.write().remove_connection(peer_id);
+    }
+    let closed = wait_until(|| {
+        server.nb_in_connections() == 0
+            && Resources::current().threads <= idle.threads
+            && Resources::current().fds <= idle.fds
+    });
+    let end = Resources::current();
+    println!(
+        "soak: rss {} KiB once connected, {} KiB at the end of the traffic",
+        format_optional(connected.rss_kib),
+        format_optional(steady.rss_kib)
+    );
+    println!(
+        "soak: threads {} before connecting, {} after closing; fds {} before connecting, {} \
+         after closing",
+        format_optional(idle.threads),
+        format_optional(end.threads),
+        format_optional(idle.fds),
+        format_optional(end.fds)
+    );
+    server.stop_listener(TransportType::Tcp, addr).unwrap();
+    if !closed {
+        eprintln!("soak: connections, threads or file descriptors leaked");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Messages of the peer management handler, which carry the listeners of other peers
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use peernet::defaults::DefaultPeerId;
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::internal_handlers::peer_management::{
+    PeerManagementHooks, PeerManagementMessage, PeerManagementMessageSerializer,
+};
+use peernet::messages::MessagesSerializer;
+
+/// Ids on 8 bytes, the signatures aren't checked by the parsing
+#[derive(Clone)]
+struct FuzzHooks;
+
+impl PeerManagementHooks<DefaultPeerId> for FuzzHooks {
+    fn sign(&self, _data: &[u8]) -> PeerNetResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn verify(
+        &self,
+        _peer_id: &DefaultPeerId,
+        _data: &[u8],
+        _signature: &[u8],
+    ) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    fn serialize_peer_id(
+        &self,
+        peer_id: &DefaultPeerId,
+        buffer: &mut Vec<u8>,
+    ) -> PeerNetResult<()> {
+        buffer.extend_from_slice(&peer_id.id.to_be_bytes());
+        Ok(())
+    }
+
+    fn deserialize_peer_id(&self, data: &[u8]) -> PeerNetResult<(DefaultPeerId, usize)> {
+        let id = data
+            .get(..8)
+            .ok_or_else(|| PeerNetError::InvalidMessage.error("deserialize id", None))?;
+        Ok((
+            DefaultPeerId {
+                id: u64::from_be_bytes(id.try_into().unwrap()),
+            },
+            8,
+        ))
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let serializer = PeerManagementMessageSerializer { hooks: FuzzHooks };
+    let Ok(message): PeerNetResult<PeerManagementMessage<DefaultPeerId>> =
+        serializer.deserialize(data)
+    else {
+        return;
+    };
+    // anything accepted is read back the same once written again
+    let mut buffer = Vec::new();
+    serializer.serialize(&message, &mut buffer).unwrap();
+    assert_eq!(serializer.deserialize(&buffer).unwrap(), message);
+});
@@ -0,0 +1,51 @@
+//! Length-prefixed frames of the TCP connections, read by the endpoints and by the event loops:
+//! the first byte sets the maximum size of the messages, the second the size of the chunks of
+//! the streamed messages (0 for none) and the third the size of the reads, the rest is read as
+//! received from a peer
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use peernet::error::PeerNetError;
+use peernet::transports::{decode_frames, read_frames, FrameEvent};
+
+fuzz_target!(|data: &[u8]| {
+    let [max_message_size, chunk_size, read_size, data @ ..] = data else {
+        return;
+    };
+    let max_message_size = usize::from(*max_message_size) * 16;
+    let chunk_size = (*chunk_size != 0).then_some(usize::from(*chunk_size));
+    let read_size = usize::from(*read_size);
+
+    let (events, error) = read_frames(data, read_size, Some(max_message_size), chunk_size);
+    let (loop_events, loop_error) =
+        decode_frames(data, read_size, Some(max_message_size), chunk_size);
+    assert_eq!(events, loop_events);
+    assert_eq!(error.error_type(), loop_error.error_type());
+    assert!(matches!(
+        error.error_type(),
+        PeerNetError::ConnectionClosed | PeerNetError::InvalidMessage
+    ));
+
+    let mut streamed = None;
+    for event in events {
+        match event {
+            FrameEvent::Message(message) => {
+                assert!(streamed.is_none());
+                assert!(!message.is_empty() && message.len() <= max_message_size);
+            }
+            FrameEvent::MessageStart(size) => {
+                assert!(streamed.is_none());
+                assert!(size <= max_message_size);
+                streamed = Some((size, 0));
+            }
+            FrameEvent::Chunk(chunk) => {
+                let (_, received) = streamed.as_mut().unwrap();
+                *received += chunk.len();
+            }
+            FrameEvent::MessageEnd => {
+                let (size, received) = streamed.take().unwrap();
+                assert_eq!(size, received);
+            }
+        }
+    }
+});
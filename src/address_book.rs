@@ -0,0 +1,210 @@
+//! Addresses on which the peers can be dialed, by peer id
+//!
+//! The id of a peer is only known once its handshake authenticated it, while a dial needs an
+//! address and a transport. The `AddressBook` keeps the addresses known for each id with the
+//! time they were last seen (announced, or given by the application) and last connected, so
+//! that a peer can be dialed again after it disconnects.
+//!
+//! The manager records in `ActiveConnections::address_book` the addresses of the out
+//! connections whose handshake succeeded, the `ConnectionSupervisor` dials them again when
+//! there are not enough out connections. The addresses of the in connections are the ports the
+//! peers dialed from, they are not recorded.
+//!
+//! The book can be exported to seed another node, with the scores given by the application,
+//! see `PeerListSnapshot`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::internal_handlers::peer_management::ListenersMap;
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+/// Address of a peer in the `AddressBook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressEntry {
+    pub transport_type: TransportType,
+    pub addr: SocketAddr,
+    /// Last time the address was inserted or connected
+    pub last_seen: Instant,
+    /// Last time an out connection to the address succeeded, `None` if never
+    pub last_connected: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct AddressBook<Id: PeerId> {
+    peers: HashMap<Id, Vec<AddressEntry>>,
+    /// Given by the application, kept while the peer has an address
+    scores: HashMap<Id, f64>,
+    /// Addresses kept for each peer, the least recently seen are dropped beyond it
+    max_addresses_per_peer: Option<usize>,
+}
+
+impl<Id: PeerId> Default for AddressBook<Id> {
+    fn default() -> Self {
+        AddressBook {
+            peers: HashMap::new(),
+            scores: HashMap::new(),
+            max_addresses_per_peer: None,
+        }
+    }
+}
+
+impl<Id: PeerId> AddressBook<Id> {
+    pub fn set_max_addresses_per_peer(mut self, max_addresses_per_peer: usize) -> Self {
+        self.max_addresses_per_peer = Some(max_addresses_per_peer);
+        self
+    }
+
+    /// Add an address of `peer_id`, or refresh it if it's known
+    pub fn insert(
+        &mut self,
+        peer_id: Id,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        self.entry(peer_id, transport_type, addr, now);
+    }
+
+    /// Add the listeners announced by `peer_id`
+    pub fn insert_listeners(&mut self, peer_id: Id, listeners: &ListenersMap, now: Instant) {
+        for (addr, transport_type) in listeners {
+            self.entry(peer_id.clone(), *transport_type, *addr, now);
+        }
+    }
+
+    /// Record an out connection to `peer_id` on `addr` whose handshake succeeded
+    pub fn record_connected(
+        &mut self,
+        peer_id: Id,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        self.entry(peer_id, transport_type, addr, now)
+            .last_connected = Some(now);
+    }
+
+    /// Add an address of `peer_id` known elsewhere, e.g. imported from a `PeerListSnapshot`.
+    /// The most recent times are kept if it's known.
+    pub fn merge_entry(&mut self, peer_id: Id, entry: AddressEntry) {
+        let current = self.entry(peer_id, entry.transport_type, entry.addr, entry.last_seen);
+        current.last_connected = current.last_connected.max(entry.last_connected);
+    }
+
+    fn entry(
+        &mut self,
+        peer_id: Id,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> &mut AddressEntry {
+        let entries = self.peers.entry(peer_id).or_default();
+        let index = match entries
+            .iter()
+            .position(|entry| entry.addr == addr && entry.transport_type == transport_type)
+        {
+            Some(index) => index,
+            None => {
+                if let Some(max) = self.max_addresses_per_peer {
+                    while !entries.is_empty() && entries.len() >= max.max(1) {
+                        let stalest = (0..entries.len())
+                            .min_by_key(|index| entries[*index].last_seen)
+                            .unwrap_or(0);
+                        entries.swap_remove(stalest);
+                    }
+                }
+                entries.push(AddressEntry {
+                    transport_type,
+                    addr,
+                    last_seen: now,
+                    last_connected: None,
+                });
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[index];
+        entry.last_seen = entry.last_seen.max(now);
+        entry
+    }
+
+    /// Addresses of `peer_id` to dial, the ones connected most recently first, then the ones
+    /// seen most recently
+    pub fn addresses(&self, peer_id: &Id) -> Vec<(TransportType, SocketAddr)> {
+        let mut entries = self.entries(peer_id).to_vec();
+        entries
+            .sort_by(|a, b| (b.last_connected, b.last_seen).cmp(&(a.last_connected, a.last_seen)));
+        entries
+            .into_iter()
+            .map(|entry| (entry.transport_type, entry.addr))
+            .collect()
+    }
+
+    pub fn entries(&self, peer_id: &Id) -> &[AddressEntry] {
+        self.peers.get(peer_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Peer whose address is `addr`, if it's known
+    pub fn peer_of(&self, addr: &SocketAddr) -> Option<&Id> {
+        self.peers
+            .iter()
+            .find(|(_, entries)| entries.iter().any(|entry| entry.addr == *addr))
+            .map(|(peer_id, _)| peer_id)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &Id> {
+        self.peers.keys()
+    }
+
+    /// Score of `peer_id` given by the application, e.g. to share a curated list. It's ignored
+    /// if the peer has no address.
+    pub fn set_score(&mut self, peer_id: Id, score: f64) {
+        if self.peers.contains_key(&peer_id) {
+            self.scores.insert(peer_id, score);
+        }
+    }
+
+    pub fn score(&self, peer_id: &Id) -> Option<f64> {
+        self.scores.get(peer_id).copied()
+    }
+
+    pub fn remove_peer(&mut self, peer_id: &Id) {
+        self.peers.remove(peer_id);
+        self.scores.remove(peer_id);
+    }
+
+    pub fn remove_address(&mut self, peer_id: &Id, addr: &SocketAddr) {
+        if let Some(entries) = self.peers.get_mut(peer_id) {
+            entries.retain(|entry| entry.addr != *addr);
+            if entries.is_empty() {
+                self.remove_peer(peer_id);
+            }
+        }
+    }
+
+    /// Remove the addresses not seen for `max_age`, and the peers left without address.
+    /// Returns the number of addresses removed.
+    pub fn prune(&mut self, max_age: Duration, now: Instant) -> usize {
+        let mut nb_removed = 0;
+        self.peers.retain(|_, entries| {
+            let before = entries.len();
+            entries.retain(|entry| now.saturating_duration_since(entry.last_seen) < max_age);
+            nb_removed += before - entries.len();
+            !entries.is_empty()
+        });
+        let peers = &self.peers;
+        self.scores.retain(|peer_id, _| peers.contains_key(peer_id));
+        nb_removed
+    }
+
+    /// Number of peers with an address
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
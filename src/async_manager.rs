@@ -0,0 +1,284 @@
+//! Async API over the threaded manager (feature `async`)
+//!
+//! `AsyncPeerNetManager` doesn't depend on a runtime: the connections are still driven by the
+//! threads of a `PeerNetManager` and the futures are completed from them, so it can be used
+//! from tokio, async-std or any other executor. The received messages are consumed from the
+//! `MessagesStream` created with the `AsyncMessagesHandler` of the manager:
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use futures::StreamExt;
+//! use peernet::async_manager::{AsyncMessagesHandler, AsyncPeerNetManager};
+//! use peernet::config::PeerNetConfigurationBuilder;
+//! use peernet::defaults::{DefaultContext, DefaultInitConnection, DefaultPeerId};
+//! use peernet::messages::RawSerializer;
+//! use peernet::peer_id::PeerId;
+//! use peernet::transports::TransportType;
+//!
+//! # let port = (10000..u16::MAX)
+//! #     .find(|port| std::net::TcpListener::bind(("127.0.0.1", *port)).is_ok())
+//! #     .unwrap();
+//! let addr = format!("127.0.0.1:{port}").parse().unwrap();
+//! let new_manager = || {
+//!     let (message_handler, messages) = AsyncMessagesHandler::new(100);
+//!     let context = DefaultContext {
+//!         our_id: DefaultPeerId::generate(),
+//!     };
+//!     let config = PeerNetConfigurationBuilder::new(context, DefaultInitConnection, message_handler)
+//!         .build()
+//!         .unwrap();
+//!     (AsyncPeerNetManager::new(config).unwrap(), messages)
+//! };
+//! let (mut manager, mut messages) = new_manager();
+//! manager.manager.start_listener(TransportType::Tcp, addr).unwrap();
+//! // the peers are disconnected if the stream of their manager is dropped
+//! let (mut manager2, _messages2) = new_manager();
+//!
+//! futures::executor::block_on(async {
+//!     let peer_id = manager2
+//!         .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+//!         .await
+//!         .unwrap();
+//!     manager2
+//!         .send_to(&peer_id, &RawSerializer, b"hello".to_vec(), false)
+//!         .await
+//!         .unwrap();
+//!     let (_, data) = messages.next().await.unwrap();
+//!     assert_eq!(&data[..], b"hello");
+//! });
+//! ```
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crossbeam::channel::{unbounded, Receiver, Select, Sender, TryRecvError, TrySendError};
+use futures::channel::{mpsc, oneshot};
+use futures::SinkExt;
+
+use crate::config::PeerNetConfiguration;
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::messages::{Bytes, MessagesHandler, MessagesSerializer};
+use crate::network_manager::{wait_handshake, PeerNetManager};
+use crate::peer::{InitConnectionHandler, QueuedMessage, SendChannels};
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+/// Messages received by an `AsyncPeerNetManager` with the id of the peer that sent them
+pub type MessagesStream<Id> = mpsc::Receiver<(Id, Bytes)>;
+
+/// Forwards the received messages to the `MessagesStream` created with it. The peers stop
+/// reading while the stream is full, and are disconnected once it's dropped.
+#[derive(Clone)]
+pub struct AsyncMessagesHandler<Id> {
+    sender: mpsc::Sender<(Id, Bytes)>,
+}
+
+impl<Id: PeerId> AsyncMessagesHandler<Id> {
+    /// `buffer` is the number of messages that can wait in the stream
+    pub fn new(buffer: usize) -> (AsyncMessagesHandler<Id>, MessagesStream<Id>) {
+        let (sender, receiver) = mpsc::channel(buffer);
+        (AsyncMessagesHandler { sender }, receiver)
+    }
+}
+
+impl<Id: PeerId> MessagesHandler<Id> for AsyncMessagesHandler<Id> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        // called from the threads of the manager, not from an executor
+        futures::executor::block_on(self.sender.clone().send((peer_id.clone(), data)))
+            .map_err(|err| PeerNetError::HandlerError.new("async messages handler", err, None))
+    }
+}
+
+/// `PeerNetManager` with futures for the operations that wait for the network
+pub struct AsyncPeerNetManager<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, AsyncMessagesHandler<Id>>,
+> {
+    /// The threaded manager, for the operations that don't wait (listeners, configuration...)
+    pub manager: PeerNetManager<Id, Ctx, I, AsyncMessagesHandler<Id>>,
+    /// Messages of `send_to` waiting for room in the send channels of their peer, see
+    /// `send_bridge`
+    blocked_sends: Sender<BlockedSend>,
+}
+
+impl<Id: PeerId, Ctx: Context<Id>, I: InitConnectionHandler<Id, Ctx, AsyncMessagesHandler<Id>>>
+    AsyncPeerNetManager<Id, Ctx, I>
+{
+    pub fn new(
+        config: PeerNetConfiguration<Id, Ctx, I, AsyncMessagesHandler<Id>>,
+    ) -> PeerNetResult<AsyncPeerNetManager<Id, Ctx, I>> {
+        let (blocked_sends, blocked_sends_rx) = unbounded();
+        std::thread::Builder::new()
+            .name("async_send_bridge".to_string())
+            .spawn(move || send_bridge(blocked_sends_rx))
+            .map_err(|err| PeerNetError::SocketError.new("spawn async_send_bridge", err, None))?;
+        Ok(AsyncPeerNetManager {
+            manager: PeerNetManager::new(config)?,
+            blocked_sends,
+        })
+    }
+
+    /// Start a connection to `addr` right away. The future resolves once the handshake is done,
+    /// with the id it gave to the peer.
+    pub fn try_connect(
+        &mut self,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> impl Future<Output = PeerNetResult<Id>> + Send + 'static {
+        let started = self
+            .manager
+            .try_connect_reporting(transport_type, addr, None, timeout);
+        async move {
+            let (connect, result_rx) = started?;
+            in_thread(format!("async_try_connect_{:?}", addr), move || {
+                wait_handshake(connect, result_rx)
+            })
+            .await
+        }
+    }
+
+    /// Queue a message for the connected peer `peer_id`. The future resolves once the message
+    /// is in the send channels of the peer, it waits while they are full. The message is
+    /// refused as by `SendChannels::send`, e.g. beyond `SendChannels::max_message_size`.
+    pub fn send_to<T, MS: MessagesSerializer<T>>(
+        &self,
+        peer_id: &Id,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+    ) -> impl Future<Output = PeerNetResult<()>> + Send + 'static {
+        let queued = self
+            .manager
+            .active_connections
+            .read()
+            .connections
+            .get(peer_id)
+            .map(|connection| connection.send_channels.clone())
+            .ok_or_else(|| {
+                PeerNetError::PeerConnectionError.error(
+                    "async send_to",
+                    Some(format!("not connected: {:?}", peer_id)),
+                )
+            })
+            .and_then(|send_channels| {
+                let message = send_channels.serialize_message(
+                    message_serializer,
+                    message,
+                    high_priority,
+                    None,
+                )?;
+                Ok((send_channels, message))
+            });
+        let blocked_sends = self.blocked_sends.clone();
+        async move {
+            let (send_channels, message) = queued?;
+            match send_channels.try_push(message, high_priority) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(message)) => {
+                    let (result_tx, result_rx) = oneshot::channel();
+                    blocked_sends
+                        .send(BlockedSend {
+                            send_channels,
+                            message,
+                            high_priority,
+                            result_tx,
+                        })
+                        .map_err(|err| PeerNetError::SendError.new("async send_to", err, None))?;
+                    result_rx.await.map_err(|_| {
+                        PeerNetError::SendError
+                            .error("async send_to", Some("send bridge stopped".to_string()))
+                    })?
+                }
+                Err(err @ TrySendError::Disconnected(_)) => {
+                    Err(PeerNetError::SendError.new("async send_to", err, None))
+                }
+            }
+        }
+    }
+}
+
+/// Message of `send_to` that found the send channels of its peer full
+struct BlockedSend {
+    send_channels: SendChannels,
+    message: QueuedMessage,
+    high_priority: bool,
+    result_tx: oneshot::Sender<PeerNetResult<()>>,
+}
+
+/// Thread queuing the `BlockedSend` once there is room for them, for all the peers, until the
+/// `AsyncPeerNetManager` is dropped and they are all queued. The futures that were dropped
+/// give up their message.
+fn send_bridge(blocked_sends_rx: Receiver<BlockedSend>) {
+    let mut blocked: Vec<BlockedSend> = Vec::new();
+    let mut open = true;
+    loop {
+        blocked.retain(|send| !send.result_tx.is_canceled());
+        if open {
+            loop {
+                match blocked_sends_rx.try_recv() {
+                    Ok(send) => blocked.push(send),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        open = false;
+                        break;
+                    }
+                }
+            }
+        }
+        if !open && blocked.is_empty() {
+            return;
+        }
+        // only tells which channel has room, the messages are then queued with `try_push`
+        let ready = {
+            let mut select = Select::new();
+            for send in &blocked {
+                select.send(send.send_channels.channel(send.high_priority));
+            }
+            if open {
+                select.recv(&blocked_sends_rx);
+            }
+            select.ready()
+        };
+        if ready == blocked.len() {
+            continue;
+        }
+        let send = blocked.remove(ready);
+        let result = match send
+            .send_channels
+            .try_push(send.message, send.high_priority)
+        {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                // taken by another sender in the meantime
+                blocked.insert(ready, BlockedSend { message, ..send });
+                continue;
+            }
+            Err(err @ TrySendError::Disconnected(_)) => {
+                Err(PeerNetError::SendError.new("async send_to", err, None))
+            }
+        };
+        let _ = send.result_tx.send(result);
+    }
+}
+
+/// Run `f` in a new thread, the returned future resolves with its result
+fn in_thread<T: Send + 'static>(
+    name: String,
+    f: impl FnOnce() -> PeerNetResult<T> + Send + 'static,
+) -> impl Future<Output = PeerNetResult<T>> + Send + 'static {
+    let (result_tx, result_rx) = oneshot::channel();
+    let spawned = std::thread::Builder::new().name(name).spawn(move || {
+        let _ = result_tx.send(f());
+    });
+    async move {
+        spawned.map_err(|err| PeerNetError::SocketError.new("spawn async thread", err, None))?;
+        result_rx.await.map_err(|_| {
+            PeerNetError::PeerConnectionError
+                .error("async thread", Some("thread panicked".to_string()))
+        })?
+    }
+}
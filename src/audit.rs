@@ -0,0 +1,259 @@
+//! Audit log of the connection attempts, see `PeerNetFeatures::audit`
+//!
+//! Each dial and each connection accepted by a listener gives an `AuditRecord` when it starts
+//! and another one with its outcome, so that the attempts of a node can be reviewed after an
+//! incident. The records are given to an `AuditSink`; `FileAuditSink` writes them one per line
+//! in a file rotated by size, from a thread of its own.
+//!
+//! The records of the listeners and handshakes are given under the lock of the
+//! `ActiveConnections`, with the events of the `ConnectionHistory`: the sink shouldn't block.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{bounded, Sender, TrySendError};
+
+use crate::history::ConnectionEventKind;
+use crate::peer::PeerConnectionType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// We started to dial the address
+    Dialing,
+    /// The dial couldn't be started, e.g. the address is one of ours, or the TCP connection
+    /// couldn't be established
+    DialFailed,
+    /// A listener accepted the connection, its handshake starts
+    Accepted,
+    /// Refused by the limits of the connections, before or after the handshake
+    Refused,
+    HandshakeFailed,
+    /// The handshake succeeded and the connection was confirmed
+    Connected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    /// Address of the other side of the connection
+    pub addr: SocketAddr,
+    pub direction: PeerConnectionType,
+    pub outcome: AuditOutcome,
+    /// `Debug` format of the id of the peer, once its handshake authenticated it
+    pub peer_id: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    /// The record of an event of the `ConnectionHistory`, `None` for the disconnections
+    pub(crate) fn from_event<Id: fmt::Debug>(
+        time: SystemTime,
+        addr: SocketAddr,
+        kind: &ConnectionEventKind<Id>,
+    ) -> Option<AuditRecord> {
+        let (direction, outcome, peer_id, reason) = match kind {
+            ConnectionEventKind::Accepted => {
+                (PeerConnectionType::IN, AuditOutcome::Accepted, None, None)
+            }
+            ConnectionEventKind::Refused { direction } => {
+                (*direction, AuditOutcome::Refused, None, None)
+            }
+            ConnectionEventKind::HandshakeSucceeded { peer_id, direction } => (
+                *direction,
+                AuditOutcome::Connected,
+                Some(format!("{:?}", peer_id)),
+                None,
+            ),
+            ConnectionEventKind::HandshakeFailed { direction, reason } => (
+                *direction,
+                AuditOutcome::HandshakeFailed,
+                None,
+                Some(reason.clone()),
+            ),
+            ConnectionEventKind::Disconnected { .. } => return None,
+        };
+        Some(AuditRecord {
+            time,
+            addr,
+            direction,
+            outcome,
+            peer_id,
+            reason,
+        })
+    }
+}
+
+/// `<unix time in ms> <IN|OUT> <addr> <outcome> [peer_id=<id>] [reason=<reason>]`, the reason
+/// is quoted so that the line can be split on the spaces
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        write!(
+            f,
+            "{} {:?} {} {:?}",
+            time, self.direction, self.addr, self.outcome
+        )?;
+        if let Some(peer_id) = &self.peer_id {
+            write!(f, " peer_id={}", peer_id)?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, " reason={:?}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the `AuditRecord`s, it's called from the threads of the manager
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+pub type SharedAuditSink = Arc<dyn AuditSink>;
+
+/// Give the record built by `record` to `audit`, it's only built if there is a sink
+pub(crate) fn audit(audit: &Option<SharedAuditSink>, record: impl FnOnce() -> AuditRecord) {
+    if let Some(audit) = audit {
+        audit.record(&record());
+    }
+}
+
+/// Lines waiting for the writer thread of a `FileAuditSink`
+const FILE_AUDIT_QUEUE_SIZE: usize = 4096;
+
+/// Writes the records in `path`, one per line. Before a record would make the file exceed
+/// `max_file_size` bytes, it's renamed `<path>.1`, the previous `<path>.1` becomes `<path>.2`
+/// and so on, the files beyond `<path>.<max_files>` are removed.
+///
+/// The lines are written by a thread of the sink, not to block the manager on the disk. A
+/// record is dropped, and counted in `nb_dropped`, if the thread is 4096 lines late. The write errors are logged, the records are then lost. The
+/// queued lines are written before the sink is dropped.
+pub struct FileAuditSink {
+    path: PathBuf,
+    lines: Option<Sender<String>>,
+    nb_dropped: AtomicU64,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl FileAuditSink {
+    /// Appends to `path` if it exists
+    pub fn new(
+        path: impl Into<PathBuf>,
+        max_file_size: u64,
+        max_files: usize,
+    ) -> std::io::Result<FileAuditSink> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        let mut writer = FileWriter {
+            path: path.clone(),
+            max_file_size,
+            max_files,
+            file,
+            size,
+        };
+        let (lines_tx, lines_rx) = bounded::<String>(FILE_AUDIT_QUEUE_SIZE);
+        let writer = std::thread::Builder::new()
+            .name("audit_writer".to_string())
+            .spawn(move || {
+                for line in lines_rx.iter() {
+                    if let Err(err) = writer.write(&line) {
+                        tracing::warn!(path = ?writer.path, "audit log write failed: {}", err);
+                    }
+                }
+            })?;
+        Ok(FileAuditSink {
+            path,
+            lines: Some(lines_tx),
+            nb_dropped: AtomicU64::new(0),
+            writer: Some(writer),
+        })
+    }
+
+    /// Records dropped because the writer thread was late
+    pub fn nb_dropped(&self) -> u64 {
+        self.nb_dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let Some(lines) = &self.lines else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = lines.try_send(format!("{}\n", record)) {
+            if self.nb_dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                tracing::warn!(path = ?self.path, "audit log late, records dropped");
+            }
+        }
+    }
+}
+
+impl Drop for FileAuditSink {
+    fn drop(&mut self) {
+        // the writer stops once it wrote the queued lines
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// The file of a `FileAuditSink`, owned by its writer thread
+struct FileWriter {
+    path: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl FileWriter {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    /// Shift the rotated files and start a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
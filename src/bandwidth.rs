@@ -0,0 +1,206 @@
+//! Counters of the bytes and messages sent and received by the manager and by each endpoint
+//!
+//! The counters are updated by the threads reading and writing the connections without taking
+//! a lock, and read with `Bandwidth::snapshot`. Only the payload of the messages is counted, not
+//! the framing nor the handshakes. A frame is a message, counted once it's entirely sent or
+//! received.
+//!
+//! `Bandwidth::delta` gives the counts since its previous call, for the monitoring polling the
+//! endpoints at an interval. `Bandwidth::reset` restarts the counters of `snapshot` from zero,
+//! the rates, the deltas and `total` go on.
+//!
+//! The rates are exponentially weighted moving averages of the bytes per second, updated from
+//! the counters each time they are read with `Bandwidth::rates` so that counting stays free.
+
+use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Time for the weight of the old rates to decrease by a factor e
+pub const RATE_TIME_CONSTANT: Duration = Duration::from_secs(5);
+
+pub type SharedBandwidth = Arc<Bandwidth>;
+
+#[derive(Debug)]
+pub struct Bandwidth {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    // counters at the last call to `reset`, subtracted by `snapshot`
+    reset_at: Mutex<BandwidthSnapshot>,
+    // counters at the last call to `delta`
+    delta_at: Mutex<BandwidthSnapshot>,
+    // counters and rates at the last call to `rates`
+    rates: Mutex<(Instant, BandwidthSnapshot, BandwidthRates)>,
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth {
+            bytes_sent: Default::default(),
+            bytes_received: Default::default(),
+            frames_sent: Default::default(),
+            frames_received: Default::default(),
+            reset_at: Default::default(),
+            delta_at: Default::default(),
+            rates: Mutex::new((
+                Instant::now(),
+                BandwidthSnapshot::default(),
+                BandwidthRates::default(),
+            )),
+        }
+    }
+}
+
+impl Bandwidth {
+    pub fn new_shared() -> SharedBandwidth {
+        Arc::new(Bandwidth::default())
+    }
+
+    /// A message of `nb_bytes` sent
+    pub fn add_sent(&self, nb_bytes: u64) {
+        self.add_sent_frames(1, nb_bytes);
+    }
+
+    /// A message of `nb_bytes` received
+    pub fn add_received(&self, nb_bytes: u64) {
+        self.add_received_frames(1, nb_bytes);
+    }
+
+    /// `nb_frames` messages of `nb_bytes` in total sent, `nb_frames` is 0 for the first parts
+    /// of a message sent in several writes
+    pub fn add_sent_frames(&self, nb_frames: u64, nb_bytes: u64) {
+        self.bytes_sent.fetch_add(nb_bytes, Ordering::Relaxed);
+        self.frames_sent.fetch_add(nb_frames, Ordering::Relaxed);
+    }
+
+    /// `nb_frames` messages of `nb_bytes` in total received, `nb_frames` is 0 for the first
+    /// chunks of a streamed message
+    pub fn add_received_frames(&self, nb_frames: u64, nb_bytes: u64) {
+        self.bytes_received.fetch_add(nb_bytes, Ordering::Relaxed);
+        self.frames_received.fetch_add(nb_frames, Ordering::Relaxed);
+    }
+
+    /// Counts since the creation of the counters. They are read one after the other, a message
+    /// sent in between can be counted in `bytes_sent` and not yet in `bytes_received`.
+    fn counters(&self) -> BandwidthSnapshot {
+        BandwidthSnapshot {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts since the creation of the counters, not affected by `reset`
+    pub fn total(&self) -> BandwidthSnapshot {
+        self.counters()
+    }
+
+    /// Counts since the creation of the counters or their last `reset`
+    pub fn snapshot(&self) -> BandwidthSnapshot {
+        self.counters().since(&self.reset_at.lock())
+    }
+
+    /// Restart the counts of `snapshot` from zero, returns the counts before the reset
+    pub fn reset(&self) -> BandwidthSnapshot {
+        let mut reset_at = self.reset_at.lock();
+        let counters = self.counters();
+        let counted = counters.since(&reset_at);
+        *reset_at = counters;
+        counted
+    }
+
+    /// Counts since the previous call, or since the creation of the counters for the first
+    /// one. Not affected by `reset`.
+    pub fn delta(&self) -> BandwidthSnapshot {
+        let mut delta_at = self.delta_at.lock();
+        let counters = self.counters();
+        let delta = counters.since(&delta_at);
+        *delta_at = counters;
+        delta
+    }
+
+    /// Bytes per second sent and received lately, averaged over about `RATE_TIME_CONSTANT`
+    pub fn rates(&self) -> BandwidthRates {
+        let mut rates = self.rates.lock();
+        let (last_time, last_snapshot, last_rates) = &mut *rates;
+        let now = Instant::now();
+        let elapsed = now.duration_since(*last_time).as_secs_f64();
+        if elapsed <= 0.0 {
+            return *last_rates;
+        }
+        let snapshot = self.counters();
+        let bytes = snapshot.since(last_snapshot);
+        // the weight of the new sample grows with the time it covers
+        let weight = 1.0 - (-elapsed / RATE_TIME_CONSTANT.as_secs_f64()).exp();
+        let average = |last: f64, nb_bytes: u64| last + weight * (nb_bytes as f64 / elapsed - last);
+        *last_rates = BandwidthRates {
+            bytes_sent_per_sec: average(last_rates.bytes_sent_per_sec, bytes.bytes_sent),
+            bytes_received_per_sec: average(
+                last_rates.bytes_received_per_sec,
+                bytes.bytes_received,
+            ),
+        };
+        *last_time = now;
+        *last_snapshot = snapshot;
+        *last_rates
+    }
+}
+
+/// Bytes per second sent and received, see `Bandwidth::rates`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct BandwidthRates {
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
+}
+
+impl Add for BandwidthRates {
+    type Output = BandwidthRates;
+
+    fn add(self, other: BandwidthRates) -> BandwidthRates {
+        BandwidthRates {
+            bytes_sent_per_sec: self.bytes_sent_per_sec + other.bytes_sent_per_sec,
+            bytes_received_per_sec: self.bytes_received_per_sec + other.bytes_received_per_sec,
+        }
+    }
+}
+
+/// Bytes and messages sent and received at the time of the snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BandwidthSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+}
+
+impl BandwidthSnapshot {
+    /// Bytes and messages sent and received since `earlier`, a snapshot of the same counters
+    pub fn since(&self, earlier: &BandwidthSnapshot) -> BandwidthSnapshot {
+        BandwidthSnapshot {
+            bytes_sent: self.bytes_sent.saturating_sub(earlier.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            frames_sent: self.frames_sent.saturating_sub(earlier.frames_sent),
+            frames_received: self.frames_received.saturating_sub(earlier.frames_received),
+        }
+    }
+}
+
+impl Add for BandwidthSnapshot {
+    type Output = BandwidthSnapshot;
+
+    fn add(self, other: BandwidthSnapshot) -> BandwidthSnapshot {
+        BandwidthSnapshot {
+            bytes_sent: self.bytes_sent.saturating_add(other.bytes_sent),
+            bytes_received: self.bytes_received.saturating_add(other.bytes_received),
+            frames_sent: self.frames_sent.saturating_add(other.frames_sent),
+            frames_received: self.frames_received.saturating_add(other.frames_received),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Pool of byte buffers reused for the serialization, the sending and the reading of messages
+//!
+//! Buffers are sorted in size classes so that a message gets a buffer that can hold it without
+//! reallocating. A buffer handed out through `Bytes` goes back to the pool once every `Bytes`
+//! referencing it is dropped.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::config::BufferPoolConfig;
+
+pub type SharedBufferPool = Arc<BufferPool>;
+
+pub struct BufferPool {
+    // (capacity of the buffers, idle buffers) sorted by capacity
+    classes: Vec<(usize, Mutex<Vec<Vec<u8>>>)>,
+    max_buffers_per_class: usize,
+}
+
+impl BufferPool {
+    pub fn new(config: &BufferPoolConfig) -> BufferPool {
+        let mut size_classes = config.size_classes.clone();
+        size_classes.sort_unstable();
+        size_classes.dedup();
+        BufferPool {
+            classes: size_classes
+                .into_iter()
+                .map(|capacity| (capacity, Mutex::new(Vec::new())))
+                .collect(),
+            max_buffers_per_class: config.max_buffers_per_class,
+        }
+    }
+
+    /// Get an empty buffer that can hold at least `size` bytes without reallocating
+    pub fn get(&self, size: usize) -> Vec<u8> {
+        match self.classes.iter().find(|(capacity, _)| *capacity >= size) {
+            Some((capacity, buffers)) => buffers
+                .lock()
+                .pop()
+                .unwrap_or_else(|| Vec::with_capacity(*capacity)),
+            None => Vec::with_capacity(size),
+        }
+    }
+
+    /// Give back a buffer to the pool. It's stored in the biggest class it can serve, or dropped
+    /// if this class is full or if the buffer is more than twice bigger than the biggest class.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        let biggest_class = self.classes.last().map_or(0, |(capacity, _)| *capacity);
+        if buffer.capacity() > biggest_class.saturating_mul(2) {
+            return;
+        }
+        let Some((_, buffers)) = self
+            .classes
+            .iter()
+            .rev()
+            .find(|(capacity, _)| *capacity <= buffer.capacity())
+        else {
+            return;
+        };
+        let mut buffers = buffers.lock();
+        if buffers.len() < self.max_buffers_per_class {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+
+    /// Wrap a buffer of the pool in `Bytes`, it's given back to the pool when the last
+    /// reference to it is dropped.
+    pub fn into_bytes(self: &Arc<Self>, buffer: Vec<u8>) -> Bytes {
+        Bytes::from_owner(PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        })
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(&BufferPoolConfig::default())
+    }
+}
+
+struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: SharedBufferPool,
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
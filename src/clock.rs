@@ -0,0 +1,114 @@
+//! Time of the timeouts of the manager, see `PeerNetFeatures::clock`
+//!
+//! The handshake timeout, the read and write timeouts of the TCP connections, the age of the
+//! connections, the times reported in `DiagnosticEvent::Connected`, and the rotation and dial
+//! backoff of the `ConnectionSupervisor` are measured on the clock of the manager. Tests can give
+//! it a `MockClock` and advance the time instead of sleeping through the timeouts. The waits on
+//! the sockets are cut by `Clock::os_timeout` to look at the clock again, the TCP keepalive is
+//! applied by the OS on the real time.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "testing")]
+use crossbeam::channel::TryRecvError;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+#[cfg(feature = "testing")]
+use parking_lot::{Condvar, Mutex};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wait until `timeout` has passed on the clock, or until `stop` receives a message or is
+    /// disconnected. Returns false if the timeout passed.
+    fn wait(&self, stop: &Receiver<()>, timeout: Duration) -> bool;
+
+    /// Real time to block in a call of the OS, like the read of a socket, that waits for
+    /// `timeout` on the clock. The caller looks at the clock again when the call times out.
+    fn os_timeout(&self, timeout: Duration) -> Duration {
+        timeout
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+impl Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// The real time, used if `PeerNetFeatures::clock` isn't set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wait(&self, stop: &Receiver<()>, timeout: Duration) -> bool {
+        !matches!(stop.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+}
+
+/// The waits notice a stop within this real time, the `MockClock` isn't woken by the channels
+#[cfg(feature = "testing")]
+const MOCK_STOP_POLL: Duration = Duration::from_millis(10);
+
+/// Clock that only moves with `advance`, starting at the current instant (feature `testing`)
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+    advanced: Condvar,
+}
+
+#[cfg(feature = "testing")]
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Mutex::new(Instant::now()),
+            advanced: Condvar::new(),
+        }
+    }
+
+    /// Move the clock forward, waking the waits whose timeout passed
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+        self.advanced.notify_all();
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+
+    fn wait(&self, stop: &Receiver<()>, timeout: Duration) -> bool {
+        let mut now = self.now.lock();
+        let deadline = *now + timeout;
+        loop {
+            if !matches!(stop.try_recv(), Err(TryRecvError::Empty)) {
+                return true;
+            }
+            if *now >= deadline {
+                return false;
+            }
+            self.advanced.wait_for(&mut now, MOCK_STOP_POLL);
+        }
+    }
+
+    fn os_timeout(&self, timeout: Duration) -> Duration {
+        timeout.min(MOCK_STOP_POLL)
+    }
+}
@@ -3,28 +3,345 @@
 //! This module contains the configuration for the PeerNet manager.
 //! It regroups all the information needed to initialize a PeerNet manager.
 
-use std::collections::HashMap;
-use std::net::IpAddr;
-use std::time::Duration;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
+use crossbeam::channel::Sender;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
+use crate::audit::SharedAuditSink;
+use crate::clock::{SharedClock, SystemClock};
 use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetErrorData, PeerNetResult};
+use crate::eviction::SharedEvictionPolicy;
 use crate::messages::MessagesHandler;
-use crate::peer::InitConnectionHandler;
+use crate::network_manager::to_canonical;
+use crate::peer::{InitConnectionHandler, PeerConnectionType};
 use crate::peer_id::PeerId;
+use crate::port_mapping::PortMappingEvent;
 
 pub const RATE_LIMIT: u64 = u64::MAX; //1024 * 1024 * 120; // 120 Mo / sec
+/// Size of the reads/writes of the rate limiter of the TCP connections
+pub const MIN_OPERATION_SIZE: u64 = 60 * 1024;
+/// Maximum of `PeerNetFeatures::small_message_size`, the frames of the small messages are
+/// built on the stack
+pub const MAX_SMALL_MESSAGE_SIZE: usize = 1024;
 
+/// Invalid configuration, see `PeerNetConfiguration::validate`
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ConfigError {
+    #[error("rate_bucket_size {rate_bucket_size} is lower than the min operation size {MIN_OPERATION_SIZE}")]
+    RateBucketTooSmall { rate_bucket_size: u64 },
+    #[error("max_message_size is 0")]
+    ZeroMaxMessageSize,
+    #[error("send_data_channel_size is 0")]
+    ZeroSendDataChannelSize,
+    #[error("{0} is 0")]
+    ZeroDuration(&'static str),
+    #[error("small_message_size {0} is greater than {MAX_SMALL_MESSAGE_SIZE}")]
+    SmallMessageSizeTooBig(usize),
+    /// `category` is `None` for the default category
+    #[error("category {category:?}: max_in_connections_per_ip {max_in_connections_per_ip} is greater than max_in_connections {max_in_connections}")]
+    PerIpLimitAboveCategoryLimit {
+        category: Option<String>,
+        max_in_connections_per_ip: usize,
+        max_in_connections: usize,
+    },
+    #[error("SNI route {server_name}: category {category} is not in peers_categories")]
+    UnknownSniCategory {
+        server_name: String,
+        category: String,
+    },
+    #[error("dial_pacing.max_dials_per_sec is 0")]
+    ZeroDialRate,
+}
+
+impl ConfigError {
+    /// See `PeerNetError::code`
+    pub fn code(&self) -> u16 {
+        match self {
+            ConfigError::RateBucketTooSmall { .. } => 300,
+            ConfigError::ZeroMaxMessageSize => 301,
+            ConfigError::ZeroSendDataChannelSize => 302,
+            ConfigError::ZeroDuration(_) => 303,
+            ConfigError::SmallMessageSizeTooBig(_) => 304,
+            ConfigError::PerIpLimitAboveCategoryLimit { .. } => 305,
+            ConfigError::UnknownSniCategory { .. } => 306,
+            ConfigError::ZeroDialRate => 307,
+        }
+    }
+}
+
+/// Limits of the connections of a category of peers, `None` for no limit
 #[derive(Clone, Copy, Default, Debug, Serialize, Deserialize)]
 pub struct PeerNetCategoryInfo {
-    pub max_in_connections: usize,
-    pub max_in_connections_per_ip: usize,
-    pub max_out_connections: usize,
+    pub max_in_connections: Option<usize>,
+    pub max_in_connections_per_ip: Option<usize>,
+    pub max_out_connections: Option<usize>,
+    /// Connections we open to the same IP, checked after the handshake
+    #[serde(default)]
+    pub max_out_connections_per_ip: Option<usize>,
+    /// Replaces `TcpSettings::read_timeout` for the TCP connections of the category, e.g. longer
+    /// for distant peers. In milliseconds when serialized
+    #[serde(default, with = "optional_duration_millis")]
+    pub read_timeout: Option<Duration>,
+    /// Replaces `TcpSettings::write_timeout` for the TCP connections of the category
+    #[serde(default, with = "optional_duration_millis")]
+    pub write_timeout: Option<Duration>,
+}
+
+/// Check that `count` is under `limit`, no limit if `None`
+pub(crate) fn under_limit(count: usize, limit: Option<usize>) -> bool {
+    limit.map_or(true, |limit| count < limit)
 }
 
 pub type PeerNetCategories = HashMap<String, (Vec<IpAddr>, PeerNetCategoryInfo)>;
 
+/// Peer whose connections are accepted even when the limits of its category or of the manager
+/// are reached, see `PeerNetConfiguration::trusted_peers`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustedPeer<Id> {
+    /// Only known after the handshake: the connection must still pass the limits checked before
+    /// the handshake
+    Id(Id),
+    /// Checked before and after the handshake
+    Ip(IpAddr),
+}
+
+#[derive(Debug, Clone)]
+pub struct TrustedPeers<Id> {
+    pub peers: Vec<TrustedPeer<Id>>,
+    /// Disconnect the untrusted peer of the same direction and category that exchanged the
+    /// fewest bytes when a trusted peer connects beyond the limits
+    pub evict_untrusted: bool,
+}
+
+impl<Id> Default for TrustedPeers<Id> {
+    fn default() -> Self {
+        TrustedPeers {
+            peers: Vec::new(),
+            evict_untrusted: false,
+        }
+    }
+}
+
+/// Peers accepted or refused by their id after the handshake, whatever their address, see
+/// `PeerNetConfiguration::peer_id_rules`
+#[derive(Debug, Clone)]
+pub struct PeerIdRules<Id> {
+    /// Only these peers are accepted. All the peers are if `None`
+    pub allowed_peer_ids: Option<HashSet<Id>>,
+    /// Always refused, even if they are allowed or trusted
+    pub denied_peer_ids: HashSet<Id>,
+}
+
+impl<Id> Default for PeerIdRules<Id> {
+    fn default() -> Self {
+        PeerIdRules {
+            allowed_peer_ids: None,
+            denied_peer_ids: HashSet::new(),
+        }
+    }
+}
+
+impl<Id: PeerId> PeerIdRules<Id> {
+    pub fn is_accepted(&self, id: &Id) -> bool {
+        !self.denied_peer_ids.contains(id)
+            && self
+                .allowed_peer_ids
+                .as_ref()
+                .map_or(true, |allowed_peer_ids| allowed_peer_ids.contains(id))
+    }
+}
+
+/// The part of `TrustedPeers` that can be loaded from a configuration file with the
+/// `PeerNetSettings`: the ids of the peers can't be written in it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrustedPeersSettings {
+    /// Trusted before and after the handshake, see `TrustedPeer::Ip`
+    pub ips: Vec<IpAddr>,
+    /// See `TrustedPeers::evict_untrusted`
+    pub evict_untrusted: bool,
+}
+
+impl<Id: PeerId> TrustedPeers<Id> {
+    /// The trusted IPs, the trusted ids are left out
+    pub fn settings(&self) -> TrustedPeersSettings {
+        TrustedPeersSettings {
+            ips: self
+                .peers
+                .iter()
+                .filter_map(|peer| match peer {
+                    TrustedPeer::Ip(ip) => Some(*ip),
+                    TrustedPeer::Id(_) => None,
+                })
+                .collect(),
+            evict_untrusted: self.evict_untrusted,
+        }
+    }
+
+    /// Replace the trusted IPs and `evict_untrusted` by `settings`, the trusted ids are kept
+    pub fn set_settings(&mut self, settings: TrustedPeersSettings) {
+        self.peers.retain(|peer| matches!(peer, TrustedPeer::Id(_)));
+        self.peers
+            .extend(settings.ips.into_iter().map(TrustedPeer::Ip));
+        self.evict_untrusted = settings.evict_untrusted;
+    }
+
+    /// Whether the peer at `addr` is trusted, `id` is `None` before the handshake
+    pub fn is_trusted(&self, id: Option<&Id>, addr: &SocketAddr) -> bool {
+        let ip = to_canonical(addr.ip());
+        self.peers.iter().any(|peer| match peer {
+            TrustedPeer::Id(trusted_id) => Some(trusted_id) == id,
+            TrustedPeer::Ip(trusted_ip) => to_canonical(*trusted_ip) == ip,
+        })
+    }
+}
+
+/// Settings of the TCP transport, the durations are in milliseconds when serialized
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcpSettings {
+    /// Maximum size of a message that we can read. No limit if `None`
+    pub max_message_size: Option<usize>,
+    /// Number of bytes we can read/write in `rate_time_window`
+    pub rate_limit: u64,
+    /// Window of time we wait between each read/write
+    #[serde(with = "duration_millis")]
+    pub rate_time_window: Duration,
+    /// Maximum tokens store in the Limiter. Refer to the stream_limiter crate documentation.
+    pub rate_bucket_size: u64,
+    /// Timeout for write
+    #[serde(with = "duration_millis")]
+    pub write_timeout: Duration,
+    /// Timeout for read
+    #[serde(with = "duration_millis")]
+    pub read_timeout: Duration,
+    /// Maximum time a listener waits for new connections before checking whether it's stopped,
+    /// so that `stop_listener` returns even if the wake-up of the listener is lost
+    #[serde(with = "duration_millis")]
+    pub listener_poll_interval: Duration,
+    /// Probe the idle connections, so that the NATs and firewalls on the way keep their state
+    /// and a dead peer is detected without waiting for a write to time out. Disabled if `None`
+    pub keepalive: Option<TcpKeepalive>,
+    /// `SO_LINGER` of the connections: closing a socket waits at most this long for its
+    /// queued data to be sent, e.g. a goodbye message written just before `Endpoint::shutdown`,
+    /// and resets the connection after it. The system default (no wait) if `None`
+    #[serde(default, with = "optional_duration_millis")]
+    pub linger: Option<Duration>,
+}
+
+/// TCP keepalive of the connections, see `TcpSettings::keepalive`. The durations are in
+/// milliseconds when serialized
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TcpKeepalive {
+    /// Idle time of a connection before its first probe
+    #[serde(with = "duration_millis")]
+    pub idle: Duration,
+    /// Time between two probes without answer. Only on the platforms where it can be set
+    /// (Linux, Android, the BSDs, macOS/iOS, Windows), the system default is used elsewhere
+    #[serde(with = "duration_millis")]
+    pub interval: Duration,
+    /// Number of probes without answer before the connection is closed. Only on the platforms
+    /// where it can be set (Linux, Android, the BSDs, macOS/iOS), not on Windows
+    pub count: u32,
+}
+
+impl Default for TcpSettings {
+    fn default() -> Self {
+        TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: RATE_LIMIT,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: RATE_LIMIT.saturating_mul(3),
+            write_timeout: Duration::from_secs(7),
+            read_timeout: Duration::from_secs(7),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        }
+    }
+}
+
+/// Settings of the QUIC transport
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QuicSettings {
+    /// Address of the UDP socket used by the out connections if there is a listener on it.
+    /// Otherwise they go through one of the QUIC listeners of the same IP version, so that the
+    /// peers see the address we listen on. Without any, the first connection starts a socket on
+    /// an ephemeral port of the unspecified address of its IP version, shared by the next ones
+    /// and not announced to the peers.
+    pub local_addr: SocketAddr,
+    /// Certificate presented by the listeners to the peers whose server name (SNI) has no
+    /// route in `sni_routes`
+    pub certificate: QuicCertificate,
+    /// Server name (SNI) sent in the TLS handshake of the out connections, none if `None`
+    pub server_name: Option<String>,
+    /// Certificate and category of the in connections by the server name they request, so
+    /// that a listener can serve different populations of peers (public, partners...) with
+    /// their own policies
+    pub sni_routes: Vec<SniRoute>,
+}
+
+impl Default for QuicSettings {
+    fn default() -> Self {
+        QuicSettings {
+            local_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+            certificate: QuicCertificate::default(),
+            server_name: None,
+            sni_routes: Vec::new(),
+        }
+    }
+}
+
+/// PEM files of a certificate chain and of its private key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuicCertificate {
+    pub cert_chain_path: String,
+    pub priv_key_path: String,
+}
+
+impl Default for QuicCertificate {
+    fn default() -> Self {
+        QuicCertificate {
+            cert_chain_path: "./src/cert.crt".to_string(),
+            priv_key_path: "./src/cert.key".to_string(),
+        }
+    }
+}
+
+/// In connections of a QUIC listener requesting the server name `server_name`, see
+/// `QuicSettings::sni_routes`. The server name is read in the first packet of the connection,
+/// a client whose handshake doesn't fit in it gets the default certificate and category.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SniRoute {
+    /// Compared without case
+    pub server_name: String,
+    /// Presented in place of `QuicSettings::certificate` if it's set
+    pub certificate: Option<QuicCertificate>,
+    /// Category of `PeerNetConfiguration::peers_categories` of the connections, in place of
+    /// the one of their IP
+    pub category: Option<String>,
+}
+
+/// New settings of a transport, see `PeerNetManager::replace_transport_config`
+#[derive(Clone, Debug)]
+pub enum TransportSettings {
+    Tcp(TcpSettings),
+    Quic(QuicSettings),
+}
+
+/// Written in place of the secrets (private keys...) by the `Debug` implementations
+pub const REDACTED: &str = "<redacted>";
+
 /// Struct containing the configuration for the PeerNet manager.
 pub struct PeerNetConfiguration<
     Id: PeerId,
@@ -41,27 +358,26 @@ pub struct PeerNetConfiguration<
     pub optional_features: PeerNetFeatures,
     /// Structure for message handler
     pub message_handler: M,
-    /// Maximum number of in connections if we have more we just don't accept the connection
-    pub max_in_connections: usize,
-    /// Maximum size of a message that we can read
-    pub max_message_size: usize,
+    /// Maximum number of in connections if we have more we just don't accept the connection.
+    /// No limit if `None`
+    pub max_in_connections: Option<usize>,
     /// Size of send data channel
     pub send_data_channel_size: usize,
-    /// Number of bytes we can read/write in `rate_time_window`
-    pub rate_limit: u64,
-    /// Window of time we wait between each read/write
-    pub rate_time_window: Duration,
-    /// Maximum tokens store in the Limiter. Refer to the stream_limiter crate documentation.
-    pub rate_bucket_size: u64,
     /// List of categories of peers
     pub peers_categories: PeerNetCategories,
     /// Default category info for all peers not in a specific category (category info, number of connections accepted only for handshake //TODO: Remove when refactored on massa side)
     pub default_category_info: PeerNetCategoryInfo,
+    /// Settings of the TCP transport
+    pub tcp: TcpSettings,
+    /// Settings of the QUIC transport
+    pub quic: QuicSettings,
+    /// Peers accepted beyond the limits from the start, they can be replaced with
+    /// `PeerNetManager::set_trusted_peers`
+    pub trusted_peers: TrustedPeers<Id>,
+    /// Peers accepted or refused by their id from the start, they can be replaced with
+    /// `PeerNetManager::set_peer_id_rules`
+    pub peer_id_rules: PeerIdRules<Id>,
     pub _phantom: std::marker::PhantomData<Id>,
-    /// Timeout for write
-    pub write_timeout: Duration,
-    /// Timeout for read
-    pub read_timeout: Duration,
 }
 
 impl<
@@ -71,30 +387,983 @@ impl<
         M: MessagesHandler<Id>,
     > PeerNetConfiguration<Id, Ctx, I, M>
 {
+    /// Check that the values of the configuration make sense together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tcp.rate_bucket_size < MIN_OPERATION_SIZE {
+            return Err(ConfigError::RateBucketTooSmall {
+                rate_bucket_size: self.tcp.rate_bucket_size,
+            });
+        }
+        if self.tcp.max_message_size == Some(0) {
+            return Err(ConfigError::ZeroMaxMessageSize);
+        }
+        if self.send_data_channel_size == 0 {
+            return Err(ConfigError::ZeroSendDataChannelSize);
+        }
+        if let Some(small_message_size) = self.optional_features.small_message_size {
+            if small_message_size > MAX_SMALL_MESSAGE_SIZE {
+                return Err(ConfigError::SmallMessageSizeTooBig(small_message_size));
+            }
+        }
+        for (name, duration) in [
+            ("tcp.rate_time_window", Some(self.tcp.rate_time_window)),
+            ("tcp.read_timeout", Some(self.tcp.read_timeout)),
+            ("tcp.write_timeout", Some(self.tcp.write_timeout)),
+            (
+                "tcp.listener_poll_interval",
+                Some(self.tcp.listener_poll_interval),
+            ),
+            (
+                "tcp.keepalive.idle",
+                self.tcp.keepalive.map(|keepalive| keepalive.idle),
+            ),
+            (
+                "tcp.keepalive.interval",
+                self.tcp.keepalive.map(|keepalive| keepalive.interval),
+            ),
+            (
+                "optional_features.handshake_timeout",
+                self.optional_features.handshake_timeout,
+            ),
+        ] {
+            if duration.map_or(false, |duration| duration.is_zero()) {
+                return Err(ConfigError::ZeroDuration(name));
+            }
+        }
+        let categories = self
+            .peers_categories
+            .iter()
+            .map(|(name, (_, info))| (Some(name), info))
+            .chain(std::iter::once((None, &self.default_category_info)));
+        for (category, info) in categories {
+            if let (Some(max_in_connections_per_ip), Some(max_in_connections)) =
+                (info.max_in_connections_per_ip, info.max_in_connections)
+            {
+                if max_in_connections_per_ip > max_in_connections {
+                    return Err(ConfigError::PerIpLimitAboveCategoryLimit {
+                        category: category.cloned(),
+                        max_in_connections_per_ip,
+                        max_in_connections,
+                    });
+                }
+            }
+            for (name, duration) in [
+                ("category read_timeout", info.read_timeout),
+                ("category write_timeout", info.write_timeout),
+            ] {
+                if duration.map_or(false, |duration| duration.is_zero()) {
+                    return Err(ConfigError::ZeroDuration(name));
+                }
+            }
+        }
+        if let Some(dial_pacing) = self.optional_features.dial_pacing {
+            if dial_pacing.max_dials_per_sec == 0 {
+                return Err(ConfigError::ZeroDialRate);
+            }
+        }
+        for route in &self.quic.sni_routes {
+            if let Some(category) = &route.category {
+                if !self.peers_categories.contains_key(category) {
+                    return Err(ConfigError::UnknownSniCategory {
+                        server_name: route.server_name.clone(),
+                        category: category.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The data part of the configuration
+    pub fn settings(&self) -> PeerNetSettings {
+        PeerNetSettings {
+            max_in_connections: self.max_in_connections,
+            send_data_channel_size: self.send_data_channel_size,
+            peers_categories: self.peers_categories.clone(),
+            default_category_info: self.default_category_info,
+            tcp: self.tcp.clone(),
+            quic: self.quic.clone(),
+            trusted_peers: self.trusted_peers.settings(),
+        }
+    }
+
+    /// Summary of the configuration that can be logged or shown to the operators: the context
+    /// and the handlers, that may hold keys, are left out
+    pub fn describe(&self) -> String {
+        let mut categories: Vec<&str> = self.peers_categories.keys().map(String::as_str).collect();
+        categories.sort();
+        format!(
+            "max_in_connections: {:?}, send_data_channel_size: {}, categories: {:?}, \
+             default_category_info: {:?}, tcp: {:?}, quic: {:?}, trusted_peers: {:?}, \
+             peer_id_rules: {:?}, features: [{}]",
+            self.max_in_connections,
+            self.send_data_channel_size,
+            categories,
+            self.default_category_info,
+            self.tcp,
+            self.quic,
+            self.trusted_peers,
+            self.peer_id_rules,
+            self.optional_features.describe(),
+        )
+    }
+
     pub fn default(init_connection_handler: I, message_handler: M, context: Ctx) -> Self {
         PeerNetConfiguration {
             context,
-            max_in_connections: 10,
+            max_in_connections: Some(10),
             init_connection_handler,
             optional_features: PeerNetFeatures::default(),
             message_handler,
             peers_categories: HashMap::new(),
-            max_message_size: 1048576000,
             send_data_channel_size: 10000,
             default_category_info: PeerNetCategoryInfo {
-                max_in_connections: 0,
-                max_in_connections_per_ip: 0,
-                max_out_connections: 0,
+                max_in_connections: Some(0),
+                max_in_connections_per_ip: Some(0),
+                max_out_connections: Some(0),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
             },
-            rate_time_window: Duration::from_secs(1),
-            rate_bucket_size: RATE_LIMIT.saturating_mul(3),
-            rate_limit: RATE_LIMIT,
+            tcp: TcpSettings::default(),
+            quic: QuicSettings::default(),
+            trusted_peers: TrustedPeers::default(),
+            peer_id_rules: PeerIdRules::default(),
             _phantom: std::marker::PhantomData,
-            write_timeout: Duration::from_secs(7),
-            read_timeout: Duration::from_secs(7),
         }
     }
 }
 
+/// The context and the handlers are redacted, see `PeerNetConfiguration::describe`
+impl<
+        Id: PeerId,
+        Ctx: Context<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+        M: MessagesHandler<Id>,
+    > std::fmt::Debug for PeerNetConfiguration<Id, Ctx, I, M>
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerNetConfiguration")
+            .field("context", &REDACTED)
+            .field("init_connection_handler", &REDACTED)
+            .field("optional_features", &self.optional_features.describe())
+            .field("message_handler", &REDACTED)
+            .field("max_in_connections", &self.max_in_connections)
+            .field("send_data_channel_size", &self.send_data_channel_size)
+            .field("peers_categories", &self.peers_categories)
+            .field("default_category_info", &self.default_category_info)
+            .field("tcp", &self.tcp)
+            .field("quic", &self.quic)
+            .field("trusted_peers", &self.trusted_peers)
+            .field("peer_id_rules", &self.peer_id_rules)
+            .finish()
+    }
+}
+
+/// The data part of a `PeerNetConfiguration`, without the handlers and the context, that can be
+/// loaded from a configuration file. The missing fields take their default value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerNetSettings {
+    /// Maximum number of in connections if we have more we just don't accept the connection.
+    /// No limit if `None`
+    pub max_in_connections: Option<usize>,
+    /// Size of send data channel
+    pub send_data_channel_size: usize,
+    /// List of categories of peers
+    pub peers_categories: PeerNetCategories,
+    /// Category info for all peers not in a specific category
+    pub default_category_info: PeerNetCategoryInfo,
+    /// Settings of the TCP transport
+    pub tcp: TcpSettings,
+    /// Settings of the QUIC transport
+    pub quic: QuicSettings,
+    /// IPs of the peers accepted beyond the limits
+    pub trusted_peers: TrustedPeersSettings,
+}
+
+impl Default for PeerNetSettings {
+    fn default() -> Self {
+        PeerNetSettings {
+            max_in_connections: Some(10),
+            send_data_channel_size: 10000,
+            peers_categories: HashMap::new(),
+            // the peers in no category can connect, one connection per IP
+            default_category_info: PeerNetCategoryInfo {
+                max_in_connections: Some(10),
+                max_in_connections_per_ip: Some(1),
+                max_out_connections: Some(10),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
+            },
+            tcp: TcpSettings::default(),
+            quic: QuicSettings::default(),
+            trusted_peers: TrustedPeersSettings::default(),
+        }
+    }
+}
+
+mod duration_millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+mod optional_duration_millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&(duration.as_millis() as u64)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|millis| millis.map(Duration::from_millis))
+    }
+}
+
+/// Builder of a `PeerNetConfiguration`, the fields that are not set keep their default value
+pub struct PeerNetConfigurationBuilder<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+> {
+    config: PeerNetConfiguration<Id, Ctx, I, M>,
+}
+
+impl<
+        Id: PeerId,
+        Ctx: Context<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+        M: MessagesHandler<Id>,
+    > PeerNetConfigurationBuilder<Id, Ctx, I, M>
+{
+    pub fn new(context: Ctx, init_connection_handler: I, message_handler: M) -> Self {
+        PeerNetConfigurationBuilder {
+            config: PeerNetConfiguration::default(
+                init_connection_handler,
+                message_handler,
+                context,
+            ),
+        }
+        .set_settings(PeerNetSettings::default())
+    }
+
+    /// Set all the data part of the configuration, e.g. loaded from a file. The trusted peers
+    /// set by their id are kept
+    pub fn set_settings(mut self, settings: PeerNetSettings) -> Self {
+        self.config.max_in_connections = settings.max_in_connections;
+        self.config.send_data_channel_size = settings.send_data_channel_size;
+        self.config.peers_categories = settings.peers_categories;
+        self.config.default_category_info = settings.default_category_info;
+        self.config.tcp = settings.tcp;
+        self.config.quic = settings.quic;
+        self.config
+            .trusted_peers
+            .set_settings(settings.trusted_peers);
+        self
+    }
+
+    pub fn set_optional_features(mut self, optional_features: PeerNetFeatures) -> Self {
+        self.config.optional_features = optional_features;
+        self
+    }
+
+    pub fn set_max_in_connections(mut self, max_in_connections: Option<usize>) -> Self {
+        self.config.max_in_connections = max_in_connections;
+        self
+    }
+
+    pub fn set_send_data_channel_size(mut self, send_data_channel_size: usize) -> Self {
+        self.config.send_data_channel_size = send_data_channel_size;
+        self
+    }
+
+    pub fn set_peers_categories(mut self, peers_categories: PeerNetCategories) -> Self {
+        self.config.peers_categories = peers_categories;
+        self
+    }
+
+    pub fn add_peers_category(
+        mut self,
+        name: String,
+        ips: Vec<IpAddr>,
+        category_info: PeerNetCategoryInfo,
+    ) -> Self {
+        self.config
+            .peers_categories
+            .insert(name, (ips, category_info));
+        self
+    }
+
+    pub fn set_default_category_info(mut self, default_category_info: PeerNetCategoryInfo) -> Self {
+        self.config.default_category_info = default_category_info;
+        self
+    }
+
+    pub fn set_tcp_settings(mut self, tcp: TcpSettings) -> Self {
+        self.config.tcp = tcp;
+        self
+    }
+
+    pub fn set_quic_settings(mut self, quic: QuicSettings) -> Self {
+        self.config.quic = quic;
+        self
+    }
+
+    pub fn set_trusted_peers(mut self, trusted_peers: TrustedPeers<Id>) -> Self {
+        self.config.trusted_peers = trusted_peers;
+        self
+    }
+
+    pub fn set_peer_id_rules(mut self, peer_id_rules: PeerIdRules<Id>) -> Self {
+        self.config.peer_id_rules = peer_id_rules;
+        self
+    }
+
+    pub fn build(self) -> PeerNetResult<PeerNetConfiguration<Id, Ctx, I, M>> {
+        self.config.validate().map_err(|err| {
+            PeerNetError::ConfigError(err.clone()).new("build configuration", err, None)
+        })?;
+        Ok(self.config)
+    }
+}
+
+/// Budget used by the write thread of a peer to batch several small queued messages
+/// in a single write on the endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct MessageCoalescing {
+    /// A batch is closed as soon as the total size of its messages reaches this value
+    pub max_batch_size: usize,
+    /// Maximum time the write thread waits for new messages to add to a batch
+    pub max_delay: Duration,
+}
+
+/// Size classes of the buffers reused for messages, see `buffer_pool`
+#[derive(Clone, Debug)]
+pub struct BufferPoolConfig {
+    /// Capacities of the buffers kept in the pool. An empty list disables the pool
+    pub size_classes: Vec<usize>,
+    /// Maximum number of idle buffers kept for each size class
+    pub max_buffers_per_class: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> Self {
+        BufferPoolConfig {
+            size_classes: vec![512, 4 * 1024, 64 * 1024, 1024 * 1024],
+            max_buffers_per_class: 128,
+        }
+    }
+}
+
+/// Pool of threads running the handshakes of the incoming connections, see `handshake_pool`
+#[derive(Clone, Copy, Debug)]
+pub struct HandshakeWorkers {
+    /// Number of handshakes running at the same time
+    pub nb_workers: usize,
+    /// Number of accepted connections that can wait for a worker. A connection accepted when
+    /// it's full fails its handshake.
+    pub queue_size: usize,
+}
+
+/// Pool of threads running the messages handler instead of the read loop of each peer
+#[derive(Clone, Copy, Debug)]
+pub struct HandlerWorkers {
+    /// Number of threads calling the messages handler
+    pub nb_workers: usize,
+    /// Number of received messages that can wait for a worker, the read loops block when it's full
+    pub queue_size: usize,
+    /// Bytes of the messages of a peer received and not handled yet. Its read loop blocks when
+    /// it's reached, which stops reading the socket. With `PeerNetFeatures::tcp_reactor`, the
+    /// event loop stops polling the socket of the peer instead, its other peers aren't blocked.
+    /// No limit if `None`
+    pub max_in_flight_bytes_per_peer: Option<usize>,
+    /// Bytes of the messages of all the peers received and not handled yet. No limit if `None`
+    pub max_in_flight_bytes: Option<usize>,
+    /// How long a read loop (or a connection of an event loop) waits for room in the queue and
+    /// in the limits of the bytes in flight. Past it, the message is refused with
+    /// `PeerNetError::Backpressure` and the peer is disconnected. Waits as long as needed if `None`
+    pub dispatch_timeout: Option<Duration>,
+}
+
+/// Send the queued messages of a peer before closing its connection, see
+/// `PeerNetFeatures::disconnect_flush`
+#[derive(Clone, Copy, Debug)]
+pub struct DisconnectFlush {
+    /// Longest time spent sending them, the ones left are dropped
+    pub max_duration: Duration,
+    /// Most bytes of messages sent, the ones left are dropped
+    pub max_bytes: usize,
+}
+
+/// Caps of the memory held by the messages of the peers, see `memory`
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryBudget {
+    /// Bytes held for a single peer. No limit if `None`
+    pub max_per_peer: Option<usize>,
+    /// Bytes held for all the peers. No limit if `None`
+    pub max_total: Option<usize>,
+    /// What is done when a cap is exceeded
+    pub action: MemoryAction,
+}
+
+/// See `MemoryBudget::action`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAction {
+    /// The low priority messages that don't fit are refused with
+    /// `PeerNetError::MemoryBudgetExceeded`
+    DropLowPriority,
+    /// The peer holding the most memory is disconnected with `DisconnectReason::MemoryBudget`,
+    /// the peer itself for `max_per_peer`
+    DisconnectHeaviest,
+}
+
+/// Pace of the TCP and QUIC dials, see `dial_pacing`
+#[derive(Clone, Copy, Debug)]
+pub struct DialPacing {
+    /// Dials started per second at most, once the burst is spent
+    pub max_dials_per_sec: u32,
+    /// Dials started at once before the pace applies
+    pub burst: u32,
+}
+
+/// Puzzle that the peers connecting to us must solve before the handshake, see `proof_of_work`.
+/// Both sides of a connection must enable it.
+#[derive(Clone, Debug)]
+pub struct ProofOfWork {
+    /// Number of leading zero bits required in the hash of the solution
+    pub difficulty: u8,
+    /// Peers of these categories get a challenge of difficulty 0
+    pub trusted_categories: Vec<String>,
+    /// Highest difficulty we accept to solve when connecting to a peer
+    pub max_difficulty: u8,
+}
+
+/// Ask the peers the address they see for us after the handshake, see
+/// `PeerNetManager::external_addresses`. Both sides of a connection must enable it.
+#[derive(Clone, Copy, Debug)]
+pub struct ObservedAddresses {
+    /// Number of connected peers that must report an IP for it to be confirmed, the peers
+    /// connected from the same /24 (IPv4) or /48 (IPv6) counting as one
+    pub min_confirmations: usize,
+}
+
+/// Key/value pairs defined by the application (version of the client, id of the chain...)
+/// exchanged with the peers after the handshake, see `PeerConnection::metadata`. Both sides of
+/// a connection must enable it.
+#[derive(Clone, Debug, Default)]
+pub struct PeerMetadata {
+    /// Sent to the peers, each key and value must fit in 65535 bytes
+    pub values: BTreeMap<String, String>,
+    /// Maximum size of the metadata received from a peer, the handshake fails beyond it
+    pub max_size: usize,
+}
+
+/// Event loops driving the TCP connections once their handshake is done, see `reactor`.
+/// The handshakes still run in a thread of their own, or on the `handshake_workers`.
+#[derive(Clone, Copy, Debug)]
+pub struct TcpReactor {
+    /// Number of threads polling the connections, each one drives a share of the peers
+    pub nb_event_loops: usize,
+}
+
+/// Options of the threads spawned for each peer (read and write), listener and outgoing
+/// connection. Defaults to the ones of `std::thread::Builder`.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadsConfig {
+    /// Stack size of the threads in bytes, thousands of peers can otherwise hit the virtual
+    /// memory limit of the process
+    pub stack_size: Option<usize>,
+    /// Added before the name of the threads, to tell apart the threads of several managers
+    pub name_prefix: Option<String>,
+}
+
+impl ThreadsConfig {
+    pub(crate) fn builder(&self, name: String) -> std::thread::Builder {
+        let name = match &self.name_prefix {
+            Some(prefix) => format!("{prefix}{name}"),
+            None => name,
+        };
+        let builder = std::thread::Builder::new().name(name);
+        match self.stack_size {
+            Some(stack_size) => builder.stack_size(stack_size),
+            None => builder,
+        }
+    }
+}
+
+/// Forward the port of the listeners on the gateway of the local network, see `port_mapping`
+#[derive(Clone, Debug)]
+pub struct PortMapping {
+    /// Duration of the mappings asked to the gateway, they are renewed at half of it
+    pub lease_duration: Duration,
+    /// Address of the NAT-PMP server of the gateway (port `NAT_PMP_PORT`).
+    /// NAT-PMP is not tried if `None`
+    pub nat_pmp_gateway: Option<SocketAddr>,
+    /// Time given to the gateway to answer
+    pub timeout: Duration,
+    /// Receives the external addresses of the listeners
+    pub events: Sender<PortMappingEvent>,
+}
+
+/// Notable events of the manager, given to the `DiagnosticsSink` of `PeerNetFeatures`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticEvent {
+    /// The handshake with the peer at `addr` failed
+    HandshakeFailed {
+        addr: SocketAddr,
+        direction: PeerConnectionType,
+        reason: String,
+    },
+    /// A message for the peer at `addr` was refused by `try_send` because its channel is full
+    SendQueueFull {
+        addr: SocketAddr,
+        high_priority: bool,
+    },
+    /// The rate limit holds the reads or the writes of the peer at `addr` until `until`.
+    /// Only reported by the event loops of `tcp_reactor`
+    Throttled {
+        addr: SocketAddr,
+        write: bool,
+        until: Instant,
+    },
+    /// The listener on `addr` couldn't accept a connection, or failed to poll or read its
+    /// socket. It keeps running.
+    ListenerError { addr: SocketAddr, reason: String },
+    /// The connection with the peer at `addr` was accepted after its handshake
+    Connected {
+        addr: SocketAddr,
+        direction: PeerConnectionType,
+        /// From the start of the dial, or the accept of the listener, to the start of the
+        /// handshake: the TCP connection of the out connections. 0 for the QUIC connections
+        connect: Duration,
+        /// Proof of work, handshake and negotiations after it
+        handshake: Duration,
+        /// From the start of the dial, or the accept, to `ActiveConnections::confirm_connection`
+        total: Duration,
+    },
+    /// We dialed ourselves at `addr`. It's detected by `try_connect` from the addresses of our
+    /// listeners, or after the handshake from the id of the peer: `try_connect` then refuses
+    /// the address.
+    SelfConnection {
+        addr: SocketAddr,
+        direction: PeerConnectionType,
+    },
+}
+
+/// Receives the `DiagnosticEvent`s, e.g. to route them to the logging or alerting of the
+/// application. It's called from the threads of the manager and shouldn't block them.
+pub trait DiagnosticsSink: Send + Sync {
+    fn report(&self, event: DiagnosticEvent);
+}
+
+pub type SharedDiagnosticsSink = Arc<dyn DiagnosticsSink>;
+
+/// Report the event built by `event` to `diagnostics`, it's only built if there is a sink
+pub(crate) fn report(
+    diagnostics: &Option<SharedDiagnosticsSink>,
+    event: impl FnOnce() -> DiagnosticEvent,
+) {
+    if let Some(diagnostics) = diagnostics {
+        diagnostics.report(event());
+    }
+}
+
+/// Called with the errors of the background threads, see `PeerNetFeatures::on_error`
+pub type ErrorCallback = Arc<dyn Fn(&PeerNetErrorData) + Send + Sync>;
+
 #[derive(Clone, Default)]
-pub struct PeerNetFeatures {}
+pub struct PeerNetFeatures {
+    /// Batch small outgoing messages in a single write. Disabled if `None`
+    pub message_coalescing: Option<MessageCoalescing>,
+    /// Buffers reused for the serialization and the reading of messages
+    pub buffer_pool: BufferPoolConfig,
+    /// Handle the received messages in a pool of threads. Handled in the read loop if `None`
+    pub handler_workers: Option<HandlerWorkers>,
+    /// Names of the sub-protocols we support, exchanged with the peer after the handshake
+    /// to agree on the ones both sides can use. No negotiation if `None`, the other peer
+    /// must then not negotiate either.
+    pub protocols: Option<Vec<String>>,
+    /// Ask the peers connecting to us to solve a puzzle before the handshake. Disabled if `None`
+    pub proof_of_work: Option<ProofOfWork>,
+    /// Maximum number of incoming TCP connections doing their handshake at the same time.
+    /// The connections accepted beyond it are refused with the fallback function. No limit if `None`
+    pub max_concurrent_handshakes: Option<usize>,
+    /// Map the port of each listener started on the gateway. Disabled if `None`
+    pub port_mapping: Option<PortMapping>,
+    /// Exchange with the peers the addresses we see for each other. Disabled if `None`
+    pub observed_addresses: Option<ObservedAddresses>,
+    /// Exchange metadata with the peers after the handshake. Disabled if `None`
+    pub metadata: Option<PeerMetadata>,
+    /// Drive the TCP connections from a pool of event loops instead of a read and a write
+    /// thread per peer. The blocking sends of the handlers called by a loop don't wait for room
+    /// in the channels, see `SendChannels::send`. Disabled if `None`
+    pub tcp_reactor: Option<TcpReactor>,
+    /// Stack size and names of the peer, listener and connection threads
+    pub threads: ThreadsConfig,
+    /// The TCP messages up to this size are framed on the stack instead of a buffer of the pool,
+    /// they are still counted by the rate limiter. Not used by the event loops
+    /// of `tcp_reactor`, nor with `message_coalescing`. Disabled if `None`
+    pub small_message_size: Option<usize>,
+    /// Receives the notable events of the connections and listeners. Only logged if `None`
+    pub diagnostics: Option<SharedDiagnosticsSink>,
+    /// Number of lifecycle events of the connections kept for `PeerNetManager::recent_events`.
+    /// Nothing is kept if `None`
+    pub connection_history: Option<usize>,
+    /// Receives the errors of the listener and outgoing connection threads, their panics
+    /// included, that would otherwise only be in their `JoinHandle`. Only logged if `None`
+    pub on_error: Option<ErrorCallback>,
+    /// The addresses staying longer in the connection queues, because the thread of their
+    /// handshake died, are evicted so that they don't hold a place forever. Never evicted if
+    /// `None`
+    pub handshake_timeout: Option<Duration>,
+    /// Evict an incoming connection chosen by the policy when a TCP listener reaches
+    /// `max_in_connections`, instead of refusing the newcomer. The eviction happens once the
+    /// newcomer passed its handshake, see `eviction`. Disabled if `None`
+    pub eviction_policy: Option<SharedEvictionPolicy>,
+    /// When we close a connection (`DisconnectReason::is_orderly`), its write thread sends the
+    /// messages still queued before shutting it down. Not done for the connections of
+    /// `tcp_reactor`. The queued messages are dropped if `None`
+    pub disconnect_flush: Option<DisconnectFlush>,
+    /// Measure the time spent on each peer, see `timings`
+    pub peer_timings: bool,
+    /// Read and write the TCP connections through an io_uring ring shared by all of them, if the
+    /// crate is built with the `io_uring` feature on Linux and the kernel allows it, see
+    /// `TcpIo`. The blocking calls are used otherwise. Not used by the event loops of
+    /// `tcp_reactor` after the handshake
+    pub io_uring: bool,
+    /// Maximum bytes of messages received from a TCP peer, and sent to it, before the end of its
+    /// handshake (proof of work included), e.g. 64 KiB. It bounds what the unauthenticated
+    /// peers can make us read, allocate and send; the handshake fails beyond it. No limit if
+    /// `None`
+    pub handshake_data_limit: Option<usize>,
+    /// Run the handshakes of the incoming connections on a pool of threads, the connections only
+    /// get their own threads once their handshake succeeded. Disabled if `None`, each accepted
+    /// connection then gets a thread for its handshake.
+    pub handshake_workers: Option<HandshakeWorkers>,
+    /// Time of the handshake timeout, of the read and write timeouts of the TCP connections, of
+    /// the age of the connections and of the `ConnectionSupervisor`, see `clock`. The system
+    /// clock if `None`
+    pub clock: Option<SharedClock>,
+    /// Receives a record of each dial and accepted connection, and of its outcome, see
+    /// `audit`. Disabled if `None`
+    pub audit: Option<SharedAuditSink>,
+    /// Count the memory held by the messages of each peer and enforce the caps of the budget,
+    /// see `memory`. Not counted if `None`
+    pub memory_budget: Option<MemoryBudget>,
+    /// Delay the dials beyond the pace, e.g. not to send a burst of connection attempts when
+    /// the bootstrap list is long. The relayed dials are not paced. Not paced if `None`
+    pub dial_pacing: Option<DialPacing>,
+}
+
+impl PeerNetFeatures {
+    pub fn set_message_coalescing(mut self, message_coalescing: MessageCoalescing) -> Self {
+        self.message_coalescing = Some(message_coalescing);
+        self
+    }
+
+    pub fn set_buffer_pool(mut self, buffer_pool: BufferPoolConfig) -> Self {
+        self.buffer_pool = buffer_pool;
+        self
+    }
+
+    pub fn set_handler_workers(mut self, handler_workers: HandlerWorkers) -> Self {
+        self.handler_workers = Some(handler_workers);
+        self
+    }
+
+    pub fn set_protocols(mut self, protocols: Vec<String>) -> Self {
+        self.protocols = Some(protocols);
+        self
+    }
+
+    pub fn set_proof_of_work(mut self, proof_of_work: ProofOfWork) -> Self {
+        self.proof_of_work = Some(proof_of_work);
+        self
+    }
+
+    pub fn set_max_concurrent_handshakes(mut self, max_concurrent_handshakes: usize) -> Self {
+        self.max_concurrent_handshakes = Some(max_concurrent_handshakes);
+        self
+    }
+
+    pub fn set_port_mapping(mut self, port_mapping: PortMapping) -> Self {
+        self.port_mapping = Some(port_mapping);
+        self
+    }
+
+    pub fn set_observed_addresses(mut self, observed_addresses: ObservedAddresses) -> Self {
+        self.observed_addresses = Some(observed_addresses);
+        self
+    }
+
+    pub fn set_metadata(mut self, metadata: PeerMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn set_tcp_reactor(mut self, tcp_reactor: TcpReactor) -> Self {
+        self.tcp_reactor = Some(tcp_reactor);
+        self
+    }
+
+    pub fn set_threads(mut self, threads: ThreadsConfig) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn set_small_message_size(mut self, small_message_size: usize) -> Self {
+        self.small_message_size = Some(small_message_size);
+        self
+    }
+
+    pub fn set_diagnostics(mut self, diagnostics: SharedDiagnosticsSink) -> Self {
+        self.diagnostics = Some(diagnostics);
+        self
+    }
+
+    pub fn set_connection_history(mut self, connection_history: usize) -> Self {
+        self.connection_history = Some(connection_history);
+        self
+    }
+
+    pub fn set_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = Some(handshake_timeout);
+        self
+    }
+
+    pub fn set_eviction_policy(mut self, eviction_policy: SharedEvictionPolicy) -> Self {
+        self.eviction_policy = Some(eviction_policy);
+        self
+    }
+
+    pub fn set_disconnect_flush(mut self, disconnect_flush: DisconnectFlush) -> Self {
+        self.disconnect_flush = Some(disconnect_flush);
+        self
+    }
+
+    pub fn set_peer_timings(mut self, peer_timings: bool) -> Self {
+        self.peer_timings = peer_timings;
+        self
+    }
+
+    pub fn set_io_uring(mut self, io_uring: bool) -> Self {
+        self.io_uring = io_uring;
+        self
+    }
+
+    pub fn set_handshake_data_limit(mut self, handshake_data_limit: usize) -> Self {
+        self.handshake_data_limit = Some(handshake_data_limit);
+        self
+    }
+
+    pub fn set_handshake_workers(mut self, handshake_workers: HandshakeWorkers) -> Self {
+        self.handshake_workers = Some(handshake_workers);
+        self
+    }
+
+    pub fn set_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn set_audit(mut self, audit: SharedAuditSink) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    pub fn set_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(memory_budget);
+        self
+    }
+
+    pub fn set_dial_pacing(mut self, dial_pacing: DialPacing) -> Self {
+        self.dial_pacing = Some(dial_pacing);
+        self
+    }
+
+    pub fn set_on_error(
+        mut self,
+        on_error: impl Fn(&PeerNetErrorData) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Some(Arc::new(on_error));
+        self
+    }
+
+    /// The enabled features and their main parameters, separated by commas. The values of
+    /// `metadata` are left out, only its keys are listed.
+    pub fn describe(&self) -> String {
+        let mut features = Vec::new();
+        if let Some(message_coalescing) = &self.message_coalescing {
+            features.push(format!("{:?}", message_coalescing));
+        }
+        if let Some(handler_workers) = &self.handler_workers {
+            features.push(format!("{:?}", handler_workers));
+        }
+        if let Some(protocols) = &self.protocols {
+            features.push(format!("protocols: {:?}", protocols));
+        }
+        if let Some(proof_of_work) = &self.proof_of_work {
+            features.push(format!("{:?}", proof_of_work));
+        }
+        if let Some(max_concurrent_handshakes) = self.max_concurrent_handshakes {
+            features.push(format!(
+                "max_concurrent_handshakes: {}",
+                max_concurrent_handshakes
+            ));
+        }
+        if let Some(port_mapping) = &self.port_mapping {
+            features.push(format!(
+                "PortMapping {{ lease_duration: {:?}, nat_pmp_gateway: {:?} }}",
+                port_mapping.lease_duration, port_mapping.nat_pmp_gateway
+            ));
+        }
+        if let Some(observed_addresses) = &self.observed_addresses {
+            features.push(format!("{:?}", observed_addresses));
+        }
+        if let Some(metadata) = &self.metadata {
+            features.push(format!(
+                "metadata: {{ keys: {:?}, max_size: {} }}",
+                metadata.values.keys().collect::<Vec<_>>(),
+                metadata.max_size
+            ));
+        }
+        if let Some(tcp_reactor) = &self.tcp_reactor {
+            features.push(format!("{:?}", tcp_reactor));
+        }
+        if let Some(small_message_size) = self.small_message_size {
+            features.push(format!("small_message_size: {}", small_message_size));
+        }
+        if self.diagnostics.is_some() {
+            features.push("diagnostics".to_string());
+        }
+        if let Some(connection_history) = self.connection_history {
+            features.push(format!("connection_history: {}", connection_history));
+        }
+        if self.on_error.is_some() {
+            features.push("on_error".to_string());
+        }
+        if let Some(handshake_timeout) = self.handshake_timeout {
+            features.push(format!("handshake_timeout: {:?}", handshake_timeout));
+        }
+        if self.eviction_policy.is_some() {
+            features.push("eviction_policy".to_string());
+        }
+        if let Some(disconnect_flush) = &self.disconnect_flush {
+            features.push(format!("{:?}", disconnect_flush));
+        }
+        if self.peer_timings {
+            features.push("peer_timings".to_string());
+        }
+        if self.io_uring {
+            features.push("io_uring".to_string());
+        }
+        if let Some(handshake_data_limit) = self.handshake_data_limit {
+            features.push(format!("handshake_data_limit: {}", handshake_data_limit));
+        }
+        if let Some(handshake_workers) = &self.handshake_workers {
+            features.push(format!("{:?}", handshake_workers));
+        }
+        if self.clock.is_some() {
+            features.push("clock".to_string());
+        }
+        if self.audit.is_some() {
+            features.push("audit".to_string());
+        }
+        if let Some(memory_budget) = &self.memory_budget {
+            features.push(format!("{:?}", memory_budget));
+        }
+        if let Some(dial_pacing) = &self.dial_pacing {
+            features.push(format!("{:?}", dial_pacing));
+        }
+        features.join(", ")
+    }
+
+    pub(crate) fn clock(&self) -> SharedClock {
+        self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock))
+    }
+
+    /// Builder of a thread whose error is given to `on_error`, see `ReportingBuilder`
+    pub(crate) fn reporting_builder(
+        &self,
+        name: String,
+        location: &'static str,
+    ) -> ReportingBuilder {
+        ReportingBuilder {
+            builder: self.threads.builder(name),
+            location,
+            on_error: self.on_error.clone(),
+        }
+    }
+}
+
+/// Spawns a thread whose panic is turned into a `ThreadPanicked` error, its error is given to
+/// `PeerNetFeatures::on_error` before being returned by the thread
+pub(crate) struct ReportingBuilder {
+    builder: std::thread::Builder,
+    location: &'static str,
+    on_error: Option<ErrorCallback>,
+}
+
+impl ReportingBuilder {
+    pub(crate) fn spawn<T: Send + 'static>(
+        self,
+        body: impl FnOnce() -> PeerNetResult<T> + Send + 'static,
+    ) -> std::io::Result<JoinHandle<PeerNetResult<T>>> {
+        let ReportingBuilder {
+            builder,
+            location,
+            on_error,
+        } = self;
+        builder.spawn(move || {
+            let result = catch_panic(location, body);
+            if let Err(err) = &result {
+                match &on_error {
+                    Some(on_error) => on_error(err),
+                    None => tracing::error!("{location} failed: {err}"),
+                }
+            }
+            result
+        })
+    }
+}
+
+/// Run `body`, its panic is turned into a `ThreadPanicked` error
+pub(crate) fn catch_panic<T>(
+    location: &'static str,
+    body: impl FnOnce() -> PeerNetResult<T>,
+) -> PeerNetResult<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        Err(PeerNetError::ThreadPanicked.error(location, Some(panic_message(&panic))))
+    })
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
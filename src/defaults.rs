@@ -0,0 +1,85 @@
+//! Ready to use implementations of the traits needed by a `PeerNetManager` (feature `defaults`)
+//!
+//! The peers are identified by a random number and the handshake doesn't authenticate
+//! anything, so they are only meant for tests and for trying the crate quickly:
+//! ```rust
+//! use peernet::config::PeerNetConfigurationBuilder;
+//! use peernet::defaults::{DefaultContext, DefaultInitConnection, DefaultMessagesHandler, DefaultPeerId};
+//! use peernet::network_manager::PeerNetManager;
+//! use peernet::peer_id::PeerId;
+//!
+//! let context = DefaultContext {
+//!     our_id: DefaultPeerId::generate(),
+//! };
+//! let config =
+//!     PeerNetConfigurationBuilder::new(context, DefaultInitConnection, DefaultMessagesHandler {})
+//!         .build()
+//!         .unwrap();
+//! let manager = PeerNetManager::new(config).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::context::Context;
+use crate::error::PeerNetResult;
+use crate::messages::{Bytes, MessagesHandler};
+use crate::peer::InitConnectionHandler;
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::Endpoint;
+use crate::transports::TransportType;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct DefaultPeerId {
+    pub id: u64,
+}
+
+impl PeerId for DefaultPeerId {
+    fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let random_number: u64 = rng.gen();
+        DefaultPeerId { id: random_number }
+    }
+}
+
+#[derive(Clone)]
+pub struct DefaultContext {
+    pub our_id: DefaultPeerId,
+}
+
+impl Context<DefaultPeerId> for DefaultContext {
+    fn get_peer_id(&self) -> DefaultPeerId {
+        self.our_id.clone()
+    }
+}
+
+/// Drops all the messages received
+#[derive(Clone)]
+pub struct DefaultMessagesHandler {}
+
+impl MessagesHandler<DefaultPeerId> for DefaultMessagesHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        Ok(())
+    }
+}
+
+/// Handshake that exchanges nothing, the remote peer gets a random id
+#[derive(Clone)]
+pub struct DefaultInitConnection;
+
+impl<M: MessagesHandler<DefaultPeerId>> InitConnectionHandler<DefaultPeerId, DefaultContext, M>
+    for DefaultInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _context: &DefaultContext,
+        _endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: M,
+    ) -> PeerNetResult<DefaultPeerId> {
+        Ok(DefaultPeerId::generate())
+    }
+}
@@ -0,0 +1,397 @@
+//! Kademlia-like discovery of the peers
+//!
+//! Each peer has a key, the sha256 of its serialized id. The `RoutingTable` sorts the peers we
+//! know in buckets by the distance (xor) of their key to ours, keeping at most `k` peers per
+//! bucket. A `FIND_NODE` query asks a peer for the announcements of the peers it knows that are
+//! the closest to a target key, they are verified and stored in the `PeerDB` and the routing
+//! table, so they can then be dialed (e.g. by the `ConnectionSupervisor`) and queried in turn.
+//!
+//! `KadHandler` is registered in a `MessageHandlers` like the `PeerManagementHandler` it shares
+//! its `PeerDB` with.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{bounded, Sender};
+use parking_lot::{Mutex, RwLock};
+use sha2::{Digest, Sha256};
+
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::handlers::{MessageHandler, RoutedSerializer};
+use crate::internal_handlers::peer_management::{
+    Announcement, PeerManagementHooks, Reader, SharedPeerDB, MAX_LISTENERS_PER_PEER,
+};
+use crate::messages::{Bytes, MessagesSerializer};
+use crate::peer::{PeerHandle, SendChannels};
+use crate::peer_id::PeerId;
+
+pub const KEY_SIZE: usize = 32;
+pub type KadKey = [u8; KEY_SIZE];
+
+/// Maximum number of peers in an answer to `FIND_NODE`
+pub const MAX_NODES_IN_ANSWER: usize = 100;
+
+const FIND_NODE: u8 = 0;
+const NODES: u8 = 1;
+
+/// Key of a peer in the routing table
+pub fn kad_key<Id, H: PeerManagementHooks<Id>>(peer_id: &Id, hooks: &H) -> PeerNetResult<KadKey> {
+    let mut data = Vec::new();
+    hooks.serialize_peer_id(peer_id, &mut data)?;
+    Ok(Sha256::digest(&data).into())
+}
+
+fn distance(a: &KadKey, b: &KadKey) -> KadKey {
+    let mut distance = [0u8; KEY_SIZE];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    distance
+}
+
+/// Peers known, in buckets by distance to our key
+#[derive(Debug)]
+pub struct RoutingTable<Id> {
+    local_key: KadKey,
+    /// Bucket `i` holds the peers whose distance to us has `i` leading zero bits
+    buckets: Vec<Vec<(KadKey, Id)>>,
+    k: usize,
+}
+
+impl<Id: PeerId> RoutingTable<Id> {
+    pub fn new(local_key: KadKey, k: usize) -> Self {
+        RoutingTable {
+            local_key,
+            buckets: vec![Vec::new(); KEY_SIZE * 8],
+            k,
+        }
+    }
+
+    fn bucket_index(&self, key: &KadKey) -> Option<usize> {
+        let distance = distance(&self.local_key, key);
+        let mut zeros = 0;
+        for byte in distance {
+            if byte != 0 {
+                return Some(zeros + byte.leading_zeros() as usize);
+            }
+            zeros += 8;
+        }
+        // our own key
+        None
+    }
+
+    /// Add a peer, or move it to the end of its bucket if it's known.
+    /// The peers already known are kept when the bucket is full, returns whether it was added.
+    pub fn insert(&mut self, key: KadKey, peer_id: Id) -> bool {
+        let Some(index) = self.bucket_index(&key) else {
+            return false;
+        };
+        let k = self.k;
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|(_, id)| *id == peer_id) {
+            let entry = bucket.remove(position);
+            bucket.push(entry);
+            return true;
+        }
+        if bucket.len() >= k {
+            return false;
+        }
+        bucket.push((key, peer_id));
+        true
+    }
+
+    pub fn remove(&mut self, key: &KadKey, peer_id: &Id) {
+        if let Some(index) = self.bucket_index(key) {
+            self.buckets[index].retain(|(_, id)| id != peer_id);
+        }
+    }
+
+    /// The `count` peers the closest to `target`
+    pub fn closest(&self, target: &KadKey, count: usize) -> Vec<Id> {
+        let mut peers: Vec<&(KadKey, Id)> = self.buckets.iter().flatten().collect();
+        peers.sort_by_key(|(key, _)| distance(key, target));
+        peers
+            .into_iter()
+            .take(count)
+            .map(|(_, id)| id.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KadMessage<Id> {
+    /// Ask for the peers the closest to `target`, the answer has the same `request_id`
+    FindNode { request_id: u64, target: KadKey },
+    Nodes {
+        request_id: u64,
+        nodes: Vec<(Id, Announcement)>,
+    },
+}
+
+/// Serializer of the `KadMessage`, to wrap in a `RoutedSerializer`
+#[derive(Clone)]
+pub struct KadMessageSerializer<H> {
+    pub hooks: H,
+}
+
+impl<Id, H: PeerManagementHooks<Id>> MessagesSerializer<KadMessage<Id>>
+    for KadMessageSerializer<H>
+{
+    fn serialize(&self, message: &KadMessage<Id>, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        match message {
+            KadMessage::FindNode { request_id, target } => {
+                buffer.push(FIND_NODE);
+                buffer.extend_from_slice(&request_id.to_be_bytes());
+                buffer.extend_from_slice(target);
+                Ok(())
+            }
+            KadMessage::Nodes { request_id, nodes } => {
+                if nodes.len() > MAX_NODES_IN_ANSWER {
+                    return Err(PeerNetError::InvalidMessage.error(
+                        "serialize nodes",
+                        Some(format!("too many nodes: {}", nodes.len())),
+                    ));
+                }
+                buffer.push(NODES);
+                buffer.extend_from_slice(&request_id.to_be_bytes());
+                buffer.extend_from_slice(&(nodes.len() as u16).to_be_bytes());
+                for (peer_id, announcement) in nodes {
+                    self.hooks.serialize_peer_id(peer_id, buffer)?;
+                    announcement.write(buffer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<H> KadMessageSerializer<H> {
+    pub fn deserialize<Id>(&self, data: &[u8]) -> PeerNetResult<KadMessage<Id>>
+    where
+        H: PeerManagementHooks<Id>,
+    {
+        let mut reader = Reader { data, position: 0 };
+        let [message_type] = reader.read_array()?;
+        let request_id = u64::from_be_bytes(reader.read_array()?);
+        let message = match message_type {
+            FIND_NODE => KadMessage::FindNode {
+                request_id,
+                target: reader.read_array()?,
+            },
+            NODES => {
+                let nb_nodes = u16::from_be_bytes(reader.read_array()?) as usize;
+                if nb_nodes > MAX_NODES_IN_ANSWER {
+                    return Err(PeerNetError::InvalidMessage.error(
+                        "deserialize nodes",
+                        Some(format!("too many nodes: {}", nb_nodes)),
+                    ));
+                }
+                let mut nodes = Vec::with_capacity(nb_nodes);
+                for _ in 0..nb_nodes {
+                    let (peer_id, len) =
+                        self.hooks.deserialize_peer_id(&data[reader.position..])?;
+                    reader.read_bytes(len)?;
+                    nodes.push((peer_id, Announcement::read(&mut reader)?));
+                }
+                KadMessage::Nodes { request_id, nodes }
+            }
+            _ => {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "deserialize kad message",
+                    Some(format!("unknown message type: {}", message_type)),
+                ))
+            }
+        };
+        if reader.position != data.len() {
+            return Err(PeerNetError::InvalidMessage.error(
+                "deserialize kad message",
+                Some(format!("{} trailing bytes", data.len() - reader.position)),
+            ));
+        }
+        Ok(message)
+    }
+}
+
+type PendingQueries<Id> = HashMap<u64, (Id, Sender<Vec<Id>>)>;
+
+/// Handler of the `KadMessage`, to register in a `MessageHandlers` with `handler_id`
+#[derive(Clone)]
+pub struct KadHandler<Id: PeerId, H> {
+    handler_id: u64,
+    hooks: H,
+    peer_db: SharedPeerDB<Id>,
+    pub routing_table: Arc<RwLock<RoutingTable<Id>>>,
+    pending_queries: Arc<Mutex<PendingQueries<Id>>>,
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> KadHandler<Id, H> {
+    /// `k` is the size of the buckets of the routing table
+    pub fn new<Ctx: Context<Id>>(
+        handler_id: u64,
+        context: &Ctx,
+        k: usize,
+        hooks: H,
+        peer_db: SharedPeerDB<Id>,
+    ) -> PeerNetResult<Self> {
+        let local_key = kad_key(&context.get_peer_id(), &hooks)?;
+        Ok(KadHandler {
+            handler_id,
+            hooks,
+            peer_db,
+            routing_table: Arc::new(RwLock::new(RoutingTable::new(local_key, k))),
+            pending_queries: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn serializer(&self) -> RoutedSerializer<KadMessageSerializer<H>> {
+        RoutedSerializer {
+            handler_id: self.handler_id,
+            serializer: KadMessageSerializer {
+                hooks: self.hooks.clone(),
+            },
+        }
+    }
+
+    /// Add a peer to the routing table, e.g. once connected to it
+    pub fn add_peer(&self, peer_id: &Id) -> PeerNetResult<bool> {
+        let key = kad_key(peer_id, &self.hooks)?;
+        Ok(self.routing_table.write().insert(key, peer_id.clone()))
+    }
+
+    /// Ask `peer_id` for the peers the closest to `target` and wait for its answer.
+    /// Returns the ids of the peers of the answer that have been verified and stored.
+    pub fn find_node(
+        &self,
+        peer_id: &Id,
+        send_channels: &SendChannels,
+        target: KadKey,
+        timeout: Duration,
+    ) -> PeerNetResult<Vec<Id>> {
+        let (answer_tx, answer_rx) = bounded(1);
+        // random ids, so that the other peers can't answer the queries they weren't asked
+        let request_id = {
+            let mut pending_queries = self.pending_queries.lock();
+            loop {
+                if let Entry::Vacant(entry) = pending_queries.entry(rand::random()) {
+                    let request_id = *entry.key();
+                    entry.insert((peer_id.clone(), answer_tx));
+                    break request_id;
+                }
+            }
+        };
+        let deadline = Instant::now() + timeout;
+        let result = send_channels
+            .send_with_deadline(
+                &self.serializer(),
+                KadMessage::<Id>::FindNode { request_id, target },
+                false,
+                deadline,
+            )
+            .and_then(|_| {
+                answer_rx.recv_deadline(deadline).map_err(|err| {
+                    PeerNetError::TimeOut.new("find_node", err, Some(format!("{:?}", peer_id)))
+                })
+            });
+        self.pending_queries.lock().remove(&request_id);
+        result
+    }
+
+    /// The peers of the routing table the closest to `target` that have a stored announcement
+    fn closest_announced(&self, target: &KadKey, exclude: &Id) -> Vec<(Id, Announcement)> {
+        let closest = self
+            .routing_table
+            .read()
+            .closest(target, MAX_NODES_IN_ANSWER + 1);
+        let peer_db = self.peer_db.read();
+        closest
+            .into_iter()
+            .filter(|id| id != exclude)
+            .filter_map(|id| {
+                let announcement = peer_db.peers.get(&id)?.announcement.clone();
+                Some((id, announcement))
+            })
+            .take(MAX_NODES_IN_ANSWER)
+            .collect()
+    }
+
+    /// Verify and store the nodes of an answer, returns the ids of the ones stored
+    fn receive_nodes(&self, nodes: Vec<(Id, Announcement)>) -> PeerNetResult<Vec<Id>> {
+        let mut stored = Vec::with_capacity(nodes.len());
+        for (peer_id, announcement) in nodes {
+            if announcement.listeners.len() > MAX_LISTENERS_PER_PEER {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "receive nodes",
+                    Some(format!(
+                        "too many listeners: {}",
+                        announcement.listeners.len()
+                    )),
+                ));
+            }
+            announcement.verify(&peer_id, &self.hooks)?;
+            self.add_peer(&peer_id)?;
+            self.peer_db
+                .write()
+                .insert_announcement(peer_id.clone(), announcement);
+            stored.push(peer_id);
+        }
+        Ok(stored)
+    }
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> MessageHandler<Id> for KadHandler<Id, H> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        match self.serializer().serializer.deserialize(&data)? {
+            KadMessage::Nodes { request_id, nodes } => {
+                // only the answers to our queries, from the peer we asked, are accepted. The
+                // others are ignored: an answer arriving after the timeout of its query is no
+                // reason to disconnect the peer
+                let answer_tx = {
+                    let mut pending_queries = self.pending_queries.lock();
+                    match pending_queries.get(&request_id) {
+                        Some((queried, _)) if queried == peer_id => {
+                            pending_queries.remove(&request_id).map(|(_, tx)| tx)
+                        }
+                        _ => None,
+                    }
+                };
+                let Some(answer_tx) = answer_tx else {
+                    tracing::debug!(?peer_id, request_id, "ignoring kad nodes of no query");
+                    return Ok(());
+                };
+                let stored = self.receive_nodes(nodes)?;
+                let _ = answer_tx.send(stored);
+                Ok(())
+            }
+            KadMessage::FindNode { .. } => Err(PeerNetError::HandlerError.error(
+                "kad find node",
+                Some("can't answer without the handle of the peer".to_string()),
+            )),
+        }
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        self.add_peer(&peer.peer_id)?;
+        match self.serializer().serializer.deserialize::<Id>(&data)? {
+            KadMessage::FindNode { request_id, target } => {
+                let nodes = self.closest_announced(&target, &peer.peer_id);
+                peer.send_channels.send(
+                    &self.serializer(),
+                    KadMessage::Nodes { request_id, nodes },
+                    false,
+                )
+            }
+            KadMessage::Nodes { .. } => self.handle(data, &peer.peer_id),
+        }
+    }
+}
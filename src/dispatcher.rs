@@ -0,0 +1,284 @@
+//! Pool of threads running the messages handler
+//!
+//! When `PeerNetFeatures::handler_workers` is set, the read loop of a peer only pushes the
+//! received messages in a bounded queue consumed by the workers, so a slow handler doesn't
+//! block the reading (and the rate limiting) of the connection anymore. With more than one
+//! worker, two messages of the same peer can be handled concurrently.
+//!
+//! The bytes of the messages queued or being handled are counted per peer and in total. A read
+//! loop blocks before queueing a message that would exceed `HandlerWorkers::max_in_flight_bytes_per_peer`
+//! or `HandlerWorkers::max_in_flight_bytes`, until the workers catch up, so a slow handler bounds
+//! the memory held by the received messages instead of letting it grow. A message is always
+//! accepted when nothing of its peer (or nothing at all) is in flight, even if it's bigger than
+//! the limit.
+//!
+//! With `HandlerWorkers::dispatch_timeout`, a read loop doesn't wait more than this timeout: the
+//! message is refused with `PeerNetError::Backpressure` and its peer is disconnected, as for an
+//! error of the handler.
+//!
+//! The event loops of `PeerNetFeatures::tcp_reactor` can't wait, they drive other peers: with
+//! `MessageDispatcher::try_dispatch`, a message that doesn't fit is given back to the loop, which
+//! stops reading its connection and is called back once the workers release bytes.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{bounded, SendTimeoutError, Sender, TrySendError};
+use parking_lot::{Condvar, Mutex};
+
+use crate::config::{catch_panic, HandlerWorkers};
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::history::DisconnectReason;
+use crate::memory::MemoryCharge;
+use crate::messages::{Bytes, MessageMeta, MessagesHandler};
+use crate::network_manager::SharedActiveConnections;
+use crate::peer::PeerHandle;
+use crate::peer_id::PeerId;
+use crate::timings::{timed, PeerTimers};
+
+/// Called once bytes are released, see `MessageDispatcher::try_dispatch`
+pub(crate) type Resume = Box<dyn FnOnce() + Send>;
+
+struct InFlightBytes<Id> {
+    total: usize,
+    per_peer: HashMap<Id, usize>,
+    // the event loops waiting for the next release
+    resumes: Vec<Resume>,
+}
+
+/// Bytes of the messages dispatched and not handled yet
+struct InFlight<Id> {
+    bytes: Mutex<InFlightBytes<Id>>,
+    released: Condvar,
+    max_per_peer: Option<usize>,
+    max_total: Option<usize>,
+}
+
+impl<Id: PeerId> InFlight<Id> {
+    fn fits(&self, bytes: &InFlightBytes<Id>, peer_id: &Id, size: usize) -> bool {
+        let fits = |current: usize, max: Option<usize>| {
+            current == 0 || max.map_or(true, |max| current + size <= max)
+        };
+        let peer_bytes = bytes.per_peer.get(peer_id).copied().unwrap_or(0);
+        fits(peer_bytes, self.max_per_peer) && fits(bytes.total, self.max_total)
+    }
+
+    /// Count `size` bytes for `peer_id`, waits until they fit in the limits. Returns `false`
+    /// without counting them if they still don't fit at `deadline`.
+    fn acquire(&self, peer_id: &Id, size: usize, deadline: Option<Instant>) -> bool {
+        let mut bytes = self.bytes.lock();
+        loop {
+            if self.fits(&bytes, peer_id, size) {
+                break;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.released.wait_until(&mut bytes, deadline).timed_out() {
+                        return false;
+                    }
+                }
+                None => self.released.wait(&mut bytes),
+            }
+        }
+        bytes.total += size;
+        *bytes.per_peer.entry(peer_id.clone()).or_default() += size;
+        true
+    }
+
+    /// Count `size` bytes for `peer_id` if they fit in the limits. Otherwise `resume` is taken
+    /// and called at the next release, which can't be missed: there are bytes in flight.
+    fn try_acquire(&self, peer_id: &Id, size: usize, resume: &mut Option<Resume>) -> bool {
+        let mut bytes = self.bytes.lock();
+        if !self.fits(&bytes, peer_id, size) {
+            bytes.resumes.extend(resume.take());
+            return false;
+        }
+        bytes.total += size;
+        *bytes.per_peer.entry(peer_id.clone()).or_default() += size;
+        true
+    }
+
+    fn release(&self, peer_id: &Id, size: usize) {
+        let resumes = {
+            let mut bytes = self.bytes.lock();
+            Self::uncount(&mut bytes, peer_id, size);
+            std::mem::take(&mut bytes.resumes)
+        };
+        self.released.notify_all();
+        for resume in resumes {
+            resume();
+        }
+    }
+
+    fn uncount(bytes: &mut InFlightBytes<Id>, peer_id: &Id, size: usize) {
+        bytes.total -= size;
+        if let Some(peer_bytes) = bytes.per_peer.get_mut(peer_id) {
+            *peer_bytes -= size;
+            if *peer_bytes == 0 {
+                bytes.per_peer.remove(peer_id);
+            }
+        }
+    }
+}
+
+/// A message queued for the workers, the charge keeps it accounted to the memory of its peer
+/// until it's handled
+type Dispatched<Id> = (Bytes, PeerHandle<Id>, MessageMeta, Option<MemoryCharge>);
+
+#[derive(Clone)]
+pub(crate) struct MessageDispatcher<Id: PeerId> {
+    sender: Sender<Dispatched<Id>>,
+    in_flight: Arc<InFlight<Id>>,
+    timeout: Option<Duration>,
+}
+
+impl<Id: PeerId> MessageDispatcher<Id> {
+    /// Spawn the workers. They stop once every clone of the dispatcher is dropped.
+    pub(crate) fn start<M: MessagesHandler<Id>>(
+        config: HandlerWorkers,
+        message_handler: M,
+        active_connections: SharedActiveConnections<Id>,
+    ) -> MessageDispatcher<Id> {
+        let (sender, receiver) = bounded::<Dispatched<Id>>(config.queue_size);
+        let in_flight = Arc::new(InFlight {
+            bytes: Mutex::new(InFlightBytes {
+                total: 0,
+                per_peer: HashMap::new(),
+                resumes: Vec::new(),
+            }),
+            released: Condvar::new(),
+            max_per_peer: config.max_in_flight_bytes_per_peer,
+            max_total: config.max_in_flight_bytes,
+        });
+        for index in 0..config.nb_workers.max(1) {
+            let receiver = receiver.clone();
+            let in_flight = in_flight.clone();
+            let message_handler = message_handler.clone();
+            let active_connections = active_connections.clone();
+            std::thread::Builder::new()
+                .name(format!("message_handler_worker_{}", index))
+                .spawn(move || {
+                    for (data, peer, meta, charge) in receiver.iter() {
+                        let size = data.len();
+                        // a panic of the handler disconnects its peer as an error would, the
+                        // worker keeps running and the bytes in flight are released
+                        let res = catch_panic("message handler", || {
+                            timed(peer.timers.as_deref(), PeerTimers::add_handler, || {
+                                message_handler.handle_with_meta(data, &peer, meta)
+                            })
+                        });
+                        in_flight.release(&peer.peer_id, size);
+                        drop(charge);
+                        if let Err(err) = res {
+                            tracing::warn!(peer_id = ?peer.peer_id, "error handling message: {:?}", err);
+                            {
+                                let mut write_active_connections = active_connections.write();
+                                write_active_connections.remove_connection_with_reason(
+                                    &peer.peer_id,
+                                    DisconnectReason::HandlerError(err.to_string()),
+                                );
+                            }
+                        }
+                    }
+                })
+                .expect("Failed to spawn message_handler_worker");
+        }
+        MessageDispatcher {
+            sender,
+            in_flight,
+            timeout: config.dispatch_timeout,
+        }
+    }
+
+    /// Queue a message for the workers, blocks while the queue is full or the message doesn't
+    /// fit in the limits of the bytes in flight, at most `HandlerWorkers::dispatch_timeout`
+    pub(crate) fn dispatch(
+        &self,
+        data: Bytes,
+        peer: &PeerHandle<Id>,
+        meta: MessageMeta,
+    ) -> PeerNetResult<()> {
+        let size = data.len();
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        if !self.in_flight.acquire(&peer.peer_id, size, deadline) {
+            return Err(PeerNetError::Backpressure.error(
+                "dispatch message",
+                Some(format!("{} bytes in flight", self.in_flight_bytes())),
+            ));
+        }
+        let charge = peer.send_channels.charge_received(size);
+        let res = match deadline {
+            Some(deadline) => self
+                .sender
+                .send_timeout(
+                    (data, peer.clone(), meta, charge),
+                    deadline.saturating_duration_since(Instant::now()),
+                )
+                .map_err(|err| match err {
+                    SendTimeoutError::Timeout(_) => PeerNetError::Backpressure
+                        .error("dispatch message", Some("queue full".to_string())),
+                    SendTimeoutError::Disconnected(_) => {
+                        PeerNetError::HandlerError.error("dispatch message", None)
+                    }
+                }),
+            None => self
+                .sender
+                .send((data, peer.clone(), meta, charge))
+                .map_err(|_| PeerNetError::HandlerError.error("dispatch message", None)),
+        };
+        if res.is_err() {
+            self.in_flight.release(&peer.peer_id, size);
+        }
+        res
+    }
+
+    /// Queue a message for the workers without waiting, for the event loops. The message is
+    /// given back if the queue is full or if it doesn't fit in the limits of the bytes in flight,
+    /// `resume` is then called once the workers release some.
+    pub(crate) fn try_dispatch(
+        &self,
+        data: Bytes,
+        peer: &PeerHandle<Id>,
+        meta: MessageMeta,
+        resume: Resume,
+    ) -> PeerNetResult<Option<Bytes>> {
+        let size = data.len();
+        let mut resume = Some(resume);
+        if !self.in_flight.try_acquire(&peer.peer_id, size, &mut resume) {
+            return Ok(Some(data));
+        }
+        let charge = peer.send_channels.charge_received(size);
+        match self.sender.try_send((data, peer.clone(), meta, charge)) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full((data, ..))) => {
+                let mut bytes = self.in_flight.bytes.lock();
+                InFlight::uncount(&mut bytes, &peer.peer_id, size);
+                self.in_flight.released.notify_all();
+                // the queued messages will be released after this, unless the workers took
+                // them all already
+                if self.sender.is_full() {
+                    bytes.resumes.extend(resume);
+                } else if let Some(resume) = resume {
+                    drop(bytes);
+                    resume();
+                }
+                Ok(Some(data))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.in_flight.release(&peer.peer_id, size);
+                Err(PeerNetError::HandlerError.error("dispatch message", None))
+            }
+        }
+    }
+
+    /// See `HandlerWorkers::dispatch_timeout`
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Bytes of the messages queued or being handled, of all the peers
+    pub(crate) fn in_flight_bytes(&self) -> usize {
+        self.in_flight.bytes.lock().total
+    }
+}
@@ -0,0 +1,248 @@
+//! Peer ids derived from ed25519 public keys (feature `ed25519`)
+//!
+//! `Ed25519KeyPair` signs with the private key of a peer and `Ed25519PeerId` is its public key.
+//! They implement the traits of the crate: `Ed25519Context` for the context of the manager,
+//! `Ed25519Hooks` for the announcements of `PeerManagementHandler` and `Ed25519InitConnection`
+//! for a handshake in which each peer proves it owns the key of its id.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+
+use crate::config::REDACTED;
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::internal_handlers::peer_management::PeerManagementHooks;
+use crate::messages::MessagesHandler;
+use crate::peer::InitConnectionHandler;
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::Endpoint;
+use crate::transports::TransportType;
+
+pub const PUBLIC_KEY_SIZE: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+pub const SECRET_KEY_SIZE: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+pub const SIGNATURE_SIZE: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+// size of the random challenge each peer signs during the handshake
+const CHALLENGE_SIZE: usize = 32;
+// prefix of the data signed during the handshake, see `ANNOUNCEMENT_SIGNATURE_TAG`
+const HANDSHAKE_SIGNATURE_TAG: &[u8] = b"peernet ed25519 handshake v1";
+
+/// Id of a peer, its ed25519 public key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ed25519PeerId([u8; PUBLIC_KEY_SIZE]);
+
+impl Ed25519PeerId {
+    pub fn from_public_key(public_key: &VerifyingKey) -> Self {
+        Ed25519PeerId(public_key.to_bytes())
+    }
+
+    /// Fails if `bytes` isn't a valid public key
+    pub fn from_bytes(bytes: &[u8]) -> PeerNetResult<Self> {
+        let bytes: [u8; PUBLIC_KEY_SIZE] = bytes.try_into().map_err(|_| {
+            PeerNetError::PeerIdError
+                .error("ed25519 peer id", Some(format!("len: {}", bytes.len())))
+        })?;
+        VerifyingKey::from_bytes(&bytes)
+            .map_err(|err| PeerNetError::PeerIdError.new("ed25519 peer id", err, None))?;
+        Ok(Ed25519PeerId(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.0
+    }
+
+    pub fn public_key(&self) -> PeerNetResult<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.0)
+            .map_err(|err| PeerNetError::PeerIdError.new("ed25519 public key", err, None))
+    }
+
+    /// Check that `signature` of `data` has been made by the key of this peer
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> PeerNetResult<()> {
+        let signature = Signature::from_slice(signature)
+            .map_err(|err| PeerNetError::SignError.new("ed25519 signature", err, None))?;
+        self.public_key()?
+            .verify(data, &signature)
+            .map_err(|err| PeerNetError::SignError.new("ed25519 verify", err, None))
+    }
+}
+
+impl PeerId for Ed25519PeerId {
+    /// Id of a new random key, for the peers that don't need to sign
+    fn generate() -> Self {
+        Ed25519KeyPair::generate().peer_id()
+    }
+}
+
+/// Private key of a peer
+#[derive(Clone)]
+pub struct Ed25519KeyPair {
+    signing_key: SigningKey,
+}
+
+impl Ed25519KeyPair {
+    pub fn generate() -> Self {
+        Ed25519KeyPair {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn from_bytes(secret_key: &[u8; SECRET_KEY_SIZE]) -> Self {
+        Ed25519KeyPair {
+            signing_key: SigningKey::from_bytes(secret_key),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; SECRET_KEY_SIZE] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn peer_id(&self) -> Ed25519PeerId {
+        Ed25519PeerId::from_public_key(&self.signing_key.verifying_key())
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.signing_key.sign(data).to_bytes()
+    }
+}
+
+/// Only the public part, the private key is redacted
+impl std::fmt::Debug for Ed25519KeyPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ed25519KeyPair")
+            .field("peer_id", &self.peer_id())
+            .field("signing_key", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ed25519Context {
+    pub keypair: Ed25519KeyPair,
+}
+
+impl Context<Ed25519PeerId> for Ed25519Context {
+    fn get_peer_id(&self) -> Ed25519PeerId {
+        self.keypair.peer_id()
+    }
+}
+
+/// Announcements signed with our key, the ids are encoded as their public key
+#[derive(Clone, Debug)]
+pub struct Ed25519Hooks {
+    pub keypair: Ed25519KeyPair,
+}
+
+impl PeerManagementHooks<Ed25519PeerId> for Ed25519Hooks {
+    fn sign(&self, data: &[u8]) -> PeerNetResult<Vec<u8>> {
+        Ok(self.keypair.sign(data).to_vec())
+    }
+
+    fn verify(&self, peer_id: &Ed25519PeerId, data: &[u8], signature: &[u8]) -> PeerNetResult<()> {
+        peer_id.verify(data, signature)
+    }
+
+    fn serialize_peer_id(
+        &self,
+        peer_id: &Ed25519PeerId,
+        buffer: &mut Vec<u8>,
+    ) -> PeerNetResult<()> {
+        buffer.extend_from_slice(&peer_id.to_bytes());
+        Ok(())
+    }
+
+    fn deserialize_peer_id(&self, data: &[u8]) -> PeerNetResult<(Ed25519PeerId, usize)> {
+        let bytes = data.get(..PUBLIC_KEY_SIZE).ok_or_else(|| {
+            PeerNetError::InvalidMessage.error("deserialize ed25519 peer id", None)
+        })?;
+        Ok((Ed25519PeerId::from_bytes(bytes)?, PUBLIC_KEY_SIZE))
+    }
+}
+
+/// Handshake handler in which both peers send their public key and a random challenge, then
+/// sign both keys and both challenges. The messages are not encrypted, see
+/// `NoiseInitConnectionHandler` for that.
+#[derive(Clone, Debug)]
+pub struct Ed25519InitConnection {
+    pub keypair: Ed25519KeyPair,
+}
+
+impl Ed25519InitConnection {
+    /// Run the handshake on `endpoint`, returns the id of the remote peer once it proved it
+    /// owns its key. Can be called from another `InitConnectionHandler` that does more after it.
+    pub fn handshake(&self, endpoint: &mut Endpoint) -> PeerNetResult<Ed25519PeerId> {
+        let our_challenge: [u8; CHALLENGE_SIZE] = rand::random();
+        let mut message = self.keypair.peer_id().to_bytes().to_vec();
+        message.extend_from_slice(&our_challenge);
+        endpoint.send::<Ed25519PeerId>(&message)?;
+
+        let message = endpoint.receive::<Ed25519PeerId>()?;
+        if message.len() != PUBLIC_KEY_SIZE + CHALLENGE_SIZE {
+            return Err(PeerNetError::HandshakeError.error(
+                "ed25519 handshake",
+                Some(format!("key and challenge len: {}", message.len())),
+            ));
+        }
+        let peer_id = Ed25519PeerId::from_bytes(&message[..PUBLIC_KEY_SIZE])?;
+        // otherwise our own challenge and signature could be sent back to us
+        if peer_id == self.keypair.peer_id() {
+            return Err(PeerNetError::HandshakeError.error(
+                "ed25519 handshake",
+                Some("remote peer has our key".to_string()),
+            ));
+        }
+        let their_challenge = &message[PUBLIC_KEY_SIZE..];
+        // bound to the keys, a signature relayed to a third peer doesn't match its key
+        endpoint.send::<Ed25519PeerId>(&self.keypair.sign(&handshake_transcript(
+            &self.keypair.peer_id(),
+            &our_challenge,
+            &peer_id,
+            their_challenge,
+        )))?;
+
+        let signature = endpoint.receive::<Ed25519PeerId>()?;
+        peer_id
+            .verify(
+                &handshake_transcript(
+                    &peer_id,
+                    their_challenge,
+                    &self.keypair.peer_id(),
+                    &our_challenge,
+                ),
+                &signature,
+            )
+            .map_err(|err| PeerNetError::HandshakeError.new("ed25519 handshake", err, None))?;
+        Ok(peer_id)
+    }
+}
+
+/// Data signed by `signer` during the handshake with `verifier`
+fn handshake_transcript(
+    signer: &Ed25519PeerId,
+    signer_challenge: &[u8],
+    verifier: &Ed25519PeerId,
+    verifier_challenge: &[u8],
+) -> Vec<u8> {
+    let mut transcript = HANDSHAKE_SIGNATURE_TAG.to_vec();
+    transcript.extend_from_slice(&signer.to_bytes());
+    transcript.extend_from_slice(signer_challenge);
+    transcript.extend_from_slice(&verifier.to_bytes());
+    transcript.extend_from_slice(verifier_challenge);
+    transcript
+}
+
+impl<Ctx: Context<Ed25519PeerId>, M: MessagesHandler<Ed25519PeerId>>
+    InitConnectionHandler<Ed25519PeerId, Ctx, M> for Ed25519InitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _context: &Ctx,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: M,
+    ) -> PeerNetResult<Ed25519PeerId> {
+        self.handshake(endpoint)
+    }
+}
@@ -1,22 +1,28 @@
 //! Error types for the PeerNet library
 
 use std::error::Error;
+
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
 use thiserror::Error;
 
+use crate::config::ConfigError;
 use crate::transports::TransportErrorType;
 
 pub type PeerNetResult<T> = Result<T, PeerNetErrorData>;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub enum PeerNetError {
     ListenerError,
     PeerIdError,
+    PeerIdMismatch,
     WrongConfigType,
     PeerConnectionError,
     SendError,
     ReceiveError,
     HandshakeError,
     HandlerError,
+    UnknownHandlerId,
     SignError,
     SocketError,
     BoundReached,
@@ -24,10 +30,66 @@ pub enum PeerNetError {
     CouldNotSetTimeout,
     ConnectionClosed,
     TimeOut,
+    /// A background thread panicked, the message of the panic is in the error
+    ThreadPanicked,
+    /// The message is bigger than the `max_message_size` of the connection, it wasn't queued
+    MessageTooLarge,
+    /// The handler workers didn't take a received message before `HandlerWorkers::dispatch_timeout`
+    Backpressure,
+    /// No listener of the transport on the address
+    ListenerNotFound,
+    /// The transport isn't used by the manager yet: no listener was started nor connection
+    /// dialed with it
+    TransportNotStarted,
+    /// The address dialed is one of ours, see `DiagnosticEvent::SelfConnection`
+    SelfConnection,
+    /// A received message was refused by the `MessageFilter`, that disconnects the peer
+    MessageFiltered,
+    /// A low priority message was refused because it didn't fit in the `MemoryBudget`
+    MemoryBudgetExceeded,
+    /// A `PeerListSnapshot` of a version that this version of the library doesn't read
+    PeerListVersion,
     TransportError(TransportErrorType),
+    ConfigError(ConfigError),
 }
 
 impl PeerNetError {
+    /// Number identifying the kind of error, that doesn't change across versions: it can be
+    /// matched or stored by the applications. The transport errors are in 1xx (TCP) and 2xx
+    /// (QUIC), the configuration errors in 3xx.
+    pub fn code(&self) -> u16 {
+        match self {
+            PeerNetError::ListenerError => 1,
+            PeerNetError::PeerIdError => 2,
+            PeerNetError::PeerIdMismatch => 3,
+            PeerNetError::WrongConfigType => 4,
+            PeerNetError::PeerConnectionError => 5,
+            PeerNetError::SendError => 6,
+            PeerNetError::ReceiveError => 7,
+            PeerNetError::HandshakeError => 8,
+            PeerNetError::HandlerError => 9,
+            PeerNetError::UnknownHandlerId => 10,
+            PeerNetError::SignError => 11,
+            PeerNetError::SocketError => 12,
+            PeerNetError::BoundReached => 13,
+            PeerNetError::InvalidMessage => 14,
+            PeerNetError::CouldNotSetTimeout => 15,
+            PeerNetError::ConnectionClosed => 16,
+            PeerNetError::TimeOut => 17,
+            PeerNetError::ThreadPanicked => 18,
+            PeerNetError::MessageTooLarge => 19,
+            PeerNetError::Backpressure => 20,
+            PeerNetError::ListenerNotFound => 21,
+            PeerNetError::TransportNotStarted => 22,
+            PeerNetError::SelfConnection => 23,
+            PeerNetError::MessageFiltered => 24,
+            PeerNetError::MemoryBudgetExceeded => 25,
+            PeerNetError::PeerListVersion => 26,
+            PeerNetError::TransportError(err) => err.code(),
+            PeerNetError::ConfigError(err) => err.code(),
+        }
+    }
+
     #[allow(clippy::new_ret_no_self)]
     /// Create a PeerNetErrorData from the variant
     pub fn new<E: Error>(
@@ -64,6 +126,44 @@ pub struct PeerNetErrorData {
     add_msg: Option<String>,
 }
 
+impl PeerNetErrorData {
+    pub fn error_type(&self) -> &PeerNetError {
+        &self.error_type
+    }
+
+    /// See `PeerNetError::code`
+    pub fn code(&self) -> u16 {
+        self.error_type.code()
+    }
+
+    /// Where the error happened
+    pub fn location(&self) -> &'static str {
+        self.location
+    }
+
+    /// The error of the underlying library, if any
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    pub fn add_msg(&self) -> Option<&str> {
+        self.add_msg.as_deref()
+    }
+}
+
+/// Serialized with the `code` of its type
+impl Serialize for PeerNetErrorData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("PeerNetErrorData", 5)?;
+        state.serialize_field("code", &self.code())?;
+        state.serialize_field("error_type", &self.error_type)?;
+        state.serialize_field("location", self.location)?;
+        state.serialize_field("error", &self.error)?;
+        state.serialize_field("add_msg", &self.add_msg)?;
+        state.end()
+    }
+}
+
 impl std::fmt::Display for PeerNetErrorData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         writeln!(f, "Location: {}", self.location)?;
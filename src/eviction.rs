@@ -0,0 +1,120 @@
+//! Choice of a connection to drop when a TCP listener is full, see
+//! `PeerNetFeatures::eviction_policy`
+//!
+//! When `max_in_connections` is reached, the listener lets the newcomer start its handshake
+//! instead of refusing it. Once its handshake succeeded and the gater accepted it after the
+//! handshake, the incoming connections of the untrusted peers are given to the policy: the
+//! connection it selects is disconnected with `DisconnectReason::Evicted` and the newcomer takes
+//! its place. The policy can also select none, the newcomer is then refused as without a policy.
+//! Nobody is evicted for a newcomer that fails its handshake.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bandwidth::{BandwidthRates, BandwidthSnapshot};
+use crate::network_manager::to_canonical;
+
+/// Incoming connection that can be evicted
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    /// `Debug` format of the id of the peer
+    pub peer_id: String,
+    pub addr: SocketAddr,
+    pub category: Option<String>,
+    /// Time since the end of its handshake
+    pub connected_for: Duration,
+    /// Counts since the connection was created, see `Endpoint::get_total_bandwidth`
+    pub bandwidth: BandwidthSnapshot,
+    pub rates: BandwidthRates,
+}
+
+impl EvictionCandidate {
+    fn total_rate(&self) -> f64 {
+        self.rates.bytes_sent_per_sec + self.rates.bytes_received_per_sec
+    }
+}
+
+pub trait EvictionPolicy: Send + Sync {
+    /// Index in `candidates` of the connection to evict, the newcomer is refused if `None`.
+    /// `candidates` is never empty.
+    fn select(&self, candidates: &[EvictionCandidate]) -> Option<usize>;
+}
+
+pub type SharedEvictionPolicy = Arc<dyn EvictionPolicy>;
+
+/// The connection with the lowest throughput, the oldest one between those with the same
+/// throughput
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OldestIdle;
+
+impl EvictionPolicy for OldestIdle {
+    fn select(&self, candidates: &[EvictionCandidate]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                a.total_rate()
+                    .partial_cmp(&b.total_rate())
+                    .unwrap_or(Ordering::Equal)
+                    .then(b.connected_for.cmp(&a.connected_for))
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+/// The most recent connection of the IP with the most connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MostRepresentedIp;
+
+impl EvictionPolicy for MostRepresentedIp {
+    fn select(&self, candidates: &[EvictionCandidate]) -> Option<usize> {
+        most_represented(candidates, |candidate| to_canonical(candidate.addr.ip()))
+    }
+}
+
+/// The most recent connection of the category with the most connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MostRepresentedCategory;
+
+impl EvictionPolicy for MostRepresentedCategory {
+    fn select(&self, candidates: &[EvictionCandidate]) -> Option<usize> {
+        most_represented(candidates, |candidate| candidate.category.clone())
+    }
+}
+
+/// The connection with the lowest score given by the application, e.g. from its own view of
+/// the behavior of the peers
+pub struct LowestScore<F>(pub F);
+
+impl<F: Fn(&EvictionCandidate) -> f64 + Send + Sync> EvictionPolicy for LowestScore<F> {
+    fn select(&self, candidates: &[EvictionCandidate]) -> Option<usize> {
+        candidates
+            .iter()
+            .map(&self.0)
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(index, _)| index)
+    }
+}
+
+fn most_represented<K: std::hash::Hash + Eq>(
+    candidates: &[EvictionCandidate],
+    key: impl Fn(&EvictionCandidate) -> K,
+) -> Option<usize> {
+    let mut counts: HashMap<K, usize> = HashMap::new();
+    for candidate in candidates {
+        *counts.entry(key(candidate)).or_default() += 1;
+    }
+    candidates
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| {
+            counts[&key(a)]
+                .cmp(&counts[&key(b)])
+                .then(b.connected_for.cmp(&a.connected_for))
+        })
+        .map(|(index, _)| index)
+}
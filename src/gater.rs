@@ -0,0 +1,85 @@
+//! Admission of the connections, see `PeerNetManager::set_connection_gater`
+//!
+//! A connection goes through three checks: when a listener accepts it, before its handshake and
+//! after its handshake, once the id of the peer is known. `DefaultConnectionGater` applies the
+//! limits of the configuration and its categories. An application can replace it to add its own
+//! policies, e.g. a diversity of the networks of the peers, usually by calling the default
+//! checks first.
+//!
+//! The trusted peers (`TrustedPeers`) are accepted without asking the gater.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::config::{under_limit, PeerNetCategoryInfo};
+use crate::network_manager::ActiveConnections;
+use crate::peer::PeerConnectionType;
+use crate::peer_id::PeerId;
+
+pub trait ConnectionGater<Id: PeerId>: Send + Sync {
+    /// Whether a TCP or QUIC listener takes a new connection from `addr`, against the limit of
+    /// the incoming connections of the manager. The handshakes in progress count, see
+    /// `ActiveConnections::nb_in_reservations`. It's called under the write lock of the
+    /// connections, the accepted connection takes its slot before the lock is released.
+    /// Only this global limit is reserved, see `check_pre_handshake` for the categories.
+    fn check_accept(
+        &self,
+        active_connections: &ActiveConnections<Id>,
+        _addr: &SocketAddr,
+        max_in_connections: Option<usize>,
+    ) -> bool {
+        under_limit(
+            active_connections.nb_in_connections + active_connections.nb_in_reservations(),
+            max_in_connections,
+        )
+    }
+
+    /// Whether the incoming connection from `addr` can start its handshake, against the limits
+    /// of its category. Unlike `max_in_connections`, no slot is reserved: the concurrent
+    /// handshakes of a category aren't bounded by its `max_in_connections`, which only holds
+    /// for the connected peers as `check_post_handshake` checks it again.
+    fn check_pre_handshake(
+        &self,
+        active_connections: &ActiveConnections<Id>,
+        addr: &SocketAddr,
+        category_name: Option<String>,
+        category_info: PeerNetCategoryInfo,
+    ) -> bool {
+        active_connections.check_addr_accepted_pre_handshake(addr, category_name, category_info)
+    }
+
+    /// Whether the connection with `id` is kept after its handshake, against the limits of its
+    /// category. Called for the incoming and outgoing connections.
+    fn check_post_handshake(
+        &self,
+        active_connections: &ActiveConnections<Id>,
+        addr: &SocketAddr,
+        category_name: Option<String>,
+        category_info: PeerNetCategoryInfo,
+        id: &Id,
+        connection_type: PeerConnectionType,
+    ) -> bool {
+        active_connections.check_addr_accepted_post_handshake(
+            addr,
+            category_name,
+            category_info,
+            id,
+            connection_type,
+        )
+    }
+}
+
+pub type SharedConnectionGater<Id> = Arc<dyn ConnectionGater<Id>>;
+
+impl<Id: PeerId> Debug for dyn ConnectionGater<Id> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConnectionGater")
+    }
+}
+
+/// The limits of the configuration and its categories
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultConnectionGater;
+
+impl<Id: PeerId> ConnectionGater<Id> for DefaultConnectionGater {}
@@ -0,0 +1,130 @@
+//! Routing of the messages to per-protocol handlers
+//!
+//! `MessageHandlers` is a `MessagesHandler` that reads a u64 handler id (big endian) at the start
+//! of each message and gives the rest of the message to the handler registered for this id.
+//! Messages for it are serialized with a `RoutedSerializer` that writes the id of the target.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::messages::{Bytes, MessageMeta, MessagesHandler, MessagesSerializer};
+use crate::peer::PeerHandle;
+
+const HANDLER_ID_SIZE: usize = std::mem::size_of::<u64>();
+
+/// Handler of the messages of one protocol
+pub trait MessageHandler<Id>: Send + Sync + 'static {
+    /// Handle a message without its handler id
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()>;
+
+    /// Same as `MessagesHandler::handle_with_peer`. Calls `handle` by default.
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        self.handle(data, &peer.peer_id)
+    }
+
+    /// Same as `MessagesHandler::handle_with_meta`. Calls `handle_with_peer` by default.
+    fn handle_with_meta(
+        &self,
+        data: Bytes,
+        peer: &PeerHandle<Id>,
+        _meta: MessageMeta,
+    ) -> PeerNetResult<()> {
+        self.handle_with_peer(data, peer)
+    }
+}
+
+pub struct MessageHandlers<Id> {
+    handlers: HashMap<u64, Arc<dyn MessageHandler<Id>>>,
+}
+
+impl<Id> MessageHandlers<Id> {
+    pub fn new() -> Self {
+        MessageHandlers {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register the handler of the messages with the id `id`
+    pub fn add_handler<H: MessageHandler<Id>>(&mut self, id: u64, handler: H) -> PeerNetResult<()> {
+        if self.handlers.contains_key(&id) {
+            return Err(PeerNetError::HandlerError.error(
+                "add_handler",
+                Some(format!("a handler is already registered for id {}", id)),
+            ));
+        }
+        self.handlers.insert(id, Arc::new(handler));
+        Ok(())
+    }
+
+    /// Split the handler id from the message and find the handler registered for it
+    fn route(&self, mut data: Bytes) -> PeerNetResult<(&Arc<dyn MessageHandler<Id>>, Bytes)> {
+        if data.len() < HANDLER_ID_SIZE {
+            return Err(PeerNetError::InvalidMessage.error(
+                "route message",
+                Some(format!(
+                    "message too short for a handler id: {}",
+                    data.len()
+                )),
+            ));
+        }
+        let payload = data.split_off(HANDLER_ID_SIZE);
+        let mut id_bytes = [0u8; HANDLER_ID_SIZE];
+        id_bytes.copy_from_slice(&data);
+        let id = u64::from_be_bytes(id_bytes);
+        let handler = self.handlers.get(&id).ok_or_else(|| {
+            PeerNetError::UnknownHandlerId.error("route message", Some(format!("id: {}", id)))
+        })?;
+        Ok((handler, payload))
+    }
+}
+
+impl<Id> Default for MessageHandlers<Id> {
+    fn default() -> Self {
+        MessageHandlers::new()
+    }
+}
+
+impl<Id> Clone for MessageHandlers<Id> {
+    fn clone(&self) -> Self {
+        MessageHandlers {
+            handlers: self.handlers.clone(),
+        }
+    }
+}
+
+impl<Id: 'static> MessagesHandler<Id> for MessageHandlers<Id> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        let (handler, payload) = self.route(data)?;
+        handler.handle(payload, peer_id)
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        let (handler, payload) = self.route(data)?;
+        handler.handle_with_peer(payload, peer)
+    }
+
+    fn handle_with_meta(
+        &self,
+        data: Bytes,
+        peer: &PeerHandle<Id>,
+        meta: MessageMeta,
+    ) -> PeerNetResult<()> {
+        let (handler, payload) = self.route(data)?;
+        handler.handle_with_meta(payload, peer, meta)
+    }
+}
+
+/// Serializer writing the id of the handler that must receive the message before the message
+/// serialized by `serializer`
+pub struct RoutedSerializer<MS> {
+    pub handler_id: u64,
+    pub serializer: MS,
+}
+
+impl<T, MS: MessagesSerializer<T>> MessagesSerializer<T> for RoutedSerializer<MS> {
+    fn serialize(&self, message: &T, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        buffer.extend_from_slice(&self.handler_id.to_be_bytes());
+        self.serializer.serialize(message, buffer)
+    }
+}
@@ -0,0 +1,74 @@
+//! Pool of threads running the handshakes of the incoming connections
+//!
+//! When `PeerNetFeatures::handshake_workers` is set, a connection accepted by a listener doesn't
+//! get a thread of its own before the end of its handshake: the handshake runs on one of the
+//! workers, which then gives the connection its peer thread, or its event loop with
+//! `PeerNetFeatures::tcp_reactor`. The workers bound the handshakes running at the same time and
+//! the queue the connections waiting for a worker. A connection accepted when the queue is full
+//! fails its handshake at once, it's recorded and reported as any failed handshake.
+//!
+//! A panic during a handshake is given to `PeerNetFeatures::on_error` and the worker goes on
+//! with the next connection.
+//!
+//! A peer that doesn't answer holds its worker until the read timeout of its endpoint, the
+//! `PeerNetFeatures::handshake_data_limit` and the `proof_of_work` keep the handshakes short.
+
+use crossbeam::channel::{bounded, Sender, TrySendError};
+
+use crate::config::{catch_panic, HandshakeWorkers, PeerNetFeatures};
+use crate::error::{PeerNetError, PeerNetErrorData, PeerNetResult};
+
+/// Handshake of a connection, called with the error refusing it if it can't be run
+pub(crate) type HandshakeJob = Box<dyn FnOnce(Option<PeerNetErrorData>) + Send>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct HandshakePool {
+    sender: Sender<HandshakeJob>,
+}
+
+impl HandshakePool {
+    /// Spawn the workers. They stop once every clone of the pool is dropped.
+    pub(crate) fn start(
+        config: HandshakeWorkers,
+        features: &PeerNetFeatures,
+    ) -> PeerNetResult<HandshakePool> {
+        let (sender, receiver) = bounded::<HandshakeJob>(config.queue_size);
+        for index in 0..config.nb_workers.max(1) {
+            let receiver = receiver.clone();
+            let on_error = features.on_error.clone();
+            features
+                .threads
+                .builder(format!("handshake_worker_{}", index))
+                .spawn(move || {
+                    for job in receiver.iter() {
+                        // a panic fails the handshake of its connection, not the worker
+                        if let Err(err) = catch_panic("handshake job", || {
+                            job(None);
+                            Ok(())
+                        }) {
+                            match &on_error {
+                                Some(on_error) => on_error(&err),
+                                None => tracing::error!("handshake job failed: {err}"),
+                            }
+                        }
+                    }
+                })
+                .map_err(|err| {
+                    PeerNetError::SocketError.new("spawn handshake_worker", err, None)
+                })?;
+        }
+        Ok(HandshakePool { sender })
+    }
+
+    /// Queue the handshake of a connection, it's run at once as refused if the queue is full
+    pub(crate) fn submit(&self, job: HandshakeJob) {
+        if let Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) =
+            self.sender.try_send(job)
+        {
+            job(Some(PeerNetError::HandshakeError.error(
+                "handshake pool",
+                Some("no worker available".to_string()),
+            )));
+        }
+    }
+}
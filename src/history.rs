@@ -0,0 +1,139 @@
+//! Bounded history of the lifecycle events of the connections, see
+//! `PeerNetManager::recent_events`
+//!
+//! The events are recorded under the lock of the `ActiveConnections` they change, the oldest
+//! ones are dropped once `PeerNetFeatures::connection_history` events are kept.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use crate::audit::{AuditRecord, SharedAuditSink};
+use crate::peer::PeerConnectionType;
+
+/// Why a connection was removed, see `ConnectionEventKind::Disconnected`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Removed by us with `ActiveConnections::remove_connection`
+    Closed,
+    /// Closed by the other side
+    ClosedByPeer,
+    /// A read or a write on the connection failed
+    Error(String),
+    /// The messages handler returned an error for a message of the peer
+    HandlerError(String),
+    /// Disconnected to make room for a trusted peer, see `TrustedPeers::evict_untrusted`
+    EvictedForTrustedPeer,
+    /// Disconnected to make room for an incoming connection, see
+    /// `PeerNetFeatures::eviction_policy`
+    Evicted,
+    /// Disconnected by the `PeerRotation` of a `ConnectionSupervisor`
+    Rotated,
+    /// Refused by new `PeerIdRules`, see `PeerNetManager::set_peer_id_rules`
+    Denied,
+    /// The peer is ourselves, we dialed one of our own addresses
+    SelfConnection,
+    /// The `MessageFilter` refused a message of the peer, with its reason
+    Filtered(String),
+    /// The peer held the most memory when a cap of the `MemoryBudget` was exceeded
+    MemoryBudget,
+}
+
+impl DisconnectReason {
+    /// Whether we decided to close the connection while it was working, its queued messages
+    /// are then sent first with `PeerNetFeatures::disconnect_flush`
+    pub fn is_orderly(&self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Closed
+                | DisconnectReason::EvictedForTrustedPeer
+                | DisconnectReason::Evicted
+                | DisconnectReason::Rotated
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEventKind<Id> {
+    /// A listener accepted the connection, its handshake starts
+    Accepted,
+    /// Refused by the limits of the connections, before or after the handshake
+    Refused { direction: PeerConnectionType },
+    HandshakeSucceeded {
+        peer_id: Id,
+        direction: PeerConnectionType,
+    },
+    HandshakeFailed {
+        direction: PeerConnectionType,
+        reason: String,
+    },
+    Disconnected {
+        peer_id: Id,
+        reason: DisconnectReason,
+        /// Messages still queued for the peer that were never sent
+        dropped_messages: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionEvent<Id> {
+    pub time: SystemTime,
+    /// Address of the other side of the connection
+    pub addr: SocketAddr,
+    pub kind: ConnectionEventKind<Id>,
+}
+
+pub struct ConnectionHistory<Id> {
+    capacity: usize,
+    events: VecDeque<ConnectionEvent<Id>>,
+    /// Also receives the events of the connection attempts, see `PeerNetFeatures::audit`
+    audit: Option<SharedAuditSink>,
+}
+
+impl<Id: Debug> Debug for ConnectionHistory<Id> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionHistory")
+            .field("capacity", &self.capacity)
+            .field("events", &self.events)
+            .field("audit", &self.audit.is_some())
+            .finish()
+    }
+}
+
+impl<Id: Clone + Debug> ConnectionHistory<Id> {
+    /// Keeps the last `capacity` events, nothing is recorded if it's 0
+    pub fn new(capacity: usize) -> ConnectionHistory<Id> {
+        ConnectionHistory {
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+            audit: None,
+        }
+    }
+
+    pub fn set_audit(mut self, audit: Option<SharedAuditSink>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn record(&mut self, addr: SocketAddr, kind: ConnectionEventKind<Id>) {
+        let time = SystemTime::now();
+        if let Some(audit) = &self.audit {
+            if let Some(record) = AuditRecord::from_event(time, addr, &kind) {
+                audit.record(&record);
+            }
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(ConnectionEvent { time, addr, kind });
+    }
+
+    /// The last `n` events, the most recent first
+    pub fn recent(&self, n: usize) -> Vec<ConnectionEvent<Id>> {
+        self.events.iter().rev().take(n).cloned().collect()
+    }
+}
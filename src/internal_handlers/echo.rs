@@ -0,0 +1,165 @@
+//! Echo protocol to check that a node is reachable, without a handshake nor a message handler
+//!
+//! The `EchoServer` answers the pings received on a UDP socket, usually bound to the port of a
+//! TCP listener of the node, with the time it received them. `echo_ping` sends a ping and waits
+//! for the answer, e.g. for operators to check a node from outside, or for the `Tester` (see
+//! `Tester::start_with_echo`).
+//!
+//! A ping is `PING`, a nonce and the time it was sent, padded to the size of a pong so that the
+//! server never sends more than it receives to a spoofed address. A pong is `PONG`, the nonce
+//! and the time the ping was sent, followed by the time the server received it. The times are
+//! microseconds since the UNIX epoch, in big endian as the nonce.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::{PeerNetError, PeerNetResult};
+
+const PING: u8 = 0;
+const PONG: u8 = 1;
+const MESSAGE_LEN: usize = 1 + 3 * 8;
+/// Time between two checks of the stop flag of the server
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Answer of a node to `echo_ping`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EchoReply {
+    pub rtt: Duration,
+    /// Clock of the node when it received the ping
+    pub remote_time: SystemTime,
+}
+
+/// Answers the pings of `echo_ping` in a background thread
+pub struct EchoServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl EchoServer {
+    /// Bind a UDP socket to `addr` and answer the pings it receives. A QUIC listener can't use
+    /// the same port.
+    pub fn start(addr: SocketAddr) -> PeerNetResult<EchoServer> {
+        let socket = UdpSocket::bind(addr)
+            .map_err(|err| PeerNetError::ListenerError.new("bind echo server", err, None))?;
+        let local_addr = socket
+            .local_addr()
+            .map_err(|err| PeerNetError::SocketError.new("echo server addr", err, None))?;
+        socket
+            .set_read_timeout(Some(STOP_POLL_INTERVAL))
+            .map_err(|err| PeerNetError::SocketError.new("echo server timeout", err, None))?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = std::thread::Builder::new()
+            .name("echo_server".to_string())
+            .spawn({
+                let stopped = stopped.clone();
+                move || serve(&socket, &stopped)
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn echo_server", err, None))?;
+        Ok(EchoServer {
+            local_addr,
+            stopped,
+            handle,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::Release);
+        let _ = self.handle.join();
+    }
+}
+
+fn serve(socket: &UdpSocket, stopped: &AtomicBool) {
+    let mut buf = [0; MESSAGE_LEN];
+    while !stopped.load(Ordering::Acquire) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => {
+                tracing::debug!("echo server receive failed: {:?}", err);
+                continue;
+            }
+        };
+        if len != MESSAGE_LEN || buf[0] != PING {
+            continue;
+        }
+        buf[0] = PONG;
+        buf[17..].copy_from_slice(&now_micros().to_be_bytes());
+        if let Err(err) = socket.send_to(&buf, from) {
+            tracing::debug!(%from, "echo server send failed: {:?}", err);
+        }
+    }
+}
+
+/// Ping the `EchoServer` at `addr` and wait at most `timeout` for its answer
+pub fn echo_ping(addr: SocketAddr, timeout: Duration) -> PeerNetResult<EchoReply> {
+    let local_addr: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(local_addr)
+        .and_then(|socket| socket.connect(addr).map(|_| socket))
+        .map_err(|err| PeerNetError::SocketError.new("echo ping socket", err, None))?;
+    let nonce: u64 = rand::random();
+    let mut ping = [0; MESSAGE_LEN];
+    ping[0] = PING;
+    ping[1..9].copy_from_slice(&nonce.to_be_bytes());
+    ping[9..17].copy_from_slice(&now_micros().to_be_bytes());
+    let start = Instant::now();
+    socket
+        .send(&ping)
+        .map_err(|err| PeerNetError::SendError.new("echo ping", err, None))?;
+
+    let mut pong = [0; MESSAGE_LEN];
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            return Err(PeerNetError::TimeOut.error("echo ping", Some(format!("{}", addr))));
+        }
+        socket
+            .set_read_timeout(Some(remaining))
+            .map_err(|err| PeerNetError::SocketError.new("echo ping timeout", err, None))?;
+        match socket.recv(&mut pong) {
+            // ignore the late answers to other pings
+            Ok(len) if len == MESSAGE_LEN && pong[0] == PONG && pong[1..17] == ping[1..17] => {
+                let remote_micros = u64::from_be_bytes(pong[17..].try_into().unwrap());
+                return Ok(EchoReply {
+                    rtt: start.elapsed(),
+                    remote_time: UNIX_EPOCH + Duration::from_micros(remote_micros),
+                });
+            }
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(PeerNetError::TimeOut.error("echo ping", Some(format!("{}", addr))));
+            }
+            Err(err) => return Err(PeerNetError::ReceiveError.new("echo ping", err, None)),
+        }
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
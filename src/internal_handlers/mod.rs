@@ -1 +1,7 @@
+#[cfg(feature = "diagnostics")]
+pub mod echo;
 pub mod peer_management;
+pub mod ping;
+pub mod relay;
+pub mod supervisor;
+pub mod tester;
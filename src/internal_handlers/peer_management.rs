@@ -0,0 +1,673 @@
+//! Announcement of the listeners of the peers and exchange of the known peers
+//!
+//! Each peer announces its listeners in an `Announcement` signed with its key. When a peer
+//! announces itself, we store it in the `PeerDB` and answer with the announcements of the peers
+//! we know (`LIST_PEERS`). The list is a random sample of at most `max_peers_in_list` peers and
+//! each peer is answered at most once per `list_interval`, so that small announcements can't be
+//! used to make us send large lists in a loop. Every announcement is checked against the id of the peer it is for
+//! before being stored, so a peer can't advertise addresses in the name of another.
+//!
+//! The `PeerDB` holds at most `max_peers` peers. When it's full, a new peer replaces the peer
+//! found unreachable by the tester, or else the one with the oldest announcement, if the new
+//! announcement is more recent. The lists received from a peer are also taken at most once per
+//! `list_interval`, so that a peer can't fill the `PeerDB` with the ids it makes up.
+//!
+//! Only the most recent announcement of a peer is kept, so a replayed announcement can't replace
+//! a newer one. The announcements dated in the future beyond `MAX_ANNOUNCEMENT_CLOCK_DRIFT` are
+//! ignored, not to pin the listeners of a peer forever, as well as those older than the
+//! `max_announcement_age` of the handler if it's set.
+//!
+//! Signing, verification and the encoding of the ids are left to the application through
+//! `PeerManagementHooks`. `PeerManagementHandler` is registered in a `MessageHandlers`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, RwLock};
+use rand::seq::IteratorRandom;
+
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::handlers::{MessageHandler, RoutedSerializer};
+use crate::messages::{Bytes, MessagesSerializer};
+use crate::peer::{PeerHandle, SendChannels};
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+pub type ListenersMap = HashMap<SocketAddr, TransportType>;
+
+/// Maximum number of announcements sent in a `LIST_PEERS`
+pub const MAX_PEERS_IN_LIST: usize = 1000;
+/// Number of announcements sent in a `LIST_PEERS` by default
+pub const DEFAULT_MAX_PEERS_IN_LIST: usize = 100;
+/// Minimum time between two `LIST_PEERS` sent to a peer (or taken from it) by default
+pub const DEFAULT_LIST_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of peers kept in a `PeerDB` by default
+pub const DEFAULT_MAX_PEERS_IN_DB: usize = 10_000;
+/// Maximum number of listeners in an announcement
+pub const MAX_LISTENERS_PER_PEER: usize = 100;
+/// How far in the future the timestamp of an announcement can be, for the clocks of the peers
+/// that are ahead of ours
+pub const MAX_ANNOUNCEMENT_CLOCK_DRIFT: Duration = Duration::from_secs(60);
+
+/// Prefix of the data signed for an announcement, so that a signature made with the same key
+/// for another purpose (e.g. a handshake) can't be taken for an announcement
+pub const ANNOUNCEMENT_SIGNATURE_TAG: &[u8] = b"peernet announcement v1";
+
+const NEW_PEER_CONNECTED: u8 = 0;
+const LIST_PEERS: u8 = 1;
+
+/// Signing and verification of the announcements, encoding of the ids of the peers
+pub trait PeerManagementHooks<Id>: Clone + Send + Sync + 'static {
+    /// Sign `data` with our key
+    fn sign(&self, data: &[u8]) -> PeerNetResult<Vec<u8>>;
+
+    /// Check that `signature` of `data` has been made by the key of `peer_id`
+    fn verify(&self, peer_id: &Id, data: &[u8], signature: &[u8]) -> PeerNetResult<()>;
+
+    fn serialize_peer_id(&self, peer_id: &Id, buffer: &mut Vec<u8>) -> PeerNetResult<()>;
+
+    /// Read a peer id at the start of `data`, returns it with the number of bytes read
+    fn deserialize_peer_id(&self, data: &[u8]) -> PeerNetResult<(Id, usize)>;
+}
+
+/// Listeners of a peer, signed by it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announcement {
+    pub listeners: ListenersMap,
+    /// Milliseconds since the unix epoch, only the most recent announcement of a peer is kept
+    pub timestamp: u64,
+    pub signature: Vec<u8>,
+}
+
+impl Announcement {
+    /// Create an announcement of `listeners` signed with the hooks
+    pub fn new<Id, H: PeerManagementHooks<Id>>(
+        listeners: ListenersMap,
+        hooks: &H,
+    ) -> PeerNetResult<Self> {
+        Self::with_timestamp(listeners, now_millis()?, hooks)
+    }
+
+    /// Same as `new` with the given `timestamp` instead of the current time
+    pub fn with_timestamp<Id, H: PeerManagementHooks<Id>>(
+        listeners: ListenersMap,
+        timestamp: u64,
+        hooks: &H,
+    ) -> PeerNetResult<Self> {
+        if listeners.len() > MAX_LISTENERS_PER_PEER {
+            return Err(PeerNetError::InvalidMessage.error(
+                "create announcement",
+                Some(format!("too many listeners: {}", listeners.len())),
+            ));
+        }
+        let mut announcement = Announcement {
+            listeners,
+            timestamp,
+            signature: Vec::new(),
+        };
+        announcement.signature = hooks.sign(&announcement.signed_data())?;
+        Ok(announcement)
+    }
+
+    /// Check that the announcement has been signed by `peer_id`
+    pub fn verify<Id, H: PeerManagementHooks<Id>>(
+        &self,
+        peer_id: &Id,
+        hooks: &H,
+    ) -> PeerNetResult<()> {
+        hooks.verify(peer_id, &self.signed_data(), &self.signature)
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = ANNOUNCEMENT_SIGNATURE_TAG.to_vec();
+        self.write_content(&mut data);
+        data
+    }
+
+    /// The listeners and the timestamp, as sent
+    fn write_content(&self, buffer: &mut Vec<u8>) {
+        write_listeners(&self.listeners, buffer);
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+    }
+
+    pub(crate) fn write(&self, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        if self.signature.len() > u16::MAX as usize {
+            return Err(PeerNetError::InvalidMessage.error(
+                "serialize announcement",
+                Some(format!("signature too long: {}", self.signature.len())),
+            ));
+        }
+        self.write_content(buffer);
+        buffer.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(&self.signature);
+        Ok(())
+    }
+
+    pub(crate) fn read(reader: &mut Reader) -> PeerNetResult<Self> {
+        let listeners = read_listeners(reader)?;
+        let timestamp = u64::from_be_bytes(reader.read_array()?);
+        let signature_len = u16::from_be_bytes(reader.read_array()?) as usize;
+        let signature = reader.read_bytes(signature_len)?.to_vec();
+        Ok(Announcement {
+            listeners,
+            timestamp,
+            signature,
+        })
+    }
+}
+
+/// Milliseconds since the unix epoch
+fn now_millis() -> PeerNetResult<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| PeerNetError::SignError.new("announcement timestamp", err, None))?
+        .as_millis() as u64)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerManagementMessage<Id> {
+    /// Announcement of the peer sending it
+    NewPeerConnected(Announcement),
+    /// Announcements of the peers known by the peer sending it
+    ListPeers(Vec<(Id, Announcement)>),
+}
+
+/// Serializer of the `PeerManagementMessage`. Must be wrapped in a `RoutedSerializer` with the
+/// handler id the `PeerManagementHandler` is registered with.
+#[derive(Clone)]
+pub struct PeerManagementMessageSerializer<H> {
+    pub hooks: H,
+}
+
+impl<Id, H: PeerManagementHooks<Id>> MessagesSerializer<PeerManagementMessage<Id>>
+    for PeerManagementMessageSerializer<H>
+{
+    fn serialize(
+        &self,
+        message: &PeerManagementMessage<Id>,
+        buffer: &mut Vec<u8>,
+    ) -> PeerNetResult<()> {
+        match message {
+            PeerManagementMessage::NewPeerConnected(announcement) => {
+                buffer.push(NEW_PEER_CONNECTED);
+                announcement.write(buffer)
+            }
+            PeerManagementMessage::ListPeers(peers) => {
+                if peers.len() > MAX_PEERS_IN_LIST {
+                    return Err(PeerNetError::InvalidMessage.error(
+                        "serialize list peers",
+                        Some(format!("too many peers: {}", peers.len())),
+                    ));
+                }
+                buffer.push(LIST_PEERS);
+                buffer.extend_from_slice(&(peers.len() as u16).to_be_bytes());
+                for (peer_id, announcement) in peers {
+                    self.hooks.serialize_peer_id(peer_id, buffer)?;
+                    announcement.write(buffer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<H> PeerManagementMessageSerializer<H> {
+    pub fn deserialize<Id>(&self, data: &[u8]) -> PeerNetResult<PeerManagementMessage<Id>>
+    where
+        H: PeerManagementHooks<Id>,
+    {
+        let mut reader = Reader { data, position: 0 };
+        let [message_type] = reader.read_array()?;
+        let message = match message_type {
+            NEW_PEER_CONNECTED => {
+                PeerManagementMessage::NewPeerConnected(Announcement::read(&mut reader)?)
+            }
+            LIST_PEERS => {
+                let nb_peers = u16::from_be_bytes(reader.read_array()?) as usize;
+                if nb_peers > MAX_PEERS_IN_LIST {
+                    return Err(PeerNetError::InvalidMessage.error(
+                        "deserialize list peers",
+                        Some(format!("too many peers: {}", nb_peers)),
+                    ));
+                }
+                let mut peers = Vec::with_capacity(nb_peers);
+                for _ in 0..nb_peers {
+                    let (peer_id, len) =
+                        self.hooks.deserialize_peer_id(&data[reader.position..])?;
+                    reader.read_bytes(len)?;
+                    peers.push((peer_id, Announcement::read(&mut reader)?));
+                }
+                PeerManagementMessage::ListPeers(peers)
+            }
+            _ => {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "deserialize peer management message",
+                    Some(format!("unknown message type: {}", message_type)),
+                ))
+            }
+        };
+        if reader.position != data.len() {
+            return Err(PeerNetError::InvalidMessage.error(
+                "deserialize peer management message",
+                Some(format!("{} trailing bytes", data.len() - reader.position)),
+            ));
+        }
+        Ok(message)
+    }
+}
+
+/// What we know about a peer
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    /// Last announcement of the peer
+    pub announcement: Announcement,
+    /// Whether we could dial one of the listeners of the announcement, `None` until tested
+    pub reachable: Option<bool>,
+    /// When the listeners were last dialed by the tester
+    pub last_test: Option<Instant>,
+}
+
+impl PeerInfo {
+    fn new(announcement: Announcement) -> Self {
+        PeerInfo {
+            announcement,
+            reachable: None,
+            last_test: None,
+        }
+    }
+
+    /// The peers evicted first from a full `PeerDB` are the lowest: the unreachable ones, then
+    /// the untested ones, then the oldest announcements
+    fn eviction_rank(&self) -> (u8, u64) {
+        let reachable = match self.reachable {
+            Some(false) => 0,
+            None => 1,
+            Some(true) => 2,
+        };
+        (reachable, self.announcement.timestamp)
+    }
+}
+
+/// Peers announced to us, directly or through the lists of the other peers
+#[derive(Debug)]
+pub struct PeerDB<Id: PeerId> {
+    pub peers: HashMap<Id, PeerInfo>,
+    max_peers: usize,
+}
+
+impl<Id: PeerId> Default for PeerDB<Id> {
+    fn default() -> Self {
+        PeerDB {
+            peers: HashMap::new(),
+            max_peers: DEFAULT_MAX_PEERS_IN_DB,
+        }
+    }
+}
+
+impl<Id: PeerId> PeerDB<Id> {
+    /// Keep at most `max_peers` peers, the ones beyond are evicted now
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+        while self.peers.len() > self.max_peers {
+            let Some(peer_id) = self.eviction_candidate() else {
+                break;
+            };
+            self.peers.remove(&peer_id);
+        }
+    }
+
+    fn eviction_candidate(&self) -> Option<Id> {
+        self.peers
+            .iter()
+            .min_by_key(|(_, info)| info.eviction_rank())
+            .map(|(peer_id, _)| peer_id.clone())
+    }
+
+    /// Store the announcement of `peer_id` if it is more recent than the one we have. A new
+    /// peer is only stored in a full DB if it ranks above a peer to evict, see the module docs.
+    /// Returns whether it has been stored. The announcement must have been verified.
+    pub fn insert_announcement(&mut self, peer_id: Id, announcement: Announcement) -> bool {
+        match self.peers.get_mut(&peer_id) {
+            Some(info) if info.announcement.timestamp >= announcement.timestamp => false,
+            Some(info) => {
+                // new listeners have to be tested again
+                if info.announcement.listeners != announcement.listeners {
+                    info.reachable = None;
+                    info.last_test = None;
+                }
+                info.announcement = announcement;
+                true
+            }
+            None => {
+                let info = PeerInfo::new(announcement);
+                if self.peers.len() >= self.max_peers {
+                    let evicted = self.eviction_candidate().filter(|evicted| {
+                        self.peers[evicted].eviction_rank() < info.eviction_rank()
+                    });
+                    let Some(evicted) = evicted else {
+                        return false;
+                    };
+                    self.peers.remove(&evicted);
+                }
+                self.peers.insert(peer_id, info);
+                true
+            }
+        }
+    }
+
+    /// Record the result of a test of the listeners of `peer_id`
+    pub fn set_reachable(&mut self, peer_id: &Id, reachable: bool) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.reachable = Some(reachable);
+            info.last_test = Some(Instant::now());
+        }
+    }
+}
+
+pub type SharedPeerDB<Id> = Arc<RwLock<PeerDB<Id>>>;
+
+/// Handler of the `PeerManagementMessage`, to register in a `MessageHandlers` with `handler_id`
+#[derive(Clone)]
+pub struct PeerManagementHandler<Id: PeerId, H> {
+    handler_id: u64,
+    our_id: Id,
+    hooks: H,
+    // only send the peers whose listeners have been reached by the tester
+    gossip_only_reachable: bool,
+    // the older announcements are ignored
+    max_announcement_age: Option<Duration>,
+    max_peers_in_list: usize,
+    list_interval: Duration,
+    // when we last sent a list to each peer
+    last_lists: Arc<Mutex<HashMap<Id, Instant>>>,
+    // when we last took a list from each peer
+    last_received_lists: Arc<Mutex<HashMap<Id, Instant>>>,
+    pub peer_db: SharedPeerDB<Id>,
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> PeerManagementHandler<Id, H> {
+    pub fn new<Ctx: Context<Id>>(handler_id: u64, context: &Ctx, hooks: H) -> Self {
+        PeerManagementHandler {
+            handler_id,
+            our_id: context.get_peer_id(),
+            hooks,
+            gossip_only_reachable: false,
+            max_announcement_age: None,
+            max_peers_in_list: DEFAULT_MAX_PEERS_IN_LIST,
+            list_interval: DEFAULT_LIST_INTERVAL,
+            last_lists: Arc::new(Mutex::new(HashMap::new())),
+            last_received_lists: Arc::new(Mutex::new(HashMap::new())),
+            peer_db: Arc::new(RwLock::new(PeerDB::default())),
+        }
+    }
+
+    /// Only send to the other peers the peers marked as reachable by a `Tester`
+    pub fn set_gossip_only_reachable(mut self, gossip_only_reachable: bool) -> Self {
+        self.gossip_only_reachable = gossip_only_reachable;
+        self
+    }
+
+    /// Ignore the announcements older than `max_announcement_age`, e.g. replayed from a peer
+    /// that isn't listening there anymore. They are all accepted by default.
+    pub fn set_max_announcement_age(mut self, max_announcement_age: Duration) -> Self {
+        self.max_announcement_age = Some(max_announcement_age);
+        self
+    }
+
+    /// Send at most `max_peers_in_list` peers in the answers to the announcements, up to
+    /// `MAX_PEERS_IN_LIST`
+    pub fn set_max_peers_in_list(mut self, max_peers_in_list: usize) -> Self {
+        self.max_peers_in_list = max_peers_in_list.min(MAX_PEERS_IN_LIST);
+        self
+    }
+
+    /// Answer the announcements of a peer with our peers at most once per `list_interval`, and
+    /// take the lists of a peer at most once per `list_interval`
+    pub fn set_list_interval(mut self, list_interval: Duration) -> Self {
+        self.list_interval = list_interval;
+        self
+    }
+
+    /// Keep at most `max_peers_in_db` peers in the `PeerDB`, `DEFAULT_MAX_PEERS_IN_DB` by default
+    pub fn set_max_peers_in_db(self, max_peers_in_db: usize) -> Self {
+        self.peer_db.write().set_max_peers(max_peers_in_db);
+        self
+    }
+
+    /// Whether a list can be sent to `peer_id`, records it as sent if so
+    fn take_list_slot(&self, peer_id: &Id) -> bool {
+        self.take_slot(&self.last_lists, peer_id)
+    }
+
+    /// Whether a list of `peer_id` can be taken, records it as taken if so
+    fn take_received_list_slot(&self, peer_id: &Id) -> bool {
+        self.take_slot(&self.last_received_lists, peer_id)
+    }
+
+    fn take_slot(&self, slots: &Mutex<HashMap<Id, Instant>>, peer_id: &Id) -> bool {
+        let now = Instant::now();
+        let mut slots = slots.lock();
+        slots.retain(|_, last| now.saturating_duration_since(*last) < self.list_interval);
+        if slots.contains_key(peer_id) {
+            return false;
+        }
+        slots.insert(peer_id.clone(), now);
+        true
+    }
+
+    /// Serializer of the messages for the handler of the remote peers
+    pub fn serializer(&self) -> RoutedSerializer<PeerManagementMessageSerializer<H>> {
+        RoutedSerializer {
+            handler_id: self.handler_id,
+            serializer: PeerManagementMessageSerializer {
+                hooks: self.hooks.clone(),
+            },
+        }
+    }
+
+    /// Announce our `listeners` to a peer, it will answer with the peers it knows
+    pub fn announce(
+        &self,
+        listeners: &ListenersMap,
+        send_channels: &SendChannels,
+    ) -> PeerNetResult<()> {
+        let announcement = Announcement::new(listeners.clone(), &self.hooks)?;
+        send_channels.send(
+            &self.serializer(),
+            PeerManagementMessage::<Id>::NewPeerConnected(announcement),
+            false,
+        )
+    }
+
+    /// Verify and store the announcement of `peer_id`
+    fn receive_announcement(&self, peer_id: Id, announcement: Announcement) -> PeerNetResult<()> {
+        if peer_id == self.our_id {
+            return Ok(());
+        }
+        if announcement.listeners.len() > MAX_LISTENERS_PER_PEER {
+            return Err(PeerNetError::InvalidMessage.error(
+                "receive announcement",
+                Some(format!(
+                    "too many listeners: {}",
+                    announcement.listeners.len()
+                )),
+            ));
+        }
+        announcement.verify(&peer_id, &self.hooks)?;
+        let now = now_millis()?;
+        let too_recent =
+            announcement.timestamp > now + MAX_ANNOUNCEMENT_CLOCK_DRIFT.as_millis() as u64;
+        let too_old = self.max_announcement_age.map_or(false, |max_age| {
+            announcement.timestamp < now.saturating_sub(max_age.as_millis() as u64)
+        });
+        if too_recent || too_old {
+            tracing::debug!(
+                ?peer_id,
+                timestamp = announcement.timestamp,
+                "ignoring announcement out of the accepted time range"
+            );
+            return Ok(());
+        }
+        self.peer_db
+            .write()
+            .insert_announcement(peer_id, announcement);
+        Ok(())
+    }
+
+    fn handle_message(
+        &self,
+        data: Bytes,
+        peer_id: &Id,
+    ) -> PeerNetResult<PeerManagementMessage<Id>> {
+        let message = self.serializer().serializer.deserialize(&data)?;
+        match &message {
+            PeerManagementMessage::NewPeerConnected(announcement) => {
+                self.receive_announcement(peer_id.clone(), announcement.clone())?;
+            }
+            PeerManagementMessage::ListPeers(peers) => {
+                if !self.take_received_list_slot(peer_id) {
+                    tracing::debug!(?peer_id, "ignoring list received too soon");
+                    return Ok(message);
+                }
+                for (id, announcement) in peers {
+                    if let Err(err) = self.receive_announcement(id.clone(), announcement.clone()) {
+                        // a refused list doesn't count, the peer is disconnected for it
+                        self.last_received_lists.lock().remove(peer_id);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        Ok(message)
+    }
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> MessageHandler<Id> for PeerManagementHandler<Id, H> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        self.handle_message(data, peer_id).map(|_| ())
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        let message = self.handle_message(data, &peer.peer_id)?;
+        if let PeerManagementMessage::NewPeerConnected(_) = message {
+            if !self.take_list_slot(&peer.peer_id) {
+                tracing::debug!(peer_id = ?peer.peer_id, "list already sent recently");
+                return Ok(());
+            }
+            let peer_db = self.peer_db.read();
+            let peers: Vec<(Id, Announcement)> = peer_db
+                .peers
+                .iter()
+                .filter(|(id, info)| {
+                    **id != peer.peer_id
+                        && (!self.gossip_only_reachable || info.reachable == Some(true))
+                })
+                .choose_multiple(&mut rand::thread_rng(), self.max_peers_in_list)
+                .into_iter()
+                .map(|(id, info)| (id.clone(), info.announcement.clone()))
+                .collect();
+            drop(peer_db);
+            if !peers.is_empty() {
+                peer.send_channels.send(
+                    &self.serializer(),
+                    PeerManagementMessage::ListPeers(peers),
+                    false,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cursor on a received message
+pub(crate) struct Reader<'a> {
+    pub(crate) data: &'a [u8],
+    pub(crate) position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn read_bytes(&mut self, len: usize) -> PeerNetResult<&'a [u8]> {
+        // the length can come from the message, it must not overflow
+        let bytes = self
+            .position
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| {
+                PeerNetError::InvalidMessage.error(
+                    "read peer management message",
+                    Some(format!("truncated at {}", self.position)),
+                )
+            })?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn read_array<const N: usize>(&mut self) -> PeerNetResult<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+}
+
+// listeners are sorted so that the signed data doesn't depend on the order of the map
+pub(crate) fn write_listeners(listeners: &ListenersMap, buffer: &mut Vec<u8>) {
+    let mut listeners: Vec<_> = listeners.iter().collect();
+    listeners.sort_by_key(|(addr, _)| **addr);
+    buffer.extend_from_slice(&(listeners.len() as u16).to_be_bytes());
+    for (addr, transport_type) in listeners {
+        buffer.push(*transport_type as u8);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buffer.push(4);
+                buffer.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buffer.push(6);
+                buffer.extend_from_slice(&ip.octets());
+            }
+        }
+        buffer.extend_from_slice(&addr.port().to_be_bytes());
+    }
+}
+
+pub(crate) fn read_listeners(reader: &mut Reader) -> PeerNetResult<ListenersMap> {
+    let nb_listeners = u16::from_be_bytes(reader.read_array()?) as usize;
+    if nb_listeners > MAX_LISTENERS_PER_PEER {
+        return Err(PeerNetError::InvalidMessage.error(
+            "read listeners",
+            Some(format!("too many listeners: {}", nb_listeners)),
+        ));
+    }
+    let mut listeners = HashMap::with_capacity(nb_listeners);
+    for _ in 0..nb_listeners {
+        let [transport_type, ip_version] = reader.read_array()?;
+        let transport_type = match transport_type {
+            0 => TransportType::Tcp,
+            1 => TransportType::Quic,
+            #[cfg(feature = "testing")]
+            2 => TransportType::Mock,
+            _ => {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "read listeners",
+                    Some(format!("unknown transport: {}", transport_type)),
+                ))
+            }
+        };
+        let ip = match ip_version {
+            4 => IpAddr::V4(Ipv4Addr::from(reader.read_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(reader.read_array::<16>()?)),
+            _ => {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "read listeners",
+                    Some(format!("unknown ip version: {}", ip_version)),
+                ))
+            }
+        };
+        let port = u16::from_be_bytes(reader.read_array()?);
+        listeners.insert(SocketAddr::new(ip, port), transport_type);
+    }
+    Ok(listeners)
+}
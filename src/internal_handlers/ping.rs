@@ -0,0 +1,299 @@
+//! Round-trip time of the connected peers
+//!
+//! The `Pinger` periodically sends a `Ping` with a random nonce to each connected peer, which
+//! answers with a `Pong` carrying the same nonce. The time between the two is recorded in a
+//! `LatencyHistogram` per peer, read with `PingHandler::latency`. Only the last ping sent to a
+//! peer is waited for: a pong arriving after the next ping is ignored.
+//!
+//! `PingHandler` is registered in a `MessageHandlers` on both sides of the connections.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{unbounded, RecvTimeoutError, Sender};
+use parking_lot::{Mutex, RwLock};
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::handlers::{MessageHandler, RoutedSerializer};
+use crate::messages::{Bytes, MessagesSerializer};
+use crate::network_manager::SharedActiveConnections;
+use crate::peer::{PeerHandle, SendChannels};
+use crate::peer_id::PeerId;
+
+use super::peer_management::Reader;
+
+const PING: u8 = 0;
+const PONG: u8 = 1;
+
+// 4 buckets per power of two of microseconds, from 1µs to about 2 minutes
+const BUCKETS_PER_POWER: usize = 4;
+const NB_BUCKETS: usize = 27 * BUCKETS_PER_POWER;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingMessage {
+    Ping(u64),
+    Pong(u64),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PingMessageSerializer;
+
+impl MessagesSerializer<PingMessage> for PingMessageSerializer {
+    fn serialize(&self, message: &PingMessage, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        let (message_type, nonce) = match message {
+            PingMessage::Ping(nonce) => (PING, nonce),
+            PingMessage::Pong(nonce) => (PONG, nonce),
+        };
+        buffer.push(message_type);
+        buffer.extend_from_slice(&nonce.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl PingMessageSerializer {
+    pub fn deserialize(&self, data: &[u8]) -> PeerNetResult<PingMessage> {
+        let mut reader = Reader { data, position: 0 };
+        let [message_type] = reader.read_array()?;
+        let nonce = u64::from_be_bytes(reader.read_array()?);
+        if reader.position != data.len() {
+            return Err(PeerNetError::InvalidMessage.error(
+                "deserialize ping message",
+                Some(format!("{} trailing bytes", data.len() - reader.position)),
+            ));
+        }
+        match message_type {
+            PING => Ok(PingMessage::Ping(nonce)),
+            PONG => Ok(PingMessage::Pong(nonce)),
+            _ => Err(PeerNetError::InvalidMessage.error(
+                "deserialize ping message",
+                Some(format!("unknown message type: {}", message_type)),
+            )),
+        }
+    }
+}
+
+/// Round-trip times in buckets of exponential width, the percentiles are the upper bounds of
+/// the buckets so they are at most 25% above the actual values
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    buckets: [u64; NB_BUCKETS],
+    nb_samples: u64,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: [0; NB_BUCKETS],
+            nb_samples: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+/// Percentiles of the round-trip times of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub nb_samples: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, rtt: Duration) {
+        let micros = rtt.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_of(micros)] += 1;
+        self.nb_samples += 1;
+        self.max = self.max.max(rtt);
+    }
+
+    /// Upper bound of the round-trip times of the `quantile` (between 0 and 1) of the samples
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.nb_samples as f64).ceil() as u64).max(1);
+        let mut count = 0;
+        for (index, nb_samples) in self.buckets.iter().enumerate() {
+            count += nb_samples;
+            if count >= rank {
+                return Duration::from_micros(bucket_upper_bound(index)).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// `None` until a sample is recorded
+    pub fn stats(&self) -> Option<LatencyStats> {
+        if self.nb_samples == 0 {
+            return None;
+        }
+        Some(LatencyStats {
+            nb_samples: self.nb_samples,
+            p50: self.percentile(0.5),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            max: self.max,
+        })
+    }
+}
+
+fn bucket_of(micros: u64) -> usize {
+    if micros == 0 {
+        return 0;
+    }
+    let power = 63 - micros.leading_zeros() as usize;
+    // the 2 bits after the leading one select the bucket in the power of two
+    let sub_bucket = ((micros as u128) << 2 >> power) as usize & (BUCKETS_PER_POWER - 1);
+    (power * BUCKETS_PER_POWER + sub_bucket).min(NB_BUCKETS - 1)
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+    let power = index / BUCKETS_PER_POWER;
+    let sub_bucket = (index % BUCKETS_PER_POWER) as u64;
+    (((BUCKETS_PER_POWER as u64 + sub_bucket + 1) << power) >> 2).max(1)
+}
+
+/// Handler of the `PingMessage`, to register in a `MessageHandlers` with `handler_id`
+#[derive(Clone)]
+pub struct PingHandler<Id: PeerId> {
+    handler_id: u64,
+    // nonce and time of the last ping sent to each peer
+    pending: Arc<Mutex<HashMap<Id, (u64, Instant)>>>,
+    latencies: Arc<RwLock<HashMap<Id, LatencyHistogram>>>,
+}
+
+impl<Id: PeerId> PingHandler<Id> {
+    pub fn new(handler_id: u64) -> Self {
+        PingHandler {
+            handler_id,
+            pending: Default::default(),
+            latencies: Default::default(),
+        }
+    }
+
+    /// Serializer of the messages for the handler of the remote peers
+    pub fn serializer(&self) -> RoutedSerializer<PingMessageSerializer> {
+        RoutedSerializer {
+            handler_id: self.handler_id,
+            serializer: PingMessageSerializer,
+        }
+    }
+
+    /// Send a ping to `peer_id`, the round-trip time is recorded when it answers. Doesn't wait
+    /// if its send channels are full.
+    pub fn ping(&self, peer_id: &Id, send_channels: &SendChannels) -> PeerNetResult<()> {
+        let nonce = rand::random();
+        self.pending
+            .lock()
+            .insert(peer_id.clone(), (nonce, Instant::now()));
+        send_channels.try_send(&self.serializer(), PingMessage::Ping(nonce), true)
+    }
+
+    /// Percentiles of the round-trip times of `peer_id`, `None` if it never answered
+    pub fn latency(&self, peer_id: &Id) -> Option<LatencyStats> {
+        self.latencies.read().get(peer_id)?.stats()
+    }
+
+    /// Percentiles of the round-trip times of all the peers that answered, e.g. to pick the
+    /// closest ones
+    pub fn latencies(&self) -> HashMap<Id, LatencyStats> {
+        self.latencies
+            .read()
+            .iter()
+            .filter_map(|(peer_id, histogram)| Some((peer_id.clone(), histogram.stats()?)))
+            .collect()
+    }
+
+    /// Forget the peers for which `keep` is false
+    fn retain(&self, keep: impl Fn(&Id) -> bool) {
+        self.pending.lock().retain(|peer_id, _| keep(peer_id));
+        self.latencies.write().retain(|peer_id, _| keep(peer_id));
+    }
+}
+
+impl<Id: PeerId> MessageHandler<Id> for PingHandler<Id> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        match PingMessageSerializer.deserialize(&data)? {
+            // needs the send channels of the peer to answer
+            PingMessage::Ping(_) => Ok(()),
+            PingMessage::Pong(nonce) => {
+                let sent = {
+                    let mut pending = self.pending.lock();
+                    match pending.get(peer_id) {
+                        Some((expected, sent)) if *expected == nonce => {
+                            let sent = *sent;
+                            pending.remove(peer_id);
+                            sent
+                        }
+                        _ => return Ok(()),
+                    }
+                };
+                self.latencies
+                    .write()
+                    .entry(peer_id.clone())
+                    .or_default()
+                    .record(sent.elapsed());
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        match PingMessageSerializer.deserialize(&data)? {
+            PingMessage::Ping(nonce) => {
+                // only a measure: dropped when the channels of the peer are full, the peer isn't
+                // disconnected for it
+                if let Err(err) =
+                    peer.send_channels
+                        .try_send(&self.serializer(), PingMessage::Pong(nonce), true)
+                {
+                    tracing::debug!(peer_id = ?peer.peer_id, "pong dropped: {:?}", err);
+                }
+                Ok(())
+            }
+            PingMessage::Pong(_) => self.handle(data, &peer.peer_id),
+        }
+    }
+}
+
+/// Pings the connected peers every `interval`, see `PingHandler`
+pub struct Pinger {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Pinger {
+    pub fn start<Id: PeerId>(
+        active_connections: SharedActiveConnections<Id>,
+        handler: PingHandler<Id>,
+        interval: Duration,
+    ) -> PeerNetResult<Pinger> {
+        let (stop_tx, stop_rx) = unbounded();
+        let handle = std::thread::Builder::new()
+            .name("pinger".to_string())
+            .spawn(move || loop {
+                match stop_rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        let active_connections = active_connections.read();
+                        handler
+                            .retain(|peer_id| active_connections.connections.contains_key(peer_id));
+                        for (peer_id, connection) in &active_connections.connections {
+                            if let Err(err) = handler.ping(peer_id, &connection.send_channels) {
+                                tracing::debug!(?peer_id, "ping failed: {:?}", err);
+                            }
+                        }
+                    }
+                    _ => return,
+                }
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn pinger", err, None))?;
+        Ok(Pinger { stop_tx, handle })
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
@@ -0,0 +1,845 @@
+//! Relay of the connections to the peers that can't be reached directly
+//!
+//! A peer that can't be dialed (e.g. behind a NAT) reserves a slot on a relay it is connected
+//! to. Another peer connected to the relay can then open a circuit to it: the relay forwards the
+//! data of the circuit from one end to the other, and both ends use the circuit as a
+//! `RelayedEndpoint` to connect to each other with the usual handshake (see
+//! `PeerNetManager::try_connect_relayed` and `PeerNetManager::accept_relayed_connections`).
+//!
+//! The same `RelayHandler` opens circuits and, when created with a `RelayConfig`, runs the relay
+//! service. The relay limits the number of reservations and circuits, the number of relays a
+//! circuit goes through (a relayed peer can itself be a relay) and the bandwidth of each
+//! circuit: a circuit going over it is closed.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use parking_lot::Mutex;
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::handlers::{MessageHandler, RoutedSerializer};
+use crate::messages::{Bytes, MessagesSerializer};
+use crate::network_manager::DisconnectHook;
+use crate::peer::{PeerHandle, SendChannels};
+use crate::peer_id::PeerId;
+use crate::transports::{RelayedEndpoint, RELAY_CLOSE, RELAY_DATA};
+
+use super::peer_management::{PeerManagementHooks, Reader};
+
+const RESERVE: u8 = 0;
+const RESERVE_ANSWER: u8 = 1;
+const CONNECT: u8 = 2;
+const CONNECT_ANSWER: u8 = 3;
+const INCOMING: u8 = 4;
+const FORWARDED: u8 = 7;
+const CLOSED: u8 = 8;
+
+/// Bit set in the ids of the circuits allocated by the relay, the ids chosen by the peers
+/// opening circuits don't have it
+const RELAY_ALLOCATED: u64 = 1 << 63;
+
+/// Number of messages of a circuit waiting to be read by its endpoint
+const CIRCUIT_CHANNEL_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelayConfig {
+    /// Number of peers that can reserve a slot on the relay
+    pub max_reservations: usize,
+    /// Number of circuits relayed at the same time
+    pub max_circuits: usize,
+    /// Number of relays a circuit can go through, including this one
+    pub max_hops: u8,
+    /// Bytes per second forwarded on a circuit, both directions together, before it's closed
+    pub max_circuit_bandwidth: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelayMessage<Id> {
+    /// Ask the relay to accept the circuits opened to us
+    Reserve,
+    ReserveAnswer {
+        accepted: bool,
+    },
+    /// Open a circuit to `target`, `hops` is the number of relays between us and the relay
+    Connect {
+        circuit_id: u64,
+        hops: u8,
+        target: Id,
+    },
+    ConnectAnswer {
+        circuit_id: u64,
+        accepted: bool,
+    },
+    /// `source` opened a circuit to us through `hops` relays
+    Incoming {
+        circuit_id: u64,
+        hops: u8,
+        source: Id,
+    },
+    /// Data to forward to the other end of the circuit
+    Data {
+        circuit_id: u64,
+        payload: Bytes,
+    },
+    /// Close the circuit
+    Close {
+        circuit_id: u64,
+    },
+    /// Data received from the other end of the circuit
+    Forwarded {
+        circuit_id: u64,
+        payload: Bytes,
+    },
+    /// The circuit has been closed by the other end or the relay
+    Closed {
+        circuit_id: u64,
+    },
+}
+
+/// Serializer of the `RelayMessage`, to wrap in a `RoutedSerializer`
+#[derive(Clone)]
+pub struct RelayMessageSerializer<H> {
+    pub hooks: H,
+}
+
+impl<Id, H: PeerManagementHooks<Id>> MessagesSerializer<RelayMessage<Id>>
+    for RelayMessageSerializer<H>
+{
+    fn serialize(&self, message: &RelayMessage<Id>, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        match message {
+            RelayMessage::Reserve => buffer.push(RESERVE),
+            RelayMessage::ReserveAnswer { accepted } => {
+                buffer.push(RESERVE_ANSWER);
+                buffer.push(*accepted as u8);
+            }
+            RelayMessage::Connect {
+                circuit_id,
+                hops,
+                target,
+            } => {
+                buffer.push(CONNECT);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+                buffer.push(*hops);
+                self.hooks.serialize_peer_id(target, buffer)?;
+            }
+            RelayMessage::ConnectAnswer {
+                circuit_id,
+                accepted,
+            } => {
+                buffer.push(CONNECT_ANSWER);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+                buffer.push(*accepted as u8);
+            }
+            RelayMessage::Incoming {
+                circuit_id,
+                hops,
+                source,
+            } => {
+                buffer.push(INCOMING);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+                buffer.push(*hops);
+                self.hooks.serialize_peer_id(source, buffer)?;
+            }
+            RelayMessage::Data {
+                circuit_id,
+                payload,
+            } => {
+                buffer.push(RELAY_DATA);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+                buffer.extend_from_slice(payload);
+            }
+            RelayMessage::Close { circuit_id } => {
+                buffer.push(RELAY_CLOSE);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+            }
+            RelayMessage::Forwarded {
+                circuit_id,
+                payload,
+            } => {
+                buffer.push(FORWARDED);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+                buffer.extend_from_slice(payload);
+            }
+            RelayMessage::Closed { circuit_id } => {
+                buffer.push(CLOSED);
+                buffer.extend_from_slice(&circuit_id.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_bool(reader: &mut Reader) -> PeerNetResult<bool> {
+    match reader.read_array()? {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        [value] => Err(PeerNetError::InvalidMessage.error(
+            "deserialize relay message",
+            Some(format!("invalid bool: {}", value)),
+        )),
+    }
+}
+
+impl<H> RelayMessageSerializer<H> {
+    pub fn deserialize<Id>(&self, data: &Bytes) -> PeerNetResult<RelayMessage<Id>>
+    where
+        H: PeerManagementHooks<Id>,
+    {
+        let mut reader = Reader {
+            data: &data[..],
+            position: 0,
+        };
+        let read_peer_id = |reader: &mut Reader| -> PeerNetResult<Id> {
+            let (peer_id, len) = self.hooks.deserialize_peer_id(&data[reader.position..])?;
+            reader.read_bytes(len)?;
+            Ok(peer_id)
+        };
+        let [message_type] = reader.read_array()?;
+        let message = match message_type {
+            RESERVE => RelayMessage::Reserve,
+            RESERVE_ANSWER => RelayMessage::ReserveAnswer {
+                accepted: read_bool(&mut reader)?,
+            },
+            CONNECT => RelayMessage::Connect {
+                circuit_id: u64::from_be_bytes(reader.read_array()?),
+                hops: reader.read_array::<1>()?[0],
+                target: read_peer_id(&mut reader)?,
+            },
+            CONNECT_ANSWER => RelayMessage::ConnectAnswer {
+                circuit_id: u64::from_be_bytes(reader.read_array()?),
+                accepted: read_bool(&mut reader)?,
+            },
+            INCOMING => RelayMessage::Incoming {
+                circuit_id: u64::from_be_bytes(reader.read_array()?),
+                hops: reader.read_array::<1>()?[0],
+                source: read_peer_id(&mut reader)?,
+            },
+            RELAY_DATA | FORWARDED => {
+                let circuit_id = u64::from_be_bytes(reader.read_array()?);
+                // the rest of the message, without copy
+                let payload = data.slice(reader.position..);
+                reader.position = data.len();
+                if message_type == RELAY_DATA {
+                    RelayMessage::Data {
+                        circuit_id,
+                        payload,
+                    }
+                } else {
+                    RelayMessage::Forwarded {
+                        circuit_id,
+                        payload,
+                    }
+                }
+            }
+            RELAY_CLOSE => RelayMessage::Close {
+                circuit_id: u64::from_be_bytes(reader.read_array()?),
+            },
+            CLOSED => RelayMessage::Closed {
+                circuit_id: u64::from_be_bytes(reader.read_array()?),
+            },
+            _ => {
+                return Err(PeerNetError::InvalidMessage.error(
+                    "deserialize relay message",
+                    Some(format!("unknown message type: {}", message_type)),
+                ))
+            }
+        };
+        if reader.position != data.len() {
+            return Err(PeerNetError::InvalidMessage.error(
+                "deserialize relay message",
+                Some(format!("{} trailing bytes", data.len() - reader.position)),
+            ));
+        }
+        Ok(message)
+    }
+}
+
+/// A circuit relayed by us, seen from one of its ends
+struct RelayedCircuit<Id> {
+    /// The other end and the id of the circuit for it
+    peer_id: Id,
+    circuit_id: u64,
+    send_channels: SendChannels,
+    /// Start of the current second and bytes forwarded since, shared by both directions
+    usage: Arc<Mutex<(Instant, u64)>>,
+}
+
+/// A circuit of which we are an end
+struct Circuit<Id> {
+    /// The other end
+    peer_id: Id,
+    hops: u8,
+    /// Dropped when the circuit is closed, the endpoint then reads the end of the connection
+    data_tx: Sender<Bytes>,
+}
+
+struct RelayState<Id> {
+    /// Peers that reserved a slot on our relay service
+    reservations: HashMap<Id, SendChannels>,
+    /// Circuits relayed by us, by end and circuit id (two entries per circuit)
+    relayed: HashMap<(Id, u64), RelayedCircuit<Id>>,
+    /// Relays on which we have a reservation
+    reserved_on: HashSet<Id>,
+    /// Circuits of which we are an end, by relay and circuit id
+    circuits: HashMap<(Id, u64), Circuit<Id>>,
+    pending_reservations: HashMap<Id, Sender<bool>>,
+    pending_connects: HashMap<(Id, u64), Sender<bool>>,
+}
+
+impl<Id: PeerId> RelayState<Id> {
+    /// Number of relays between us and `peer_id`, 0 if it is a direct connection
+    fn hops_to(&self, peer_id: &Id) -> u8 {
+        self.circuits
+            .values()
+            .filter(|circuit| circuit.peer_id == *peer_id)
+            .map(|circuit| circuit.hops)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Remove a relayed circuit, returns its ends
+    fn remove_relayed(&mut self, peer_id: &Id, circuit_id: u64) -> Vec<(SendChannels, u64)> {
+        let Some(other_end) = self.relayed.remove(&(peer_id.clone(), circuit_id)) else {
+            return Vec::new();
+        };
+        let mut ends = vec![(other_end.send_channels, other_end.circuit_id)];
+        if let Some(end) = self
+            .relayed
+            .remove(&(other_end.peer_id, other_end.circuit_id))
+        {
+            ends.push((end.send_channels, end.circuit_id));
+        }
+        ends
+    }
+
+    /// Forget a disconnected peer, returns the ends of the circuits it relayed
+    fn remove_peer(&mut self, peer_id: &Id) -> Vec<(SendChannels, u64)> {
+        self.reservations.remove(peer_id);
+        self.reserved_on.remove(peer_id);
+        self.pending_reservations.remove(peer_id);
+        self.pending_connects
+            .retain(|(relay_id, _), _| relay_id != peer_id);
+        // the circuits going through the peer, their endpoints read the end of the connection
+        self.circuits.retain(|(relay_id, _), _| relay_id != peer_id);
+        let circuit_ids: Vec<u64> = self
+            .relayed
+            .keys()
+            .filter(|(end_id, _)| end_id == peer_id)
+            .map(|(_, circuit_id)| *circuit_id)
+            .collect();
+        circuit_ids
+            .into_iter()
+            .flat_map(|circuit_id| self.remove_relayed(peer_id, circuit_id))
+            .collect()
+    }
+}
+
+/// Handler of the `RelayMessage`, to register in a `MessageHandlers` with `handler_id`.
+/// It must have the same id on all the peers.
+#[derive(Clone)]
+pub struct RelayHandler<Id: PeerId, H> {
+    handler_id: u64,
+    hooks: H,
+    service: Option<RelayConfig>,
+    state: Arc<Mutex<RelayState<Id>>>,
+    next_circuit_id: Arc<AtomicU64>,
+    incoming_tx: Sender<(Id, RelayedEndpoint)>,
+    incoming_rx: Receiver<(Id, RelayedEndpoint)>,
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> RelayHandler<Id, H> {
+    /// The relay service is enabled if `service` is set
+    pub fn new(handler_id: u64, hooks: H, service: Option<RelayConfig>) -> Self {
+        let (incoming_tx, incoming_rx) = unbounded();
+        RelayHandler {
+            handler_id,
+            hooks,
+            service,
+            state: Arc::new(Mutex::new(RelayState {
+                reservations: HashMap::new(),
+                relayed: HashMap::new(),
+                reserved_on: HashSet::new(),
+                circuits: HashMap::new(),
+                pending_reservations: HashMap::new(),
+                pending_connects: HashMap::new(),
+            })),
+            next_circuit_id: Arc::new(AtomicU64::new(0)),
+            incoming_tx,
+            incoming_rx,
+        }
+    }
+
+    pub fn serializer(&self) -> RoutedSerializer<RelayMessageSerializer<H>> {
+        RoutedSerializer {
+            handler_id: self.handler_id,
+            serializer: RelayMessageSerializer {
+                hooks: self.hooks.clone(),
+            },
+        }
+    }
+
+    /// Endpoints of the circuits opened to us, with the id of their relay
+    pub(crate) fn incoming(&self) -> Receiver<(Id, RelayedEndpoint)> {
+        self.incoming_rx.clone()
+    }
+
+    /// Called by the manager with the disconnected peers, see `PeerNetManager::watch_relay`.
+    /// The key is the same for all the clones of the handler.
+    pub(crate) fn disconnect_hook(&self) -> (usize, DisconnectHook<Id>) {
+        let relay = self.clone();
+        (
+            Arc::as_ptr(&self.state) as usize,
+            Arc::new(move |peer_id: &Id| relay.remove_peer(peer_id)),
+        )
+    }
+
+    /// Close the circuits of a disconnected peer on their other end
+    fn remove_peer(&self, peer_id: &Id) {
+        let ends = self.state.lock().remove_peer(peer_id);
+        for (send_channels, circuit_id) in ends {
+            let _ = send_channels.try_send(
+                &self.serializer(),
+                RelayMessage::Closed { circuit_id },
+                true,
+            );
+        }
+    }
+
+    fn send(&self, send_channels: &SendChannels, message: RelayMessage<Id>) -> PeerNetResult<()> {
+        send_channels.send(&self.serializer(), message, false)
+    }
+
+    /// Ask the relay `relay_id` to accept the circuits opened to us and wait for its answer
+    pub fn reserve(
+        &self,
+        relay_id: &Id,
+        send_channels: &SendChannels,
+        timeout: Duration,
+    ) -> PeerNetResult<()> {
+        let (answer_tx, answer_rx) = bounded(1);
+        self.state
+            .lock()
+            .pending_reservations
+            .insert(relay_id.clone(), answer_tx);
+        let deadline = Instant::now() + timeout;
+        let result = send_channels
+            .send_with_deadline(&self.serializer(), RelayMessage::Reserve, false, deadline)
+            .and_then(|_| {
+                answer_rx.recv_deadline(deadline).map_err(|err| {
+                    PeerNetError::TimeOut.new("relay reserve", err, Some(format!("{:?}", relay_id)))
+                })
+            });
+        let mut state = self.state.lock();
+        state.pending_reservations.remove(relay_id);
+        match result? {
+            true => {
+                state.reserved_on.insert(relay_id.clone());
+                Ok(())
+            }
+            false => Err(PeerNetError::PeerConnectionError
+                .error("relay reserve", Some(format!("refused by {:?}", relay_id)))),
+        }
+    }
+
+    /// Open a circuit to `target` through the relay `relay_id`, reachable at `relay_addr`
+    pub(crate) fn open_circuit(
+        &self,
+        relay_id: &Id,
+        relay_addr: SocketAddr,
+        send_channels: &SendChannels,
+        target: Id,
+        timeout: Duration,
+    ) -> PeerNetResult<RelayedEndpoint> {
+        let circuit_id = self.next_circuit_id.fetch_add(1, Ordering::Relaxed) & !RELAY_ALLOCATED;
+        let key = (relay_id.clone(), circuit_id);
+        let (answer_tx, answer_rx) = bounded(1);
+        let (data_tx, data_rx) = bounded(CIRCUIT_CHANNEL_SIZE);
+        let hops = {
+            let mut state = self.state.lock();
+            let hops = state.hops_to(relay_id);
+            state.pending_connects.insert(key.clone(), answer_tx);
+            // the circuit is registered before the answer as the target can send first
+            state.circuits.insert(
+                key.clone(),
+                Circuit {
+                    peer_id: target.clone(),
+                    hops: hops.saturating_add(1),
+                    data_tx,
+                },
+            );
+            hops
+        };
+        let deadline = Instant::now() + timeout;
+        let result = send_channels
+            .send_with_deadline(
+                &self.serializer(),
+                RelayMessage::Connect {
+                    circuit_id,
+                    hops,
+                    target: target.clone(),
+                },
+                false,
+                deadline,
+            )
+            .and_then(|_| {
+                answer_rx.recv_deadline(deadline).map_err(|err| {
+                    PeerNetError::TimeOut.new("relay connect", err, Some(format!("{:?}", target)))
+                })
+            });
+        let mut state = self.state.lock();
+        state.pending_connects.remove(&key);
+        match result {
+            Ok(true) => Ok(RelayedEndpoint::new(
+                relay_addr,
+                self.handler_id,
+                circuit_id,
+                send_channels.clone(),
+                data_rx,
+                CIRCUIT_CHANNEL_SIZE,
+            )),
+            Ok(false) => {
+                state.circuits.remove(&key);
+                Err(PeerNetError::PeerConnectionError.error(
+                    "relay connect",
+                    Some(format!("{:?} refused by {:?}", target, relay_id)),
+                ))
+            }
+            Err(err) => {
+                state.circuits.remove(&key);
+                drop(state);
+                // the relay may have opened the circuit after the timeout
+                let _ = send_channels.try_send(
+                    &self.serializer(),
+                    RelayMessage::Close { circuit_id },
+                    true,
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn handle_reserve(&self, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        let accepted = match self.service {
+            Some(config) => {
+                let mut state = self.state.lock();
+                let accepted = state.reservations.len() < config.max_reservations
+                    || state.reservations.contains_key(&peer.peer_id);
+                if accepted {
+                    state
+                        .reservations
+                        .insert(peer.peer_id.clone(), peer.send_channels.clone());
+                }
+                accepted
+            }
+            None => false,
+        };
+        self.send(
+            &peer.send_channels,
+            RelayMessage::ReserveAnswer { accepted },
+        )
+    }
+
+    fn handle_connect(
+        &self,
+        peer: &PeerHandle<Id>,
+        circuit_id: u64,
+        hops: u8,
+        target: Id,
+    ) -> PeerNetResult<()> {
+        let refuse = || {
+            self.send(
+                &peer.send_channels,
+                RelayMessage::ConnectAnswer {
+                    circuit_id,
+                    accepted: false,
+                },
+            )
+        };
+        let Some(config) = self.service else {
+            return refuse();
+        };
+        let (target_channels, target_circuit_id, hops) = {
+            let mut state = self.state.lock();
+            // the peer can't hide that it is itself relayed
+            let hops = hops.max(state.hops_to(&peer.peer_id)).saturating_add(1);
+            let key = (peer.peer_id.clone(), circuit_id);
+            let target_channels = match state.reservations.get(&target) {
+                Some(target_channels)
+                    if circuit_id & RELAY_ALLOCATED == 0
+                        && target != peer.peer_id
+                        && hops <= config.max_hops
+                        && state.relayed.len() / 2 < config.max_circuits
+                        && !state.relayed.contains_key(&key) =>
+                {
+                    target_channels.clone()
+                }
+                _ => {
+                    drop(state);
+                    return refuse();
+                }
+            };
+            let target_circuit_id =
+                self.next_circuit_id.fetch_add(1, Ordering::Relaxed) | RELAY_ALLOCATED;
+            let usage = Arc::new(Mutex::new((Instant::now(), 0)));
+            state.relayed.insert(
+                key,
+                RelayedCircuit {
+                    peer_id: target.clone(),
+                    circuit_id: target_circuit_id,
+                    send_channels: target_channels.clone(),
+                    usage: usage.clone(),
+                },
+            );
+            state.relayed.insert(
+                (target.clone(), target_circuit_id),
+                RelayedCircuit {
+                    peer_id: peer.peer_id.clone(),
+                    circuit_id,
+                    send_channels: peer.send_channels.clone(),
+                    usage,
+                },
+            );
+            (target_channels, target_circuit_id, hops)
+        };
+        let incoming = RelayMessage::Incoming {
+            circuit_id: target_circuit_id,
+            hops,
+            source: peer.peer_id.clone(),
+        };
+        if self.send(&target_channels, incoming).is_err() {
+            // the target is gone, its reservation with it
+            let mut state = self.state.lock();
+            state.reservations.remove(&target);
+            state.remove_relayed(&peer.peer_id, circuit_id);
+            drop(state);
+            return refuse();
+        }
+        self.send(
+            &peer.send_channels,
+            RelayMessage::ConnectAnswer {
+                circuit_id,
+                accepted: true,
+            },
+        )
+    }
+
+    /// Close a relayed circuit on both ends
+    fn close_relayed(&self, peer_id: &Id, circuit_id: u64) {
+        let ends = self.state.lock().remove_relayed(peer_id, circuit_id);
+        for (send_channels, circuit_id) in ends {
+            let _ = send_channels.try_send(
+                &self.serializer(),
+                RelayMessage::Closed { circuit_id },
+                true,
+            );
+        }
+    }
+
+    fn handle_data(&self, peer_id: &Id, circuit_id: u64, payload: Bytes) -> PeerNetResult<()> {
+        let Some(config) = self.service else {
+            return Ok(());
+        };
+        let (send_channels, other_circuit_id, usage) = {
+            let state = self.state.lock();
+            // data of a circuit already closed is dropped
+            let Some(other_end) = state.relayed.get(&(peer_id.clone(), circuit_id)) else {
+                return Ok(());
+            };
+            (
+                other_end.send_channels.clone(),
+                other_end.circuit_id,
+                other_end.usage.clone(),
+            )
+        };
+        let over_bandwidth = {
+            let mut usage = usage.lock();
+            if usage.0.elapsed() >= Duration::from_secs(1) {
+                *usage = (Instant::now(), 0);
+            }
+            usage.1 += payload.len() as u64;
+            usage.1 > config.max_circuit_bandwidth
+        };
+        if over_bandwidth {
+            self.close_relayed(peer_id, circuit_id);
+            return Ok(());
+        }
+        let forwarded = RelayMessage::Forwarded {
+            circuit_id: other_circuit_id,
+            payload,
+        };
+        if self.send(&send_channels, forwarded).is_err() {
+            self.close_relayed(peer_id, circuit_id);
+        }
+        Ok(())
+    }
+
+    fn handle_incoming(
+        &self,
+        peer: &PeerHandle<Id>,
+        circuit_id: u64,
+        hops: u8,
+        source: Id,
+    ) -> PeerNetResult<()> {
+        let key = (peer.peer_id.clone(), circuit_id);
+        let data_rx = {
+            let mut state = self.state.lock();
+            if !state.reserved_on.contains(&peer.peer_id) || state.circuits.contains_key(&key) {
+                None
+            } else {
+                let (data_tx, data_rx) = bounded(CIRCUIT_CHANNEL_SIZE);
+                state.circuits.insert(
+                    key,
+                    Circuit {
+                        peer_id: source,
+                        hops,
+                        data_tx,
+                    },
+                );
+                Some(data_rx)
+            }
+        };
+        let Some(data_rx) = data_rx else {
+            return self.send(&peer.send_channels, RelayMessage::Close { circuit_id });
+        };
+        // the address of the relay is set by the manager
+        let endpoint = RelayedEndpoint::new(
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            self.handler_id,
+            circuit_id,
+            peer.send_channels.clone(),
+            data_rx,
+            CIRCUIT_CHANNEL_SIZE,
+        );
+        let _ = self.incoming_tx.send((peer.peer_id.clone(), endpoint));
+        Ok(())
+    }
+
+    /// Give the data of a circuit to its endpoint. The circuit is closed if the endpoint has
+    /// been dropped or doesn't keep up, not to block the messages of the relay.
+    fn handle_forwarded(
+        &self,
+        peer_id: &Id,
+        circuit_id: u64,
+        payload: Bytes,
+        relay: Option<&SendChannels>,
+    ) {
+        let key = (peer_id.clone(), circuit_id);
+        let data_tx = match self.state.lock().circuits.get(&key) {
+            Some(circuit) => circuit.data_tx.clone(),
+            None => return,
+        };
+        if data_tx.try_send(payload).is_err() {
+            drop(data_tx);
+            self.state.lock().circuits.remove(&key);
+            if let Some(relay) = relay {
+                let _ =
+                    relay.try_send(&self.serializer(), RelayMessage::Close { circuit_id }, true);
+            }
+        }
+    }
+
+    fn handle_message(
+        &self,
+        message: RelayMessage<Id>,
+        peer_id: &Id,
+        peer: Option<&PeerHandle<Id>>,
+    ) -> PeerNetResult<()> {
+        match (message, peer) {
+            (RelayMessage::Reserve, Some(peer)) => self.handle_reserve(peer),
+            (
+                RelayMessage::Connect {
+                    circuit_id,
+                    hops,
+                    target,
+                },
+                Some(peer),
+            ) => self.handle_connect(peer, circuit_id, hops, target),
+            (
+                RelayMessage::Incoming {
+                    circuit_id,
+                    hops,
+                    source,
+                },
+                Some(peer),
+            ) => self.handle_incoming(peer, circuit_id, hops, source),
+            (RelayMessage::ReserveAnswer { accepted }, _) => {
+                if let Some(answer_tx) = self.state.lock().pending_reservations.remove(peer_id) {
+                    let _ = answer_tx.send(accepted);
+                }
+                Ok(())
+            }
+            (
+                RelayMessage::ConnectAnswer {
+                    circuit_id,
+                    accepted,
+                },
+                _,
+            ) => {
+                let pending = self
+                    .state
+                    .lock()
+                    .pending_connects
+                    .remove(&(peer_id.clone(), circuit_id));
+                if let Some(answer_tx) = pending {
+                    let _ = answer_tx.send(accepted);
+                }
+                Ok(())
+            }
+            (
+                RelayMessage::Data {
+                    circuit_id,
+                    payload,
+                },
+                _,
+            ) => self.handle_data(peer_id, circuit_id, payload),
+            (RelayMessage::Close { circuit_id }, _) => {
+                self.close_relayed(peer_id, circuit_id);
+                Ok(())
+            }
+            (
+                RelayMessage::Forwarded {
+                    circuit_id,
+                    payload,
+                },
+                peer,
+            ) => {
+                let relay = peer.map(|peer| &peer.send_channels);
+                self.handle_forwarded(peer_id, circuit_id, payload, relay);
+                Ok(())
+            }
+            (RelayMessage::Closed { circuit_id }, _) => {
+                // dropping the sender closes the endpoint
+                self.state
+                    .lock()
+                    .circuits
+                    .remove(&(peer_id.clone(), circuit_id));
+                Ok(())
+            }
+            (
+                RelayMessage::Reserve
+                | RelayMessage::Connect { .. }
+                | RelayMessage::Incoming { .. },
+                None,
+            ) => Err(PeerNetError::HandlerError.error(
+                "relay message",
+                Some("can't answer without the handle of the peer".to_string()),
+            )),
+        }
+    }
+}
+
+impl<Id: PeerId, H: PeerManagementHooks<Id>> MessageHandler<Id> for RelayHandler<Id, H> {
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()> {
+        let message = self.serializer().serializer.deserialize(&data)?;
+        self.handle_message(message, peer_id, None)
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        let message = self.serializer().serializer.deserialize(&data)?;
+        self.handle_message(message, &peer.peer_id, Some(peer))
+    }
+}
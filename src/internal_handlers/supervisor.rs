@@ -0,0 +1,425 @@
+//! Maintenance of the number of out connections
+//!
+//! The `ConnectionSupervisor` periodically compares the out connections of each category with
+//! its target and dials peers of the `PeerDB` when there are not enough of them, so that the
+//! peers that disconnect are replaced. The peers of the `AddressBook` of the manager that the
+//! `PeerDB` doesn't know are dialed again at the address they were last connected on. The
+//! peers marked unreachable by the `Tester` are not dialed and an address is not dialed again
+//! before `retry_after`.
+//!
+//! With a `DialBackoff`, the addresses that can't be connected wait longer after each failed
+//! dial, and are given up after too many failures in a row, so that the unreachable entries
+//! (e.g. stale bootstrap peers) don't take the place of the other candidates forever.
+//!
+//! With a `PeerRotation`, it also disconnects periodically a random part of the long-lived out
+//! connections. They are replaced by other peers of the `PeerDB` at the same check, so that the
+//! topology of a gossip network keeps changing.
+//!
+//! The checks, the backoff and the rotation follow the `PeerNetFeatures::clock` of the manager.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{unbounded, Sender, TryRecvError};
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+
+use crate::config::PeerNetCategories;
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::history::DisconnectReason;
+use crate::messages::MessagesHandler;
+use crate::network_manager::{to_canonical, PeerNetManager, ReportingConnect};
+use crate::peer::{InitConnectionHandler, PeerConnectionType};
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+use super::peer_management::SharedPeerDB;
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Time between two checks of the out connections
+    pub interval: Duration,
+    /// Timeout of the connections to the candidates
+    pub connect_timeout: Duration,
+    /// Time before an address that has been dialed can be dialed again
+    pub retry_after: Duration,
+    /// Number of out connections wanted for each category
+    pub target_out_connections: HashMap<String, usize>,
+    /// Number of out connections wanted with the peers that are in no category
+    pub default_target_out_connections: usize,
+    /// Replace periodically some of the out connections. Disabled if `None`
+    pub rotation: Option<PeerRotation>,
+    /// Wait longer before dialing again the addresses that failed. Only `retry_after` is
+    /// applied if `None`
+    pub backoff: Option<DialBackoff>,
+}
+
+/// Backoff of the addresses whose dials fail, see `SupervisorConfig::backoff`. A dial fails if
+/// the connection or its handshake fails.
+#[derive(Debug, Clone)]
+pub struct DialBackoff {
+    /// Wait after the first failure, doubled at each following one. `retry_after` is still
+    /// waited if it's longer.
+    pub initial: Duration,
+    /// Maximum wait between two dials of an address
+    pub max: Duration,
+    /// Number of failures in a row after which the address isn't dialed anymore. Never given up
+    /// if `None`
+    pub max_failures: Option<u32>,
+}
+
+impl DialBackoff {
+    /// Wait after the `nb_failures`-th failure in a row
+    fn delay(&self, nb_failures: u32) -> Duration {
+        2u32.checked_pow(nb_failures.saturating_sub(1))
+            .and_then(|factor| self.initial.checked_mul(factor))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// Failed dials in a row of an address, see `ConnectionSupervisor::dial_failures`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialFailures {
+    pub nb_failures: u32,
+    /// Not dialed before this time, `None` if the address is given up
+    pub retry_at: Option<Instant>,
+}
+
+type SharedDialFailures = Arc<Mutex<HashMap<SocketAddr, DialFailures>>>;
+
+/// Dials in progress and failed dials of the supervisor
+struct Dials<Id: PeerId> {
+    pending: HashMap<SocketAddr, ReportingConnect<Id>>,
+    failures: SharedDialFailures,
+}
+
+impl<Id: PeerId> Dials<Id> {
+    /// Whether `addr` isn't being dialed and isn't waiting after failures
+    fn can_dial(&self, addr: &SocketAddr, now: Instant) -> bool {
+        !self.pending.contains_key(addr)
+            && self.failures.lock().get(addr).map_or(true, |failures| {
+                failures.retry_at.map_or(false, |retry_at| retry_at <= now)
+            })
+    }
+
+    /// Update the failures with the dials that are over
+    fn resolve(&mut self, backoff: &DialBackoff, now: Instant) {
+        let mut over = Vec::new();
+        for (addr, (connect, result_rx)) in &self.pending {
+            if !connect.is_finished() {
+                continue;
+            }
+            // the sender is dropped without a result if the connection failed
+            match result_rx.try_recv() {
+                Ok(result) => over.push((*addr, result.is_ok())),
+                Err(TryRecvError::Disconnected) => over.push((*addr, false)),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        let mut failures = self.failures.lock();
+        for (addr, success) in over {
+            self.pending.remove(&addr);
+            if success {
+                failures.remove(&addr);
+            } else {
+                record_failure(&mut failures, addr, backoff, now);
+            }
+        }
+    }
+}
+
+fn record_failure(
+    failures: &mut HashMap<SocketAddr, DialFailures>,
+    addr: SocketAddr,
+    backoff: &DialBackoff,
+    now: Instant,
+) {
+    let failures = failures.entry(addr).or_insert(DialFailures {
+        nb_failures: 0,
+        retry_at: None,
+    });
+    failures.nb_failures = failures.nb_failures.saturating_add(1);
+    if backoff
+        .max_failures
+        .map_or(false, |max_failures| failures.nb_failures >= max_failures)
+    {
+        tracing::warn!(
+            "connection_supervisor giving up on {} after {} failed dials",
+            addr,
+            failures.nb_failures
+        );
+        failures.retry_at = None;
+    } else {
+        let delay = backoff.delay(failures.nb_failures);
+        tracing::debug!(
+            "connection_supervisor dial {} failed {} times, retrying in {:?}",
+            addr,
+            failures.nb_failures,
+            delay
+        );
+        failures.retry_at = Some(now + delay);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerRotation {
+    /// Time between two rotations, rounded up to a multiple of the `interval` of the checks
+    pub interval: Duration,
+    /// Part of the eligible out connections disconnected at each rotation, between 0 and 1.
+    /// Rounded down, nothing is rotated while it's less than a connection.
+    pub fraction: f64,
+    /// Only the out connections older than this are rotated
+    pub min_age: Duration,
+}
+
+pub type SharedPeerNetManager<Id, Ctx, I, M> = Arc<Mutex<PeerNetManager<Id, Ctx, I, M>>>;
+
+pub struct ConnectionSupervisor {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+    dial_failures: SharedDialFailures,
+}
+
+impl ConnectionSupervisor {
+    /// Start maintaining the out connections of `manager` with the peers of `peer_db`
+    pub fn start<
+        Id: PeerId,
+        Ctx: Context<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+        M: MessagesHandler<Id>,
+    >(
+        manager: SharedPeerNetManager<Id, Ctx, I, M>,
+        peer_db: SharedPeerDB<Id>,
+        config: SupervisorConfig,
+    ) -> PeerNetResult<ConnectionSupervisor> {
+        let (stop_tx, stop_rx) = unbounded();
+        let dial_failures = SharedDialFailures::default();
+        let mut dials = Dials {
+            pending: HashMap::new(),
+            failures: dial_failures.clone(),
+        };
+        let clock = manager.lock().config.optional_features.clock();
+        let handle = std::thread::Builder::new()
+            .name("connection_supervisor".to_string())
+            .spawn(move || {
+                let mut last_attempts = HashMap::new();
+                let mut last_rotation = clock.now();
+                while !clock.wait(&stop_rx, config.interval) {
+                    let now = clock.now();
+                    if let Some(rotation) = &config.rotation {
+                        if now.saturating_duration_since(last_rotation) >= rotation.interval {
+                            last_rotation = now;
+                            rotate(&manager, rotation, &mut last_attempts, now);
+                        }
+                    }
+                    supervise(
+                        &manager,
+                        &peer_db,
+                        &config,
+                        &mut last_attempts,
+                        &mut dials,
+                        now,
+                    )
+                }
+            })
+            .map_err(|err| {
+                PeerNetError::SocketError.new("spawn connection_supervisor", err, None)
+            })?;
+        Ok(ConnectionSupervisor {
+            stop_tx,
+            handle,
+            dial_failures,
+        })
+    }
+
+    /// The addresses whose last dials failed, with `SupervisorConfig::backoff`
+    pub fn dial_failures(&self) -> HashMap<SocketAddr, DialFailures> {
+        self.dial_failures.lock().clone()
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn category_of(addr: &SocketAddr, categories: &PeerNetCategories) -> Option<String> {
+    let ip = to_canonical(addr.ip());
+    categories
+        .iter()
+        .find(|(_, (ips, _))| ips.contains(&ip))
+        .map(|(name, _)| name.clone())
+}
+
+/// Disconnect a random part of the out connections older than `min_age`. Their addresses are
+/// not dialed again before `retry_after`.
+fn rotate<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+>(
+    manager: &SharedPeerNetManager<Id, Ctx, I, M>,
+    rotation: &PeerRotation,
+    last_attempts: &mut HashMap<SocketAddr, Instant>,
+    now: Instant,
+) {
+    let manager = manager.lock();
+    let mut active_connections = manager.active_connections.write();
+    let eligible: Vec<(Id, SocketAddr)> = active_connections
+        .connections
+        .iter()
+        .filter(|(_, connection)| {
+            connection.connection_type == PeerConnectionType::OUT
+                && now.saturating_duration_since(connection.connected_at) >= rotation.min_age
+        })
+        .map(|(id, connection)| (id.clone(), *connection.endpoint.get_target_addr()))
+        .collect();
+    let nb_rotated = (eligible.len() as f64 * rotation.fraction.clamp(0.0, 1.0)).floor() as usize;
+    for (id, addr) in eligible.choose_multiple(&mut rand::thread_rng(), nb_rotated) {
+        tracing::debug!(peer_id = ?id, %addr, "rotating out connection");
+        // the replacement must be another peer
+        last_attempts.insert(*addr, now);
+        active_connections.remove_connection_with_reason(id, DisconnectReason::Rotated);
+    }
+}
+
+fn supervise<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+>(
+    manager: &SharedPeerNetManager<Id, Ctx, I, M>,
+    peer_db: &SharedPeerDB<Id>,
+    config: &SupervisorConfig,
+    last_attempts: &mut HashMap<SocketAddr, Instant>,
+    dials: &mut Dials<Id>,
+    now: Instant,
+) {
+    last_attempts.retain(|_, last_attempt| {
+        now.saturating_duration_since(*last_attempt) < config.retry_after
+    });
+    if let Some(backoff) = &config.backoff {
+        dials.resolve(backoff, now);
+    }
+    // the state of the manager is read at once, its lock isn't held while dialing
+    let (categories, mut missing, connected, dialing, known_addresses) = {
+        let manager = manager.lock();
+        let categories = manager.config.peers_categories.clone();
+
+        // number of connections missing in each category, the connections in progress count
+        let mut missing: HashMap<Option<String>, usize> = config
+            .target_out_connections
+            .iter()
+            .map(|(name, target)| (Some(name.clone()), *target))
+            .collect();
+        missing.insert(None, config.default_target_out_connections);
+        let active_connections = manager.active_connections.read();
+        for connection in active_connections.connections.values() {
+            if connection.connection_type == PeerConnectionType::OUT {
+                if let Some(missing) = missing.get_mut(&connection.category_name) {
+                    *missing = missing.saturating_sub(1);
+                }
+            }
+        }
+        for addr in active_connections.out_connection_queue.keys() {
+            if let Some(missing) = missing.get_mut(&category_of(addr, &categories)) {
+                *missing = missing.saturating_sub(1);
+            }
+        }
+        (
+            categories,
+            missing,
+            active_connections
+                .connections
+                .keys()
+                .cloned()
+                .collect::<Vec<Id>>(),
+            active_connections.out_connection_queue.clone(),
+            // the peers we were connected to, to reconnect
+            active_connections
+                .address_book
+                .peers()
+                .filter(|id| !active_connections.connections.contains_key(id))
+                .map(|id| (id.clone(), active_connections.address_book.addresses(id)))
+                .collect::<Vec<(Id, Vec<(TransportType, SocketAddr)>)>>(),
+        )
+    };
+    if missing.values().all(|missing| *missing == 0) {
+        return;
+    }
+
+    let can_dial = |addr: &SocketAddr| {
+        !dialing.contains_key(addr)
+            && !last_attempts.contains_key(addr)
+            && dials.can_dial(addr, now)
+    };
+    let peer_db = peer_db.read();
+    let mut candidates: Vec<(SocketAddr, TransportType)> = peer_db
+        .peers
+        .iter()
+        .filter(|(id, info)| info.reachable != Some(false) && !connected.contains(id))
+        .filter_map(|(_, info)| {
+            info.announcement
+                .listeners
+                .iter()
+                .map(|(addr, transport_type)| (*addr, *transport_type))
+                .find(|(addr, _)| can_dial(addr))
+        })
+        .collect();
+    // the peers of the address book that the `PeerDB` doesn't know, at their last address
+    candidates.extend(
+        known_addresses
+            .into_iter()
+            .filter(|(id, _)| !peer_db.peers.contains_key(id))
+            .filter_map(|(_, addresses)| {
+                addresses
+                    .into_iter()
+                    .map(|(transport_type, addr)| (addr, transport_type))
+                    .find(|(addr, _)| can_dial(addr))
+            }),
+    );
+    drop(peer_db);
+    for (addr, transport_type) in candidates {
+        let Some(missing) = missing.get_mut(&category_of(&addr, &categories)) else {
+            continue;
+        };
+        if *missing == 0 {
+            continue;
+        }
+        last_attempts.insert(addr, now);
+        let Some(backoff) = &config.backoff else {
+            let connect = manager
+                .lock()
+                .try_connect(transport_type, addr, config.connect_timeout);
+            match connect {
+                Ok(_) => *missing -= 1,
+                Err(err) => {
+                    tracing::error!("connection_supervisor try_connect {}: {:?}", addr, err)
+                }
+            }
+            continue;
+        };
+        let connect = manager.lock().try_connect_reporting(
+            transport_type,
+            addr,
+            None,
+            config.connect_timeout,
+        );
+        match connect {
+            Ok(connect) => {
+                dials.pending.insert(addr, connect);
+                *missing -= 1;
+            }
+            Err(err) => {
+                tracing::error!("connection_supervisor try_connect {}: {:?}", addr, err);
+                record_failure(&mut dials.failures.lock(), addr, backoff, now);
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Test of the listeners announced by the peers
+//!
+//! The `Tester` periodically dials the listeners of the peers of a `PeerDB` and marks the peers
+//! reachable or unreachable, so that only addresses we could reach are gossiped (see
+//! `PeerManagementHandler::set_gossip_only_reachable`). A listener is reachable if a TCP
+//! connection to it can be opened before the timeout. QUIC listeners are not tested.
+//!
+//! With the `diagnostics` feature, `Tester::start_with_echo` tests the listeners with an echo
+//! ping instead, for the peers running an `EchoServer` on the ports of their TCP listeners.
+
+use std::net::{SocketAddr, TcpStream};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam::channel::{unbounded, RecvTimeoutError, Sender};
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+use super::peer_management::SharedPeerDB;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TesterConfig {
+    /// Time between two rounds of tests
+    pub interval: Duration,
+    /// Timeout of the connection to a listener
+    pub connect_timeout: Duration,
+    /// Number of peers tested in a round
+    pub max_tests_per_round: usize,
+    /// Time before a peer already tested is tested again
+    pub retest_after: Duration,
+}
+
+pub struct Tester {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Tester {
+    /// Start testing the peers of `peer_db` in a background thread
+    pub fn start<Id: PeerId>(
+        peer_db: SharedPeerDB<Id>,
+        config: TesterConfig,
+    ) -> PeerNetResult<Tester> {
+        Tester::start_with(peer_db, config, |addr, timeout| {
+            TcpStream::connect_timeout(addr, timeout).is_ok()
+        })
+    }
+
+    /// Start testing the peers of `peer_db` with `echo_ping`, `connect_timeout` being the
+    /// timeout of the ping
+    #[cfg(feature = "diagnostics")]
+    pub fn start_with_echo<Id: PeerId>(
+        peer_db: SharedPeerDB<Id>,
+        config: TesterConfig,
+    ) -> PeerNetResult<Tester> {
+        Tester::start_with(peer_db, config, |addr, timeout| {
+            super::echo::echo_ping(*addr, timeout).is_ok()
+        })
+    }
+
+    /// `is_reachable` tests a listener before a timeout
+    fn start_with<Id: PeerId>(
+        peer_db: SharedPeerDB<Id>,
+        config: TesterConfig,
+        is_reachable: fn(&SocketAddr, Duration) -> bool,
+    ) -> PeerNetResult<Tester> {
+        let (stop_tx, stop_rx) = unbounded();
+        let handle = std::thread::Builder::new()
+            .name("peer_tester".to_string())
+            .spawn(move || loop {
+                match stop_rx.recv_timeout(config.interval) {
+                    Err(RecvTimeoutError::Timeout) => test_round(&peer_db, &config, is_reachable),
+                    _ => return,
+                }
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn peer_tester", err, None))?;
+        Ok(Tester { stop_tx, handle })
+    }
+
+    /// Stop the tests and wait for the round in progress to end
+    pub fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn test_round<Id: PeerId>(
+    peer_db: &SharedPeerDB<Id>,
+    config: &TesterConfig,
+    is_reachable: fn(&SocketAddr, Duration) -> bool,
+) {
+    // the peers never tested first, then the ones tested the longest ago
+    let mut candidates: Vec<_> = peer_db
+        .read()
+        .peers
+        .iter()
+        .filter(|(_, info)| {
+            info.last_test
+                .map_or(true, |last_test| last_test.elapsed() >= config.retest_after)
+        })
+        .map(|(id, info)| {
+            let listeners: Vec<SocketAddr> = info
+                .announcement
+                .listeners
+                .iter()
+                .filter(|(_, transport_type)| **transport_type == TransportType::Tcp)
+                .map(|(addr, _)| *addr)
+                .collect();
+            (info.last_test, id.clone(), listeners)
+        })
+        .filter(|(_, _, listeners)| !listeners.is_empty())
+        .collect();
+    candidates.sort_by_key(|(last_test, _, _)| *last_test);
+
+    // the database isn't locked while dialing
+    for (_, peer_id, listeners) in candidates.into_iter().take(config.max_tests_per_round) {
+        let reachable = listeners
+            .iter()
+            .any(|addr| is_reachable(addr, config.connect_timeout));
+        peer_db.write().set_reachable(&peer_id, reachable);
+    }
+}
@@ -3,64 +3,13 @@
 //! ``` rust
 //! use std::{thread::sleep, collections::HashMap, time::Duration};
 //! use peernet::{
-//!     context::Context, error::PeerNetResult, messages::MessagesHandler, peer_id::PeerId,
-//!     config::{PeerNetConfiguration, PeerNetFeatures, PeerNetCategoryInfo},
+//!     config::{PeerNetConfiguration, PeerNetFeatures, PeerNetCategoryInfo, QuicSettings, TcpSettings, PeerIdRules, TrustedPeers},
+//!     defaults::{DefaultContext, DefaultInitConnection, DefaultMessagesHandler, DefaultPeerId},
 //!     network_manager::PeerNetManager,
-//!     peer::InitConnectionHandler,
+//!     peer_id::PeerId,
 //!     transports::TransportType,
 //! };
 //!
-//! use rand::Rng;
-//! #[derive(Clone)]
-//! pub struct DefaultContext {
-//!     pub our_id: DefaultPeerId,
-//! }
-//!
-//! #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-//! pub struct DefaultPeerId {
-//!     pub id: u64,
-//! }
-//!
-//! impl PeerId for DefaultPeerId {
-//!     fn generate() -> Self {
-//!         let mut rng = rand::thread_rng();
-//!         let random_number: u64 = rng.gen();
-//!         DefaultPeerId { id: random_number }
-//!     }
-//! }
-//!
-//! impl Context<DefaultPeerId> for DefaultContext {
-//!     fn get_peer_id(&self) -> DefaultPeerId {
-//!         self.our_id.clone()
-//!     }
-//! }
-//!
-//! #[derive(Clone)]
-//! pub struct DefaultMessagesHandler {}
-//!
-//! impl MessagesHandler<DefaultPeerId> for DefaultMessagesHandler {
-//!     fn handle(&self, _data: &[u8], _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
-//!         Ok(())
-//!     }
-//! }
-//!
-//! #[derive(Clone)]
-//! pub struct DefaultInitConnection;
-//! impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
-//!     for DefaultInitConnection
-//! {
-//!     fn perform_handshake(
-//!         &mut self,
-//!         _keypair: &DefaultContext,
-//!         _endpoint: &mut peernet::transports::endpoint::Endpoint,
-//!         _listeners: &std::collections::HashMap<std::net::SocketAddr, TransportType>,
-//!         _messages_handler: DefaultMessagesHandler,
-//!     ) -> peernet::error::PeerNetResult<DefaultPeerId> {
-//!         Ok(DefaultPeerId::generate())
-//!     }
-//! }
-//!
-//!
 //! // Generating a context for the first peer
 //! let context = DefaultContext {
 //!   our_id: DefaultPeerId::generate(),
@@ -68,24 +17,35 @@
 //! // Setup configuration for the first peer
 //! let config = PeerNetConfiguration {
 //!     context: context,
-//!     max_in_connections: 10,
-//!     max_message_size: 1048576000,
-//!     rate_bucket_size: 60*1024,
-//!     rate_limit: 10000,
-//!     rate_time_window: Duration::from_secs(1),
+//!     max_in_connections: Some(10),
+//!     tcp: TcpSettings {
+//!         max_message_size: Some(1048576000),
+//!         rate_limit: 10000,
+//!         rate_time_window: Duration::from_secs(1),
+//!         rate_bucket_size: 60*1024,
+//!         read_timeout: Duration::from_secs(10),
+//!         write_timeout: Duration::from_secs(10),
+//!         listener_poll_interval: Duration::from_secs(1),
+//!         keepalive: None,
+//!         linger: None,
+//!     },
+//!     quic: QuicSettings::default(),
 //!     send_data_channel_size: 1000,
 //!     init_connection_handler: DefaultInitConnection,
 //!     optional_features: PeerNetFeatures::default(),
 //!     message_handler: DefaultMessagesHandler {},
 //!     peers_categories: HashMap::default(),
 //!     default_category_info: PeerNetCategoryInfo {
-//!         max_in_connections: 10,
-//!         max_out_connections: 10,
-//!         max_in_connections_per_ip: 10,
+//!         max_in_connections: Some(10),
+//!         max_out_connections: Some(10),
+//!         max_out_connections_per_ip: None,
+//!         read_timeout: None,
+//!         write_timeout: None,
+//!         max_in_connections_per_ip: Some(10),
 //!     },
+//!     trusted_peers: TrustedPeers::default(),
+//!     peer_id_rules: PeerIdRules::default(),
 //!     _phantom: std::marker::PhantomData,
-//!     read_timeout: Duration::from_secs(10),
-//!     write_timeout: Duration::from_secs(10),
 //! };
 //! // Setup the manager for the first peer
 //! let mut manager: PeerNetManager<
@@ -93,7 +53,7 @@
 //! DefaultContext,
 //! DefaultInitConnection,
 //! DefaultMessagesHandler,
-//! > = PeerNetManager::new(config);
+//! > = PeerNetManager::new(config).unwrap();
 //! // Get a random TCP port to connect to
 //! let port = {
 //!    let mut port = 0;
@@ -119,24 +79,35 @@
 //! // Setup configuration for the second peer
 //! let config = PeerNetConfiguration {
 //!     context: context2,
-//!     max_in_connections: 10,
+//!     max_in_connections: Some(10),
 //!     send_data_channel_size: 1000,
-//!     max_message_size: 1048576000,
-//!     rate_bucket_size: 60*1024,
-//!     rate_limit: 10000,
-//!     rate_time_window: Duration::from_secs(1),
+//!     tcp: TcpSettings {
+//!         max_message_size: Some(1048576000),
+//!         rate_limit: 10000,
+//!         rate_time_window: Duration::from_secs(1),
+//!         rate_bucket_size: 60*1024,
+//!         read_timeout: Duration::from_secs(10),
+//!         write_timeout: Duration::from_secs(10),
+//!         listener_poll_interval: Duration::from_secs(1),
+//!         keepalive: None,
+//!         linger: None,
+//!     },
+//!     quic: QuicSettings::default(),
 //!     message_handler: DefaultMessagesHandler {},
 //!     init_connection_handler: DefaultInitConnection,
 //!     optional_features: PeerNetFeatures::default(),
 //!     peers_categories: HashMap::default(),
 //!     default_category_info: PeerNetCategoryInfo {
-//!         max_in_connections: 10,
-//!         max_out_connections: 10,
-//!         max_in_connections_per_ip: 10,
+//!         max_in_connections: Some(10),
+//!         max_out_connections: Some(10),
+//!         max_out_connections_per_ip: None,
+//!         read_timeout: None,
+//!         write_timeout: None,
+//!         max_in_connections_per_ip: Some(10),
 //!     },
+//!     trusted_peers: TrustedPeers::default(),
+//!     peer_id_rules: PeerIdRules::default(),
 //!     _phantom: std::marker::PhantomData,
-//!     read_timeout: Duration::from_secs(10),
-//!     write_timeout: Duration::from_secs(10),
 //! };
 //! // Setup the manager for the second peer
 //! let mut manager2: PeerNetManager<
@@ -144,7 +115,7 @@
 //! DefaultContext,
 //! DefaultInitConnection,
 //! DefaultMessagesHandler,
-//! > = PeerNetManager::new(config);
+//! > = PeerNetManager::new(config).unwrap();
 //! // Try to connect to the first peer listener on its TCP port.
 //! manager2
 //!     .try_connect(
@@ -161,11 +132,42 @@
 //! ```
 // #![feature(tcp_linger)]
 
+pub mod address_book;
+#[cfg(feature = "async")]
+pub mod async_manager;
+pub mod audit;
+pub mod bandwidth;
+pub mod buffer_pool;
+pub mod clock;
 pub mod config;
 pub mod context;
+#[cfg(feature = "defaults")]
+pub mod defaults;
+pub mod discovery;
+mod dispatcher;
+#[cfg(feature = "ed25519")]
+pub mod ed25519;
 pub mod error;
+pub mod eviction;
+pub mod gater;
+pub mod handlers;
+mod handshake_pool;
+pub mod history;
+pub mod internal_handlers;
+pub mod memory;
+pub mod message_filter;
 pub mod messages;
 pub mod network_manager;
+#[cfg(feature = "snow")]
+pub mod noise;
 pub mod peer;
 pub mod peer_id;
+pub mod peer_list;
+pub mod port_mapping;
+pub mod proof_of_work;
+pub mod rejection;
+#[cfg(feature = "testing")]
+pub mod simulator;
+pub mod state;
+pub mod timings;
 pub mod transports;
@@ -0,0 +1,303 @@
+//! Memory held by the messages of the peers, see `PeerNetFeatures::memory_budget`
+//!
+//! The bytes of the messages waiting in the send channels of a peer, and of the messages it
+//! sent us until they're handled (in the queue of the handler workers included), are charged to
+//! the peer and to the manager while they're held. The figures are in
+//! `PeerNetManager::memory_stats` and in the state dump.
+//!
+//! When a message makes a peer, or all of them, exceed a cap of the `MemoryBudget`:
+//! - with `MemoryAction::DropLowPriority`, a low priority message to send is refused. The high
+//!   priority messages and the received ones are always kept.
+//! - with `MemoryAction::DisconnectHeaviest`, the message is kept and the peer holding the most
+//!   memory (the peer itself for `max_per_peer`) is marked. Its endpoint is shut down right away,
+//!   so that a peer that stopped reading is disconnected too, and its loops remove it with
+//!   `DisconnectReason::MemoryBudget`, not to take the lock of the connections from the senders.
+//!   For `max_total`, no other peer is marked until the memory of the marked one is released.
+//!
+//! As for the handler workers, a message is always accepted when nothing of its peer is held,
+//! even if it's bigger than `max_per_peer`.
+//!
+//! Only the complete messages are charged: the frame being read, and the chunks gathered by
+//! `receive_streamed` or by the TCP event loops until a frame is complete, are not. They're
+//! bounded by the max message size of each connection.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::{MemoryAction, MemoryBudget};
+
+/// Bytes held for a peer, see `SendChannels::memory_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PeerMemoryUsage {
+    /// Messages waiting in the send channels
+    pub send_queues: usize,
+    /// Received messages not handled yet
+    pub received: usize,
+}
+
+/// Memory of all the peers, see `PeerNetManager::memory_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryStats {
+    pub total: usize,
+    /// Low priority messages refused by `MemoryAction::DropLowPriority`
+    pub nb_dropped_messages: u64,
+    /// Peers marked to be disconnected by `MemoryAction::DisconnectHeaviest`
+    pub nb_disconnected_peers: u64,
+}
+
+pub(crate) type SharedMemoryAccounting = Arc<MemoryAccounting>;
+
+pub(crate) struct MemoryAccounting {
+    budget: MemoryBudget,
+    total: AtomicUsize,
+    // to find the heaviest peer, the ones disconnected are removed at the next registration
+    peers: Mutex<Vec<Weak<PeerMemory>>>,
+    // a peer was marked for `max_total` and still holds memory
+    eviction_pending: AtomicBool,
+    nb_dropped_messages: AtomicU64,
+    nb_disconnected_peers: AtomicU64,
+}
+
+impl MemoryAccounting {
+    pub(crate) fn new(budget: MemoryBudget) -> SharedMemoryAccounting {
+        Arc::new(MemoryAccounting {
+            budget,
+            total: AtomicUsize::new(0),
+            peers: Mutex::new(Vec::new()),
+            eviction_pending: AtomicBool::new(false),
+            nb_dropped_messages: AtomicU64::new(0),
+            nb_disconnected_peers: AtomicU64::new(0),
+        })
+    }
+
+    /// Accounting of a new connection
+    pub(crate) fn register(self: &Arc<Self>) -> Arc<PeerMemory> {
+        let peer = Arc::new(PeerMemory {
+            accounting: self.clone(),
+            send_queues: AtomicUsize::new(0),
+            received: AtomicUsize::new(0),
+            over_budget: AtomicBool::new(false),
+            victim: AtomicBool::new(false),
+            on_over_budget: Mutex::new(None),
+        });
+        let mut peers = self.peers.lock();
+        peers.retain(|peer| peer.strong_count() > 0);
+        peers.push(Arc::downgrade(&peer));
+        peer
+    }
+
+    pub(crate) fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            total: self.total.load(Ordering::Relaxed),
+            nb_dropped_messages: self.nb_dropped_messages.load(Ordering::Relaxed),
+            nb_disconnected_peers: self.nb_disconnected_peers.load(Ordering::Relaxed),
+        }
+    }
+
+    fn disconnect_heaviest(&self) {
+        if self.eviction_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let heaviest = self
+            .peers
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|peer| !peer.over_budget.load(Ordering::Relaxed))
+            .max_by_key(|peer| peer.held());
+        match heaviest {
+            Some(heaviest) => {
+                heaviest.victim.store(true, Ordering::Release);
+                heaviest.mark_over_budget();
+                // released in the meantime
+                if heaviest.held() == 0 {
+                    heaviest.release_victim();
+                }
+            }
+            None => self.eviction_pending.store(false, Ordering::Release),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryAccounting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryAccounting")
+            .field("budget", &self.budget)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// Memory held for a peer, shared by its send channels and its read loop
+pub(crate) struct PeerMemory {
+    accounting: SharedMemoryAccounting,
+    send_queues: AtomicUsize,
+    received: AtomicUsize,
+    // set by `MemoryAction::DisconnectHeaviest`, the peer is disconnected by its loops
+    over_budget: AtomicBool,
+    // marked for `max_total`, the next one is marked once this one holds nothing
+    victim: AtomicBool,
+    // shuts the endpoint of the peer down, see `PeerMemory::on_over_budget`
+    on_over_budget: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl PeerMemory {
+    pub(crate) fn usage(&self) -> PeerMemoryUsage {
+        PeerMemoryUsage {
+            send_queues: self.send_queues.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+        }
+    }
+
+    fn held(&self) -> usize {
+        self.send_queues.load(Ordering::Relaxed) + self.received.load(Ordering::Relaxed)
+    }
+
+    /// Whether adding `size` bytes exceeds the cap of the peer and the cap of all the peers
+    fn exceeds(&self, size: usize) -> (bool, bool) {
+        let exceeds = |current: usize, max: Option<usize>| {
+            current > 0 && max.map_or(false, |max| current + size > max)
+        };
+        let budget = &self.accounting.budget;
+        (
+            exceeds(self.held(), budget.max_per_peer),
+            exceeds(
+                self.accounting.total.load(Ordering::Relaxed),
+                budget.max_total,
+            ),
+        )
+    }
+
+    /// Charge a message to send, `None` if it's refused by `MemoryAction::DropLowPriority`
+    pub(crate) fn charge_send(
+        self: &Arc<Self>,
+        size: usize,
+        high_priority: bool,
+    ) -> Option<MemoryCharge> {
+        let (peer_exceeded, total_exceeded) = self.exceeds(size);
+        if (peer_exceeded || total_exceeded)
+            && !high_priority
+            && self.accounting.budget.action == MemoryAction::DropLowPriority
+        {
+            self.accounting
+                .nb_dropped_messages
+                .fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let charge = self.charge(size, true);
+        self.enforce(peer_exceeded, total_exceeded);
+        Some(charge)
+    }
+
+    /// Charge a received message
+    pub(crate) fn charge_received(self: &Arc<Self>, size: usize) -> MemoryCharge {
+        let (peer_exceeded, total_exceeded) = self.exceeds(size);
+        let charge = self.charge(size, false);
+        self.enforce(peer_exceeded, total_exceeded);
+        charge
+    }
+
+    fn charge(self: &Arc<Self>, size: usize, send: bool) -> MemoryCharge {
+        self.counter(send).fetch_add(size, Ordering::Relaxed);
+        self.accounting.total.fetch_add(size, Ordering::Relaxed);
+        MemoryCharge {
+            peer: self.clone(),
+            size,
+            send,
+        }
+    }
+
+    fn counter(&self, send: bool) -> &AtomicUsize {
+        if send {
+            &self.send_queues
+        } else {
+            &self.received
+        }
+    }
+
+    fn enforce(&self, peer_exceeded: bool, total_exceeded: bool) {
+        if self.accounting.budget.action != MemoryAction::DisconnectHeaviest {
+            return;
+        }
+        if peer_exceeded {
+            self.mark_over_budget();
+        } else if total_exceeded {
+            self.accounting.disconnect_heaviest();
+        }
+    }
+
+    fn mark_over_budget(&self) {
+        if !self.over_budget.swap(true, Ordering::Relaxed) {
+            tracing::debug!(held = self.held(), "peer over the memory budget");
+            self.accounting
+                .nb_disconnected_peers
+                .fetch_add(1, Ordering::Relaxed);
+            let on_over_budget = self.on_over_budget.lock().take();
+            if let Some(on_over_budget) = on_over_budget {
+                on_over_budget();
+            }
+        }
+    }
+
+    fn release_victim(&self) {
+        if self.victim.swap(false, Ordering::AcqRel) {
+            self.accounting
+                .eviction_pending
+                .store(false, Ordering::Release);
+        }
+    }
+
+    /// Called once when the peer is marked, to shut its endpoint down even if it stopped
+    /// reading or sending. Right away if it's already marked.
+    pub(crate) fn on_over_budget(&self, on_over_budget: impl FnOnce() + Send + 'static) {
+        *self.on_over_budget.lock() = Some(Box::new(on_over_budget));
+        if self.over_budget() {
+            let on_over_budget = self.on_over_budget.lock().take();
+            if let Some(on_over_budget) = on_over_budget {
+                on_over_budget();
+            }
+        }
+    }
+
+    /// Drop the callback of `PeerMemory::on_over_budget` once the peer is disconnected
+    pub(crate) fn clear_on_over_budget(&self) {
+        self.on_over_budget.lock().take();
+    }
+
+    /// Whether the peer must be disconnected with `DisconnectReason::MemoryBudget`
+    pub(crate) fn over_budget(&self) -> bool {
+        self.over_budget.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes of a message charged to its peer until it's dropped
+pub(crate) struct MemoryCharge {
+    peer: Arc<PeerMemory>,
+    size: usize,
+    send: bool,
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.peer
+            .counter(self.send)
+            .fetch_sub(self.size, Ordering::Relaxed);
+        self.peer
+            .accounting
+            .total
+            .fetch_sub(self.size, Ordering::Relaxed);
+        if self.peer.held() == 0 {
+            self.peer.release_victim();
+        }
+    }
+}
+
+impl Drop for PeerMemory {
+    fn drop(&mut self) {
+        self.release_victim();
+    }
+}
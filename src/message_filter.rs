@@ -0,0 +1,68 @@
+//! Admission of the received messages, see `PeerNetManager::set_message_filter`
+//!
+//! The filter is called by the read loop of a peer (or its event loop) for each message it
+//! receives, before it's given to the handler workers or the messages handler. It only sees the
+//! size and the first bytes of the message, e.g. the id of its handler, to reject the obviously
+//! invalid messages without deserializing them. The messages streamed to the handler
+//! (`MessagesHandler::stream_chunk_size`) are not filtered.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::error::{PeerNetError, PeerNetResult};
+
+/// Maximum number of bytes of a message given to the filter
+pub const MESSAGE_FILTER_PREFIX_SIZE: usize = 16;
+
+/// What to do with a received message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageVerdict {
+    /// Given to the handler
+    Accept,
+    /// Dropped, the connection is kept
+    Drop,
+    /// Dropped and the peer disconnected with `DisconnectReason::Filtered`
+    Disconnect(String),
+}
+
+pub trait MessageFilter<Id>: Send + Sync {
+    /// Verdict on a message of `size` bytes from `peer_id`, starting with `first_bytes` (at
+    /// most `MESSAGE_FILTER_PREFIX_SIZE` bytes). Called in the read loop of the peer, it
+    /// shouldn't block.
+    fn check(&self, peer_id: &Id, size: usize, first_bytes: &[u8]) -> MessageVerdict;
+}
+
+pub type SharedMessageFilter<Id> = Arc<dyn MessageFilter<Id>>;
+
+impl<Id> Debug for dyn MessageFilter<Id> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MessageFilter")
+    }
+}
+
+/// Filter of the manager, shared with the read loops so that a change applies to the
+/// connected peers
+pub(crate) type MessageFilterSlot<Id> = Arc<RwLock<Option<SharedMessageFilter<Id>>>>;
+
+/// Whether the message `data` of `peer_id` is given to the handler, a `MessageFiltered` error
+/// if the peer must be disconnected
+pub(crate) fn check_message<Id>(
+    slot: &MessageFilterSlot<Id>,
+    peer_id: &Id,
+    data: &[u8],
+) -> PeerNetResult<bool> {
+    // not locked while the filter runs
+    let Some(filter) = slot.read().clone() else {
+        return Ok(true);
+    };
+    let first_bytes = &data[..data.len().min(MESSAGE_FILTER_PREFIX_SIZE)];
+    match filter.check(peer_id, data.len(), first_bytes) {
+        MessageVerdict::Accept => Ok(true),
+        MessageVerdict::Drop => Ok(false),
+        MessageVerdict::Disconnect(reason) => {
+            Err(PeerNetError::MessageFiltered.error("message filter", Some(reason)))
+        }
+    }
+}
@@ -1,11 +1,98 @@
+use std::time::Instant;
+
 use crate::error::PeerNetResult;
+use crate::peer::PeerHandle;
+
+pub use bytes::Bytes;
+
+/// Information about a received message, see `MessagesHandler::handle_with_meta`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageMeta {
+    /// When its last byte was read, before it waited for a handler worker (see
+    /// `PeerNetFeatures::handler_workers`)
+    pub received_at: Instant,
+}
+
+impl MessageMeta {
+    /// Meta of a message received now
+    pub fn now() -> MessageMeta {
+        MessageMeta {
+            received_at: Instant::now(),
+        }
+    }
+}
 
 pub trait MessagesSerializer<M> {
     /// Serialize the message
     fn serialize(&self, message: &M, buffer: &mut Vec<u8>) -> PeerNetResult<()>;
+
+    /// Expected size of the serialized message, to take a buffer of the pool that can hold it
+    /// without reallocating. 0 by default, the smallest buffers of the pool are used.
+    fn size_hint(&self, _message: &M) -> usize {
+        0
+    }
+}
+
+/// Serializer of the messages already serialized, sent as they are. For the applications that
+/// serialize their messages themselves, and for the relayed transport forwarding the frames it
+/// receives
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawSerializer;
+
+impl MessagesSerializer<Vec<u8>> for RawSerializer {
+    fn serialize(&self, message: &Vec<u8>, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        buffer.extend_from_slice(message);
+        Ok(())
+    }
 }
 
 pub trait MessagesHandler<Id>: Clone + Send + 'static {
-    /// Handle the message received from the network
-    fn handle(&self, data: &[u8], peer_id: &Id) -> PeerNetResult<()>;
+    /// Handle the message received from the network.
+    /// The buffer is handed by value so that it can be kept without being copied.
+    fn handle(&self, data: Bytes, peer_id: &Id) -> PeerNetResult<()>;
+
+    /// Handle the message received from the network with a handle on the peer that sent it,
+    /// that can be used to answer directly without going through the manager.
+    /// Calls `handle` by default.
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        self.handle(data, &peer.peer_id)
+    }
+
+    /// Same as `handle_with_peer` with the information of the message, e.g. its reception time
+    /// to measure the latency of its propagation. This is the method called by the read loops
+    /// and the handler workers, it calls `handle_with_peer` by default.
+    /// The streamed messages (see `stream_chunk_size`) don't go through it.
+    fn handle_with_meta(
+        &self,
+        data: Bytes,
+        peer: &PeerHandle<Id>,
+        _meta: MessageMeta,
+    ) -> PeerNetResult<()> {
+        self.handle_with_peer(data, peer)
+    }
+
+    /// Opt into the streaming of big messages: the messages bigger than this size are not
+    /// buffered whole but given to `on_message_start`, `on_chunk` and `on_message_end` in chunks
+    /// of at most this size. The chunks are always delivered from the read loop of the peer
+    /// (or from its event loop, see `PeerNetFeatures::tcp_reactor`).
+    /// Only plain TCP endpoints stream messages, others deliver them whole to `handle_with_peer`.
+    /// A size of 0 is handled as 1.
+    fn stream_chunk_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called when a message bigger than `stream_chunk_size` starts being received
+    fn on_message_start(&self, _size: usize, _peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    /// Called for each chunk of a streamed message, in order
+    fn on_chunk(&self, _chunk: Bytes, _peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    /// Called once every chunk of a streamed message has been given to `on_chunk`
+    fn on_message_end(&self, _peer: &PeerHandle<Id>) -> PeerNetResult<()> {
+        Ok(())
+    }
 }
@@ -2,20 +2,54 @@
 //!
 //! It is the entry point of the library and is used to create and manage the transports and the peers.
 
-use std::collections::HashSet;
-use std::net::IpAddr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread::JoinHandle;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+};
 
-use crate::config::PeerNetCategoryInfo;
+use crate::address_book::AddressBook;
+use crate::audit::{audit, AuditOutcome, AuditRecord};
+use crate::bandwidth::{Bandwidth, BandwidthRates, BandwidthSnapshot, SharedBandwidth};
+use crate::buffer_pool::{BufferPool, SharedBufferPool};
+use crate::clock::SharedClock;
+use crate::config::{
+    report, under_limit, DiagnosticEvent, PeerIdRules, PeerNetCategories, PeerNetCategoryInfo,
+    PeerNetFeatures, TransportSettings, TrustedPeers,
+};
 use crate::context::Context;
+use crate::dispatcher::MessageDispatcher;
+use crate::error::PeerNetError;
+use crate::eviction::{EvictionCandidate, SharedEvictionPolicy};
+use crate::gater::{DefaultConnectionGater, SharedConnectionGater};
+use crate::handshake_pool::HandshakePool;
+use crate::history::{ConnectionEvent, ConnectionEventKind, ConnectionHistory, DisconnectReason};
+use crate::internal_handlers::peer_management::PeerManagementHooks;
+use crate::internal_handlers::relay::RelayHandler;
+use crate::memory::{MemoryAccounting, MemoryStats, SharedMemoryAccounting};
+use crate::message_filter::{MessageFilterSlot, SharedMessageFilter};
 use crate::messages::MessagesHandler;
-use crate::peer::PeerConnectionType;
+use crate::peer::{join_threads, new_peer, PeerConnectionType, PeerThreads};
 use crate::peer_id::PeerId;
+use crate::peer_list::PeerListSnapshot;
+use crate::port_mapping::PortMapper;
+use crate::state::{
+    category_stats, connections_snapshot, snapshot, CategoryStats, ConnectionsSnapshot,
+    PeerNetStateSnapshot, STATE_LOCK_TIMEOUT,
+};
+#[cfg(feature = "testing")]
+use crate::transports::MockTransportConfig;
 use crate::transports::{
-    QuicConnectionConfig, QuicTransportConfig, TcpConnectionConfig, TcpTransportConfig,
-    TransportConfig,
+    limiter_options, DialPacer, QuicConnectionConfig, QuicTransportConfig, TcpConnectionConfig,
+    TcpTransportConfig, TransportConfig,
 };
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use crossbeam::select;
 use parking_lot::RwLock;
 
 use crate::{
@@ -29,11 +63,88 @@ use crate::{
 pub struct ActiveConnections<Id: PeerId> {
     pub nb_in_connections: usize,
     pub nb_out_connections: usize,
-    /// Peers attempting to connect but not yet finished initialization
-    pub in_connection_queue: HashSet<SocketAddr>,
-    pub out_connection_queue: HashSet<SocketAddr>,
+    /// Peers attempting to connect but not yet finished initialization, with the time they
+    /// were queued
+    pub in_connection_queue: HashMap<SocketAddr, Instant>,
+    pub out_connection_queue: HashMap<SocketAddr, Instant>,
+    /// Slots of `max_in_connections` held by the incoming connections doing their handshake,
+    /// see `nb_in_reservations`
+    pub(crate) in_reservations: Arc<AtomicUsize>,
+    /// Incoming connections doing their handshake to replace a peer, see `reserve_incoming`
+    pub(crate) in_evictions: Arc<AtomicUsize>,
+    /// Entries of the queues evicted by `evict_stale_handshakes`
+    pub nb_evicted_handshakes: u64,
     pub connections: HashMap<Id, PeerConnection>,
     pub listeners: HashMap<SocketAddr, TransportType>,
+    /// IP the connected peers see for us, reported after the handshake
+    pub observed_addresses: HashMap<Id, IpAddr>,
+    /// Addresses we dialed and on which we reached ourselves, refused by `try_connect`. At most
+    /// `MAX_SELF_ADDRESSES`, see `add_self_address`
+    pub self_addresses: HashSet<SocketAddr>,
+    /// Last lifecycle events of the connections, see `PeerNetManager::recent_events`
+    pub history: ConnectionHistory<Id>,
+    /// Bytes sent and received by the connections already removed, and by the connected ones
+    /// before `reset_bandwidth`, per category (`None` for the peers of no category), see
+    /// `PeerNetManager::category_stats`
+    pub removed_bandwidth: HashMap<Option<String>, BandwidthSnapshot>,
+    /// Threads of the peers, joined when the manager is dropped
+    pub peer_threads: PeerThreads,
+    /// Peers accepted beyond the limits, see `PeerNetConfiguration::trusted_peers`
+    pub trusted_peers: TrustedPeers<Id>,
+    /// Peers accepted or refused by their id, see `PeerNetConfiguration::peer_id_rules`
+    pub peer_id_rules: PeerIdRules<Id>,
+    /// Checks of the new connections, see `PeerNetManager::set_connection_gater`
+    pub gater: SharedConnectionGater<Id>,
+    /// Checks of the received messages, see `PeerNetManager::set_message_filter`
+    pub(crate) message_filter: MessageFilterSlot<Id>,
+    /// Time of the connection queues and of `PeerConnection::connected_at`, see
+    /// `PeerNetFeatures::clock`
+    pub clock: SharedClock,
+    /// Memory held by the messages of the peers, see `PeerNetFeatures::memory_budget`
+    pub(crate) memory: Option<SharedMemoryAccounting>,
+    /// Runs the handshakes of the incoming connections, see `PeerNetFeatures::handshake_workers`
+    pub(crate) handshake_pool: Option<HandshakePool>,
+    /// Start times of the dials, see `PeerNetFeatures::dial_pacing`
+    pub(crate) dial_pacer: Option<DialPacer>,
+    /// Addresses of the out connections that succeeded, dialed again by the
+    /// `ConnectionSupervisor`
+    pub address_book: AddressBook<Id>,
+    /// Called with the removed connections, see `PeerNetManager::watch_relay`
+    pub(crate) disconnect_hooks: DisconnectHooks<Id>,
+}
+
+/// Called with the id of a removed connection
+pub(crate) type DisconnectHook<Id> = Arc<dyn Fn(&Id) + Send + Sync>;
+
+/// Hooks by key, a key is registered once
+pub(crate) struct DisconnectHooks<Id>(Vec<(usize, DisconnectHook<Id>)>);
+
+impl<Id> Default for DisconnectHooks<Id> {
+    fn default() -> Self {
+        DisconnectHooks(Vec::new())
+    }
+}
+
+impl<Id> fmt::Debug for DisconnectHooks<Id> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DisconnectHooks")
+            .field("len", &self.0.len())
+            .finish()
+    }
+}
+
+impl<Id> DisconnectHooks<Id> {
+    pub(crate) fn register(&mut self, (key, hook): (usize, DisconnectHook<Id>)) {
+        if !self.0.iter().any(|(registered, _)| *registered == key) {
+            self.0.push((key, hook));
+        }
+    }
+
+    fn call(&self, id: &Id) {
+        for (_, hook) in &self.0 {
+            hook(id);
+        }
+    }
 }
 
 // TODO: Use std one when stable
@@ -49,8 +160,144 @@ pub(crate) fn to_canonical(ip: IpAddr) -> IpAddr {
     }
 }
 
+/// Network of a peer reporting our address: its /24 for IPv4, its /48 for IPv6
+fn voter_network(ip: IpAddr) -> IpAddr {
+    match to_canonical(ip) {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & 0xffff_ff00)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+    }
+}
+
+pub(crate) fn category_of(
+    addr: &SocketAddr,
+    categories: &PeerNetCategories,
+    default_category_info: PeerNetCategoryInfo,
+) -> (Option<String>, PeerNetCategoryInfo) {
+    let ip = to_canonical(addr.ip());
+    match categories.iter().find(|(_, info)| info.0.contains(&ip)) {
+        Some((category_name, info)) => (Some(category_name.clone()), info.1),
+        None => (None, default_category_info),
+    }
+}
+
+/// Reserve a slot for an in connection from `addr` that wasn't accepted by a listener (relayed
+/// or given to `PeerNetManager::connect_endpoint`), `None` if it's refused before its handshake
+fn reserve_unlistened_in<Id: PeerId>(
+    active_connections: &ActiveConnections<Id>,
+    addr: &SocketAddr,
+    max_in_connections: Option<usize>,
+    category_name: Option<String>,
+    category_info: PeerNetCategoryInfo,
+) -> Option<InReservation> {
+    let accepted = active_connections.trusted_peers.is_trusted(None, addr)
+        || (active_connections
+            .gater
+            .check_accept(active_connections, addr, max_in_connections)
+            && active_connections.gater.check_pre_handshake(
+                active_connections,
+                addr,
+                category_name,
+                category_info,
+            ));
+    accepted.then(|| active_connections.reserve_in())
+}
+
+/// Slot of `max_in_connections` held by an incoming connection from its accept to the end of
+/// its handshake, released when dropped. `confirm_reserved` drops it under the lock that adds
+/// the connection, so that the slot is never counted twice nor missed.
+#[derive(Debug)]
+pub(crate) struct InReservation {
+    counter: Arc<AtomicUsize>,
+    // accepted beyond `max_in_connections` to replace a peer, see `reserve_incoming`
+    eviction: Option<PendingEviction>,
+}
+
+impl Drop for InReservation {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub(crate) struct PendingEviction {
+    max_in_connections: Option<usize>,
+    policy: SharedEvictionPolicy,
+}
+
+impl fmt::Debug for PendingEviction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingEviction")
+            .field("max_in_connections", &self.max_in_connections)
+            .finish()
+    }
+}
+
 impl<Id: PeerId> ActiveConnections<Id> {
-    /// Check if a new connection from a specific address can be accepted or not
+    /// Number of incoming connections accepted and still doing their handshake, they count in
+    /// `max_in_connections` with `nb_in_connections`
+    pub fn nb_in_reservations(&self) -> usize {
+        self.in_reservations.load(Ordering::Acquire)
+    }
+
+    /// Take a slot of `max_in_connections`, the caller holds the lock of the connections since
+    /// its check so that the concurrent accepts can't take the same slot
+    pub(crate) fn reserve_in(&self) -> InReservation {
+        self.in_reservations.fetch_add(1, Ordering::AcqRel);
+        InReservation {
+            counter: self.in_reservations.clone(),
+            eviction: None,
+        }
+    }
+
+    /// Queue the dial of `addr` and return how long it waits for its turn. The address is in
+    /// `out_connection_queue` while it waits, so that it counts in the out connections.
+    pub(crate) fn queue_dial(&mut self, addr: SocketAddr) -> Duration {
+        let now = self.clock.now();
+        self.out_connection_queue.insert(addr, now);
+        self.dial_delay()
+    }
+
+    /// How long a dial requested now waits for its turn, see `PeerNetFeatures::dial_pacing`
+    pub(crate) fn dial_delay(&self) -> Duration {
+        self.dial_pacer
+            .as_ref()
+            .map_or(Duration::ZERO, |dial_pacer| {
+                dial_pacer.reserve(self.clock.now())
+            })
+    }
+
+    /// Remember an address on which a dial reached ourselves, nothing is added once there are
+    /// `MAX_SELF_ADDRESSES`
+    pub(crate) fn add_self_address(&mut self, addr: SocketAddr) {
+        if self.self_addresses.len() < MAX_SELF_ADDRESSES {
+            self.self_addresses
+                .insert(SocketAddr::new(to_canonical(addr.ip()), addr.port()));
+        }
+    }
+
+    /// Check if dialing `addr` with `transport_type` would reach ourselves: one of our
+    /// listeners, or an address on which we already reached ourselves. A listener on all the
+    /// interfaces is reached on the loopback. The IPs the peers report for us are not trusted
+    /// here, a peer could make us refuse any address: an address forwarded to us is only known
+    /// once we reached ourselves on it.
+    pub fn is_self_address(&self, transport_type: TransportType, addr: &SocketAddr) -> bool {
+        let ip = to_canonical(addr.ip());
+        if self
+            .self_addresses
+            .contains(&SocketAddr::new(ip, addr.port()))
+        {
+            return true;
+        }
+        self.listeners.iter().any(|(listener, listener_type)| {
+            let listener_ip = to_canonical(listener.ip());
+            *listener_type == transport_type
+                && listener.port() == addr.port()
+                && (listener_ip == ip
+                    || (listener_ip.is_unspecified() && (ip.is_loopback() || ip.is_unspecified())))
+        })
+    }
+
+    /// Check if a new connection from a specific address can be accepted or not. The
+    /// connections still doing their handshake count in the limit per IP.
     pub fn check_addr_accepted_pre_handshake(
         &self,
         addr: &SocketAddr,
@@ -74,8 +321,20 @@ impl<Id: PeerId> ActiveConnections<Id> {
                 }
             }
         }
-        nb_connection_for_this_ip < category_info.max_in_connections_per_ip
-            && nb_connection_for_this_category < category_info.max_in_connections
+        // the connections of the same IP still doing their handshake, `addr` itself can already
+        // be queued
+        nb_connection_for_this_ip += self
+            .in_connection_queue
+            .keys()
+            .filter(|queued| *queued != addr && to_canonical(queued.ip()) == ip)
+            .count();
+        under_limit(
+            nb_connection_for_this_ip,
+            category_info.max_in_connections_per_ip,
+        ) && under_limit(
+            nb_connection_for_this_category,
+            category_info.max_in_connections,
+        )
     }
 
     pub fn check_addr_accepted_post_handshake(
@@ -105,16 +364,47 @@ impl<Id: PeerId> ActiveConnections<Id> {
                 }
             }
         }
-        let category_check = if connection_type == PeerConnectionType::IN {
-            nb_connection_for_this_category < category_info.max_in_connections
-        } else {
-            nb_connection_for_this_category < category_info.max_out_connections
+        let (max_connections, max_connections_per_ip) = match connection_type {
+            PeerConnectionType::IN => (
+                category_info.max_in_connections,
+                category_info.max_in_connections_per_ip,
+            ),
+            PeerConnectionType::OUT => (
+                category_info.max_out_connections,
+                category_info.max_out_connections_per_ip,
+            ),
         };
-
-        nb_connection_for_this_ip < category_info.max_in_connections_per_ip && category_check
+        under_limit(nb_connection_for_this_ip, max_connections_per_ip)
+            && under_limit(nb_connection_for_this_category, max_connections)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn confirm_connection(
+        &mut self,
+        id: Id,
+        endpoint: Endpoint,
+        send_channels: SendChannels,
+        connection_type: PeerConnectionType,
+        category_name: Option<String>,
+        category_info: PeerNetCategoryInfo,
+        protocols: Vec<String>,
+    ) -> bool {
+        self.confirm_reserved(
+            id,
+            endpoint,
+            send_channels,
+            connection_type,
+            category_name,
+            category_info,
+            protocols,
+            None,
+        )
+    }
+
+    /// Same as `confirm_connection` for a connection that holds `reservation`, released once
+    /// the connection is added or refused
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn confirm_reserved(
         &mut self,
         id: Id,
         mut endpoint: Endpoint,
@@ -122,14 +412,55 @@ impl<Id: PeerId> ActiveConnections<Id> {
         connection_type: PeerConnectionType,
         category_name: Option<String>,
         category_info: PeerNetCategoryInfo,
+        protocols: Vec<String>,
+        reservation: Option<InReservation>,
     ) -> bool {
-        if self.check_addr_accepted_post_handshake(
+        let accepted = if !self.peer_id_rules.is_accepted(&id) {
+            tracing::debug!(peer_id = ?id, "refused by the peer id rules");
+            false
+        } else if self.gater.clone().check_post_handshake(
+            self,
             endpoint.get_target_addr(),
             category_name.clone(),
             category_info,
             &id,
             connection_type,
-        ) {
+        ) && reservation
+            .as_ref()
+            .and_then(|reservation| reservation.eviction.as_ref())
+            .map_or(true, |eviction| self.evict_for_incoming(eviction))
+        {
+            true
+        } else if !self.connections.contains_key(&id)
+            && self
+                .trusted_peers
+                .is_trusted(Some(&id), endpoint.get_target_addr())
+        {
+            if self.trusted_peers.evict_untrusted {
+                self.evict_untrusted(connection_type, &category_name);
+            }
+            true
+        } else {
+            false
+        };
+        if accepted {
+            self.history.record(
+                *endpoint.get_target_addr(),
+                ConnectionEventKind::HandshakeSucceeded {
+                    peer_id: id.clone(),
+                    direction: connection_type,
+                },
+            );
+            if connection_type == PeerConnectionType::OUT {
+                if let Some(transport_type) = endpoint.transport_type() {
+                    self.address_book.record_connected(
+                        id.clone(),
+                        transport_type,
+                        *endpoint.get_target_addr(),
+                        self.clock.now(),
+                    );
+                }
+            }
             self.connections.insert(
                 id,
                 PeerConnection {
@@ -139,23 +470,217 @@ impl<Id: PeerId> ActiveConnections<Id> {
                     //transport specific, it should be a wrapped type `ShutdownHandle`
                     endpoint,
                     connection_type,
+                    protocols,
+                    metadata: BTreeMap::new(),
+                    connected_at: self.clock.now(),
+                    flush: None,
+                    timers: None,
                 },
             );
             self.compute_counters();
             true
         } else {
+            self.history.record(
+                *endpoint.get_target_addr(),
+                ConnectionEventKind::Refused {
+                    direction: connection_type,
+                },
+            );
             endpoint.shutdown();
             self.compute_counters();
             false
         }
     }
 
+    /// Remove the untrusted connection of `connection_type` in `category_name` that exchanged
+    /// the fewest bytes since it was connected, whatever the resets of its counters
+    fn evict_untrusted(
+        &mut self,
+        connection_type: PeerConnectionType,
+        category_name: &Option<String>,
+    ) {
+        let evicted = self
+            .connections
+            .iter()
+            .filter(|(id, connection)| {
+                connection.connection_type == connection_type
+                    && connection.category_name == *category_name
+                    && !self
+                        .trusted_peers
+                        .is_trusted(Some(id), connection.endpoint.get_target_addr())
+            })
+            .min_by_key(|(_, connection)| {
+                let bandwidth = connection.endpoint.get_total_bandwidth();
+                bandwidth.bytes_sent + bandwidth.bytes_received
+            })
+            .map(|(id, _)| id.clone());
+        if let Some(id) = evicted {
+            tracing::info!(peer_id = ?id, "evicting peer for a trusted peer");
+            self.remove_connection_with_reason(&id, DisconnectReason::EvictedForTrustedPeer);
+        }
+    }
+
+    /// Reserve a slot for an incoming connection. When there are already `max_in_connections`
+    /// connected or doing their handshake, the connection is accepted to replace one of the
+    /// untrusted incoming ones if there is an `eviction_policy`: the peer it selects is only
+    /// evicted once the newcomer passed its handshake and `check_post_handshake`, so that an
+    /// unauthenticated socket can't evict anyone. There are at most as many of these newcomers
+    /// as peers that can be evicted. Returns `None` if the connection is refused.
+    pub(crate) fn reserve_incoming(
+        &mut self,
+        addr: &SocketAddr,
+        max_in_connections: Option<usize>,
+        eviction_policy: Option<&SharedEvictionPolicy>,
+    ) -> Option<InReservation> {
+        if self
+            .gater
+            .clone()
+            .check_accept(self, addr, max_in_connections)
+        {
+            return Some(self.reserve_in());
+        }
+        let eviction_policy = eviction_policy?;
+        let nb_candidates = self.eviction_candidates().0.len();
+        if self.in_evictions.load(Ordering::Acquire) >= nb_candidates {
+            return None;
+        }
+        self.in_evictions.fetch_add(1, Ordering::AcqRel);
+        Some(InReservation {
+            counter: self.in_evictions.clone(),
+            eviction: Some(PendingEviction {
+                max_in_connections,
+                policy: eviction_policy.clone(),
+            }),
+        })
+    }
+
+    /// The untrusted incoming connections, with their ids
+    fn eviction_candidates(&self) -> (Vec<&Id>, Vec<EvictionCandidate>) {
+        let now = self.clock.now();
+        self.connections
+            .iter()
+            .filter(|(id, connection)| {
+                connection.connection_type == PeerConnectionType::IN
+                    && !self
+                        .trusted_peers
+                        .is_trusted(Some(id), connection.endpoint.get_target_addr())
+            })
+            .map(|(id, connection)| {
+                (
+                    id,
+                    EvictionCandidate {
+                        peer_id: format!("{:?}", id),
+                        addr: *connection.endpoint.get_target_addr(),
+                        category: connection.category_name.clone(),
+                        connected_for: now.saturating_duration_since(connection.connected_at),
+                        bandwidth: connection.endpoint.get_total_bandwidth(),
+                        rates: connection.endpoint.get_rates(),
+                    },
+                )
+            })
+            .unzip()
+    }
+
+    /// Make room for a newcomer accepted beyond `max_in_connections`, by evicting the peer
+    /// selected by the policy unless a slot was freed during its handshake. Returns whether
+    /// there is room.
+    fn evict_for_incoming(&mut self, eviction: &PendingEviction) -> bool {
+        if under_limit(
+            self.nb_in_connections + self.nb_in_reservations(),
+            eviction.max_in_connections,
+        ) {
+            return true;
+        }
+        let (ids, candidates) = self.eviction_candidates();
+        if candidates.is_empty() {
+            return false;
+        }
+        let Some(id) = eviction
+            .policy
+            .select(&candidates)
+            .and_then(|index| ids.get(index))
+            .map(|id| (*id).clone())
+        else {
+            return false;
+        };
+        tracing::info!(peer_id = ?id, "evicting peer for an incoming connection");
+        self.remove_connection_with_reason(&id, DisconnectReason::Evicted);
+        true
+    }
+
+    /// Restart the bandwidth counters of the connection with `id` from zero, see
+    /// `Endpoint::reset_counters`. The counts before the reset stay in the stats of its
+    /// category. Returns them, `None` if the peer isn't connected.
+    pub fn reset_bandwidth(&mut self, id: &Id) -> Option<BandwidthSnapshot> {
+        let connection = self.connections.get(id)?;
+        let counted = connection.endpoint.reset_counters();
+        let bandwidth = self
+            .removed_bandwidth
+            .entry(connection.category_name.clone())
+            .or_default();
+        *bandwidth = *bandwidth + counted;
+        Some(counted)
+    }
+
     pub fn remove_connection(&mut self, id: &Id) {
-        println!("Removing connection from: {:?}", id);
+        self.remove_connection_with_reason(id, DisconnectReason::Closed);
+    }
+
+    /// Same as `remove_connection`, `reason` is kept in the history if the peer was connected.
+    /// With `PeerNetFeatures::disconnect_flush` and an orderly `reason`, the connection is shut
+    /// down (and its event recorded) by its write thread once it sent what it could of its queue.
+    pub fn remove_connection_with_reason(&mut self, id: &Id, reason: DisconnectReason) {
+        tracing::debug!(peer_id = ?id, ?reason, "removing connection");
+        self.observed_addresses.remove(id);
         if let Some(mut connection) = self.connections.remove(id) {
-            connection.shutdown();
+            let bandwidth = self
+                .removed_bandwidth
+                .entry(connection.category_name.clone())
+                .or_default();
+            *bandwidth = *bandwidth + connection.endpoint.get_bandwidth();
+            let flushing = reason.is_orderly()
+                && connection
+                    .flush
+                    .as_ref()
+                    .map_or(false, |flush| flush.try_send(reason.clone()).is_ok());
+            if !flushing {
+                let (nb_high_priority, nb_low_priority) =
+                    connection.send_channels.queued_messages();
+                self.history.record(
+                    *connection.endpoint.get_target_addr(),
+                    ConnectionEventKind::Disconnected {
+                        peer_id: id.clone(),
+                        reason,
+                        dropped_messages: nb_high_priority + nb_low_priority,
+                    },
+                );
+                connection.shutdown();
+            }
             self.compute_counters();
+            self.disconnect_hooks.call(id);
+        }
+    }
+
+    /// Remove the entries of the connection queues older than `timeout`, left by handshake
+    /// threads that died. Returns the number of entries removed.
+    pub fn evict_stale_handshakes(&mut self, timeout: Duration) -> usize {
+        let mut nb_evicted = 0;
+        let now = self.clock.now();
+        for queue in [
+            &mut self.in_connection_queue,
+            &mut self.out_connection_queue,
+        ] {
+            queue.retain(|addr, queued| {
+                let stale = now.saturating_duration_since(*queued) >= timeout;
+                if stale {
+                    tracing::warn!(%addr, "evicting stale handshake");
+                    nb_evicted += 1;
+                }
+                !stale
+            });
         }
+        self.nb_evicted_handshakes += nb_evicted as u64;
+        nb_evicted
     }
 
     pub fn compute_counters(&mut self) {
@@ -174,6 +699,120 @@ impl<Id: PeerId> ActiveConnections<Id> {
 
 pub type SharedActiveConnections<Id> = Arc<RwLock<ActiveConnections<Id>>>;
 
+/// Addresses kept in `ActiveConnections::self_addresses`
+pub const MAX_SELF_ADDRESSES: usize = 64;
+
+/// Longest wait for the threads of the peers when the manager is dropped
+pub const PEER_THREADS_JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Evict the stale entries of the connection queues every half of `handshake_timeout`, see
+/// `PeerNetFeatures::handshake_timeout`
+fn start_handshake_reaper<Id: PeerId>(
+    active_connections: SharedActiveConnections<Id>,
+    handshake_timeout: Duration,
+    features: &PeerNetFeatures,
+) -> PeerNetResult<(Sender<()>, JoinHandle<()>)> {
+    let (stop_tx, stop_rx) = unbounded();
+    let clock = features.clock();
+    let handle = features
+        .threads
+        .builder("handshake_reaper".to_string())
+        .spawn(move || {
+            while !clock.wait(&stop_rx, handshake_timeout / 2) {
+                active_connections
+                    .write()
+                    .evict_stale_handshakes(handshake_timeout);
+            }
+        })
+        .map_err(|err| PeerNetError::SocketError.new("spawn handshake_reaper", err, None))?;
+    Ok((stop_tx, handle))
+}
+
+/// Handshake of `inner`, failing if the peer authenticated isn't `expected_id` when it's set.
+/// The result of the handshake is also sent to `result_tx`.
+#[derive(Clone)]
+struct ReportingInitConnection<Id, I> {
+    inner: I,
+    expected_id: Option<Id>,
+    result_tx: Sender<PeerNetResult<Id>>,
+}
+
+impl<
+        Id: PeerId,
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    > InitConnectionHandler<Id, Ctx, M> for ReportingInitConnection<Id, I>
+{
+    fn perform_handshake(
+        &mut self,
+        context: &Ctx,
+        endpoint: &mut Endpoint,
+        listeners: &HashMap<SocketAddr, TransportType>,
+        messages_handler: M,
+    ) -> PeerNetResult<Id> {
+        let mismatch = |peer_id: &Id| {
+            PeerNetError::PeerIdMismatch.error(
+                "perform_handshake",
+                Some(format!(
+                    "expected {:?}, got {:?}",
+                    self.expected_id, peer_id
+                )),
+            )
+        };
+        let (result, reported) =
+            match self
+                .inner
+                .perform_handshake(context, endpoint, listeners, messages_handler)
+            {
+                Ok(peer_id)
+                    if self
+                        .expected_id
+                        .as_ref()
+                        .map_or(true, |expected_id| &peer_id == expected_id) =>
+                {
+                    (Ok(peer_id.clone()), Ok(peer_id))
+                }
+                Ok(peer_id) => (Err(mismatch(&peer_id)), Err(mismatch(&peer_id))),
+                Err(err) => {
+                    let reported = PeerNetError::HandshakeError
+                        .error("perform_handshake", Some(err.to_string()));
+                    (Err(err), Err(reported))
+                }
+            };
+        let _ = self.result_tx.try_send(reported);
+        result
+    }
+
+    fn fallback_function(
+        &mut self,
+        context: &Ctx,
+        endpoint: &mut Endpoint,
+        listeners: &HashMap<SocketAddr, TransportType>,
+    ) -> PeerNetResult<()> {
+        self.inner.fallback_function(context, endpoint, listeners)
+    }
+}
+
+// connecting thread and result of the handshake, returned by `try_connect_reporting`
+pub(crate) type ReportingConnect<Id> = (JoinHandle<PeerNetResult<()>>, Receiver<PeerNetResult<Id>>);
+
+/// Wait for a connection started by `PeerNetManager::try_connect_reporting` and return the id
+/// given by its handshake
+pub(crate) fn wait_handshake<Id: PeerId>(
+    connect: JoinHandle<PeerNetResult<()>>,
+    result_rx: Receiver<PeerNetResult<Id>>,
+) -> PeerNetResult<Id> {
+    connect.join().map_err(|_| {
+        PeerNetError::PeerConnectionError
+            .error("wait_handshake", Some("connect panicked".to_string()))
+    })??;
+    // no result if the connection failed before the handshake
+    result_rx
+        .recv()
+        .map_err(|err| PeerNetError::HandshakeError.new("wait_handshake", err, None))?
+}
+
 /// Main structure of the PeerNet library used to manage the transports and the peers.
 pub struct PeerNetManager<
     Id: PeerId,
@@ -187,8 +826,15 @@ pub struct PeerNetManager<
     init_connection_handler: I,
     context: Ctx,
     transports: HashMap<TransportType, InternalTransportType<Id>>,
-    total_bytes_received: Arc<RwLock<u64>>,
-    total_bytes_sent: Arc<RwLock<u64>>,
+    total_bandwidth: SharedBandwidth,
+    buffer_pool: SharedBufferPool,
+    dispatcher: Option<MessageDispatcher<Id>>,
+    port_mappers: HashMap<SocketAddr, PortMapper>,
+    // never triggered, the relayed peers and the ones of `connect_endpoint` are stopped by
+    // shutting down their endpoint
+    relayed_peer_stop: (Sender<()>, Receiver<()>),
+    relay_acceptor: Option<(Sender<()>, JoinHandle<PeerNetResult<()>>)>,
+    handshake_reaper: Option<(Sender<()>, JoinHandle<()>)>,
 }
 
 impl<
@@ -199,23 +845,71 @@ impl<
     > PeerNetManager<Id, Ctx, I, M>
 {
     /// Creates a new PeerNetManager. Initializes a new database of peers and have no transports by default.
-    pub fn new(config: PeerNetConfiguration<Id, Ctx, I, M>) -> PeerNetManager<Id, Ctx, I, M> {
+    /// Fails if the configuration isn't valid, see `PeerNetConfiguration::validate`.
+    pub fn new(
+        config: PeerNetConfiguration<Id, Ctx, I, M>,
+    ) -> PeerNetResult<PeerNetManager<Id, Ctx, I, M>> {
+        config.validate().map_err(|err| {
+            PeerNetError::ConfigError(err.clone()).new("PeerNetManager::new", err, None)
+        })?;
+        tracing::info!(config = %config.describe(), "starting PeerNet manager");
         let context = config.context.clone();
+        let buffer_pool = Arc::new(BufferPool::new(&config.optional_features.buffer_pool));
+        let handshake_pool = match config.optional_features.handshake_workers {
+            Some(handshake_workers) => Some(HandshakePool::start(
+                handshake_workers,
+                &config.optional_features,
+            )?),
+            None => None,
+        };
         let active_connections = Arc::new(RwLock::new(ActiveConnections {
             nb_out_connections: 0,
             nb_in_connections: 0,
-            in_connection_queue: HashSet::new(),
-            out_connection_queue: HashSet::new(),
+            in_connection_queue: HashMap::new(),
+            out_connection_queue: HashMap::new(),
+            in_reservations: Default::default(),
+            in_evictions: Default::default(),
+            nb_evicted_handshakes: 0,
             connections: Default::default(),
             listeners: Default::default(),
+            observed_addresses: Default::default(),
+            self_addresses: Default::default(),
+            removed_bandwidth: Default::default(),
+            peer_threads: Default::default(),
+            trusted_peers: config.trusted_peers.clone(),
+            peer_id_rules: config.peer_id_rules.clone(),
+            gater: Arc::new(DefaultConnectionGater),
+            message_filter: Default::default(),
+            clock: config.optional_features.clock(),
+            handshake_pool,
+            memory: config
+                .optional_features
+                .memory_budget
+                .map(MemoryAccounting::new),
+            dial_pacer: config.optional_features.dial_pacing.map(DialPacer::new),
+            address_book: Default::default(),
+            disconnect_hooks: Default::default(),
+            history: ConnectionHistory::new(
+                config.optional_features.connection_history.unwrap_or(0),
+            )
+            .set_audit(config.optional_features.audit.clone()),
         }));
+        let dispatcher = config
+            .optional_features
+            .handler_workers
+            .map(|handler_workers| {
+                MessageDispatcher::start(
+                    handler_workers,
+                    config.message_handler.clone(),
+                    active_connections.clone(),
+                )
+            });
 
         #[cfg(feature = "deadlock_detection")]
         {
             // only for #[cfg]
             use parking_lot::deadlock;
             use std::thread;
-            use std::time::Duration;
 
             // Create a background thread which checks for deadlocks every 10s
             thread::spawn(move || loop {
@@ -225,28 +919,134 @@ impl<
                     continue;
                 }
 
-                println!("{} deadlocks detected", deadlocks.len());
+                tracing::error!("{} deadlocks detected", deadlocks.len());
                 for (i, threads) in deadlocks.iter().enumerate() {
-                    println!("Deadlock #{}", i);
                     for t in threads {
-                        println!("Thread Id {:#?}", t.thread_id());
-                        println!("{:#?}", t.backtrace());
+                        tracing::error!(
+                            deadlock = i,
+                            thread_id = t.thread_id(),
+                            "{:#?}",
+                            t.backtrace()
+                        );
                     }
                 }
             });
         } // only for #[cfg]
-        PeerNetManager {
+        let handshake_reaper = match config.optional_features.handshake_timeout {
+            Some(handshake_timeout) => Some(start_handshake_reaper(
+                active_connections.clone(),
+                handshake_timeout,
+                &config.optional_features,
+            )?),
+            None => None,
+        };
+        Ok(PeerNetManager {
             init_connection_handler: config.init_connection_handler.clone(),
             message_handler: config.message_handler.clone(),
             config,
             context,
             transports: Default::default(),
             active_connections,
-            total_bytes_received: Arc::new(RwLock::new(0)),
-            total_bytes_sent: Arc::new(RwLock::new(0)),
+            total_bandwidth: Bandwidth::new_shared(),
+            buffer_pool,
+            dispatcher,
+            port_mappers: HashMap::new(),
+            relayed_peer_stop: unbounded(),
+            relay_acceptor: None,
+            handshake_reaper,
+        })
+    }
+
+    /// The transport of `transport_type`, created from its settings on first use
+    fn transport(&mut self, transport_type: TransportType) -> &mut InternalTransportType<Id> {
+        let config = &self.config;
+        self.transports.entry(transport_type).or_insert_with(|| {
+            InternalTransportType::from_transport_type(
+                transport_type,
+                self.active_connections.clone(),
+                Self::transport_config(config, transport_type),
+                config.optional_features.clone(),
+                self.total_bandwidth.clone(),
+                self.buffer_pool.clone(),
+                self.dispatcher.clone(),
+            )
+        })
+    }
+
+    /// Configuration of the transport `transport_type` from the settings of the manager
+    fn transport_config(
+        config: &PeerNetConfiguration<Id, Ctx, I, M>,
+        transport_type: TransportType,
+    ) -> TransportConfig {
+        match transport_type {
+            TransportType::Tcp => TransportConfig::Tcp(Box::new(TcpTransportConfig {
+                max_in_connections: config.max_in_connections,
+                peer_categories: config.peers_categories.clone(),
+                default_category_info: config.default_category_info,
+                connection_config: TcpConnectionConfig {
+                    rate_limit: config.tcp.rate_limit,
+                    rate_time_window: config.tcp.rate_time_window,
+                    rate_bucket_size: config.tcp.rate_bucket_size,
+                    data_channel_size: config.send_data_channel_size,
+                    max_message_size: config.tcp.max_message_size,
+                    read_timeout: config.tcp.read_timeout,
+                    write_timeout: config.tcp.write_timeout,
+                    small_message_size: config.optional_features.small_message_size,
+                    handshake_data_limit: config.optional_features.handshake_data_limit,
+                    clock: config.optional_features.clock(),
+                    io_uring: config.optional_features.io_uring,
+                },
+                read_timeout: config.tcp.read_timeout,
+                write_timeout: config.tcp.write_timeout,
+                listener_poll_interval: config.tcp.listener_poll_interval,
+                keepalive: config.tcp.keepalive,
+                linger: config.tcp.linger,
+            })),
+            TransportType::Quic => TransportConfig::Quic(Box::new(QuicTransportConfig {
+                max_in_connections: config.max_in_connections,
+                connection_config: QuicConnectionConfig {
+                    local_addr: config.quic.local_addr,
+                    data_channel_size: config.send_data_channel_size,
+                },
+                peer_categories: config.peers_categories.clone(),
+                certificate: config.quic.certificate.clone(),
+                server_name: config.quic.server_name.clone(),
+                sni_routes: config.quic.sni_routes.clone(),
+            })),
+            #[cfg(feature = "testing")]
+            TransportType::Mock => TransportConfig::Mock(Box::new(MockTransportConfig {
+                max_in_connections: config.max_in_connections,
+                peer_categories: config.peers_categories.clone(),
+                default_category_info: config.default_category_info,
+                data_channel_size: config.send_data_channel_size,
+            })),
         }
     }
 
+    /// The transport of `transport_type` if it has a listener on `addr`. It's never created
+    /// here, not to start a transport only to find that it has no listener.
+    fn listener_transport(
+        &mut self,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        location: &'static str,
+    ) -> PeerNetResult<&mut InternalTransportType<Id>> {
+        let Some(transport) = self.transports.get_mut(&transport_type) else {
+            return Err(PeerNetError::TransportNotStarted
+                .error(location, Some(format!("transport: {:?}", transport_type))));
+        };
+        if self.active_connections.read().listeners.get(&addr) != Some(&transport_type) {
+            return Err(PeerNetError::ListenerNotFound.error(
+                location,
+                Some(format!(
+                    "address: {}, transport: {:?}",
+                    addr, transport_type
+                )),
+            ));
+        }
+        Ok(transport)
+    }
+
     /// Starts a listener on the given address and transport type.
     /// The listener will accept incoming connections, verify we have seats for the peer and then create a new peer and his thread.
     pub fn start_listener(
@@ -254,93 +1054,68 @@ impl<
         transport_type: TransportType,
         addr: SocketAddr,
     ) -> PeerNetResult<()> {
-        let transport = self.transports.entry(transport_type).or_insert_with(|| {
-            InternalTransportType::from_transport_type(
-                transport_type,
-                self.active_connections.clone(),
-                //TODO: Find a better way to avoid match there
-                match transport_type {
-                    TransportType::Tcp => TransportConfig::Tcp(Box::new(TcpTransportConfig {
-                        max_in_connections: self.config.max_in_connections,
-                        peer_categories: self.config.peers_categories.clone(),
-                        default_category_info: self.config.default_category_info,
-                        connection_config: TcpConnectionConfig {
-                            rate_limit: self.config.rate_limit,
-                            rate_time_window: self.config.rate_time_window,
-                            rate_bucket_size: self.config.rate_bucket_size,
-                            data_channel_size: self.config.send_data_channel_size,
-                            max_message_size: self.config.max_message_size,
-                            read_timeout: self.config.read_timeout,
-                            write_timeout: self.config.write_timeout,
-                        },
-                        read_timeout: self.config.read_timeout,
-                        write_timeout: self.config.write_timeout,
-                    })),
-                    TransportType::Quic => TransportConfig::Quic(Box::new(QuicTransportConfig {
-                        connection_config: QuicConnectionConfig {
-                            local_addr: "127.0.0.1:8080".parse().unwrap(),
-                            data_channel_size: self.config.send_data_channel_size,
-                        },
-                    })),
-                },
-                self.config.optional_features.clone(),
-                addr,
-                self.total_bytes_received.clone(),
-                self.total_bytes_sent.clone(),
-            )
-        });
-        transport.start_listener(
-            self.context.clone(),
+        let context = self.context.clone();
+        let message_handler = self.message_handler.clone();
+        let init_connection_handler = self.init_connection_handler.clone();
+        self.transport(transport_type).start_listener(
+            context,
             addr,
-            self.message_handler.clone(),
-            self.init_connection_handler.clone(),
+            message_handler,
+            init_connection_handler,
         )?;
+        if let Some(port_mapping) = &self.config.optional_features.port_mapping {
+            let port_mapper = PortMapper::start(addr, transport_type, port_mapping.clone())?;
+            self.port_mappers.insert(addr, port_mapper);
+        }
         Ok(())
     }
 
     /// Stops a listener on the given address and transport type.
+    /// The peers it accepted are disconnected, except those driven by `PeerNetFeatures::tcp_reactor`.
     /// TODO: Maybe have listener ids
     pub fn stop_listener(
         &mut self,
         transport_type: TransportType,
         addr: SocketAddr,
     ) -> PeerNetResult<()> {
-        let transport = self.transports.entry(transport_type).or_insert_with(|| {
-            InternalTransportType::from_transport_type(
-                transport_type,
-                self.active_connections.clone(),
-                //TODO: Find a better way to avoid match there
-                match transport_type {
-                    TransportType::Tcp => TransportConfig::Tcp(Box::new(TcpTransportConfig {
-                        max_in_connections: self.config.max_in_connections,
-                        peer_categories: self.config.peers_categories.clone(),
-                        default_category_info: self.config.default_category_info,
-                        connection_config: TcpConnectionConfig {
-                            rate_limit: self.config.rate_limit,
-                            rate_time_window: self.config.rate_time_window,
-                            rate_bucket_size: self.config.rate_bucket_size,
-                            data_channel_size: self.config.send_data_channel_size,
-                            max_message_size: self.config.max_message_size,
-                            read_timeout: self.config.read_timeout,
-                            write_timeout: self.config.write_timeout,
-                        },
-                        read_timeout: self.config.read_timeout,
-                        write_timeout: self.config.write_timeout,
-                    })),
-                    TransportType::Quic => TransportConfig::Quic(Box::new(QuicTransportConfig {
-                        connection_config: QuicConnectionConfig {
-                            local_addr: "127.0.0.1:8080".parse().unwrap(),
-                            data_channel_size: self.config.send_data_channel_size,
-                        },
-                    })),
-                },
-                self.config.optional_features.clone(),
-                addr,
-                self.total_bytes_received.clone(),
-                self.total_bytes_sent.clone(),
-            )
-        });
-        transport.stop_listener(addr)?;
+        self.listener_transport(transport_type, addr, "stop_listener")?
+            .stop_listener(addr)?;
+        if let Some(port_mapper) = self.port_mappers.remove(&addr) {
+            port_mapper.stop();
+        }
+        Ok(())
+    }
+
+    /// Moves the listener of `old_addr` to `new_addr`, e.g. to change its port or to listen
+    /// on a single interface. The new listener is started before the old one is stopped so
+    /// that we can always be dialed, the two addresses must not overlap.
+    /// With TCP the peers accepted by the old listener stay connected and in their category,
+    /// they are stopped along with the new listener. With QUIC they are disconnected as their
+    /// packets go through the socket of the old listener.
+    pub fn rebind_listener(
+        &mut self,
+        transport_type: TransportType,
+        old_addr: SocketAddr,
+        new_addr: SocketAddr,
+    ) -> PeerNetResult<()> {
+        let context = self.context.clone();
+        let message_handler = self.message_handler.clone();
+        let init_connection_handler = self.init_connection_handler.clone();
+        self.listener_transport(transport_type, old_addr, "rebind_listener")?
+            .rebind_listener(
+                context,
+                old_addr,
+                new_addr,
+                message_handler,
+                init_connection_handler,
+            )?;
+        if let Some(port_mapper) = self.port_mappers.remove(&old_addr) {
+            port_mapper.stop();
+        }
+        if let Some(port_mapping) = &self.config.optional_features.port_mapping {
+            let port_mapper = PortMapper::start(new_addr, transport_type, port_mapping.clone())?;
+            self.port_mappers.insert(new_addr, port_mapper);
+        }
         Ok(())
     }
 
@@ -353,48 +1128,379 @@ impl<
         addr: SocketAddr,
         timeout: std::time::Duration,
     ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
-        let transport = self.transports.entry(transport_type).or_insert_with(|| {
-            InternalTransportType::from_transport_type(
-                transport_type,
-                self.active_connections.clone(),
-                //TODO: Find a better way to avoid match there
-                match transport_type {
-                    TransportType::Tcp => TransportConfig::Tcp(Box::new(TcpTransportConfig {
-                        max_in_connections: self.config.max_in_connections,
-                        peer_categories: self.config.peers_categories.clone(),
-                        default_category_info: self.config.default_category_info,
-                        connection_config: TcpConnectionConfig {
-                            rate_limit: self.config.rate_limit,
-                            rate_time_window: self.config.rate_time_window,
-                            rate_bucket_size: self.config.rate_bucket_size,
-                            data_channel_size: self.config.send_data_channel_size,
-                            max_message_size: self.config.max_message_size,
-                            read_timeout: self.config.read_timeout,
-                            write_timeout: self.config.write_timeout,
-                        },
-                        read_timeout: self.config.read_timeout,
-                        write_timeout: self.config.write_timeout,
-                    })),
-                    TransportType::Quic => TransportConfig::Quic(Box::new(QuicTransportConfig {
-                        connection_config: QuicConnectionConfig {
-                            local_addr: "127.0.0.1:8080".parse().unwrap(),
-                            data_channel_size: self.config.send_data_channel_size,
-                        },
-                    })),
-                },
-                self.config.optional_features.clone(),
+        let init_connection_handler = self.init_connection_handler.clone();
+        self.try_connect_with(transport_type, addr, timeout, init_connection_handler)
+    }
+
+    /// Same as `try_connect`, but the connection is dropped if the handshake doesn't authenticate
+    /// `expected_id`, e.g. if the address of a bootstrap peer has been hijacked. The returned
+    /// thread waits for the handshake and fails with `PeerIdMismatch` in this case.
+    pub fn try_connect_expecting(
+        &mut self,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        expected_id: Id,
+        timeout: std::time::Duration,
+    ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
+        let (connect, result_rx) =
+            self.try_connect_reporting(transport_type, addr, Some(expected_id), timeout)?;
+        self.config
+            .optional_features
+            .threads
+            .builder(format!("try_connect_expecting_{:?}", addr))
+            .spawn(move || wait_handshake(connect, result_rx).map(|_| ()))
+            .map_err(|err| PeerNetError::SocketError.new("spawn try_connect_expecting", err, None))
+    }
+
+    /// Same as `try_connect`, the result of the handshake is also sent to the returned channel,
+    /// see `wait_handshake`
+    pub(crate) fn try_connect_reporting(
+        &mut self,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        expected_id: Option<Id>,
+        timeout: std::time::Duration,
+    ) -> PeerNetResult<ReportingConnect<Id>> {
+        let (result_tx, result_rx) = bounded(1);
+        let connect = self.try_connect_with(
+            transport_type,
+            addr,
+            timeout,
+            ReportingInitConnection {
+                inner: self.init_connection_handler.clone(),
+                expected_id,
+                result_tx,
+            },
+        )?;
+        Ok((connect, result_rx))
+    }
+
+    fn try_connect_with<J: InitConnectionHandler<Id, Ctx, M>>(
+        &mut self,
+        transport_type: TransportType,
+        addr: SocketAddr,
+        timeout: std::time::Duration,
+        init_connection_handler: J,
+    ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
+        let result = if self
+            .active_connections
+            .read()
+            .is_self_address(transport_type, &addr)
+        {
+            report(&self.config.optional_features.diagnostics, || {
+                DiagnosticEvent::SelfConnection {
+                    addr,
+                    direction: PeerConnectionType::OUT,
+                }
+            });
+            Err(PeerNetError::SelfConnection.error("try_connect", Some(addr.to_string())))
+        } else {
+            let context = self.context.clone();
+            let message_handler = self.message_handler.clone();
+            self.transport(transport_type).try_connect(
+                context,
                 addr,
-                self.total_bytes_received.clone(),
-                self.total_bytes_sent.clone(),
+                timeout,
+                message_handler,
+                init_connection_handler,
             )
+        };
+        audit(&self.config.optional_features.audit, || AuditRecord {
+            time: SystemTime::now(),
+            addr,
+            direction: PeerConnectionType::OUT,
+            outcome: if result.is_ok() {
+                AuditOutcome::Dialing
+            } else {
+                AuditOutcome::DialFailed
+            },
+            peer_id: None,
+            reason: result.as_ref().err().map(ToString::to_string),
         });
-        transport.try_connect(
+        result
+    }
+
+    /// Close the circuits and forget the reservations of `relay` that go through the peers
+    /// disconnected from us. Done by `try_connect_relayed` and `accept_relayed_connections`,
+    /// to call for a handler that only runs the relay service.
+    pub fn watch_relay<H: PeerManagementHooks<Id>>(&self, relay: &RelayHandler<Id, H>) {
+        self.active_connections
+            .write()
+            .disconnect_hooks
+            .register(relay.disconnect_hook());
+    }
+
+    /// Tries to connect to `target` through the connected peer `relay_id`, which must run the
+    /// relay service and on which `target` must have a reservation (see `RelayHandler`).
+    pub fn try_connect_relayed<H: PeerManagementHooks<Id>>(
+        &mut self,
+        relay: &RelayHandler<Id, H>,
+        relay_id: &Id,
+        target: Id,
+        timeout: std::time::Duration,
+    ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
+        self.watch_relay(relay);
+        let (relay_addr, send_channels) = {
+            let active_connections = self.active_connections.read();
+            let connection = active_connections
+                .connections
+                .get(relay_id)
+                .ok_or_else(|| {
+                    PeerNetError::PeerConnectionError.error(
+                        "try_connect_relayed",
+                        Some(format!("relay not connected: {:?}", relay_id)),
+                    )
+                })?;
+            (
+                *connection.endpoint.get_target_addr(),
+                connection.send_channels.clone(),
+            )
+        };
+        // the connection is in the category of the relay
+        let (category_name, category_info) = category_of(
+            &relay_addr,
+            &self.config.peers_categories,
+            self.config.default_category_info,
+        );
+        let relay = relay.clone();
+        let relay_id = relay_id.clone();
+        let context = self.context.clone();
+        let init_connection_handler = self.init_connection_handler.clone();
+        let message_handler = self.message_handler.clone();
+        let active_connections = self.active_connections.clone();
+        let peer_stop_rx = self.relayed_peer_stop.1.clone();
+        let features = self.config.optional_features.clone();
+        let buffer_pool = self.buffer_pool.clone();
+        let dispatcher = self.dispatcher.clone();
+        features
+            .reporting_builder(
+                format!("relayed_try_connect_{:?}", target),
+                "relayed try_connect",
+            )
+            .spawn(move || {
+                let endpoint =
+                    relay.open_circuit(&relay_id, relay_addr, &send_channels, target, timeout)?;
+                new_peer(
+                    context,
+                    Endpoint::Relayed(endpoint),
+                    init_connection_handler,
+                    message_handler,
+                    active_connections,
+                    peer_stop_rx,
+                    PeerConnectionType::OUT,
+                    category_name,
+                    category_info,
+                    features,
+                    buffer_pool,
+                    dispatcher,
+                    None,
+                    None,
+                );
+                Ok(())
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn relayed_try_connect", err, None))
+    }
+
+    /// Accepts the connections opened to us through the relays on which we have a reservation
+    /// (see `RelayHandler::reserve`), until the manager is dropped.
+    pub fn accept_relayed_connections<H: PeerManagementHooks<Id>>(
+        &mut self,
+        relay: &RelayHandler<Id, H>,
+    ) -> PeerNetResult<()> {
+        if self.relay_acceptor.is_some() {
+            return Err(PeerNetError::ListenerError.error(
+                "accept_relayed_connections",
+                Some("already accepting relayed connections".to_string()),
+            ));
+        }
+        self.watch_relay(relay);
+        let incoming = relay.incoming();
+        let (stop_tx, stop_rx) = unbounded::<()>();
+        let context = self.context.clone();
+        let init_connection_handler = self.init_connection_handler.clone();
+        let message_handler = self.message_handler.clone();
+        let active_connections = self.active_connections.clone();
+        let peer_stop_rx = self.relayed_peer_stop.1.clone();
+        let features = self.config.optional_features.clone();
+        let buffer_pool = self.buffer_pool.clone();
+        let dispatcher = self.dispatcher.clone();
+        let categories = self.config.peers_categories.clone();
+        let default_category_info = self.config.default_category_info;
+        let max_in_connections = self.config.max_in_connections;
+        let handle = features
+            .reporting_builder("relayed_listener".to_string(), "relayed listener")
+            .spawn(move || loop {
+                let (relay_id, mut endpoint) = select! {
+                    recv(stop_rx) -> _ => return Ok(()),
+                    recv(incoming) -> incoming => match incoming {
+                        Ok(incoming) => incoming,
+                        Err(_) => return Ok(()),
+                    },
+                };
+                let relay_addr = match active_connections.read().connections.get(&relay_id) {
+                    Some(connection) => *connection.endpoint.get_target_addr(),
+                    None => {
+                        endpoint.shutdown();
+                        continue;
+                    }
+                };
+                endpoint.address = relay_addr;
+                let (category_name, category_info) =
+                    category_of(&relay_addr, &categories, default_category_info);
+                // under the write lock, not to give the same slot to concurrent connections
+                let reservation = reserve_unlistened_in(
+                    &active_connections.write(),
+                    &relay_addr,
+                    max_in_connections,
+                    category_name.clone(),
+                    category_info,
+                );
+                if reservation.is_none() {
+                    endpoint.shutdown();
+                    continue;
+                }
+                new_peer(
+                    context.clone(),
+                    Endpoint::Relayed(endpoint),
+                    init_connection_handler.clone(),
+                    message_handler.clone(),
+                    active_connections.clone(),
+                    peer_stop_rx.clone(),
+                    PeerConnectionType::IN,
+                    category_name,
+                    category_info,
+                    features.clone(),
+                    buffer_pool.clone(),
+                    dispatcher.clone(),
+                    None,
+                    reservation,
+                );
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn relayed_listener", err, None))?;
+        self.relay_acceptor = Some((stop_tx, handle));
+        Ok(())
+    }
+
+    /// Run the handshake on a connection opened outside of the transports, e.g. an
+    /// `Endpoint::Custom` linking two managers in memory, and add the peer if it succeeds. An in
+    /// connection is checked by the gater like the accepted ones and is in the category of
+    /// the target address of the endpoint. The peer is stopped by shutting down its endpoint.
+    pub fn connect_endpoint(
+        &mut self,
+        mut endpoint: Endpoint,
+        direction: PeerConnectionType,
+    ) -> PeerNetResult<()> {
+        let addr = *endpoint.get_target_addr();
+        let (category_name, category_info) = category_of(
+            &addr,
+            &self.config.peers_categories,
+            self.config.default_category_info,
+        );
+        let reservation = match direction {
+            PeerConnectionType::IN => reserve_unlistened_in(
+                &self.active_connections.write(),
+                &addr,
+                self.config.max_in_connections,
+                category_name.clone(),
+                category_info,
+            ),
+            PeerConnectionType::OUT => None,
+        };
+        if direction == PeerConnectionType::IN && reservation.is_none() {
+            endpoint.shutdown();
+            return Err(PeerNetError::PeerConnectionError.error(
+                "connect_endpoint",
+                Some(format!("connection from {} refused", addr)),
+            ));
+        }
+        new_peer(
             self.context.clone(),
-            addr,
-            timeout,
-            self.message_handler.clone(),
+            endpoint,
             self.init_connection_handler.clone(),
-        )
+            self.message_handler.clone(),
+            self.active_connections.clone(),
+            self.relayed_peer_stop.1.clone(),
+            direction,
+            category_name,
+            category_info,
+            self.config.optional_features.clone(),
+            self.buffer_pool.clone(),
+            self.dispatcher.clone(),
+            None,
+            reservation,
+        );
+        Ok(())
+    }
+
+    /// Change the rate limit of the TCP connections. The connected peers apply it before their
+    /// next read or write, except the ones established before a `replace_transport_config`
+    /// that changed the rate limit. The configuration is left unchanged if the values are
+    /// invalid.
+    pub fn set_rate_limit(
+        &mut self,
+        rate_limit: u64,
+        rate_time_window: std::time::Duration,
+        rate_bucket_size: u64,
+    ) -> PeerNetResult<()> {
+        let previous = self.config.tcp.clone();
+        self.config.tcp.rate_limit = rate_limit;
+        self.config.tcp.rate_time_window = rate_time_window;
+        self.config.tcp.rate_bucket_size = rate_bucket_size;
+        if let Err(err) = self.config.validate() {
+            self.config.tcp = previous;
+            return Err(PeerNetError::ConfigError(err.clone()).new("set_rate_limit", err, None));
+        }
+        if let Some(InternalTransportType::Tcp(transport)) =
+            self.transports.get_mut(&TransportType::Tcp)
+        {
+            let mut config = transport.config.write();
+            let connection_config = &mut config.0.connection_config;
+            connection_config.rate_limit = rate_limit;
+            connection_config.rate_time_window = rate_time_window;
+            connection_config.rate_bucket_size = rate_bucket_size;
+            config.1.set(limiter_options(
+                rate_limit,
+                rate_time_window,
+                rate_bucket_size,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replace the settings of a transport for the connections established from now on,
+    /// without restarting its listeners: the running TCP listeners apply them from their next
+    /// connection. The connected peers keep the timeouts, message size and rate limit they were
+    /// established with. The running QUIC listeners keep their certificates and SNI routes until
+    /// they're restarted. The settings are left unchanged if they're invalid.
+    pub fn replace_transport_config(
+        &mut self,
+        transport_type: TransportType,
+        settings: TransportSettings,
+    ) -> PeerNetResult<()> {
+        let previous = (self.config.tcp.clone(), self.config.quic.clone());
+        match (transport_type, settings) {
+            (TransportType::Tcp, TransportSettings::Tcp(tcp)) => self.config.tcp = tcp,
+            (TransportType::Quic, TransportSettings::Quic(quic)) => self.config.quic = quic,
+            (transport_type, settings) => {
+                return Err(PeerNetError::WrongConfigType.error(
+                    "replace_transport_config",
+                    Some(format!("{:?} settings for {:?}", settings, transport_type)),
+                ))
+            }
+        }
+        if let Err(err) = self.config.validate() {
+            (self.config.tcp, self.config.quic) = previous;
+            return Err(PeerNetError::ConfigError(err.clone()).new(
+                "replace_transport_config",
+                err,
+                None,
+            ));
+        }
+        let config = Self::transport_config(&self.config, transport_type);
+        if let Some(transport) = self.transports.get_mut(&transport_type) {
+            transport.replace_config(config)?;
+        }
+        Ok(())
     }
 
     /// Get the nb_in_connections of manager
@@ -402,12 +1508,188 @@ impl<
         self.active_connections.read().nb_in_connections
     }
 
+    /// Addresses of our listeners with the IPs reported by at least `min_confirmations`
+    /// connected peers (see `ObservedAddresses`). Empty if the feature is disabled.
+    ///
+    /// The peers connected from the same network (see `voter_network`) count as a single one,
+    /// so that a host can't confirm an address alone by connecting with many ids.
+    pub fn external_addresses(&self) -> Vec<SocketAddr> {
+        let Some(observed_addresses) = self.config.optional_features.observed_addresses else {
+            return Vec::new();
+        };
+        let active_connections = self.active_connections.read();
+        let mut votes: HashMap<IpAddr, HashSet<IpAddr>> = HashMap::new();
+        for (peer_id, ip) in &active_connections.observed_addresses {
+            let Some(connection) = active_connections.connections.get(peer_id) else {
+                continue;
+            };
+            votes
+                .entry(*ip)
+                .or_default()
+                .insert(voter_network(connection.endpoint.get_target_addr().ip()));
+        }
+        let mut addresses: Vec<SocketAddr> = votes
+            .into_iter()
+            .filter(|(_, voters)| voters.len() >= observed_addresses.min_confirmations)
+            .flat_map(|(ip, _)| {
+                active_connections
+                    .listeners
+                    .keys()
+                    .map(move |listener| SocketAddr::new(ip, listener.port()))
+            })
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
     pub fn get_total_bytes_received(&self) -> u64 {
-        *self.total_bytes_received.read()
+        self.total_bandwidth.snapshot().bytes_received
     }
 
     pub fn get_total_bytes_sent(&self) -> u64 {
-        *self.total_bytes_sent.read()
+        self.total_bandwidth.snapshot().bytes_sent
+    }
+
+    /// Bytes sent and received by all the peers since the creation of the manager
+    pub fn get_bandwidth(&self) -> BandwidthSnapshot {
+        self.total_bandwidth.snapshot()
+    }
+
+    /// Bytes and messages sent and received by all the peers since the previous call, see
+    /// `Bandwidth::delta`
+    pub fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        self.total_bandwidth.delta()
+    }
+
+    /// Bytes per second sent and received lately by all the peers, see `Bandwidth::rates`
+    pub fn get_rates(&self) -> BandwidthRates {
+        self.total_bandwidth.rates()
+    }
+
+    /// Restart the bandwidth counters of the peer `id` from zero, see
+    /// `ActiveConnections::reset_bandwidth`
+    pub fn reset_bandwidth(&self, id: &Id) -> Option<BandwidthSnapshot> {
+        self.active_connections.write().reset_bandwidth(id)
+    }
+
+    /// Listeners, peers, counters and queues of the manager, see `PeerNetStateSnapshot`. Doesn't
+    /// block if the connections are locked, e.g. in a deadlock: the snapshot is then `locked`.
+    pub fn dump_state(&self) -> PeerNetStateSnapshot {
+        let bandwidth = self.total_bandwidth.snapshot();
+        let rates = self.total_bandwidth.rates();
+        let mut state = match self.active_connections.try_read_for(STATE_LOCK_TIMEOUT) {
+            Some(active_connections) => snapshot(
+                &active_connections,
+                &self.config.peers_categories,
+                self.config.default_category_info,
+                bandwidth,
+                rates,
+            ),
+            None => PeerNetStateSnapshot {
+                locked: true,
+                bandwidth,
+                rates,
+                ..Default::default()
+            },
+        };
+        state.config = self.config.describe();
+        state
+    }
+
+    /// The peers of the address book with their addresses and scores, to seed another node
+    pub fn export_peer_list(&self) -> PeerListSnapshot<Id> {
+        let active_connections = self.active_connections.read();
+        PeerListSnapshot::from_address_book(
+            &active_connections.address_book,
+            active_connections.clock.now(),
+        )
+    }
+
+    /// Add the peers of `snapshot` to the address book, they're dialed by the supervisor if
+    /// there are not enough out connections. Returns the number of addresses imported.
+    pub fn import_peer_list(&self, snapshot: &PeerListSnapshot<Id>) -> PeerNetResult<usize> {
+        let mut active_connections = self.active_connections.write();
+        let now = active_connections.clock.now();
+        snapshot.merge_into(&mut active_connections.address_book, now)
+    }
+
+    /// Memory held by the messages of all the peers, `None` if `PeerNetFeatures::memory_budget`
+    /// is not set. The memory of each peer is in `SendChannels::memory_usage`.
+    pub fn memory_stats(&self) -> Option<MemoryStats> {
+        self.active_connections
+            .read()
+            .memory
+            .as_ref()
+            .map(|memory| memory.stats())
+    }
+
+    /// Copy of the connections, taken under a short read of the lock, to format or process
+    /// them without holding it
+    pub fn connections_snapshot(&self) -> ConnectionsSnapshot<Id> {
+        connections_snapshot(&self.active_connections.read())
+    }
+
+    /// Connections and bytes of the peers of the category `name`, `None` for the peers of no
+    /// category. The bytes of the peers already disconnected are included.
+    pub fn category_stats(&self, name: Option<&str>) -> CategoryStats {
+        category_stats(&self.active_connections.read(), name)
+    }
+
+    /// The last `n` lifecycle events of the connections, the most recent first. Empty if
+    /// `PeerNetFeatures::connection_history` is not set.
+    pub fn recent_events(&self, n: usize) -> Vec<ConnectionEvent<Id>> {
+        self.active_connections.read().history.recent(n)
+    }
+
+    /// Accept the connections of `trusted_peers` beyond the limits, e.g. the other nodes of the
+    /// operator during a flood of incoming connections. Replaces the previous trusted peers,
+    /// the ones of `PeerNetConfiguration::trusted_peers` included.
+    pub fn set_trusted_peers(&mut self, trusted_peers: TrustedPeers<Id>) {
+        self.active_connections.write().trusted_peers = trusted_peers;
+    }
+
+    /// Refuse the peers denied by `peer_id_rules` or not allowed by them after their handshake,
+    /// e.g. compromised identities that change their address. The connected peers that they
+    /// refuse are disconnected. Replaces the previous rules, the ones of
+    /// `PeerNetConfiguration::peer_id_rules` included.
+    pub fn set_peer_id_rules(&mut self, peer_id_rules: PeerIdRules<Id>) {
+        let mut active_connections = self.active_connections.write();
+        let refused: Vec<Id> = active_connections
+            .connections
+            .keys()
+            .filter(|id| !peer_id_rules.is_accepted(id))
+            .cloned()
+            .collect();
+        for id in refused {
+            active_connections.remove_connection_with_reason(&id, DisconnectReason::Denied);
+        }
+        active_connections.peer_id_rules = peer_id_rules;
+    }
+
+    /// Replace the checks of the new connections, by default the limits of the configuration
+    /// (`DefaultConnectionGater`). Applies to the connections accepted or dialed after it.
+    pub fn set_connection_gater(&mut self, gater: SharedConnectionGater<Id>) {
+        self.active_connections.write().gater = gater;
+    }
+
+    /// Check the messages received from the peers before they are handled, see
+    /// `message_filter`. Applies to the connected peers too. No check if `None`
+    pub fn set_message_filter(&mut self, filter: Option<SharedMessageFilter<Id>>) {
+        *self.active_connections.read().message_filter.write() = filter;
+    }
+
+    /// Threads of the peers still running, including those doing their handshake. Drops to 0
+    /// once all the peers are disconnected.
+    pub fn active_thread_count(&self) -> usize {
+        self.active_connections.read().peer_threads.count()
+    }
+
+    /// Bytes of the received messages waiting for or being handled by the
+    /// `PeerNetFeatures::handler_workers`, 0 without them
+    pub fn in_flight_bytes(&self) -> usize {
+        self.dispatcher
+            .as_ref()
+            .map_or(0, |dispatcher| dispatcher.in_flight_bytes())
     }
 }
 
@@ -419,11 +1701,26 @@ impl<
     > Drop for PeerNetManager<Id, Ctx, I, M>
 {
     fn drop(&mut self) {
+        if let Some((stop_tx, handle)) = self.relay_acceptor.take() {
+            let _ = stop_tx.send(());
+            let _ = handle.join();
+        }
+        if let Some((stop_tx, handle)) = self.handshake_reaper.take() {
+            let _ = stop_tx.send(());
+            let _ = handle.join();
+        }
+        // stops the listeners, no peer is added while the threads are joined
+        self.transports.clear();
         {
             let mut active_connections = self.active_connections.write();
             for (_, mut peer) in active_connections.connections.drain() {
                 peer.shutdown();
             }
         }
+        let handles = self.active_connections.write().peer_threads.take();
+        let nb_running = join_threads(handles, PEER_THREADS_JOIN_TIMEOUT);
+        if nb_running > 0 {
+            tracing::warn!("{} peer threads still running after the drop", nb_running);
+        }
     }
 }
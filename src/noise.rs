@@ -0,0 +1,149 @@
+//! Noise XX handshake and encryption of the connections (feature `snow`)
+//!
+//! `NoiseInitConnectionHandler` authenticates both peers with their static key and derives
+//! their `PeerId` from it. Once the handshake is done, the keys it agreed on are installed
+//! on the endpoint that encrypts every following message (see `Endpoint::install_encryption`).
+
+use snow::{Builder, HandshakeState};
+
+use crate::config::REDACTED;
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::messages::MessagesHandler;
+use crate::peer::InitConnectionHandler;
+use crate::peer_id::PeerId;
+use crate::transports::{endpoint::Endpoint, TransportType};
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+// a noise message can't be bigger than this
+const NOISE_MAX_MESSAGE_SIZE: usize = 65535;
+// size of the random nonces exchanged to decide which peer initiates the handshake
+const ROLE_NONCE_SIZE: usize = 32;
+
+fn noise_builder<'a>() -> PeerNetResult<Builder<'a>> {
+    let params = NOISE_PARAMS
+        .parse()
+        .map_err(|err| PeerNetError::HandshakeError.new("noise params", err, None))?;
+    Ok(Builder::new(params))
+}
+
+/// Handshake handler running a Noise XX handshake.
+/// The id of the remote peer is computed from its static public key by `peer_id_from_key`.
+#[derive(Clone)]
+pub struct NoiseInitConnectionHandler<Id> {
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+    peer_id_from_key: fn(&[u8]) -> PeerNetResult<Id>,
+}
+
+/// The private key is redacted
+impl<Id> std::fmt::Debug for NoiseInitConnectionHandler<Id> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseInitConnectionHandler")
+            .field("private_key", &REDACTED)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+impl<Id: PeerId> NoiseInitConnectionHandler<Id> {
+    pub fn new(
+        private_key: Vec<u8>,
+        public_key: Vec<u8>,
+        peer_id_from_key: fn(&[u8]) -> PeerNetResult<Id>,
+    ) -> Self {
+        NoiseInitConnectionHandler {
+            private_key,
+            public_key,
+            peer_id_from_key,
+        }
+    }
+
+    /// Generate a static keypair (private key, public key) usable by the handshake
+    pub fn generate_keypair() -> PeerNetResult<(Vec<u8>, Vec<u8>)> {
+        let keypair = noise_builder()?
+            .generate_keypair()
+            .map_err(|err| PeerNetError::HandshakeError.new("noise keypair", err, None))?;
+        Ok((keypair.private, keypair.public))
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Run the handshake on `endpoint` and make it encrypt the following messages.
+    /// Can be called from another `InitConnectionHandler` that does more after the handshake.
+    pub fn handshake(&self, endpoint: &mut Endpoint) -> PeerNetResult<Id> {
+        // both peers can't be the initiator, the one with the biggest nonce is
+        let our_nonce: [u8; ROLE_NONCE_SIZE] = rand::random();
+        endpoint.send::<Id>(&our_nonce)?;
+        let their_nonce = endpoint.receive::<Id>()?;
+        let builder = noise_builder()?.local_private_key(&self.private_key);
+        let mut handshake = match our_nonce[..].cmp(&their_nonce[..]) {
+            std::cmp::Ordering::Greater => builder.build_initiator(),
+            std::cmp::Ordering::Less => builder.build_responder(),
+            std::cmp::Ordering::Equal => {
+                return Err(PeerNetError::HandshakeError.error("noise role", None))
+            }
+        }
+        .map_err(|err| PeerNetError::HandshakeError.new("noise build", err, None))?;
+
+        while !handshake.is_handshake_finished() {
+            if handshake.is_my_turn() {
+                write_handshake_message::<Id>(&mut handshake, endpoint)?;
+            } else {
+                read_handshake_message::<Id>(&mut handshake, endpoint)?;
+            }
+        }
+        let peer_id = match handshake.get_remote_static() {
+            Some(remote_key) => (self.peer_id_from_key)(remote_key)?,
+            None => return Err(PeerNetError::HandshakeError.error("noise remote static key", None)),
+        };
+        // the first key encrypts what the initiator sends, the second what it receives
+        let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+        if handshake.is_initiator() {
+            endpoint.install_encryption(&initiator_key, &responder_key)?;
+        } else {
+            endpoint.install_encryption(&responder_key, &initiator_key)?;
+        }
+        Ok(peer_id)
+    }
+}
+
+fn write_handshake_message<Id: PeerId>(
+    handshake: &mut HandshakeState,
+    endpoint: &mut Endpoint,
+) -> PeerNetResult<()> {
+    let mut message = vec![0u8; NOISE_MAX_MESSAGE_SIZE];
+    let len = handshake
+        .write_message(&[], &mut message)
+        .map_err(|err| PeerNetError::HandshakeError.new("noise write", err, None))?;
+    endpoint.send::<Id>(&message[..len])
+}
+
+fn read_handshake_message<Id: PeerId>(
+    handshake: &mut HandshakeState,
+    endpoint: &mut Endpoint,
+) -> PeerNetResult<()> {
+    let message = endpoint.receive::<Id>()?;
+    let mut payload = vec![0u8; NOISE_MAX_MESSAGE_SIZE];
+    handshake
+        .read_message(&message, &mut payload)
+        .map_err(|err| PeerNetError::HandshakeError.new("noise read", err, None))?;
+    Ok(())
+}
+
+impl<Id: PeerId, Ctx: Context<Id>, M: MessagesHandler<Id>> InitConnectionHandler<Id, Ctx, M>
+    for NoiseInitConnectionHandler<Id>
+{
+    fn perform_handshake(
+        &mut self,
+        _context: &Ctx,
+        endpoint: &mut Endpoint,
+        _listeners: &std::collections::HashMap<std::net::SocketAddr, TransportType>,
+        _messages_handler: M,
+    ) -> PeerNetResult<Id> {
+        self.handshake(endpoint)
+    }
+}
@@ -1,22 +1,39 @@
 //! Every information about a peer (not used for now)
 
-use std::collections::HashMap;
-use std::{fmt::Debug, net::SocketAddr};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+};
 
-use crate::config::PeerNetCategoryInfo;
+use crate::buffer_pool::SharedBufferPool;
+use crate::config::{
+    report, DiagnosticEvent, DisconnectFlush, MessageCoalescing, PeerMetadata, PeerNetCategoryInfo,
+    PeerNetFeatures, SharedDiagnosticsSink,
+};
 use crate::context::Context;
-use crate::error::{PeerNetError, PeerNetResult};
-use crate::messages::{MessagesHandler, MessagesSerializer};
+use crate::dispatcher::MessageDispatcher;
+use crate::error::{PeerNetError, PeerNetErrorData, PeerNetResult};
+use crate::history::{ConnectionEventKind, DisconnectReason};
+use crate::memory::{MemoryAccounting, MemoryCharge, PeerMemory, PeerMemoryUsage};
+use crate::message_filter::{check_message, MessageFilterSlot};
+use crate::messages::{MessageMeta, MessagesHandler, MessagesSerializer};
 use crate::peer_id::PeerId;
-use crossbeam::channel::bounded;
-use crossbeam::{
-    channel::{Receiver, Sender, TryRecvError},
-    select,
-};
+use crate::proof_of_work::{answer_challenge, challenge_peer};
+use crate::timings::{timed, PeerTimers, PeerTimings};
+use crossbeam::channel::{bounded, Receiver, Select, Sender, TryRecvError, TrySendError};
+use parking_lot::Mutex;
+use serde::Serialize;
 
 use crate::{
-    network_manager::SharedActiveConnections,
-    transports::{endpoint::Endpoint, TransportType},
+    network_manager::{to_canonical, InReservation, SharedActiveConnections},
+    transports::{
+        endpoint::Endpoint, on_event_loop, ReactorHandle, ReactorSlot, TransportType, WriteNotifier,
+    },
 };
 
 pub trait InitConnectionHandler<Id: PeerId, Ctx: Context<Id>, M: MessagesHandler<Id>>:
@@ -43,54 +60,316 @@ pub trait InitConnectionHandler<Id: PeerId, Ctx: Context<Id>, M: MessagesHandler
     }
 }
 
+/// A message waiting in a send queue for the write thread of the peer
+pub(crate) struct QueuedMessage {
+    pub(crate) data: Vec<u8>,
+    /// If set, the message is dropped instead of being sent once this instant is passed
+    pub(crate) deadline: Option<Instant>,
+    /// Bytes of the message charged to the peer while it's queued, released when dropped
+    pub(crate) _charge: Option<MemoryCharge>,
+}
+
+impl QueuedMessage {
+    pub(crate) fn is_expired(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Counters of the messages sent to a peer, see `SendChannels::send_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SendStats {
+    /// Messages refused by `try_send` because their channel was full
+    pub nb_dropped_full: u64,
+    /// Messages that couldn't be queued because the connection was closed
+    pub nb_send_errors: u64,
+    /// Most messages waiting at once in the high and low priority channels
+    pub max_queued_messages: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct SendCounters {
+    nb_dropped_full: AtomicU64,
+    nb_send_errors: AtomicU64,
+    max_queued_messages: AtomicUsize,
+    // number of messages dropped by the write thread because their deadline passed
+    pub(crate) nb_expired_messages: AtomicU64,
+}
+
+#[derive(Clone)]
 pub struct SendChannels {
-    low_priority: Sender<Vec<u8>>,
-    high_priority: Sender<Vec<u8>>,
+    low_priority: Sender<QueuedMessage>,
+    high_priority: Sender<QueuedMessage>,
+    counters: Arc<SendCounters>,
+    buffer_pool: SharedBufferPool,
+    // wakes the event loop of the peer if its connection is driven by one
+    write_notifier: Option<WriteNotifier>,
+    // address of the peer, for the diagnostics
+    addr: SocketAddr,
+    diagnostics: Option<SharedDiagnosticsSink>,
+    max_message_size: Option<usize>,
+    // if `PeerNetFeatures::memory_budget` is set
+    pub(crate) memory: Option<Arc<PeerMemory>>,
 }
 
 impl SendChannels {
+    /// Queue a message, blocks while the channel is full. Called from a handler run by an event
+    /// loop of `PeerNetFeatures::tcp_reactor`, it doesn't wait and fails as `try_send` does.
     pub fn send<T, MS: MessagesSerializer<T>>(
         &self,
         message_serializer: &MS,
         message: T,
         high_priority: bool,
     ) -> PeerNetResult<()> {
-        let mut data = Vec::new();
+        self.queue(message_serializer, message, high_priority, None, true)
+    }
+
+    pub fn try_send<T, MS: MessagesSerializer<T>>(
+        &self,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+    ) -> PeerNetResult<()> {
+        self.queue(message_serializer, message, high_priority, None, false)
+    }
+
+    /// Same as `send` but the message is dropped by the write thread if it couldn't be
+    /// transmitted before `deadline` (e.g. stale gossip after a congestion episode)
+    pub fn send_with_deadline<T, MS: MessagesSerializer<T>>(
+        &self,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+        deadline: Instant,
+    ) -> PeerNetResult<()> {
+        self.queue(
+            message_serializer,
+            message,
+            high_priority,
+            Some(deadline),
+            true,
+        )
+    }
+
+    /// Same as `try_send` but the message is dropped by the write thread if it couldn't be
+    /// transmitted before `deadline`
+    pub fn try_send_with_deadline<T, MS: MessagesSerializer<T>>(
+        &self,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+        deadline: Instant,
+    ) -> PeerNetResult<()> {
+        self.queue(
+            message_serializer,
+            message,
+            high_priority,
+            Some(deadline),
+            false,
+        )
+    }
+
+    /// Number of messages dropped because their deadline passed before transmission
+    pub fn nb_expired_messages(&self) -> u64 {
+        self.counters.nb_expired_messages.load(Ordering::Relaxed)
+    }
+
+    /// Number of high and low priority messages waiting to be written
+    pub fn queued_messages(&self) -> (usize, usize) {
+        (self.high_priority.len(), self.low_priority.len())
+    }
+
+    /// Drops, errors and high-water mark of the queue since the connection was established,
+    /// e.g. to find the peers that don't keep up with what we send them
+    pub fn send_stats(&self) -> SendStats {
+        SendStats {
+            nb_dropped_full: self.counters.nb_dropped_full.load(Ordering::Relaxed),
+            nb_send_errors: self.counters.nb_send_errors.load(Ordering::Relaxed),
+            max_queued_messages: self.counters.max_queued_messages.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Biggest serialized message the connection accepts, the bigger ones are refused with
+    /// `PeerNetError::MessageTooLarge` instead of being queued. No limit if `None`
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Bytes held by the messages of the peer, `None` if `PeerNetFeatures::memory_budget` is not
+    /// set
+    pub fn memory_usage(&self) -> Option<PeerMemoryUsage> {
+        self.memory.as_ref().map(|memory| memory.usage())
+    }
+
+    /// A message to queue, charged to the memory of the peer. Refused with
+    /// `PeerNetError::MemoryBudgetExceeded` by `MemoryAction::DropLowPriority`
+    pub(crate) fn message(
+        &self,
+        data: Vec<u8>,
+        high_priority: bool,
+        deadline: Option<Instant>,
+    ) -> PeerNetResult<QueuedMessage> {
+        let charge = match &self.memory {
+            Some(memory) => match memory.charge_send(data.len(), high_priority) {
+                Some(charge) => Some(charge),
+                None => {
+                    let len = data.len();
+                    self.buffer_pool.put(data);
+                    return Err(PeerNetError::MemoryBudgetExceeded
+                        .error("queue message", Some(format!("{} bytes", len))));
+                }
+            },
+            None => None,
+        };
+        Ok(QueuedMessage {
+            data,
+            deadline,
+            _charge: charge,
+        })
+    }
+
+    /// Whether the peer was marked by `MemoryAction::DisconnectHeaviest`
+    pub(crate) fn over_budget(&self) -> bool {
+        self.memory
+            .as_ref()
+            .map_or(false, |memory| memory.over_budget())
+    }
+
+    /// Charge a received message to the memory of the peer until it's handled
+    pub(crate) fn charge_received(&self, size: usize) -> Option<MemoryCharge> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.charge_received(size))
+    }
+
+    /// Serialize a message to queue, refused with `PeerNetError::MessageTooLarge` beyond
+    /// `max_message_size`, see `message`
+    pub(crate) fn serialize_message<T, MS: MessagesSerializer<T>>(
+        &self,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+        deadline: Option<Instant>,
+    ) -> PeerNetResult<QueuedMessage> {
+        let mut data = self.buffer_pool.get(message_serializer.size_hint(&message));
         message_serializer.serialize(&message, &mut data)?;
+        if let Some(max_message_size) = self.max_message_size {
+            if data.len() > max_message_size {
+                let len = data.len();
+                self.buffer_pool.put(data);
+                return Err(PeerNetError::MessageTooLarge.error(
+                    "queue message",
+                    Some(format!("{} bytes, max {}", len, max_message_size)),
+                ));
+            }
+        }
+        self.message(data, high_priority, deadline)
+    }
+
+    fn queue<T, MS: MessagesSerializer<T>>(
+        &self,
+        message_serializer: &MS,
+        message: T,
+        high_priority: bool,
+        deadline: Option<Instant>,
+        blocking: bool,
+    ) -> PeerNetResult<()> {
+        let message =
+            self.serialize_message(message_serializer, message, high_priority, deadline)?;
+        // a handler called by an event loop could otherwise wait forever for a peer of its loop
+        if blocking && !on_event_loop() {
+            return self.push(message, high_priority);
+        }
+        self.try_push(message, high_priority).map_err(|err| {
+            if let TrySendError::Full(_) = err {
+                self.counters
+                    .nb_dropped_full
+                    .fetch_add(1, Ordering::Relaxed);
+                report(&self.diagnostics, || DiagnosticEvent::SendQueueFull {
+                    addr: self.addr,
+                    high_priority,
+                });
+            }
+            if high_priority {
+                PeerNetError::SendError.new("try_send sendchannels highprio", err, None)
+            } else {
+                PeerNetError::SendError.new("try_send sendchannels lowprio", err, None)
+            }
+        })
+    }
+
+    /// Queue a serialized message, blocks while the channel is full
+    pub(crate) fn push(&self, message: QueuedMessage, high_priority: bool) -> PeerNetResult<()> {
         if high_priority {
-            self.high_priority.send(data).map_err(|err| {
+            self.high_priority.send(message).map_err(|err| {
+                self.counters.nb_send_errors.fetch_add(1, Ordering::Relaxed);
                 PeerNetError::SendError.new("send sendchannels highprio", err, None)
             })?;
         } else {
-            self.low_priority.send(data).map_err(|err| {
+            self.low_priority.send(message).map_err(|err| {
+                self.counters.nb_send_errors.fetch_add(1, Ordering::Relaxed);
                 PeerNetError::SendError.new("send sendchannels lowprio", err, None)
             })?;
         }
+        self.queued();
         Ok(())
     }
 
-    pub fn try_send<T, MS: MessagesSerializer<T>>(
+    /// Queue a serialized message, it's given back in the error if the channel is full
+    pub(crate) fn try_push(
         &self,
-        message_serializer: &MS,
-        message: T,
+        message: QueuedMessage,
         high_priority: bool,
-    ) -> PeerNetResult<()> {
-        let mut data = Vec::new();
-        message_serializer.serialize(&message, &mut data)?;
-        if high_priority {
-            self.high_priority.try_send(data).map_err(|err| {
-                PeerNetError::SendError.new("try_send sendchannels highprio", err, None)
-            })?;
+    ) -> Result<(), TrySendError<QueuedMessage>> {
+        let res = if high_priority {
+            self.high_priority.try_send(message)
         } else {
-            self.low_priority.try_send(data).map_err(|err| {
-                PeerNetError::SendError.new("try_send sendchannels lowprio", err, None)
-            })?;
+            self.low_priority.try_send(message)
+        };
+        if let Err(TrySendError::Disconnected(_)) = res {
+            self.counters.nb_send_errors.fetch_add(1, Ordering::Relaxed);
         }
+        res?;
+        self.queued();
         Ok(())
     }
+
+    /// Channel of the messages of `high_priority`, to wait for room in it
+    #[cfg(feature = "async")]
+    pub(crate) fn channel(&self, high_priority: bool) -> &Sender<QueuedMessage> {
+        if high_priority {
+            &self.high_priority
+        } else {
+            &self.low_priority
+        }
+    }
+
+    /// Called once a message is queued
+    fn queued(&self) {
+        let (nb_high_priority, nb_low_priority) = self.queued_messages();
+        self.counters
+            .max_queued_messages
+            .fetch_max(nb_high_priority + nb_low_priority, Ordering::Relaxed);
+        self.notify_write();
+    }
+
+    fn notify_write(&self) {
+        if let Some(write_notifier) = &self.write_notifier {
+            write_notifier.notify();
+        }
+    }
+}
+
+/// Lightweight handle on a connected peer, given to the messages handler so that it can reply
+#[derive(Clone)]
+pub struct PeerHandle<Id> {
+    pub peer_id: Id,
+    pub send_channels: SendChannels,
+    pub(crate) timers: Option<Arc<PeerTimers>>,
+    pub(crate) message_filter: MessageFilterSlot<Id>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum PeerConnectionType {
     IN,
     OUT,
@@ -106,12 +385,32 @@ pub struct PeerConnection {
     pub connection_type: PeerConnectionType,
     // Category name
     pub category_name: Option<String>,
+    // Sub-protocols supported by both sides, empty if they were not negotiated
+    pub protocols: Vec<String>,
+    // Metadata sent by the peer, empty if not exchanged, see `PeerNetFeatures::metadata`
+    pub metadata: BTreeMap<String, String>,
+    // End of the handshake
+    pub connected_at: Instant,
+    // Asks the write thread to send the queued messages and close the connection, see
+    // `PeerNetFeatures::disconnect_flush`
+    pub(crate) flush: Option<Sender<DisconnectReason>>,
+    pub(crate) timers: Option<Arc<PeerTimers>>,
 }
 
 impl PeerConnection {
     pub fn shutdown(&mut self) {
         self.endpoint.shutdown();
     }
+
+    /// Time spent on the peer, if `PeerNetFeatures::peer_timings` is enabled
+    pub fn timings(&self) -> Option<PeerTimings> {
+        self.timers.as_ref().map(|timers| timers.timings())
+    }
+
+    /// Check if the sub-protocol `name` was agreed on with this peer
+    pub fn supports_protocol(&self, name: &str) -> bool {
+        self.protocols.iter().any(|protocol| protocol == name)
+    }
 }
 
 //TODO: Proper debug
@@ -121,6 +420,8 @@ impl Debug for PeerConnection {
             .field("send_channels", &"SendChannels")
             .field("endpoint", &"Endpoint")
             .field("category_nae", &format!("{:?}", self.category_name))
+            .field("protocols", &self.protocols)
+            .field("metadata", &self.metadata)
             .finish()
     }
 }
@@ -141,34 +442,126 @@ pub(crate) fn new_peer<
     connection_type: PeerConnectionType,
     category_name: Option<String>,
     category_info: PeerNetCategoryInfo,
+    features: PeerNetFeatures,
+    buffer_pool: SharedBufferPool,
+    dispatcher: Option<MessageDispatcher<Id>>,
+    reactor: Option<ReactorHandle<Id>>,
+    reservation: Option<InReservation>,
 ) {
+    let threads_active_connections = active_connections.clone();
+    let threads = features.threads.clone();
+    // the accepted connections wait for a worker of the pool for their handshake
+    let handshake_pool = match connection_type {
+        PeerConnectionType::IN => active_connections.read().handshake_pool.clone(),
+        PeerConnectionType::OUT => None,
+    };
+    let pooled = handshake_pool.is_some();
     //TODO: All the unwrap should pass the error to a function that remove the peer from our records
-    std::thread::Builder::new()
-        .name("peer_thread".into())
-        .spawn(move || {
-        let listeners = {
-            let active_connections = active_connections.read();
-            active_connections.listeners.clone()
+    let peer = move |refusal: Option<PeerNetErrorData>| {
+        // the events of the peer and of its write thread carry the connection
+        let span = tracing::info_span!(
+            "peer",
+            addr = %endpoint.get_target_addr(),
+            transport = endpoint.transport_name(),
+            direction = ?connection_type,
+            peer_id = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        // the times of `DiagnosticEvent::Connected` are measured on the clock of the queues
+        let clock = features.clock();
+        let handshake_start = clock.now();
+        // when the dial or the accept started, see `DiagnosticEvent::Connected`
+        let (listeners, queued_at) = {
+            let mut write_active_connections = active_connections.write();
+            let queued_at = if connection_type == PeerConnectionType::IN {
+                write_active_connections
+                    .history
+                    .record(*endpoint.get_target_addr(), ConnectionEventKind::Accepted);
+                write_active_connections
+                    .in_connection_queue
+                    .get(endpoint.get_target_addr())
+            } else {
+                write_active_connections
+                    .out_connection_queue
+                    .get(endpoint.get_target_addr())
+            };
+            (
+                write_active_connections.listeners.clone(),
+                queued_at.copied().unwrap_or(handshake_start),
+            )
         };
         //HANDSHAKE
-        let peer_id = match handshake_handler.perform_handshake(
-            &context,
-            &mut endpoint,
-            &listeners,
-            message_handler.clone(),
-        ) {
-            Ok(peer_id) => peer_id,
-            Err(_) => {
+        let timers = features
+            .peer_timings
+            .then(|| Arc::new(PeerTimers::default()));
+        let proof_of_work = match (refusal, &features.proof_of_work, connection_type) {
+            (Some(err), _, _) => Err(err),
+            (None, Some(proof_of_work), PeerConnectionType::IN) => {
+                let trusted = category_name.as_ref().map_or(false, |category_name| {
+                    proof_of_work.trusted_categories.contains(category_name)
+                });
+                let difficulty = if trusted { 0 } else { proof_of_work.difficulty };
+                challenge_peer::<Id>(&mut endpoint, difficulty)
+            }
+            (None, Some(proof_of_work), PeerConnectionType::OUT) => {
+                answer_challenge::<Id>(&mut endpoint, proof_of_work)
+            }
+            (None, None, _) => Ok(()),
+        };
+        let handshake = proof_of_work
+            .and_then(|_| {
+                handshake_handler.perform_handshake(
+                    &context,
+                    &mut endpoint,
+                    &listeners,
+                    message_handler.clone(),
+                )
+            })
+            .and_then(|peer_id| {
+                let protocols = match &features.protocols {
+                    Some(protocols) => negotiate_protocols::<Id>(&mut endpoint, protocols)?,
+                    None => Vec::new(),
+                };
+                let observed_addr = match features.observed_addresses {
+                    Some(_) => Some(exchange_observed_addresses::<Id>(&mut endpoint)?),
+                    None => None,
+                };
+                let metadata = match &features.metadata {
+                    Some(metadata) => exchange_metadata::<Id>(&mut endpoint, metadata)?,
+                    None => BTreeMap::new(),
+                };
+                Ok((peer_id, protocols, observed_addr, metadata))
+            });
+        let handshake_duration = clock.now().saturating_duration_since(handshake_start);
+        if let Some(timers) = &timers {
+            timers.add_handshake(handshake_duration);
+        }
+        let (peer_id, protocols, observed_addr, metadata) = match handshake {
+            Ok(handshake) => handshake,
+            Err(err) => {
+                tracing::debug!("handshake failed: {:?}", err);
+                report(&features.diagnostics, || DiagnosticEvent::HandshakeFailed {
+                    addr: *endpoint.get_target_addr(),
+                    direction: connection_type,
+                    reason: err.to_string(),
+                });
                 {
                     let mut write_active_connections = active_connections.write();
+                    write_active_connections.history.record(
+                        *endpoint.get_target_addr(),
+                        ConnectionEventKind::HandshakeFailed {
+                            direction: connection_type,
+                            reason: err.to_string(),
+                        },
+                    );
                     if connection_type == PeerConnectionType::IN {
                         write_active_connections
                             .in_connection_queue
-                            .retain(|addr| addr != endpoint.get_target_addr());
+                            .remove(endpoint.get_target_addr());
                     } else {
                         write_active_connections
                             .out_connection_queue
-                            .retain(|addr| addr != endpoint.get_target_addr());
+                            .remove(endpoint.get_target_addr());
                     }
                     write_active_connections.compute_counters();
                 }
@@ -176,25 +569,62 @@ pub(crate) fn new_peer<
             }
         };
 
+        span.record("peer_id", tracing::field::debug(&peer_id));
+        endpoint.end_handshake();
         let channel_size = endpoint.get_data_channel_size();
+        // plain TCP connections are given to an event loop after the handshake if enabled
+        let reactor_slot = match (&reactor, &endpoint) {
+            (Some(reactor), Endpoint::Tcp(_)) => Some(reactor.reserve()),
+            _ => None,
+        };
 
-        let (low_write_tx, low_write_rx) = bounded::<Vec<u8>>(channel_size);
-        let (high_write_tx, high_write_rx) = bounded::<Vec<u8>>(channel_size);
+        let (low_write_tx, low_write_rx) = bounded::<QueuedMessage>(channel_size);
+        let (high_write_tx, high_write_rx) = bounded::<QueuedMessage>(channel_size);
+        let counters = Arc::new(SendCounters::default());
+        // only the write threads flush, not the event loops
+        let (flush_tx, flush) = match (features.disconnect_flush, &reactor_slot) {
+            (Some(disconnect_flush), None) => {
+                let (flush_tx, flush_rx) = bounded::<DisconnectReason>(1);
+                (Some(flush_tx), Some((disconnect_flush, flush_rx)))
+            }
+            _ => (None, None),
+        };
+        let memory = active_connections
+            .read()
+            .memory
+            .as_ref()
+            .map(MemoryAccounting::register);
+        let peer_handle = PeerHandle {
+            peer_id: peer_id.clone(),
+            send_channels: SendChannels {
+                low_priority: low_write_tx,
+                high_priority: high_write_tx,
+                counters: counters.clone(),
+                buffer_pool: buffer_pool.clone(),
+                write_notifier: reactor_slot.as_ref().map(ReactorSlot::notifier),
+                addr: *endpoint.get_target_addr(),
+                diagnostics: features.diagnostics.clone(),
+                max_message_size: endpoint.get_max_message_size(),
+                memory,
+            },
+            timers,
+            message_filter: active_connections.read().message_filter.clone(),
+        };
 
         let endpoint_connection = match endpoint.try_clone() {
             Ok(write_endpoint) => write_endpoint,
             Err(err) => {
-                println!("Error while cloning endpoint: {:?}", err);
+                tracing::error!("error while cloning endpoint: {:?}", err);
                 {
                     let mut write_active_connections = active_connections.write();
                     if connection_type == PeerConnectionType::IN {
                         write_active_connections
                             .in_connection_queue
-                            .retain(|addr| addr != endpoint.get_target_addr());
+                            .remove(endpoint.get_target_addr());
                     } else {
                         write_active_connections
                             .out_connection_queue
-                            .retain(|addr| addr != endpoint.get_target_addr());
+                            .remove(endpoint.get_target_addr());
                     }
                     write_active_connections.remove_connection(&peer_id);
                 }
@@ -202,146 +632,719 @@ pub(crate) fn new_peer<
             }
         };
 
-         {
+        {
             let id: Id = context.get_peer_id();
 
             let mut write_active_connections = active_connections.write();
             if connection_type == PeerConnectionType::IN {
                 write_active_connections
                     .in_connection_queue
-                    .retain(|addr| addr != endpoint.get_target_addr());
+                    .remove(endpoint.get_target_addr());
             } else {
                 write_active_connections
                     .out_connection_queue
-                    .retain(|addr| addr != endpoint.get_target_addr());
+                    .remove(endpoint.get_target_addr());
             }
-            // if peer_id == PeerId::from_public_key(self_keypair.get_public_key()) || !active_connections.write().confirm_connection(
-            if peer_id == id || !write_active_connections.confirm_connection(
+            if peer_id == id {
+                tracing::debug!("connection to ourselves refused");
+                let addr = *endpoint.get_target_addr();
+                // the port of an incoming connection is not the one of a listener
+                if connection_type == PeerConnectionType::OUT {
+                    write_active_connections.add_self_address(addr);
+                }
+                write_active_connections.history.record(
+                    addr,
+                    ConnectionEventKind::Disconnected {
+                        peer_id,
+                        reason: DisconnectReason::SelfConnection,
+                        dropped_messages: 0,
+                    },
+                );
+                drop(write_active_connections);
+                report(&features.diagnostics, || DiagnosticEvent::SelfConnection {
+                    addr,
+                    direction: connection_type,
+                });
+                return;
+            }
+            if !write_active_connections.confirm_reserved(
                 peer_id.clone(),
                 endpoint_connection,
-                SendChannels {
-                    low_priority: low_write_tx,
-                    high_priority: high_write_tx,
-                },
+                peer_handle.send_channels.clone(),
                 connection_type,
                 category_name,
-                category_info
+                category_info,
+                protocols,
+                reservation,
             ) {
+                tracing::debug!("connection refused");
                 return;
             }
-         }
-
-        // SPAWN WRITING THREAD
-        // https://github.com/crossbeam-rs/crossbeam/issues/288
-        let write_thread_handle = std::thread::spawn({
-            let write_peer_id = peer_id.clone();
-            let write_active_connections = active_connections.clone();
-            let mut write_endpoint = match endpoint.try_clone() {
-                Ok(write_endpoint) => write_endpoint,
-                Err(err) => {
-                    println!("Error while cloning endpoint: {:?}", err);
-                    {
-                        let mut write_active_connections = write_active_connections.write();
-                        write_active_connections.remove_connection(&write_peer_id);
-                    }
-                    return;
+            tracing::info!(handshake = ?handshake_duration, "connected");
+            if let Some(connection) = write_active_connections.connections.get_mut(&peer_id) {
+                connection.flush = flush_tx;
+                connection.timers = peer_handle.timers.clone();
+                connection.metadata = metadata;
+            }
+            if let Some(observed_addr) = observed_addr {
+                write_active_connections
+                    .observed_addresses
+                    .insert(peer_id.clone(), to_canonical(observed_addr.ip()));
+            }
+        }
+        report(&features.diagnostics, || DiagnosticEvent::Connected {
+            addr: *endpoint.get_target_addr(),
+            direction: connection_type,
+            connect: handshake_start.saturating_duration_since(queued_at),
+            handshake: handshake_duration,
+            total: clock.now().saturating_duration_since(queued_at),
+        });
+
+        // a marked peer may not be reading nor sending anymore, its loops only notice it once its
+        // endpoint is shut down
+        if let Some(memory) = &peer_handle.send_channels.memory {
+            match endpoint.try_clone() {
+                Ok(mut over_budget_endpoint) => {
+                    memory.on_over_budget(move || over_budget_endpoint.shutdown())
                 }
-            };
-            move || loop {
-                match high_write_rx.try_recv() {
-                    Ok(data) => {
-                        if write_endpoint.send::<Id>(&data).is_err() {
+                Err(err) => tracing::debug!("error while cloning endpoint: {:?}", err),
+            }
+        }
+
+        let mut endpoint = match (reactor_slot, endpoint) {
+            (Some(reactor_slot), Endpoint::Tcp(endpoint)) => {
+                if let Err(err) = reactor_slot.register(
+                    endpoint,
+                    peer_handle,
+                    high_write_rx,
+                    low_write_rx,
+                    counters,
+                    message_handler,
+                    dispatcher,
+                ) {
+                    tracing::error!(
+                        "error while giving the connection to its event loop: {:?}",
+                        err
+                    );
+                    let mut write_active_connections = active_connections.write();
+                    write_active_connections.remove_connection_with_reason(
+                        &peer_id,
+                        DisconnectReason::Error(err.to_string()),
+                    );
+                }
+                return;
+            }
+            (_, endpoint) => endpoint,
+        };
+
+        let peer_thread = features.threads.builder("peer_thread".to_string());
+        let run_span = span.clone();
+        let thread_span = span.clone();
+        let thread_active_connections = active_connections.clone();
+        let thread_peer_id = peer_id.clone();
+        let run = move || {
+            // closed once the reader loop is over: the handles kept by the handlers keep the send
+            // channels open, the write thread can't wait for all of them to be dropped
+            let (reader_done_tx, reader_done_rx) = bounded::<()>(0);
+            // SPAWN WRITING THREAD
+            // https://github.com/crossbeam-rs/crossbeam/issues/288
+            let write_thread_handle = features
+                .threads
+                .builder("peer_write_thread".to_string())
+                .spawn({
+                    let write_peer_id = peer_id.clone();
+                    let write_active_connections = active_connections.clone();
+                    let write_span = run_span.clone();
+                    let write_timers = peer_handle.timers.clone();
+                    let write_memory = peer_handle.send_channels.memory.clone();
+                    let write_buffer_pool = buffer_pool.clone();
+                    let mut write_endpoint = match endpoint.try_clone() {
+                        Ok(write_endpoint) => write_endpoint,
+                        Err(err) => {
+                            tracing::error!("error while cloning endpoint: {:?}", err);
                             {
                                 let mut write_active_connections = write_active_connections.write();
-                                write_active_connections.remove_connection(&write_peer_id);
+                                write_active_connections.remove_connection_with_reason(
+                                    &write_peer_id,
+                                    DisconnectReason::Error(err.to_string()),
+                                );
                             }
-                            break;
+                            return;
                         }
-                        continue;
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => {
-                        return;
-                    }
-                }
-                select! {
-                    recv(peer_stop) -> _ => {
-                        return;
-                    }
-                    recv(low_write_rx) -> msg => {
-                        match msg {
-                            Ok(data) => {
-                                if write_endpoint.send::<Id>(&data).is_err() {
-                                    {
-                                        let mut write_active_connections = write_active_connections.write();
-                                        write_active_connections.remove_connection(&write_peer_id);
+                    };
+                    move || {
+                        let flush_rx = flush.as_ref().map(|(_, flush_rx)| flush_rx);
+                        let closing = || flush_rx.map_or(false, |flush_rx| !flush_rx.is_empty());
+                        let over_budget = || {
+                            write_memory
+                                .as_ref()
+                                .map_or(false, |memory| memory.over_budget())
+                        };
+                        write_span.in_scope(|| loop {
+                            if let Some((disconnect_flush, flush_rx)) = &flush {
+                                if let Ok(reason) = flush_rx.try_recv() {
+                                    let dropped_messages = flush_queue::<Id>(
+                                        &mut write_endpoint,
+                                        &high_write_rx,
+                                        &low_write_rx,
+                                        *disconnect_flush,
+                                        &counters,
+                                    );
+                                    if let Err(err) = write_endpoint.flush() {
+                                        tracing::debug!("flush before disconnect: {}", err);
                                     }
-                                    break;
+                                    write_endpoint.shutdown();
+                                    let mut write_active_connections =
+                                        write_active_connections.write();
+                                    write_active_connections.history.record(
+                                        *write_endpoint.get_target_addr(),
+                                        ConnectionEventKind::Disconnected {
+                                            peer_id: write_peer_id.clone(),
+                                            reason,
+                                            dropped_messages,
+                                        },
+                                    );
+                                    return;
                                 }
                             }
-                            Err(_) => {
+                            let Some(msg) = next_message(
+                                &high_write_rx,
+                                &low_write_rx,
+                                Some(&peer_stop),
+                                flush_rx,
+                                Some(&reader_done_rx),
+                                None,
+                            ) else {
+                                if closing() {
+                                    continue;
+                                }
+                                // stopped with the listener that accepted the connection, the
+                                // reader loop ends once the endpoint is shut down
+                                if !matches!(peer_stop.try_recv(), Err(TryRecvError::Empty)) {
+                                    let mut write_active_connections =
+                                        write_active_connections.write();
+                                    write_active_connections.remove_connection_with_reason(
+                                        &write_peer_id,
+                                        DisconnectReason::Closed,
+                                    );
+                                }
+                                if closing() {
+                                    continue;
+                                }
                                 return;
+                            };
+                            if over_budget() {
+                                let mut write_active_connections = write_active_connections.write();
+                                write_active_connections.remove_connection_with_reason(
+                                    &write_peer_id,
+                                    DisconnectReason::MemoryBudget,
+                                );
+                                break;
                             }
-                        }
-                    }
-                    recv(high_write_rx) -> msg => {
-                        match msg {
-                            Ok(data) => {
-                                if write_endpoint.send::<Id>(&data).is_err() {
-                                    {
-                                        let mut write_active_connections =
-                                            write_active_connections.write();
-                                        write_active_connections.remove_connection(&write_peer_id);
+                            if msg.is_expired() {
+                                counters.nb_expired_messages.fetch_add(1, Ordering::Relaxed);
+                                write_buffer_pool.put(msg.data);
+                                continue;
+                            }
+                            // the buffers go back to the pool once written
+                            let res = match features.message_coalescing {
+                                Some(coalescing) => {
+                                    let batch = collect_batch(
+                                        msg,
+                                        &high_write_rx,
+                                        &low_write_rx,
+                                        coalescing,
+                                        &counters,
+                                    );
+                                    let res = timed(
+                                        write_timers.as_deref(),
+                                        PeerTimers::add_write,
+                                        || write_endpoint.send_batch::<Id>(&batch),
+                                    );
+                                    for data in batch {
+                                        write_buffer_pool.put(data);
                                     }
-                                    break;
+                                    res
                                 }
+                                None => {
+                                    let res = timed(
+                                        write_timers.as_deref(),
+                                        PeerTimers::add_write,
+                                        || write_endpoint.send::<Id>(&msg.data),
+                                    );
+                                    write_buffer_pool.put(msg.data);
+                                    res
+                                }
+                            };
+                            if let Err(err) = res {
+                                tracing::debug!("error on write: {:?}", err);
+                                let reason = if over_budget() {
+                                    DisconnectReason::MemoryBudget
+                                } else {
+                                    DisconnectReason::Error(err.to_string())
+                                };
+                                {
+                                    let mut write_active_connections =
+                                        write_active_connections.write();
+                                    write_active_connections
+                                        .remove_connection_with_reason(&write_peer_id, reason);
+                                }
+                                break;
                             }
-                            Err(_) => {
-                                return;
+                        })
+                    }
+                })
+                .expect("Failed to spawn peer_write_thread");
+            // READER LOOP
+            let stream_chunk_size = message_handler.stream_chunk_size();
+            loop {
+                let received = match stream_chunk_size {
+                    Some(chunk_size) => {
+                        endpoint.receive_streamed(chunk_size, &message_handler, &peer_handle)
+                    }
+                    None => endpoint.receive::<Id>().map(Some),
+                };
+                match received {
+                    // the message was streamed to the handler
+                    Ok(None) => {}
+                    Ok(Some(data)) => {
+                        if data.is_empty() {
+                            // We arrive here in two cases:
+                            // 1. When we shutdown the endpoint from the clone that is in the manager
+                            // 2. When the other side closes the connection
+                            // In the first case the peer will already be removed from `connections` and so the remove is useless
+                            // but in the second case we need to remove it. We have no possibilities to know which case we are in
+                            // so we just try to remove it and ignore the error if it's not there.
+                            let reason = if peer_handle.send_channels.over_budget() {
+                                DisconnectReason::MemoryBudget
+                            } else {
+                                DisconnectReason::ClosedByPeer
+                            };
+                            {
+                                let mut write_active_connections = active_connections.write();
+                                write_active_connections
+                                    .remove_connection_with_reason(&peer_id, reason);
                             }
+                            break;
                         }
-                    }
-                }
-            }
-        });
-        // READER LOOP
-        loop {
-
-            match endpoint.receive::<Id>() {
-                Ok(data) => {
-                    if data.is_empty() {
-                        // We arrive here in two cases:
-                        // 1. When we shutdown the endpoint from the clone that is in the manager
-                        // 2. When the other side closes the connection
-                        // In the first case the peer will already be removed from `connections` and so the remove is useless
-                        // but in the second case we need to remove it. We have no possibilities to know which case we are in
-                        // so we just try to remove it and ignore the error if it's not there.
-                        {
+                        if peer_handle.send_channels.over_budget() {
                             let mut write_active_connections = active_connections.write();
-                            write_active_connections.remove_connection(&peer_id);
+                            write_active_connections.remove_connection_with_reason(
+                                &peer_id,
+                                DisconnectReason::MemoryBudget,
+                            );
+                            break;
+                        }
+                        match check_message(&peer_handle.message_filter, &peer_id, &data) {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(err) => {
+                                tracing::debug!("message refused by the filter: {:?}", err);
+                                {
+                                    let mut write_active_connections = active_connections.write();
+                                    write_active_connections.remove_connection_with_reason(
+                                        &peer_id,
+                                        DisconnectReason::Filtered(
+                                            err.add_msg().unwrap_or_default().to_string(),
+                                        ),
+                                    );
+                                }
+                                continue;
+                            }
+                        }
+                        let meta = MessageMeta::now();
+                        let res = match &dispatcher {
+                            Some(dispatcher) => dispatcher.dispatch(data, &peer_handle, meta),
+                            None => {
+                                let _charge = peer_handle.send_channels.charge_received(data.len());
+                                timed(
+                                    peer_handle.timers.as_deref(),
+                                    PeerTimers::add_handler,
+                                    || message_handler.handle_with_meta(data, &peer_handle, meta),
+                                )
+                            }
+                        };
+                        if let Err(err) = res {
+                            tracing::warn!("error handling message: {:?}", err);
+                            {
+                                let mut write_active_connections = active_connections.write();
+                                write_active_connections.remove_connection_with_reason(
+                                    &peer_id,
+                                    DisconnectReason::HandlerError(err.to_string()),
+                                );
+                            }
                         }
-                        let _ = write_thread_handle.join();
-                        return;
                     }
-                    if let Err(err) = message_handler.handle(&data, &peer_id) {
-                        println!("Error handling message: {:?}", err);
+                    Err(e) => {
+                        if e.error_type == PeerNetError::TimeOut {
+                            continue;
+                        }
+                        let reason = if peer_handle.send_channels.over_budget() {
+                            DisconnectReason::MemoryBudget
+                        } else if e.error_type == PeerNetError::ConnectionClosed {
+                            DisconnectReason::ClosedByPeer
+                        } else {
+                            tracing::debug!("error on read: {:?}", e);
+                            DisconnectReason::Error(e.to_string())
+                        };
                         {
                             let mut write_active_connections = active_connections.write();
-                            write_active_connections.remove_connection(&peer_id);
+                            write_active_connections
+                                .remove_connection_with_reason(&peer_id, reason);
                         }
+                        break;
                     }
                 }
-                Err(e) => {
-                    if e.error_type == PeerNetError::TimeOut {
-                        continue;
-                    }
-                    {
-                        let mut write_active_connections = active_connections.write();
-                        write_active_connections.remove_connection(&peer_id);
+            }
+            if let Some(memory) = &peer_handle.send_channels.memory {
+                memory.clear_on_over_budget();
+            }
+            // stops the write thread, joined so that the peer is gone once this thread is finished
+            drop(reader_done_tx);
+            drop(peer_handle);
+            let _ = write_thread_handle.join();
+        };
+        if !pooled {
+            run();
+            return;
+        }
+        // the worker goes back to the pool, the connection gets a thread of its own
+        match peer_thread.spawn(move || thread_span.in_scope(run)) {
+            Ok(handle) => thread_active_connections
+                .write()
+                .peer_threads
+                .register(handle),
+            Err(err) => {
+                tracing::error!("error while spawning peer_thread: {:?}", err);
+                thread_active_connections
+                    .write()
+                    .remove_connection_with_reason(
+                        &thread_peer_id,
+                        DisconnectReason::Error(err.to_string()),
+                    );
+            }
+        }
+    };
+    match handshake_pool {
+        Some(handshake_pool) => handshake_pool.submit(Box::new(peer)),
+        None => {
+            // kept to refuse the handshake on this thread if no thread can be spawned for it,
+            // the listener goes on accepting
+            let job = Arc::new(Mutex::new(Some(peer)));
+            let thread_job = job.clone();
+            let spawned = threads.builder("peer_thread".to_string()).spawn(move || {
+                let peer = thread_job.lock().take();
+                if let Some(peer) = peer {
+                    peer(None);
+                }
+            });
+            match spawned {
+                Ok(handle) => threads_active_connections
+                    .write()
+                    .peer_threads
+                    .register(handle),
+                Err(err) => {
+                    tracing::error!("error while spawning peer_thread: {:?}", err);
+                    let peer = job.lock().take();
+                    if let Some(peer) = peer {
+                        peer(Some(PeerNetError::HandshakeError.new(
+                            "spawn peer_thread",
+                            err,
+                            None,
+                        )));
                     }
-                    return;
                 }
             }
         }
-    }).expect("Failed to spawn peer_thread");
+    }
+}
+
+/// Join handles of the threads of the peers, see `PeerNetManager::active_thread_count`. Each
+/// one joins the write thread of its peer before finishing.
+#[derive(Debug, Default)]
+pub struct PeerThreads {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl PeerThreads {
+    /// Keep `handle`, the threads already finished are joined
+    pub(crate) fn register(&mut self, handle: JoinHandle<()>) {
+        self.join_finished();
+        self.handles.push(handle);
+    }
+
+    /// Threads still running
+    pub fn count(&self) -> usize {
+        self.handles
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count()
+    }
+
+    fn join_finished(&mut self) {
+        let (finished, running) = std::mem::take(&mut self.handles)
+            .into_iter()
+            .partition(|handle| handle.is_finished());
+        self.handles = running;
+        for handle in finished {
+            let _ = handle.join();
+        }
+    }
+
+    /// Handles to join outside of the lock of the connections, see `join_threads`
+    pub(crate) fn take(&mut self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut self.handles)
+    }
+}
+
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Join the threads finished before `timeout`, the others are detached. Returns the number of
+/// threads still running.
+pub(crate) fn join_threads(handles: Vec<JoinHandle<()>>, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    let mut handles = handles;
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) =
+            handles.into_iter().partition(|handle| handle.is_finished());
+        for handle in finished {
+            let _ = handle.join();
+        }
+        if running.is_empty() || Instant::now() >= deadline {
+            return running.len();
+        }
+        handles = running;
+        std::thread::sleep(JOIN_POLL_INTERVAL);
+    }
+}
+
+/// Next message to write, the high priority ones are always taken first. Sleeps until one of
+/// the channels is ready, `None` once `stop` is signaled, `done` is closed, `deadline` is reached
+/// or the channels are closed.
+fn next_message(
+    high_write_rx: &Receiver<QueuedMessage>,
+    low_write_rx: &Receiver<QueuedMessage>,
+    stop: Option<&Receiver<()>>,
+    close: Option<&Receiver<DisconnectReason>>,
+    done: Option<&Receiver<()>>,
+    deadline: Option<Instant>,
+) -> Option<QueuedMessage> {
+    // built only once the channels are empty, the messages queued under load skip it
+    let mut select: Option<(Select, Option<usize>)> = None;
+    loop {
+        for write_rx in [high_write_rx, low_write_rx] {
+            match write_rx.try_recv() {
+                Ok(msg) => return Some(msg),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        let (select, stop_index) = select.get_or_insert_with(|| {
+            let mut select = Select::new();
+            select.recv(high_write_rx);
+            select.recv(low_write_rx);
+            let stop_index = stop.map(|stop| select.recv(stop));
+            // the other channels only end the wait
+            if let Some(close) = close {
+                select.recv(close);
+            }
+            if let Some(done) = done {
+                select.recv(done);
+            }
+            (select, stop_index)
+        });
+        // only tells which channel is ready, the message is taken by the loop in priority order
+        let ready = match deadline {
+            Some(deadline) => select.ready_deadline(deadline).ok()?,
+            None => select.ready(),
+        };
+        if let (Some(stop), Some(stop_index)) = (stop, *stop_index) {
+            // the signal can have been taken by another peer in the meantime
+            if ready == stop_index && !matches!(stop.try_recv(), Err(TryRecvError::Empty)) {
+                return None;
+            }
+        }
+        // the connection was removed, the reason is left for the caller if it must be flushed,
+        // or its reader loop is over
+        if ready > 1 && Some(ready) != *stop_index {
+            return None;
+        }
+    }
+}
+
+/// Send the messages already queued for a peer whose connection is closing, within the limits
+/// of `disconnect_flush`. Returns the number of messages left unsent.
+fn flush_queue<Id: PeerId>(
+    endpoint: &mut Endpoint,
+    high_write_rx: &Receiver<QueuedMessage>,
+    low_write_rx: &Receiver<QueuedMessage>,
+    disconnect_flush: DisconnectFlush,
+    counters: &SendCounters,
+) -> usize {
+    let deadline = Instant::now() + disconnect_flush.max_duration;
+    let mut flushed_bytes = 0;
+    while let Ok(msg) = high_write_rx
+        .try_recv()
+        .or_else(|_| low_write_rx.try_recv())
+    {
+        if msg.is_expired() {
+            counters.nb_expired_messages.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        flushed_bytes += msg.data.len();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero()
+            || flushed_bytes > disconnect_flush.max_bytes
+            || endpoint.send_timeout::<Id>(&msg.data, timeout).is_err()
+        {
+            return 1 + high_write_rx.len() + low_write_rx.len();
+        }
+    }
+    0
+}
+
+/// Gather the messages that are already queued or arrive before `coalescing.max_delay`
+/// to send them along with `first` in a single write. High priority messages are taken first.
+fn collect_batch(
+    first: QueuedMessage,
+    high_write_rx: &Receiver<QueuedMessage>,
+    low_write_rx: &Receiver<QueuedMessage>,
+    coalescing: MessageCoalescing,
+    counters: &SendCounters,
+) -> Vec<Vec<u8>> {
+    let deadline = Instant::now() + coalescing.max_delay;
+    let mut batch_size = first.data.len();
+    let mut batch = vec![first.data];
+    while batch_size < coalescing.max_batch_size {
+        let Some(msg) = next_message(
+            high_write_rx,
+            low_write_rx,
+            None,
+            None,
+            None,
+            Some(deadline),
+        ) else {
+            break;
+        };
+        if msg.is_expired() {
+            counters.nb_expired_messages.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        batch_size += msg.data.len();
+        batch.push(msg.data);
+    }
+    batch
+}
+
+// maximum number of sub-protocols a peer can announce
+const MAX_PROTOCOLS: usize = 256;
+
+/// Exchange the names of the sub-protocols supported with the peer and return the ones
+/// supported by both sides, in our order of preference.
+/// A name is sent as its length (u16 big endian) followed by its bytes.
+fn negotiate_protocols<Id: PeerId>(
+    endpoint: &mut Endpoint,
+    protocols: &[String],
+) -> PeerNetResult<Vec<String>> {
+    let mut data = Vec::new();
+    for protocol in protocols.iter().take(MAX_PROTOCOLS) {
+        let len: u16 = protocol.len().try_into().map_err(|_| {
+            PeerNetError::HandshakeError.error("protocol name too long", Some(protocol.clone()))
+        })?;
+        data.extend_from_slice(&len.to_be_bytes());
+        data.extend_from_slice(protocol.as_bytes());
+    }
+    endpoint.send::<Id>(&data)?;
+
+    let data = endpoint.receive::<Id>()?;
+    let mut remote_protocols = Vec::new();
+    let mut cursor = 0;
+    while cursor < data.len() {
+        if remote_protocols.len() == MAX_PROTOCOLS || cursor + 2 > data.len() {
+            return Err(PeerNetError::InvalidMessage.error("protocols list", None));
+        }
+        let len = u16::from_be_bytes([data[cursor], data[cursor + 1]]) as usize;
+        cursor += 2;
+        let name = data
+            .get(cursor..cursor + len)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or(PeerNetError::InvalidMessage.error("protocol name", None))?;
+        remote_protocols.push(name);
+        cursor += len;
+    }
+    Ok(protocols
+        .iter()
+        .filter(|protocol| remote_protocols.contains(&protocol.as_str()))
+        .cloned()
+        .collect())
+}
+
+/// Exchange our metadata with the peer and return its own.
+/// Each key and value is sent as its length (u16 big endian) followed by its bytes.
+fn exchange_metadata<Id: PeerId>(
+    endpoint: &mut Endpoint,
+    metadata: &PeerMetadata,
+) -> PeerNetResult<BTreeMap<String, String>> {
+    let mut data = Vec::new();
+    for item in metadata.values.iter().flat_map(|(key, value)| [key, value]) {
+        let len: u16 = item.len().try_into().map_err(|_| {
+            PeerNetError::HandshakeError.error("metadata item too long", Some(item.clone()))
+        })?;
+        data.extend_from_slice(&len.to_be_bytes());
+        data.extend_from_slice(item.as_bytes());
+    }
+    endpoint.send::<Id>(&data)?;
+
+    // the size is checked before the metadata is read
+    let data = endpoint.receive_limited::<Id>(metadata.max_size)?;
+    let mut items = Vec::new();
+    let mut cursor = 0;
+    while cursor < data.len() {
+        if cursor + 2 > data.len() {
+            return Err(PeerNetError::InvalidMessage.error("metadata", None));
+        }
+        let len = u16::from_be_bytes([data[cursor], data[cursor + 1]]) as usize;
+        cursor += 2;
+        let item = data
+            .get(cursor..cursor + len)
+            .and_then(|item| std::str::from_utf8(item).ok())
+            .ok_or(PeerNetError::InvalidMessage.error("metadata item", None))?;
+        items.push(item.to_string());
+        cursor += len;
+    }
+    if items.len() % 2 != 0 {
+        return Err(PeerNetError::InvalidMessage.error("metadata key without value", None));
+    }
+    let mut remote_metadata = BTreeMap::new();
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        if remote_metadata.insert(key, value).is_some() {
+            return Err(PeerNetError::InvalidMessage.error("metadata duplicate key", None));
+        }
+    }
+    Ok(remote_metadata)
+}
+
+/// Send to the peer the address we see for it and return the one it sees for us
+fn exchange_observed_addresses<Id: PeerId>(endpoint: &mut Endpoint) -> PeerNetResult<SocketAddr> {
+    let addr = *endpoint.get_target_addr();
+    let mut data = Vec::with_capacity(18);
+    match addr.ip() {
+        IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => data.extend_from_slice(&ip.octets()),
+    }
+    data.extend_from_slice(&addr.port().to_be_bytes());
+    endpoint.send::<Id>(&data)?;
+
+    let data = endpoint.receive::<Id>()?;
+    let ip = match data.len() {
+        6 => IpAddr::from(<[u8; 4]>::try_from(&data[..4]).unwrap()),
+        18 => IpAddr::from(<[u8; 16]>::try_from(&data[..16]).unwrap()),
+        len => {
+            return Err(PeerNetError::InvalidMessage
+                .error("observed address", Some(format!("len: {}", len))))
+        }
+    };
+    let port = u16::from_be_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    Ok(SocketAddr::new(ip, port))
 }
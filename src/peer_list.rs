@@ -0,0 +1,137 @@
+//! Export and import of the known peers, see `PeerNetManager::export_peer_list`
+//!
+//! A `PeerListSnapshot` holds the peers of the `AddressBook` with their addresses, the times
+//! they were last seen and connected, and the scores given by the application. It's serialized
+//! with serde in any format, e.g. to seed a new node with the knowledge of an existing one, or
+//! to share a curated list of peers between services.
+//!
+//! The times are in milliseconds since the unix epoch, so that a snapshot can be imported by
+//! another process. The snapshots of another `version` are refused.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::address_book::{AddressBook, AddressEntry};
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::peer_id::PeerId;
+use crate::transports::TransportType;
+
+/// Version of the format written by this version of the library
+pub const PEER_LIST_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerListSnapshot<Id> {
+    pub version: u32,
+    /// When the snapshot was taken, in ms since the unix epoch
+    pub exported_at: u64,
+    pub peers: Vec<PeerListEntry<Id>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerListEntry<Id> {
+    pub peer_id: Id,
+    pub addresses: Vec<PeerListAddress>,
+    /// See `AddressBook::set_score`
+    pub score: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerListAddress {
+    pub transport_type: TransportType,
+    pub addr: SocketAddr,
+    /// In ms since the unix epoch
+    pub last_seen: u64,
+    /// In ms since the unix epoch, `None` if we never connected to it
+    pub last_connected: Option<u64>,
+}
+
+impl<Id: PeerId> PeerListSnapshot<Id> {
+    /// The peers of `address_book`, `now` is the time of its clock
+    pub fn from_address_book(address_book: &AddressBook<Id>, now: Instant) -> Self {
+        let system_now = SystemTime::now();
+        let to_millis = |instant: Instant| {
+            unix_millis(
+                system_now
+                    .checked_sub(now.saturating_duration_since(instant))
+                    .unwrap_or(UNIX_EPOCH),
+            )
+        };
+        let mut peers: Vec<PeerListEntry<Id>> = address_book
+            .peers()
+            .map(|peer_id| PeerListEntry {
+                peer_id: peer_id.clone(),
+                addresses: address_book
+                    .entries(peer_id)
+                    .iter()
+                    .map(|entry| PeerListAddress {
+                        transport_type: entry.transport_type,
+                        addr: entry.addr,
+                        last_seen: to_millis(entry.last_seen),
+                        last_connected: entry.last_connected.map(to_millis),
+                    })
+                    .collect(),
+                score: address_book.score(peer_id),
+            })
+            .collect();
+        // the same book gives the same snapshot
+        peers.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        PeerListSnapshot {
+            version: PEER_LIST_VERSION,
+            exported_at: unix_millis(system_now),
+            peers,
+        }
+    }
+
+    /// Add the peers to `address_book`, `now` is the time of its clock. The addresses seen
+    /// before the start of the clock can't be represented, they are skipped. Returns the number
+    /// of addresses imported.
+    pub fn merge_into(
+        &self,
+        address_book: &mut AddressBook<Id>,
+        now: Instant,
+    ) -> PeerNetResult<usize> {
+        if self.version != PEER_LIST_VERSION {
+            return Err(PeerNetError::PeerListVersion.error(
+                "import peer list",
+                Some(format!(
+                    "version {}, supported {}",
+                    self.version, PEER_LIST_VERSION
+                )),
+            ));
+        }
+        let system_now = unix_millis(SystemTime::now());
+        // times in the future are taken as now
+        let to_instant =
+            |millis: u64| now.checked_sub(Duration::from_millis(system_now.saturating_sub(millis)));
+        let mut nb_imported = 0;
+        for peer in &self.peers {
+            for address in &peer.addresses {
+                let Some(last_seen) = to_instant(address.last_seen) else {
+                    continue;
+                };
+                address_book.merge_entry(
+                    peer.peer_id.clone(),
+                    AddressEntry {
+                        transport_type: address.transport_type,
+                        addr: address.addr,
+                        last_seen,
+                        last_connected: address.last_connected.and_then(to_instant),
+                    },
+                );
+                nb_imported += 1;
+            }
+            if let Some(score) = peer.score {
+                address_book.set_score(peer.peer_id.clone(), score);
+            }
+        }
+        Ok(nb_imported)
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
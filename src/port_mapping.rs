@@ -0,0 +1,314 @@
+//! Mapping of the port of the listeners on the gateway of the local network
+//!
+//! When `PeerNetFeatures::port_mapping` is set, each listener started asks the gateway to
+//! forward its port, with UPnP IGD (feature `igd-next`) and then NAT-PMP if UPnP failed. The
+//! external address obtained is reported with a `PortMappingEvent` and the mapping is renewed
+//! at half of its lease until the listener is stopped.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crossbeam::channel::{unbounded, RecvTimeoutError, Sender};
+
+use crate::config::PortMapping;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::transports::TransportType;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+
+const NAT_PMP_VERSION: u8 = 0;
+const NAT_PMP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_MAP_UDP: u8 = 1;
+const NAT_PMP_MAP_TCP: u8 = 2;
+const NAT_PMP_RESPONSE: u8 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMappingProtocol {
+    Upnp,
+    NatPmp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMappingEvent {
+    /// The port of the listener `local_addr` is reachable at `external_addr`
+    Mapped {
+        local_addr: SocketAddr,
+        external_addr: SocketAddr,
+        protocol: PortMappingProtocol,
+    },
+    /// The gateway couldn't map the port of the listener `local_addr`
+    Failed {
+        local_addr: SocketAddr,
+        error: String,
+    },
+}
+
+/// Thread mapping the port of a listener and renewing the mapping
+pub(crate) struct PortMapper {
+    stop_tx: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl PortMapper {
+    pub(crate) fn start(
+        local_addr: SocketAddr,
+        transport_type: TransportType,
+        config: PortMapping,
+    ) -> PeerNetResult<PortMapper> {
+        let (stop_tx, stop_rx) = unbounded();
+        let handle = std::thread::Builder::new()
+            .name(format!("port_mapper_{}", local_addr))
+            .spawn(move || {
+                let mut mapping = None;
+                loop {
+                    mapping = match map_port(local_addr, transport_type, &config) {
+                        Ok((external_addr, protocol)) => {
+                            // only the changes are reported
+                            if mapping != Some((external_addr, protocol)) {
+                                let _ = config.events.send(PortMappingEvent::Mapped {
+                                    local_addr,
+                                    external_addr,
+                                    protocol,
+                                });
+                            }
+                            Some((external_addr, protocol))
+                        }
+                        Err(err) => {
+                            let _ = config.events.send(PortMappingEvent::Failed {
+                                local_addr,
+                                error: err.to_string(),
+                            });
+                            None
+                        }
+                    };
+                    match stop_rx.recv_timeout(config.lease_duration / 2) {
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        // stopped, or the manager has been dropped
+                        _ => break,
+                    }
+                }
+                if let Some((external_addr, protocol)) = mapping {
+                    if let Err(err) =
+                        unmap_port(local_addr, external_addr, transport_type, protocol, &config)
+                    {
+                        tracing::error!("port mapping removal of {}: {:?}", local_addr, err);
+                    }
+                }
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn port_mapper", err, None))?;
+        Ok(PortMapper { stop_tx, handle })
+    }
+
+    /// Stop renewing the mapping and remove it from the gateway
+    pub(crate) fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn map_port(
+    local_addr: SocketAddr,
+    transport_type: TransportType,
+    config: &PortMapping,
+) -> PeerNetResult<(SocketAddr, PortMappingProtocol)> {
+    #[cfg(feature = "testing")]
+    if transport_type == TransportType::Mock {
+        return Err(PeerNetError::SocketError.error(
+            "map port",
+            Some("the mock listeners can't be mapped".to_string()),
+        ));
+    }
+    #[cfg(feature = "igd-next")]
+    match upnp::map_port(local_addr, transport_type, config) {
+        Ok(external_addr) => return Ok((external_addr, PortMappingProtocol::Upnp)),
+        Err(err) if config.nat_pmp_gateway.is_none() => return Err(err),
+        Err(_) => {}
+    }
+    match config.nat_pmp_gateway {
+        Some(gateway) => nat_pmp_map_port(gateway, local_addr.port(), transport_type, config)
+            .map(|external_addr| (external_addr, PortMappingProtocol::NatPmp)),
+        None => Err(PeerNetError::SocketError.error(
+            "map port",
+            Some("no port mapping protocol available".to_string()),
+        )),
+    }
+}
+
+fn unmap_port(
+    local_addr: SocketAddr,
+    external_addr: SocketAddr,
+    transport_type: TransportType,
+    protocol: PortMappingProtocol,
+    config: &PortMapping,
+) -> PeerNetResult<()> {
+    match protocol {
+        #[cfg(feature = "igd-next")]
+        PortMappingProtocol::Upnp => upnp::unmap_port(external_addr, transport_type, config),
+        #[cfg(not(feature = "igd-next"))]
+        PortMappingProtocol::Upnp => {
+            let _ = external_addr;
+            Ok(())
+        }
+        PortMappingProtocol::NatPmp => match config.nat_pmp_gateway {
+            Some(gateway) => nat_pmp_request(
+                gateway,
+                &nat_pmp_map_request(transport_type, local_addr.port(), 0, Duration::ZERO),
+                config.timeout,
+            )
+            .map(|_| ()),
+            None => Ok(()),
+        },
+    }
+}
+
+fn nat_pmp_map_request(
+    transport_type: TransportType,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Vec<u8> {
+    let opcode = match transport_type {
+        TransportType::Tcp => NAT_PMP_MAP_TCP,
+        TransportType::Quic => NAT_PMP_MAP_UDP,
+        #[cfg(feature = "testing")]
+        TransportType::Mock => unreachable!("checked by map_port"),
+    };
+    let mut request = vec![NAT_PMP_VERSION, opcode, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+/// Send a request to the NAT-PMP server and check the header of its answer
+fn nat_pmp_request(
+    gateway: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+) -> PeerNetResult<Vec<u8>> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+        .map_err(|err| PeerNetError::SocketError.new("nat-pmp bind", err, None))?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .map_err(|err| PeerNetError::CouldNotSetTimeout.new("nat-pmp", err, None))?;
+    socket
+        .send_to(request, gateway)
+        .map_err(|err| PeerNetError::SendError.new("nat-pmp send", err, None))?;
+    let mut response = [0u8; 16];
+    let (len, _) = socket
+        .recv_from(&mut response)
+        .map_err(|err| PeerNetError::ReceiveError.new("nat-pmp receive", err, None))?;
+    if len < 4 || response[0] != NAT_PMP_VERSION || response[1] != NAT_PMP_RESPONSE + request[1] {
+        return Err(PeerNetError::InvalidMessage
+            .error("nat-pmp response", Some(format!("{:?}", &response[..len]))));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(PeerNetError::SocketError
+            .error("nat-pmp response", Some(format!("result code: {}", result))));
+    }
+    Ok(response[..len].to_vec())
+}
+
+fn nat_pmp_map_port(
+    gateway: SocketAddr,
+    port: u16,
+    transport_type: TransportType,
+    config: &PortMapping,
+) -> PeerNetResult<SocketAddr> {
+    let response = nat_pmp_request(
+        gateway,
+        &[NAT_PMP_VERSION, NAT_PMP_EXTERNAL_ADDRESS],
+        config.timeout,
+    )?;
+    if response.len() != 12 {
+        return Err(PeerNetError::InvalidMessage.error(
+            "nat-pmp external address",
+            Some(format!("len: {}", response.len())),
+        ));
+    }
+    let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+    let response = nat_pmp_request(
+        gateway,
+        &nat_pmp_map_request(transport_type, port, port, config.lease_duration),
+        config.timeout,
+    )?;
+    if response.len() != 16 {
+        return Err(PeerNetError::InvalidMessage
+            .error("nat-pmp mapping", Some(format!("len: {}", response.len()))));
+    }
+    // the gateway can choose another external port than the one asked
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    Ok(SocketAddr::new(IpAddr::V4(external_ip), external_port))
+}
+
+#[cfg(feature = "igd-next")]
+mod upnp {
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+    use igd_next::{search_gateway, Gateway, PortMappingProtocol, SearchOptions};
+
+    use crate::config::PortMapping;
+    use crate::error::{PeerNetError, PeerNetResult};
+    use crate::transports::TransportType;
+
+    fn gateway(config: &PortMapping) -> PeerNetResult<Gateway> {
+        search_gateway(SearchOptions {
+            timeout: Some(config.timeout),
+            ..Default::default()
+        })
+        .map_err(|err| PeerNetError::SocketError.new("upnp search gateway", err, None))
+    }
+
+    fn protocol(transport_type: TransportType) -> PortMappingProtocol {
+        match transport_type {
+            TransportType::Tcp => PortMappingProtocol::TCP,
+            TransportType::Quic => PortMappingProtocol::UDP,
+            #[cfg(feature = "testing")]
+            TransportType::Mock => unreachable!("checked by map_port"),
+        }
+    }
+
+    pub(super) fn map_port(
+        local_addr: SocketAddr,
+        transport_type: TransportType,
+        config: &PortMapping,
+    ) -> PeerNetResult<SocketAddr> {
+        let gateway = gateway(config)?;
+        // the gateway needs our address in the local network, not the unspecified one
+        let local_addr = if local_addr.ip().is_unspecified() {
+            let socket = UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0))
+                .and_then(|socket| socket.connect(gateway.addr).map(|_| socket))
+                .and_then(|socket| socket.local_addr())
+                .map_err(|err| PeerNetError::SocketError.new("upnp local address", err, None))?;
+            SocketAddr::new(socket.ip(), local_addr.port())
+        } else {
+            local_addr
+        };
+        gateway
+            .add_port(
+                protocol(transport_type),
+                local_addr.port(),
+                local_addr,
+                config.lease_duration.as_secs() as u32,
+                "peernet",
+            )
+            .map_err(|err| PeerNetError::SocketError.new("upnp add port", err, None))?;
+        let external_ip: IpAddr = gateway
+            .get_external_ip()
+            .map_err(|err| PeerNetError::SocketError.new("upnp external ip", err, None))?;
+        Ok(SocketAddr::new(external_ip, local_addr.port()))
+    }
+
+    pub(super) fn unmap_port(
+        external_addr: SocketAddr,
+        transport_type: TransportType,
+        config: &PortMapping,
+    ) -> PeerNetResult<()> {
+        gateway(config)?
+            .remove_port(protocol(transport_type), external_addr.port())
+            .map_err(|err| PeerNetError::SocketError.new("upnp remove port", err, None))
+    }
+}
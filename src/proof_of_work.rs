@@ -0,0 +1,86 @@
+//! Client puzzle solved by the peers connecting to us before their handshake
+//!
+//! The listener sends a random challenge and a difficulty, the connecting peer must find a
+//! nonce such that `sha256(challenge || nonce)` starts with `difficulty` zero bits. Checking a
+//! solution costs one hash while finding it costs `2^difficulty` hashes on average, which
+//! makes flooding a node with new connections expensive.
+
+use sha2::{Digest, Sha256};
+
+use crate::config::ProofOfWork;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::Endpoint;
+
+pub const CHALLENGE_SIZE: usize = 32;
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Check that `nonce` solves `challenge` for `difficulty`
+pub fn check_solution(challenge: &[u8], difficulty: u8, nonce: u64) -> bool {
+    let hash = Sha256::new()
+        .chain_update(challenge)
+        .chain_update(nonce.to_be_bytes())
+        .finalize();
+    leading_zero_bits(&hash) >= difficulty as u32
+}
+
+/// Find the first nonce solving `challenge` for `difficulty`
+pub fn solve_challenge(challenge: &[u8], difficulty: u8) -> u64 {
+    (0..u64::MAX)
+        .find(|nonce| check_solution(challenge, difficulty, *nonce))
+        .unwrap_or(u64::MAX)
+}
+
+/// Listener side: send a challenge of `difficulty` and verify the answer of the peer
+pub(crate) fn challenge_peer<Id: PeerId>(
+    endpoint: &mut Endpoint,
+    difficulty: u8,
+) -> PeerNetResult<()> {
+    let challenge: [u8; CHALLENGE_SIZE] = rand::random();
+    let mut data = Vec::with_capacity(CHALLENGE_SIZE + 1);
+    data.push(difficulty);
+    data.extend_from_slice(&challenge);
+    endpoint.send::<Id>(&data)?;
+
+    let answer = endpoint.receive::<Id>()?;
+    let nonce = u64::from_be_bytes(answer[..].try_into().map_err(|_| {
+        PeerNetError::InvalidMessage.error("proof of work answer", Some(format!("{:?}", answer)))
+    })?);
+    if !check_solution(&challenge, difficulty, nonce) {
+        return Err(PeerNetError::HandshakeError.error("wrong proof of work", None));
+    }
+    Ok(())
+}
+
+/// Connecting side: solve the challenge sent by the listener
+pub(crate) fn answer_challenge<Id: PeerId>(
+    endpoint: &mut Endpoint,
+    config: &ProofOfWork,
+) -> PeerNetResult<()> {
+    let data = endpoint.receive::<Id>()?;
+    if data.len() != CHALLENGE_SIZE + 1 {
+        return Err(PeerNetError::InvalidMessage.error(
+            "proof of work challenge",
+            Some(format!("len: {}", data.len())),
+        ));
+    }
+    let difficulty = data[0];
+    if difficulty > config.max_difficulty {
+        return Err(PeerNetError::HandshakeError.error(
+            "proof of work too difficult",
+            Some(format!("difficulty: {}", difficulty)),
+        ));
+    }
+    let nonce = solve_challenge(&data[1..], difficulty);
+    endpoint.send::<Id>(&nonce.to_be_bytes())
+}
@@ -0,0 +1,126 @@
+//! Structured refusal of a connection, sent by the listener instead of its handshake
+//!
+//! When the node is full, the listener calls `InitConnectionHandler::fallback_function` in
+//! place of the handshake. The fallback can send a `Rejection` telling the dialer why it is
+//! refused, when to retry and which other peers to try. The dialer reads the first message of
+//! the connection in its handshake and checks it with `Rejection::parse` before its own parsing.
+//!
+//! The frame starts with `REJECTION_MAGIC` so that it can't be mistaken for the first message
+//! of a handshake.
+
+use std::time::Duration;
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::internal_handlers::peer_management::{
+    read_listeners, write_listeners, ListenersMap, Reader, MAX_LISTENERS_PER_PEER,
+};
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::Endpoint;
+
+pub const REJECTION_MAGIC: [u8; 4] = *b"PNRJ";
+
+// no retry-after in the frame
+const NO_RETRY_AFTER: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// No room for more connections
+    Full,
+    /// Too many connections from the IP of the dialer
+    TooManyFromIp,
+    /// Too many handshakes in progress
+    Busy,
+    /// A code unknown to this version, sent by a newer one
+    Other(u8),
+}
+
+impl RejectionReason {
+    fn code(&self) -> u8 {
+        match self {
+            RejectionReason::Full => 0,
+            RejectionReason::TooManyFromIp => 1,
+            RejectionReason::Busy => 2,
+            RejectionReason::Other(code) => *code,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0 => RejectionReason::Full,
+            1 => RejectionReason::TooManyFromIp,
+            2 => RejectionReason::Busy,
+            code => RejectionReason::Other(code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: RejectionReason,
+    /// Time before the dialer should try again, in whole seconds on the wire
+    pub retry_after: Option<Duration>,
+    /// Listeners of other peers the dialer can try, at most `MAX_LISTENERS_PER_PEER`
+    pub alternative_peers: ListenersMap,
+}
+
+impl Rejection {
+    pub fn new(reason: RejectionReason) -> Self {
+        Rejection {
+            reason,
+            retry_after: None,
+            alternative_peers: ListenersMap::new(),
+        }
+    }
+
+    pub fn serialize(&self, buffer: &mut Vec<u8>) -> PeerNetResult<()> {
+        if self.alternative_peers.len() > MAX_LISTENERS_PER_PEER {
+            return Err(PeerNetError::InvalidMessage.error(
+                "serialize rejection",
+                Some(format!(
+                    "too many alternative peers: {}",
+                    self.alternative_peers.len()
+                )),
+            ));
+        }
+        let retry_after = self.retry_after.map_or(NO_RETRY_AFTER, |retry_after| {
+            retry_after.as_secs().min(NO_RETRY_AFTER as u64 - 1) as u32
+        });
+        buffer.extend_from_slice(&REJECTION_MAGIC);
+        buffer.push(self.reason.code());
+        buffer.extend_from_slice(&retry_after.to_be_bytes());
+        write_listeners(&self.alternative_peers, buffer);
+        Ok(())
+    }
+
+    /// `None` if `data` is not a rejection, e.g. the first message of a handshake
+    pub fn parse(data: &[u8]) -> Option<PeerNetResult<Rejection>> {
+        let payload = data.strip_prefix(&REJECTION_MAGIC[..])?;
+        Some(Self::deserialize(payload))
+    }
+
+    fn deserialize(data: &[u8]) -> PeerNetResult<Rejection> {
+        let mut reader = Reader { data, position: 0 };
+        let [reason] = reader.read_array()?;
+        let retry_after = u32::from_be_bytes(reader.read_array()?);
+        let alternative_peers = read_listeners(&mut reader)?;
+        if reader.position != data.len() {
+            return Err(PeerNetError::InvalidMessage.error(
+                "deserialize rejection",
+                Some(format!("{} trailing bytes", data.len() - reader.position)),
+            ));
+        }
+        Ok(Rejection {
+            reason: RejectionReason::from_code(reason),
+            retry_after: (retry_after != NO_RETRY_AFTER)
+                .then(|| Duration::from_secs(retry_after as u64)),
+            alternative_peers,
+        })
+    }
+
+    /// Send the rejection on `endpoint`, from the fallback function
+    pub fn send<Id: PeerId>(&self, endpoint: &mut Endpoint) -> PeerNetResult<()> {
+        let mut data = Vec::new();
+        self.serialize(&mut data)?;
+        endpoint.send::<Id>(&data)
+    }
+}
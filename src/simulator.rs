@@ -0,0 +1,180 @@
+//! Simulated network of in-process managers, to test the protocols built on PeerNet (gossip,
+//! peer exchange...) without sockets (feature `testing`)
+//!
+//! The nodes of a `Simulator` are connected by memory links given to
+//! `PeerNetManager::connect_endpoint`, each with its latency, jitter and loss (see
+//! `LinkConditions`) that can be changed while the nodes are connected, e.g. to partition the
+//! network. The jitter and the loss are drawn from random generators seeded by the seed of the
+//! simulator, so that a run with the same seed and the same messages delays and drops the same
+//! messages. The delays are waited in real time, they should be kept short.
+//!
+//! A link delivers the messages in order, like a TCP connection: a message isn't delivered
+//! before the ones sent before it even if it drew less jitter. The loss applies to the messages
+//! of the handshake too, a handshake missing a message never ends: the loss should only be set
+//! once the nodes are connected.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::PeerNetConfiguration;
+use crate::context::Context;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::messages::MessagesHandler;
+use crate::network_manager::PeerNetManager;
+use crate::peer::{InitConnectionHandler, PeerConnectionType};
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::Endpoint;
+use crate::transports::{MemoryEndpoint, SharedLinkConditions};
+
+pub use crate::transports::LinkConditions;
+
+/// Port of the simulated listeners, the dialers use ports above it
+const LISTENER_PORT: u16 = 1000;
+
+pub struct Simulator<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+> {
+    nodes: Vec<PeerNetManager<Id, Ctx, I, M>>,
+    rng: StdRng,
+    default_conditions: LinkConditions,
+    // by the indexes of the two nodes, the lowest first
+    links: HashMap<(usize, usize), SharedLinkConditions>,
+    next_port: u16,
+}
+
+impl<
+        Id: PeerId,
+        Ctx: Context<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+        M: MessagesHandler<Id>,
+    > Simulator<Id, Ctx, I, M>
+{
+    /// Empty network whose links have `default_conditions` until they are changed with
+    /// `set_link`
+    pub fn new(seed: u64, default_conditions: LinkConditions) -> Self {
+        Simulator {
+            nodes: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+            default_conditions,
+            links: HashMap::new(),
+            next_port: LISTENER_PORT + 1,
+        }
+    }
+
+    /// Start a manager, returns its index in the simulator
+    pub fn add_node(
+        &mut self,
+        config: PeerNetConfiguration<Id, Ctx, I, M>,
+    ) -> PeerNetResult<usize> {
+        self.nodes.push(PeerNetManager::new(config)?);
+        Ok(self.nodes.len() - 1)
+    }
+
+    pub fn nb_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node(&self, index: usize) -> &PeerNetManager<Id, Ctx, I, M> {
+        &self.nodes[index]
+    }
+
+    pub fn node_mut(&mut self, index: usize) -> &mut PeerNetManager<Id, Ctx, I, M> {
+        &mut self.nodes[index]
+    }
+
+    /// Address under which the node `index` is seen by the nodes it connects to
+    pub fn addr(&self, index: usize) -> SocketAddr {
+        SocketAddr::new(node_ip(index).into(), LISTENER_PORT)
+    }
+
+    /// Change the conditions of the link between `a` and `b`, applied to the messages they
+    /// send from now on, on their current and future connections
+    pub fn set_link(&mut self, a: usize, b: usize, conditions: LinkConditions) {
+        *self.link(a, b).write() = conditions;
+    }
+
+    pub fn link_conditions(&self, a: usize, b: usize) -> LinkConditions {
+        self.links
+            .get(&(a.min(b), a.max(b)))
+            .map_or(self.default_conditions, |link| *link.read())
+    }
+
+    /// Connect `from` to `to`, the handshake runs in the background as with
+    /// `PeerNetManager::try_connect`. Fails if `to` refuses the connection.
+    pub fn connect(&mut self, from: usize, to: usize) -> PeerNetResult<()> {
+        if from == to || from.max(to) >= self.nodes.len() {
+            return Err(PeerNetError::PeerConnectionError.error(
+                "simulator connect",
+                Some(format!("no link from node {} to node {}", from, to)),
+            ));
+        }
+        let conditions = self.link(from, to);
+        let from_addr = SocketAddr::new(node_ip(from).into(), self.next_port);
+        self.next_port = self.next_port.checked_add(1).unwrap_or(LISTENER_PORT + 1);
+        let (out_endpoint, in_endpoint) = MemoryEndpoint::pair(
+            self.addr(to),
+            from_addr,
+            conditions,
+            [self.rng.gen(), self.rng.gen()],
+        )?;
+        self.nodes[to].connect_endpoint(
+            Endpoint::Custom(Box::new(in_endpoint)),
+            PeerConnectionType::IN,
+        )?;
+        self.nodes[from].connect_endpoint(
+            Endpoint::Custom(Box::new(out_endpoint)),
+            PeerConnectionType::OUT,
+        )
+    }
+
+    /// Connect every node to the `degree` nodes after it, wrapping around
+    pub fn connect_ring(&mut self, degree: usize) -> PeerNetResult<()> {
+        let nb_nodes = self.nodes.len();
+        for from in 0..nb_nodes {
+            for offset in 1..=degree.min(nb_nodes.saturating_sub(1)) {
+                self.connect(from, (from + offset) % nb_nodes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait at most `timeout` for `condition` to be true on all the nodes
+    pub fn wait_until(
+        &self,
+        timeout: Duration,
+        condition: impl Fn(&PeerNetManager<Id, Ctx, I, M>) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.nodes.iter().all(&condition) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn link(&mut self, a: usize, b: usize) -> SharedLinkConditions {
+        let default_conditions = self.default_conditions;
+        self.links
+            .entry((a.min(b), a.max(b)))
+            .or_insert_with(|| Arc::new(RwLock::new(default_conditions)))
+            .clone()
+    }
+}
+
+fn node_ip(index: usize) -> Ipv4Addr {
+    // 10.0.0.1 for the first node
+    Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 1)) + index as u32)
+}
@@ -0,0 +1,311 @@
+//! Snapshot of the state of a manager, see `PeerNetManager::dump_state`
+//!
+//! The snapshot is taken under a single read of the `ActiveConnections` so that the counters,
+//! the peers and the queues are consistent with each other. It can be serialized with serde to
+//! be returned by the status API of a node.
+//!
+//! `ConnectionsSnapshot` is a lighter copy of the connections, with the ids of the peers, for
+//! the monitoring code that would otherwise hold the lock while formatting them.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::bandwidth::{BandwidthRates, BandwidthSnapshot};
+use crate::config::{PeerNetCategories, PeerNetCategoryInfo, TrustedPeer};
+use crate::memory::{MemoryStats, PeerMemoryUsage};
+use crate::network_manager::ActiveConnections;
+use crate::peer::{PeerConnectionType, SendStats};
+use crate::peer_id::PeerId;
+use crate::timings::PeerTimings;
+use crate::transports::TransportType;
+
+/// Longest wait for the lock of the connections in `PeerNetManager::dump_state`
+pub const STATE_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerNetStateSnapshot {
+    /// The connections were still locked after `STATE_LOCK_TIMEOUT`, e.g. in a deadlock. Only
+    /// `bandwidth`, `rates` and `config` are filled then.
+    pub locked: bool,
+    pub listeners: Vec<(SocketAddr, TransportType)>,
+    pub nb_in_connections: usize,
+    pub nb_out_connections: usize,
+    /// Addresses of the connections doing their handshake
+    pub in_connection_queue: Vec<SocketAddr>,
+    pub out_connection_queue: Vec<SocketAddr>,
+    /// Entries of the queues evicted after `PeerNetFeatures::handshake_timeout`
+    pub nb_evicted_handshakes: u64,
+    /// Connected peers, ordered by address
+    pub peers: Vec<PeerSnapshot>,
+    /// Occupancy of each category, the peers of no category first
+    pub categories: Vec<CategorySnapshot>,
+    /// Total of all the peers since the creation of the manager
+    pub bandwidth: BandwidthSnapshot,
+    pub rates: BandwidthRates,
+    /// If `PeerNetFeatures::memory_budget` is set
+    pub memory: Option<MemoryStats>,
+    /// Peers accepted beyond the limits
+    pub trusted_peers: TrustedPeersSnapshot,
+    /// Peers accepted or refused by their id
+    pub peer_id_rules: PeerIdRulesSnapshot,
+    /// `PeerNetConfiguration::describe` of the manager
+    pub config: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerSnapshot {
+    /// `Debug` format of the id of the peer
+    pub peer_id: String,
+    pub addr: SocketAddr,
+    pub transport: &'static str,
+    pub direction: PeerConnectionType,
+    pub category: Option<String>,
+    pub protocols: Vec<String>,
+    /// See `PeerConnection::metadata`
+    pub metadata: BTreeMap<String, String>,
+    /// IP the peer sees for us, if it was exchanged
+    pub observed_ip: Option<IpAddr>,
+    /// Messages waiting in the send channels
+    pub queued_high_priority: usize,
+    pub queued_low_priority: usize,
+    pub nb_expired_messages: u64,
+    pub send_stats: SendStats,
+    /// If `PeerNetFeatures::peer_timings` is enabled
+    pub timings: Option<PeerTimings>,
+    /// If `PeerNetFeatures::memory_budget` is set
+    pub memory: Option<PeerMemoryUsage>,
+    pub bandwidth: BandwidthSnapshot,
+    pub rates: BandwidthRates,
+}
+
+/// See `TrustedPeers`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TrustedPeersSnapshot {
+    /// `Debug` format of the trusted ids, ordered
+    pub ids: Vec<String>,
+    pub ips: Vec<IpAddr>,
+    pub evict_untrusted: bool,
+}
+
+/// See `PeerIdRules`, the ids are in `Debug` format and ordered
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerIdRulesSnapshot {
+    /// All the peers are allowed if `None`
+    pub allowed_peer_ids: Option<Vec<String>>,
+    /// The banned peers, refused whatever their address
+    pub denied_peer_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CategorySnapshot {
+    /// `None` for the peers of no category
+    pub name: Option<String>,
+    pub stats: CategoryStats,
+    pub limits: PeerNetCategoryInfo,
+}
+
+/// Connections of a category and bytes of its peers since the creation of the manager, see
+/// `PeerNetManager::category_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CategoryStats {
+    pub nb_in_connections: usize,
+    pub nb_out_connections: usize,
+    pub bandwidth: BandwidthSnapshot,
+}
+
+/// Connections of a manager at one instant, see `PeerNetManager::connections_snapshot`
+#[derive(Debug, Clone)]
+pub struct ConnectionsSnapshot<Id> {
+    pub nb_in_connections: usize,
+    pub nb_out_connections: usize,
+    /// Connections doing their handshake
+    pub nb_in_handshakes: usize,
+    pub nb_out_handshakes: usize,
+    /// Ordered by address
+    pub connections: Vec<ConnectionInfo<Id>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo<Id> {
+    pub peer_id: Id,
+    pub addr: SocketAddr,
+    pub direction: PeerConnectionType,
+    pub category: Option<String>,
+    /// See `PeerConnection::metadata`
+    pub metadata: BTreeMap<String, String>,
+}
+
+pub(crate) fn connections_snapshot<Id: PeerId>(
+    active_connections: &ActiveConnections<Id>,
+) -> ConnectionsSnapshot<Id> {
+    let mut connections: Vec<ConnectionInfo<Id>> = active_connections
+        .connections
+        .iter()
+        .map(|(peer_id, connection)| ConnectionInfo {
+            peer_id: peer_id.clone(),
+            addr: *connection.endpoint.get_target_addr(),
+            direction: connection.connection_type,
+            category: connection.category_name.clone(),
+            metadata: connection.metadata.clone(),
+        })
+        .collect();
+    connections.sort_by_key(|connection| connection.addr);
+    ConnectionsSnapshot {
+        nb_in_connections: active_connections.nb_in_connections,
+        nb_out_connections: active_connections.nb_out_connections,
+        nb_in_handshakes: active_connections.in_connection_queue.len(),
+        nb_out_handshakes: active_connections.out_connection_queue.len(),
+        connections,
+    }
+}
+
+pub(crate) fn category_stats<Id: PeerId>(
+    active_connections: &ActiveConnections<Id>,
+    name: Option<&str>,
+) -> CategoryStats {
+    let removed = active_connections
+        .removed_bandwidth
+        .get(&name.map(str::to_string))
+        .copied()
+        .unwrap_or_default();
+    active_connections
+        .connections
+        .values()
+        .filter(|connection| connection.category_name.as_deref() == name)
+        .fold(
+            CategoryStats {
+                bandwidth: removed,
+                ..Default::default()
+            },
+            |mut stats, connection| {
+                match connection.connection_type {
+                    PeerConnectionType::IN => stats.nb_in_connections += 1,
+                    PeerConnectionType::OUT => stats.nb_out_connections += 1,
+                }
+                stats.bandwidth = stats.bandwidth + connection.endpoint.get_bandwidth();
+                stats
+            },
+        )
+}
+
+pub(crate) fn snapshot<Id: PeerId>(
+    active_connections: &ActiveConnections<Id>,
+    categories: &PeerNetCategories,
+    default_category_info: PeerNetCategoryInfo,
+    bandwidth: BandwidthSnapshot,
+    rates: BandwidthRates,
+) -> PeerNetStateSnapshot {
+    let mut peers: Vec<PeerSnapshot> = active_connections
+        .connections
+        .iter()
+        .map(|(peer_id, connection)| {
+            let (queued_high_priority, queued_low_priority) =
+                connection.send_channels.queued_messages();
+            PeerSnapshot {
+                peer_id: format!("{:?}", peer_id),
+                addr: *connection.endpoint.get_target_addr(),
+                transport: connection.endpoint.transport_name(),
+                direction: connection.connection_type,
+                category: connection.category_name.clone(),
+                protocols: connection.protocols.clone(),
+                metadata: connection.metadata.clone(),
+                observed_ip: active_connections.observed_addresses.get(peer_id).copied(),
+                queued_high_priority,
+                queued_low_priority,
+                nb_expired_messages: connection.send_channels.nb_expired_messages(),
+                send_stats: connection.send_channels.send_stats(),
+                timings: connection.timings(),
+                memory: connection.send_channels.memory_usage(),
+                bandwidth: connection.endpoint.get_bandwidth(),
+                rates: connection.endpoint.get_rates(),
+            }
+        })
+        .collect();
+    peers.sort_by_key(|peer| peer.addr);
+
+    let mut names: Vec<&String> = categories.keys().collect();
+    names.sort();
+    let categories = std::iter::once((None, default_category_info))
+        .chain(
+            names
+                .into_iter()
+                .map(|name| (Some(name), categories[name].1)),
+        )
+        .map(|(name, limits)| CategorySnapshot {
+            name: name.cloned(),
+            stats: category_stats(active_connections, name.map(String::as_str)),
+            limits,
+        })
+        .collect();
+
+    let mut listeners: Vec<(SocketAddr, TransportType)> = active_connections
+        .listeners
+        .iter()
+        .map(|(addr, transport_type)| (*addr, *transport_type))
+        .collect();
+    listeners.sort_by_key(|(addr, _)| *addr);
+    let mut in_connection_queue: Vec<SocketAddr> = active_connections
+        .in_connection_queue
+        .keys()
+        .copied()
+        .collect();
+    in_connection_queue.sort();
+    let mut out_connection_queue: Vec<SocketAddr> = active_connections
+        .out_connection_queue
+        .keys()
+        .copied()
+        .collect();
+    out_connection_queue.sort();
+
+    let format_ids = |ids: &mut dyn Iterator<Item = &Id>| {
+        let mut ids: Vec<String> = ids.map(|id| format!("{:?}", id)).collect();
+        ids.sort();
+        ids
+    };
+    let trusted_peers = &active_connections.trusted_peers;
+    let mut trusted_ips = Vec::new();
+    let mut trusted_ids = Vec::new();
+    for peer in &trusted_peers.peers {
+        match peer {
+            TrustedPeer::Id(id) => trusted_ids.push(id),
+            TrustedPeer::Ip(ip) => trusted_ips.push(*ip),
+        }
+    }
+    trusted_ips.sort();
+    let peer_id_rules = &active_connections.peer_id_rules;
+
+    PeerNetStateSnapshot {
+        locked: false,
+        listeners,
+        nb_in_connections: active_connections.nb_in_connections,
+        nb_out_connections: active_connections.nb_out_connections,
+        in_connection_queue,
+        out_connection_queue,
+        nb_evicted_handshakes: active_connections.nb_evicted_handshakes,
+        peers,
+        categories,
+        bandwidth,
+        rates,
+        memory: active_connections
+            .memory
+            .as_ref()
+            .map(|memory| memory.stats()),
+        trusted_peers: TrustedPeersSnapshot {
+            ids: format_ids(&mut trusted_ids.into_iter()),
+            ips: trusted_ips,
+            evict_untrusted: trusted_peers.evict_untrusted,
+        },
+        peer_id_rules: PeerIdRulesSnapshot {
+            allowed_peer_ids: peer_id_rules
+                .allowed_peer_ids
+                .as_ref()
+                .map(|allowed_peer_ids| format_ids(&mut allowed_peer_ids.iter())),
+            denied_peer_ids: format_ids(&mut peer_id_rules.denied_peer_ids.iter()),
+        },
+        // filled by `PeerNetManager::dump_state`
+        config: String::new(),
+    }
+}
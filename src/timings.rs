@@ -0,0 +1,80 @@
+//! Time spent on each peer, see `PeerNetFeatures::peer_timings`
+//!
+//! The times are summed from the threads working for the peer: its handshake, the calls of the
+//! messages handler (from its read loop, the handler workers or an event loop) and the writes of
+//! its write thread. They find the handlers that are slow for some messages and the peers that
+//! send them. The reads are not measured as they mostly wait for the peer.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Times of a peer since the start of its handshake, see `PeerSnapshot::timings`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PeerTimings {
+    /// Proof of work, handshake and negotiations after it
+    pub handshake: Duration,
+    /// In the messages handler, the streamed messages excluded
+    pub handler: Duration,
+    pub nb_handled_messages: u64,
+    /// In the writes of the messages, the waits of the rate limiter included. Not measured for
+    /// the connections driven by `PeerNetFeatures::tcp_reactor`
+    pub write: Duration,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PeerTimers {
+    handshake_nanos: AtomicU64,
+    handler_nanos: AtomicU64,
+    nb_handled_messages: AtomicU64,
+    write_nanos: AtomicU64,
+}
+
+impl PeerTimers {
+    pub(crate) fn add_handshake(&self, elapsed: Duration) {
+        add(&self.handshake_nanos, elapsed);
+    }
+
+    pub(crate) fn add_handler(&self, elapsed: Duration) {
+        add(&self.handler_nanos, elapsed);
+        self.nb_handled_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_write(&self, elapsed: Duration) {
+        add(&self.write_nanos, elapsed);
+    }
+
+    pub(crate) fn timings(&self) -> PeerTimings {
+        PeerTimings {
+            handshake: Duration::from_nanos(self.handshake_nanos.load(Ordering::Relaxed)),
+            handler: Duration::from_nanos(self.handler_nanos.load(Ordering::Relaxed)),
+            nb_handled_messages: self.nb_handled_messages.load(Ordering::Relaxed),
+            write: Duration::from_nanos(self.write_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn add(nanos: &AtomicU64, elapsed: Duration) {
+    nanos.fetch_add(
+        u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+/// Run `f`, its duration is given to `record` if the peer is measured
+pub(crate) fn timed<T>(
+    timers: Option<&PeerTimers>,
+    record: fn(&PeerTimers, Duration),
+    f: impl FnOnce() -> T,
+) -> T {
+    match timers {
+        Some(timers) => {
+            let start = Instant::now();
+            let res = f();
+            record(timers, start.elapsed());
+            res
+        }
+        None => f(),
+    }
+}
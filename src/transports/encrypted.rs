@@ -0,0 +1,137 @@
+//! Encryption of the messages of an endpoint once the handshake agreed on session keys
+//!
+//! Every message is sealed with ChaCha20-Poly1305. Each direction has its own key and a nonce
+//! counting the messages sent in this direction, so a message replayed, reordered or altered
+//! on the way can't be decrypted.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use parking_lot::Mutex;
+
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::peer_id::PeerId;
+
+use super::endpoint::Endpoint;
+
+pub const SESSION_KEY_SIZE: usize = 32;
+pub const AUTHENTICATION_TAG_SIZE: usize = 16;
+
+/// Cipher and nonce of one direction of the connection
+struct DirectionCipher {
+    cipher: ChaCha20Poly1305,
+    nonce: u64,
+}
+
+impl DirectionCipher {
+    fn new(key: &[u8; SESSION_KEY_SIZE]) -> Self {
+        DirectionCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> PeerNetResult<Nonce> {
+        // a nonce must never be used twice with the same key
+        if self.nonce == u64::MAX {
+            return Err(PeerNetError::InvalidMessage.error("session nonce exhausted", None));
+        }
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_be_bytes());
+        self.nonce += 1;
+        Ok(Nonce::from(nonce))
+    }
+}
+
+/// Endpoint encrypting the messages sent and decrypting the messages received on `inner`.
+/// The clones of the endpoint share the ciphers: the write thread only uses the sending one
+/// and the read loop the receiving one. The lock of a cipher is held from the choice of the
+/// nonce to the write, or the read, of the message, so that the messages of concurrent clones
+/// are sent in the order of their nonces.
+pub struct EncryptedEndpoint {
+    pub(crate) inner: Box<Endpoint>,
+    send_cipher: Arc<Mutex<DirectionCipher>>,
+    receive_cipher: Arc<Mutex<DirectionCipher>>,
+}
+
+impl EncryptedEndpoint {
+    pub(crate) fn new(
+        inner: Endpoint,
+        send_key: &[u8; SESSION_KEY_SIZE],
+        receive_key: &[u8; SESSION_KEY_SIZE],
+    ) -> Self {
+        EncryptedEndpoint {
+            inner: Box::new(inner),
+            send_cipher: Arc::new(Mutex::new(DirectionCipher::new(send_key))),
+            receive_cipher: Arc::new(Mutex::new(DirectionCipher::new(receive_key))),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> PeerNetResult<Self> {
+        Ok(EncryptedEndpoint {
+            inner: Box::new(self.inner.try_clone()?),
+            send_cipher: self.send_cipher.clone(),
+            receive_cipher: self.receive_cipher.clone(),
+        })
+    }
+
+    fn encrypt(send_cipher: &mut DirectionCipher, data: &[u8]) -> PeerNetResult<Vec<u8>> {
+        let nonce = send_cipher.next_nonce()?;
+        send_cipher
+            .cipher
+            .encrypt(&nonce, data)
+            .map_err(|_| PeerNetError::SendError.error("encrypt message", None))
+    }
+
+    fn decrypt(receive_cipher: &mut DirectionCipher, data: &[u8]) -> PeerNetResult<Bytes> {
+        let nonce = receive_cipher.next_nonce()?;
+        receive_cipher
+            .cipher
+            .decrypt(&nonce, data)
+            .map(Bytes::from)
+            .map_err(|_| PeerNetError::InvalidMessage.error("decrypt message", None))
+    }
+
+    pub(crate) fn send<Id: PeerId>(&mut self, data: &[u8]) -> PeerNetResult<()> {
+        let mut send_cipher = self.send_cipher.lock();
+        let encrypted = Self::encrypt(&mut send_cipher, data)?;
+        self.inner.send::<Id>(&encrypted)
+    }
+
+    pub(crate) fn send_timeout<Id: PeerId>(
+        &mut self,
+        data: &[u8],
+        timeout: Duration,
+    ) -> PeerNetResult<()> {
+        let mut send_cipher = self.send_cipher.lock();
+        let encrypted = Self::encrypt(&mut send_cipher, data)?;
+        self.inner.send_timeout::<Id>(&encrypted, timeout)
+    }
+
+    pub(crate) fn send_batch<Id: PeerId>(&mut self, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        let mut send_cipher = self.send_cipher.lock();
+        let encrypted = data
+            .iter()
+            .map(|message| Self::encrypt(&mut send_cipher, message))
+            .collect::<PeerNetResult<Vec<Vec<u8>>>>()?;
+        self.inner.send_batch::<Id>(&encrypted)
+    }
+
+    pub(crate) fn receive<Id: PeerId>(&mut self) -> PeerNetResult<Bytes> {
+        let mut receive_cipher = self.receive_cipher.lock();
+        let data = self.inner.receive::<Id>()?;
+        Self::decrypt(&mut receive_cipher, &data)
+    }
+
+    /// Same as `receive` for a message of at most `max_size` bytes once decrypted
+    pub(crate) fn receive_limited<Id: PeerId>(&mut self, max_size: usize) -> PeerNetResult<Bytes> {
+        let mut receive_cipher = self.receive_cipher.lock();
+        let data = self
+            .inner
+            .receive_limited::<Id>(max_size.saturating_add(AUTHENTICATION_TAG_SIZE))?;
+        Self::decrypt(&mut receive_cipher, &data)
+    }
+}
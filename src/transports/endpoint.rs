@@ -1,27 +1,110 @@
+use std::net::SocketAddr;
 use std::time::Duration;
 
+use bytes::Bytes;
+
+use crate::bandwidth::{BandwidthRates, BandwidthSnapshot};
 use crate::context::Context;
-use crate::error::PeerNetResult;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::messages::MessagesHandler;
+use crate::peer::PeerHandle;
 use crate::peer_id::PeerId;
 
+use super::encrypted::{EncryptedEndpoint, AUTHENTICATION_TAG_SIZE, SESSION_KEY_SIZE};
+use super::relayed::RelayedEndpoint;
 use super::tcp::TcpEndpoint;
 use super::{
     quic::{QuicEndpoint, QuicTransport},
     tcp::TcpTransport,
-    Transport,
+    Transport, TransportType,
 };
 
-#[cfg(feature = "testing")]
-use crate::error::PeerNetError;
 #[cfg(feature = "testing")]
 use crossbeam::channel::{Receiver, Sender};
-#[cfg(feature = "testing")]
-use std::net::SocketAddr;
+
+/// Endpoint of a transport or of a wrapper (compression, tunneling...) defined outside of the
+/// crate, see `Endpoint::Custom`. A wrapper can be installed during the handshake by replacing
+/// the endpoint with one wrapping its `try_clone`, like `Endpoint::install_encryption`.
+pub trait EndpointImpl: Send + Sync {
+    fn get_target_addr(&self) -> &SocketAddr;
+
+    /// Name of the transport in the logs
+    fn transport_name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Transport with which the target address can be dialed again, `None` if it can't
+    fn transport_type(&self) -> Option<TransportType> {
+        None
+    }
+
+    /// Capacity of the send channels of the peer
+    fn get_data_channel_size(&self) -> usize;
+
+    /// Biggest message the connection can send, checked before queueing. No limit if `None`
+    fn get_max_message_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Another handle on the same connection, for the write thread of the peer
+    fn try_clone(&self) -> PeerNetResult<Box<dyn EndpointImpl>>;
+
+    fn send(&mut self, data: &[u8]) -> PeerNetResult<()>;
+
+    fn send_timeout(&mut self, data: &[u8], _timeout: Duration) -> PeerNetResult<()> {
+        self.send(data)
+    }
+
+    fn send_batch(&mut self, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        for message in data {
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> PeerNetResult<Bytes>;
+
+    /// Hand the data sent so far to the transport, see `Endpoint::flush`
+    fn flush(&mut self) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    /// Close the connection, the pending `receive` of the other handles must return
+    fn shutdown(&mut self);
+
+    fn get_bandwidth(&self) -> BandwidthSnapshot {
+        BandwidthSnapshot::default()
+    }
+
+    /// See `Endpoint::get_bandwidth_delta`
+    fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        BandwidthSnapshot::default()
+    }
+
+    /// See `Endpoint::reset_counters`
+    fn reset_counters(&self) -> BandwidthSnapshot {
+        BandwidthSnapshot::default()
+    }
+
+    /// See `Endpoint::get_total_bandwidth`
+    fn get_total_bandwidth(&self) -> BandwidthSnapshot {
+        BandwidthSnapshot::default()
+    }
+
+    fn get_rates(&self) -> BandwidthRates {
+        BandwidthRates::default()
+    }
+}
 
 #[allow(clippy::large_enum_variant)]
 pub enum Endpoint {
     Tcp(TcpEndpoint),
     Quic(QuicEndpoint),
+    /// Endpoint encrypted after the handshake, see `install_encryption`
+    Encrypted(EncryptedEndpoint),
+    /// Endpoint tunneled through a relay peer, see `PeerNetManager::try_connect_relayed`
+    Relayed(RelayedEndpoint),
+    Custom(Box<dyn EndpointImpl>),
     #[cfg(feature = "testing")]
     // First parameter is a sender that should be received by the user and the second is
     // a receiver that the user should send to
@@ -33,25 +116,78 @@ impl Endpoint {
         match self {
             Endpoint::Tcp(TcpEndpoint { address, .. }) => address,
             Endpoint::Quic(QuicEndpoint { address, .. }) => address,
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_target_addr(),
+            Endpoint::Relayed(RelayedEndpoint { address, .. }) => address,
+            Endpoint::Custom(endpoint) => endpoint.get_target_addr(),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((_, _, address)) => address,
         }
     }
 
-    pub(crate) fn get_data_channel_size(&self) -> usize {
+    /// Name of the transport in the logs
+    pub(crate) fn transport_name(&self) -> &'static str {
+        match self {
+            Endpoint::Tcp(_) => "tcp",
+            Endpoint::Quic(_) => "quic",
+            Endpoint::Encrypted(endpoint) => endpoint.inner.transport_name(),
+            Endpoint::Relayed(_) => "relayed",
+            Endpoint::Custom(endpoint) => endpoint.transport_name(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => "mock",
+        }
+    }
+
+    /// Transport with which the target address can be dialed again, `None` for the relayed
+    /// endpoints and the custom ones that don't tell
+    pub fn transport_type(&self) -> Option<TransportType> {
+        match self {
+            Endpoint::Tcp(_) => Some(TransportType::Tcp),
+            Endpoint::Quic(_) => Some(TransportType::Quic),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.transport_type(),
+            Endpoint::Relayed(_) => None,
+            Endpoint::Custom(endpoint) => endpoint.transport_type(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => None,
+        }
+    }
+
+    pub fn get_data_channel_size(&self) -> usize {
         match self {
             Endpoint::Tcp(TcpEndpoint { config, .. }) => config.data_channel_size,
             //TODO: Real value
             Endpoint::Quic(QuicEndpoint { .. }) => 0,
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_data_channel_size(),
+            Endpoint::Relayed(endpoint) => endpoint.get_data_channel_size(),
+            Endpoint::Custom(endpoint) => endpoint.get_data_channel_size(),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint(_) => 0,
         }
     }
 
+    /// Biggest message that can be sent on the endpoint, no limit if `None`
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        match self {
+            Endpoint::Tcp(TcpEndpoint { config, .. }) => config.max_message_size,
+            Endpoint::Quic(_) => None,
+            // the frames carry the authentication tag after the message
+            Endpoint::Encrypted(endpoint) => endpoint
+                .inner
+                .get_max_message_size()
+                .map(|max_message_size| max_message_size.saturating_sub(AUTHENTICATION_TAG_SIZE)),
+            Endpoint::Relayed(_) => None,
+            Endpoint::Custom(endpoint) => endpoint.get_max_message_size(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => None,
+        }
+    }
+
     pub fn try_clone(&self) -> PeerNetResult<Endpoint> {
         match self {
             Endpoint::Tcp(endpoint) => Ok(Endpoint::Tcp(endpoint.try_clone()?)),
             Endpoint::Quic(endpoint) => Ok(Endpoint::Quic(endpoint.clone())),
+            Endpoint::Encrypted(endpoint) => Ok(Endpoint::Encrypted(endpoint.try_clone()?)),
+            Endpoint::Relayed(endpoint) => Ok(Endpoint::Relayed(endpoint.clone())),
+            Endpoint::Custom(endpoint) => Ok(Endpoint::Custom(endpoint.try_clone()?)),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((sender, receiver, addr)) => Ok(Endpoint::MockEndpoint((
                 sender.clone(),
@@ -65,6 +201,9 @@ impl Endpoint {
         match self {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send(endpoint, data),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::send(endpoint, data),
+            Endpoint::Encrypted(endpoint) => endpoint.send::<Id>(data),
+            Endpoint::Relayed(endpoint) => endpoint.send(data),
+            Endpoint::Custom(endpoint) => endpoint.send(data),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((sender, _, _)) => sender
                 .send(data.to_vec())
@@ -80,6 +219,9 @@ impl Endpoint {
         match self {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send_timeout(endpoint, data, timeout),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::send_timeout(endpoint, data, timeout),
+            Endpoint::Encrypted(endpoint) => endpoint.send_timeout::<Id>(data, timeout),
+            Endpoint::Relayed(endpoint) => endpoint.send_timeout(data, timeout),
+            Endpoint::Custom(endpoint) => endpoint.send_timeout(data, timeout),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((sender, _, _)) => sender
                 .send(data.to_vec())
@@ -87,17 +229,103 @@ impl Endpoint {
         }
     }
 
-    pub fn receive<Id: PeerId>(&mut self) -> PeerNetResult<Vec<u8>> {
+    pub fn send_batch<Id: PeerId>(&mut self, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        match self {
+            Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send_batch(endpoint, data),
+            Endpoint::Quic(endpoint) => QuicTransport::<Id>::send_batch(endpoint, data),
+            Endpoint::Encrypted(endpoint) => endpoint.send_batch::<Id>(data),
+            Endpoint::Relayed(endpoint) => endpoint.send_batch(data),
+            Endpoint::Custom(endpoint) => endpoint.send_batch(data),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint((sender, _, _)) => {
+                for message in data {
+                    sender
+                        .send(message.clone())
+                        .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn receive<Id: PeerId>(&mut self) -> PeerNetResult<Bytes> {
         match self {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::receive(endpoint),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::receive(endpoint),
+            Endpoint::Encrypted(endpoint) => endpoint.receive::<Id>(),
+            Endpoint::Relayed(endpoint) => endpoint.receive(),
+            Endpoint::Custom(endpoint) => endpoint.receive(),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((_, receiver, _)) => receiver
                 .recv()
+                .map(Bytes::from)
                 .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None)),
         }
     }
 
+    /// Receive a message of at most `max_size` bytes, e.g. during the handshake. The TCP
+    /// endpoints refuse a bigger frame from its header, before reading it, the other transports
+    /// once they received the message.
+    pub(crate) fn receive_limited<Id: PeerId>(&mut self, max_size: usize) -> PeerNetResult<Bytes> {
+        let data = match self {
+            Endpoint::Tcp(endpoint) => {
+                let max_message_size = endpoint.config.max_message_size;
+                endpoint.config.max_message_size =
+                    Some(max_message_size.map_or(max_size, |max| max.min(max_size)));
+                let data = TcpTransport::<Id>::receive(endpoint);
+                endpoint.config.max_message_size = max_message_size;
+                data?
+            }
+            Endpoint::Encrypted(endpoint) => endpoint.receive_limited::<Id>(max_size)?,
+            _ => self.receive::<Id>()?,
+        };
+        if data.len() > max_size {
+            return Err(PeerNetError::InvalidMessage.error(
+                "message too large",
+                Some(format!("size: {}, max: {}", data.len(), max_size)),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Receive a message, the messages bigger than `chunk_size` are streamed to the chunk
+    /// callbacks of `message_handler` and `None` is returned. Transports that can't stream
+    /// always return the whole message.
+    pub(crate) fn receive_streamed<Id: PeerId, M: MessagesHandler<Id>>(
+        &mut self,
+        chunk_size: usize,
+        message_handler: &M,
+        peer: &PeerHandle<Id>,
+    ) -> PeerNetResult<Option<Bytes>> {
+        match self {
+            Endpoint::Tcp(endpoint) => {
+                TcpTransport::<Id>::receive_streamed(endpoint, chunk_size, message_handler, peer)
+            }
+            _ => self.receive::<Id>().map(Some),
+        }
+    }
+
+    /// Encrypt all the following messages with the session keys agreed on during the handshake.
+    /// `send_key` must be the `receive_key` of the other peer and reciprocally.
+    pub fn install_encryption(
+        &mut self,
+        send_key: &[u8; SESSION_KEY_SIZE],
+        receive_key: &[u8; SESSION_KEY_SIZE],
+    ) -> PeerNetResult<()> {
+        let inner = self.try_clone()?;
+        *self = Endpoint::Encrypted(EncryptedEndpoint::new(inner, send_key, receive_key));
+        Ok(())
+    }
+
+    /// Remove the limits of the handshake, see `PeerNetFeatures::handshake_data_limit`
+    pub(crate) fn end_handshake(&mut self) {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.config.handshake_data_limit = None,
+            Endpoint::Encrypted(endpoint) => endpoint.inner.end_handshake(),
+            _ => {}
+        }
+    }
+
     pub(crate) fn handshake<Id: PeerId, Ctx: Context<Id>>(
         &mut self,
         _context: Ctx,
@@ -105,32 +333,101 @@ impl Endpoint {
         Ok(Id::generate())
     }
 
+    /// Hand the data sent so far to the transport, so that a last message, e.g. a goodbye, is
+    /// transmitted even if `shutdown` follows right after it. For TCP, the socket must also
+    /// linger (`TcpSettings::linger`) for its send buffer to outlive the close.
+    pub fn flush(&mut self) -> PeerNetResult<()> {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.flush(),
+            // the QUIC thread sends the data queued before the shutdown
+            Endpoint::Quic(_) => Ok(()),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.flush(),
+            // queued on the connection of the relay, flushed by its write thread
+            Endpoint::Relayed(_) => Ok(()),
+            Endpoint::Custom(endpoint) => endpoint.flush(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => Ok(()),
+        }
+    }
+
     pub fn shutdown(&mut self) {
         match self {
             Endpoint::Tcp(endpoint) => endpoint.shutdown(),
             Endpoint::Quic(endpoint) => endpoint.shutdown(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.shutdown(),
+            Endpoint::Relayed(endpoint) => endpoint.shutdown(),
+            Endpoint::Custom(endpoint) => endpoint.shutdown(),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint(_) => {}
         }
     }
 
-    /// return total bytes sent and received for the endpoint (sent, received)
-    pub fn get_bandwidth(&self) -> (u64, u64) {
+    /// return total bytes sent and received for the endpoint
+    pub fn get_bandwidth(&self) -> BandwidthSnapshot {
         match self {
-            Endpoint::Tcp(endpoint) => {
-                let receive = endpoint.get_bytes_received();
-                let sent = endpoint.get_bytes_sent();
-                (sent, receive)
-            }
-            Endpoint::Quic(endpoint) => {
-                let receive = endpoint.get_bytes_received();
-                let sent = endpoint.get_bytes_sent();
-                (sent, receive)
-            }
+            Endpoint::Tcp(endpoint) => endpoint.endpoint_bandwidth.snapshot(),
+            Endpoint::Quic(endpoint) => endpoint.get_bandwidth(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_bandwidth(),
+            Endpoint::Relayed(endpoint) => endpoint.get_bandwidth(),
+            Endpoint::Custom(endpoint) => endpoint.get_bandwidth(),
             #[cfg(feature = "testing")]
-            Endpoint::MockEndpoint(_) => (0, 0),
+            Endpoint::MockEndpoint(_) => BandwidthSnapshot::default(),
+        }
+    }
+
+    /// return the bytes and messages sent and received since the previous call, for the first
+    /// one since the creation of the endpoint. Not affected by `reset_counters`.
+    pub fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.endpoint_bandwidth.delta(),
+            Endpoint::Quic(endpoint) => endpoint.get_bandwidth_delta(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_bandwidth_delta(),
+            Endpoint::Relayed(endpoint) => endpoint.get_bandwidth_delta(),
+            Endpoint::Custom(endpoint) => endpoint.get_bandwidth_delta(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => BandwidthSnapshot::default(),
+        }
+    }
+
+    /// restart the counts of `get_bandwidth` from zero, returns the counts before the reset.
+    /// The rates and the deltas go on. For a connected peer, use
+    /// `PeerNetManager::reset_bandwidth` so that the stats of its category keep the counts.
+    pub fn reset_counters(&self) -> BandwidthSnapshot {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.endpoint_bandwidth.reset(),
+            Endpoint::Quic(endpoint) => endpoint.reset_counters(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.reset_counters(),
+            Endpoint::Relayed(endpoint) => endpoint.reset_counters(),
+            Endpoint::Custom(endpoint) => endpoint.reset_counters(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => BandwidthSnapshot::default(),
         }
     }
-}
 
-//TODO: Create trait for endpoint and match naming convention
+    /// return the bytes and messages sent and received since the creation of the endpoint. Not
+    /// affected by `reset_counters`, used to choose the peers to evict.
+    pub fn get_total_bandwidth(&self) -> BandwidthSnapshot {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.endpoint_bandwidth.total(),
+            Endpoint::Quic(endpoint) => endpoint.get_total_bandwidth(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_total_bandwidth(),
+            Endpoint::Relayed(endpoint) => endpoint.get_total_bandwidth(),
+            Endpoint::Custom(endpoint) => endpoint.get_total_bandwidth(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => BandwidthSnapshot::default(),
+        }
+    }
+
+    /// return the bytes per second sent and received lately by the endpoint
+    pub fn get_rates(&self) -> BandwidthRates {
+        match self {
+            Endpoint::Tcp(endpoint) => endpoint.endpoint_bandwidth.rates(),
+            Endpoint::Quic(endpoint) => endpoint.get_rates(),
+            Endpoint::Encrypted(endpoint) => endpoint.inner.get_rates(),
+            Endpoint::Relayed(endpoint) => endpoint.get_rates(),
+            Endpoint::Custom(endpoint) => endpoint.get_rates(),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint(_) => BandwidthRates::default(),
+        }
+    }
+}
@@ -0,0 +1,642 @@
+//! In-memory transport (feature `testing`), to test the logic of the manager without sockets
+//!
+//! The mock listeners of all the managers of the process are in a registry, so that a manager
+//! reaches the one of another manager by its address with `TransportType::Mock`. A connection
+//! goes through the same steps as a TCP one: limits of the in connections, category of the IP
+//! of the peer, gater, fallback of the refused connections and handshake. A manager dials from
+//! the IP of its lowest mock listener, or from 127.0.0.1 if it has none, with a new port for
+//! each connection.
+//!
+//! The connections are pairs of `MemoryEndpoint`, also used by the `Simulator` that delays and
+//! drops their messages, see `LinkConditions`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam::channel::{never, unbounded, Receiver, RecvTimeoutError, Sender};
+use crossbeam::select;
+use parking_lot::{Mutex, RwLock};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::bandwidth::{Bandwidth, BandwidthSnapshot, SharedBandwidth};
+use crate::buffer_pool::SharedBufferPool;
+use crate::config::{PeerNetCategories, PeerNetCategoryInfo, PeerNetFeatures};
+use crate::context::Context;
+use crate::dispatcher::MessageDispatcher;
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::history::ConnectionEventKind;
+use crate::messages::MessagesHandler;
+use crate::network_manager::{category_of, SharedActiveConnections};
+use crate::peer::{new_peer, InitConnectionHandler, PeerConnectionType};
+use crate::peer_id::PeerId;
+use crate::transports::endpoint::{Endpoint, EndpointImpl};
+
+use super::{Transport, TransportType};
+
+/// Capacity of the send channels of the connections of the `Simulator`
+pub const MEMORY_DATA_CHANNEL_SIZE: usize = 1000;
+/// First port of the dialing side of the mock connections
+const FIRST_DIAL_PORT: u16 = 49152;
+
+/// Mock listeners of the process, by address
+static LISTENERS: Mutex<BTreeMap<SocketAddr, Sender<MemoryEndpoint>>> = Mutex::new(BTreeMap::new());
+static NEXT_DIAL_PORT: AtomicU16 = AtomicU16::new(FIRST_DIAL_PORT);
+
+#[derive(Clone, Debug)]
+pub struct MockTransportConfig {
+    pub max_in_connections: Option<usize>,
+    pub peer_categories: PeerNetCategories,
+    pub default_category_info: PeerNetCategoryInfo,
+    pub data_channel_size: usize,
+}
+
+type MockListenerHandle = (Sender<()>, Vec<Sender<()>>, JoinHandle<PeerNetResult<()>>);
+
+pub(crate) struct MockTransport<Id: PeerId> {
+    active_connections: SharedActiveConnections<Id>,
+    // stop of the listener thread, stops of the peers it accepted, listener thread
+    listeners: HashMap<SocketAddr, MockListenerHandle>,
+    features: PeerNetFeatures,
+    config: MockTransportConfig,
+    total_bandwidth: SharedBandwidth,
+    buffer_pool: SharedBufferPool,
+    dispatcher: Option<MessageDispatcher<Id>>,
+}
+
+impl<Id: PeerId> MockTransport<Id> {
+    pub fn new(
+        active_connections: SharedActiveConnections<Id>,
+        config: MockTransportConfig,
+        features: PeerNetFeatures,
+        total_bandwidth: SharedBandwidth,
+        buffer_pool: SharedBufferPool,
+        dispatcher: Option<MessageDispatcher<Id>>,
+    ) -> MockTransport<Id> {
+        MockTransport {
+            active_connections,
+            listeners: Default::default(),
+            features,
+            config,
+            total_bandwidth,
+            buffer_pool,
+            dispatcher,
+        }
+    }
+
+    /// Give `config` to the connections dialed and to the listeners started from now on
+    pub(crate) fn replace_config(&mut self, config: MockTransportConfig) {
+        self.config = config;
+    }
+}
+
+impl<Id: PeerId> Drop for MockTransport<Id> {
+    fn drop(&mut self) {
+        let all_addresses: Vec<SocketAddr> = self.listeners.keys().cloned().collect();
+        all_addresses
+            .into_iter()
+            .for_each(|a| self.stop_listener(a).unwrap());
+    }
+}
+
+impl<Id: PeerId> Transport<Id> for MockTransport<Id> {
+    type TransportConfig = MockTransportConfig;
+
+    type Endpoint = MemoryEndpoint;
+
+    fn start_listener<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        address: SocketAddr,
+        message_handler: M,
+        mut init_connection_handler: I,
+    ) -> PeerNetResult<()> {
+        let (incoming_tx, incoming) = unbounded::<MemoryEndpoint>();
+        {
+            let mut listeners = LISTENERS.lock();
+            if listeners.contains_key(&address) {
+                return Err(PeerNetError::ListenerError
+                    .error("mock bind", Some(format!("address in use: {}", address))));
+            }
+            listeners.insert(address, incoming_tx);
+        }
+        let (stop_tx, stop_rx) = unbounded::<()>();
+        // the sender is dropped when the listener stops, which stops the peers it accepted only
+        let (peer_stop_tx, peer_stop_rx) = unbounded::<()>();
+        let spawned = self
+            .features
+            .reporting_builder(
+                format!("mock_listener_handle_{:?}", address),
+                "mock listener",
+            )
+            .spawn({
+                let active_connections = self.active_connections.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
+                let config = self.config.clone();
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
+                move || loop {
+                    let mut endpoint = select! {
+                        recv(stop_rx) -> _ => return Ok(()),
+                        recv(incoming) -> endpoint => match endpoint {
+                            Ok(endpoint) => endpoint,
+                            Err(_) => return Ok(()),
+                        },
+                    };
+                    let address = endpoint.address;
+                    let trusted = active_connections
+                        .read()
+                        .trusted_peers
+                        .is_trusted(None, &address);
+                    // the slot is held until the end of the handshake
+                    let reservation = if trusted {
+                        Some(active_connections.read().reserve_in())
+                    } else {
+                        active_connections.write().reserve_incoming(
+                            &address,
+                            config.max_in_connections,
+                            features.eviction_policy.as_ref(),
+                        )
+                    };
+                    let Some(reservation) = reservation else {
+                        endpoint.shutdown();
+                        continue;
+                    };
+                    let (category_name, category_info) = category_of(
+                        &address,
+                        &config.peer_categories,
+                        config.default_category_info,
+                    );
+                    endpoint.name = "mock";
+                    endpoint.data_channel_size = config.data_channel_size;
+                    endpoint.total_bandwidth = Some(total_bandwidth.clone());
+                    let mut endpoint = Endpoint::Custom(Box::new(endpoint));
+                    let listeners = {
+                        let mut active_connections = active_connections.write();
+                        // each peer in the queue has a thread running its handshake
+                        let handshakes_available =
+                            features.max_concurrent_handshakes.map_or(true, |max| {
+                                active_connections.in_connection_queue.len() < max
+                            });
+                        let now = active_connections.clock.now();
+                        active_connections.in_connection_queue.insert(address, now);
+                        if trusted
+                            || (handshakes_available
+                                && active_connections.gater.clone().check_pre_handshake(
+                                    &active_connections,
+                                    &address,
+                                    category_name.clone(),
+                                    category_info,
+                                ))
+                        {
+                            active_connections.compute_counters();
+                            None
+                        } else {
+                            Some(active_connections.listeners.clone())
+                        }
+                    };
+                    if let Some(listeners) = listeners {
+                        if let Err(err) = init_connection_handler.fallback_function(
+                            &context,
+                            &mut endpoint,
+                            &listeners,
+                        ) {
+                            tracing::error!(
+                                "Error while sending fallback to address {}, err:{}",
+                                address,
+                                err
+                            )
+                        }
+                        endpoint.shutdown();
+                        let mut active_connections = active_connections.write();
+                        active_connections.in_connection_queue.remove(&address);
+                        active_connections.history.record(
+                            address,
+                            ConnectionEventKind::Refused {
+                                direction: PeerConnectionType::IN,
+                            },
+                        );
+                        continue;
+                    }
+                    new_peer(
+                        context.clone(),
+                        endpoint,
+                        init_connection_handler.clone(),
+                        message_handler.clone(),
+                        active_connections.clone(),
+                        peer_stop_rx.clone(),
+                        PeerConnectionType::IN,
+                        category_name,
+                        category_info,
+                        features.clone(),
+                        buffer_pool.clone(),
+                        dispatcher.clone(),
+                        None,
+                        Some(reservation),
+                    );
+                }
+            });
+        let listener_handle = match spawned {
+            Ok(listener_handle) => listener_handle,
+            Err(err) => {
+                LISTENERS.lock().remove(&address);
+                return Err(PeerNetError::SocketError.new("spawn mock_listener", err, None));
+            }
+        };
+        self.active_connections
+            .write()
+            .listeners
+            .insert(address, TransportType::Mock);
+        self.listeners
+            .insert(address, (stop_tx, vec![peer_stop_tx], listener_handle));
+        Ok(())
+    }
+
+    /// The connection is opened at once, `_timeout` is unused
+    fn try_connect<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        address: SocketAddr,
+        _timeout: Duration,
+        message_handler: M,
+        handshake_handler: I,
+    ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
+        let config = self.config.clone();
+        self.features
+            .reporting_builder(
+                format!("mock_try_connect_{:?}", address),
+                "mock try_connect",
+            )
+            .spawn({
+                let active_connections = self.active_connections.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
+                move || {
+                    let local_ip = {
+                        let mut active_connections = active_connections.write();
+                        let now = active_connections.clock.now();
+                        active_connections.out_connection_queue.insert(address, now);
+                        active_connections
+                            .listeners
+                            .iter()
+                            .filter(|(_, transport_type)| **transport_type == TransportType::Mock)
+                            .map(|(addr, _)| addr.ip())
+                            .min()
+                            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                    };
+                    let from =
+                        SocketAddr::new(local_ip, NEXT_DIAL_PORT.fetch_add(1, Ordering::Relaxed));
+                    let connected = MemoryEndpoint::pair(
+                        address,
+                        from,
+                        Arc::new(RwLock::new(LinkConditions::default())),
+                        [0, 0],
+                    )
+                    .and_then(|(endpoint, peer_endpoint)| {
+                        let listener = LISTENERS.lock().get(&address).cloned();
+                        match listener.map(|listener| listener.send(peer_endpoint)) {
+                            Some(Ok(())) => Ok(endpoint),
+                            _ => Err(PeerNetError::PeerConnectionError.error(
+                                "mock try_connect",
+                                Some(format!("connection refused: {}", address)),
+                            )),
+                        }
+                    });
+                    let mut endpoint = match connected {
+                        Ok(endpoint) => endpoint,
+                        Err(err) => {
+                            active_connections
+                                .write()
+                                .out_connection_queue
+                                .remove(&address);
+                            return Err(err);
+                        }
+                    };
+                    let (category_name, category_info) = category_of(
+                        &address,
+                        &config.peer_categories,
+                        config.default_category_info,
+                    );
+                    endpoint.name = "mock";
+                    endpoint.data_channel_size = config.data_channel_size;
+                    endpoint.total_bandwidth = Some(total_bandwidth);
+                    new_peer(
+                        context,
+                        Endpoint::Custom(Box::new(endpoint)),
+                        handshake_handler,
+                        message_handler,
+                        active_connections,
+                        never(),
+                        PeerConnectionType::OUT,
+                        category_name,
+                        category_info,
+                        features,
+                        buffer_pool,
+                        dispatcher,
+                        None,
+                        None,
+                    );
+                    Ok(())
+                }
+            })
+            .map_err(|err| PeerNetError::SocketError.new("spawn mock_try_connect", err, None))
+    }
+
+    fn stop_listener(&mut self, address: SocketAddr) -> PeerNetResult<()> {
+        let (stop_tx, peer_stops, handle) = self.listeners.remove(&address).ok_or(
+            PeerNetError::ListenerError.error("rm addr", Some(format!("address: {}", address))),
+        )?;
+        LISTENERS.lock().remove(&address);
+        self.active_connections.write().listeners.remove(&address);
+        drop(stop_tx);
+        let result = handle
+            .join()
+            .unwrap_or_else(|_| panic!("Couldn't join listener for address {}", address));
+        // no more peers can be accepted, stop the ones it accepted
+        drop(peer_stops);
+        result
+    }
+
+    /// The peers of the old listener stay connected, they are stopped with the new one
+    fn rebind_listener<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        old_address: SocketAddr,
+        new_address: SocketAddr,
+        message_handler: M,
+        init_connection_handler: I,
+    ) -> PeerNetResult<()> {
+        if !self.listeners.contains_key(&old_address) {
+            return Err(PeerNetError::ListenerError
+                .error("rebind", Some(format!("address: {}", old_address))));
+        }
+        self.start_listener(
+            context,
+            new_address,
+            message_handler,
+            init_connection_handler,
+        )?;
+        let old_peer_stops = std::mem::take(&mut self.listeners.get_mut(&old_address).unwrap().1);
+        if let Some((_, peer_stops, _)) = self.listeners.get_mut(&new_address) {
+            peer_stops.extend(old_peer_stops);
+        }
+        self.stop_listener(old_address)
+    }
+
+    fn send(endpoint: &mut Self::Endpoint, data: &[u8]) -> PeerNetResult<()> {
+        EndpointImpl::send(endpoint, data)
+    }
+
+    fn send_timeout(
+        endpoint: &mut Self::Endpoint,
+        data: &[u8],
+        _timeout: Duration,
+    ) -> PeerNetResult<()> {
+        EndpointImpl::send(endpoint, data)
+    }
+
+    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Bytes> {
+        EndpointImpl::receive(endpoint)
+    }
+}
+
+/// Conditions of a link between two nodes of the `Simulator`, the same in both directions
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay of every message
+    pub latency: Duration,
+    /// Maximum delay added to the latency, drawn uniformly for each message
+    pub jitter: Duration,
+    /// Probability for a message to be dropped, between 0 and 1. 1 partitions the two nodes.
+    pub loss: f64,
+}
+
+pub(crate) type SharedLinkConditions = Arc<RwLock<LinkConditions>>;
+
+/// Message in flight on a direction of a connection, `None` closes the connection
+type InFlight = Option<(Instant, Bytes)>;
+
+/// Closes both directions of a connection when it's shut down or when the last handle of one
+/// of its sides is dropped, like the socket of a TCP connection
+struct Closer {
+    closed: Arc<AtomicBool>,
+    directions: [Sender<InFlight>; 2],
+}
+
+impl Closer {
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        for direction in &self.directions {
+            let _ = direction.send(None);
+        }
+    }
+}
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// One side of an in-memory connection
+pub struct MemoryEndpoint {
+    address: SocketAddr,
+    name: &'static str,
+    data_channel_size: usize,
+    conditions: SharedLinkConditions,
+    // shared by the handles of the side, as the draws and the order of the messages
+    rng: Arc<Mutex<StdRng>>,
+    last_delivery: Arc<Mutex<Instant>>,
+    outgoing: Sender<InFlight>,
+    incoming: Receiver<Bytes>,
+    closer: Arc<Closer>,
+    total_bandwidth: Option<SharedBandwidth>,
+    bandwidth: SharedBandwidth,
+}
+
+impl MemoryEndpoint {
+    /// The out side, targeting `to`, and the in side, targeting `from`. The messages of each
+    /// side are delayed and dropped by a generator seeded with its seed.
+    pub(crate) fn pair(
+        to: SocketAddr,
+        from: SocketAddr,
+        conditions: SharedLinkConditions,
+        seeds: [u64; 2],
+    ) -> PeerNetResult<(MemoryEndpoint, MemoryEndpoint)> {
+        let (out_tx, out_rx) = deliver(to)?;
+        let (in_tx, in_rx) = deliver(from)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let side = |address, seed, outgoing: &Sender<InFlight>, incoming| MemoryEndpoint {
+            address,
+            name: "simulated",
+            data_channel_size: MEMORY_DATA_CHANNEL_SIZE,
+            conditions: conditions.clone(),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+            last_delivery: Arc::new(Mutex::new(Instant::now())),
+            outgoing: outgoing.clone(),
+            incoming,
+            closer: Arc::new(Closer {
+                closed: closed.clone(),
+                directions: [out_tx.clone(), in_tx.clone()],
+            }),
+            total_bandwidth: None,
+            bandwidth: Bandwidth::new_shared(),
+        };
+        Ok((
+            side(to, seeds[0], &out_tx, in_rx),
+            side(from, seeds[1], &in_tx, out_rx),
+        ))
+    }
+}
+
+impl EndpointImpl for MemoryEndpoint {
+    fn get_target_addr(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    fn transport_name(&self) -> &'static str {
+        self.name
+    }
+
+    fn transport_type(&self) -> Option<TransportType> {
+        // the simulated links aren't dialed
+        (self.name == "mock").then_some(TransportType::Mock)
+    }
+
+    fn get_data_channel_size(&self) -> usize {
+        self.data_channel_size
+    }
+
+    fn try_clone(&self) -> PeerNetResult<Box<dyn EndpointImpl>> {
+        Ok(Box::new(MemoryEndpoint {
+            address: self.address,
+            name: self.name,
+            data_channel_size: self.data_channel_size,
+            conditions: self.conditions.clone(),
+            rng: self.rng.clone(),
+            last_delivery: self.last_delivery.clone(),
+            outgoing: self.outgoing.clone(),
+            incoming: self.incoming.clone(),
+            closer: self.closer.clone(),
+            total_bandwidth: self.total_bandwidth.clone(),
+            bandwidth: self.bandwidth.clone(),
+        }))
+    }
+
+    fn send(&mut self, data: &[u8]) -> PeerNetResult<()> {
+        if self.closer.closed.load(Ordering::Acquire) {
+            return Err(PeerNetError::ConnectionClosed.error("memory send", None));
+        }
+        self.bandwidth.add_sent(data.len() as u64);
+        if let Some(total_bandwidth) = &self.total_bandwidth {
+            total_bandwidth.add_sent(data.len() as u64);
+        }
+        let conditions = *self.conditions.read();
+        let deliver_at = {
+            let mut rng = self.rng.lock();
+            if conditions.loss > 0.0 && rng.gen_bool(conditions.loss.min(1.0)) {
+                return Ok(());
+            }
+            let jitter = conditions.jitter.mul_f64(rng.gen::<f64>());
+            let mut last_delivery = self.last_delivery.lock();
+            *last_delivery = (*last_delivery).max(Instant::now() + conditions.latency + jitter);
+            *last_delivery
+        };
+        self.outgoing
+            .send(Some((deliver_at, Bytes::copy_from_slice(data))))
+            .map_err(|_| PeerNetError::ConnectionClosed.error("memory send", None))
+    }
+
+    fn receive(&mut self) -> PeerNetResult<Bytes> {
+        let data = self
+            .incoming
+            .recv()
+            .map_err(|_| PeerNetError::ConnectionClosed.error("memory receive", None))?;
+        self.bandwidth.add_received(data.len() as u64);
+        if let Some(total_bandwidth) = &self.total_bandwidth {
+            total_bandwidth.add_received(data.len() as u64);
+        }
+        Ok(data)
+    }
+
+    fn shutdown(&mut self) {
+        self.closer.close();
+    }
+
+    fn get_bandwidth(&self) -> BandwidthSnapshot {
+        self.bandwidth.snapshot()
+    }
+
+    fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        self.bandwidth.delta()
+    }
+
+    fn reset_counters(&self) -> BandwidthSnapshot {
+        self.bandwidth.reset()
+    }
+
+    fn get_total_bandwidth(&self) -> BandwidthSnapshot {
+        self.bandwidth.total()
+    }
+}
+
+/// Thread delivering the messages sent toward `to` once their time has come
+fn deliver(to: SocketAddr) -> PeerNetResult<(Sender<InFlight>, Receiver<Bytes>)> {
+    let (in_flight_tx, in_flight_rx) = unbounded::<InFlight>();
+    let (delivered_tx, delivered_rx) = unbounded();
+    std::thread::Builder::new()
+        .name(format!("memory_link_{}", to))
+        .spawn(move || {
+            // in the order they were sent, which is the order of their delivery times. The
+            // receiver of the messages is disconnected when the thread ends.
+            let mut pending: VecDeque<(Instant, Bytes)> = VecDeque::new();
+            loop {
+                let received = match pending.front() {
+                    Some((deliver_at, _)) => in_flight_rx
+                        .recv_timeout(deliver_at.saturating_duration_since(Instant::now())),
+                    None => in_flight_rx
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(Some(in_flight)) => pending.push_back(in_flight),
+                    Ok(None) | Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+                let now = Instant::now();
+                while pending
+                    .front()
+                    .map_or(false, |(deliver_at, _)| *deliver_at <= now)
+                {
+                    let (_, data) = pending.pop_front().unwrap();
+                    if delivered_tx.send(data).is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+        .map_err(|err| PeerNetError::SocketError.new("spawn memory_link", err, None))?;
+    Ok((in_flight_tx, delivered_rx))
+}
@@ -2,35 +2,89 @@
 //!
 //! This module use enum dispatch to avoid using trait objects and to save runtime costs.
 
-use std::sync::Arc;
 use std::thread::JoinHandle;
 use std::{net::SocketAddr, time::Duration};
 
+use crate::bandwidth::SharedBandwidth;
+use crate::buffer_pool::SharedBufferPool;
 use crate::context::Context;
+use crate::dispatcher::MessageDispatcher;
 use crate::messages::MessagesHandler;
 use crate::peer_id::PeerId;
 use crate::{
-    config::PeerNetFeatures, error::PeerNetResult, network_manager::SharedActiveConnections,
+    config::{report, DiagnosticEvent, PeerNetFeatures},
+    error::{PeerNetError, PeerNetResult},
+    network_manager::SharedActiveConnections,
     peer::InitConnectionHandler,
 };
 
 use self::{endpoint::Endpoint, quic::QuicTransport, tcp::TcpTransport};
 
+mod encrypted;
 pub mod endpoint;
+#[cfg(feature = "testing")]
+mod mock;
+mod pacing;
 mod quic;
+mod reactor;
+mod relayed;
 mod tcp;
+mod tcp_io;
 
-use parking_lot::RwLock;
+use bytes::Bytes;
+pub use encrypted::{EncryptedEndpoint, AUTHENTICATION_TAG_SIZE, SESSION_KEY_SIZE};
+#[cfg(feature = "testing")]
+use mock::MockTransport;
+#[cfg(feature = "testing")]
+pub(crate) use mock::SharedLinkConditions;
+#[cfg(feature = "testing")]
+pub use mock::{LinkConditions, MemoryEndpoint, MockTransportConfig, MEMORY_DATA_CHANNEL_SIZE};
+pub(crate) use pacing::DialPacer;
 pub use quic::{QuicConnectionConfig, QuicTransportConfig};
+#[cfg(feature = "testing")]
+pub use reactor::decode_frames;
+pub(crate) use reactor::{on_event_loop, ReactorHandle, ReactorSlot, WriteNotifier};
+pub use relayed::RelayedEndpoint;
+pub(crate) use relayed::{RELAY_CLOSE, RELAY_DATA};
 use serde::{Deserialize, Serialize};
-pub use tcp::{TcpConnectionConfig, TcpEndpoint, TcpTransportConfig};
+pub(crate) use tcp::limiter_options;
+#[cfg(feature = "testing")]
+pub use tcp::{read_frames, FrameEvent};
+pub use tcp::{SharedRateLimit, TcpConnectionConfig, TcpEndpoint, TcpTransportConfig};
+pub use tcp_io::TcpIo;
 
-#[derive(Debug, PartialEq, Eq)]
+/// Pause of a listener after an error of its poll, not to spin on an error that lasts
+pub(crate) const LISTENER_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Log and report an error of the listener on `address`, that keeps running
+pub(crate) fn listener_error(
+    features: &PeerNetFeatures,
+    address: SocketAddr,
+    error: impl std::fmt::Display,
+) {
+    tracing::error!(%address, "listener error: {}", error);
+    report(&features.diagnostics, || DiagnosticEvent::ListenerError {
+        addr: address,
+        reason: error.to_string(),
+    });
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub enum TransportErrorType {
     Tcp(tcp::TcpError),
     Quic(quic::QuicError),
 }
 
+impl TransportErrorType {
+    /// See `PeerNetError::code`, 1xx for TCP and 2xx for QUIC
+    pub fn code(&self) -> u16 {
+        match self {
+            TransportErrorType::Tcp(err) => err.code(),
+            TransportErrorType::Quic(err) => err.code(),
+        }
+    }
+}
+
 /// Define the different transports available
 /// TODO: Maybe try to fusion with the InternalTransportType enum above
 #[derive(Hash, Eq, PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
@@ -38,6 +92,9 @@ pub enum TransportErrorType {
 pub enum TransportType {
     Tcp = 0,
     Quic = 1,
+    /// In-memory connections between the managers of the process, see `MockTransportConfig`
+    #[cfg(feature = "testing")]
+    Mock = 2,
 }
 
 impl TransportType {
@@ -46,6 +103,8 @@ impl TransportType {
         match config {
             TransportConfig::Tcp(_) => TransportType::Tcp,
             TransportConfig::Quic(_) => TransportType::Quic,
+            #[cfg(feature = "testing")]
+            TransportConfig::Mock(_) => TransportType::Mock,
         }
     }
 }
@@ -56,6 +115,8 @@ impl TransportType {
 pub(crate) enum InternalTransportType<Id: PeerId> {
     Tcp(TcpTransport<Id>),
     Quic(QuicTransport<Id>),
+    #[cfg(feature = "testing")]
+    Mock(MockTransport<Id>),
 }
 
 /// All configurations for out connection depending on the transport type
@@ -63,6 +124,8 @@ pub(crate) enum InternalTransportType<Id: PeerId> {
 pub enum TransportConfig {
     Tcp(Box<TcpTransportConfig>),
     Quic(Box<QuicTransportConfig>),
+    #[cfg(feature = "testing")]
+    Mock(Box<MockTransportConfig>),
 }
 
 impl From<TcpTransportConfig> for TransportConfig {
@@ -77,6 +140,13 @@ impl From<QuicTransportConfig> for TransportConfig {
     }
 }
 
+#[cfg(feature = "testing")]
+impl From<MockTransportConfig> for TransportConfig {
+    fn from(inner: MockTransportConfig) -> Self {
+        TransportConfig::Mock(Box::new(inner))
+    }
+}
+
 // impl From<<TcpTransport as Transport>::OutConnectionConfig> for OutConnectionConfig {
 //     fn from(inner: TcpConnectionConfig) -> Self {
 //         OutConnectionConfig::Tcp(Box::new(inner))
@@ -115,6 +185,10 @@ impl<Id: PeerId> Transport<Id> for InternalTransportType<Id> {
             InternalTransportType::Quic(transport) => {
                 transport.start_listener(context, address, message_handler, init_connection_handler)
             }
+            #[cfg(feature = "testing")]
+            InternalTransportType::Mock(transport) => {
+                transport.start_listener(context, address, message_handler, init_connection_handler)
+            }
         }
     }
 
@@ -145,6 +219,14 @@ impl<Id: PeerId> Transport<Id> for InternalTransportType<Id> {
                 message_handler,
                 init_connection_handler,
             ),
+            #[cfg(feature = "testing")]
+            InternalTransportType::Mock(transport) => transport.try_connect(
+                context,
+                address,
+                timeout,
+                message_handler,
+                init_connection_handler,
+            ),
         }
     }
 
@@ -152,6 +234,46 @@ impl<Id: PeerId> Transport<Id> for InternalTransportType<Id> {
         match self {
             InternalTransportType::Tcp(transport) => transport.stop_listener(address),
             InternalTransportType::Quic(transport) => transport.stop_listener(address),
+            #[cfg(feature = "testing")]
+            InternalTransportType::Mock(transport) => transport.stop_listener(address),
+        }
+    }
+
+    fn rebind_listener<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        old_address: SocketAddr,
+        new_address: SocketAddr,
+        message_handler: M,
+        init_connection_handler: I,
+    ) -> PeerNetResult<()> {
+        match self {
+            InternalTransportType::Tcp(transport) => transport.rebind_listener(
+                context,
+                old_address,
+                new_address,
+                message_handler,
+                init_connection_handler,
+            ),
+            InternalTransportType::Quic(transport) => transport.rebind_listener(
+                context,
+                old_address,
+                new_address,
+                message_handler,
+                init_connection_handler,
+            ),
+            #[cfg(feature = "testing")]
+            InternalTransportType::Mock(transport) => transport.rebind_listener(
+                context,
+                old_address,
+                new_address,
+                message_handler,
+                init_connection_handler,
+            ),
         }
     }
 
@@ -159,20 +281,28 @@ impl<Id: PeerId> Transport<Id> for InternalTransportType<Id> {
         match endpoint {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send(endpoint, data),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::send(endpoint, data),
+            Endpoint::Encrypted(endpoint) => endpoint.send::<Id>(data),
+            Endpoint::Relayed(endpoint) => endpoint.send(data),
+            Endpoint::Custom(endpoint) => endpoint.send(data),
             #[cfg(feature = "testing")]
-            Endpoint::MockEndpoint((sender, _, _)) => {
-                sender.send(data.to_vec()).unwrap();
-                Ok(())
-            }
+            Endpoint::MockEndpoint((sender, _, _)) => sender
+                .send(data.to_vec())
+                .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None)),
         }
     }
 
-    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Vec<u8>> {
+    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Bytes> {
         match endpoint {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::receive(endpoint),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::receive(endpoint),
+            Endpoint::Encrypted(endpoint) => endpoint.receive::<Id>(),
+            Endpoint::Relayed(endpoint) => endpoint.receive(),
+            Endpoint::Custom(endpoint) => endpoint.receive(),
             #[cfg(feature = "testing")]
-            Endpoint::MockEndpoint((_, receiver, _)) => Ok(receiver.recv().unwrap()),
+            Endpoint::MockEndpoint((_, receiver, _)) => receiver
+                .recv()
+                .map(Bytes::from)
+                .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None)),
         }
     }
 
@@ -184,9 +314,30 @@ impl<Id: PeerId> Transport<Id> for InternalTransportType<Id> {
         match endpoint {
             Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send_timeout(endpoint, data, timeout),
             Endpoint::Quic(endpoint) => QuicTransport::<Id>::send_timeout(endpoint, data, timeout),
+            Endpoint::Encrypted(endpoint) => endpoint.send_timeout::<Id>(data, timeout),
+            Endpoint::Relayed(endpoint) => endpoint.send_timeout(data, timeout),
+            Endpoint::Custom(endpoint) => endpoint.send_timeout(data, timeout),
+            #[cfg(feature = "testing")]
+            Endpoint::MockEndpoint((sender, _, _)) => sender
+                .send(data.to_vec())
+                .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None)),
+        }
+    }
+
+    fn send_batch(endpoint: &mut Self::Endpoint, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        match endpoint {
+            Endpoint::Tcp(endpoint) => TcpTransport::<Id>::send_batch(endpoint, data),
+            Endpoint::Quic(endpoint) => QuicTransport::<Id>::send_batch(endpoint, data),
+            Endpoint::Encrypted(endpoint) => endpoint.send_batch::<Id>(data),
+            Endpoint::Relayed(endpoint) => endpoint.send_batch(data),
+            Endpoint::Custom(endpoint) => endpoint.send_batch(data),
             #[cfg(feature = "testing")]
             Endpoint::MockEndpoint((sender, _, _)) => {
-                sender.send(data.to_vec()).unwrap();
+                for message in data {
+                    sender
+                        .send(message.clone())
+                        .map_err(|err| PeerNetError::ReceiveError.new("MockEndpoint", err, None))?;
+                }
                 Ok(())
             }
         }
@@ -200,9 +351,9 @@ impl<Id: PeerId> InternalTransportType<Id> {
         active_connections: SharedActiveConnections<Id>,
         config: TransportConfig,
         features: PeerNetFeatures,
-        local_addr: SocketAddr,
-        total_bytes_received: Arc<RwLock<u64>>,
-        total_bytes_sent: Arc<RwLock<u64>>,
+        total_bandwidth: SharedBandwidth,
+        buffer_pool: SharedBufferPool,
+        dispatcher: Option<MessageDispatcher<Id>>,
     ) -> Self {
         match (transport_type, config) {
             (TransportType::Tcp, TransportConfig::Tcp(config)) => {
@@ -210,24 +361,62 @@ impl<Id: PeerId> InternalTransportType<Id> {
                     active_connections,
                     *config,
                     features,
-                    total_bytes_received,
-                    total_bytes_sent,
+                    total_bandwidth,
+                    buffer_pool,
+                    dispatcher,
                 ))
             }
-            //TODO: Use config
-            (TransportType::Quic, TransportConfig::Quic(_config)) => {
+            (TransportType::Quic, TransportConfig::Quic(config)) => {
                 InternalTransportType::Quic(QuicTransport::new(
                     active_connections,
+                    *config,
+                    features,
+                    total_bandwidth,
+                    buffer_pool,
+                    dispatcher,
+                ))
+            }
+            #[cfg(feature = "testing")]
+            (TransportType::Mock, TransportConfig::Mock(config)) => {
+                InternalTransportType::Mock(MockTransport::new(
+                    active_connections,
+                    *config,
                     features,
-                    0,
-                    local_addr,
-                    total_bytes_received,
-                    total_bytes_sent,
+                    total_bandwidth,
+                    buffer_pool,
+                    dispatcher,
                 ))
             }
             _ => panic!("Wrong transport type"),
         }
     }
+
+    /// Configuration of the connections established from now on, see
+    /// `PeerNetManager::replace_transport_config`
+    pub(crate) fn replace_config(&mut self, config: TransportConfig) -> PeerNetResult<()> {
+        match (self, config) {
+            (InternalTransportType::Tcp(transport), TransportConfig::Tcp(config)) => {
+                transport.replace_config(*config)
+            }
+            (InternalTransportType::Quic(transport), TransportConfig::Quic(config)) => {
+                transport.replace_config(*config)
+            }
+            #[cfg(feature = "testing")]
+            (InternalTransportType::Mock(transport), TransportConfig::Mock(config)) => {
+                transport.replace_config(*config)
+            }
+            (_, config) => {
+                return Err(PeerNetError::WrongConfigType.error(
+                    "replace transport config",
+                    Some(format!(
+                        "{:?}",
+                        TransportType::from_transport_config(&config)
+                    )),
+                ))
+            }
+        }
+        Ok(())
+    }
 }
 
 /// This trait is used to abstract the transport layer
@@ -258,13 +447,44 @@ pub trait Transport<Id: PeerId> {
         message_handler: M,
         init_connection_handler: I,
     ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>>;
-    /// Stop a listener of a given address
+    /// Stop a listener of a given address, and the peers it accepted. The other connections are
+    /// left open.
     fn stop_listener(&mut self, address: SocketAddr) -> PeerNetResult<()>;
+    /// Start a listener on `new_address` before stopping the one of `old_address`, so that we
+    /// can be dialed at all times. By default the peers of the old listener are disconnected.
+    fn rebind_listener<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        old_address: SocketAddr,
+        new_address: SocketAddr,
+        message_handler: M,
+        init_connection_handler: I,
+    ) -> PeerNetResult<()> {
+        self.start_listener(
+            context,
+            new_address,
+            message_handler,
+            init_connection_handler,
+        )?;
+        self.stop_listener(old_address)
+    }
     fn send(endpoint: &mut Self::Endpoint, data: &[u8]) -> PeerNetResult<()>;
     fn send_timeout(
         endpoint: &mut Self::Endpoint,
         data: &[u8],
         timeout: Duration,
     ) -> PeerNetResult<()>;
-    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Vec<u8>>;
+    /// Send several messages at once. Transports that can write all the frames
+    /// in a single operation should override it.
+    fn send_batch(endpoint: &mut Self::Endpoint, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        for message in data {
+            Self::send(endpoint, message)?;
+        }
+        Ok(())
+    }
+    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Bytes>;
 }
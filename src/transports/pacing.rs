@@ -0,0 +1,44 @@
+//! Pace of the dials, see `PeerNetFeatures::dial_pacing`
+//!
+//! Each dial reserves the next start time when it's requested, its thread waits until then on
+//! the `Clock` of the manager before opening the connection. A TCP dial is in the
+//! `out_connection_queue` from its request, its wait included. The first `burst` dials start at
+//! once, then one every `1 / max_dials_per_sec`, so that a long bootstrap list doesn't send a
+//! burst of connection attempts to the network.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::DialPacing;
+
+#[derive(Debug)]
+pub(crate) struct DialPacer {
+    interval: Duration,
+    // how far ahead of the pace the dials can start
+    tolerance: Duration,
+    // start time of the next dial at the pace, without the burst
+    next: Mutex<Option<Instant>>,
+}
+
+impl DialPacer {
+    pub(crate) fn new(pacing: DialPacing) -> DialPacer {
+        let interval = Duration::from_secs(1) / pacing.max_dials_per_sec.max(1);
+        DialPacer {
+            interval,
+            tolerance: interval * pacing.burst.saturating_sub(1),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Reserve the start of a dial requested at `now`, returns how long it must wait
+    pub(crate) fn reserve(&self, now: Instant) -> Duration {
+        let mut next = self.next.lock();
+        let at_pace = next.map_or(now, |next| next.max(now));
+        *next = Some(at_pace + self.interval);
+        at_pace
+            .checked_sub(self.tolerance)
+            .unwrap_or(now)
+            .saturating_duration_since(now)
+    }
+}
@@ -1,35 +1,44 @@
 use std::{
     collections::HashMap,
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     sync::Arc,
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
 use crate::{
-    config::PeerNetCategoryInfo, context::Context, messages::MessagesHandler,
-    peer::PeerConnectionType, peer_id::PeerId,
+    config::{PeerNetCategories, PeerNetCategoryInfo, QuicCertificate, SniRoute},
+    context::Context,
+    messages::MessagesHandler,
+    peer::PeerConnectionType,
+    peer_id::PeerId,
 };
+use bytes::Bytes;
 use crossbeam::{channel, sync::WaitGroup};
 use mio::{net::UdpSocket as MioUdpSocket, Events, Interest, Poll, Token, Waker};
 use parking_lot::RwLock;
+use serde::Serialize;
 
 use crate::{
+    audit::{audit, AuditOutcome, AuditRecord},
+    bandwidth::{Bandwidth, BandwidthRates, BandwidthSnapshot, SharedBandwidth},
+    buffer_pool::SharedBufferPool,
     config::PeerNetFeatures,
+    dispatcher::MessageDispatcher,
     error::{PeerNetError, PeerNetResult},
     network_manager::SharedActiveConnections,
     peer::{new_peer, InitConnectionHandler},
     transports::{Endpoint, TransportErrorType},
 };
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::channel::{never, unbounded, Receiver};
 
-use super::Transport;
+use super::{listener_error, Transport, LISTENER_ERROR_DELAY};
 
 const NEW_PACKET_SERVER: Token = Token(0);
 const STOP_LISTENER: Token = Token(10);
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub enum QuicError {
     InitListener,
     StopListener,
@@ -43,6 +52,17 @@ impl QuicError {
     fn wrap(self) -> PeerNetError {
         PeerNetError::TransportError(TransportErrorType::Quic(self))
     }
+
+    pub(crate) fn code(&self) -> u16 {
+        match self {
+            QuicError::InitListener => 200,
+            QuicError::StopListener => 201,
+            QuicError::SocketConfig => 202,
+            QuicError::QuicheConfig => 203,
+            QuicError::ConnectionError => 204,
+            QuicError::InternalFail => 205,
+        }
+    }
 }
 
 type QuicConnection = (
@@ -52,20 +72,26 @@ type QuicConnection = (
     bool,
 );
 type QuicConnectionsMap = Arc<RwLock<HashMap<SocketAddr, QuicConnection>>>;
+// the receiver is disconnected when the listener stops, to stop the peers using its socket
+type QuicListener = (
+    Waker,
+    UdpSocket,
+    Receiver<()>,
+    JoinHandle<PeerNetResult<()>>,
+);
 
 pub(crate) struct QuicTransport<Id: PeerId> {
     pub active_connections: SharedActiveConnections<Id>,
     //pub fallback_function: Option<&'static FallbackFunction>,
     pub out_connection_attempts: WaitGroup,
-    pub listeners: HashMap<SocketAddr, (Waker, UdpSocket, JoinHandle<PeerNetResult<()>>)>,
+    pub listeners: HashMap<SocketAddr, QuicListener>,
     //(quiche::Connection, data_receiver, data_sender, is_established)
     pub connections: QuicConnectionsMap,
-    _features: PeerNetFeatures,
-    stop_peer_tx: Sender<()>,
-    stop_peer_rx: Receiver<()>,
+    features: PeerNetFeatures,
     config: QuicTransportConfig,
-    total_bytes_received: Arc<RwLock<u64>>,
-    total_bytes_sent: Arc<RwLock<u64>>,
+    total_bandwidth: SharedBandwidth,
+    buffer_pool: SharedBufferPool,
+    dispatcher: Option<MessageDispatcher<Id>>,
 }
 
 pub(crate) enum QuicInternalMessage {
@@ -78,10 +104,8 @@ pub struct QuicEndpoint {
     pub(crate) data_sender: channel::Sender<QuicInternalMessage>,
     pub(crate) data_receiver: channel::Receiver<QuicInternalMessage>,
     pub address: SocketAddr,
-    total_bytes_received: Arc<RwLock<u64>>,
-    total_bytes_sent: Arc<RwLock<u64>>,
-    endpoint_bytes_received: Arc<RwLock<u64>>,
-    endpoint_bytes_sent: Arc<RwLock<u64>>,
+    total_bandwidth: SharedBandwidth,
+    endpoint_bandwidth: SharedBandwidth,
 }
 
 impl QuicEndpoint {
@@ -92,11 +116,31 @@ impl QuicEndpoint {
     }
 
     pub fn get_bytes_received(&self) -> u64 {
-        *self.endpoint_bytes_received.read()
+        self.endpoint_bandwidth.snapshot().bytes_received
     }
 
     pub fn get_bytes_sent(&self) -> u64 {
-        *self.endpoint_bytes_sent.read()
+        self.endpoint_bandwidth.snapshot().bytes_sent
+    }
+
+    pub fn get_bandwidth(&self) -> BandwidthSnapshot {
+        self.endpoint_bandwidth.snapshot()
+    }
+
+    pub fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        self.endpoint_bandwidth.delta()
+    }
+
+    pub fn reset_counters(&self) -> BandwidthSnapshot {
+        self.endpoint_bandwidth.reset()
+    }
+
+    pub fn get_total_bandwidth(&self) -> BandwidthSnapshot {
+        self.endpoint_bandwidth.total()
+    }
+
+    pub fn get_rates(&self) -> BandwidthRates {
+        self.endpoint_bandwidth.rates()
     }
 }
 
@@ -108,37 +152,137 @@ pub struct QuicConnectionConfig {
 
 #[derive(Clone, Debug)]
 pub struct QuicTransportConfig {
+    pub max_in_connections: Option<usize>,
     pub connection_config: QuicConnectionConfig,
+    pub peer_categories: PeerNetCategories,
+    /// See `QuicSettings::certificate`
+    pub certificate: QuicCertificate,
+    /// See `QuicSettings::server_name`
+    pub server_name: Option<String>,
+    /// See `QuicSettings::sni_routes`
+    pub sni_routes: Vec<SniRoute>,
+}
+
+/// Configuration of the connections accepted by a listener, presenting `certificate`
+fn listener_quiche_config(certificate: &QuicCertificate) -> PeerNetResult<quiche::Config> {
+    let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(|err| {
+        QuicError::QuicheConfig.wrap().new(
+            "new from protocol",
+            err,
+            Some(format!("version: {:?}", quiche::PROTOCOL_VERSION)),
+        )
+    })?;
+    config.set_max_recv_udp_payload_size(1200);
+    // Create certificate from ed25519 as made in libp2p tls
+    config
+        .load_cert_chain_from_pem_file(&certificate.cert_chain_path)
+        .map_err(|err| {
+            QuicError::QuicheConfig.wrap().new(
+                "load_cert_chain",
+                err,
+                Some(format!("path: {}", certificate.cert_chain_path)),
+            )
+        })?;
+    config
+        .load_priv_key_from_pem_file(&certificate.priv_key_path)
+        .map_err(|err| {
+            QuicError::QuicheConfig.wrap().new(
+                "load_priv_key",
+                err,
+                Some(format!("path: {}", certificate.priv_key_path)),
+            )
+        })?;
+    config
+        .set_application_protos(&[b"massa/1.0"])
+        .map_err(|err| {
+            QuicError::QuicheConfig
+                .wrap()
+                .new("cfg set_protocol", err, None)
+        })?;
+    config.enable_dgram(true, 10, 10);
+    Ok(config)
+}
+
+/// Accept the connection of the first packet `packet` with `config`
+fn accept_connection(
+    scid: &quiche::ConnectionId,
+    address: SocketAddr,
+    from_addr: SocketAddr,
+    packet: &[u8],
+    config: &mut quiche::Config,
+) -> PeerNetResult<quiche::Connection> {
+    let mut connection = quiche::accept(scid, None, address, from_addr, config).map_err(|err| {
+        QuicError::ConnectionError.wrap().new(
+            "accept",
+            err,
+            Some(format!("address: {}, from_addr: {}", address, from_addr)),
+        )
+    })?;
+    let recv_info = quiche::RecvInfo {
+        from: from_addr,
+        to: address,
+    };
+    // decrypted in place, the packet is kept to be accepted again with another certificate
+    connection
+        .recv(&mut packet.to_vec(), recv_info)
+        .map_err(|err| {
+            QuicError::ConnectionError.wrap().new(
+                "recv",
+                err,
+                Some(format!("RecvInfo: from: {}, to: {}", from_addr, address)),
+            )
+        })?;
+    Ok(connection)
 }
 
 impl<Id: PeerId> QuicTransport<Id> {
     pub fn new(
         active_connections: SharedActiveConnections<Id>,
+        config: QuicTransportConfig,
         features: PeerNetFeatures,
-        data_channel_size: usize,
-        local_addr: SocketAddr,
-        total_bytes_received: Arc<RwLock<u64>>,
-        total_bytes_sent: Arc<RwLock<u64>>,
+        total_bandwidth: SharedBandwidth,
+        buffer_pool: SharedBufferPool,
+        dispatcher: Option<MessageDispatcher<Id>>,
     ) -> QuicTransport<Id> {
-        let (stop_peer_tx, stop_peer_rx) = unbounded();
         QuicTransport {
             out_connection_attempts: WaitGroup::new(),
             listeners: Default::default(),
             connections: Arc::new(RwLock::new(HashMap::new())),
             active_connections,
-            _features: features,
-            stop_peer_tx,
-            stop_peer_rx,
-            config: QuicTransportConfig {
-                connection_config: QuicConnectionConfig {
-                    local_addr,
-                    data_channel_size,
-                },
-            },
-            total_bytes_received,
-            total_bytes_sent,
+            features,
+            config,
+            total_bandwidth,
+            buffer_pool,
+            dispatcher,
         }
     }
+
+    /// Give `config` to the connections dialed from now on and to the listeners started from now
+    /// on, the running listeners keep their certificates and categories
+    pub(crate) fn replace_config(&mut self, config: QuicTransportConfig) {
+        self.config = config;
+    }
+
+    /// Address of the socket of the out connection to `target`, see `QuicSettings::local_addr`.
+    /// The listener of the lowest address is taken if several of them can be used, the socket
+    /// of an ephemeral port of the unspecified address of the IP version of `target` if none.
+    fn out_local_addr(&self, target: &SocketAddr) -> SocketAddr {
+        let local_addr = self.config.connection_config.local_addr;
+        if self.listeners.contains_key(&local_addr) {
+            return local_addr;
+        }
+        let unspecified = match target.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        // the sockets started by the dials have the port 0, they come after the listeners
+        self.listeners
+            .keys()
+            .filter(|addr| addr.is_ipv4() == target.is_ipv4())
+            .min_by_key(|addr| (addr.port() == 0, **addr))
+            .copied()
+            .unwrap_or(SocketAddr::new(unspecified, 0))
+    }
 }
 
 impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
@@ -164,74 +308,77 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
         let waker = Waker::new(poll.registry(), STOP_LISTENER)
             .map_err(|err| QuicError::InitListener.wrap().new("init waker", err, None))?;
         let connections = self.connections.clone();
-        let server = UdpSocket::bind(address)
-            .unwrap_or_else(|_| panic!("Can't bind QUIC transport to address {}", address));
+        let server = UdpSocket::bind(address).map_err(|err| {
+            QuicError::InitListener
+                .wrap()
+                .new("bind", err, Some(format!("address: {}", address)))
+        })?;
         server.set_nonblocking(false).map_err(|err| {
             QuicError::InitListener
                 .wrap()
                 .new("server set nonblocking", err, None)
         })?;
-        let mut config = quiche::Config::new(quiche::PROTOCOL_VERSION).map_err(|err| {
-            QuicError::QuicheConfig.wrap().new(
-                "new from protocol",
-                err,
-                Some(format!("version: {:?}", quiche::PROTOCOL_VERSION)),
-            )
-        })?;
-        config.set_max_recv_udp_payload_size(1200);
-        // Create certificate from ed25519 as made in libp2p tls
-        config
-            .load_cert_chain_from_pem_file("./src/cert.crt")
-            .map_err(|err| {
-                QuicError::QuicheConfig
-                    .wrap()
-                    .new("load_cert_chain", err, None)
-            })?;
-        config
-            .load_priv_key_from_pem_file("./src/cert.key")
-            .map_err(|err| {
-                QuicError::QuicheConfig
-                    .wrap()
-                    .new("load_priv_key", err, None)
-            })?;
-        config
-            .set_application_protos(&[b"massa/1.0"])
+        let mut socket = MioUdpSocket::from_std(server.try_clone().map_err(|err| {
+            QuicError::InitListener
+                .wrap()
+                .new("server try_clone", err, None)
+        })?);
+        // Start listening for incoming connections.
+        poll.registry()
+            .register(&mut socket, NEW_PACKET_SERVER, Interest::READABLE)
             .map_err(|err| {
-                QuicError::QuicheConfig
-                    .wrap()
-                    .new("cfg set_protocol", err, None)
+                QuicError::InitListener.wrap().new(
+                    "register listener",
+                    err,
+                    Some(format!("address: {}", address)),
+                )
             })?;
-        config.enable_dgram(true, 10, 10);
-
-        let listener_handle: JoinHandle<PeerNetResult<()>> = std::thread::Builder::new()
-            .name(format!("quic_listener_handle_{:?}", address))
+        let mut config = listener_quiche_config(&self.config.certificate)?;
+        // the routes presenting their own certificate have their own config
+        let mut sni_routes = self
+            .config
+            .sni_routes
+            .iter()
+            .map(|route| {
+                let config = route
+                    .certificate
+                    .as_ref()
+                    .map(listener_quiche_config)
+                    .transpose()?;
+                Ok((route.clone(), config))
+            })
+            .collect::<PeerNetResult<Vec<(SniRoute, Option<quiche::Config>)>>>()?;
+        let peer_categories = self.config.peer_categories.clone();
+        let max_in_connections = self.config.max_in_connections;
+
+        // dropped when the listener stops, which stops the peers using its socket only
+        let (stop_peer_tx, stop_peer_rx) = unbounded::<()>();
+        let listener_handle: JoinHandle<PeerNetResult<()>> = self
+            .features
+            .reporting_builder(
+                format!("quic_listener_handle_{:?}", address),
+                "quic listener",
+            )
             .spawn({
                 let active_connections = self.active_connections.clone();
-                let total_bytes_received = self.total_bytes_received.clone();
-                let total_bytes_sent = self.total_bytes_sent.clone();
-                let server = server.try_clone().unwrap();
-                let stop_peer_rx = self.stop_peer_rx.clone();
-                let stop_peer_tx = self.stop_peer_tx.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
+                let stop_peer_rx = stop_peer_rx.clone();
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
 
                 move || {
-                    let mut socket = MioUdpSocket::from_std(server);
-                    // Start listening for incoming connections.
-                    poll.registry()
-                        .register(&mut socket, NEW_PACKET_SERVER, Interest::READABLE)
-                        .unwrap_or_else(|_| {
-                            panic!(
-                                "Can't register polling on QUIC transport of address {}",
-                                address
-                            )
-                        });
                     let mut buf = [0; 65507];
                     loop {
                         // Poll Mio for events, blocking until we get an event.
                         //TODO: Configurable timeout (cf. https://github.com/cloudflare/quiche/blob/master/apps/src/bin/quiche-server.rs#L177)
-                        poll.poll(&mut events, Some(Duration::from_millis(100)))
-                            .unwrap_or_else(|_| {
-                                panic!("Can't poll QUIC transport of address {}", address)
-                            });
+                        if let Err(err) = poll.poll(&mut events, Some(Duration::from_millis(100))) {
+                            if err.kind() != std::io::ErrorKind::Interrupted {
+                                listener_error(&features, address, err);
+                                std::thread::sleep(LISTENER_ERROR_DELAY);
+                            }
+                            continue;
+                        }
 
                         // Process each event.
                         for event in events.iter() {
@@ -245,15 +392,17 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                             Err(e) => {
                                                 // There are no more UDP packets to read, so end the read
                                                 // loop.
-                                                if e.kind() == std::io::ErrorKind::WouldBlock {
-                                                    break 'read;
+                                                if e.kind() != std::io::ErrorKind::WouldBlock {
+                                                    listener_error(&features, address, e);
                                                 }
-                                                panic!("recv() failed: {:?}", e);
+                                                break 'read;
                                             }
                                         };
-                                        println!(
-                                            "server {}: Received {} bytes from {} ",
-                                            address, num_recv, from_addr
+                                        tracing::trace!(
+                                            %address,
+                                            from = %from_addr,
+                                            "received {} bytes",
+                                            num_recv
                                         );
                                         // Parse the QUIC packet's header.
                                         let hdr = match quiche::Header::from_slice(
@@ -263,8 +412,13 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                             Ok(v) => v,
 
                                             Err(e) => {
-                                                println!("Parsing packet header failed: {:?}", e);
-                                                panic!("Parsing packet header failed: {:?}", e)
+                                                tracing::debug!(
+                                                    %address,
+                                                    from = %from_addr,
+                                                    "parsing packet header failed: {:?}",
+                                                    e
+                                                );
+                                                continue;
                                             }
                                         };
                                         let new_connection = {
@@ -272,32 +426,108 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                             !connections.contains_key(&from_addr)
                                         };
                                         if new_connection {
-                                            println!(
-                                                "server {}: New connection {}",
-                                                address, from_addr
+                                            tracing::debug!(
+                                                %address,
+                                                from = %from_addr,
+                                                "new connection"
                                             );
                                             if hdr.ty != quiche::Type::Initial {
-                                                println!("Packet is not Initial");
+                                                tracing::debug!(
+                                                    %address,
+                                                    from = %from_addr,
+                                                    "packet is not Initial"
+                                                );
                                                 continue;
                                             }
 
-                                            let connection = quiche::accept(
-                                                &hdr.scid,
-                                                None,
+                                            let trusted = active_connections
+                                                .read()
+                                                .trusted_peers
+                                                .is_trusted(None, &from_addr);
+                                            // the slot is held until the end of the handshake
+                                            let reservation = if trusted {
+                                                active_connections.read().reserve_in()
+                                            } else {
+                                                match active_connections.write().reserve_incoming(
+                                                    &from_addr,
+                                                    max_in_connections,
+                                                    features.eviction_policy.as_ref(),
+                                                ) {
+                                                    Some(reservation) => reservation,
+                                                    None => continue,
+                                                }
+                                            };
+                                            let scid = hdr.scid.clone().into_owned();
+                                            let packet = &buf[..num_recv];
+                                            let mut connection = match accept_connection(
+                                                &scid,
                                                 address,
                                                 from_addr,
+                                                packet,
                                                 &mut config,
-                                            )
-                                            .map_err(|err| {
-                                                QuicError::ConnectionError.wrap().new(
-                                                    "accept",
-                                                    err,
-                                                    Some(format!(
-                                                        "address: {}, from_addr: {}",
-                                                        address, from_addr
-                                                    )),
-                                                )
-                                            })?;
+                                            ) {
+                                                Ok(connection) => connection,
+                                                Err(err) => {
+                                                    listener_error(&features, address, err);
+                                                    continue;
+                                                }
+                                            };
+                                            // the server name is known once the first packet
+                                            // is read, the connection is accepted again if
+                                            // its route has another certificate
+                                            let route =
+                                                connection.server_name().and_then(|server_name| {
+                                                    sni_routes.iter().position(|(route, _)| {
+                                                        route
+                                                            .server_name
+                                                            .eq_ignore_ascii_case(server_name)
+                                                    })
+                                                });
+                                            if let Some((route, Some(route_config))) =
+                                                route.map(|index| &mut sni_routes[index])
+                                            {
+                                                tracing::debug!(
+                                                    %address,
+                                                    from = %from_addr,
+                                                    server_name = %route.server_name,
+                                                    "SNI route certificate"
+                                                );
+                                                connection = match accept_connection(
+                                                    &scid,
+                                                    address,
+                                                    from_addr,
+                                                    packet,
+                                                    route_config,
+                                                ) {
+                                                    Ok(connection) => connection,
+                                                    Err(err) => {
+                                                        listener_error(&features, address, err);
+                                                        continue;
+                                                    }
+                                                };
+                                            }
+                                            let (category_name, category_info) = match route
+                                                .and_then(|index| {
+                                                    sni_routes[index].0.category.as_ref()
+                                                })
+                                                .and_then(|category| {
+                                                    peer_categories.get_key_value(category)
+                                                }) {
+                                                Some((category_name, info)) => {
+                                                    (Some(category_name.clone()), info.1)
+                                                }
+                                                None => (
+                                                    Some(String::from("quic")),
+                                                    PeerNetCategoryInfo {
+                                                        max_in_connections_per_ip: Some(0),
+                                                        max_in_connections: Some(0),
+                                                        max_out_connections: Some(0),
+                                                        max_out_connections_per_ip: Some(0),
+                                                        read_timeout: None,
+                                                        write_timeout: None,
+                                                    },
+                                                ),
+                                            };
 
                                             //TODO: Make filter connection quic
                                             let (send_tx, send_rx) = channel::bounded(10000);
@@ -316,26 +546,24 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                                     data_receiver: recv_rx,
                                                     data_sender: send_tx,
                                                     address,
-                                                    total_bytes_received: total_bytes_received
-                                                        .clone(),
-                                                    total_bytes_sent: total_bytes_sent.clone(),
-                                                    endpoint_bytes_received: Arc::new(RwLock::new(
-                                                        0,
-                                                    )),
-                                                    endpoint_bytes_sent: Arc::new(RwLock::new(0)),
+                                                    total_bandwidth: total_bandwidth.clone(),
+                                                    endpoint_bandwidth: Bandwidth::new_shared(),
                                                 }),
                                                 init_connection_handler.clone(),
                                                 message_handler.clone(),
                                                 active_connections.clone(),
                                                 stop_peer_rx.clone(),
                                                 PeerConnectionType::IN,
-                                                Some(String::from("quic")),
-                                                PeerNetCategoryInfo {
-                                                    max_in_connections_per_ip: 0,
-                                                    max_in_connections: 0,
-                                                    max_out_connections: 0,
-                                                },
+                                                category_name,
+                                                category_info,
+                                                features.clone(),
+                                                buffer_pool.clone(),
+                                                dispatcher.clone(),
+                                                None,
+                                                Some(reservation),
                                             );
+                                            // the first packet is read by the accept
+                                            continue;
                                         }
                                         {
                                             let mut connections = connections.write();
@@ -346,7 +574,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                                 from: from_addr,
                                                 to: address,
                                             };
-                                            connection
+                                            if let Err(err) = connection
                                                 .recv(&mut buf[..num_recv], recv_info)
                                                 .map_err(|err| {
                                                     QuicError::ConnectionError.wrap().new(
@@ -357,30 +585,37 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                                             from_addr, address
                                                         )),
                                                     )
-                                                })?;
+                                                })
+                                            {
+                                                listener_error(&features, address, err);
+                                                continue;
+                                            }
                                             if *is_established {
                                                 let mut dgram_buf = [0; 512];
                                                 while let Ok(len) =
                                                     connection.dgram_recv(&mut dgram_buf)
                                                 {
-                                                    sender
+                                                    // the peer is gone
+                                                    if sender
                                                         .send(QuicInternalMessage::Data(
                                                             dgram_buf[..len].to_vec(),
                                                         ))
-                                                        .map_err(|err| {
-                                                            QuicError::InternalFail.wrap().new(
-                                                                "send internal msg",
-                                                                err,
-                                                                None,
-                                                            )
-                                                        })?;
+                                                        .is_err()
+                                                    {
+                                                        tracing::debug!(
+                                                            %address,
+                                                            from = %from_addr,
+                                                            "connection closed"
+                                                        );
+                                                        break;
+                                                    }
                                                 }
                                             }
                                         }
                                     }
                                 }
                                 STOP_LISTENER => {
-                                    stop_peer_tx.send(()).unwrap();
+                                    drop(stop_peer_tx);
                                     return Ok(());
                                 }
                                 // We don't expect any events with tokens other than those we provided. (from mio doc)
@@ -397,7 +632,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                 connections.iter_mut()
                             {
                                 if !*is_established && connection.is_established() {
-                                    println!("server {}: Connection established", address);
+                                    tracing::debug!(%address, "connection established");
                                     *is_established = true;
                                 }
                                 if *is_established {
@@ -408,7 +643,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                                 let _ = connection.dgram_send(&data);
                                             }
                                             QuicInternalMessage::Shutdown => {
-                                                println!("server {}: Connection closed", address);
+                                                tracing::debug!(%address, "connection closed");
                                                 //TODO: Close
                                                 //connection.close(app, err, reason)
                                                 break;
@@ -426,25 +661,21 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                         }
 
                                         Err(e) => {
-                                            println!("server {}: send failed: {:?}", address, e);
+                                            tracing::warn!(%address, "send failed: {:?}", e);
                                             // An error occurred, handle it.
                                             break;
                                         }
                                     };
-                                    println!(
-                                        "server {}: Sending {} bytes to {} ",
-                                        address, write, send_info.to
+                                    tracing::trace!(
+                                        %address,
+                                        to = %send_info.to,
+                                        "sending {} bytes",
+                                        write
                                     );
-                                    socket.send_to(&buf[..write], send_info.to).map_err(|err| {
-                                        QuicError::ConnectionError.wrap().new(
-                                            "listener send_to",
-                                            err,
-                                            Some(format!(
-                                                "from {}, to {}, {} bytes",
-                                                address, send_info.to, write
-                                            )),
-                                        )
-                                    })?;
+                                    if let Err(err) = socket.send_to(&buf[..write], send_info.to) {
+                                        tracing::warn!(%address, "send_to failed: {:?}", err);
+                                        break;
+                                    }
                                 }
                             }
                         }
@@ -452,7 +683,8 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                 }
             })
             .expect("Failed to spawn thread quic_listener_handle");
-        {
+        // a socket of the dials is on an ephemeral port, not a listener to announce to the peers
+        if address.port() != 0 {
             let mut active_connections = self.active_connections.write();
             active_connections
                 .listeners
@@ -460,7 +692,12 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
         }
         self.listeners.insert(
             address,
-            (waker, server.try_clone().unwrap(), listener_handle),
+            (
+                waker,
+                server.try_clone().unwrap(),
+                stop_peer_rx,
+                listener_handle,
+            ),
         );
         Ok(())
     }
@@ -477,40 +714,52 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
         message_handler: M,
         init_connection_handler: I,
     ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
-        let stop_peer_rx = self.stop_peer_rx.clone();
         //TODO: Use timeout
-        let config = self.config.clone();
-        let (_, socket, _) = if self
-            .listeners
-            .contains_key(&config.connection_config.local_addr)
-        {
-            self.listeners
-                .get(&config.connection_config.local_addr)
-                .expect("Listener not found")
+        let local_addr = self.out_local_addr(&address);
+        let (_, socket, stop_peer_rx, _) = if self.listeners.contains_key(&local_addr) {
+            self.listeners.get(&local_addr).expect("Listener not found")
         } else {
             self.start_listener(
                 self_keypair.clone(),
-                config.connection_config.local_addr,
+                local_addr,
                 message_handler.clone(),
                 init_connection_handler.clone(),
             )?;
             //TODO: Make things more elegant with waker etc
             std::thread::sleep(Duration::from_millis(100));
-            self.listeners
-                .get(&config.connection_config.local_addr)
-                .expect("Listener not found")
+            self.listeners.get(&local_addr).expect("Listener not found")
         };
         let socket = socket.try_clone().unwrap();
-        let connection_handler: JoinHandle<PeerNetResult<()>> = std::thread::Builder::new()
-            .name(format!("quic_try_connect_{:?}", address))
+        let stop_peer_rx = stop_peer_rx.clone();
+        let (dial_delay, clock) = {
+            let active_connections = self.active_connections.read();
+            (
+                active_connections.dial_delay(),
+                active_connections.clock.clone(),
+            )
+        };
+        let connection_handler: JoinHandle<PeerNetResult<()>> = self
+            .features
+            .reporting_builder(
+                format!("quic_try_connect_{:?}", address),
+                "quic try_connect",
+            )
             .spawn({
                 let active_connections = self.active_connections.clone();
-                let total_bytes_received = self.total_bytes_received.clone();
-                let total_bytes_sent = self.total_bytes_sent.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
                 let wg = self.out_connection_attempts.clone();
-                move || {
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
+                let server_name = self.config.server_name.clone();
+                let dial_audit = features.audit.clone();
+                let dial = move || {
+                    if !dial_delay.is_zero() {
+                        tracing::debug!(%address, delay = ?dial_delay, "dial paced");
+                        clock.wait(&never(), dial_delay);
+                    }
                     let mut out = [0; 65507];
-                    println!("Connecting to {}", address);
+                    tracing::debug!(%address, transport = "quic", "connecting");
                     //TODO: Use configs for quiche passed from config object.
                     //and error handling
                     let mut quiche_config = quiche::Config::new(quiche::PROTOCOL_VERSION)
@@ -527,9 +776,9 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                     let scid = [0; quiche::MAX_CONN_ID_LEN];
                     let scid = quiche::ConnectionId::from_ref(&scid);
                     let mut conn = quiche::connect(
-                        None,
+                        server_name.as_deref(),
                         &scid,
-                        config.connection_config.local_addr,
+                        local_addr,
                         address,
                         &mut quiche_config,
                     )
@@ -537,10 +786,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                         QuicError::ConnectionError.wrap().new(
                             "try_connect connect",
                             err,
-                            Some(format!(
-                                "local_addr: {:?}, addr: {:?}",
-                                config.connection_config.local_addr, address
-                            )),
+                            Some(format!("local_addr: {:?}, addr: {:?}", local_addr, address)),
                         )
                     })?;
                     loop {
@@ -550,7 +796,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                                 break;
                             }
                             Err(e) => {
-                                println!("send failed: {:?}", e);
+                                tracing::warn!(%address, "send failed: {:?}", e);
                                 return Err(QuicError::ConnectionError.wrap().new(
                                     "try_connect conn.send",
                                     e,
@@ -559,16 +805,13 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                             }
                         };
 
-                        println!(
-                            "client: init: send_info: {:?} sent {} bytes",
-                            send_info, write
-                        );
+                        tracing::trace!(%address, to = %send_info.to, "sent {} bytes", write);
                         while let Err(e) = socket.send_to(&out[..write], send_info.to) {
                             if e.kind() == std::io::ErrorKind::WouldBlock {
                                 continue;
                             }
 
-                            println!("send() failed: {:?}", e);
+                            tracing::warn!(%address, "send_to failed: {:?}", e);
                             return Err(QuicError::ConnectionError.wrap().new(
                                 "quic try_connect socket.send_to",
                                 e,
@@ -585,10 +828,8 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                             data_receiver: recv_rx,
                             data_sender: send_tx,
                             address,
-                            total_bytes_received: total_bytes_received.clone(),
-                            total_bytes_sent: total_bytes_sent.clone(),
-                            endpoint_bytes_received: Arc::new(RwLock::new(0)),
-                            endpoint_bytes_sent: Arc::new(RwLock::new(0)),
+                            total_bandwidth: total_bandwidth.clone(),
+                            endpoint_bandwidth: Bandwidth::new_shared(),
                         }),
                         init_connection_handler.clone(),
                         message_handler.clone(),
@@ -598,13 +839,35 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                         //TODO: Change
                         Some(String::from("quic")),
                         PeerNetCategoryInfo {
-                            max_in_connections_per_ip: 0,
-                            max_in_connections: 0,
-                            max_out_connections: 0,
+                            max_in_connections_per_ip: Some(0),
+                            max_in_connections: Some(0),
+                            max_out_connections: Some(0),
+                            max_out_connections_per_ip: Some(0),
+                            read_timeout: None,
+                            write_timeout: None,
                         },
+                        features,
+                        buffer_pool,
+                        dispatcher,
+                        None,
+                        None,
                     );
                     drop(wg);
                     Ok(())
+                };
+                move || {
+                    let result = dial();
+                    if let Err(err) = &result {
+                        audit(&dial_audit, || AuditRecord {
+                            time: SystemTime::now(),
+                            addr: address,
+                            direction: PeerConnectionType::OUT,
+                            outcome: AuditOutcome::DialFailed,
+                            peer_id: None,
+                            reason: Some(err.to_string()),
+                        });
+                    }
+                    result
                 }
             })
             .expect("Failed to spawn thread quic_listener_handle");
@@ -612,7 +875,7 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
     }
 
     fn stop_listener(&mut self, address: SocketAddr) -> PeerNetResult<()> {
-        let (waker, _, handle) =
+        let (waker, _, _, handle) =
             self.listeners
                 .remove(&address)
                 .ok_or(QuicError::InternalFail.wrap().error(
@@ -642,11 +905,8 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                     .new("data_sender send", err, None)
             })?;
 
-        let mut write = endpoint.total_bytes_sent.write();
-        *write += data.len() as u64;
-
-        let mut endpoint_write = endpoint.endpoint_bytes_sent.write();
-        *endpoint_write += data.len() as u64;
+        endpoint.total_bandwidth.add_sent(data.len() as u64);
+        endpoint.endpoint_bandwidth.add_sent(data.len() as u64);
 
         Ok(())
     }
@@ -665,16 +925,13 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
                     .new("data_sender send", err, None)
             })?;
 
-        let mut write = endpoint.total_bytes_sent.write();
-        *write += data.len() as u64;
-
-        let mut endpoint_write = endpoint.endpoint_bytes_sent.write();
-        *endpoint_write += data.len() as u64;
+        endpoint.total_bandwidth.add_sent(data.len() as u64);
+        endpoint.endpoint_bandwidth.add_sent(data.len() as u64);
 
         Ok(())
     }
 
-    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Vec<u8>> {
+    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Bytes> {
         let data = endpoint.data_receiver.recv().map_err(|err| {
             QuicError::ConnectionError
                 .wrap()
@@ -682,13 +939,10 @@ impl<Id: PeerId> Transport<Id> for QuicTransport<Id> {
         })?;
         match data {
             QuicInternalMessage::Data(data) => {
-                let mut write = endpoint.total_bytes_received.write();
-                *write += data.len() as u64;
-
-                let mut endpoint_write = endpoint.endpoint_bytes_received.write();
-                *endpoint_write += data.len() as u64;
+                endpoint.total_bandwidth.add_received(data.len() as u64);
+                endpoint.endpoint_bandwidth.add_received(data.len() as u64);
 
-                Ok(data)
+                Ok(Bytes::from(data))
             }
             QuicInternalMessage::Shutdown => Err(QuicError::InternalFail
                 .wrap()
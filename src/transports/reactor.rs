@@ -0,0 +1,1079 @@
+//! Event loops driving the TCP connections after their handshake (`PeerNetFeatures::tcp_reactor`)
+//!
+//! By default each connected peer has a thread reading its messages and another one writing the
+//! messages queued in its send channels, which doesn't scale past a few thousand peers.
+//! With a reactor, the socket of a TCP peer is switched to non-blocking mode once its handshake
+//! is done and given to one of a few threads polling their connections with mio. A loop reads
+//! the frames of its connections as they arrive and writes the queued messages when the send
+//! channels of a peer wake it up. The rate limit is applied with a token bucket per direction,
+//! a throttled connection is polled again once its bucket has enough tokens.
+//!
+//! With `PeerNetFeatures::handler_workers`, a message that the workers can't take yet is kept by
+//! its connection, which isn't polled for reads anymore until the workers release bytes.
+//!
+//! Only plain TCP endpoints are given to the loops, the other ones keep their threads.
+
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
+use stream_limiter::LimiterOptions;
+
+use crate::bandwidth::SharedBandwidth;
+use crate::buffer_pool::SharedBufferPool;
+use crate::config::{report, DiagnosticEvent, SharedDiagnosticsSink, TcpReactor};
+use crate::dispatcher::{MessageDispatcher, Resume};
+use crate::error::{PeerNetError, PeerNetResult};
+use crate::history::DisconnectReason;
+use crate::memory::PeerMemory;
+use crate::message_filter::check_message;
+use crate::messages::{MessageMeta, MessagesHandler};
+use crate::network_manager::SharedActiveConnections;
+use crate::peer::{PeerHandle, QueuedMessage, SendCounters};
+use crate::peer_id::PeerId;
+use crate::timings::{timed, PeerTimers};
+
+use super::tcp::{
+    decode_frame_len, frame_len, ChunkHandler, SharedRateLimit, TcpConnectionConfig, TcpEndpoint,
+};
+
+// token of the waker of a loop, its connections get the next ones
+const WAKER: Token = Token(0);
+// a write gathers the queued messages until their frames reach this size
+const MAX_WRITE_BATCH: usize = 256 * 1024;
+// interval between two checks of the write timeouts of the connections of a loop
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Wakes the event loop of a peer when a message is queued in its send channels
+#[derive(Clone)]
+pub(crate) struct WriteNotifier {
+    token: Token,
+    // set until the loop looks at the send channels, so that it's woken once for several messages
+    pending: Arc<AtomicBool>,
+    writes: Sender<Token>,
+    waker: Arc<Waker>,
+}
+
+impl WriteNotifier {
+    pub(crate) fn notify(&self) {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // the loop is stopped if the channel is closed, the connection is gone with it
+        if self.writes.send(self.token).is_ok() {
+            if let Err(err) = self.waker.wake() {
+                tracing::error!("Error waking the event loop: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Wakes the event loop of a peer when the handler workers can take its messages again
+#[derive(Clone)]
+struct ReadResumer {
+    token: Token,
+    resumes: Sender<Token>,
+    waker: Arc<Waker>,
+}
+
+impl ReadResumer {
+    fn resume(&self) -> Resume {
+        let resumer = self.clone();
+        Box::new(move || {
+            if resumer.resumes.send(resumer.token).is_ok() {
+                if let Err(err) = resumer.waker.wake() {
+                    tracing::error!("Error waking the event loop: {:?}", err);
+                }
+            }
+        })
+    }
+}
+
+thread_local! {
+    // set on the threads of the event loops
+    static ON_EVENT_LOOP: Cell<bool> = Cell::new(false);
+}
+
+/// Whether the current thread is an event loop. The handlers called from a loop must not wait
+/// for room in a send channel: the loop that would empty it may be this one.
+pub(crate) fn on_event_loop() -> bool {
+    ON_EVENT_LOOP.with(Cell::get)
+}
+
+enum Command<Id: PeerId> {
+    Register(Token, Box<Connection<Id>>),
+    Stop,
+}
+
+/// Event loops of the TCP transport, stopped when it's dropped
+pub(crate) struct Reactor<Id: PeerId> {
+    handle: ReactorHandle<Id>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<Id: PeerId> Reactor<Id> {
+    pub(crate) fn start(
+        config: TcpReactor,
+        active_connections: SharedActiveConnections<Id>,
+        diagnostics: Option<SharedDiagnosticsSink>,
+    ) -> PeerNetResult<Reactor<Id>> {
+        let mut loops = Vec::new();
+        let mut threads = Vec::new();
+        for index in 0..config.nb_event_loops.max(1) {
+            let poll = Poll::new()
+                .map_err(|err| PeerNetError::SocketError.new("reactor poll new", err, None))?;
+            let waker = Waker::new(poll.registry(), WAKER)
+                .map_err(|err| PeerNetError::SocketError.new("reactor waker new", err, None))?;
+            let (commands_tx, commands_rx) = unbounded();
+            let (writes_tx, writes_rx) = unbounded();
+            let (resumes_tx, resumes_rx) = unbounded();
+            let event_loop = EventLoop {
+                poll,
+                commands: commands_rx,
+                writes: writes_rx,
+                resumes: resumes_rx,
+                connections: HashMap::new(),
+                throttled: BinaryHeap::new(),
+                active_connections: active_connections.clone(),
+                diagnostics: diagnostics.clone(),
+            };
+            match std::thread::Builder::new()
+                .name(format!("tcp_event_loop_{}", index))
+                .spawn(move || event_loop.run())
+            {
+                Ok(thread) => threads.push(thread),
+                Err(err) => {
+                    // stops the loops already started
+                    drop(Reactor::<Id> {
+                        handle: ReactorHandle {
+                            loops: Arc::new(loops),
+                            next_loop: Arc::new(AtomicUsize::new(0)),
+                        },
+                        threads,
+                    });
+                    return Err(PeerNetError::SocketError.new("spawn tcp_event_loop", err, None));
+                }
+            }
+            loops.push(EventLoopHandle {
+                commands: commands_tx,
+                writes: writes_tx,
+                resumes: resumes_tx,
+                waker: Arc::new(waker),
+                next_token: AtomicUsize::new(WAKER.0 + 1),
+            });
+        }
+        Ok(Reactor {
+            handle: ReactorHandle {
+                loops: Arc::new(loops),
+                next_loop: Arc::new(AtomicUsize::new(0)),
+            },
+            threads,
+        })
+    }
+
+    pub(crate) fn handle(&self) -> ReactorHandle<Id> {
+        self.handle.clone()
+    }
+}
+
+impl<Id: PeerId> Drop for Reactor<Id> {
+    fn drop(&mut self) {
+        for event_loop in self.handle.loops.iter() {
+            let _ = event_loop.commands.send(Command::Stop);
+            let _ = event_loop.waker.wake();
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct EventLoopHandle<Id: PeerId> {
+    commands: Sender<Command<Id>>,
+    writes: Sender<Token>,
+    resumes: Sender<Token>,
+    waker: Arc<Waker>,
+    next_token: AtomicUsize,
+}
+
+/// Handle on the event loops given to the peers, the connections are spread over the loops
+#[derive(Clone)]
+pub(crate) struct ReactorHandle<Id: PeerId> {
+    loops: Arc<Vec<EventLoopHandle<Id>>>,
+    next_loop: Arc<AtomicUsize>,
+}
+
+impl<Id: PeerId> ReactorHandle<Id> {
+    /// Choose the loop of a new connection. It's done before the send channels of the peer are
+    /// created so that they can wake the loop.
+    pub(crate) fn reserve(&self) -> ReactorSlot<Id> {
+        let index = self.next_loop.fetch_add(1, Ordering::Relaxed) % self.loops.len();
+        let event_loop = &self.loops[index];
+        ReactorSlot {
+            commands: event_loop.commands.clone(),
+            resumes: event_loop.resumes.clone(),
+            notifier: WriteNotifier {
+                token: Token(event_loop.next_token.fetch_add(1, Ordering::Relaxed)),
+                pending: Arc::new(AtomicBool::new(false)),
+                writes: event_loop.writes.clone(),
+                waker: event_loop.waker.clone(),
+            },
+        }
+    }
+}
+
+/// Place of a connection in an event loop, see `ReactorHandle::reserve`
+pub(crate) struct ReactorSlot<Id: PeerId> {
+    commands: Sender<Command<Id>>,
+    resumes: Sender<Token>,
+    notifier: WriteNotifier,
+}
+
+impl<Id: PeerId> ReactorSlot<Id> {
+    pub(crate) fn notifier(&self) -> WriteNotifier {
+        self.notifier.clone()
+    }
+
+    /// Give the connection of a peer to its loop, which reads and writes it from now on
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn register<M: MessagesHandler<Id>>(
+        self,
+        endpoint: TcpEndpoint,
+        peer: PeerHandle<Id>,
+        high_priority: Receiver<QueuedMessage>,
+        low_priority: Receiver<QueuedMessage>,
+        counters: Arc<SendCounters>,
+        message_handler: M,
+        dispatcher: Option<MessageDispatcher<Id>>,
+    ) -> PeerNetResult<()> {
+        let TcpEndpoint {
+            address,
+            config,
+            stream_limiter,
+            rate_limit,
+            total_bandwidth,
+            endpoint_bandwidth,
+            buffer_pool,
+            ..
+        } = endpoint;
+        let stream = stream_limiter
+            .get_stream()
+            .into_stream()
+            .map_err(|err| PeerNetError::SocketError.new("reactor take stream", err, None))?;
+        stream
+            .set_nonblocking(true)
+            .map_err(|err| PeerNetError::SocketError.new("reactor set nonblocking", err, None))?;
+        let (rate_limit, options) = rate_limit.subscribe();
+        let last_write = config.clock.now();
+        let dispatch_timeout = dispatcher.as_ref().and_then(MessageDispatcher::timeout);
+        let resumer = ReadResumer {
+            token: self.notifier.token,
+            resumes: self.resumes,
+            waker: self.notifier.waker.clone(),
+        };
+        let connection = Connection {
+            peer_id: peer.peer_id.clone(),
+            memory: peer.send_channels.memory.clone(),
+            address,
+            stream: TcpStream::from_std(stream),
+            decoder: FrameDecoder::new(
+                config.max_message_size,
+                message_handler.stream_chunk_size(),
+                buffer_pool.clone(),
+            ),
+            config,
+            handler: Box::new(PeerMessages {
+                peer,
+                message_handler,
+                dispatcher,
+                resumer,
+            }),
+            deferred: None,
+            dispatch_timeout,
+            read_bucket: Bucket::new(options.clone()),
+            read_throttled: None,
+            write_buffer: Vec::new(),
+            written: 0,
+            write_payload: 0,
+            write_frames: 0,
+            last_write,
+            write_bucket: Bucket::new(options),
+            write_throttled: None,
+            high_priority,
+            low_priority,
+            pending: self.notifier.pending.clone(),
+            counters,
+            rate_limit,
+            total_bandwidth,
+            endpoint_bandwidth,
+            buffer_pool,
+        };
+        self.commands
+            .send(Command::Register(self.notifier.token, Box::new(connection)))
+            .map_err(|_| PeerNetError::SocketError.error("reactor register", None))?;
+        self.notifier
+            .waker
+            .wake()
+            .map_err(|err| PeerNetError::SocketError.new("reactor wake", err, None))
+    }
+}
+
+/// Delivers the messages read by a loop like the read loop of a peer would
+trait ConnectionHandler: ChunkHandler + Send {
+    /// The message is given back if the handler workers can't take it yet, the loop is woken
+    /// when they can
+    fn message(&self, data: Bytes, meta: MessageMeta) -> PeerNetResult<Option<Bytes>>;
+
+    /// Whether the message passes the filter of the peer, checked once before `message`
+    fn accepts(&self, _data: &Bytes) -> PeerNetResult<bool> {
+        Ok(true)
+    }
+}
+
+struct PeerMessages<Id: PeerId, M: MessagesHandler<Id>> {
+    peer: PeerHandle<Id>,
+    message_handler: M,
+    dispatcher: Option<MessageDispatcher<Id>>,
+    resumer: ReadResumer,
+}
+
+impl<Id: PeerId, M: MessagesHandler<Id>> ConnectionHandler for PeerMessages<Id, M> {
+    fn message(&self, data: Bytes, meta: MessageMeta) -> PeerNetResult<Option<Bytes>> {
+        match &self.dispatcher {
+            Some(dispatcher) => {
+                dispatcher.try_dispatch(data, &self.peer, meta, self.resumer.resume())
+            }
+            None => {
+                let _charge = self.peer.send_channels.charge_received(data.len());
+                timed(self.peer.timers.as_deref(), PeerTimers::add_handler, || {
+                    self.message_handler
+                        .handle_with_meta(data, &self.peer, meta)
+                })
+                .map(|_| None)
+            }
+        }
+    }
+
+    fn accepts(&self, data: &Bytes) -> PeerNetResult<bool> {
+        check_message(&self.peer.message_filter, &self.peer.peer_id, data)
+    }
+}
+
+impl<Id: PeerId, M: MessagesHandler<Id>> ChunkHandler for PeerMessages<Id, M> {
+    fn message_start(&self, size: usize) -> PeerNetResult<()> {
+        self.message_handler.on_message_start(size, &self.peer)
+    }
+
+    fn chunk(&self, chunk: Bytes) -> PeerNetResult<()> {
+        self.message_handler.on_chunk(chunk, &self.peer)
+    }
+
+    fn message_end(&self) -> PeerNetResult<()> {
+        self.message_handler.on_message_end(&self.peer)
+    }
+}
+
+/// Token bucket with the options of the `Limiter` of a blocking endpoint
+struct Bucket {
+    options: LimiterOptions,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(options: LimiterOptions) -> Bucket {
+        Bucket {
+            tokens: options.bucket_size,
+            options,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn set_options(&mut self, options: LimiterOptions) {
+        self.refill();
+        self.tokens = self.tokens.min(options.bucket_size);
+        self.options = options;
+    }
+
+    /// Number of bytes that can be transferred now
+    fn available(&mut self) -> u64 {
+        self.refill();
+        self.tokens
+    }
+
+    fn consume(&mut self, nb_bytes: usize) {
+        self.tokens = self.tokens.saturating_sub(nb_bytes as u64);
+    }
+
+    /// Tokens needed to transfer `wanted` bytes, the transfers are made by `min_operation_size`
+    fn needed(&self, wanted: usize) -> u64 {
+        (wanted as u64).min(self.options.min_operation_size).max(1)
+    }
+
+    /// Time at which `nb_tokens` tokens will be available
+    fn ready_at(&self, nb_tokens: u64) -> Instant {
+        let missing = nb_tokens.saturating_sub(self.tokens) as u128;
+        let nanos =
+            missing * self.options.wtime_ns as u128 / (self.options.window_length as u128).max(1);
+        self.last_refill + Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if self.tokens >= self.options.bucket_size || self.options.wtime_ns == 0 {
+            self.tokens = self.options.bucket_size;
+            self.last_refill = now;
+            return;
+        }
+        let elapsed = now.duration_since(self.last_refill).as_nanos();
+        let new_tokens =
+            elapsed * self.options.window_length as u128 / self.options.wtime_ns as u128;
+        if new_tokens == 0 {
+            return;
+        }
+        let missing = (self.options.bucket_size - self.tokens) as u128;
+        if new_tokens >= missing {
+            self.tokens = self.options.bucket_size;
+            self.last_refill = now;
+        } else {
+            self.tokens += new_tokens as u64;
+            // keep the time of the fraction of token not given yet
+            let used =
+                new_tokens * self.options.wtime_ns as u128 / self.options.window_length as u128;
+            self.last_refill += Duration::from_nanos(used as u64);
+        }
+    }
+}
+
+/// Part of a frame being read
+enum Reading {
+    /// Size of the next message, 4 bytes big endian
+    Header { bytes: [u8; 4], filled: usize },
+    /// Message delivered whole to the handler
+    Message { data: Vec<u8>, filled: usize },
+    /// Chunk of a streamed message, `remaining` bytes of the message follow it
+    Chunk {
+        data: Vec<u8>,
+        filled: usize,
+        remaining: usize,
+    },
+}
+
+impl Reading {
+    fn header() -> Reading {
+        Reading::Header {
+            bytes: [0; 4],
+            filled: 0,
+        }
+    }
+
+    /// The bytes still to read
+    fn unfilled(&mut self) -> &mut [u8] {
+        match self {
+            Reading::Header { bytes, filled } => &mut bytes[*filled..],
+            Reading::Message { data, filled } | Reading::Chunk { data, filled, .. } => {
+                &mut data[*filled..]
+            }
+        }
+    }
+
+    /// Mark `nb_bytes` as read, returns true if the part is complete
+    fn advance(&mut self, nb_bytes: usize) -> bool {
+        let (filled, len) = match self {
+            Reading::Header { bytes, filled } => (filled, bytes.len()),
+            Reading::Message { data, filled } | Reading::Chunk { data, filled, .. } => {
+                (filled, data.len())
+            }
+        };
+        *filled += nb_bytes;
+        *filled == len
+    }
+}
+
+/// Parser of the frames of a connection, fed with the bytes read from its socket
+struct FrameDecoder {
+    reading: Reading,
+    max_message_size: Option<usize>,
+    // the messages bigger than this are streamed to the handler
+    chunk_size: Option<usize>,
+    buffer_pool: SharedBufferPool,
+}
+
+impl FrameDecoder {
+    fn new(
+        max_message_size: Option<usize>,
+        chunk_size: Option<usize>,
+        buffer_pool: SharedBufferPool,
+    ) -> FrameDecoder {
+        FrameDecoder {
+            reading: Reading::header(),
+            max_message_size,
+            chunk_size: chunk_size.map(|chunk_size| chunk_size.max(1)),
+            buffer_pool,
+        }
+    }
+
+    /// Where to read the next bytes of the connection
+    fn unfilled(&mut self) -> &mut [u8] {
+        self.reading.unfilled()
+    }
+
+    /// Take `nb_bytes` read in `unfilled`, the messages they complete are given to `handler`
+    /// and counted with `count_received(nb_frames, nb_bytes)`. Returns the message that the
+    /// handler gave back, see `ConnectionHandler::message`.
+    fn advance(
+        &mut self,
+        nb_bytes: usize,
+        handler: &dyn ConnectionHandler,
+        count_received: impl Fn(u64, usize),
+    ) -> PeerNetResult<Option<(Bytes, MessageMeta)>> {
+        if self.reading.advance(nb_bytes) {
+            return self.part_read(handler, count_received);
+        }
+        Ok(None)
+    }
+
+    /// Handle a part of a frame once it's complete and start reading the next one
+    fn part_read(
+        &mut self,
+        handler: &dyn ConnectionHandler,
+        count_received: impl Fn(u64, usize),
+    ) -> PeerNetResult<Option<(Bytes, MessageMeta)>> {
+        match std::mem::replace(&mut self.reading, Reading::header()) {
+            Reading::Header { bytes, .. } => {
+                let size = decode_frame_len(bytes, self.max_message_size)? as usize;
+                // an empty message is read as a closed connection by the blocking endpoints
+                if size == 0 {
+                    return Err(PeerNetError::ConnectionClosed.error("reactor empty message", None));
+                }
+                self.reading = match self.chunk_size {
+                    Some(chunk_size) if size > chunk_size => {
+                        handler.message_start(size)?;
+                        self.chunk(chunk_size, size)
+                    }
+                    _ => {
+                        let mut data = self.buffer_pool.get(size);
+                        data.resize(size, 0);
+                        Reading::Message { data, filled: 0 }
+                    }
+                };
+            }
+            Reading::Message { data, .. } => {
+                count_received(1, data.len());
+                let data = self.buffer_pool.into_bytes(data);
+                if handler.accepts(&data)? {
+                    let meta = MessageMeta::now();
+                    if let Some(data) = handler.message(data, meta)? {
+                        return Ok(Some((data, meta)));
+                    }
+                }
+            }
+            Reading::Chunk {
+                data, remaining, ..
+            } => {
+                // the message is counted with its last chunk
+                count_received(u64::from(remaining == 0), data.len());
+                handler.chunk(self.buffer_pool.into_bytes(data))?;
+                match self.chunk_size {
+                    Some(chunk_size) if remaining > 0 => {
+                        self.reading = self.chunk(chunk_size, remaining);
+                    }
+                    _ => handler.message_end()?,
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Next chunk of a streamed message of which `remaining` bytes are left
+    fn chunk(&self, chunk_size: usize, remaining: usize) -> Reading {
+        let len = remaining.min(chunk_size);
+        let mut data = self.buffer_pool.get(len);
+        data.resize(len, 0);
+        Reading::Chunk {
+            data,
+            filled: 0,
+            remaining: remaining - len,
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+impl ConnectionHandler for super::tcp::FrameRecorder {
+    fn message(&self, data: Bytes, _meta: MessageMeta) -> PeerNetResult<Option<Bytes>> {
+        self.events
+            .borrow_mut()
+            .push(super::tcp::FrameEvent::Message(data.to_vec()));
+        Ok(None)
+    }
+}
+
+/// Read the frames of `data` with the parser of the event loops, fed with at most `read_size`
+/// bytes at a time, until an error: the messages bigger than `chunk_size` are streamed.
+/// Returns what the handler got and the error that stopped the reads, `ConnectionClosed` at the
+/// end of `data` or on an empty message. See `read_frames` for the parser of the endpoints.
+#[cfg(feature = "testing")]
+pub fn decode_frames(
+    data: &[u8],
+    read_size: usize,
+    max_message_size: Option<usize>,
+    chunk_size: Option<usize>,
+) -> (Vec<super::tcp::FrameEvent>, crate::error::PeerNetErrorData) {
+    let buffer_pool = Arc::new(crate::buffer_pool::BufferPool::new(&Default::default()));
+    let mut decoder = FrameDecoder::new(max_message_size, chunk_size, buffer_pool);
+    let recorder = super::tcp::FrameRecorder::default();
+    let mut data = data;
+    let error = loop {
+        if data.is_empty() {
+            break PeerNetError::ConnectionClosed.error("reactor read len = 0", None);
+        }
+        let unfilled = decoder.unfilled();
+        let len = unfilled.len().min(read_size.max(1)).min(data.len());
+        unfilled[..len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+        if let Err(err) = decoder.advance(len, &recorder, |_, _| {}) {
+            break err;
+        }
+    };
+    (recorder.events.into_inner(), error)
+}
+
+/// A TCP connection driven by an event loop
+struct Connection<Id: PeerId> {
+    peer_id: Id,
+    // marked by `MemoryAction::DisconnectHeaviest`, the connection is closed at its next drive
+    memory: Option<Arc<PeerMemory>>,
+    address: SocketAddr,
+    stream: TcpStream,
+    config: TcpConnectionConfig,
+    handler: Box<dyn ConnectionHandler>,
+    // message that the handler workers couldn't take, the reads stop until it's dispatched
+    deferred: Option<(Bytes, MessageMeta)>,
+    // the connection is closed if `deferred` waits longer than this
+    dispatch_timeout: Option<Duration>,
+    decoder: FrameDecoder,
+    read_bucket: Bucket,
+    // the reads wait for tokens until this instant
+    read_throttled: Option<Instant>,
+    // frames being written, of which `written` bytes are sent
+    write_buffer: Vec<u8>,
+    written: usize,
+    // size and number of the messages in `write_buffer`, counted as sent once it's written
+    write_payload: u64,
+    write_frames: u64,
+    // last progress of the writes, `write_timeout` applies from it
+    last_write: Instant,
+    write_bucket: Bucket,
+    // the writes wait for tokens until this instant
+    write_throttled: Option<Instant>,
+    high_priority: Receiver<QueuedMessage>,
+    low_priority: Receiver<QueuedMessage>,
+    // shared with the `WriteNotifier` of the send channels
+    pending: Arc<AtomicBool>,
+    // shared with the send channels
+    counters: Arc<SendCounters>,
+    rate_limit: SharedRateLimit,
+    total_bandwidth: SharedBandwidth,
+    endpoint_bandwidth: SharedBandwidth,
+    buffer_pool: SharedBufferPool,
+}
+
+impl<Id: PeerId> Connection<Id> {
+    /// Apply the last rate limit set on the manager
+    fn update_rate_limit(&mut self) {
+        if let Some(options) = self.rate_limit.changed() {
+            self.read_bucket.set_options(options.clone());
+            self.write_bucket.set_options(options);
+        }
+    }
+
+    /// Read until the socket has no more data or a message is deferred. Returns the instant at
+    /// which the reads can continue if the rate limit stopped them.
+    fn read(&mut self) -> PeerNetResult<Option<Instant>> {
+        loop {
+            if self.deferred.is_some() {
+                return Ok(None);
+            }
+            self.update_rate_limit();
+            let wanted = self.decoder.unfilled().len();
+            let needed = self.read_bucket.needed(wanted);
+            let available = self.read_bucket.available();
+            if available < needed {
+                return Ok(Some(self.read_bucket.ready_at(needed)));
+            }
+            let len = wanted.min(available as usize);
+            match self.stream.read(&mut self.decoder.unfilled()[..len]) {
+                Ok(0) => {
+                    return Err(PeerNetError::ConnectionClosed.error("reactor read len = 0", None))
+                }
+                Ok(nb_bytes) => {
+                    self.read_bucket.consume(nb_bytes);
+                    self.deferred =
+                        self.decoder
+                            .advance(nb_bytes, &*self.handler, |nb_frames, nb_bytes| {
+                                self.total_bandwidth
+                                    .add_received_frames(nb_frames, nb_bytes as u64);
+                                self.endpoint_bandwidth
+                                    .add_received_frames(nb_frames, nb_bytes as u64);
+                            })?;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => {
+                    return Err(PeerNetError::ReceiveError.new("reactor read", err, None));
+                }
+            }
+        }
+    }
+
+    /// Write the queued messages until there are no more or the socket is full. Returns the
+    /// instant at which the writes can continue if the rate limit stopped them.
+    fn write(&mut self) -> PeerNetResult<Option<Instant>> {
+        loop {
+            if self.written == self.write_buffer.len() {
+                self.total_bandwidth
+                    .add_sent_frames(self.write_frames, self.write_payload);
+                self.endpoint_bandwidth
+                    .add_sent_frames(self.write_frames, self.write_payload);
+                if !self.fill_write_buffer()? {
+                    return Ok(None);
+                }
+                self.last_write = self.config.clock.now();
+            }
+            self.update_rate_limit();
+            let wanted = self.write_buffer.len() - self.written;
+            let needed = self.write_bucket.needed(wanted);
+            let available = self.write_bucket.available();
+            if available < needed {
+                return Ok(Some(self.write_bucket.ready_at(needed)));
+            }
+            let len = wanted.min(available as usize);
+            match self
+                .stream
+                .write(&self.write_buffer[self.written..self.written + len])
+            {
+                Ok(0) => return Err(PeerNetError::SendError.error("reactor write len = 0", None)),
+                Ok(nb_bytes) => {
+                    self.write_bucket.consume(nb_bytes);
+                    self.written += nb_bytes;
+                    self.last_write = self.config.clock.now();
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(PeerNetError::SendError.new("reactor write", err, None)),
+            }
+        }
+    }
+
+    /// Put the frames of the queued messages in the write buffer, high priority ones first.
+    /// Returns false if there were none.
+    fn fill_write_buffer(&mut self) -> PeerNetResult<bool> {
+        self.write_buffer.clear();
+        self.written = 0;
+        self.write_payload = 0;
+        self.write_frames = 0;
+        while self.write_buffer.len() < MAX_WRITE_BATCH {
+            let Ok(msg) = self
+                .high_priority
+                .try_recv()
+                .or_else(|_| self.low_priority.try_recv())
+            else {
+                break;
+            };
+            if msg.is_expired() {
+                self.counters
+                    .nb_expired_messages
+                    .fetch_add(1, Ordering::Relaxed);
+                self.buffer_pool.put(msg.data);
+                continue;
+            }
+            let msg_size = frame_len(&self.config, &msg.data)?;
+            self.write_buffer.extend_from_slice(&msg_size.to_be_bytes());
+            self.write_buffer.extend_from_slice(&msg.data);
+            self.write_payload += msg.data.len() as u64;
+            self.write_frames += 1;
+            self.buffer_pool.put(msg.data);
+        }
+        Ok(!self.write_buffer.is_empty())
+    }
+
+    /// Give the deferred message to the handler again. Returns true if it took it.
+    fn redeliver(&mut self) -> PeerNetResult<bool> {
+        let Some((data, meta)) = self.deferred.take() else {
+            return Ok(true);
+        };
+        self.deferred = self.handler.message(data, meta)?.map(|data| (data, meta));
+        Ok(self.deferred.is_none())
+    }
+
+    /// Whether the deferred message waited longer than `dispatch_timeout`
+    fn dispatch_timed_out(&self, now: Instant) -> bool {
+        match (&self.deferred, self.dispatch_timeout) {
+            (Some((_, meta)), Some(timeout)) => {
+                now.saturating_duration_since(meta.received_at) > timeout
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the writes made no progress for `write_timeout`, on the clock of the manager
+    fn write_timed_out(&self) -> bool {
+        self.written < self.write_buffer.len()
+            && self
+                .config
+                .clock
+                .now()
+                .saturating_duration_since(self.last_write)
+                > self.config.write_timeout
+    }
+}
+
+struct EventLoop<Id: PeerId> {
+    poll: Poll,
+    commands: Receiver<Command<Id>>,
+    // connections with messages queued in their send channels
+    writes: Receiver<Token>,
+    // connections with a deferred message, of which the handler workers released bytes
+    resumes: Receiver<Token>,
+    connections: HashMap<Token, Connection<Id>>,
+    // connections waiting for tokens of their rate limit, the first to resume on top
+    throttled: BinaryHeap<Reverse<(Instant, Token)>>,
+    active_connections: SharedActiveConnections<Id>,
+    diagnostics: Option<SharedDiagnosticsSink>,
+}
+
+impl<Id: PeerId> EventLoop<Id> {
+    fn run(mut self) {
+        ON_EVENT_LOOP.with(|on_event_loop| on_event_loop.set(true));
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let mut next_timeout_check = Instant::now() + TIMEOUT_CHECK_INTERVAL;
+        loop {
+            let wake_up = self
+                .throttled
+                .peek()
+                .map_or(next_timeout_check, |Reverse((instant, _))| {
+                    next_timeout_check.min(*instant)
+                });
+            if let Err(err) = self.poll.poll(
+                &mut events,
+                Some(wake_up.saturating_duration_since(Instant::now())),
+            ) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                tracing::error!("Can't poll the TCP event loop: {:?}", err);
+                return;
+            }
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    let readable =
+                        event.is_readable() || event.is_read_closed() || event.is_error();
+                    self.drive(event.token(), readable, event.is_writable());
+                }
+            }
+            while let Ok(command) = self.commands.try_recv() {
+                match command {
+                    Command::Register(token, connection) => self.register(token, *connection),
+                    Command::Stop => return,
+                }
+            }
+            while let Ok(token) = self.writes.try_recv() {
+                if let Some(connection) = self.connections.get(&token) {
+                    connection.pending.store(false, Ordering::Release);
+                    self.drive(token, false, true);
+                }
+            }
+            while let Ok(token) = self.resumes.try_recv() {
+                self.resume(token);
+            }
+            let now = Instant::now();
+            while let Some(Reverse((instant, token))) = self.throttled.peek().copied() {
+                if instant > now {
+                    break;
+                }
+                self.throttled.pop();
+                self.drive(token, true, true);
+            }
+            if now >= next_timeout_check {
+                let timed_out: Vec<Token> = self
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| connection.write_timed_out())
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in timed_out {
+                    self.close(token, DisconnectReason::Error("write timeout".to_string()));
+                }
+                let dispatch_timed_out: Vec<Token> = self
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| connection.dispatch_timed_out(now))
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in dispatch_timed_out {
+                    let err = PeerNetError::Backpressure
+                        .error("dispatch message", Some("deferred too long".to_string()));
+                    self.close(token, DisconnectReason::Error(err.to_string()));
+                }
+                next_timeout_check = now + TIMEOUT_CHECK_INTERVAL;
+            }
+        }
+    }
+
+    fn register(&mut self, token: Token, mut connection: Connection<Id>) {
+        if let Err(err) = self.poll.registry().register(
+            &mut connection.stream,
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        ) {
+            tracing::error!(
+                "Can't register a connection in the TCP event loop: {:?}",
+                err
+            );
+            let mut write_active_connections = self.active_connections.write();
+            write_active_connections.remove_connection_with_reason(
+                &connection.peer_id,
+                DisconnectReason::Error(err.to_string()),
+            );
+            return;
+        }
+        // messages may have been queued since the handshake
+        connection.pending.store(false, Ordering::Release);
+        self.connections.insert(token, connection);
+        self.drive(token, true, true);
+    }
+
+    /// Read and write the connection as far as the socket and the rate limit allow it
+    fn drive(&mut self, token: Token, read: bool, write: bool) {
+        let over_budget = self.connections.get(&token).map_or(false, |connection| {
+            connection
+                .memory
+                .as_ref()
+                .map_or(false, |memory| memory.over_budget())
+        });
+        if over_budget {
+            self.close(token, DisconnectReason::MemoryBudget);
+            return;
+        }
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut res = Ok(());
+        if read
+            && connection
+                .read_throttled
+                .map_or(true, |instant| instant <= now)
+        {
+            res = connection.read().map(|throttled| {
+                if let Some(instant) = throttled {
+                    self.throttled.push(Reverse((instant, token)));
+                    report(&self.diagnostics, || DiagnosticEvent::Throttled {
+                        addr: connection.address,
+                        write: false,
+                        until: instant,
+                    });
+                }
+                connection.read_throttled = throttled;
+            });
+            // the socket isn't polled for reads until the deferred message is dispatched
+            if res.is_ok() && connection.deferred.is_some() {
+                res = self
+                    .poll
+                    .registry()
+                    .reregister(&mut connection.stream, token, Interest::WRITABLE)
+                    .map_err(|err| PeerNetError::SocketError.new("reactor pause reads", err, None));
+            }
+        }
+        if res.is_ok()
+            && write
+            && connection
+                .write_throttled
+                .map_or(true, |instant| instant <= now)
+        {
+            res = connection.write().map(|throttled| {
+                if let Some(instant) = throttled {
+                    self.throttled.push(Reverse((instant, token)));
+                    report(&self.diagnostics, || DiagnosticEvent::Throttled {
+                        addr: connection.address,
+                        write: true,
+                        until: instant,
+                    });
+                }
+                connection.write_throttled = throttled;
+            });
+        }
+        if let Err(err) = res {
+            let reason = if err.error_type == PeerNetError::ConnectionClosed {
+                DisconnectReason::ClosedByPeer
+            } else if err.error_type == PeerNetError::MessageFiltered {
+                DisconnectReason::Filtered(err.add_msg().unwrap_or_default().to_string())
+            } else {
+                tracing::warn!(
+                    peer_id = ?connection.peer_id,
+                    "error on connection driven by the event loop: {:?}",
+                    err
+                );
+                DisconnectReason::Error(err.to_string())
+            };
+            self.close(token, reason);
+        }
+    }
+
+    /// Dispatch the deferred message of a connection, its reads continue if it's taken
+    fn resume(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let res = connection.redeliver().and_then(|taken| {
+            if taken {
+                self.poll
+                    .registry()
+                    .reregister(
+                        &mut connection.stream,
+                        token,
+                        Interest::READABLE | Interest::WRITABLE,
+                    )
+                    .map_err(|err| {
+                        PeerNetError::SocketError.new("reactor resume reads", err, None)
+                    })?;
+            }
+            Ok(taken)
+        });
+        match res {
+            // the bytes already in the socket don't trigger a new event
+            Ok(true) => self.drive(token, true, false),
+            Ok(false) => {}
+            Err(err) => self.close(token, DisconnectReason::Error(err.to_string())),
+        }
+    }
+
+    fn close(&mut self, token: Token, reason: DisconnectReason) {
+        let Some(mut connection) = self.connections.remove(&token) else {
+            return;
+        };
+        if let Err(err) = self.poll.registry().deregister(&mut connection.stream) {
+            tracing::error!(
+                "Can't deregister a connection from the TCP event loop: {:?}",
+                err
+            );
+        }
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        if let Some(memory) = &connection.memory {
+            memory.clear_on_over_budget();
+        }
+        let mut write_active_connections = self.active_connections.write();
+        write_active_connections.remove_connection_with_reason(&connection.peer_id, reason);
+    }
+}
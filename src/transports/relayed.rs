@@ -0,0 +1,157 @@
+//! Endpoint tunneled through a relay peer
+//!
+//! The messages of a `RelayedEndpoint` are sent to the relay in `DATA` messages of a circuit,
+//! which forwards them to the other end of the circuit (see `internal_handlers::relay`). The
+//! messages received on the circuit are given to the endpoint by the `RelayHandler`.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use crossbeam::select;
+
+use crate::bandwidth::{Bandwidth, BandwidthRates, BandwidthSnapshot, SharedBandwidth};
+use crate::error::PeerNetResult;
+use crate::messages::RawSerializer;
+use crate::peer::SendChannels;
+
+pub(crate) const RELAY_DATA: u8 = 5;
+pub(crate) const RELAY_CLOSE: u8 = 6;
+
+/// Header of the messages of the circuit `circuit_id` for the relay handler `handler_id`
+fn relay_header(handler_id: u64, message_type: u8, circuit_id: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(17);
+    header.extend_from_slice(&handler_id.to_be_bytes());
+    header.push(message_type);
+    header.extend_from_slice(&circuit_id.to_be_bytes());
+    header
+}
+
+#[derive(Clone)]
+pub struct RelayedEndpoint {
+    /// Address of the relay
+    pub(crate) address: SocketAddr,
+    handler_id: u64,
+    circuit_id: u64,
+    relay: SendChannels,
+    incoming: Receiver<Bytes>,
+    // wakes up the reader when the endpoint is shut down
+    close_tx: Sender<()>,
+    close_rx: Receiver<()>,
+    closed: Arc<AtomicBool>,
+    data_channel_size: usize,
+    bandwidth: SharedBandwidth,
+}
+
+impl RelayedEndpoint {
+    pub(crate) fn new(
+        address: SocketAddr,
+        handler_id: u64,
+        circuit_id: u64,
+        relay: SendChannels,
+        incoming: Receiver<Bytes>,
+        data_channel_size: usize,
+    ) -> Self {
+        let (close_tx, close_rx) = unbounded();
+        RelayedEndpoint {
+            address,
+            handler_id,
+            circuit_id,
+            relay,
+            incoming,
+            close_tx,
+            close_rx,
+            closed: Arc::new(AtomicBool::new(false)),
+            data_channel_size,
+            bandwidth: Bandwidth::new_shared(),
+        }
+    }
+
+    pub(crate) fn get_data_channel_size(&self) -> usize {
+        self.data_channel_size
+    }
+
+    fn data_message(&self, data: &[u8]) -> Vec<u8> {
+        let mut message = relay_header(self.handler_id, RELAY_DATA, self.circuit_id);
+        message.extend_from_slice(data);
+        message
+    }
+
+    pub(crate) fn send(&mut self, data: &[u8]) -> PeerNetResult<()> {
+        self.relay
+            .send(&RawSerializer, self.data_message(data), false)?;
+        self.bandwidth.add_sent(data.len() as u64);
+        Ok(())
+    }
+
+    pub(crate) fn send_timeout(&mut self, data: &[u8], timeout: Duration) -> PeerNetResult<()> {
+        self.relay.send_with_deadline(
+            &RawSerializer,
+            self.data_message(data),
+            false,
+            Instant::now() + timeout,
+        )?;
+        self.bandwidth.add_sent(data.len() as u64);
+        Ok(())
+    }
+
+    pub(crate) fn send_batch(&mut self, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        for message in data {
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    /// An empty message is returned once the circuit is closed, like a closed TCP stream
+    pub(crate) fn receive(&mut self) -> PeerNetResult<Bytes> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Ok(Bytes::new());
+        }
+        select! {
+            recv(self.incoming) -> data => match data {
+                Ok(data) => {
+                    self.bandwidth.add_received(data.len() as u64);
+                    Ok(data)
+                }
+                Err(_) => Ok(Bytes::new()),
+            },
+            recv(self.close_rx) -> _ => Ok(Bytes::new()),
+        }
+    }
+
+    /// Close the circuit on the relay
+    pub(crate) fn shutdown(&mut self) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let _ = self.relay.try_send(
+            &RawSerializer,
+            relay_header(self.handler_id, RELAY_CLOSE, self.circuit_id),
+            true,
+        );
+        let _ = self.close_tx.send(());
+    }
+
+    pub(crate) fn get_bandwidth(&self) -> BandwidthSnapshot {
+        self.bandwidth.snapshot()
+    }
+
+    pub(crate) fn get_bandwidth_delta(&self) -> BandwidthSnapshot {
+        self.bandwidth.delta()
+    }
+
+    pub(crate) fn reset_counters(&self) -> BandwidthSnapshot {
+        self.bandwidth.reset()
+    }
+
+    pub(crate) fn get_total_bandwidth(&self) -> BandwidthSnapshot {
+        self.bandwidth.total()
+    }
+
+    pub(crate) fn get_rates(&self) -> BandwidthRates {
+        self.bandwidth.rates()
+    }
+}
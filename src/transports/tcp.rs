@@ -1,29 +1,43 @@
 use std::collections::HashMap;
 use std::io::{ErrorKind, Read, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime};
 
-use crate::config::{PeerNetCategories, PeerNetCategoryInfo, PeerNetFeatures};
+use crate::audit::{audit, AuditOutcome, AuditRecord};
+use crate::bandwidth::{Bandwidth, SharedBandwidth};
+use crate::buffer_pool::SharedBufferPool;
+use crate::clock::{SharedClock, SystemClock};
+use crate::config::{
+    PeerNetCategories, PeerNetCategoryInfo, PeerNetFeatures, TcpKeepalive, MAX_SMALL_MESSAGE_SIZE,
+    MIN_OPERATION_SIZE,
+};
 use crate::context::Context;
+use crate::dispatcher::MessageDispatcher;
 use crate::error::{PeerNetError, PeerNetResult};
+use crate::history::ConnectionEventKind;
 use crate::messages::MessagesHandler;
 use crate::network_manager::{to_canonical, SharedActiveConnections};
-use crate::peer::{new_peer, InitConnectionHandler, PeerConnectionType};
+use crate::peer::{new_peer, InitConnectionHandler, PeerConnectionType, PeerHandle};
 use crate::peer_id::PeerId;
 use crate::transports::Endpoint;
 
-use super::{Transport, TransportErrorType};
+use super::reactor::Reactor;
+use super::tcp_io::TcpIo;
+use super::{listener_error, Transport, TransportErrorType, LISTENER_ERROR_DELAY};
 
-use crossbeam::channel::{unbounded, Receiver, Sender};
+use bytes::Bytes;
+use crossbeam::channel::{never, unbounded, Sender};
 use crossbeam::sync::WaitGroup;
 use mio::net::TcpListener;
 use mio::{Events, Interest, Poll, Token, Waker};
 use parking_lot::RwLock;
+use serde::Serialize;
 use stream_limiter::{Limiter, LimiterOptions};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub enum TcpError {
     InitListener,
     ConnectionError,
@@ -34,30 +48,72 @@ impl TcpError {
     fn wrap(self) -> PeerNetError {
         PeerNetError::TransportError(TransportErrorType::Tcp(self))
     }
+
+    pub(crate) fn code(&self) -> u16 {
+        match self {
+            TcpError::InitListener => 100,
+            TcpError::ConnectionError => 101,
+            TcpError::StopListener => 102,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 #[allow(dead_code)]
 pub struct TcpTransportConfig {
-    pub max_in_connections: usize,
+    pub max_in_connections: Option<usize>,
     pub connection_config: TcpConnectionConfig,
     pub peer_categories: PeerNetCategories,
     pub default_category_info: PeerNetCategoryInfo,
     pub write_timeout: Duration,
     pub read_timeout: Duration,
+    /// See `TcpSettings::listener_poll_interval`
+    pub listener_poll_interval: Duration,
+    /// See `TcpSettings::keepalive`
+    pub keepalive: Option<TcpKeepalive>,
+    /// See `TcpSettings::linger`
+    pub linger: Option<Duration>,
+}
+
+/// Stop of a listener thread: it's woken up to see the flag, and sees it anyway after
+/// `TcpSettings::listener_poll_interval` if the wake-up is lost or fails
+pub(crate) struct ListenerStop {
+    waker: Waker,
+    stopped: Arc<AtomicBool>,
 }
 
+impl ListenerStop {
+    fn stop(&self, address: SocketAddr) {
+        self.stopped.store(true, Ordering::Release);
+        if let Err(err) = self.waker.wake() {
+            tracing::warn!(
+                "Could not wake up the listener on {}, it stops at its next poll: {:?}",
+                address,
+                err
+            );
+        }
+    }
+}
+
+// the senders of the stop channels of the peers accepted by the listener and by the ones it
+// replaced, see `TcpTransport::rebind_listener`. Dropped when the listener stops.
+type TcpListenerHandle = (ListenerStop, Vec<Sender<()>>, JoinHandle<PeerNetResult<()>>);
+
+/// Configuration of the connections accepted or dialed from now on, with the rate limit they
+/// share. The listeners read it at each connection, see `TcpTransport::replace_config`.
+pub(crate) type TcpConfigSlot = Arc<RwLock<(TcpTransportConfig, SharedRateLimit)>>;
+
 pub(crate) struct TcpTransport<Id: PeerId> {
     pub active_connections: SharedActiveConnections<Id>,
     pub out_connection_attempts: WaitGroup,
-    pub listeners: HashMap<SocketAddr, (Waker, JoinHandle<PeerNetResult<()>>)>,
-    _features: PeerNetFeatures,
-
-    peer_stop_tx: Sender<()>,
-    peer_stop_rx: Receiver<()>,
-    pub config: TcpTransportConfig,
-    pub total_bytes_received: Arc<RwLock<u64>>,
-    pub total_bytes_sent: Arc<RwLock<u64>>,
+    pub listeners: HashMap<SocketAddr, TcpListenerHandle>,
+    features: PeerNetFeatures,
+    pub(crate) config: TcpConfigSlot,
+    pub total_bandwidth: SharedBandwidth,
+    buffer_pool: SharedBufferPool,
+    dispatcher: Option<MessageDispatcher<Id>>,
+    // event loops driving the connections after their handshake if enabled
+    reactor: Option<Reactor<Id>>,
 }
 
 const NEW_CONNECTION: Token = Token(0);
@@ -69,17 +125,96 @@ pub struct TcpConnectionConfig {
     pub rate_time_window: Duration,
     pub rate_bucket_size: u64,
     pub data_channel_size: usize,
-    pub max_message_size: usize,
+    pub max_message_size: Option<usize>,
     pub write_timeout: Duration,
     pub read_timeout: Duration,
+    /// See `PeerNetFeatures::small_message_size`
+    pub small_message_size: Option<usize>,
+    /// See `PeerNetFeatures::handshake_data_limit`, removed from the endpoint after the
+    /// handshake
+    pub handshake_data_limit: Option<usize>,
+    /// See `PeerNetFeatures::clock`, the read and write timeouts are measured on it
+    pub clock: SharedClock,
+    /// See `PeerNetFeatures::io_uring`
+    pub io_uring: bool,
+}
+
+impl TcpConnectionConfig {
+    /// The configuration of the connections of a category, with its own timeouts if it has
+    pub(crate) fn for_category(&self, category_info: &PeerNetCategoryInfo) -> TcpConnectionConfig {
+        TcpConnectionConfig {
+            read_timeout: category_info.read_timeout.unwrap_or(self.read_timeout),
+            write_timeout: category_info.write_timeout.unwrap_or(self.write_timeout),
+            ..self.clone()
+        }
+    }
 }
 
 impl From<TcpConnectionConfig> for LimiterOptions {
     fn from(val: TcpConnectionConfig) -> Self {
-        let mut opts =
-            LimiterOptions::new(val.rate_limit, val.rate_time_window, val.rate_bucket_size);
-        opts.set_min_operation_size(60 * 1024); // Min packet size for TCP: 60 Kb
-        opts
+        limiter_options(val.rate_limit, val.rate_time_window, val.rate_bucket_size)
+    }
+}
+
+pub(crate) fn limiter_options(
+    rate_limit: u64,
+    rate_time_window: Duration,
+    rate_bucket_size: u64,
+) -> LimiterOptions {
+    let mut opts = LimiterOptions::new(rate_limit, rate_time_window, rate_bucket_size);
+    // Min packet size for TCP: 60 Kb. A whole frame (size + message) is a single operation, the
+    // frames smaller than that wait for tokens for all their bytes at once.
+    opts.set_min_operation_size(MIN_OPERATION_SIZE.min(rate_bucket_size));
+    opts
+}
+
+/// Rate limit of the TCP connections of a manager, shared with their endpoints so that it can be
+/// changed while they are connected. Each endpoint applies a new value before its next read or
+/// write.
+#[derive(Clone)]
+pub struct SharedRateLimit {
+    shared: Arc<(AtomicU64, RwLock<LimiterOptions>)>,
+    // version of the options used by the limiter of the endpoint holding this handle
+    version: u64,
+}
+
+impl SharedRateLimit {
+    pub fn new(options: LimiterOptions) -> Self {
+        SharedRateLimit {
+            shared: Arc::new((AtomicU64::new(0), RwLock::new(options))),
+            version: 0,
+        }
+    }
+
+    pub fn options(&self) -> LimiterOptions {
+        self.shared.1.read().clone()
+    }
+
+    /// Replace the options of all the endpoints sharing this rate limit
+    pub fn set(&self, options: LimiterOptions) {
+        let mut current = self.shared.1.write();
+        *current = options;
+        self.shared.0.fetch_add(1, Ordering::Release);
+    }
+
+    /// New handle for an endpoint, with the options its limiter must use
+    pub(super) fn subscribe(&self) -> (SharedRateLimit, LimiterOptions) {
+        let options = self.shared.1.read();
+        let handle = SharedRateLimit {
+            shared: self.shared.clone(),
+            version: self.shared.0.load(Ordering::Acquire),
+        };
+        (handle, options.clone())
+    }
+
+    /// The options to use if they changed since the last call
+    pub(super) fn changed(&mut self) -> Option<LimiterOptions> {
+        if self.shared.0.load(Ordering::Acquire) == self.version {
+            return None;
+        }
+        let options = self.shared.1.read();
+        self.version = self.shared.0.load(Ordering::Acquire);
+        Some(options.clone())
     }
 }
 
@@ -89,10 +224,14 @@ impl Default for TcpConnectionConfig {
             rate_limit: 10 * 1024,
             rate_time_window: Duration::from_secs(1),
             rate_bucket_size: 10 * 1024,
-            max_message_size: 100000,
+            max_message_size: Some(100000),
             data_channel_size: 10000,
             write_timeout: Duration::from_secs(7),
             read_timeout: Duration::from_secs(7),
+            small_message_size: None,
+            handshake_data_limit: None,
+            clock: Arc::new(SystemClock),
+            io_uring: false,
         }
     }
 }
@@ -101,19 +240,20 @@ impl Default for TcpConnectionConfig {
 pub struct TcpEndpoint {
     pub config: TcpConnectionConfig,
     pub address: SocketAddr,
-    pub stream_limiter: Limiter<TcpStream>,
-    // shared between all endpoints
-    pub total_bytes_received: Arc<RwLock<u64>>,
+    pub stream_limiter: Limiter<TcpIo>,
+    // shared between all endpoints, changes are applied to `stream_limiter`
+    pub rate_limit: SharedRateLimit,
     // shared between all endpoints
-    pub total_bytes_sent: Arc<RwLock<u64>>,
-    // received by this endpoint
-    pub endpoint_bytes_received: Arc<RwLock<u64>>,
-    // sent by this endpoint
-    pub endpoint_bytes_sent: Arc<RwLock<u64>>,
+    pub total_bandwidth: SharedBandwidth,
+    // sent and received by this endpoint
+    pub endpoint_bandwidth: SharedBandwidth,
+    // buffers used to read and write messages
+    pub buffer_pool: SharedBufferPool,
 }
 
 impl TcpEndpoint {
     pub fn try_clone(&self) -> PeerNetResult<Self> {
+        let (rate_limit, options) = self.rate_limit.subscribe();
         Ok(TcpEndpoint {
             address: self.address,
             stream_limiter: Limiter::new(
@@ -122,17 +262,25 @@ impl TcpEndpoint {
                         .wrap()
                         .new("cannot clone stream", err, None)
                 })?,
-                Some(self.config.clone().into()),
-                Some(self.config.clone().into()),
+                Some(options.clone()),
+                Some(options),
             ),
+            rate_limit,
             config: self.config.clone(),
-            total_bytes_received: self.total_bytes_received.clone(),
-            total_bytes_sent: self.total_bytes_sent.clone(),
-            endpoint_bytes_received: self.endpoint_bytes_received.clone(),
-            endpoint_bytes_sent: self.endpoint_bytes_sent.clone(),
+            total_bandwidth: self.total_bandwidth.clone(),
+            endpoint_bandwidth: self.endpoint_bandwidth.clone(),
+            buffer_pool: self.buffer_pool.clone(),
         })
     }
 
+    /// Write out what the limiter holds, the kernel sends the rest even after `shutdown`
+    /// unless the socket is reset, see `TcpSettings::linger`
+    pub fn flush(&mut self) -> PeerNetResult<()> {
+        self.stream_limiter
+            .flush()
+            .map_err(|err| PeerNetError::SendError.error("error on flush", Some(err.to_string())))
+    }
+
     pub fn shutdown(&mut self) {
         let _ = self
             .stream_limiter
@@ -141,11 +289,74 @@ impl TcpEndpoint {
     }
 
     pub fn get_bytes_sent(&self) -> u64 {
-        *self.endpoint_bytes_sent.read()
+        self.endpoint_bandwidth.snapshot().bytes_sent
     }
 
     pub fn get_bytes_received(&self) -> u64 {
-        *self.endpoint_bytes_received.read()
+        self.endpoint_bandwidth.snapshot().bytes_received
+    }
+
+    /// Apply the last rate limit set on the manager
+    fn update_rate_limit(&mut self) {
+        if let Some(options) = self.rate_limit.changed() {
+            self.stream_limiter.read_opt = Some(options.clone());
+            self.stream_limiter.write_opt = Some(options);
+        }
+    }
+}
+
+/// Bytes the frames of a connection are read from by `receive_frame` and `receive_streamed`:
+/// the socket of a `TcpEndpoint`, or a buffer for the fuzz targets and property tests
+pub(crate) trait FrameSource {
+    fn config(&self) -> &TcpConnectionConfig;
+    fn buffer_pool(&self) -> &SharedBufferPool;
+    /// Sent and received by this connection
+    fn bandwidth(&self) -> &Bandwidth;
+    /// Timeout of the next `read`, applied with the last rate limit
+    fn set_read_timeout(&mut self, timeout: Duration) -> PeerNetResult<()>;
+    fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize>;
+    fn shutdown(&mut self);
+    fn count_received(&self, nb_frames: u64, nb_bytes: u64);
+}
+
+impl FrameSource for TcpEndpoint {
+    fn config(&self) -> &TcpConnectionConfig {
+        &self.config
+    }
+
+    fn buffer_pool(&self) -> &SharedBufferPool {
+        &self.buffer_pool
+    }
+
+    fn bandwidth(&self) -> &Bandwidth {
+        &self.endpoint_bandwidth
+    }
+
+    fn set_read_timeout(&mut self, timeout: Duration) -> PeerNetResult<()> {
+        self.update_rate_limit();
+        if let Some(ref mut opts) = self.stream_limiter.read_opt {
+            opts.set_timeout(timeout);
+        }
+        self.stream_limiter
+            .stream
+            .set_read_timeout(Some(timeout))
+            .map_err(|e| {
+                tracing::error!("error setting read timeout: {e:?}");
+                PeerNetError::CouldNotSetTimeout
+                    .error("error setting read timeout", Some(e.to_string()))
+            })
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        self.stream_limiter.read(data)
+    }
+
+    fn shutdown(&mut self) {
+        TcpEndpoint::shutdown(self);
+    }
+
+    fn count_received(&self, nb_frames: u64, nb_bytes: u64) {
+        count_bytes_received(self, nb_frames, nb_bytes);
     }
 }
 
@@ -154,22 +365,53 @@ impl<Id: PeerId> TcpTransport<Id> {
         active_connections: SharedActiveConnections<Id>,
         config: TcpTransportConfig,
         features: PeerNetFeatures,
-        total_bytes_received: Arc<RwLock<u64>>,
-        total_bytes_sent: Arc<RwLock<u64>>,
+        total_bandwidth: SharedBandwidth,
+        buffer_pool: SharedBufferPool,
+        dispatcher: Option<MessageDispatcher<Id>>,
     ) -> TcpTransport<Id> {
-        let (peer_stop_tx, peer_stop_rx) = unbounded();
+        let rate_limit = SharedRateLimit::new(config.connection_config.clone().into());
+        let reactor = features.tcp_reactor.map(|tcp_reactor| {
+            Reactor::start(
+                tcp_reactor,
+                active_connections.clone(),
+                features.diagnostics.clone(),
+            )
+            .expect("Failed to start the TCP event loops")
+        });
         TcpTransport {
             active_connections,
             out_connection_attempts: WaitGroup::new(),
             listeners: Default::default(),
-            _features: features,
-            peer_stop_rx,
-            peer_stop_tx,
-            config,
-            total_bytes_received,
-            total_bytes_sent,
+            features,
+            config: Arc::new(RwLock::new((config, rate_limit))),
+            total_bandwidth,
+            buffer_pool,
+            dispatcher,
+            reactor,
         }
     }
+
+    /// Give `config` to the connections accepted or dialed from now on, the connected peers
+    /// keep theirs. They get a rate limit of their own if it's changed, the previous one is
+    /// left to the connected peers.
+    pub(crate) fn replace_config(&mut self, config: TcpTransportConfig) {
+        let mut current = self.config.write();
+        let (previous, rate_limit) = &*current;
+        let rate_limit = if (
+            previous.connection_config.rate_limit,
+            previous.connection_config.rate_time_window,
+            previous.connection_config.rate_bucket_size,
+        ) == (
+            config.connection_config.rate_limit,
+            config.connection_config.rate_time_window,
+            config.connection_config.rate_bucket_size,
+        ) {
+            rate_limit.clone()
+        } else {
+            SharedRateLimit::new(config.connection_config.clone().into())
+        };
+        *current = (config, rate_limit);
+    }
 }
 
 impl<Id: PeerId> Drop for TcpTransport<Id> {
@@ -202,34 +444,53 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
         let mut events = Events::with_capacity(128);
         let waker = Waker::new(poll.registry(), STOP_LISTENER)
             .map_err(|err| TcpError::InitListener.wrap().new("waker new", err, None))?;
-        let listener_handle: JoinHandle<PeerNetResult<()>> = std::thread::Builder::new()
-            .name(format!("tcp_listener_handle_{:?}", address))
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut server = TcpListener::bind(address).map_err(|err| {
+            TcpError::InitListener
+                .wrap()
+                .new("bind", err, Some(format!("address: {}", address)))
+        })?;
+        // Start listening for incoming connections.
+        poll.registry()
+            .register(&mut server, NEW_CONNECTION, Interest::READABLE)
+            .map_err(|err| {
+                TcpError::InitListener.wrap().new(
+                    "register listener",
+                    err,
+                    Some(format!("address: {}", address)),
+                )
+            })?;
+        // the sender is dropped when the listener stops, which stops the peers it accepted only
+        let (peer_stop_tx, peer_stop_rx) = unbounded::<()>();
+        let listener_handle: JoinHandle<PeerNetResult<()>> = self
+            .features
+            .reporting_builder(format!("tcp_listener_handle_{:?}", address), "tcp listener")
             .spawn({
                 let active_connections = self.active_connections.clone();
-                let total_bytes_received = self.total_bytes_received.clone();
-                let total_bytes_sent = self.total_bytes_sent.clone();
-                let peer_stop_rx = self.peer_stop_rx.clone();
-                let peer_stop_tx = self.peer_stop_tx.clone();
-                let config = self.config.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
+                let config_slot = self.config.clone();
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
+                let reactor = self.reactor.as_ref().map(Reactor::handle);
+                let stopped = stopped.clone();
                 move || {
-                    let mut server = TcpListener::bind(address).unwrap_or_else(|_| {
-                        panic!("Can't bind TCP transport to address {}", address)
-                    });
-
-                    // Start listening for incoming connections.
-                    poll.registry()
-                        .register(&mut server, NEW_CONNECTION, Interest::READABLE)
-                        .unwrap_or_else(|_| {
-                            panic!(
-                                "Can't register polling on TCP transport of address {}",
-                                address
-                            )
-                        });
                     loop {
-                        // Poll Mio for events, blocking until we get an event.
-                        poll.poll(&mut events, None).unwrap_or_else(|_| {
-                            panic!("Can't poll TCP transport of address {}", address)
-                        });
+                        // checked after each poll, the wake-ups of `STOP_LISTENER` can be
+                        // spurious or lost
+                        if stopped.load(Ordering::Acquire) {
+                            return Ok(());
+                        }
+                        // Poll Mio for events, blocking until we get an event or the interval
+                        // elapses.
+                        let listener_poll_interval = config_slot.read().0.listener_poll_interval;
+                        if let Err(err) = poll.poll(&mut events, Some(listener_poll_interval)) {
+                            if err.kind() != ErrorKind::Interrupted {
+                                listener_error(&features, address, err);
+                                std::thread::sleep(LISTENER_ERROR_DELAY);
+                            }
+                            continue;
+                        }
                         // Process each event.
                         for event in events.iter() {
                             match event.token() {
@@ -238,7 +499,7 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                         let (stream, address) = match server.accept() {
                                             Ok((mut stream, address)) => {
                                                 if let Err(e) = poll.registry().deregister(&mut stream) {
-                                                    log::error!("Could not deregister the stream {:?} from the mio poll: {:?}", stream, e);
+                                                    tracing::error!("Could not deregister the stream {:?} from the mio poll: {:?}", stream, e);
                                                 };
                                                 let stream: std::net::TcpStream = mio_stream_to_std(stream);
                                                 (stream, address)
@@ -246,24 +507,34 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                             Err(e) if e.kind() == ErrorKind::WouldBlock => {
                                                 break;
                                             }
+                                            // e.g. out of file descriptors, retried after a
+                                            // pause instead of spinning on it
                                             Err(e) => {
-                                                log::error!("Error accepting connection: {:?}", e);
-                                                continue;
+                                                listener_error(&features, address, e);
+                                                std::thread::sleep(LISTENER_ERROR_DELAY);
+                                                // the poll is edge-triggered, re-registering
+                                                // reports the connections still pending
+                                                if let Err(e) = poll.registry().reregister(&mut server, NEW_CONNECTION, Interest::READABLE) {
+                                                    listener_error(&features, address, e);
+                                                }
+                                                break;
                                             }
                                         };
-                                        {
-                                            let read_active_connections = active_connections.read();
-                                            let total_in_connections = read_active_connections
-                                                .connections
-                                                .iter()
-                                                .filter(|(_, connection)| connection.connection_type == PeerConnectionType::IN)
-                                                .count() +  read_active_connections
-                                                .in_connection_queue.len();
-                                            if total_in_connections >= config.max_in_connections {
-                                                continue;
+                                        let (config, rate_limit) = config_slot.read().clone();
+                                        let trusted = active_connections.read().trusted_peers.is_trusted(None, &address);
+                                        // the slot is held until the end of the handshake
+                                        let reservation = if trusted {
+                                            active_connections.read().reserve_in()
+                                        } else {
+                                            match active_connections.write().reserve_incoming(
+                                                &address,
+                                                config.max_in_connections,
+                                                features.eviction_policy.as_ref(),
+                                            ) {
+                                                Some(reservation) => reservation,
+                                                None => continue,
                                             }
-                                        }
-                                        set_tcp_stream_config(&stream, &config);
+                                        };
                                         let ip_canonical = to_canonical(address.ip());
                                         let (category_name, category_info) = match config
                                             .peer_categories
@@ -275,30 +546,37 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                             }
                                             None => (None, config.default_category_info),
                                         };
+                                        let connection_config = config.connection_config.for_category(&category_info);
+                                        set_tcp_stream_config(&stream, &connection_config, config.keepalive.as_ref(), config.linger);
 
+                                        let (rate_limit, options) = rate_limit.subscribe();
                                         let mut endpoint = Endpoint::Tcp(TcpEndpoint {
                                             address,
                                             stream_limiter: Limiter::new(
-                                                stream,
-                                                Some(config.connection_config.clone().into()),
-                                                Some(config.connection_config.clone().into()),
+                                                TcpIo::new(stream, connection_config.io_uring),
+                                                Some(options.clone()),
+                                                Some(options),
                                             ),
-                                            config: config.connection_config.clone(),
-                                            total_bytes_received: total_bytes_received.clone(),
-                                            total_bytes_sent: total_bytes_sent.clone(),
-                                            endpoint_bytes_received: Arc::new(RwLock::new(0)),
-                                            endpoint_bytes_sent: Arc::new(RwLock::new(0)),
+                                            rate_limit,
+                                            config: connection_config,
+                                            total_bandwidth: total_bandwidth.clone(),
+                                            endpoint_bandwidth: Bandwidth::new_shared(),
+                                            buffer_pool: buffer_pool.clone(),
                                         });
                                         let listeners = {
                                             let mut active_connections = active_connections.write();
-                                            active_connections
-                                            .in_connection_queue
-                                            .insert(address);
-                                            if active_connections.check_addr_accepted_pre_handshake(
+                                            // each peer in the queue has a thread running its handshake
+                                            let handshakes_available = features
+                                                .max_concurrent_handshakes
+                                                .map_or(true, |max| active_connections.in_connection_queue.len() < max);
+                                            let now = active_connections.clock.now();
+                                            active_connections.in_connection_queue.insert(address, now);
+                                            if trusted || (handshakes_available && active_connections.gater.clone().check_pre_handshake(
+                                                &active_connections,
                                                 &address,
                                                 category_name.clone(),
                                                 category_info,
-                                            ) {
+                                            )) {
                                                 active_connections.compute_counters();
                                                 None
                                             } else {
@@ -311,13 +589,19 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                                 &mut endpoint,
                                                 &listeners,
                                             ) {
-                                                log::error!("Error while sending fallback to address {}, err:{}", address, err)
+                                                tracing::error!("Error while sending fallback to address {}, err:{}", address, err)
                                             }
                                             //TODO: Wait end of thread to remove connection from queue
                                             let mut active_connections = active_connections.write();
                                             active_connections
                                             .in_connection_queue
                                             .remove(&address);
+                                            active_connections.history.record(
+                                                address,
+                                                ConnectionEventKind::Refused {
+                                                    direction: PeerConnectionType::IN,
+                                                },
+                                            );
                                             continue;
                                         }
                                         new_peer(
@@ -330,12 +614,18 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                             PeerConnectionType::IN,
                                             category_name,
                                             category_info,
+                                            features.clone(),
+                                            buffer_pool.clone(),
+                                            dispatcher.clone(),
+                                            reactor.clone(),
+                                            Some(reservation),
                                         );
                                     }
                                 }
                                 STOP_LISTENER => {
-                                    peer_stop_tx.send(()).unwrap();
-                                    return Ok(());
+                                    if stopped.load(Ordering::Acquire) {
+                                        return Ok(());
+                                    }
                                 }
                                 _ => {}
                             }
@@ -350,7 +640,14 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                 .listeners
                 .insert(address, super::TransportType::Tcp);
         }
-        self.listeners.insert(address, (waker, listener_handle));
+        self.listeners.insert(
+            address,
+            (
+                ListenerStop { waker, stopped },
+                vec![peer_stop_tx],
+                listener_handle,
+            ),
+        );
         Ok(())
     }
 
@@ -366,22 +663,32 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
         message_handler: M,
         handshake_handler: I,
     ) -> PeerNetResult<JoinHandle<PeerNetResult<()>>> {
-        let peer_stop_rx = self.peer_stop_rx.clone();
-        let config = self.config.clone();
-        Ok(std::thread::Builder::new()
-            .name(format!("tcp_try_connect_{:?}", address))
+        let (config, rate_limit) = self.config.read().clone();
+        let (dial_delay, clock) = {
+            let mut active_connections = self.active_connections.write();
+            (
+                active_connections.queue_dial(address),
+                active_connections.clock.clone(),
+            )
+        };
+        Ok(self
+            .features
+            .reporting_builder(format!("tcp_try_connect_{:?}", address), "tcp try_connect")
             .spawn({
                 let active_connections = self.active_connections.clone();
-                let total_bytes_received = self.total_bytes_received.clone();
-                let total_bytes_sent = self.total_bytes_sent.clone();
+                let total_bandwidth = self.total_bandwidth.clone();
                 let wg = self.out_connection_attempts.clone();
+                let features = self.features.clone();
+                let buffer_pool = self.buffer_pool.clone();
+                let dispatcher = self.dispatcher.clone();
+                let reactor = self.reactor.as_ref().map(Reactor::handle);
                 move || {
-                    active_connections
-                        .write()
-                        .out_connection_queue
-                        .insert(address);
+                    if !dial_delay.is_zero() {
+                        tracing::debug!(%address, delay = ?dial_delay, "dial paced");
+                        clock.wait(&never(), dial_delay);
+                    }
                     let connection = TcpStream::connect_timeout(&address, timeout).map_err(|err| {
-                        log::error!("try_connect stream connect: {err:?}");
+                        tracing::error!("try_connect stream connect: {err:?}");
                         TcpError::ConnectionError.wrap().new(
                             "try_connect stream connect",
                             err,
@@ -394,15 +701,17 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                 .write()
                                 .out_connection_queue
                                 .remove(&address);
+                            audit(&features.audit, || AuditRecord {
+                                time: SystemTime::now(),
+                                addr: address,
+                                direction: PeerConnectionType::OUT,
+                                outcome: AuditOutcome::DialFailed,
+                                peer_id: None,
+                                reason: Some(e.to_string()),
+                            });
                             Err(e)
                         }
                         Ok(stream) => {
-                            set_tcp_stream_config(&stream, &config);
-                            let stream_limiter = Limiter::new(
-                                stream,
-                                Some(config.connection_config.clone().into()),
-                                Some(config.connection_config.clone().into()),
-                            );
                             let ip_canonical = to_canonical(address.ip());
                             let (category_name, category_info) = match config
                                 .peer_categories
@@ -414,24 +723,43 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
                                 }
                                 None => (None, config.default_category_info),
                             };
+                            let connection_config =
+                                config.connection_config.for_category(&category_info);
+                            set_tcp_stream_config(
+                                &stream,
+                                &connection_config,
+                                config.keepalive.as_ref(),
+                                config.linger,
+                            );
+                            let (rate_limit, options) = rate_limit.subscribe();
+                            let stream_limiter = Limiter::new(
+                                TcpIo::new(stream, connection_config.io_uring),
+                                Some(options.clone()),
+                                Some(options),
+                            );
                             new_peer(
                                 context.clone(),
                                 Endpoint::Tcp(TcpEndpoint {
                                     address,
                                     stream_limiter,
-                                    config: config.connection_config.clone(),
-                                    total_bytes_received: total_bytes_received.clone(),
-                                    total_bytes_sent: total_bytes_sent.clone(),
-                                    endpoint_bytes_received: Arc::new(RwLock::new(0)),
-                                    endpoint_bytes_sent: Arc::new(RwLock::new(0)),
+                                    rate_limit,
+                                    config: connection_config,
+                                    total_bandwidth: total_bandwidth.clone(),
+                                    endpoint_bandwidth: Bandwidth::new_shared(),
+                                    buffer_pool: buffer_pool.clone(),
                                 }),
                                 handshake_handler.clone(),
                                 message_handler.clone(),
                                 active_connections.clone(),
-                                peer_stop_rx,
+                                never(),
                                 PeerConnectionType::OUT,
                                 category_name,
                                 category_info,
+                                features,
+                                buffer_pool,
+                                dispatcher,
+                                reactor,
+                                None,
                             );
                             drop(wg);
                             Ok(())
@@ -443,7 +771,7 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
     }
 
     fn stop_listener(&mut self, address: SocketAddr) -> PeerNetResult<()> {
-        let (waker, handle) = self.listeners.remove(&address).ok_or(
+        let (stop, peer_stops, handle) = self.listeners.remove(&address).ok_or(
             TcpError::StopListener
                 .wrap()
                 .error("rm addr", Some(format!("address: {}", address))),
@@ -452,41 +780,54 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
             let mut active_connections = self.active_connections.write();
             active_connections.listeners.remove(&address);
         }
-        waker
-            .wake()
-            .map_err(|e| TcpError::StopListener.wrap().new("waker wake", e, None))?;
-        handle
+        stop.stop(address);
+        let result = handle
             .join()
-            .unwrap_or_else(|_| panic!("Couldn't join listener for address {}", address))
+            .unwrap_or_else(|_| panic!("Couldn't join listener for address {}", address));
+        // no more peers can be accepted, stop the ones it accepted
+        drop(peer_stops);
+        result
     }
 
-    fn send(endpoint: &mut Self::Endpoint, data: &[u8]) -> PeerNetResult<()> {
-        let msg_size: u32 = data.len().try_into().map_err(|_| {
-            log::error!("Send len too long: {:?}", data.len());
-            TcpError::ConnectionError
+    /// The peers of the old listener stay connected, they are stopped with the new one
+    fn rebind_listener<
+        Ctx: Context<Id>,
+        M: MessagesHandler<Id>,
+        I: InitConnectionHandler<Id, Ctx, M>,
+    >(
+        &mut self,
+        context: Ctx,
+        old_address: SocketAddr,
+        new_address: SocketAddr,
+        message_handler: M,
+        init_connection_handler: I,
+    ) -> PeerNetResult<()> {
+        if !self.listeners.contains_key(&old_address) {
+            return Err(TcpError::StopListener
                 .wrap()
-                .error("send len too long", Some(format!("{:?}", data.len())))
-        })?;
-
-        // send message size first
-        let elapsed = write_exact_timeout(
-            endpoint,
-            &msg_size.to_be_bytes(),
-            endpoint.config.write_timeout,
+                .error("rebind", Some(format!("address: {}", old_address))));
+        }
+        self.start_listener(
+            context,
+            new_address,
+            message_handler,
+            init_connection_handler,
         )?;
+        let old_peer_stops = std::mem::take(&mut self.listeners.get_mut(&old_address).unwrap().1);
+        if let Some((_, peer_stops, _)) = self.listeners.get_mut(&new_address) {
+            peer_stops.extend(old_peer_stops);
+        }
+        self.stop_listener(old_address)
+    }
 
-        let timeout = endpoint.config.write_timeout.saturating_sub(elapsed);
-
-        // then send message
-        write_exact_timeout(endpoint, data, timeout)?;
-
-        let mut write = endpoint.total_bytes_sent.write();
-        *write += data.len() as u64;
-
-        let mut endpoint_write = endpoint.endpoint_bytes_sent.write();
-        *endpoint_write += data.len() as u64;
+    fn send(endpoint: &mut Self::Endpoint, data: &[u8]) -> PeerNetResult<()> {
+        let msg_size = frame_len(&endpoint.config, data)?;
+        check_handshake_data(endpoint, true, data.len())?;
+        if is_small(&endpoint.config, data) {
+            return write_small_frame(endpoint, msg_size, data, endpoint.config.write_timeout);
+        }
 
-        Ok(())
+        write_frame(endpoint, msg_size, data, endpoint.config.write_timeout)
     }
 
     fn send_timeout(
@@ -494,113 +835,434 @@ impl<Id: PeerId> Transport<Id> for TcpTransport<Id> {
         data: &[u8],
         timeout: Duration,
     ) -> Result<(), crate::error::PeerNetErrorData> {
-        let msg_size: u32 = data.len().try_into().map_err(|_| {
-            log::error!("Send_timeout len too long: {:?}", data.len());
-            TcpError::ConnectionError
-                .wrap()
-                .error("send len too long", Some(format!("{:?}", data.len())))
-        })?;
-        //TODO: Use config one
+        let msg_size = frame_len(&endpoint.config, data)?;
+        check_handshake_data(endpoint, true, data.len())?;
+        if is_small(&endpoint.config, data) {
+            return write_small_frame(endpoint, msg_size, data, timeout);
+        }
+        write_frame(endpoint, msg_size, data, timeout)
+    }
 
-        let elapsed = write_exact_timeout(endpoint, &msg_size.to_be_bytes(), timeout)?;
+    fn send_batch(endpoint: &mut Self::Endpoint, data: &[Vec<u8>]) -> PeerNetResult<()> {
+        check_handshake_data(
+            endpoint,
+            true,
+            data.iter().map(|message| message.len()).sum(),
+        )?;
+        // all the frames (size + message) are concatenated to be written at once
+        let mut frames = endpoint
+            .buffer_pool
+            .get(data.iter().map(|message| message.len() + 4).sum());
+        let mut payload_len: u64 = 0;
+        for message in data {
+            let msg_size = frame_len(&endpoint.config, message)?;
+            frames.extend_from_slice(&msg_size.to_be_bytes());
+            frames.extend_from_slice(message);
+            payload_len += message.len() as u64;
+        }
 
-        let timeout = timeout.saturating_sub(elapsed);
+        let res = write_exact_timeout(endpoint, &frames, endpoint.config.write_timeout);
+        endpoint.buffer_pool.put(frames);
+        res?;
 
-        write_exact_timeout(endpoint, data, timeout)?;
+        count_bytes_sent(endpoint, data.len() as u64, payload_len);
 
-        let mut write = endpoint.total_bytes_sent.write();
-        *write += data.len() as u64;
+        Ok(())
+    }
 
-        let mut endpoint_write = endpoint.endpoint_bytes_sent.write();
-        *endpoint_write += data.len() as u64;
+    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Bytes> {
+        receive_frame(endpoint)
+    }
+}
 
-        Ok(())
+impl<Id: PeerId> TcpTransport<Id> {
+    /// Receive a message, streaming it to `message_handler` in chunks of `chunk_size` bytes if
+    /// it's bigger than that, see `receive_streamed`.
+    /// Returns the message if it's small enough to be delivered whole, `None` otherwise.
+    pub(crate) fn receive_streamed<M: MessagesHandler<Id>>(
+        endpoint: &mut TcpEndpoint,
+        chunk_size: usize,
+        message_handler: &M,
+        peer: &PeerHandle<Id>,
+    ) -> PeerNetResult<Option<Bytes>> {
+        receive_streamed(
+            endpoint,
+            chunk_size,
+            &PeerChunks {
+                message_handler,
+                peer,
+            },
+        )
     }
+}
 
-    fn receive(endpoint: &mut Self::Endpoint) -> PeerNetResult<Vec<u8>> {
-        //TODO: Config one
-        let mut len_bytes = vec![0u8; 4];
+/// Receives the chunks of the messages streamed by `receive_streamed`
+pub(crate) trait ChunkHandler {
+    fn message_start(&self, size: usize) -> PeerNetResult<()>;
+    fn chunk(&self, chunk: Bytes) -> PeerNetResult<()>;
+    fn message_end(&self) -> PeerNetResult<()>;
+}
 
-        // read message size first
-        let elapsed = read_exact_timeout(endpoint, &mut len_bytes, endpoint.config.read_timeout)?;
+/// The chunk callbacks of the messages handler of a peer
+struct PeerChunks<'a, Id: PeerId, M: MessagesHandler<Id>> {
+    message_handler: &'a M,
+    peer: &'a PeerHandle<Id>,
+}
 
-        let res_size = u32::from_be_bytes(len_bytes.try_into().map_err(|err| {
-            log::error!("receive len: {err:?}");
-            TcpError::ConnectionError
-                .wrap()
-                .error("recv len", Some(format!("{:?}", err)))
-        })?);
+impl<Id: PeerId, M: MessagesHandler<Id>> ChunkHandler for PeerChunks<'_, Id, M> {
+    fn message_start(&self, size: usize) -> PeerNetResult<()> {
+        self.message_handler.on_message_start(size, self.peer)
+    }
 
-        if res_size > endpoint.config.max_message_size as u32 {
-            log::error!("receive len too long: {res_size:?}");
-            return Err(
-                PeerNetError::InvalidMessage.error("len too long", Some(format!("{:?}", res_size)))
-            );
+    fn chunk(&self, chunk: Bytes) -> PeerNetResult<()> {
+        self.message_handler.on_chunk(chunk, self.peer)
+    }
+
+    fn message_end(&self) -> PeerNetResult<()> {
+        self.message_handler.on_message_end(self.peer)
+    }
+}
+
+/// Read the next frame of `source` and return its message
+fn receive_frame<S: FrameSource>(source: &mut S) -> PeerNetResult<Bytes> {
+    // read message size first
+    let (res_size, elapsed) = read_frame_len(source)?;
+    let timeout = source.config().read_timeout.saturating_sub(elapsed);
+
+    // then read message in a buffer of the pool
+    let mut data = source.buffer_pool().get(res_size as usize);
+    data.resize(res_size as usize, 0);
+    if let Err(err) = read_exact_timeout(source, &mut data, timeout) {
+        source.buffer_pool().put(data);
+        return Err(err);
+    }
+
+    source.count_received(1, res_size as u64);
+
+    Ok(source.buffer_pool().into_bytes(data))
+}
+
+/// Read the next frame of `source`, streaming its message to `handler` in chunks of
+/// `chunk_size` bytes if it's bigger than that. Each chunk has its own read timeout so that
+/// slow handlers or rate limited transfers of big messages don't time out.
+/// Returns the message if it's small enough to be delivered whole, `None` otherwise.
+fn receive_streamed<S: FrameSource>(
+    source: &mut S,
+    chunk_size: usize,
+    handler: &impl ChunkHandler,
+) -> PeerNetResult<Option<Bytes>> {
+    // an empty chunk would never make the message progress
+    let chunk_size = chunk_size.max(1);
+    let (res_size, elapsed) = read_frame_len(source)?;
+    let mut remaining = res_size as usize;
+    if remaining <= chunk_size {
+        let timeout = source.config().read_timeout.saturating_sub(elapsed);
+        let mut data = source.buffer_pool().get(remaining);
+        data.resize(remaining, 0);
+        if let Err(err) = read_exact_timeout(source, &mut data, timeout) {
+            source.buffer_pool().put(data);
+            return Err(err);
         }
-        let timeout = endpoint.config.read_timeout.saturating_sub(elapsed);
+        source.count_received(1, res_size as u64);
+        return Ok(Some(source.buffer_pool().into_bytes(data)));
+    }
+
+    handler.message_start(remaining)?;
+    while remaining > 0 {
+        let len = remaining.min(chunk_size);
+        let mut chunk = source.buffer_pool().get(len);
+        chunk.resize(len, 0);
+        let timeout = source.config().read_timeout;
+        if let Err(err) = read_exact_timeout(source, &mut chunk, timeout) {
+            source.buffer_pool().put(chunk);
+            return Err(err);
+        }
+        remaining -= len;
+        // the message is counted with its last chunk
+        source.count_received(u64::from(remaining == 0), len as u64);
+        handler.chunk(source.buffer_pool().into_bytes(chunk))?;
+    }
+    handler.message_end()?;
+    Ok(None)
+}
 
-        // then read message
-        let mut data = vec![0u8; res_size as usize];
-        read_exact_timeout(endpoint, &mut data, timeout)?;
+/// Read the header of a frame and return the size of the message and the time spent reading
+fn read_frame_len<S: FrameSource>(source: &mut S) -> PeerNetResult<(u32, Duration)> {
+    //TODO: Config one
+    let mut len_bytes = [0u8; 4];
 
-        {
-            let mut write = endpoint.total_bytes_received.write();
-            *write += res_size as u64;
+    let timeout = source.config().read_timeout;
+    let elapsed = read_exact_timeout(source, &mut len_bytes, timeout)?;
+
+    let res_size = decode_frame_len(len_bytes, source.config().max_message_size)?;
+    check_handshake_data(source, false, res_size as usize)?;
+    Ok((res_size, elapsed))
+}
+
+/// Size of the message of a frame from its header, checked against `max_message_size`
+pub(super) fn decode_frame_len(
+    header: [u8; 4],
+    max_message_size: Option<usize>,
+) -> PeerNetResult<u32> {
+    let res_size = u32::from_be_bytes(header);
+    if max_message_size.map_or(false, |max_message_size| {
+        res_size as usize > max_message_size
+    }) {
+        tracing::error!("receive len too long: {res_size:?}");
+        return Err(
+            PeerNetError::InvalidMessage.error("len too long", Some(format!("{:?}", res_size)))
+        );
+    }
+    Ok(res_size)
+}
+
+/// What the parsers of the frames hand to the handlers of a connection, see `read_frames`
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameEvent {
+    /// Message delivered whole
+    Message(Vec<u8>),
+    /// Start of a streamed message of this size
+    MessageStart(usize),
+    Chunk(Vec<u8>),
+    MessageEnd,
+}
+
+/// Records what a connection receives, for the fuzz targets and property tests of the parsers
+#[cfg(feature = "testing")]
+#[derive(Default)]
+pub(super) struct FrameRecorder {
+    pub(super) events: std::cell::RefCell<Vec<FrameEvent>>,
+}
+
+#[cfg(feature = "testing")]
+impl ChunkHandler for FrameRecorder {
+    fn message_start(&self, size: usize) -> PeerNetResult<()> {
+        self.events
+            .borrow_mut()
+            .push(FrameEvent::MessageStart(size));
+        Ok(())
+    }
+
+    fn chunk(&self, chunk: Bytes) -> PeerNetResult<()> {
+        self.events
+            .borrow_mut()
+            .push(FrameEvent::Chunk(chunk.to_vec()));
+        Ok(())
+    }
+
+    fn message_end(&self) -> PeerNetResult<()> {
+        self.events.borrow_mut().push(FrameEvent::MessageEnd);
+        Ok(())
+    }
+}
 
-            let mut endpoint_write = endpoint.endpoint_bytes_received.write();
-            *endpoint_write += res_size as u64;
+/// Data received on a connection, given by reads of at most `read_size` bytes
+#[cfg(feature = "testing")]
+struct MemorySource<'a> {
+    data: &'a [u8],
+    read_size: usize,
+    config: TcpConnectionConfig,
+    buffer_pool: SharedBufferPool,
+    bandwidth: Bandwidth,
+}
+
+#[cfg(feature = "testing")]
+impl FrameSource for MemorySource<'_> {
+    fn config(&self) -> &TcpConnectionConfig {
+        &self.config
+    }
+
+    fn buffer_pool(&self) -> &SharedBufferPool {
+        &self.buffer_pool
+    }
+
+    fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    fn set_read_timeout(&mut self, _timeout: Duration) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> std::io::Result<usize> {
+        let len = data.len().min(self.read_size).min(self.data.len());
+        data[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+
+    fn shutdown(&mut self) {}
+
+    fn count_received(&self, nb_frames: u64, nb_bytes: u64) {
+        self.bandwidth.add_received_frames(nb_frames, nb_bytes);
+    }
+}
+
+/// Read the frames of `data` with the parser of the TCP endpoints, as the read loop of a peer
+/// does until an error: `data` is read by at most `read_size` bytes and the messages bigger
+/// than `chunk_size` are streamed. Returns what the handlers got and the error that stopped the
+/// reads, `ConnectionClosed` at the end of `data` or on an empty message.
+#[cfg(feature = "testing")]
+pub fn read_frames(
+    data: &[u8],
+    read_size: usize,
+    max_message_size: Option<usize>,
+    chunk_size: Option<usize>,
+) -> (Vec<FrameEvent>, crate::error::PeerNetErrorData) {
+    let mut source = MemorySource {
+        data,
+        read_size: read_size.max(1),
+        config: TcpConnectionConfig {
+            max_message_size,
+            ..Default::default()
+        },
+        buffer_pool: Arc::new(crate::buffer_pool::BufferPool::new(&Default::default())),
+        bandwidth: Bandwidth::default(),
+    };
+    let recorder = FrameRecorder::default();
+    let error = loop {
+        let received = match chunk_size {
+            Some(chunk_size) => receive_streamed(&mut source, chunk_size, &recorder),
+            None => receive_frame(&mut source).map(Some),
+        };
+        match received {
+            Ok(None) => {}
+            Ok(Some(data)) if data.is_empty() => {
+                break PeerNetError::ConnectionClosed.error("empty message", None)
+            }
+            Ok(Some(data)) => recorder
+                .events
+                .borrow_mut()
+                .push(FrameEvent::Message(data.to_vec())),
+            Err(err) => break err,
         }
+    };
+    (recorder.events.into_inner(), error)
+}
 
-        Ok(data)
+/// Check that sending or receiving a message of `len` bytes stays within the
+/// `handshake_data_limit` of the endpoint, if it's still doing its handshake
+fn check_handshake_data<S: FrameSource>(source: &S, sent: bool, len: usize) -> PeerNetResult<()> {
+    let Some(limit) = source.config().handshake_data_limit else {
+        return Ok(());
+    };
+    let bandwidth = source.bandwidth().snapshot();
+    let done = if sent {
+        bandwidth.bytes_sent
+    } else {
+        bandwidth.bytes_received
+    };
+    if done.saturating_add(len as u64) > limit as u64 {
+        return Err(PeerNetError::HandshakeError.error(
+            "handshake data limit",
+            Some(format!(
+                "{} bytes {} then {}, limit: {}",
+                done,
+                if sent { "sent" } else { "received" },
+                len,
+                limit
+            )),
+        ));
     }
+    Ok(())
+}
+
+fn count_bytes_sent(endpoint: &TcpEndpoint, nb_frames: u64, nb_bytes: u64) {
+    endpoint
+        .total_bandwidth
+        .add_sent_frames(nb_frames, nb_bytes);
+    endpoint
+        .endpoint_bandwidth
+        .add_sent_frames(nb_frames, nb_bytes);
 }
 
-fn set_tcp_stream_config(stream: &TcpStream, config: &TcpTransportConfig) {
+fn count_bytes_received(endpoint: &TcpEndpoint, nb_frames: u64, nb_bytes: u64) {
+    endpoint
+        .total_bandwidth
+        .add_received_frames(nb_frames, nb_bytes);
+    endpoint
+        .endpoint_bandwidth
+        .add_received_frames(nb_frames, nb_bytes);
+}
+
+fn set_tcp_stream_config(
+    stream: &TcpStream,
+    config: &TcpConnectionConfig,
+    keepalive: Option<&TcpKeepalive>,
+    linger: Option<Duration>,
+) {
     if let Err(e) = stream.set_nonblocking(false) {
-        log::error!("Error setting nonblocking: {:?}", e);
+        tracing::error!("Error setting nonblocking: {:?}", e);
+    }
+    if linger.is_some() {
+        if let Err(e) = socket2::SockRef::from(stream).set_linger(linger) {
+            tracing::error!("Error setting linger: {:?}", e);
+        }
     }
-    // if let Err(e) = stream.set_linger(Some(config.write_timeout)) {
-    //     log::error!("Error setting linger: {:?}", e);
-    // }
     if let Err(e) = stream.set_read_timeout(Some(config.read_timeout)) {
-        log::error!("Error setting read timeout: {:?}", e);
+        tracing::error!("Error setting read timeout: {:?}", e);
     }
     if let Err(e) = stream.set_write_timeout(Some(config.write_timeout)) {
-        log::error!("Error setting write timeout: {:?}", e);
+        tracing::error!("Error setting write timeout: {:?}", e);
+    }
+    if let Some(keepalive) = keepalive {
+        if let Err(e) =
+            socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive_params(keepalive))
+        {
+            tracing::error!("Error setting keepalive: {:?}", e);
+        }
     }
 }
 
-fn read_exact_timeout(
-    endpoint: &mut TcpEndpoint,
+fn keepalive_params(keepalive: &TcpKeepalive) -> socket2::TcpKeepalive {
+    let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let params = params.with_interval(keepalive.interval);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+    ))]
+    let params = params.with_retries(keepalive.count);
+    params
+}
+
+fn read_exact_timeout<S: FrameSource>(
+    source: &mut S,
     data: &mut [u8],
     timeout: Duration,
 ) -> PeerNetResult<Duration> {
-    let start_time = Instant::now();
+    let clock = source.config().clock.clone();
+    let start_time = clock.now();
     let mut total_read: usize = 0;
     while total_read < data.len() {
-        let remaining_time = timeout.saturating_sub(start_time.elapsed());
+        let remaining_time =
+            timeout.saturating_sub(clock.now().saturating_duration_since(start_time));
         if remaining_time.is_zero() {
-            log::error!("send read timeout");
+            tracing::error!("send read timeout");
             return Err(PeerNetError::TimeOut.error("timeout read data", None));
         }
 
-        if let Some(ref mut opts) = endpoint.stream_limiter.read_opt {
-            opts.set_timeout(remaining_time);
-        }
-        endpoint
-            .stream_limiter
-            .stream
-            .set_read_timeout(Some(remaining_time))
-            .map_err(|e| {
-                log::error!("error setting read timeout: {e:?}");
-                PeerNetError::CouldNotSetTimeout
-                    .error("error setting read timeout", Some(e.to_string()))
-            })?;
+        source.set_read_timeout(clock.os_timeout(remaining_time))?;
 
-        match endpoint.stream_limiter.read(&mut data[total_read..]) {
+        match source.read(&mut data[total_read..]) {
             Ok(0) => {
-                endpoint.shutdown();
-                log::error!("error reading: len = 0");
+                source.shutdown();
+                tracing::error!("error reading: len = 0");
                 return Err(PeerNetError::ConnectionClosed.error("Receive data read len = 0", None));
             }
             Ok(n) => total_read += n,
@@ -612,7 +1274,7 @@ fn read_exact_timeout(
                     }
                     // Handle other IO errors.
                     _ => {
-                        log::error!("error read data stream: {err:?}");
+                        tracing::error!("error read data stream: {err:?}");
                         return Err(PeerNetError::ReceiveError
                             .error("error read data stream", Some(format!("{:?}", err))));
                     }
@@ -621,36 +1283,90 @@ fn read_exact_timeout(
         }
     }
 
-    Ok(start_time.elapsed())
+    Ok(clock.now().saturating_duration_since(start_time))
 }
 
-fn write_exact_timeout(
-    endpoint: &mut TcpEndpoint,
-    data: &[u8],
-    timeout: Duration,
-) -> PeerNetResult<Duration> {
-    let start_time = Instant::now();
+/// Check that a message can be sent and return the size to write in its frame header
+pub(super) fn frame_len(config: &TcpConnectionConfig, data: &[u8]) -> PeerNetResult<u32> {
     let msg_size: u32 = data.len().try_into().map_err(|_| {
-        log::error!("write error len: {:?}", data.len());
-        PeerNetError::SendError.error("error with send len", Some(format!("{:?}", data.len())))
+        tracing::error!("Send len too long: {:?}", data.len());
+        TcpError::ConnectionError
+            .wrap()
+            .error("send len too long", Some(format!("{:?}", data.len())))
     })?;
 
-    if msg_size > endpoint.config.max_message_size as u32 {
-        log::error!("write len too long: {:?}", data.len());
+    if config.max_message_size.map_or(false, |max_message_size| {
+        msg_size as usize > max_message_size
+    }) {
+        tracing::error!("write len too long: {:?}", data.len());
         return Err(
             PeerNetError::SendError.error("send len too long", Some(format!("{:?}", data.len())))
         );
     }
+    Ok(msg_size)
+}
 
+fn is_small(config: &TcpConnectionConfig, data: &[u8]) -> bool {
+    config
+        .small_message_size
+        .map_or(false, |small_message_size| {
+            data.len() <= small_message_size.min(MAX_SMALL_MESSAGE_SIZE)
+        })
+}
+
+/// Write the frame of a small message from the stack, without taking a buffer from the pool.
+/// It goes through the rate limiter like the other frames.
+fn write_small_frame(
+    endpoint: &mut TcpEndpoint,
+    msg_size: u32,
+    data: &[u8],
+    timeout: Duration,
+) -> PeerNetResult<()> {
+    let mut frame = [0u8; 4 + MAX_SMALL_MESSAGE_SIZE];
+    frame[..4].copy_from_slice(&msg_size.to_be_bytes());
+    frame[4..4 + data.len()].copy_from_slice(data);
+    write_exact_timeout(endpoint, &frame[..4 + data.len()], timeout)?;
+    count_bytes_sent(endpoint, 1, data.len() as u64);
+    Ok(())
+}
+
+/// Write the size of a message and the message as one buffer, so that the rate limiter counts
+/// the frame as a single operation
+fn write_frame(
+    endpoint: &mut TcpEndpoint,
+    msg_size: u32,
+    data: &[u8],
+    timeout: Duration,
+) -> PeerNetResult<()> {
+    let mut frame = endpoint.buffer_pool.get(data.len() + 4);
+    frame.extend_from_slice(&msg_size.to_be_bytes());
+    frame.extend_from_slice(data);
+    let res = write_exact_timeout(endpoint, &frame, timeout);
+    endpoint.buffer_pool.put(frame);
+    res?;
+    count_bytes_sent(endpoint, 1, data.len() as u64);
+    Ok(())
+}
+
+fn write_exact_timeout(
+    endpoint: &mut TcpEndpoint,
+    data: &[u8],
+    timeout: Duration,
+) -> PeerNetResult<()> {
+    let clock = endpoint.config.clock.clone();
+    let start_time = clock.now();
     let mut write_count = 0;
     while write_count < data.len() {
-        let remaining_time = timeout.saturating_sub(start_time.elapsed());
+        let remaining_time =
+            timeout.saturating_sub(clock.now().saturating_duration_since(start_time));
 
         if remaining_time.is_zero() {
-            log::error!("send write timeout");
+            tracing::error!("send write timeout");
             return Err(PeerNetError::TimeOut.error("send write timeout", None));
         }
+        let remaining_time = clock.os_timeout(remaining_time);
 
+        endpoint.update_rate_limit();
         if let Some(ref mut opts) = endpoint.stream_limiter.write_opt {
             opts.set_timeout(remaining_time);
         }
@@ -659,7 +1375,7 @@ fn write_exact_timeout(
             .stream
             .set_write_timeout(Some(remaining_time))
             .map_err(|e| {
-                log::error!("error setting write timeout: {:?}", e);
+                tracing::error!("error setting write timeout: {:?}", e);
                 PeerNetError::CouldNotSetTimeout
                     .error("error setting write timeout", Some(e.to_string()))
             })?;
@@ -667,18 +1383,27 @@ fn write_exact_timeout(
         match endpoint.stream_limiter.write(data[write_count..].as_ref()) {
             Ok(0) => {
                 endpoint.shutdown();
-                log::error!("error on write: len = 0");
+                tracing::error!("error on write: len = 0");
                 return Err(PeerNetError::SendError.error("write len = 0", None));
             }
             Ok(count) => write_count += count,
+            // the timeout is checked at the start of the loop
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
             Err(err) => {
-                log::error!("error on write: {:?}", err);
+                tracing::error!("error on write: {:?}", err);
                 return Err(PeerNetError::SendError.error("error on write", Some(err.to_string())));
             }
         }
     }
 
-    Ok(start_time.elapsed())
+    Ok(())
 }
 
 /// Convert a mio stream to std
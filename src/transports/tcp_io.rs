@@ -0,0 +1,436 @@
+//! Reads and writes of the TCP endpoints, see `PeerNetFeatures::io_uring`
+//!
+//! With the `io_uring` feature on Linux, the reads and writes of the endpoints are submitted to
+//! a single io_uring ring, driven by a thread of its own: it submits the operations of all the
+//! connections in a batch, and wakes up the thread waiting for each operation on its completion.
+//! The ring owns the buffer of an operation and the socket it's done on until its completion,
+//! so nothing the kernel writes to or reads from can be freed or reused before.
+//!
+//! The ring is only an optimization: if it can't be created (old kernel, io_uring forbidden by a
+//! seccomp profile...) or the feature isn't compiled, the endpoints use the blocking calls of
+//! `TcpStream`.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Stream of a `TcpEndpoint`
+pub struct TcpIo {
+    // shared with the operations of the ring until their completion, so that the socket isn't
+    // closed while the kernel uses it
+    stream: Arc<TcpStream>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    ring: Option<ring::Io>,
+}
+
+impl TcpIo {
+    /// Goes through the io_uring ring if `io_uring` is set and the ring is available
+    pub fn new(stream: TcpStream, io_uring: bool) -> TcpIo {
+        #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+        if io_uring {
+            tracing::debug!("io_uring is not available in this build, using blocking calls");
+        }
+        TcpIo {
+            #[cfg(all(feature = "io_uring", target_os = "linux"))]
+            ring: io_uring.then(ring::Io::new).flatten(),
+            // the timeouts already set on the socket, see `set_tcp_stream_config`
+            read_timeout: stream.read_timeout().ok().flatten(),
+            write_timeout: stream.write_timeout().ok().flatten(),
+            stream: Arc::new(stream),
+        }
+    }
+
+    /// Whether the reads and writes go through the io_uring ring
+    pub fn uses_io_uring(&self) -> bool {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        return self.ring.is_some();
+        #[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+        false
+    }
+
+    /// Clone of the stream, going through the ring if this one does
+    pub fn try_clone(&self) -> io::Result<TcpIo> {
+        Ok(TcpIo::new(self.stream.try_clone()?, self.uses_io_uring()))
+    }
+
+    /// The stream, for the event loops. The operations of the ring are all completed when their
+    /// caller returns, so the stream is only shared if this fails
+    pub fn into_stream(self) -> io::Result<TcpStream> {
+        Arc::try_unwrap(self.stream).or_else(|stream| stream.try_clone())
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)?;
+        self.write_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl From<TcpStream> for TcpIo {
+    fn from(stream: TcpStream) -> TcpIo {
+        TcpIo::new(stream, false)
+    }
+}
+
+impl Deref for TcpIo {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsFd for TcpIo {
+    fn as_fd(&self) -> std::os::unix::io::BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
+
+#[cfg(windows)]
+impl std::os::windows::io::AsSocket for TcpIo {
+    fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+        self.stream.as_socket()
+    }
+}
+
+impl Read for TcpIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.recv(&self.stream, buf, self.read_timeout);
+        }
+        (&*self.stream).read(buf)
+    }
+}
+
+impl Write for TcpIo {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.send(&self.stream, buf, self.write_timeout);
+        }
+        (&*self.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.stream).flush()
+    }
+}
+
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod ring {
+    use std::collections::HashMap;
+    use std::io::{self, Write};
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, OnceLock};
+    use std::time::Duration;
+
+    use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+    use io_uring::{opcode, squeue, types, IoUring, Probe};
+
+    /// Entries of the submission queue, the completion queue has twice as many
+    const RING_ENTRIES: u32 = 256;
+    /// Maximum bytes of a read or a write, a bigger one is split by the callers of `Read` and
+    /// `Write` as a short read or write
+    const MAX_OPERATION_SIZE: usize = 256 * 1024;
+    /// `user_data` of the read of the wake up socket, the operations have even ids and their
+    /// timeouts odd ones
+    const WAKE: u64 = u64::MAX;
+    // the values of Linux, not to depend on libc for them
+    const MSG_NOSIGNAL: i32 = 0x4000;
+    const ECANCELED: i32 = 125;
+
+    /// The ring of the process, created at its first use. `None` if it can't be created
+    static DRIVER: OnceLock<Option<Arc<Driver>>> = OnceLock::new();
+
+    enum Operation {
+        Recv,
+        Send,
+    }
+
+    struct Request {
+        operation: Operation,
+        stream: Arc<TcpStream>,
+        // the bytes to send, or the room to receive into
+        buffer: Vec<u8>,
+        timeout: Option<Duration>,
+        completion: Sender<Completion>,
+    }
+
+    struct Completion {
+        result: i32,
+        buffer: Vec<u8>,
+    }
+
+    /// Operation submitted to the ring, kept by the thread of the ring until its completion
+    struct InFlight {
+        _stream: Arc<TcpStream>,
+        buffer: Vec<u8>,
+        _timeout: Option<Box<types::Timespec>>,
+        completion: Sender<Completion>,
+    }
+
+    /// Sends the operations to the thread of the ring
+    struct Driver {
+        requests: Sender<Request>,
+        // a byte written to it wakes up the thread waiting for completions
+        waker: UnixStream,
+    }
+
+    impl Driver {
+        fn get() -> Option<Arc<Driver>> {
+            DRIVER
+                .get_or_init(|| match Driver::start() {
+                    Ok(driver) => Some(Arc::new(driver)),
+                    Err(err) => {
+                        tracing::warn!("io_uring not available, using blocking calls: {:?}", err);
+                        None
+                    }
+                })
+                .clone()
+        }
+
+        fn start() -> io::Result<Driver> {
+            let ring = IoUring::new(RING_ENTRIES)?;
+            let mut probe = Probe::new();
+            ring.submitter().register_probe(&mut probe)?;
+            for code in [
+                opcode::Recv::CODE,
+                opcode::Send::CODE,
+                opcode::Read::CODE,
+                opcode::LinkTimeout::CODE,
+            ] {
+                if !probe.is_supported(code) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("io_uring operation {} not supported", code),
+                    ));
+                }
+            }
+            let (waker, wake_receiver) = UnixStream::pair()?;
+            // a full socket already wakes up the thread
+            waker.set_nonblocking(true)?;
+            let (requests, receiver) = unbounded();
+            std::thread::Builder::new()
+                .name("peernet_io_uring".to_string())
+                .spawn(move || {
+                    if let Err(err) = run(ring, receiver, wake_receiver) {
+                        tracing::error!("io_uring thread stopped: {:?}", err);
+                    }
+                })?;
+            Ok(Driver { requests, waker })
+        }
+
+        /// Submit the operation and wait for its completion. It fails with `TimedOut` if it's
+        /// cancelled by its timeout, as a blocking call with a socket timeout
+        fn run(
+            &self,
+            request: Request,
+            completion: &Receiver<Completion>,
+        ) -> io::Result<Completion> {
+            self.requests.send(request).map_err(|_| stopped())?;
+            if let Err(err) = (&self.waker).write(&[1]) {
+                if err.kind() != io::ErrorKind::WouldBlock {
+                    return Err(err);
+                }
+            }
+            let completion = completion.recv().map_err(|_| stopped())?;
+            match completion.result {
+                res if res >= 0 => Ok(completion),
+                res if -res == ECANCELED => Err(io::ErrorKind::TimedOut.into()),
+                res => Err(io::Error::from_raw_os_error(-res)),
+            }
+        }
+    }
+
+    fn stopped() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "io_uring thread stopped")
+    }
+
+    /// Operations of a `TcpIo` on the ring, with the buffer lent to the ring for each of them
+    pub(super) struct Io {
+        driver: Arc<Driver>,
+        buffer: Vec<u8>,
+        completion: (Sender<Completion>, Receiver<Completion>),
+    }
+
+    impl Io {
+        pub(super) fn new() -> Option<Io> {
+            Some(Io {
+                driver: Driver::get()?,
+                buffer: Vec::new(),
+                completion: bounded(1),
+            })
+        }
+
+        pub(super) fn recv(
+            &mut self,
+            stream: &Arc<TcpStream>,
+            buf: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> io::Result<usize> {
+            let len = buf.len().min(MAX_OPERATION_SIZE);
+            let mut buffer = std::mem::take(&mut self.buffer);
+            buffer.clear();
+            buffer.resize(len, 0);
+            let completion = self.driver.run(
+                Request {
+                    operation: Operation::Recv,
+                    stream: stream.clone(),
+                    buffer,
+                    timeout,
+                    completion: self.completion.0.clone(),
+                },
+                &self.completion.1,
+            )?;
+            let nb_read = completion.result as usize;
+            buf[..nb_read].copy_from_slice(&completion.buffer[..nb_read]);
+            self.buffer = completion.buffer;
+            Ok(nb_read)
+        }
+
+        pub(super) fn send(
+            &mut self,
+            stream: &Arc<TcpStream>,
+            buf: &[u8],
+            timeout: Option<Duration>,
+        ) -> io::Result<usize> {
+            let mut buffer = std::mem::take(&mut self.buffer);
+            buffer.clear();
+            buffer.extend_from_slice(&buf[..buf.len().min(MAX_OPERATION_SIZE)]);
+            let completion = self.driver.run(
+                Request {
+                    operation: Operation::Send,
+                    stream: stream.clone(),
+                    buffer,
+                    timeout,
+                    completion: self.completion.0.clone(),
+                },
+                &self.completion.1,
+            )?;
+            self.buffer = completion.buffer;
+            Ok(completion.result as usize)
+        }
+    }
+
+    /// Loop of the thread of the ring, for the life of the process: submits the requests
+    /// received and sends back their completions
+    fn run(
+        mut ring: IoUring,
+        requests: Receiver<Request>,
+        wake_receiver: UnixStream,
+    ) -> io::Result<()> {
+        let mut in_flight: HashMap<u64, InFlight> = HashMap::new();
+        let mut next_id: u64 = 0;
+        let mut wake_buffer = [0u8; 64];
+        let wake_read = opcode::Read::new(
+            types::Fd(wake_receiver.as_raw_fd()),
+            wake_buffer.as_mut_ptr(),
+            wake_buffer.len() as u32,
+        )
+        .build()
+        .user_data(WAKE);
+        // SAFETY: `wake_buffer` and `wake_receiver` live as long as the ring
+        unsafe { push(&mut ring, &[wake_read.clone()])? };
+        loop {
+            for request in requests.try_iter() {
+                let id = next_id << 1;
+                next_id += 1;
+                let fd = types::Fd(request.stream.as_raw_fd());
+                let mut buffer = request.buffer;
+                let len = buffer.len() as u32;
+                let entry = match request.operation {
+                    Operation::Recv => opcode::Recv::new(fd, buffer.as_mut_ptr(), len).build(),
+                    Operation::Send => opcode::Send::new(fd, buffer.as_ptr(), len)
+                        .flags(MSG_NOSIGNAL)
+                        .build(),
+                };
+                let timeout = request.timeout.map(|timeout| Box::new(timeout.into()));
+                // SAFETY: the buffer, the socket and the timeout are kept in `in_flight` until
+                // the completion of the operation. Moving the `Vec` doesn't move its heap memory
+                unsafe {
+                    match &timeout {
+                        Some(timespec) => push(
+                            &mut ring,
+                            &[
+                                entry.flags(squeue::Flags::IO_LINK).user_data(id),
+                                opcode::LinkTimeout::new(&**timespec)
+                                    .build()
+                                    .user_data(id | 1),
+                            ],
+                        )?,
+                        None => push(&mut ring, &[entry.user_data(id)])?,
+                    }
+                }
+                in_flight.insert(
+                    id,
+                    InFlight {
+                        _stream: request.stream,
+                        buffer,
+                        _timeout: timeout,
+                        completion: request.completion,
+                    },
+                );
+            }
+            match ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+            let mut rearm_wake = false;
+            let completions: Vec<(u64, i32)> = ring
+                .completion()
+                .map(|completion| (completion.user_data(), completion.result()))
+                .collect();
+            for (user_data, result) in completions {
+                if user_data == WAKE {
+                    rearm_wake = true;
+                } else if user_data & 1 == 0 {
+                    if let Some(operation) = in_flight.remove(&user_data) {
+                        let InFlight {
+                            buffer, completion, ..
+                        } = operation;
+                        // the socket is released before waking up the caller, see
+                        // `TcpIo::into_stream`. The caller may be gone, its buffer is dropped
+                        let _ = completion.send(Completion { result, buffer });
+                    }
+                }
+            }
+            if rearm_wake {
+                // SAFETY: as the first read of the wake up socket
+                unsafe { push(&mut ring, &[wake_read.clone()])? };
+            }
+        }
+    }
+
+    /// Push `entries` to the submission queue, submitting the queue first if it has no room for
+    /// all of them, so that a linked timeout is in the same submission as its operation
+    ///
+    /// # Safety
+    /// The memory used by `entries` must stay valid until their completion
+    unsafe fn push(ring: &mut IoUring, entries: &[squeue::Entry]) -> io::Result<()> {
+        loop {
+            let mut submission = ring.submission();
+            if submission.capacity() - submission.len() >= entries.len() {
+                return submission
+                    .push_multiple(entries)
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+            }
+            drop(submission);
+            ring.submit()?;
+        }
+    }
+}
@@ -0,0 +1,157 @@
+#![cfg(feature = "async")]
+mod util;
+use std::future::Future;
+use std::time::Duration;
+
+use futures::StreamExt;
+use peernet::async_manager::{AsyncMessagesHandler, AsyncPeerNetManager, MessagesStream};
+use peernet::config::{PeerNetConfigurationBuilder, TcpSettings};
+use peernet::error::PeerNetError;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+
+use crate::util::{
+    get_tcp_port, DefaultContext, DefaultInitConnection, DefaultPeerId, RawSerializer,
+};
+
+fn async_manager(
+    send_data_channel_size: usize,
+) -> (
+    AsyncPeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection>,
+    MessagesStream<DefaultPeerId>,
+) {
+    async_manager_with_tcp(send_data_channel_size, TcpSettings::default())
+}
+
+fn async_manager_with_tcp(
+    send_data_channel_size: usize,
+    tcp: TcpSettings,
+) -> (
+    AsyncPeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection>,
+    MessagesStream<DefaultPeerId>,
+) {
+    let (message_handler, messages) = AsyncMessagesHandler::new(100);
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let config = PeerNetConfigurationBuilder::new(context, DefaultInitConnection, message_handler)
+        .set_send_data_channel_size(send_data_channel_size)
+        .set_tcp_settings(tcp)
+        .build()
+        .unwrap();
+    (AsyncPeerNetManager::new(config).unwrap(), messages)
+}
+
+/// Run `future` on an executor of its own, fails if it doesn't complete in 5 seconds
+fn block_on_timeout<F: Future + Send + 'static>(future: F) -> F::Output
+where
+    F::Output: Send + 'static,
+{
+    let (sender, receiver) = crossbeam::channel::bounded(1);
+    std::thread::spawn(move || sender.send(futures::executor::block_on(future)));
+    receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+}
+
+#[test]
+fn async_connect_and_send() {
+    let (mut manager, messages) = async_manager(1);
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager
+        .manager
+        .start_listener(TransportType::Tcp, addr)
+        .unwrap();
+
+    // small send channels, some messages wait for room in them
+    let (mut manager2, _messages2) = async_manager(1);
+    let peer_id =
+        block_on_timeout(manager2.try_connect(TransportType::Tcp, addr, Duration::from_secs(3)))
+            .unwrap();
+    assert!(manager2
+        .manager
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&peer_id));
+    let sends: Vec<_> = (0..10)
+        .map(|i| manager2.send_to(&peer_id, &RawSerializer, vec![i; 10], false))
+        .collect();
+    block_on_timeout(async move {
+        for send in sends {
+            send.await.unwrap();
+        }
+    });
+
+    let received: Vec<_> = block_on_timeout(messages.take(10).collect::<Vec<_>>());
+    assert_eq!(
+        received
+            .iter()
+            .map(|(_, data)| data.to_vec())
+            .collect::<Vec<_>>(),
+        (0..10).map(|i| vec![i; 10]).collect::<Vec<_>>()
+    );
+    // all from the peer we accepted
+    let active_connections = manager.manager.active_connections.read();
+    assert!(received
+        .iter()
+        .all(|(id, _)| active_connections.connections.contains_key(id)));
+    drop(active_connections);
+
+    manager
+        .manager
+        .stop_listener(TransportType::Tcp, addr)
+        .unwrap();
+}
+
+#[test]
+fn async_errors() {
+    let (mut manager, _messages) = async_manager(10);
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+
+    // nobody listens
+    assert!(block_on_timeout(manager.try_connect(
+        TransportType::Tcp,
+        addr,
+        Duration::from_secs(1)
+    ))
+    .is_err());
+    assert!(block_on_timeout(manager.send_to(
+        &DefaultPeerId::generate(),
+        &RawSerializer,
+        vec![1, 2, 3],
+        false
+    ))
+    .is_err());
+}
+
+#[test]
+fn async_send_too_large() {
+    let (mut manager, _messages) = async_manager(10);
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager
+        .manager
+        .start_listener(TransportType::Tcp, addr)
+        .unwrap();
+
+    let (mut manager2, _messages2) = async_manager_with_tcp(
+        10,
+        TcpSettings {
+            max_message_size: Some(100),
+            ..Default::default()
+        },
+    );
+    let peer_id =
+        block_on_timeout(manager2.try_connect(TransportType::Tcp, addr, Duration::from_secs(3)))
+            .unwrap();
+    let err = block_on_timeout(manager2.send_to(&peer_id, &RawSerializer, vec![0; 101], false))
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::MessageTooLarge);
+    block_on_timeout(manager2.send_to(&peer_id, &RawSerializer, vec![0; 100], false)).unwrap();
+
+    manager
+        .manager
+        .stop_listener(TransportType::Tcp, addr)
+        .unwrap();
+}
@@ -0,0 +1,127 @@
+// Timeouts followed on a mock clock, advanced by the tests instead of sleeping
+#![cfg(feature = "testing")]
+mod util;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use peernet::clock::{Clock, MockClock};
+use peernet::config::{PeerNetConfigurationBuilder, PeerNetFeatures};
+use peernet::internal_handlers::peer_management::{Announcement, PeerDB};
+use peernet::internal_handlers::supervisor::{ConnectionSupervisor, DialBackoff, SupervisorConfig};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+use util::{
+    eventually, get_tcp_port, DefaultContext, DefaultInitConnection, DefaultMessagesHandler,
+    DefaultPeerId,
+};
+
+type Manager =
+    PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, DefaultMessagesHandler>;
+
+fn manager(features: PeerNetFeatures) -> Manager {
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        DefaultMessagesHandler {},
+    )
+    .set_optional_features(features)
+    .build()
+    .unwrap();
+    PeerNetManager::new(config).unwrap()
+}
+
+#[test]
+fn mock_clock_evicts_stale_handshakes() {
+    let clock = Arc::new(MockClock::new());
+    let manager = manager(
+        PeerNetFeatures::default()
+            .set_clock(clock.clone())
+            .set_handshake_timeout(Duration::from_secs(60)),
+    );
+    {
+        // left by handshake threads that died
+        let mut active_connections = manager.active_connections.write();
+        let now = active_connections.clock.now();
+        active_connections
+            .in_connection_queue
+            .insert("127.0.0.1:10001".parse().unwrap(), now);
+    }
+    let queued = || manager.active_connections.read().in_connection_queue.len();
+
+    // checked every 30s, still younger than the timeout at the first check
+    clock.advance(Duration::from_secs(30));
+    assert!(!eventually(|| queued() == 0));
+    clock.advance(Duration::from_secs(30));
+    assert!(eventually(|| queued() == 0));
+    assert_eq!(manager.active_connections.read().nb_evicted_handshakes, 1);
+}
+
+#[test]
+fn mock_clock_supervisor_backoff() {
+    let clock = Arc::new(MockClock::new());
+    let manager = Arc::new(Mutex::new(manager(
+        PeerNetFeatures::default().set_clock(clock.clone()),
+    )));
+
+    // nothing listens on the address of the peer
+    let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+        .parse()
+        .unwrap();
+    let peer_db = Arc::new(RwLock::new(PeerDB::default()));
+    peer_db.write().insert_announcement(
+        DefaultPeerId::generate(),
+        Announcement {
+            listeners: HashMap::from([(addr, TransportType::Tcp)]),
+            timestamp: 0,
+            signature: Vec::new(),
+        },
+    );
+    let interval = Duration::from_secs(1);
+    let supervisor = ConnectionSupervisor::start(
+        manager,
+        peer_db,
+        SupervisorConfig {
+            interval,
+            connect_timeout: Duration::from_secs(1),
+            retry_after: Duration::ZERO,
+            target_out_connections: HashMap::default(),
+            default_target_out_connections: 1,
+            rotation: None,
+            backoff: Some(DialBackoff {
+                initial: Duration::from_secs(60),
+                max: Duration::from_secs(600),
+                max_failures: None,
+            }),
+        },
+    )
+    .unwrap();
+    let nb_failures = || {
+        supervisor
+            .dial_failures()
+            .get(&addr)
+            .map_or(0, |failures| failures.nb_failures)
+    };
+    // a check at each interval, the refused dial is recorded at the next one
+    let tick_until = |nb: u32| {
+        eventually(|| {
+            clock.advance(interval);
+            nb_failures() == nb
+        })
+    };
+
+    assert!(tick_until(1));
+    let first_retry_at = supervisor.dial_failures()[&addr].retry_at.unwrap();
+    assert!(first_retry_at <= clock.now() + Duration::from_secs(60));
+    assert!(tick_until(2));
+    // not dialed again before the backoff, waited without sleeping
+    assert!(clock.now() >= first_retry_at);
+    let retry_at = supervisor.dial_failures()[&addr].retry_at.unwrap();
+    assert!(retry_at >= first_retry_at + Duration::from_secs(120));
+    supervisor.stop();
+}
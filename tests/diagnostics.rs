@@ -0,0 +1,30 @@
+#![cfg(feature = "diagnostics")]
+mod util;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use peernet::error::PeerNetError;
+use peernet::internal_handlers::echo::{echo_ping, EchoServer};
+
+use crate::util::get_tcp_port;
+
+#[test]
+fn echo_ping_server() {
+    let server = EchoServer::start("127.0.0.1:0".parse().unwrap()).unwrap();
+    let before = SystemTime::now();
+    let reply = echo_ping(server.local_addr(), Duration::from_secs(1)).unwrap();
+    assert!(reply.rtt < Duration::from_secs(1));
+    assert!(reply.remote_time >= before - Duration::from_millis(1));
+    assert!(reply.remote_time <= SystemTime::now());
+    server.stop();
+
+    // nothing answers on the port once stopped
+    let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+        .parse()
+        .unwrap();
+    let err = echo_ping(addr, Duration::from_millis(300)).unwrap_err();
+    assert!(matches!(
+        err.error_type(),
+        PeerNetError::TimeOut | PeerNetError::ReceiveError
+    ));
+}
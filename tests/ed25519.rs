@@ -0,0 +1,118 @@
+#![cfg(feature = "ed25519")]
+mod util;
+
+use peernet::config::PeerNetConfigurationBuilder;
+use peernet::ed25519::{
+    Ed25519Context, Ed25519Hooks, Ed25519InitConnection, Ed25519KeyPair, Ed25519PeerId,
+};
+use peernet::error::PeerNetResult;
+use peernet::internal_handlers::peer_management::{Announcement, PeerManagementHooks};
+use peernet::messages::{Bytes, MessagesHandler};
+use peernet::network_manager::PeerNetManager;
+use peernet::transports::TransportType;
+
+use crate::util::{connect_tcp, eventually, get_tcp_port};
+
+#[derive(Clone)]
+struct EmptyMessagesHandler;
+
+impl MessagesHandler<Ed25519PeerId> for EmptyMessagesHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &Ed25519PeerId) -> PeerNetResult<()> {
+        Ok(())
+    }
+}
+
+fn ed25519_manager(
+    keypair: Ed25519KeyPair,
+) -> PeerNetManager<Ed25519PeerId, Ed25519Context, Ed25519InitConnection, EmptyMessagesHandler> {
+    let config = PeerNetConfigurationBuilder::new(
+        Ed25519Context {
+            keypair: keypair.clone(),
+        },
+        Ed25519InitConnection { keypair },
+        EmptyMessagesHandler,
+    )
+    .build()
+    .unwrap();
+    PeerNetManager::new(config).unwrap()
+}
+
+#[test]
+fn sign_and_verify() {
+    let keypair = Ed25519KeyPair::generate();
+    let peer_id = keypair.peer_id();
+    let signature = keypair.sign(b"data");
+    assert!(peer_id.verify(b"data", &signature).is_ok());
+    assert!(peer_id.verify(b"other data", &signature).is_err());
+    assert!(Ed25519KeyPair::generate()
+        .peer_id()
+        .verify(b"data", &signature)
+        .is_err());
+
+    // the key can be saved and loaded
+    let loaded = Ed25519KeyPair::from_bytes(&keypair.to_bytes());
+    assert_eq!(loaded.peer_id(), peer_id);
+
+    let hooks = Ed25519Hooks { keypair };
+    let mut buffer = Vec::new();
+    hooks.serialize_peer_id(&peer_id, &mut buffer).unwrap();
+    assert_eq!(hooks.deserialize_peer_id(&buffer).unwrap(), (peer_id, 32));
+    assert!(hooks.deserialize_peer_id(&buffer[..31]).is_err());
+
+    // an announcement is only valid with the signature of its domain: the same content signed
+    // as a handshake challenge is refused
+    let announcement = Announcement::with_timestamp(Default::default(), 5, &hooks).unwrap();
+    assert!(announcement.verify(&peer_id, &hooks).is_ok());
+    let mut content = 0u16.to_be_bytes().to_vec();
+    content.extend_from_slice(&5u64.to_be_bytes());
+    let forged = Announcement {
+        signature: hooks.keypair.sign(&content).to_vec(),
+        ..announcement
+    };
+    assert!(forged.verify(&peer_id, &hooks).is_err());
+}
+
+#[test]
+fn two_peers_tcp_ed25519() {
+    let keypair1 = Ed25519KeyPair::generate();
+    let keypair2 = Ed25519KeyPair::generate();
+    let id1 = keypair1.peer_id();
+    let id2 = keypair2.peer_id();
+    let mut manager = ed25519_manager(keypair1);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = ed25519_manager(keypair2);
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    // each peer knows the other one by its public key
+    assert!(eventually(|| manager
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id2)));
+    assert!(manager2
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id1));
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn ed25519_keys_redacted() {
+    let keypair = Ed25519KeyPair::from_bytes(&[7; 32]);
+    let debug = format!("{:?}", Ed25519Context { keypair });
+    assert!(debug.contains("<redacted>"));
+    assert!(!debug.contains(&format!("{:?}", [7u8; 32])[1..10]));
+}
@@ -0,0 +1,269 @@
+mod util;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use peernet::bandwidth::{BandwidthRates, BandwidthSnapshot};
+use peernet::config::{
+    PeerIdRules, PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures, QuicSettings,
+    TcpSettings, TrustedPeers,
+};
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::messages::{Bytes, MessagesHandler};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::InitConnectionHandler;
+use peernet::peer_id::PeerId;
+use peernet::transports::{
+    endpoint::{Endpoint, EndpointImpl},
+    TransportType, AUTHENTICATION_TAG_SIZE, SESSION_KEY_SIZE,
+};
+
+use crate::util::{connect_tcp, get_tcp_port, DefaultContext, DefaultPeerId, RawSerializer};
+
+/// Each peer sends in clear the key it will encrypt with, only to test the encryption layer
+#[derive(Clone)]
+struct ClearKeysInitConnection;
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, ForwardMessagesHandler>
+    for ClearKeysInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _keypair: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<std::net::SocketAddr, TransportType>,
+        _messages_handler: ForwardMessagesHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        let send_key: [u8; SESSION_KEY_SIZE] = rand::random();
+        endpoint.send::<DefaultPeerId>(&send_key)?;
+        let receive_key = endpoint.receive::<DefaultPeerId>()?;
+        let receive_key = receive_key[..]
+            .try_into()
+            .map_err(|_| PeerNetError::HandshakeError.error("receive key", None))?;
+        endpoint.install_encryption(&send_key, receive_key)?;
+        Ok(DefaultPeerId::generate())
+    }
+}
+
+/// Wrapper of an endpoint XORing the data with a byte, installed after the key exchange
+struct XorEndpoint {
+    inner: Endpoint,
+    key: u8,
+}
+
+impl EndpointImpl for XorEndpoint {
+    fn get_target_addr(&self) -> &std::net::SocketAddr {
+        self.inner.get_target_addr()
+    }
+
+    fn get_data_channel_size(&self) -> usize {
+        self.inner.get_data_channel_size()
+    }
+
+    fn try_clone(&self) -> PeerNetResult<Box<dyn EndpointImpl>> {
+        Ok(Box::new(XorEndpoint {
+            inner: self.inner.try_clone()?,
+            key: self.key,
+        }))
+    }
+
+    fn send(&mut self, data: &[u8]) -> PeerNetResult<()> {
+        let data: Vec<u8> = data.iter().map(|byte| byte ^ self.key).collect();
+        self.inner.send::<DefaultPeerId>(&data)
+    }
+
+    fn receive(&mut self) -> PeerNetResult<Bytes> {
+        let data = self.inner.receive::<DefaultPeerId>()?;
+        Ok(data.iter().map(|byte| byte ^ self.key).collect())
+    }
+
+    fn shutdown(&mut self) {
+        self.inner.shutdown()
+    }
+
+    fn get_bandwidth(&self) -> BandwidthSnapshot {
+        self.inner.get_bandwidth()
+    }
+
+    fn get_rates(&self) -> BandwidthRates {
+        self.inner.get_rates()
+    }
+}
+
+/// Encryption with the exchanged keys then the XOR wrapper on top of it
+#[derive(Clone)]
+struct XorInitConnection;
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, ForwardMessagesHandler>
+    for XorInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        keypair: &DefaultContext,
+        endpoint: &mut Endpoint,
+        listeners: &HashMap<std::net::SocketAddr, TransportType>,
+        messages_handler: ForwardMessagesHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        let peer_id = ClearKeysInitConnection.perform_handshake(
+            keypair,
+            endpoint,
+            listeners,
+            messages_handler,
+        )?;
+        *endpoint = Endpoint::Custom(Box::new(XorEndpoint {
+            inner: endpoint.try_clone()?,
+            key: 0x5a,
+        }));
+        Ok(peer_id)
+    }
+}
+
+#[derive(Clone)]
+struct ForwardMessagesHandler {
+    received: Sender<Bytes>,
+}
+
+impl MessagesHandler<DefaultPeerId> for ForwardMessagesHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send(data)
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+fn encrypted_config<
+    I: InitConnectionHandler<DefaultPeerId, DefaultContext, ForwardMessagesHandler>,
+>(
+    received: Sender<Bytes>,
+    init_connection_handler: I,
+) -> PeerNetConfiguration<DefaultPeerId, DefaultContext, I, ForwardMessagesHandler> {
+    PeerNetConfiguration {
+        context: DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        max_in_connections: Some(10),
+        init_connection_handler,
+        optional_features: PeerNetFeatures::default(),
+        message_handler: ForwardMessagesHandler { received },
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    }
+}
+
+#[test]
+fn two_peers_tcp_encrypted() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager =
+        PeerNetManager::new(encrypted_config(sender.clone(), ClearKeysInitConnection)).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 =
+        PeerNetManager::new(encrypted_config(sender, ClearKeysInitConnection)).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for message in [vec![1, 2, 3], vec![4, 5]] {
+            connection
+                .send_channels
+                .send(&RawSerializer, message, false)
+                .unwrap();
+        }
+    }
+    // the nonces of both sides stay in sync over several messages
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![1, 2, 3]
+    );
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![4, 5]
+    );
+    // key + messages with their authentication tag
+    assert_eq!(
+        manager.get_total_bytes_received(),
+        (SESSION_KEY_SIZE + 3 + 2 + 2 * AUTHENTICATION_TAG_SIZE) as u64
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn two_peers_tcp_custom_endpoint() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager =
+        PeerNetManager::new(encrypted_config(sender.clone(), XorInitConnection)).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(encrypted_config(sender, XorInitConnection)).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    assert_eq!(manager2.dump_state().peers[0].transport, "custom");
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for message in [vec![1, 2, 3], vec![4, 5]] {
+            connection
+                .send_channels
+                .send(&RawSerializer, message, false)
+                .unwrap();
+        }
+    }
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![1, 2, 3]
+    );
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![4, 5]
+    );
+    // the bandwidth of the wrapped endpoint is still counted
+    assert_eq!(
+        manager.get_total_bytes_received(),
+        (SESSION_KEY_SIZE + 3 + 2 + 2 * AUTHENTICATION_TAG_SIZE) as u64
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
@@ -0,0 +1,94 @@
+#![cfg(all(feature = "io_uring", target_os = "linux"))]
+mod util;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use peernet::config::PeerNetFeatures;
+use peernet::transports::{TcpIo, TransportType};
+
+use crate::util::{
+    connect_tcp, default_config, default_manager, eventually, listen_tcp, RawSerializer,
+};
+
+fn tcp_io_pair() -> (TcpIo, TcpIo) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (TcpIo::new(client, true), TcpIo::new(server, true))
+}
+
+#[test]
+fn tcp_io_ring() {
+    let (mut client, mut server) = tcp_io_pair();
+    assert!(client.uses_io_uring());
+    assert!(client.try_clone().unwrap().uses_io_uring());
+    assert!(!TcpIo::from(server.try_clone().unwrap().into_stream().unwrap()).uses_io_uring());
+
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    server.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // cancelled by its timeout as a blocking read with a socket timeout
+    server
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let start = Instant::now();
+    let err = server.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // closed by the other side
+    client.shutdown(std::net::Shutdown::Both).unwrap();
+    assert_eq!(server.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn tcp_io_ring_shared() {
+    // the reads of all the connections wait on the ring at the same time
+    let pairs: Vec<(TcpIo, TcpIo)> = (0..50).map(|_| tcp_io_pair()).collect();
+    let readers: Vec<_> = pairs
+        .into_iter()
+        .enumerate()
+        .map(|(i, (mut client, mut server))| {
+            let reader = std::thread::spawn(move || {
+                let mut buf = vec![0u8; 100_000];
+                server.read_exact(&mut buf).unwrap();
+                assert!(buf.iter().all(|byte| *byte == i as u8));
+            });
+            (client.write_all(&vec![i as u8; 100_000]), reader, client)
+        })
+        .collect();
+    for (written, reader, _client) in readers {
+        written.unwrap();
+        reader.join().unwrap();
+    }
+}
+
+#[test]
+fn two_peers_io_uring() {
+    let config =
+        || default_config().set_optional_features(PeerNetFeatures::default().set_io_uring(true));
+    let mut manager = default_manager(config());
+    let addr = listen_tcp(&mut manager);
+
+    let mut manager2 = default_manager(config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i; 1000], false)
+                .unwrap();
+        }
+    }
+    assert!(eventually(
+        || manager.get_total_bytes_received() == 10 * 1000
+    ));
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
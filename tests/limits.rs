@@ -1,43 +1,46 @@
 // All the tests related to the limitations on the system.
 mod util;
+use crossbeam::channel::{unbounded, Sender};
 use parking_lot::RwLock;
 use peernet::{
-    config::{PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures},
-    network_manager::PeerNetManager,
-    peer::InitConnectionHandler,
+    bandwidth::{Bandwidth, BandwidthRates},
+    config::{
+        ConfigError, DiagnosticEvent, DiagnosticsSink, DialPacing, HandshakeWorkers, MemoryAction,
+        MemoryBudget, PeerIdRules, PeerNetCategoryInfo, PeerNetConfiguration,
+        PeerNetConfigurationBuilder, PeerNetFeatures, ProofOfWork, QuicSettings, TcpReactor,
+        TcpSettings, TransportSettings, TrustedPeer, TrustedPeers,
+    },
+    context::Context,
+    error::{PeerNetError, PeerNetResult},
+    eviction::{
+        EvictionCandidate, EvictionPolicy, LowestScore, MostRepresentedCategory, MostRepresentedIp,
+        OldestIdle,
+    },
+    gater::{ConnectionGater, DefaultConnectionGater},
+    history::{ConnectionEventKind, DisconnectReason},
+    network_manager::{ActiveConnections, PeerNetManager},
+    peer::{InitConnectionHandler, PeerConnectionType},
     peer_id::PeerId,
-    transports::{endpoint::Endpoint, TcpConnectionConfig, TcpEndpoint, TransportType},
+    rejection::{Rejection, RejectionReason},
+    transports::{
+        endpoint::Endpoint, SharedRateLimit, TcpConnectionConfig, TcpEndpoint, TransportType,
+    },
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
     str::FromStr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use stream_limiter::Limiter;
 
-// use peernet::types::KeyPair;
-
-use util::{DefaultContext, DefaultMessagesHandler, DefaultPeerId};
-
-use crate::util::get_tcp_port;
+use util::{
+    create_clients, eventually, DefaultContext, DefaultInitConnection, DefaultMessagesHandler,
+    DefaultPeerId, RawSerializer,
+};
 
-#[derive(Clone)]
-pub struct DefaultInitConnection;
-impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
-    for DefaultInitConnection
-{
-    fn perform_handshake(
-        &mut self,
-        _keypair: &DefaultContext,
-        _endpoint: &mut peernet::transports::endpoint::Endpoint,
-        _listeners: &std::collections::HashMap<std::net::SocketAddr, TransportType>,
-        _messages_handler: DefaultMessagesHandler,
-    ) -> peernet::error::PeerNetResult<DefaultPeerId> {
-        Ok(DefaultPeerId::generate())
-    }
-}
+use crate::util::{connect_tcp, get_tcp_port};
 
 #[test]
 fn check_multiple_connection_refused() {
@@ -46,24 +49,35 @@ fn check_multiple_connection_refused() {
     };
 
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         send_data_channel_size: 1000,
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 1,
-            max_in_connections_per_ip: 1,
-            max_out_connections: 10,
+            max_in_connections: Some(1),
+            max_in_connections_per_ip: Some(1),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -72,7 +86,7 @@ fn check_multiple_connection_refused() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -86,24 +100,35 @@ fn check_multiple_connection_refused() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context2,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         send_data_channel_size: 1000,
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -112,7 +137,7 @@ fn check_multiple_connection_refused() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager2
         .try_connect(
             TransportType::Tcp,
@@ -126,24 +151,35 @@ fn check_multiple_connection_refused() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context3,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         send_data_channel_size: 1000,
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
     let mut manager3: PeerNetManager<
@@ -151,7 +187,7 @@ fn check_multiple_connection_refused() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager3
         .try_connect(
             TransportType::Tcp,
@@ -176,24 +212,35 @@ fn check_too_much_in_refuse() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 1,
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        max_in_connections: Some(1),
         send_data_channel_size: 1000,
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 10,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
     let mut manager: PeerNetManager<
@@ -201,7 +248,7 @@ fn check_too_much_in_refuse() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -215,24 +262,35 @@ fn check_too_much_in_refuse() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context2,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         send_data_channel_size: 1000,
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -241,7 +299,7 @@ fn check_too_much_in_refuse() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager2
         .try_connect(
             TransportType::Tcp,
@@ -255,24 +313,35 @@ fn check_too_much_in_refuse() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context3,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         send_data_channel_size: 1000,
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -281,7 +350,7 @@ fn check_too_much_in_refuse() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager3
         .try_connect(
             TransportType::Tcp,
@@ -311,31 +380,45 @@ fn check_multiple_connection_refused_in_category() {
         (
             vec![IpAddr::from_str("127.0.0.1").unwrap()],
             PeerNetCategoryInfo {
-                max_in_connections: 1,
-                max_in_connections_per_ip: 1,
-                max_out_connections: 1,
+                max_in_connections: Some(1),
+                max_in_connections_per_ip: Some(1),
+                max_out_connections: Some(1),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
             },
         ),
     );
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         send_data_channel_size: 1000,
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories,
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 0,
-            max_in_connections_per_ip: 0,
-            max_out_connections: 0,
+            max_in_connections: Some(0),
+            max_in_connections_per_ip: Some(0),
+            max_out_connections: Some(0),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -344,7 +427,7 @@ fn check_multiple_connection_refused_in_category() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     let port = get_tcp_port(10000..u16::MAX);
     manager
         .start_listener(
@@ -357,24 +440,35 @@ fn check_multiple_connection_refused_in_category() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context2,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         send_data_channel_size: 1000,
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -383,7 +477,7 @@ fn check_multiple_connection_refused_in_category() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager2
         .try_connect(
             TransportType::Tcp,
@@ -397,24 +491,35 @@ fn check_multiple_connection_refused_in_category() {
         our_id: DefaultPeerId::generate(),
     };
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context: context3,
-        max_in_connections: 10,
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
         send_data_channel_size: 1000,
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -423,7 +528,7 @@ fn check_multiple_connection_refused_in_category() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     manager3
         .try_connect(
             TransportType::Tcp,
@@ -449,23 +554,34 @@ fn max_message_size() {
     };
 
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(40),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
-        max_message_size: 40,
-        rate_time_window: Duration::from_secs(1),
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
         send_data_channel_size: 1000,
     };
@@ -475,7 +591,7 @@ fn max_message_size() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -489,26 +605,29 @@ fn max_message_size() {
     let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
     let stream = std::net::TcpStream::connect(addr).unwrap();
 
+    let config = TcpConnectionConfig {
+        rate_time_window: Duration::from_secs(1),
+        rate_bucket_size: 60 * 1024,
+        rate_limit: 10000,
+        data_channel_size: 1000,
+        max_message_size: Some(10),
+        read_timeout: Duration::from_secs(10),
+        write_timeout: Duration::from_secs(10),
+        small_message_size: None,
+        handshake_data_limit: None,
+        ..Default::default()
+    };
     let mut endpoint = Endpoint::Tcp(TcpEndpoint {
-        config: TcpConnectionConfig {
-            rate_time_window: Duration::from_secs(1),
-            rate_bucket_size: 60 * 1024,
-            rate_limit: 10000,
-            data_channel_size: 1000,
-            max_message_size: 10,
-            read_timeout: Duration::from_secs(10),
-            write_timeout: Duration::from_secs(10),
-        },
+        rate_limit: SharedRateLimit::new(config.clone().into()),
+        config,
         address: format!("127.0.0.1:{port}").parse().unwrap(),
-        stream_limiter: Limiter::new(stream, None, None),
-        total_bytes_received: Arc::new(RwLock::new(0)),
-        total_bytes_sent: Arc::new(RwLock::new(0)),
-        endpoint_bytes_received: Arc::new(RwLock::new(0)),
-        endpoint_bytes_sent: Arc::new(RwLock::new(0)),
+        stream_limiter: Limiter::new(stream.into(), None, None),
+        total_bandwidth: Bandwidth::new_shared(),
+        endpoint_bandwidth: Bandwidth::new_shared(),
+        buffer_pool: Default::default(),
     });
 
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    assert!(manager.nb_in_connections().eq(&1));
+    assert!(eventually(|| manager.nb_in_connections() == 1));
 
     let handle = std::thread::spawn(move || {
         std::thread::sleep(std::time::Duration::from_millis(200));
@@ -528,7 +647,8 @@ fn max_message_size() {
     let result = endpoint.receive::<DefaultPeerId>();
 
     let err = result.unwrap_err();
-    assert!(err.to_string().contains("len too long"));
+    assert_eq!(err.error_type(), &PeerNetError::InvalidMessage);
+    assert_eq!(err.location(), "len too long");
 
     std::thread::sleep(std::time::Duration::from_secs(1));
 
@@ -542,30 +662,45 @@ fn max_message_size() {
         .unwrap();
 }
 
+#[cfg(feature = "testing")]
 #[test]
 fn send_timeout() {
+    use peernet::clock::MockClock;
+
+    let clock = Arc::new(MockClock::new());
     let context = DefaultContext {
         our_id: DefaultPeerId::generate(),
     };
 
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(9000000),
+            rate_limit: 1000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
-        optional_features: PeerNetFeatures::default(),
+        optional_features: PeerNetFeatures::default().set_clock(clock.clone()),
         message_handler: DefaultMessagesHandler {},
-        max_message_size: 9000000,
-        rate_time_window: Duration::from_secs(1),
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 1000,
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
         send_data_channel_size: 1000,
     };
@@ -575,7 +710,7 @@ fn send_timeout() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -590,42 +725,206 @@ fn send_timeout() {
     // add connection to the manager
     let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
     let stream = std::net::TcpStream::connect(addr).unwrap();
+    let config = TcpConnectionConfig {
+        rate_time_window: Duration::from_secs(1),
+        rate_bucket_size: 60 * 1024,
+        rate_limit: 100,
+        data_channel_size: 1000,
+        max_message_size: Some(9000000),
+        read_timeout: Duration::from_secs(10),
+        write_timeout: Duration::from_secs(10),
+        small_message_size: None,
+        handshake_data_limit: None,
+        ..Default::default()
+    };
     let _endpoint = Endpoint::Tcp(TcpEndpoint {
-        config: TcpConnectionConfig {
+        rate_limit: SharedRateLimit::new(config.clone().into()),
+        config,
+        address: format!("127.0.0.1:{port}").parse().unwrap(),
+        stream_limiter: Limiter::new(stream.into(), None, None),
+        total_bandwidth: Bandwidth::new_shared(),
+        endpoint_bandwidth: Bandwidth::new_shared(),
+        buffer_pool: Default::default(),
+    });
+
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    let (result_tx, result_rx) = unbounded();
+    let sender = std::thread::spawn(move || {
+        if let Some((_peer_id, conn)) = manager
+            .active_connections
+            .write()
+            .connections
+            .iter_mut()
+            .next()
+        {
+            // send msg with large data that trigger the timeout
+            let result = conn
+                .endpoint
+                .send_timeout::<DefaultPeerId>(&[0; 9000000], Duration::from_millis(200));
+            result_tx.send(result).unwrap();
+        }
+        manager
+    });
+    // the send waits for the rate limit until its timeout passed on the clock
+    assert!(eventually(|| {
+        clock.advance(Duration::from_millis(50));
+        !result_rx.is_empty()
+    }));
+    let err = result_rx.recv().unwrap().unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::TimeOut);
+
+    let mut manager = sender.join().unwrap();
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn handshake_read_timeout() {
+    use peernet::clock::MockClock;
+
+    let clock = Arc::new(MockClock::new());
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        WaitingInitConnection {
+            nb_fallbacks: Arc::new(RwLock::new(0)),
+        },
+        DefaultMessagesHandler {},
+    )
+    .set_tcp_settings(TcpSettings {
+        read_timeout: Duration::from_secs(10),
+        ..Default::default()
+    })
+    .set_optional_features(
+        PeerNetFeatures::default()
+            .set_clock(clock.clone())
+            .set_connection_history(10),
+    )
+    .build()
+    .unwrap();
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    // the client never sends anything, its handshake waits for the read timeout
+    let _ = create_clients(1, &addr.to_string());
+    let queued = || manager.active_connections.read().in_connection_queue.len();
+    assert!(eventually(|| queued() == 1));
+    clock.advance(Duration::from_secs(9));
+    assert!(!eventually(|| queued() == 0));
+    clock.advance(Duration::from_secs(1));
+    assert!(eventually(|| queued() == 0));
+    assert!(manager.recent_events(10).iter().any(|event| matches!(
+        &event.kind,
+        ConnectionEventKind::HandshakeFailed { reason, .. } if reason.contains("TimeOut")
+    )));
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+// TODO Perform limit tests for QUIC also
+
+/// Waits for a message of the peer during the handshake, counts the connections refused
+#[derive(Clone)]
+pub struct WaitingInitConnection {
+    nb_fallbacks: Arc<RwLock<usize>>,
+}
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
+    for WaitingInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _keypair: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: DefaultMessagesHandler,
+    ) -> peernet::error::PeerNetResult<DefaultPeerId> {
+        endpoint.receive::<DefaultPeerId>()?;
+        Ok(DefaultPeerId::generate())
+    }
+
+    fn fallback_function(
+        &mut self,
+        _context: &DefaultContext,
+        _endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+    ) -> peernet::error::PeerNetResult<()> {
+        *self.nb_fallbacks.write() += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn max_concurrent_handshakes() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let nb_fallbacks = Arc::new(RwLock::new(0));
+    let config = PeerNetConfiguration {
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
             rate_time_window: Duration::from_secs(1),
             rate_bucket_size: 60 * 1024,
-            rate_limit: 100,
-            data_channel_size: 1000,
-            max_message_size: 9000000,
             read_timeout: Duration::from_secs(10),
             write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
         },
-        address: format!("127.0.0.1:{port}").parse().unwrap(),
-        stream_limiter: Limiter::new(stream, None, None),
-        total_bytes_received: Arc::new(RwLock::new(0)),
-        total_bytes_sent: Arc::new(RwLock::new(0)),
-        endpoint_bytes_received: Arc::new(RwLock::new(0)),
-        endpoint_bytes_sent: Arc::new(RwLock::new(0)),
-    });
+        quic: QuicSettings::default(),
+        context,
+        max_in_connections: Some(10),
+        init_connection_handler: WaitingInitConnection {
+            nb_fallbacks: nb_fallbacks.clone(),
+        },
+        optional_features: PeerNetFeatures::default().set_max_concurrent_handshakes(2),
+        message_handler: DefaultMessagesHandler {},
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    };
+    let mut manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        WaitingInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config).unwrap();
 
-    std::thread::sleep(std::time::Duration::from_secs(1));
-    assert!(manager.nb_in_connections().eq(&1));
-
-    if let Some((_peer_id, conn)) = manager
-        .active_connections
-        .write()
-        .connections
-        .iter_mut()
-        .next()
-    {
-        // send msg with large data that trigger the timeout
-        let result = conn
-            .endpoint
-            .send_timeout::<DefaultPeerId>(&[0; 9000000], Duration::from_millis(200));
-        let err = result.unwrap_err();
-        println!("Err: {:?}", err);
-        assert!(err.to_string().contains("timeout"));
-    }
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // the clients never send anything, their handshakes stay pending
+    let _ = create_clients(4, format!("127.0.0.1:{port}").as_str());
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        manager.active_connections.read().in_connection_queue.len(),
+        2
+    );
+    assert_eq!(*nb_fallbacks.read(), 2);
 
     manager
         .stop_listener(
@@ -635,4 +934,1425 @@ fn send_timeout() {
         .unwrap();
 }
 
-// TODO Perform limit tests for QUIC also
+#[test]
+fn handshake_workers() {
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        WaitingInitConnection {
+            nb_fallbacks: Arc::new(RwLock::new(0)),
+        },
+        DefaultMessagesHandler {},
+    )
+    .set_optional_features(
+        PeerNetFeatures::default()
+            .set_handshake_workers(HandshakeWorkers {
+                nb_workers: 1,
+                queue_size: 1,
+            })
+            .set_connection_history(10),
+    )
+    .set_max_in_connections(Some(10))
+    .set_default_category_info(PeerNetCategoryInfo {
+        max_in_connections: Some(10),
+        max_in_connections_per_ip: Some(10),
+        max_out_connections: Some(10),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    })
+    .build()
+    .unwrap();
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    // the clients never send anything: one handshake runs, one waits for the worker and the
+    // others are refused at once
+    let _ = create_clients(4, format!("127.0.0.1:{port}").as_str());
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(
+        manager.active_connections.read().in_connection_queue.len(),
+        2
+    );
+    let refused = manager
+        .recent_events(10)
+        .into_iter()
+        .filter(|event| {
+            matches!(
+                &event.kind,
+                ConnectionEventKind::HandshakeFailed { reason, .. }
+                    if reason.contains("no worker available")
+            )
+        })
+        .count();
+    assert_eq!(refused, 2);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn stale_handshakes_evicted() {
+    use peernet::clock::MockClock;
+
+    let clock = Arc::new(MockClock::new());
+    let mut config = rate_limited_config(100 * 1024);
+    config.optional_features = PeerNetFeatures::default()
+        .set_clock(clock.clone())
+        .set_handshake_timeout(Duration::from_millis(200));
+    let manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        DefaultInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config).unwrap();
+    {
+        // left by handshake threads that died
+        let mut active_connections = manager.active_connections.write();
+        let now = active_connections.clock.now();
+        active_connections
+            .in_connection_queue
+            .insert("127.0.0.1:10001".parse().unwrap(), now);
+        active_connections
+            .out_connection_queue
+            .insert("127.0.0.1:10002".parse().unwrap(), now);
+    }
+    // checked every 100ms of the clock
+    assert!(eventually(|| {
+        clock.advance(Duration::from_millis(100));
+        manager.active_connections.read().nb_evicted_handshakes == 2
+    }));
+    let active_connections = manager.active_connections.read();
+    assert!(active_connections.in_connection_queue.is_empty());
+    assert!(active_connections.out_connection_queue.is_empty());
+    assert_eq!(active_connections.nb_evicted_handshakes, 2);
+    drop(active_connections);
+    assert_eq!(manager.dump_state().nb_evicted_handshakes, 2);
+}
+
+#[test]
+fn unlimited_connections() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+
+    let config = PeerNetConfiguration {
+        tcp: TcpSettings {
+            max_message_size: None,
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        context,
+        max_in_connections: None,
+        init_connection_handler: DefaultInitConnection {},
+        optional_features: PeerNetFeatures::default(),
+        message_handler: DefaultMessagesHandler {},
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: None,
+            max_in_connections_per_ip: None,
+            max_out_connections: None,
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    };
+    let mut manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        DefaultInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config).unwrap();
+
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // all the clients come from the same ip, without any limit they are all accepted
+    let _ = create_clients(12, format!("127.0.0.1:{port}").as_str());
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(manager.nb_in_connections(), 12);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+fn rate_limited_config(
+    rate_limit: u64,
+) -> PeerNetConfiguration<
+    DefaultPeerId,
+    DefaultContext,
+    DefaultInitConnection,
+    DefaultMessagesHandler,
+> {
+    PeerNetConfiguration {
+        context: DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        max_in_connections: Some(10),
+        init_connection_handler: DefaultInitConnection {},
+        optional_features: PeerNetFeatures::default(),
+        message_handler: DefaultMessagesHandler {},
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: rate_limit,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    }
+}
+
+fn rate_limited_manager(
+    rate_limit: u64,
+) -> PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, DefaultMessagesHandler> {
+    PeerNetManager::new(rate_limited_config(rate_limit)).unwrap()
+}
+
+#[test]
+fn rate_limit_hot_reload() {
+    let mut manager = rate_limited_manager(100 * 1024 * 1024);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // 64 KiB per second
+    let mut manager2 = rate_limited_manager(64 * 1024);
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    let send = |manager: &PeerNetManager<_, _, _, _>, nb_messages: usize| {
+        let active_connections = manager.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for _ in 0..nb_messages {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![0; 64 * 1024], false)
+                .unwrap();
+        }
+    };
+
+    send(&manager2, 4);
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(manager.get_total_bytes_received() < 4 * 64 * 1024);
+
+    // invalid values are refused and the current limit is kept
+    assert!(manager2
+        .set_rate_limit(64 * 1024, Duration::from_secs(1), 1024)
+        .is_err());
+    // 16 more messages would take 20 seconds at the previous rate
+    manager2
+        .set_rate_limit(100 * 1024 * 1024, Duration::from_secs(1), 100 * 1024 * 1024)
+        .unwrap();
+    send(&manager2, 16);
+    std::thread::sleep(Duration::from_secs(3));
+    assert!(manager.get_total_bytes_received() >= 20 * 64 * 1024);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn replace_transport_config() {
+    let mut manager = rate_limited_manager(100 * 1024 * 1024);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    let connect = || {
+        let mut manager2 = rate_limited_manager(100 * 1024 * 1024);
+        connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+        manager2
+    };
+    let max_message_sizes = |manager: &PeerNetManager<_, _, _, _>| {
+        let mut sizes: Vec<Option<usize>> = manager
+            .active_connections
+            .read()
+            .connections
+            .values()
+            .map(|connection| connection.send_channels.max_message_size())
+            .collect();
+        sizes.sort();
+        sizes
+    };
+    let _manager2 = connect();
+    assert_eq!(max_message_sizes(&manager), vec![Some(1048576000)]);
+
+    let mut settings = rate_limited_config(100 * 1024 * 1024).tcp;
+    settings.max_message_size = Some(1024);
+    // invalid or for another transport, the settings are left unchanged
+    let mut invalid = settings.clone();
+    invalid.rate_bucket_size = 1;
+    let err = manager
+        .replace_transport_config(TransportType::Tcp, TransportSettings::Tcp(invalid))
+        .unwrap_err();
+    assert!(matches!(err.error_type(), PeerNetError::ConfigError(_)));
+    let err = manager
+        .replace_transport_config(
+            TransportType::Quic,
+            TransportSettings::Tcp(settings.clone()),
+        )
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::WrongConfigType);
+
+    // the listener keeps running, the connected peer keeps its settings
+    manager
+        .replace_transport_config(TransportType::Tcp, TransportSettings::Tcp(settings))
+        .unwrap();
+    assert_eq!(max_message_sizes(&manager), vec![Some(1048576000)]);
+    let _manager3 = connect();
+    assert_eq!(
+        max_message_sizes(&manager),
+        vec![Some(1024), Some(1048576000)]
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn rate_limit_small_messages() {
+    // the min operation size can't exceed a bucket smaller than the TCP packets
+    let options = stream_limiter::LimiterOptions::from(TcpConnectionConfig::default());
+    assert_eq!(options.min_operation_size, 10 * 1024);
+
+    let mut manager = rate_limited_manager(100 * 1024 * 1024);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // 64 KiB per second, about a second for the 1000 frames of 64 bytes (size + message)
+    let mut manager2 = rate_limited_manager(64 * 1024);
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for _ in 0..1000 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![0; 60], false)
+                .unwrap();
+        }
+    }
+    std::thread::sleep(Duration::from_secs(3));
+    assert_eq!(manager.get_total_bytes_received(), 1000 * 60);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn memory_budget() {
+    let mut manager = rate_limited_manager(100 * 1024 * 1024);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // 64 KiB per second, the messages wait in the send channels
+    let mut config = rate_limited_config(64 * 1024);
+    config.optional_features = PeerNetFeatures::default().set_memory_budget(MemoryBudget {
+        max_per_peer: Some(128 * 1024),
+        max_total: None,
+        action: MemoryAction::DropLowPriority,
+    });
+    let mut manager2: PeerNetManager<_, _, _, _> = PeerNetManager::new(config).unwrap();
+    assert_eq!(manager.memory_stats(), None);
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        let mut nb_refused = 0;
+        for _ in 0..10 {
+            if let Err(err) =
+                connection
+                    .send_channels
+                    .send(&RawSerializer, vec![0; 64 * 1024], false)
+            {
+                assert_eq!(err.error_type(), &PeerNetError::MemoryBudgetExceeded);
+                nb_refused += 1;
+            }
+        }
+        assert!(nb_refused >= 6);
+        let usage = connection.send_channels.memory_usage().unwrap();
+        assert!(usage.send_queues <= 128 * 1024);
+        // the high priority messages are always kept
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![0; 64 * 1024], true)
+            .unwrap();
+        assert_eq!(
+            manager2.memory_stats().unwrap().nb_dropped_messages,
+            nb_refused
+        );
+    }
+
+    // released once written
+    std::thread::sleep(Duration::from_secs(6));
+    assert_eq!(manager2.memory_stats().unwrap().total, 0);
+    assert_eq!(manager2.dump_state().memory.unwrap().total, 0);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn dial_pacing() {
+    let mut config = rate_limited_config(100 * 1024 * 1024);
+    config.optional_features = PeerNetFeatures::default().set_dial_pacing(DialPacing {
+        max_dials_per_sec: 0,
+        burst: 1,
+    });
+    assert_eq!(config.validate(), Err(ConfigError::ZeroDialRate));
+
+    // 5 dials per second after a burst of 2
+    config.optional_features = PeerNetFeatures::default().set_dial_pacing(DialPacing {
+        max_dials_per_sec: 5,
+        burst: 2,
+    });
+    let mut manager: PeerNetManager<_, _, _, _> = PeerNetManager::new(config).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (accepted_tx, accepted_rx) = unbounded();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = accepted_tx.send((Instant::now(), stream));
+        }
+    });
+    let start = Instant::now();
+    for _ in 0..4 {
+        manager
+            .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+            .unwrap();
+    }
+    let accepted: Vec<Duration> = (0..4)
+        .map(|_| {
+            let (at, _stream) = accepted_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+            at.duration_since(start)
+        })
+        .collect();
+    assert!(accepted[1] < Duration::from_millis(150));
+    assert!(accepted[2] >= Duration::from_millis(180));
+    assert!(accepted[3] >= Duration::from_millis(380));
+}
+
+#[test]
+fn in_connections_limit_under_concurrency() {
+    let mut config = rate_limited_config(100 * 1024 * 1024);
+    config.max_in_connections = Some(3);
+    let mut manager: PeerNetManager<_, _, _, _> = PeerNetManager::new(config).unwrap();
+    // two listeners accept concurrently
+    let addrs: Vec<SocketAddr> = (0..2)
+        .map(|_| {
+            let port = get_tcp_port(10000..u16::MAX);
+            let addr = format!("127.0.0.1:{port}").parse().unwrap();
+            manager.start_listener(TransportType::Tcp, addr).unwrap();
+            addr
+        })
+        .collect();
+
+    let active_connections = manager.active_connections.clone();
+    let (stop_tx, stop_rx) = unbounded::<()>();
+    let sampler = std::thread::spawn(move || {
+        let mut max_seen = 0;
+        while stop_rx.try_recv().is_err() {
+            let active_connections = active_connections.read();
+            max_seen = max_seen.max(
+                active_connections.nb_in_connections + active_connections.nb_in_reservations(),
+            );
+            drop(active_connections);
+            std::thread::yield_now();
+        }
+        max_seen
+    });
+
+    let mut clients: Vec<PeerNetManager<_, _, _, _>> = (0..12)
+        .map(|_| PeerNetManager::new(rate_limited_config(100 * 1024 * 1024)).unwrap())
+        .collect();
+    for (index, client) in clients.iter_mut().enumerate() {
+        client
+            .try_connect(
+                TransportType::Tcp,
+                addrs[index % addrs.len()],
+                Duration::from_secs(3),
+            )
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(3));
+    stop_tx.send(()).unwrap();
+    assert!(sampler.join().unwrap() <= 3);
+    assert_eq!(manager.nb_in_connections(), 3);
+    // the refused handshakes gave their slot back
+    assert_eq!(manager.active_connections.read().nb_in_reservations(), 0);
+
+    for addr in addrs {
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+struct RecordingSink(Sender<DiagnosticEvent>);
+
+impl DiagnosticsSink for RecordingSink {
+    fn report(&self, event: DiagnosticEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+#[test]
+fn diagnostics() {
+    // the handshake waits for the answer to the puzzle
+    let proof_of_work = ProofOfWork {
+        difficulty: 0,
+        trusted_categories: Vec::new(),
+        max_difficulty: 0,
+    };
+    let (server_tx, server_events) = unbounded();
+    let mut config = rate_limited_config(100 * 1024 * 1024);
+    config.optional_features = PeerNetFeatures::default()
+        .set_proof_of_work(proof_of_work.clone())
+        .set_diagnostics(Arc::new(RecordingSink(server_tx)));
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    // closed without answering, once the listener is up
+    let stream = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(Duration::from_millis(50));
+            std::net::TcpStream::connect(addr).ok()
+        })
+        .unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    drop(stream);
+    match server_events.recv_timeout(Duration::from_secs(5)).unwrap() {
+        DiagnosticEvent::HandshakeFailed {
+            addr, direction, ..
+        } => {
+            assert_eq!(addr, client_addr);
+            assert_eq!(direction, PeerConnectionType::IN);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // 64 KiB per second and room for 2 messages, driven by an event loop
+    let (client_tx, client_events) = unbounded();
+    let mut config = rate_limited_config(64 * 1024);
+    config.send_data_channel_size = 2;
+    config.optional_features = PeerNetFeatures::default()
+        .set_proof_of_work(proof_of_work)
+        .set_tcp_reactor(TcpReactor { nb_event_loops: 1 })
+        .set_diagnostics(Arc::new(RecordingSink(client_tx)));
+    let mut manager2 = PeerNetManager::new(config).unwrap();
+    connect_tcp(&mut manager2, addr);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        let nb_sent = (0..10)
+            .filter(|_| {
+                connection
+                    .send_channels
+                    .try_send(&RawSerializer, vec![0; 64 * 1024], false)
+                    .is_ok()
+            })
+            .count();
+        assert!(nb_sent < 10);
+    }
+    std::thread::sleep(Duration::from_secs(1));
+    let events: Vec<DiagnosticEvent> = client_events.try_iter().collect();
+    assert!(events.iter().any(|event| matches!(
+        event,
+        DiagnosticEvent::SendQueueFull {
+            addr: queue_addr,
+            high_priority: false,
+        } if *queue_addr == addr
+    )));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        DiagnosticEvent::Throttled {
+            addr: throttled_addr,
+            write: true,
+            ..
+        } if *throttled_addr == addr
+    )));
+    // the dial and its handshake are timed on both sides
+    let connected = events
+        .iter()
+        .find_map(|event| match event {
+            DiagnosticEvent::Connected {
+                addr: connected_addr,
+                direction,
+                connect,
+                handshake,
+                total,
+            } if *connected_addr == addr => Some((*direction, *connect, *handshake, *total)),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(connected.0, PeerConnectionType::OUT);
+    assert!(connected.1 + connected.2 <= connected.3);
+    assert!(server_events.try_iter().any(|event| matches!(
+        event,
+        DiagnosticEvent::Connected {
+            direction: PeerConnectionType::IN,
+            ..
+        }
+    )));
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+/// Refuses with a `Rejection` when full, reports the rejections received when dialing
+#[derive(Clone)]
+pub struct RejectingInitConnection {
+    rejections: Sender<Rejection>,
+}
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
+    for RejectingInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _keypair: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: DefaultMessagesHandler,
+    ) -> peernet::error::PeerNetResult<DefaultPeerId> {
+        let data = endpoint.receive::<DefaultPeerId>()?;
+        if let Some(rejection) = Rejection::parse(&data) {
+            self.rejections.send(rejection?).unwrap();
+            return Err(PeerNetError::HandshakeError.error("rejected", None));
+        }
+        Ok(DefaultPeerId::generate())
+    }
+
+    fn fallback_function(
+        &mut self,
+        _context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+    ) -> peernet::error::PeerNetResult<()> {
+        let mut rejection = Rejection::new(RejectionReason::Full);
+        rejection.retry_after = Some(Duration::from_secs(30));
+        rejection
+            .alternative_peers
+            .insert("127.0.0.1:4242".parse().unwrap(), TransportType::Tcp);
+        rejection.send::<DefaultPeerId>(endpoint)
+    }
+}
+
+#[test]
+fn rejection_frame() {
+    let mut rejection = Rejection::new(RejectionReason::Other(42));
+    rejection
+        .alternative_peers
+        .insert("[::1]:4242".parse().unwrap(), TransportType::Quic);
+    let mut data = Vec::new();
+    rejection.serialize(&mut data).unwrap();
+    assert_eq!(Rejection::parse(&data).unwrap().unwrap(), rejection);
+    assert!(Rejection::parse(&data[4..]).is_none());
+    data.push(0);
+    assert!(Rejection::parse(&data).unwrap().is_err());
+
+    let (rejections_tx, rejections_rx) = unbounded();
+    let config = || {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            RejectingInitConnection {
+                rejections: rejections_tx.clone(),
+            },
+            DefaultMessagesHandler {},
+        )
+        .set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(0),
+            max_in_connections_per_ip: Some(0),
+            max_out_connections: Some(1),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        })
+        .build()
+        .unwrap()
+    };
+    let mut manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        RejectingInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config()).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+    let mut manager2: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        RejectingInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config()).unwrap();
+    manager2
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+
+    let rejection = rejections_rx.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(rejection.reason, RejectionReason::Full);
+    assert_eq!(rejection.retry_after, Some(Duration::from_secs(30)));
+    assert_eq!(
+        rejection.alternative_peers,
+        HashMap::from([("127.0.0.1:4242".parse().unwrap(), TransportType::Tcp)])
+    );
+    assert_eq!(manager.nb_in_connections(), 0);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn pending_handshakes_per_ip() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let nb_fallbacks = Arc::new(RwLock::new(0));
+    let config = PeerNetConfiguration {
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        context,
+        max_in_connections: Some(10),
+        init_connection_handler: WaitingInitConnection {
+            nb_fallbacks: nb_fallbacks.clone(),
+        },
+        optional_features: PeerNetFeatures::default(),
+        message_handler: DefaultMessagesHandler {},
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    };
+    let mut manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        WaitingInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config).unwrap();
+
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // the clients never finish their handshake, only 2 of them can be pending
+    let _ = create_clients(4, format!("127.0.0.1:{port}").as_str());
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        manager.active_connections.read().in_connection_queue.len(),
+        2
+    );
+    assert_eq!(*nb_fallbacks.read(), 2);
+    assert_eq!(manager.nb_in_connections(), 0);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn max_out_connections_per_ip() {
+    let config = |max_out_connections_per_ip| {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            DefaultInitConnection,
+            DefaultMessagesHandler {},
+        )
+        .set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip,
+            read_timeout: None,
+            write_timeout: None,
+        })
+        .build()
+        .unwrap()
+    };
+    let mut manager: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        DefaultInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config(None)).unwrap();
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for _ in 0..3 {
+        let port = get_tcp_port(10000..u16::MAX);
+        let addr = format!("127.0.0.1:{port}").parse().unwrap();
+        manager.start_listener(TransportType::Tcp, addr).unwrap();
+        addrs.push(addr);
+    }
+
+    let mut manager2: PeerNetManager<
+        DefaultPeerId,
+        DefaultContext,
+        DefaultInitConnection,
+        DefaultMessagesHandler,
+    > = PeerNetManager::new(config(Some(2))).unwrap();
+    for addr in &addrs {
+        manager2
+            .try_connect(TransportType::Tcp, *addr, Duration::from_secs(3))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    // the third connection to the same IP is refused after the handshake
+    assert_eq!(manager2.active_connections.read().nb_out_connections, 2);
+
+    for addr in addrs {
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+/// Each side sends the id of its context, so that the tests know the ids of the peers
+#[derive(Clone)]
+struct IdExchangeInitConnection;
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
+    for IdExchangeInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: DefaultMessagesHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        endpoint.send::<DefaultPeerId>(&context.get_peer_id().id.to_be_bytes())?;
+        let id = endpoint.receive::<DefaultPeerId>()?;
+        let id = id[..]
+            .try_into()
+            .map_err(|_| PeerNetError::HandshakeError.error("receive id", None))?;
+        Ok(DefaultPeerId {
+            id: u64::from_be_bytes(id),
+        })
+    }
+}
+
+#[test]
+fn trusted_peers() {
+    let builder = || {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            IdExchangeInitConnection,
+            DefaultMessagesHandler {},
+        )
+        .set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(1),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        })
+    };
+    let mut listeners = Vec::new();
+    for _ in 0..3 {
+        let config = builder().build().unwrap();
+        let id = config.context.get_peer_id();
+        let mut manager = PeerNetManager::new(config).unwrap();
+        let port = get_tcp_port(10000..u16::MAX);
+        let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+        manager.start_listener(TransportType::Tcp, addr).unwrap();
+        listeners.push((manager, id, addr));
+    }
+
+    // trusted from the configuration
+    let config = builder()
+        .set_trusted_peers(TrustedPeers {
+            peers: vec![
+                TrustedPeer::Id(listeners[1].1.clone()),
+                TrustedPeer::Id(listeners[2].1.clone()),
+            ],
+            evict_untrusted: false,
+        })
+        .build()
+        .unwrap();
+    let mut manager = PeerNetManager::new(config).unwrap();
+    for (_, _, addr) in &listeners[..2] {
+        manager
+            .try_connect(TransportType::Tcp, *addr, Duration::from_secs(3))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    // the trusted peer is accepted beyond the limit of 1
+    assert_eq!(manager.active_connections.read().nb_out_connections, 2);
+
+    // the untrusted peer makes room for the next trusted one
+    let mut trusted_peers = manager.active_connections.read().trusted_peers.clone();
+    trusted_peers.evict_untrusted = true;
+    manager.set_trusted_peers(trusted_peers);
+    manager
+        .try_connect(TransportType::Tcp, listeners[2].2, Duration::from_secs(3))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    {
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 2);
+        assert!(!active_connections.connections.contains_key(&listeners[0].1));
+        assert!(active_connections.connections.contains_key(&listeners[1].1));
+        assert!(active_connections.connections.contains_key(&listeners[2].1));
+    }
+
+    for (mut manager, _, addr) in listeners {
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+#[test]
+fn peer_id_rules() {
+    let config = || {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            IdExchangeInitConnection,
+            DefaultMessagesHandler {},
+        )
+        .build()
+        .unwrap()
+    };
+    let mut listeners = Vec::new();
+    for _ in 0..3 {
+        let config = config();
+        let id = config.context.get_peer_id();
+        let mut manager = PeerNetManager::new(config).unwrap();
+        let port = get_tcp_port(10000..u16::MAX);
+        let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+        manager.start_listener(TransportType::Tcp, addr).unwrap();
+        listeners.push((manager, id, addr));
+    }
+
+    // the denied peer is refused even if it's allowed and trusted
+    let mut config = config();
+    config.trusted_peers = TrustedPeers {
+        peers: vec![TrustedPeer::Id(listeners[0].1.clone())],
+        evict_untrusted: false,
+    };
+    config.peer_id_rules = PeerIdRules {
+        allowed_peer_ids: Some(HashSet::from([
+            listeners[0].1.clone(),
+            listeners[1].1.clone(),
+        ])),
+        denied_peer_ids: HashSet::from([listeners[0].1.clone()]),
+    };
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let state = manager.dump_state();
+    let denied_id = format!("{:?}", listeners[0].1);
+    assert_eq!(state.trusted_peers.ids, vec![denied_id.clone()]);
+    assert_eq!(state.peer_id_rules.denied_peer_ids, vec![denied_id]);
+    assert_eq!(
+        state.peer_id_rules.allowed_peer_ids.map(|ids| ids.len()),
+        Some(2)
+    );
+    for (_, _, addr) in &listeners {
+        manager
+            .try_connect(TransportType::Tcp, *addr, Duration::from_secs(3))
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    {
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 1);
+        assert!(active_connections.connections.contains_key(&listeners[1].1));
+    }
+
+    // the connected peers refused by new rules are disconnected
+    manager.set_peer_id_rules(PeerIdRules {
+        allowed_peer_ids: None,
+        denied_peer_ids: HashSet::from([listeners[1].1.clone()]),
+    });
+    assert!(manager.active_connections.read().connections.is_empty());
+    manager
+        .try_connect(TransportType::Tcp, listeners[2].2, Duration::from_secs(3))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    {
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 1);
+        assert!(active_connections.connections.contains_key(&listeners[2].1));
+    }
+
+    for (mut manager, _, addr) in listeners {
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+/// Each side sends `padding` bytes and reads the padding of the other side
+#[derive(Clone)]
+struct PaddedInitConnection {
+    padding: usize,
+}
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
+    for PaddedInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: DefaultMessagesHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        endpoint.send::<DefaultPeerId>(&vec![0; self.padding])?;
+        endpoint.receive::<DefaultPeerId>()?;
+        Ok(DefaultPeerId::generate())
+    }
+}
+
+#[test]
+fn handshake_data_limit() {
+    let config = |padding, features| {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            PaddedInitConnection { padding },
+            DefaultMessagesHandler {},
+        )
+        .set_optional_features(features)
+        .build()
+        .unwrap()
+    };
+    let mut manager = PeerNetManager::new(config(
+        100,
+        PeerNetFeatures::default().set_handshake_data_limit(1024),
+    ))
+    .unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    // the handshake sending more than the limit is aborted
+    let mut manager2 = PeerNetManager::new(config(4096, PeerNetFeatures::default())).unwrap();
+    manager2
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager.nb_in_connections(), 0);
+
+    // the limit doesn't apply after the handshake
+    let mut manager3 = PeerNetManager::new(config(100, PeerNetFeatures::default())).unwrap();
+    manager3
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager.nb_in_connections(), 1);
+    {
+        let active_connections = manager3.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![0; 4096], false)
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager.nb_in_connections(), 1);
+    assert!(manager.get_total_bytes_received() > 4096);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn self_connection() {
+    let (events_tx, events) = unbounded();
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        IdExchangeInitConnection,
+        DefaultMessagesHandler {},
+    )
+    .set_optional_features(
+        PeerNetFeatures::default()
+            .set_diagnostics(Arc::new(RecordingSink(events_tx)))
+            .set_connection_history(10),
+    )
+    .build()
+    .unwrap();
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    // our listener is refused before dialing
+    let err = manager
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::SelfConnection);
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(1)).unwrap(),
+        DiagnosticEvent::SelfConnection {
+            addr,
+            direction: PeerConnectionType::OUT
+        }
+    );
+
+    // an address forwarded to our listener is detected after the handshake, then refused
+    let forwarder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let forwarder_addr = forwarder.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (client, _) = forwarder.accept().unwrap();
+        let server = std::net::TcpStream::connect(addr).unwrap();
+        let (mut client_read, mut server_write) =
+            (client.try_clone().unwrap(), server.try_clone().unwrap());
+        std::thread::spawn(move || std::io::copy(&mut client_read, &mut server_write));
+        let (mut server_read, mut client_write) = (server, client);
+        let _ = std::io::copy(&mut server_read, &mut client_write);
+    });
+    manager
+        .try_connect(TransportType::Tcp, forwarder_addr, Duration::from_secs(3))
+        .unwrap();
+    // seen on both sides of the connection
+    let mut directions = Vec::new();
+    for _ in 0..2 {
+        match events.recv_timeout(Duration::from_secs(3)).unwrap() {
+            DiagnosticEvent::SelfConnection { direction, .. } => directions.push(direction),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert!(directions.contains(&PeerConnectionType::IN));
+    assert!(directions.contains(&PeerConnectionType::OUT));
+    assert!(manager.active_connections.read().connections.is_empty());
+    assert!(manager.recent_events(10).iter().any(|event| matches!(
+        event.kind,
+        ConnectionEventKind::Disconnected {
+            reason: DisconnectReason::SelfConnection,
+            ..
+        }
+    )));
+    let err = manager
+        .try_connect(TransportType::Tcp, forwarder_addr, Duration::from_secs(3))
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::SelfConnection);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn eviction_policy() {
+    // the ids are exchanged so that the sockets that send nothing stay in their handshake
+    let config = |features| {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            IdExchangeInitConnection,
+            DefaultMessagesHandler {},
+        )
+        .set_max_in_connections(Some(2))
+        .set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        })
+        .set_optional_features(features)
+        .build()
+        .unwrap()
+    };
+    let mut manager = PeerNetManager::new(config(
+        PeerNetFeatures::default().set_eviction_policy(Arc::new(OldestIdle)),
+    ))
+    .unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let mut client = PeerNetManager::new(config(PeerNetFeatures::default())).unwrap();
+        client
+            .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        clients.push(client);
+    }
+    // the oldest of the idle connections made room for the third one
+    assert_eq!(manager.nb_in_connections(), 2);
+    assert_eq!(clients[0].active_connections.read().nb_out_connections, 0);
+    assert_eq!(clients[1].active_connections.read().nb_out_connections, 1);
+    assert_eq!(clients[2].active_connections.read().nb_out_connections, 1);
+
+    // sockets that never do their handshake don't evict anyone
+    let _ = create_clients(2, &addr.to_string());
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager.nb_in_connections(), 2);
+    assert_eq!(clients[1].active_connections.read().nb_out_connections, 1);
+    assert_eq!(clients[2].active_connections.read().nb_out_connections, 1);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn eviction_policy_selection() {
+    let candidate =
+        |ip: &str, category: Option<&str>, connected_for: u64, rate: f64| EvictionCandidate {
+            peer_id: String::new(),
+            addr: format!("{ip}:8080").parse().unwrap(),
+            category: category.map(str::to_string),
+            connected_for: Duration::from_secs(connected_for),
+            bandwidth: Default::default(),
+            rates: BandwidthRates {
+                bytes_sent_per_sec: rate,
+                bytes_received_per_sec: 0.0,
+            },
+        };
+    let candidates = [
+        candidate("10.0.0.1", Some("a"), 30, 10.0),
+        candidate("10.0.0.2", Some("a"), 20, 0.0),
+        candidate("10.0.0.2", None, 10, 0.0),
+        candidate("10.0.0.3", Some("a"), 40, 0.0),
+    ];
+    assert_eq!(OldestIdle.select(&candidates), Some(3));
+    assert_eq!(MostRepresentedIp.select(&candidates), Some(2));
+    assert_eq!(MostRepresentedCategory.select(&candidates), Some(1));
+    assert_eq!(
+        LowestScore(|candidate: &EvictionCandidate| candidate.connected_for.as_secs_f64())
+            .select(&candidates),
+        Some(2)
+    );
+}
+
+/// Records the checks and refuses the connections after their handshake if `refuse`
+struct RecordingGater {
+    checks: Arc<RwLock<Vec<&'static str>>>,
+    refuse: bool,
+}
+
+impl ConnectionGater<DefaultPeerId> for RecordingGater {
+    fn check_accept(
+        &self,
+        active_connections: &ActiveConnections<DefaultPeerId>,
+        addr: &SocketAddr,
+        max_in_connections: Option<usize>,
+    ) -> bool {
+        self.checks.write().push("accept");
+        DefaultConnectionGater.check_accept(active_connections, addr, max_in_connections)
+    }
+
+    fn check_pre_handshake(
+        &self,
+        active_connections: &ActiveConnections<DefaultPeerId>,
+        addr: &SocketAddr,
+        category_name: Option<String>,
+        category_info: PeerNetCategoryInfo,
+    ) -> bool {
+        self.checks.write().push("pre_handshake");
+        DefaultConnectionGater.check_pre_handshake(
+            active_connections,
+            addr,
+            category_name,
+            category_info,
+        )
+    }
+
+    fn check_post_handshake(
+        &self,
+        _active_connections: &ActiveConnections<DefaultPeerId>,
+        _addr: &SocketAddr,
+        _category_name: Option<String>,
+        _category_info: PeerNetCategoryInfo,
+        _id: &DefaultPeerId,
+        _connection_type: PeerConnectionType,
+    ) -> bool {
+        self.checks.write().push("post_handshake");
+        !self.refuse
+    }
+}
+
+#[test]
+fn connection_gater() {
+    let checks = Arc::new(RwLock::new(Vec::new()));
+    let mut manager = rate_limited_manager(100 * 1024 * 1024);
+    manager.set_connection_gater(Arc::new(RecordingGater {
+        checks: checks.clone(),
+        refuse: true,
+    }));
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    let mut manager2 = rate_limited_manager(100 * 1024 * 1024);
+    manager2
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    // the limits are respected but the gater refuses the peer once known
+    assert!(eventually(|| checks.read().len() == 3));
+    assert_eq!(
+        *checks.read(),
+        vec!["accept", "pre_handshake", "post_handshake"]
+    );
+    assert_eq!(manager.nb_in_connections(), 0);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+/// Rejects the connections with a goodbye, or forwards the goodbye received to `goodbyes`
+#[derive(Clone)]
+struct GoodbyeInitConnection {
+    goodbye: Option<usize>,
+    goodbyes: Sender<usize>,
+}
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
+    for GoodbyeInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: DefaultMessagesHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        match self.goodbye {
+            Some(size) => {
+                endpoint.send::<DefaultPeerId>(&vec![7; size])?;
+                endpoint.flush()?;
+                endpoint.shutdown();
+            }
+            None => {
+                let goodbye = endpoint.receive::<DefaultPeerId>()?;
+                self.goodbyes.send(goodbye.len()).unwrap();
+            }
+        }
+        Err(PeerNetError::HandshakeError.error("goodbye", None))
+    }
+}
+
+#[test]
+fn linger_goodbye() {
+    let (goodbyes_tx, goodbyes) = unbounded();
+    let config = |goodbye| {
+        PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId::generate(),
+            },
+            GoodbyeInitConnection {
+                goodbye,
+                goodbyes: goodbyes_tx.clone(),
+            },
+            DefaultMessagesHandler {},
+        )
+        .set_tcp_settings(TcpSettings {
+            linger: Some(Duration::from_secs(2)),
+            ..Default::default()
+        })
+        .build()
+        .unwrap()
+    };
+    // bigger than the socket buffers, most of it is still queued when the socket is closed
+    let mut manager = PeerNetManager::new(config(Some(4_000_000))).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    let mut manager2 = PeerNetManager::new(config(None)).unwrap();
+    manager2
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(
+        goodbyes.recv_timeout(Duration::from_secs(5)).unwrap(),
+        4_000_000
+    );
+    assert_eq!(manager.nb_in_connections(), 0);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
@@ -67,7 +67,7 @@ fn two_peers_tcp_with_one_message() {
     let keypair1 = KeyPair::generate();
     let config = PeerNetConfiguration {
         self_keypair: keypair1,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: EmptyInitConnection {},
         message_handler: TestMessagesHandler {
             test_sender: sender.clone(),
@@ -77,18 +77,21 @@ fn two_peers_tcp_with_one_message() {
         default_category_info: PeerNetCategoryInfo {
             max_in_connections_pre_handshake: 10,
             max_in_connections_post_handshake: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
     };
-    let mut manager = PeerNetManager::new(config);
+    let mut manager = PeerNetManager::new(config).unwrap();
     manager
         .start_listener(TransportType::Tcp, "127.0.0.1:8081".parse().unwrap())
         .unwrap();
 
     let config = PeerNetConfiguration {
         self_keypair: keypair2,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: EmptyInitConnection {},
         message_handler: TestMessagesHandler {
             test_sender: sender,
@@ -98,11 +101,14 @@ fn two_peers_tcp_with_one_message() {
         default_category_info: PeerNetCategoryInfo {
             max_in_connections_pre_handshake: 10,
             max_in_connections_post_handshake: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
     };
-    let mut manager2 = PeerNetManager::new(config);
+    let mut manager2 = PeerNetManager::new(config).unwrap();
     manager2
         .try_connect(
             "127.0.0.1:8081".parse().unwrap(),
@@ -129,3 +135,1528 @@ fn two_peers_tcp_with_one_message() {
         .unwrap();
 }
  */
+
+mod util;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{Receiver, Sender};
+use peernet::config::{
+    HandlerWorkers, ObservedAddresses, PeerIdRules, PeerMetadata, PeerNetCategoryInfo,
+    PeerNetConfiguration, PeerNetFeatures, ProofOfWork, QuicSettings, TcpReactor, TcpSettings,
+    TrustedPeers,
+};
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::handlers::{MessageHandler, MessageHandlers, RoutedSerializer};
+use peernet::history::{ConnectionEventKind, DisconnectReason};
+use peernet::internal_handlers::ping::{LatencyHistogram, PingHandler, Pinger};
+use peernet::message_filter::{MessageFilter, MessageVerdict};
+use peernet::messages::{Bytes, MessageMeta, MessagesHandler};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::{InitConnectionHandler, PeerHandle, SendStats};
+use peernet::peer_id::PeerId;
+use peernet::proof_of_work::{check_solution, solve_challenge, CHALLENGE_SIZE};
+use peernet::transports::TransportType;
+
+use crate::util::{
+    connect_tcp, eventually, get_tcp_port, DefaultContext, DefaultPeerId, RawSerializer,
+};
+
+#[derive(Clone)]
+struct EmptyInitConnection;
+impl<M: MessagesHandler<DefaultPeerId>> InitConnectionHandler<DefaultPeerId, DefaultContext, M>
+    for EmptyInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        _keypair: &DefaultContext,
+        _endpoint: &mut peernet::transports::endpoint::Endpoint,
+        _listeners: &HashMap<std::net::SocketAddr, TransportType>,
+        _messages_handler: M,
+    ) -> PeerNetResult<DefaultPeerId> {
+        Ok(DefaultPeerId::generate())
+    }
+}
+
+/// Answers every message with the same data if `echo` is set, forward them to `received` otherwise
+#[derive(Clone)]
+struct EchoMessagesHandler {
+    echo: bool,
+    received: Sender<Bytes>,
+}
+
+impl MessagesHandler<DefaultPeerId> for EchoMessagesHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send(data)
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+
+    fn handle_with_peer(&self, data: Bytes, peer: &PeerHandle<DefaultPeerId>) -> PeerNetResult<()> {
+        if self.echo {
+            peer.send_channels
+                .send(&RawSerializer, data.to_vec(), false)
+        } else {
+            self.handle(data, &peer.peer_id)
+        }
+    }
+}
+
+fn test_config<M: MessagesHandler<DefaultPeerId>>(
+    message_handler: M,
+) -> PeerNetConfiguration<DefaultPeerId, DefaultContext, EmptyInitConnection, M> {
+    PeerNetConfiguration {
+        context: DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        max_in_connections: Some(10),
+        init_connection_handler: EmptyInitConnection,
+        optional_features: PeerNetFeatures::default(),
+        message_handler,
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    }
+}
+
+#[test]
+fn reply_with_peer_handle() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: true,
+        received: sender.clone(),
+    }))
+    .unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2, 3], false)
+            .unwrap();
+    }
+    let answer = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(answer, vec![1, 2, 3]);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Keeps the handles of the peers that sent a message
+#[derive(Clone)]
+struct KeepingMessagesHandler {
+    handles: Arc<std::sync::Mutex<Vec<PeerHandle<DefaultPeerId>>>>,
+}
+
+impl MessagesHandler<DefaultPeerId> for KeepingMessagesHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    fn handle_with_peer(
+        &self,
+        _data: Bytes,
+        peer: &PeerHandle<DefaultPeerId>,
+    ) -> PeerNetResult<()> {
+        self.handles.lock().unwrap().push(peer.clone());
+        Ok(())
+    }
+}
+
+#[test]
+fn peer_handle_kept_by_handler() {
+    let handler = KeepingMessagesHandler {
+        handles: Default::default(),
+    };
+    let mut manager = PeerNetManager::new(test_config(handler.clone())).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2, 3], false)
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(handler.handles.lock().unwrap().len(), 1);
+
+    // the threads of the peer end with the connection, though the handler keeps its handle
+    let peer_id = manager
+        .active_connections
+        .read()
+        .connections
+        .keys()
+        .next()
+        .unwrap()
+        .clone();
+    manager
+        .active_connections
+        .write()
+        .remove_connection(&peer_id);
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager.active_thread_count(), 0);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn send_message_too_large() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.tcp.max_message_size = Some(100);
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    });
+    config.tcp.max_message_size = Some(100);
+    let mut manager2 = PeerNetManager::new(config).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.send_channels.max_message_size(), Some(100));
+        // refused before being queued, the connection stays up
+        let err = connection
+            .send_channels
+            .send(&RawSerializer, vec![0; 101], false)
+            .unwrap_err();
+        assert_eq!(err.error_type(), &PeerNetError::MessageTooLarge);
+        assert_eq!(connection.send_channels.queued_messages(), (0, 0));
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1; 100], false)
+            .unwrap();
+    }
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![1; 100]
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn send_stats() {
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let mut manager = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    }))
+    .unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // the write thread is held by the rate limit while the queue fills
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    });
+    config.tcp.rate_limit = 1000;
+    config.send_data_channel_size = 4;
+    let mut manager2 = PeerNetManager::new(config).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.send_channels.send_stats(), SendStats::default());
+        let nb_refused = (0..20)
+            .filter(|_| {
+                connection
+                    .send_channels
+                    .try_send(&RawSerializer, vec![0; 30_000], false)
+                    .is_err()
+            })
+            .count();
+        let stats = connection.send_channels.send_stats();
+        assert!(nb_refused > 0);
+        assert_eq!(stats.nb_dropped_full, nb_refused as u64);
+        assert_eq!(stats.nb_send_errors, 0);
+        assert_eq!(stats.max_queued_messages, 4);
+    }
+    assert_eq!(
+        manager2.dump_state().peers[0]
+            .send_stats
+            .max_queued_messages,
+        4
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn handler_workers() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.optional_features = PeerNetFeatures::default().set_handler_workers(HandlerWorkers {
+        nb_workers: 4,
+        queue_size: 2,
+        max_in_flight_bytes_per_peer: None,
+        max_in_flight_bytes: None,
+        dispatch_timeout: None,
+    });
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i], false)
+                .unwrap();
+        }
+    }
+    // messages can be handled out of order by the workers
+    let mut received: Vec<u8> = (0..10)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(3)).unwrap()[0])
+        .collect();
+    received.sort_unstable();
+    assert_eq!(received, (0..10).collect::<Vec<u8>>());
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn handler_workers_in_flight_bytes() {
+    // the handler blocks until the test takes each message
+    let (sender, receiver) = crossbeam::channel::bounded(0);
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.optional_features = PeerNetFeatures::default().set_handler_workers(HandlerWorkers {
+        nb_workers: 1,
+        queue_size: 100,
+        max_in_flight_bytes_per_peer: Some(8),
+        max_in_flight_bytes: None,
+        dispatch_timeout: None,
+    });
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i; 4], false)
+                .unwrap();
+        }
+    }
+    std::thread::sleep(Duration::from_millis(500));
+    // the message being handled and the one queued, the read loop waits with the third
+    assert_eq!(manager.in_flight_bytes(), 8);
+    for i in 0..10 {
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            vec![i; 4]
+        );
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(manager.in_flight_bytes(), 0);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn handler_workers_dispatch_timeout() {
+    // the handler blocks until the test takes each message
+    let (sender, receiver) = crossbeam::channel::bounded(0);
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.optional_features = PeerNetFeatures::default().set_handler_workers(HandlerWorkers {
+        nb_workers: 1,
+        queue_size: 1,
+        max_in_flight_bytes_per_peer: None,
+        max_in_flight_bytes: None,
+        dispatch_timeout: Some(Duration::from_millis(200)),
+    });
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    assert_eq!(manager.active_connections.read().nb_in_connections, 1);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..3 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i], false)
+                .unwrap();
+        }
+    }
+    // the first message is being handled, the second one is queued and the third one doesn't
+    // find room before the timeout
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(manager.active_connections.read().nb_in_connections, 0);
+    for i in 0..2 {
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            vec![i]
+        );
+    }
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Panics on the messages starting with 0xff, forwards the other ones to `received`
+#[derive(Clone)]
+struct PanickingMessagesHandler {
+    received: Sender<Bytes>,
+}
+
+impl MessagesHandler<DefaultPeerId> for PanickingMessagesHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        assert_ne!(data.first(), Some(&0xff), "handler panic");
+        self.received
+            .send(data)
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+#[test]
+fn handler_workers_panic() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut config = test_config(PanickingMessagesHandler { received: sender });
+    config.optional_features = PeerNetFeatures::default()
+        .set_handler_workers(HandlerWorkers {
+            nb_workers: 1,
+            queue_size: 100,
+            max_in_flight_bytes_per_peer: Some(8),
+            max_in_flight_bytes: None,
+            dispatch_timeout: None,
+        })
+        .set_connection_history(10);
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let (sender2, _receiver2) = crossbeam::channel::unbounded();
+    let mut managers = Vec::new();
+    for first_byte in [0xff, 0] {
+        let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender2.clone(),
+        }))
+        .unwrap();
+        connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+        {
+            let active_connections = manager2.active_connections.read();
+            let connection = active_connections.connections.values().next().unwrap();
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![first_byte; 4], false)
+                .unwrap();
+        }
+        managers.push(manager2);
+    }
+    // the peer of the panic is disconnected, the worker still handles the messages of the other
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+        vec![0; 4]
+    );
+    // released once the handler returned
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(manager.active_connections.read().nb_in_connections, 1);
+    assert_eq!(manager.in_flight_bytes(), 0);
+    assert!(manager.recent_events(10).iter().any(|event| matches!(
+        &event.kind,
+        ConnectionEventKind::Disconnected {
+            reason: DisconnectReason::HandlerError(reason),
+            ..
+        } if reason.contains("handler panic")
+    )));
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn peer_timings() {
+    // the handler blocks until the test takes each message
+    let (sender, receiver) = crossbeam::channel::bounded(0);
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.optional_features = PeerNetFeatures::default().set_peer_timings(true);
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    }))
+    .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..2 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i], false)
+                .unwrap();
+        }
+    }
+    for i in 0..2 {
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            vec![i]
+        );
+    }
+    std::thread::sleep(Duration::from_millis(100));
+
+    let timings = manager.dump_state().peers[0].timings.unwrap();
+    assert_eq!(timings.nb_handled_messages, 2);
+    assert!(timings.handler >= Duration::from_millis(500));
+    assert!(timings.handshake > Duration::ZERO);
+    // not measured without the feature
+    assert_eq!(manager2.dump_state().peers[0].timings, None);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Forwards the received messages with their meta and the time they were handled
+#[derive(Clone)]
+struct MetaMessagesHandler {
+    received: Sender<(Bytes, MessageMeta, Instant)>,
+}
+
+impl MessagesHandler<DefaultPeerId> for MetaMessagesHandler {
+    fn handle(&self, _data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        unreachable!("the read loops call handle_with_meta")
+    }
+
+    fn handle_with_meta(
+        &self,
+        data: Bytes,
+        _peer: &PeerHandle<DefaultPeerId>,
+        meta: MessageMeta,
+    ) -> PeerNetResult<()> {
+        self.received
+            .send((data, meta, Instant::now()))
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+#[test]
+fn message_meta() {
+    // the handler blocks until the test takes each message
+    let (sender, receiver) = crossbeam::channel::bounded(0);
+    let mut config = test_config(MetaMessagesHandler {
+        received: sender.clone(),
+    });
+    config.optional_features = PeerNetFeatures::default().set_handler_workers(HandlerWorkers {
+        nb_workers: 1,
+        queue_size: 10,
+        max_in_flight_bytes_per_peer: None,
+        max_in_flight_bytes: None,
+        dispatch_timeout: None,
+    });
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 =
+        PeerNetManager::new(test_config(MetaMessagesHandler { received: sender })).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    let sent_at = Instant::now();
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..2 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i], false)
+                .unwrap();
+        }
+    }
+    std::thread::sleep(Duration::from_secs(1));
+    let (data, meta, _) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(data, vec![0]);
+    assert!(meta.received_at >= sent_at);
+    // received with the first one, it waited in the queue of the workers
+    let (data, meta, handled_at) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(data, vec![1]);
+    assert!(meta.received_at >= sent_at);
+    assert!(handled_at - meta.received_at >= Duration::from_millis(500));
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Records the chunks of the streamed messages and the messages delivered whole
+#[derive(Clone)]
+struct StreamingMessagesHandler {
+    events: Sender<String>,
+}
+
+impl MessagesHandler<DefaultPeerId> for StreamingMessagesHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.events
+            .send(format!("message {:?}", &data[..]))
+            .unwrap();
+        Ok(())
+    }
+
+    fn stream_chunk_size(&self) -> Option<usize> {
+        Some(4)
+    }
+
+    fn on_message_start(
+        &self,
+        size: usize,
+        _peer: &PeerHandle<DefaultPeerId>,
+    ) -> PeerNetResult<()> {
+        self.events.send(format!("start {}", size)).unwrap();
+        Ok(())
+    }
+
+    fn on_chunk(&self, chunk: Bytes, _peer: &PeerHandle<DefaultPeerId>) -> PeerNetResult<()> {
+        self.events.send(format!("chunk {:?}", &chunk[..])).unwrap();
+        Ok(())
+    }
+
+    fn on_message_end(&self, _peer: &PeerHandle<DefaultPeerId>) -> PeerNetResult<()> {
+        self.events.send("end".to_string()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn streamed_message() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager =
+        PeerNetManager::new(test_config(StreamingMessagesHandler { events: sender })).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let (sender2, _receiver2) = crossbeam::channel::unbounded();
+    let mut manager2 =
+        PeerNetManager::new(test_config(StreamingMessagesHandler { events: sender2 })).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, (0..10).collect(), false)
+            .unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2], false)
+            .unwrap();
+    }
+    let events: Vec<String> = (0..6)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "start 10",
+            "chunk [0, 1, 2, 3]",
+            "chunk [4, 5, 6, 7]",
+            "chunk [8, 9]",
+            "end",
+            "message [1, 2]",
+        ]
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+fn reactor_config<M: MessagesHandler<DefaultPeerId>>(
+    message_handler: M,
+) -> PeerNetConfiguration<DefaultPeerId, DefaultContext, EmptyInitConnection, M> {
+    let mut config = test_config(message_handler);
+    config.optional_features =
+        PeerNetFeatures::default().set_tcp_reactor(TcpReactor { nb_event_loops: 2 });
+    config
+}
+
+#[test]
+fn tcp_reactor() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager = PeerNetManager::new(reactor_config(EchoMessagesHandler {
+        echo: true,
+        received: sender.clone(),
+    }))
+    .unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // the connections of both sides are driven by event loops
+    let mut managers: Vec<_> = (0..2)
+        .map(|_| {
+            let mut manager2 = PeerNetManager::new(reactor_config(EchoMessagesHandler {
+                echo: false,
+                received: sender.clone(),
+            }))
+            .unwrap();
+            manager2
+                .try_connect(
+                    TransportType::Tcp,
+                    format!("127.0.0.1:{port}").parse().unwrap(),
+                    Duration::from_secs(3),
+                )
+                .unwrap();
+            manager2
+        })
+        .collect();
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(manager.active_connections.read().nb_in_connections, 2);
+    for manager2 in &managers {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for i in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![i; 100], false)
+                .unwrap();
+        }
+    }
+    let mut received: Vec<Bytes> = (0..20)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    received.sort_unstable();
+    let expected: Vec<Bytes> = (0..10)
+        .flat_map(|i| std::iter::repeat(Bytes::from(vec![i; 100])).take(2))
+        .collect();
+    assert_eq!(received, expected);
+
+    // the loop of the listener notices when a peer leaves
+    drop(managers.pop());
+    std::thread::sleep(Duration::from_secs(1));
+    assert_eq!(manager.active_connections.read().nb_in_connections, 1);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn tcp_reactor_streamed_message() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager =
+        PeerNetManager::new(reactor_config(StreamingMessagesHandler { events: sender })).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let (sender2, _receiver2) = crossbeam::channel::unbounded();
+    let mut manager2 =
+        PeerNetManager::new(test_config(StreamingMessagesHandler { events: sender2 })).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, (0..10).collect(), false)
+            .unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2], false)
+            .unwrap();
+    }
+    let events: Vec<String> = (0..6)
+        .map(|_| receiver.recv_timeout(Duration::from_secs(3)).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "start 10",
+            "chunk [0, 1, 2, 3]",
+            "chunk [4, 5, 6, 7]",
+            "chunk [8, 9]",
+            "end",
+            "message [1, 2]",
+        ]
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Forwards the messages to `received`, those starting with 0 once the test opens `gate`
+#[derive(Clone)]
+struct GatedMessagesHandler {
+    gate: Receiver<()>,
+    received: Sender<Bytes>,
+}
+
+impl MessagesHandler<DefaultPeerId> for GatedMessagesHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        if data.first() == Some(&0) {
+            self.gate
+                .recv()
+                .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))?;
+        }
+        self.received
+            .send(data)
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+#[test]
+fn tcp_reactor_handler_workers_backpressure() {
+    let (gate_sender, gate) = crossbeam::channel::unbounded();
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut config = reactor_config(GatedMessagesHandler {
+        gate,
+        received: sender.clone(),
+    });
+    config.optional_features = config
+        .optional_features
+        .set_tcp_reactor(TcpReactor { nb_event_loops: 1 })
+        .set_handler_workers(HandlerWorkers {
+            nb_workers: 3,
+            queue_size: 100,
+            max_in_flight_bytes_per_peer: Some(8),
+            max_in_flight_bytes: None,
+            dispatch_timeout: None,
+        });
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // both peers are driven by the only event loop of the listener
+    let managers: Vec<_> = (0..2)
+        .map(|_| {
+            let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+                echo: false,
+                received: sender.clone(),
+            }))
+            .unwrap();
+            connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+            manager2
+        })
+        .collect();
+    assert!(eventually(|| manager.nb_in_connections() == 2));
+    for (tag, manager2) in managers.iter().enumerate() {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for _ in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![tag as u8; 4], false)
+                .unwrap();
+        }
+    }
+    // the first peer has two messages in the workers and its reads wait, the loop keeps
+    // reading the second one
+    for _ in 0..10 {
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            vec![1; 4]
+        );
+    }
+    assert_eq!(manager.in_flight_bytes(), 8);
+    assert!(receiver.try_recv().is_err());
+
+    // its reads continue as the workers release its messages
+    for _ in 0..10 {
+        gate_sender.send(()).unwrap();
+    }
+    for _ in 0..10 {
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            vec![0; 4]
+        );
+    }
+    assert!(eventually(|| manager.in_flight_bytes() == 0));
+    assert_eq!(manager.nb_in_connections(), 2);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+/// Forwards the messages it receives tagged with its name
+struct TaggedMessageHandler {
+    name: &'static str,
+    received: Sender<(&'static str, Bytes)>,
+}
+
+impl MessageHandler<DefaultPeerId> for TaggedMessageHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send((self.name, data))
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+fn tagged_handlers(received: Sender<(&'static str, Bytes)>) -> MessageHandlers<DefaultPeerId> {
+    let mut handlers = MessageHandlers::new();
+    handlers
+        .add_handler(
+            1,
+            TaggedMessageHandler {
+                name: "ping",
+                received: received.clone(),
+            },
+        )
+        .unwrap();
+    handlers
+        .add_handler(
+            2,
+            TaggedMessageHandler {
+                name: "blocks",
+                received,
+            },
+        )
+        .unwrap();
+    handlers
+}
+
+#[test]
+fn routed_messages() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let mut manager = PeerNetManager::new(test_config(tagged_handlers(sender.clone()))).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut manager2 = PeerNetManager::new(test_config(tagged_handlers(sender))).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for (handler_id, message) in [(2, vec![4, 5]), (1, vec![3])] {
+            connection
+                .send_channels
+                .send(
+                    &RoutedSerializer {
+                        handler_id,
+                        serializer: RawSerializer,
+                    },
+                    message,
+                    false,
+                )
+                .unwrap();
+        }
+    }
+    let (name, data) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!((name, &data[..]), ("blocks", &[4, 5][..]));
+    let (name, data) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!((name, &data[..]), ("ping", &[3][..]));
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn routed_message_unknown_id() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let handlers = tagged_handlers(sender);
+    let peer_id = DefaultPeerId::generate();
+    assert!(handlers
+        .handle(Bytes::from(3u64.to_be_bytes().to_vec()), &peer_id)
+        .is_err());
+    // too short to hold a handler id
+    assert!(handlers.handle(Bytes::from(vec![0, 1]), &peer_id).is_err());
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn protocols_negotiation() {
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender.clone(),
+    });
+    config.optional_features =
+        PeerNetFeatures::default().set_protocols(vec!["ping".to_string(), "blocks".to_string()]);
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    });
+    config.optional_features = PeerNetFeatures::default()
+        .set_protocols(vec!["blocks".to_string(), "transactions".to_string()]);
+    let mut manager2 = PeerNetManager::new(config).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+    for manager_connections in [&manager.active_connections, &manager2.active_connections] {
+        let active_connections = manager_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.protocols, vec!["blocks".to_string()]);
+        assert!(connection.supports_protocol("blocks"));
+        assert!(!connection.supports_protocol("ping"));
+    }
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn metadata_exchange() {
+    let metadata = |values: &[(&str, String)]| PeerMetadata {
+        values: values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+        max_size: 1024,
+    };
+    let manager = |values: &[(&str, String)]| {
+        let (sender, _receiver) = crossbeam::channel::unbounded();
+        let mut config = test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender,
+        });
+        config.optional_features = PeerNetFeatures::default().set_metadata(metadata(values));
+        PeerNetManager::new(config).unwrap()
+    };
+    let mut manager1 = manager(&[
+        ("version", "1.0".to_string()),
+        ("chain", "main".to_string()),
+    ]);
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager1.start_listener(TransportType::Tcp, addr).unwrap();
+
+    let mut manager2 = manager(&[("version", "2.0".to_string())]);
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager1.nb_in_connections() == 1));
+    let snapshot = manager1.connections_snapshot();
+    assert_eq!(
+        snapshot.connections[0].metadata,
+        BTreeMap::from([("version".to_string(), "2.0".to_string())])
+    );
+    assert_eq!(
+        manager1.dump_state().peers[0].metadata["version"],
+        "2.0".to_string()
+    );
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.metadata["version"], "1.0");
+        assert_eq!(connection.metadata["chain"], "main");
+    }
+
+    // the peers sending more than `max_size` are refused
+    let mut manager3 = manager(&[("padding", "0".repeat(2000))]);
+    manager3
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    assert!(!eventually(|| manager1.nb_in_connections() > 1));
+    assert!(manager3.active_connections.read().connections.is_empty());
+
+    manager1.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+/// Drops the messages starting with 1, disconnects the peers sending more than 500 bytes
+struct TestMessageFilter;
+
+impl MessageFilter<DefaultPeerId> for TestMessageFilter {
+    fn check(&self, _peer_id: &DefaultPeerId, size: usize, first_bytes: &[u8]) -> MessageVerdict {
+        if size > 500 {
+            MessageVerdict::Disconnect(format!("{} bytes", size))
+        } else if first_bytes.first() == Some(&1) {
+            MessageVerdict::Drop
+        } else {
+            MessageVerdict::Accept
+        }
+    }
+}
+
+#[test]
+fn message_filter() {
+    // by the read loop and by an event loop
+    for tcp_reactor in [None, Some(TcpReactor { nb_event_loops: 1 })] {
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        let mut config = test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender.clone(),
+        });
+        config.optional_features = PeerNetFeatures::default().set_connection_history(10);
+        if let Some(tcp_reactor) = tcp_reactor {
+            config.optional_features = config.optional_features.set_tcp_reactor(tcp_reactor);
+        }
+        let mut manager = PeerNetManager::new(config).unwrap();
+        manager.set_message_filter(Some(Arc::new(TestMessageFilter)));
+        let port = get_tcp_port(10000..u16::MAX);
+        let addr = format!("127.0.0.1:{port}").parse().unwrap();
+        manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+        let mut manager2 = PeerNetManager::new(test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender,
+        }))
+        .unwrap();
+        manager2
+            .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        {
+            let active_connections = manager2.active_connections.read();
+            let connection = active_connections.connections.values().next().unwrap();
+            for message in [vec![1, 2], vec![0, 3], vec![0; 600]] {
+                connection
+                    .send_channels
+                    .send(&RawSerializer, message, false)
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(3)).unwrap(),
+            Bytes::from(vec![0, 3])
+        );
+        std::thread::sleep(Duration::from_millis(500));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(manager.nb_in_connections(), 0);
+        assert!(manager.recent_events(10).iter().any(|event| matches!(
+            &event.kind,
+            ConnectionEventKind::Disconnected {
+                reason: DisconnectReason::Filtered(reason),
+                ..
+            } if reason == "600 bytes"
+        )));
+
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+fn proof_of_work_manager(
+    difficulty: u8,
+    max_difficulty: u8,
+) -> PeerNetManager<DefaultPeerId, DefaultContext, EmptyInitConnection, EchoMessagesHandler> {
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let mut config = test_config(EchoMessagesHandler {
+        echo: false,
+        received: sender,
+    });
+    config.optional_features = PeerNetFeatures::default().set_proof_of_work(ProofOfWork {
+        difficulty,
+        trusted_categories: Vec::new(),
+        max_difficulty,
+    });
+    PeerNetManager::new(config).unwrap()
+}
+
+#[test]
+fn proof_of_work_challenge() {
+    let challenge = [42; CHALLENGE_SIZE];
+    let nonce = solve_challenge(&challenge, 8);
+    assert!(check_solution(&challenge, 8, nonce));
+    assert!(!check_solution(&[43; CHALLENGE_SIZE], 16, nonce));
+
+    let mut manager = proof_of_work_manager(8, 8);
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // solves the challenge
+    let mut manager2 = proof_of_work_manager(8, 16);
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+    // refuses to solve such a difficult challenge
+    let mut manager3 = proof_of_work_manager(8, 4);
+    manager3
+        .try_connect(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            Duration::from_secs(3),
+        )
+        .unwrap();
+    assert!(!eventually(|| manager.nb_in_connections() > 1));
+    assert_eq!(manager3.active_connections.read().connections.len(), 0);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn observed_addresses() {
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let observed_addresses_config = |min_confirmations| {
+        let mut config = test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender.clone(),
+        });
+        config.optional_features = PeerNetFeatures::default()
+            .set_observed_addresses(ObservedAddresses { min_confirmations });
+        config
+    };
+    let mut manager = PeerNetManager::new(observed_addresses_config(1)).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    assert!(manager.external_addresses().is_empty());
+
+    let mut manager2 = PeerNetManager::new(observed_addresses_config(2)).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    // the second peer saw us on our IP, our listener is reachable on it
+    assert_eq!(
+        manager.external_addresses(),
+        vec![format!("127.0.0.1:{port}").parse().unwrap()]
+    );
+    // one report isn't enough for the second peer, that has no listener anyway
+    assert_eq!(
+        manager2.active_connections.read().observed_addresses.len(),
+        1
+    );
+    assert!(manager2.external_addresses().is_empty());
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn observed_addresses_one_vote_per_network() {
+    let (sender, _receiver) = crossbeam::channel::unbounded();
+    let observed_addresses_config = || {
+        let mut config = test_config(EchoMessagesHandler {
+            echo: false,
+            received: sender.clone(),
+        });
+        config.optional_features =
+            PeerNetFeatures::default().set_observed_addresses(ObservedAddresses {
+                min_confirmations: 2,
+            });
+        config
+    };
+    let mut manager = PeerNetManager::new(observed_addresses_config()).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: std::net::SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+
+    let mut managers: Vec<_> = (0..2)
+        .map(|_| PeerNetManager::new(observed_addresses_config()).unwrap())
+        .collect();
+    for other in &mut managers {
+        other
+            .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+            .unwrap();
+    }
+    assert!(eventually(|| {
+        manager.active_connections.read().observed_addresses.len() == 2
+    }));
+    // both peers reported our IP, but from the same host
+    assert!(manager.external_addresses().is_empty());
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+fn ping_handlers(ping_handler: &PingHandler<DefaultPeerId>) -> MessageHandlers<DefaultPeerId> {
+    let mut handlers = MessageHandlers::new();
+    handlers.add_handler(1, ping_handler.clone()).unwrap();
+    handlers
+}
+
+#[test]
+fn ping_latencies() {
+    let server_ping = PingHandler::new(1);
+    let mut manager = PeerNetManager::new(test_config(ping_handlers(&server_ping))).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let client_ping = PingHandler::new(1);
+    let mut manager2 = PeerNetManager::new(test_config(ping_handlers(&client_ping))).unwrap();
+    manager2
+        .try_connect(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            Duration::from_secs(3),
+        )
+        .unwrap();
+    let pinger = Pinger::start(
+        manager2.active_connections.clone(),
+        client_ping.clone(),
+        Duration::from_millis(50),
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    pinger.stop();
+
+    let server_id = manager2
+        .active_connections
+        .read()
+        .connections
+        .keys()
+        .next()
+        .unwrap()
+        .clone();
+    let stats = client_ping.latency(&server_id).unwrap();
+    assert!(stats.nb_samples > 5);
+    assert!(stats.p50 <= stats.p95 && stats.p95 <= stats.p99 && stats.p99 <= stats.max);
+    assert!(stats.max < Duration::from_secs(1));
+    assert_eq!(client_ping.latencies()[&server_id], stats);
+    // the server only answers
+    assert!(server_ping.latencies().is_empty());
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn latency_histogram() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.stats(), None);
+    for millis in 1..=100 {
+        histogram.record(Duration::from_millis(millis));
+    }
+    let stats = histogram.stats().unwrap();
+    assert_eq!(stats.nb_samples, 100);
+    assert_eq!(stats.max, Duration::from_millis(100));
+    // upper bounds of the buckets, at most a quarter of a power of two above the samples
+    for (percentile, expected) in [(stats.p50, 50), (stats.p95, 95), (stats.p99, 99)] {
+        let expected = Duration::from_millis(expected);
+        assert!(percentile >= expected && percentile <= expected * 5 / 4);
+    }
+}
@@ -0,0 +1,166 @@
+// Managers connected by the in-memory transport, without sockets
+#![cfg(feature = "testing")]
+mod util;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::thread::sleep;
+use std::time::Duration;
+
+use peernet::config::{PeerNetCategoryInfo, PeerNetConfigurationBuilder, PeerNetFeatures};
+use peernet::history::ConnectionEventKind;
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::PeerConnectionType;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
+use util::{
+    DefaultContext, DefaultInitConnection, DefaultMessagesHandler, DefaultPeerId, RawSerializer,
+};
+
+type Manager =
+    PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, DefaultMessagesHandler>;
+
+fn manager(default_category_info: PeerNetCategoryInfo, partners: Vec<IpAddr>) -> Manager {
+    let config = PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        DefaultMessagesHandler {},
+    )
+    .set_optional_features(PeerNetFeatures::default().set_connection_history(10))
+    .set_default_category_info(default_category_info)
+    .set_peers_categories(HashMap::from([(
+        "partners".to_string(),
+        (partners, limits(5)),
+    )]))
+    .build()
+    .unwrap();
+    PeerNetManager::new(config).unwrap()
+}
+
+fn limits(max_in_connections: usize) -> PeerNetCategoryInfo {
+    PeerNetCategoryInfo {
+        max_in_connections: Some(max_in_connections),
+        max_in_connections_per_ip: Some(max_in_connections),
+        max_out_connections: Some(10),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    }
+}
+
+/// Manager with a mock listener on `addr`, which it also dials from
+fn listening_manager(addr: SocketAddr, max_in_connections: usize) -> Manager {
+    let mut manager = manager(
+        limits(max_in_connections),
+        vec!["10.1.0.1".parse().unwrap()],
+    );
+    manager.start_listener(TransportType::Mock, addr).unwrap();
+    manager
+}
+
+#[test]
+fn mock_transport_connect() {
+    let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+    let mut other = manager(limits(10), Vec::new());
+    let mut manager = listening_manager(addr, 10);
+    // the address is taken in the process
+    assert!(other.start_listener(TransportType::Mock, addr).is_err());
+
+    let mut manager2 = listening_manager("10.0.0.2:1000".parse().unwrap(), 10);
+    manager2
+        .try_connect(TransportType::Mock, addr, Duration::from_secs(1))
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap();
+    sleep(Duration::from_millis(200));
+    assert_eq!(manager.nb_in_connections(), 1);
+    let snapshot = manager.connections_snapshot();
+    assert_eq!(snapshot.nb_in_connections, 1);
+    assert_eq!(
+        snapshot.connections[0].addr.ip(),
+        "10.0.0.2".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(snapshot.connections[0].direction, PeerConnectionType::IN);
+    assert_eq!(manager.dump_state().peers[0].transport, "mock");
+
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(*connection.endpoint.get_target_addr(), addr);
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![0; 100], false)
+            .unwrap();
+    }
+    sleep(Duration::from_millis(200));
+    assert_eq!(manager.get_total_bytes_received(), 100);
+    assert_eq!(manager2.get_total_bytes_sent(), 100);
+
+    // no listener on the address
+    assert!(manager2
+        .try_connect(
+            TransportType::Mock,
+            "10.0.0.3:1000".parse().unwrap(),
+            Duration::from_secs(1)
+        )
+        .unwrap()
+        .join()
+        .unwrap()
+        .is_err());
+
+    // the peers accepted by the listener are stopped with it
+    manager.stop_listener(TransportType::Mock, addr).unwrap();
+    sleep(Duration::from_millis(200));
+    assert_eq!(manager.nb_in_connections(), 0);
+    assert_eq!(manager2.active_connections.read().nb_out_connections, 0);
+    assert!(manager2
+        .try_connect(TransportType::Mock, addr, Duration::from_secs(1))
+        .unwrap()
+        .join()
+        .unwrap()
+        .is_err());
+}
+
+#[test]
+fn mock_transport_limits_and_categories() {
+    let addr: SocketAddr = "10.0.1.1:1000".parse().unwrap();
+    let mut manager = listening_manager(addr, 1);
+
+    let mut dialers: Vec<Manager> = ["10.0.1.2:1000", "10.0.1.3:1000", "10.1.0.1:1000"]
+        .iter()
+        .map(|dialer_addr| listening_manager(dialer_addr.parse().unwrap(), 10))
+        .collect();
+    for dialer in &mut dialers {
+        dialer
+            .try_connect(TransportType::Mock, addr, Duration::from_secs(1))
+            .unwrap();
+        sleep(Duration::from_millis(200));
+    }
+
+    // the second peer of the default category is refused, the partner is accepted
+    let snapshot = manager.connections_snapshot();
+    let mut connections: Vec<(IpAddr, Option<String>)> = snapshot
+        .connections
+        .iter()
+        .map(|connection| (connection.addr.ip(), connection.category.clone()))
+        .collect();
+    connections.sort();
+    assert_eq!(
+        connections,
+        vec![
+            ("10.0.1.2".parse().unwrap(), None),
+            ("10.1.0.1".parse().unwrap(), Some("partners".to_string())),
+        ]
+    );
+    assert_eq!(dialers[1].active_connections.read().nb_out_connections, 0);
+    assert!(manager.recent_events(10).iter().any(|event| {
+        event.addr.ip() == "10.0.1.3".parse::<IpAddr>().unwrap()
+            && event.kind
+                == ConnectionEventKind::Refused {
+                    direction: PeerConnectionType::IN,
+                }
+    }));
+    manager.stop_listener(TransportType::Mock, addr).unwrap();
+}
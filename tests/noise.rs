@@ -0,0 +1,136 @@
+#![cfg(feature = "snow")]
+mod util;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use peernet::config::{
+    PeerIdRules, PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures, QuicSettings,
+    TcpSettings, TrustedPeers,
+};
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::messages::{Bytes, MessagesHandler};
+use peernet::network_manager::PeerNetManager;
+use peernet::noise::NoiseInitConnectionHandler;
+use peernet::transports::TransportType;
+
+use crate::util::{connect_tcp, get_tcp_port, DefaultContext, DefaultPeerId, RawSerializer};
+
+fn peer_id_from_key(key: &[u8]) -> PeerNetResult<DefaultPeerId> {
+    let id_bytes = key[..8]
+        .try_into()
+        .map_err(|_| PeerNetError::PeerIdError.error("peer id from key", None))?;
+    Ok(DefaultPeerId {
+        id: u64::from_be_bytes(id_bytes),
+    })
+}
+
+#[derive(Clone)]
+struct ForwardMessagesHandler {
+    received: Sender<(DefaultPeerId, Bytes)>,
+}
+
+impl MessagesHandler<DefaultPeerId> for ForwardMessagesHandler {
+    fn handle(&self, data: Bytes, peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send((peer_id.clone(), data))
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+fn noise_config(
+    received: Sender<(DefaultPeerId, Bytes)>,
+) -> PeerNetConfiguration<
+    DefaultPeerId,
+    DefaultContext,
+    NoiseInitConnectionHandler<DefaultPeerId>,
+    ForwardMessagesHandler,
+> {
+    let (private_key, public_key) =
+        NoiseInitConnectionHandler::<DefaultPeerId>::generate_keypair().unwrap();
+    PeerNetConfiguration {
+        context: DefaultContext {
+            our_id: peer_id_from_key(&public_key).unwrap(),
+        },
+        max_in_connections: Some(10),
+        init_connection_handler: NoiseInitConnectionHandler::new(
+            private_key,
+            public_key,
+            peer_id_from_key,
+        ),
+        optional_features: PeerNetFeatures::default(),
+        message_handler: ForwardMessagesHandler { received },
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 1024 * 1024,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 1024 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    }
+}
+
+#[test]
+fn two_peers_tcp_noise() {
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let config = noise_config(sender.clone());
+    let id1 = config.context.our_id.clone();
+    let mut manager = PeerNetManager::new(config).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    let config = noise_config(sender);
+    let id2 = config.context.our_id.clone();
+    let mut manager2 = PeerNetManager::new(config).unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+    {
+        // the peers are identified by their static keys
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.get(&id1).unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![7; 100_000], false)
+            .unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2, 3], false)
+            .unwrap();
+    }
+    let (peer_id, data) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(peer_id, id2);
+    assert_eq!(data, vec![7; 100_000]);
+    let (_, data) = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(data, vec![1, 2, 3]);
+    // the frames on the wire also carry the handshake and the authentication tags
+    assert!(manager.get_total_bytes_received() > 100_000 + 16 + 3 + 16);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
@@ -2,7 +2,10 @@ use std::collections::HashMap;
 use std::println;
 use std::time::{Duration, Instant};
 
-use peernet::config::{PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures};
+use peernet::config::{
+    PeerIdRules, PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures, QuicSettings,
+    TcpSettings, TrustedPeers,
+};
 use peernet::network_manager::PeerNetManager;
 use peernet::peer_id::PeerId;
 use peernet::{peer::InitConnectionHandler, transports::TransportType};
@@ -41,16 +44,23 @@ impl<R: Rng> TestParameters<R> {
         };
 
         PeerNetConfiguration {
-            read_timeout: Duration::from_secs(10),
-            write_timeout: Duration::from_secs(10),
+            tcp: TcpSettings {
+                max_message_size: Some(1048576000),
+                rate_limit: self.rl,
+                rate_time_window: self.rtw,
+                rate_bucket_size: self.rbs,
+                read_timeout: Duration::from_secs(10),
+                write_timeout: Duration::from_secs(10),
+                listener_poll_interval: Duration::from_secs(1),
+                keepalive: None,
+                linger: None,
+            },
+            quic: QuicSettings::default(),
             optional_features: PeerNetFeatures::default(),
             message_handler: DefaultMessagesHandler {},
             peers_categories: HashMap::default(),
 
             // Got from existing config if any
-            rate_bucket_size: self.rbs,
-            rate_limit: self.rl,
-            rate_time_window: self.rtw,
             init_connection_handler: if let Some(mut i) = init {
                 i.id = context.our_id.clone();
                 i
@@ -59,14 +69,18 @@ impl<R: Rng> TestParameters<R> {
             },
 
             // Constants
-            max_in_connections: 10,
+            max_in_connections: Some(10),
             send_data_channel_size: 1000,
-            max_message_size: 1048576000,
             default_category_info: PeerNetCategoryInfo {
-                max_in_connections: 10,
-                max_in_connections_per_ip: 10,
-                max_out_connections: 10,
+                max_in_connections: Some(10),
+                max_in_connections_per_ip: Some(10),
+                max_out_connections: Some(10),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
             },
+            trusted_peers: TrustedPeers::default(),
+            peer_id_rules: PeerIdRules::default(),
             _phantom: std::marker::PhantomData,
             context,
         }
@@ -125,7 +139,7 @@ impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler
 
         endpoint.send::<DefaultPeerId>(&self.id.id.to_be_bytes())?;
         let remote_id = endpoint.receive::<DefaultPeerId>()?;
-        let remote_id = u64::from_be_bytes(remote_id.try_into().unwrap());
+        let remote_id = u64::from_be_bytes(remote_id[..].try_into().unwrap());
 
         println!("Handshake OK in {:?}", now.elapsed());
         Ok(DefaultPeerId { id: remote_id })
@@ -145,7 +159,7 @@ fn handshake_with_limiter() {
             DefaultContext,
             DefaultInitConnection,
             DefaultMessagesHandler,
-        > = PeerNetManager::new(config);
+        > = PeerNetManager::new(config).unwrap();
 
         let port = get_tcp_port(1024..u16::MAX);
 
@@ -162,7 +176,7 @@ fn handshake_with_limiter() {
             DefaultContext,
             DefaultInitConnection,
             DefaultMessagesHandler,
-        > = PeerNetManager::new(config2);
+        > = PeerNetManager::new(config2).unwrap();
 
         let now = Instant::now();
         manager2
@@ -0,0 +1,204 @@
+// Property tests of the parsing of the data received from the peers, which must reject
+// anything malformed without panicking. The fuzz targets in `fuzz/` run the same parsers.
+#![cfg(feature = "testing")]
+mod util;
+use std::net::{IpAddr, SocketAddr};
+
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::internal_handlers::peer_management::{
+    Announcement, ListenersMap, PeerManagementHooks, PeerManagementMessage,
+    PeerManagementMessageSerializer,
+};
+use peernet::messages::MessagesSerializer;
+use peernet::transports::{decode_frames, read_frames, FrameEvent, TransportType};
+use proptest::collection::{hash_map, vec};
+use proptest::prelude::*;
+use util::DefaultPeerId;
+
+/// Ids on 8 bytes, the signatures aren't checked by the parsing
+#[derive(Clone)]
+struct TestHooks;
+
+impl PeerManagementHooks<DefaultPeerId> for TestHooks {
+    fn sign(&self, _data: &[u8]) -> PeerNetResult<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    fn verify(
+        &self,
+        _peer_id: &DefaultPeerId,
+        _data: &[u8],
+        _signature: &[u8],
+    ) -> PeerNetResult<()> {
+        Ok(())
+    }
+
+    fn serialize_peer_id(
+        &self,
+        peer_id: &DefaultPeerId,
+        buffer: &mut Vec<u8>,
+    ) -> PeerNetResult<()> {
+        buffer.extend_from_slice(&peer_id.id.to_be_bytes());
+        Ok(())
+    }
+
+    fn deserialize_peer_id(&self, data: &[u8]) -> PeerNetResult<(DefaultPeerId, usize)> {
+        let id = data
+            .get(..8)
+            .ok_or_else(|| PeerNetError::InvalidMessage.error("deserialize id", None))?;
+        Ok((
+            DefaultPeerId {
+                id: u64::from_be_bytes(id.try_into().unwrap()),
+            },
+            8,
+        ))
+    }
+}
+
+fn listeners() -> impl Strategy<Value = ListenersMap> {
+    // the flow info and scope id of IPv6 addresses aren't sent
+    let addr = (any::<IpAddr>(), any::<u16>()).prop_map(|(ip, port)| SocketAddr::new(ip, port));
+    let transport = prop_oneof![Just(TransportType::Tcp), Just(TransportType::Quic)];
+    hash_map(addr, transport, 0..10)
+}
+
+fn announcement() -> impl Strategy<Value = Announcement> {
+    (listeners(), any::<u64>(), vec(any::<u8>(), 0..100)).prop_map(
+        |(listeners, timestamp, signature)| Announcement {
+            listeners,
+            timestamp,
+            signature,
+        },
+    )
+}
+
+fn peer_management_message() -> impl Strategy<Value = PeerManagementMessage<DefaultPeerId>> {
+    prop_oneof![
+        announcement().prop_map(PeerManagementMessage::NewPeerConnected),
+        vec(
+            (
+                any::<u64>().prop_map(|id| DefaultPeerId { id }),
+                announcement()
+            ),
+            0..10
+        )
+        .prop_map(PeerManagementMessage::ListPeers),
+    ]
+}
+
+fn serialize(message: &PeerManagementMessage<DefaultPeerId>) -> Vec<u8> {
+    let mut data = Vec::new();
+    PeerManagementMessageSerializer { hooks: TestHooks }
+        .serialize(message, &mut data)
+        .unwrap();
+    data
+}
+
+fn deserialize(data: &[u8]) -> PeerNetResult<PeerManagementMessage<DefaultPeerId>> {
+    PeerManagementMessageSerializer { hooks: TestHooks }.deserialize(data)
+}
+
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(message);
+    frame
+}
+
+proptest! {
+    #[test]
+    fn peer_management_roundtrip(message in peer_management_message()) {
+        prop_assert_eq!(deserialize(&serialize(&message)).unwrap(), message);
+    }
+
+    #[test]
+    fn peer_management_truncated(message in peer_management_message(), cut in any::<prop::sample::Index>()) {
+        let data = serialize(&message);
+        prop_assert!(deserialize(&data[..cut.index(data.len())]).is_err());
+    }
+
+    #[test]
+    fn peer_management_arbitrary_bytes(data in vec(any::<u8>(), 0..500)) {
+        if let Ok(message) = deserialize(&data) {
+            // anything accepted is read back the same once written again
+            prop_assert_eq!(deserialize(&serialize(&message)).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn frames_roundtrip(
+        messages in vec(vec(any::<u8>(), 1..100), 0..10),
+        partial in vec(any::<u8>(), 0..4),
+        read_size in 1usize..20,
+    ) {
+        let mut data: Vec<u8> = messages.iter().flat_map(|message| frame(message)).collect();
+        // a header cut short is the start of the next frame
+        data.extend_from_slice(&partial);
+        let messages: Vec<_> = messages.into_iter().map(FrameEvent::Message).collect();
+        for (events, error) in [
+            read_frames(&data, read_size, Some(100), None),
+            decode_frames(&data, read_size, Some(100), None),
+        ] {
+            prop_assert_eq!(&events, &messages);
+            prop_assert_eq!(error.error_type(), &PeerNetError::ConnectionClosed);
+        }
+    }
+
+    #[test]
+    fn frames_streamed(
+        messages in vec(vec(any::<u8>(), 1..100), 0..10),
+        chunk_size in 0usize..30,
+        read_size in 1usize..20,
+    ) {
+        let data: Vec<u8> = messages.iter().flat_map(|message| frame(message)).collect();
+        let (events, error) = read_frames(&data, read_size, Some(100), Some(chunk_size));
+        prop_assert_eq!(error.error_type(), &PeerNetError::ConnectionClosed);
+        // the chunks put back together give the messages
+        let mut received = Vec::new();
+        let mut streamed: Option<Vec<u8>> = None;
+        for event in events {
+            match event {
+                FrameEvent::Message(message) => {
+                    prop_assert!(message.len() <= chunk_size.max(1));
+                    received.push(message);
+                }
+                FrameEvent::MessageStart(size) => {
+                    prop_assert!(size > chunk_size.max(1));
+                    streamed = Some(Vec::new());
+                }
+                FrameEvent::Chunk(chunk) => {
+                    prop_assert!(chunk.len() <= chunk_size.max(1));
+                    streamed.as_mut().unwrap().extend_from_slice(&chunk);
+                }
+                FrameEvent::MessageEnd => received.push(streamed.take().unwrap()),
+            }
+        }
+        prop_assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn frames_arbitrary_bytes(
+        data in vec(any::<u8>(), 0..500),
+        max_message_size in 0usize..100,
+        chunk_size in prop::option::of(0usize..30),
+        read_size in 1usize..20,
+    ) {
+        let (events, error) = read_frames(&data, read_size, Some(max_message_size), chunk_size);
+        // the endpoints and the event loops read the same frames
+        let (loop_events, loop_error) =
+            decode_frames(&data, read_size, Some(max_message_size), chunk_size);
+        prop_assert_eq!(&events, &loop_events);
+        prop_assert_eq!(error.error_type(), loop_error.error_type());
+        // only a header over the limit is refused
+        prop_assert!(matches!(
+            error.error_type(),
+            PeerNetError::ConnectionClosed | PeerNetError::InvalidMessage
+        ));
+        for event in &events {
+            match event {
+                FrameEvent::Message(message) => prop_assert!(message.len() <= max_message_size),
+                FrameEvent::MessageStart(size) => prop_assert!(*size <= max_message_size),
+                FrameEvent::Chunk(_) | FrameEvent::MessageEnd => {}
+            }
+        }
+    }
+}
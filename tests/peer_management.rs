@@ -0,0 +1,1397 @@
+mod util;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+
+use peernet::address_book::AddressBook;
+use peernet::config::{
+    PeerIdRules, PeerNetCategoryInfo, PeerNetConfiguration, PeerNetFeatures, QuicSettings,
+    TcpSettings, TrustedPeers,
+};
+use peernet::context::Context;
+use peernet::discovery::kad::{kad_key, KadHandler, KadMessage};
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::handlers::{MessageHandler, MessageHandlers, RoutedSerializer};
+use peernet::internal_handlers::peer_management::{
+    Announcement, PeerDB, PeerManagementHandler, PeerManagementHooks, PeerManagementMessage,
+    MAX_ANNOUNCEMENT_CLOCK_DRIFT, MAX_LISTENERS_PER_PEER,
+};
+use peernet::internal_handlers::relay::{RelayConfig, RelayHandler};
+use peernet::internal_handlers::supervisor::{
+    ConnectionSupervisor, DialBackoff, PeerRotation, SupervisorConfig,
+};
+use peernet::internal_handlers::tester::{Tester, TesterConfig};
+use peernet::messages::{Bytes, MessagesSerializer};
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::InitConnectionHandler;
+use peernet::peer_id::PeerId;
+use peernet::peer_list::{PeerListSnapshot, PEER_LIST_VERSION};
+use peernet::transports::{endpoint::Endpoint, TransportType};
+
+use crate::util::{
+    connect_tcp, eventually, get_tcp_port, DefaultContext, DefaultPeerId, RawSerializer,
+};
+
+const PEER_MANAGEMENT_HANDLER_ID: u64 = 1;
+const KAD_HANDLER_ID: u64 = 2;
+const RELAY_HANDLER_ID: u64 = 3;
+const FORWARD_HANDLER_ID: u64 = 4;
+
+/// Each peer sends its id in clear
+#[derive(Clone)]
+struct IdExchangeInitConnection;
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, MessageHandlers<DefaultPeerId>>
+    for IdExchangeInitConnection
+{
+    fn perform_handshake(
+        &mut self,
+        context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: MessageHandlers<DefaultPeerId>,
+    ) -> PeerNetResult<DefaultPeerId> {
+        endpoint.send::<DefaultPeerId>(&context.get_peer_id().id.to_be_bytes())?;
+        let id = endpoint.receive::<DefaultPeerId>()?;
+        let id = id[..]
+            .try_into()
+            .map_err(|_| PeerNetError::HandshakeError.error("receive id", None))?;
+        Ok(DefaultPeerId {
+            id: u64::from_be_bytes(id),
+        })
+    }
+}
+
+/// The signature is a hash of the id of the signer and the data, only to test the protocol
+#[derive(Clone)]
+struct TestHooks {
+    our_id: DefaultPeerId,
+}
+
+fn test_signature(peer_id: &DefaultPeerId, data: &[u8]) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    peer_id.hash(&mut hasher);
+    data.hash(&mut hasher);
+    hasher.finish().to_be_bytes().to_vec()
+}
+
+impl PeerManagementHooks<DefaultPeerId> for TestHooks {
+    fn sign(&self, data: &[u8]) -> PeerNetResult<Vec<u8>> {
+        Ok(test_signature(&self.our_id, data))
+    }
+
+    fn verify(&self, peer_id: &DefaultPeerId, data: &[u8], signature: &[u8]) -> PeerNetResult<()> {
+        if test_signature(peer_id, data) != signature {
+            return Err(PeerNetError::SignError.error("verify", None));
+        }
+        Ok(())
+    }
+
+    fn serialize_peer_id(
+        &self,
+        peer_id: &DefaultPeerId,
+        buffer: &mut Vec<u8>,
+    ) -> PeerNetResult<()> {
+        buffer.extend_from_slice(&peer_id.id.to_be_bytes());
+        Ok(())
+    }
+
+    fn deserialize_peer_id(&self, data: &[u8]) -> PeerNetResult<(DefaultPeerId, usize)> {
+        let id = data
+            .get(..8)
+            .ok_or_else(|| PeerNetError::InvalidMessage.error("deserialize id", None))?;
+        Ok((
+            DefaultPeerId {
+                id: u64::from_be_bytes(id.try_into().unwrap()),
+            },
+            8,
+        ))
+    }
+}
+
+type TestManager = PeerNetManager<
+    DefaultPeerId,
+    DefaultContext,
+    IdExchangeInitConnection,
+    MessageHandlers<DefaultPeerId>,
+>;
+
+fn peer_management_manager() -> (
+    TestManager,
+    PeerManagementHandler<DefaultPeerId, TestHooks>,
+    KadHandler<DefaultPeerId, TestHooks>,
+) {
+    peer_management_manager_with(|peer_management| peer_management)
+}
+
+/// Same as `peer_management_manager` with the settings of the handler changed by `configure`
+fn peer_management_manager_with(
+    configure: impl FnOnce(
+        PeerManagementHandler<DefaultPeerId, TestHooks>,
+    ) -> PeerManagementHandler<DefaultPeerId, TestHooks>,
+) -> (
+    TestManager,
+    PeerManagementHandler<DefaultPeerId, TestHooks>,
+    KadHandler<DefaultPeerId, TestHooks>,
+) {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let peer_management = configure(PeerManagementHandler::new(
+        PEER_MANAGEMENT_HANDLER_ID,
+        &context,
+        TestHooks {
+            our_id: context.our_id.clone(),
+        },
+    ));
+    let kad = KadHandler::new(
+        KAD_HANDLER_ID,
+        &context,
+        20,
+        TestHooks {
+            our_id: context.our_id.clone(),
+        },
+        peer_management.peer_db.clone(),
+    )
+    .unwrap();
+    let mut handlers = MessageHandlers::new();
+    handlers
+        .add_handler(PEER_MANAGEMENT_HANDLER_ID, peer_management.clone())
+        .unwrap();
+    handlers.add_handler(KAD_HANDLER_ID, kad.clone()).unwrap();
+    (test_manager(context, handlers), peer_management, kad)
+}
+
+fn test_manager(context: DefaultContext, handlers: MessageHandlers<DefaultPeerId>) -> TestManager {
+    let config = PeerNetConfiguration {
+        context,
+        max_in_connections: Some(10),
+        init_connection_handler: IdExchangeInitConnection,
+        optional_features: PeerNetFeatures::default(),
+        message_handler: handlers,
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
+        send_data_channel_size: 1000,
+        peers_categories: HashMap::default(),
+        default_category_info: PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
+        _phantom: std::marker::PhantomData,
+    };
+    PeerNetManager::new(config).unwrap()
+}
+
+/// Forwards the messages it receives with the id of their sender
+struct ForwardHandler {
+    received: Sender<(DefaultPeerId, Bytes)>,
+}
+
+impl MessageHandler<DefaultPeerId> for ForwardHandler {
+    fn handle(&self, data: Bytes, peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send((peer_id.clone(), data))
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+fn relay_manager(
+    service: Option<RelayConfig>,
+) -> (
+    TestManager,
+    RelayHandler<DefaultPeerId, TestHooks>,
+    Receiver<(DefaultPeerId, Bytes)>,
+) {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let relay = RelayHandler::new(
+        RELAY_HANDLER_ID,
+        TestHooks {
+            our_id: context.our_id.clone(),
+        },
+        service,
+    );
+    let mut handlers = MessageHandlers::new();
+    handlers
+        .add_handler(RELAY_HANDLER_ID, relay.clone())
+        .unwrap();
+    let (received_tx, received_rx) = unbounded();
+    handlers
+        .add_handler(
+            FORWARD_HANDLER_ID,
+            ForwardHandler {
+                received: received_tx,
+            },
+        )
+        .unwrap();
+    (test_manager(context, handlers), relay, received_rx)
+}
+
+#[test]
+fn announcement_and_list_peers() {
+    let (mut manager, peer_management, _) = peer_management_manager();
+    let (mut manager2, peer_management2, _) = peer_management_manager();
+
+    // the first peer already knows a third one
+    let third_id = DefaultPeerId::generate();
+    let third_listeners = HashMap::from([("127.0.0.3:8080".parse().unwrap(), TransportType::Tcp)]);
+    let third_announcement = Announcement::new(
+        third_listeners.clone(),
+        &TestHooks {
+            our_id: third_id.clone(),
+        },
+    )
+    .unwrap();
+    assert!(peer_management
+        .peer_db
+        .write()
+        .insert_announcement(third_id.clone(), third_announcement));
+
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+
+    let listeners2 = HashMap::from([("127.0.0.2:8080".parse().unwrap(), TransportType::Quic)]);
+    {
+        let connections = manager2.active_connections.read();
+        let connection = connections.connections.values().next().unwrap();
+        peer_management2
+            .announce(&listeners2, &connection.send_channels)
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(1));
+
+    // the first peer stored the announcement of the second one
+    let id2 = manager
+        .active_connections
+        .read()
+        .connections
+        .keys()
+        .next()
+        .unwrap()
+        .clone();
+    assert_eq!(
+        peer_management.peer_db.read().peers[&id2]
+            .announcement
+            .listeners,
+        listeners2
+    );
+    // and answered with the third one
+    let peer_db2 = peer_management2.peer_db.read();
+    assert_eq!(peer_db2.peers.len(), 1);
+    assert_eq!(
+        peer_db2.peers[&third_id].announcement.listeners,
+        third_listeners
+    );
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn list_peers_limited() {
+    let (mut manager, peer_management, _) =
+        peer_management_manager_with(|peer_management| peer_management.set_max_peers_in_list(2));
+    let (mut manager2, peer_management2, _) = peer_management_manager();
+
+    for i in 0..5 {
+        let id = DefaultPeerId::generate();
+        let listeners = HashMap::from([(
+            format!("127.0.0.{}:8080", i + 3).parse().unwrap(),
+            TransportType::Tcp,
+        )]);
+        let announcement = Announcement::new(listeners, &TestHooks { our_id: id.clone() }).unwrap();
+        assert!(peer_management
+            .peer_db
+            .write()
+            .insert_announcement(id, announcement));
+    }
+
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+    manager2
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    assert!(eventually(|| manager2
+        .active_connections
+        .read()
+        .connections
+        .len()
+        == 1));
+    let announce = |port: u16| {
+        let listeners = HashMap::from([(
+            format!("127.0.0.2:{port}").parse().unwrap(),
+            TransportType::Tcp,
+        )]);
+        let connections = manager2.active_connections.read();
+        let connection = connections.connections.values().next().unwrap();
+        peer_management2
+            .announce(&listeners, &connection.send_channels)
+            .unwrap();
+    };
+    let announced_port = |port: u16| {
+        peer_management.peer_db.read().peers.values().any(|info| {
+            info.announcement
+                .listeners
+                .keys()
+                .any(|addr| addr.port() == port)
+        })
+    };
+
+    // only two of the five peers are sent
+    announce(8081);
+    assert!(eventually(|| announced_port(8081)));
+    assert!(eventually(
+        || peer_management2.peer_db.read().peers.len() == 2
+    ));
+
+    // and no list is sent again before the interval, whatever we learned in between
+    let id = DefaultPeerId::generate();
+    let announcement = Announcement::new(
+        HashMap::from([("127.0.0.10:8080".parse().unwrap(), TransportType::Tcp)]),
+        &TestHooks { our_id: id.clone() },
+    )
+    .unwrap();
+    peer_management
+        .peer_db
+        .write()
+        .insert_announcement(id, announcement);
+    announce(8082);
+    assert!(eventually(|| announced_port(8082)));
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(peer_management2.peer_db.read().peers.len(), 2);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn forged_announcement() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let hooks = TestHooks {
+        our_id: context.our_id.clone(),
+    };
+    let peer_management = PeerManagementHandler::new(PEER_MANAGEMENT_HANDLER_ID, &context, hooks);
+    let serializer = peer_management.serializer().serializer;
+
+    // an announcement signed by a peer can't be used for another one
+    let signer = DefaultPeerId::generate();
+    let announcement = Announcement::new(
+        HashMap::from([("127.0.0.1:8080".parse().unwrap(), TransportType::Tcp)]),
+        &TestHooks {
+            our_id: signer.clone(),
+        },
+    )
+    .unwrap();
+    let mut data = Vec::new();
+    serializer
+        .serialize(
+            &PeerManagementMessage::ListPeers(vec![(
+                DefaultPeerId::generate(),
+                announcement.clone(),
+            )]),
+            &mut data,
+        )
+        .unwrap();
+    assert!(peer_management.handle(data.into(), &signer).is_err());
+    assert!(peer_management.peer_db.read().peers.is_empty());
+
+    // the same announcement for its signer is accepted and round trips
+    let mut data = Vec::new();
+    let message = PeerManagementMessage::ListPeers(vec![(signer.clone(), announcement)]);
+    serializer.serialize(&message, &mut data).unwrap();
+    assert_eq!(serializer.deserialize(&data).unwrap(), message);
+    peer_management.handle(data.into(), &signer).unwrap();
+    assert!(peer_management.peer_db.read().peers.contains_key(&signer));
+}
+
+#[test]
+fn announcement_time_range() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let hooks = TestHooks {
+        our_id: context.our_id.clone(),
+    };
+    let peer_management = PeerManagementHandler::new(PEER_MANAGEMENT_HANDLER_ID, &context, hooks)
+        .set_max_announcement_age(Duration::from_secs(3600));
+    let serializer = peer_management.serializer().serializer;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let announce = |timestamp: u64| {
+        let signer = DefaultPeerId::generate();
+        let announcement = Announcement::with_timestamp(
+            HashMap::from([("127.0.0.1:8080".parse().unwrap(), TransportType::Tcp)]),
+            timestamp,
+            &TestHooks {
+                our_id: signer.clone(),
+            },
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        serializer
+            .serialize(
+                &PeerManagementMessage::<DefaultPeerId>::NewPeerConnected(announcement),
+                &mut data,
+            )
+            .unwrap();
+        peer_management.handle(data.into(), &signer).unwrap();
+        peer_management.peer_db.read().peers.contains_key(&signer)
+    };
+
+    assert!(announce(now));
+    // within the drift of the clocks
+    assert!(announce(now + 30_000));
+    assert!(!announce(
+        now + MAX_ANNOUNCEMENT_CLOCK_DRIFT.as_millis() as u64 + 60_000
+    ));
+    assert!(announce(now - 1_800_000));
+    assert!(!announce(now - 7_200_000));
+}
+
+#[test]
+fn peer_db_capped() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let announcement = |id: &DefaultPeerId, timestamp: u64| {
+        Announcement::with_timestamp(
+            HashMap::from([("127.0.0.1:8080".parse().unwrap(), TransportType::Tcp)]),
+            timestamp,
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap()
+    };
+    let mut peer_db = PeerDB::default();
+    peer_db.set_max_peers(3);
+    let ids: Vec<DefaultPeerId> = (0..3).map(|_| DefaultPeerId::generate()).collect();
+    for (i, id) in ids.iter().enumerate() {
+        assert!(peer_db.insert_announcement(id.clone(), announcement(id, now + i as u64)));
+    }
+
+    // a full DB replaces its unreachable peers first
+    peer_db.set_reachable(&ids[2], false);
+    let new_id = DefaultPeerId::generate();
+    assert!(peer_db.insert_announcement(new_id.clone(), announcement(&new_id, now + 5)));
+    assert!(!peer_db.peers.contains_key(&ids[2]));
+
+    // then its oldest announcements, by more recent ones only
+    let old_id = DefaultPeerId::generate();
+    assert!(!peer_db.insert_announcement(old_id.clone(), announcement(&old_id, now - 1)));
+    let recent_id = DefaultPeerId::generate();
+    assert!(peer_db.insert_announcement(recent_id.clone(), announcement(&recent_id, now + 10)));
+    assert_eq!(peer_db.peers.len(), 3);
+    assert!(!peer_db.peers.contains_key(&ids[0]));
+
+    // the reachable peers are kept over the untested ones
+    for id in peer_db.peers.keys().cloned().collect::<Vec<_>>() {
+        peer_db.set_reachable(&id, true);
+    }
+    let newest_id = DefaultPeerId::generate();
+    assert!(!peer_db.insert_announcement(newest_id.clone(), announcement(&newest_id, now + 20)));
+
+    peer_db.set_max_peers(1);
+    assert_eq!(peer_db.peers.len(), 1);
+    assert!(peer_db.peers.contains_key(&recent_id));
+}
+
+#[test]
+fn list_peers_received_limited() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let hooks = TestHooks {
+        our_id: context.our_id.clone(),
+    };
+    let peer_management = PeerManagementHandler::new(PEER_MANAGEMENT_HANDLER_ID, &context, hooks);
+    let serializer = peer_management.serializer().serializer;
+    let sender = DefaultPeerId::generate();
+    let list = || {
+        let id = DefaultPeerId::generate();
+        let announcement = Announcement::new(
+            HashMap::from([("127.0.0.1:8080".parse().unwrap(), TransportType::Tcp)]),
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        serializer
+            .serialize(
+                &PeerManagementMessage::ListPeers(vec![(id, announcement)]),
+                &mut data,
+            )
+            .unwrap();
+        Bytes::from(data)
+    };
+
+    // the second list of a peer within the interval is ignored
+    peer_management.handle(list(), &sender).unwrap();
+    peer_management.handle(list(), &sender).unwrap();
+    assert_eq!(peer_management.peer_db.read().peers.len(), 1);
+    // not the list of another peer
+    peer_management
+        .handle(list(), &DefaultPeerId::generate())
+        .unwrap();
+    assert_eq!(peer_management.peer_db.read().peers.len(), 2);
+}
+
+#[test]
+fn peer_management_fuzz() {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let hooks = TestHooks {
+        our_id: context.our_id.clone(),
+    };
+    let peer_management = PeerManagementHandler::new(PEER_MANAGEMENT_HANDLER_ID, &context, hooks);
+    let serializer = peer_management.serializer().serializer;
+    let announcement = Announcement::new(
+        HashMap::from([
+            ("127.0.0.1:8080".parse().unwrap(), TransportType::Tcp),
+            ("[::1]:8081".parse().unwrap(), TransportType::Quic),
+        ]),
+        &TestHooks {
+            our_id: context.our_id.clone(),
+        },
+    )
+    .unwrap();
+    let mut valid = Vec::new();
+    serializer
+        .serialize(
+            &PeerManagementMessage::<DefaultPeerId>::ListPeers(vec![
+                (DefaultPeerId::generate(), announcement.clone()),
+                (DefaultPeerId::generate(), announcement),
+            ]),
+            &mut valid,
+        )
+        .unwrap();
+
+    // every truncation is refused
+    for len in 0..valid.len() {
+        assert!(serializer
+            .deserialize::<DefaultPeerId>(&valid[..len])
+            .is_err());
+    }
+    // more listeners than allowed
+    let mut too_many = vec![0];
+    too_many.extend_from_slice(&(MAX_LISTENERS_PER_PEER as u16 + 1).to_be_bytes());
+    assert!(serializer.deserialize::<DefaultPeerId>(&too_many).is_err());
+
+    // random and mutated inputs never panic, what is accepted round trips
+    let mut rng = SmallRng::seed_from_u64(0);
+    for _ in 0..10000 {
+        let data = if rng.gen_bool(0.5) {
+            let len = rng.gen_range(0..200);
+            (0..len).map(|_| rng.gen()).collect()
+        } else {
+            let mut data = valid.clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let index = rng.gen_range(0..data.len());
+                data[index] = rng.gen();
+            }
+            data
+        };
+        if let Ok(message) = serializer.deserialize::<DefaultPeerId>(&data) {
+            let mut buffer = Vec::new();
+            serializer.serialize(&message, &mut buffer).unwrap();
+            assert_eq!(serializer.deserialize(&buffer).unwrap(), message);
+        }
+    }
+}
+
+#[test]
+fn tester_marks_reachable_peers() {
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let peer_management = PeerManagementHandler::new(
+        PEER_MANAGEMENT_HANDLER_ID,
+        &context,
+        TestHooks {
+            our_id: context.our_id.clone(),
+        },
+    );
+
+    let port = get_tcp_port(10000..u16::MAX);
+    let _listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let closed_port = get_tcp_port(10000..u16::MAX);
+    let mut ids = Vec::new();
+    for port in [port, closed_port] {
+        let id = DefaultPeerId::generate();
+        let announcement = Announcement::new(
+            HashMap::from([(
+                format!("127.0.0.1:{port}").parse().unwrap(),
+                TransportType::Tcp,
+            )]),
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap();
+        peer_management
+            .peer_db
+            .write()
+            .insert_announcement(id.clone(), announcement);
+        ids.push(id);
+    }
+
+    let tester = Tester::start(
+        peer_management.peer_db.clone(),
+        TesterConfig {
+            interval: Duration::from_millis(100),
+            connect_timeout: Duration::from_millis(500),
+            max_tests_per_round: 10,
+            retest_after: Duration::from_secs(60),
+        },
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_secs(2));
+    tester.stop();
+
+    let peer_db = peer_management.peer_db.read();
+    assert_eq!(peer_db.peers[&ids[0]].reachable, Some(true));
+    assert_eq!(peer_db.peers[&ids[1]].reachable, Some(false));
+}
+
+#[cfg(feature = "diagnostics")]
+#[test]
+fn tester_with_echo() {
+    use peernet::internal_handlers::echo::EchoServer;
+
+    let context = DefaultContext {
+        our_id: DefaultPeerId::generate(),
+    };
+    let peer_management = PeerManagementHandler::new(
+        PEER_MANAGEMENT_HANDLER_ID,
+        &context,
+        TestHooks {
+            our_id: context.our_id.clone(),
+        },
+    );
+
+    // only the first peer answers the pings, both ports are open in TCP
+    let echo_port = get_tcp_port(10000..u16::MAX);
+    let _listener = std::net::TcpListener::bind(("127.0.0.1", echo_port)).unwrap();
+    let echo_server = EchoServer::start(format!("127.0.0.1:{echo_port}").parse().unwrap()).unwrap();
+    let port = get_tcp_port(10000..u16::MAX);
+    let _listener2 = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+    let mut ids = Vec::new();
+    for port in [echo_port, port] {
+        let id = DefaultPeerId::generate();
+        let announcement = Announcement::new(
+            HashMap::from([(
+                format!("127.0.0.1:{port}").parse().unwrap(),
+                TransportType::Tcp,
+            )]),
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap();
+        peer_management
+            .peer_db
+            .write()
+            .insert_announcement(id.clone(), announcement);
+        ids.push(id);
+    }
+
+    let tester = Tester::start_with_echo(
+        peer_management.peer_db.clone(),
+        TesterConfig {
+            interval: Duration::from_millis(100),
+            connect_timeout: Duration::from_millis(500),
+            max_tests_per_round: 10,
+            retest_after: Duration::from_secs(60),
+        },
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_secs(2));
+    tester.stop();
+    echo_server.stop();
+
+    let peer_db = peer_management.peer_db.read();
+    assert_eq!(peer_db.peers[&ids[0]].reachable, Some(true));
+    assert_eq!(peer_db.peers[&ids[1]].reachable, Some(false));
+}
+
+#[test]
+fn supervisor_replaces_dropped_peers() {
+    let (manager, peer_management, _) = peer_management_manager();
+    let manager = std::sync::Arc::new(parking_lot::Mutex::new(manager));
+
+    // two peers listening, known by the supervised one
+    let mut listeners = Vec::new();
+    for _ in 0..2 {
+        let (mut listener, _, _) = peer_management_manager();
+        let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+            .parse()
+            .unwrap();
+        listener.start_listener(TransportType::Tcp, addr).unwrap();
+        let id = DefaultPeerId::generate();
+        let announcement = Announcement::new(
+            HashMap::from([(addr, TransportType::Tcp)]),
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap();
+        peer_management
+            .peer_db
+            .write()
+            .insert_announcement(id, announcement);
+        listeners.push((listener, addr));
+    }
+
+    let supervisor = ConnectionSupervisor::start(
+        manager.clone(),
+        peer_management.peer_db.clone(),
+        SupervisorConfig {
+            interval: Duration::from_millis(200),
+            connect_timeout: Duration::from_secs(1),
+            retry_after: Duration::from_secs(60),
+            target_out_connections: HashMap::default(),
+            default_target_out_connections: 1,
+            rotation: None,
+            backoff: None,
+        },
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    let first_peer = {
+        let manager = manager.lock();
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 1);
+        let (id, connection) = active_connections.connections.iter().next().unwrap();
+        (id.clone(), *connection.endpoint.get_target_addr())
+    };
+
+    // the peer is dropped, the other one is dialed
+    manager
+        .lock()
+        .active_connections
+        .write()
+        .remove_connection(&first_peer.0);
+    std::thread::sleep(Duration::from_secs(1));
+    {
+        let manager = manager.lock();
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 1);
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_ne!(*connection.endpoint.get_target_addr(), first_peer.1);
+    }
+    supervisor.stop();
+
+    for (mut listener, addr) in listeners {
+        listener.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+#[test]
+fn supervisor_rotates_peers() {
+    let (manager, peer_management, _) = peer_management_manager();
+    let manager = std::sync::Arc::new(parking_lot::Mutex::new(manager));
+
+    let mut listeners = Vec::new();
+    for _ in 0..2 {
+        let (mut listener, _, _) = peer_management_manager();
+        let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+            .parse()
+            .unwrap();
+        listener.start_listener(TransportType::Tcp, addr).unwrap();
+        let id = DefaultPeerId::generate();
+        let announcement = Announcement::new(
+            HashMap::from([(addr, TransportType::Tcp)]),
+            &TestHooks { our_id: id.clone() },
+        )
+        .unwrap();
+        peer_management
+            .peer_db
+            .write()
+            .insert_announcement(id, announcement);
+        listeners.push((listener, addr));
+    }
+
+    let supervisor = ConnectionSupervisor::start(
+        manager.clone(),
+        peer_management.peer_db.clone(),
+        SupervisorConfig {
+            interval: Duration::from_millis(200),
+            connect_timeout: Duration::from_secs(1),
+            retry_after: Duration::from_secs(60),
+            target_out_connections: HashMap::default(),
+            default_target_out_connections: 1,
+            rotation: Some(PeerRotation {
+                interval: Duration::from_secs(1),
+                fraction: 1.0,
+                min_age: Duration::ZERO,
+            }),
+            backoff: None,
+        },
+    )
+    .unwrap();
+    let target_addr = || {
+        let manager = manager.lock();
+        let active_connections = manager.active_connections.read();
+        assert_eq!(active_connections.nb_out_connections, 1);
+        let connection = active_connections.connections.values().next().unwrap();
+        *connection.endpoint.get_target_addr()
+    };
+    std::thread::sleep(Duration::from_millis(600));
+    let first_addr = target_addr();
+    // replaced by the other peer at the first rotation
+    std::thread::sleep(Duration::from_millis(900));
+    assert_ne!(target_addr(), first_addr);
+    supervisor.stop();
+
+    for (mut listener, addr) in listeners {
+        listener.stop_listener(TransportType::Tcp, addr).unwrap();
+    }
+}
+
+#[test]
+fn supervisor_backs_off_unreachable() {
+    let (manager, peer_management, _) = peer_management_manager();
+    let manager = std::sync::Arc::new(parking_lot::Mutex::new(manager));
+
+    // nothing listens on the address of the peer
+    let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+        .parse()
+        .unwrap();
+    let id = DefaultPeerId::generate();
+    let announcement = Announcement::new(
+        HashMap::from([(addr, TransportType::Tcp)]),
+        &TestHooks { our_id: id.clone() },
+    )
+    .unwrap();
+    peer_management
+        .peer_db
+        .write()
+        .insert_announcement(id, announcement);
+
+    let supervisor = ConnectionSupervisor::start(
+        manager.clone(),
+        peer_management.peer_db.clone(),
+        SupervisorConfig {
+            interval: Duration::from_millis(50),
+            connect_timeout: Duration::from_secs(1),
+            retry_after: Duration::ZERO,
+            target_out_connections: HashMap::default(),
+            default_target_out_connections: 1,
+            rotation: None,
+            backoff: Some(DialBackoff {
+                initial: Duration::from_millis(600),
+                max: Duration::from_secs(10),
+                max_failures: Some(3),
+            }),
+        },
+    )
+    .unwrap();
+    // dialed at once, then after 600ms
+    std::thread::sleep(Duration::from_millis(300));
+    let failures = supervisor.dial_failures()[&addr];
+    assert_eq!(failures.nb_failures, 1);
+    assert!(failures.retry_at.is_some());
+    std::thread::sleep(Duration::from_millis(900));
+    assert_eq!(supervisor.dial_failures()[&addr].nb_failures, 2);
+    // then after 1.2s, and given up
+    std::thread::sleep(Duration::from_millis(1600));
+    let failures = supervisor.dial_failures()[&addr];
+    assert_eq!(failures.nb_failures, 3);
+    assert_eq!(failures.retry_at, None);
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(supervisor.dial_failures()[&addr].nb_failures, 3);
+    assert_eq!(
+        manager.lock().active_connections.read().nb_out_connections,
+        0
+    );
+    supervisor.stop();
+}
+
+#[test]
+fn supervisor_reconnects_address_book() {
+    let (mut manager, peer_management, _) = peer_management_manager();
+    let (mut listener, _, _) = peer_management_manager();
+    let addr: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+        .parse()
+        .unwrap();
+    listener.start_listener(TransportType::Tcp, addr).unwrap();
+    manager
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let id = {
+        let active_connections = manager.active_connections.read();
+        let id = active_connections
+            .connections
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        assert_eq!(
+            active_connections.address_book.addresses(&id),
+            vec![(TransportType::Tcp, addr)]
+        );
+        id
+    };
+    // the listener is in no `PeerDB`, it's dialed again from the address book
+    manager.active_connections.write().remove_connection(&id);
+    let manager = std::sync::Arc::new(parking_lot::Mutex::new(manager));
+    let supervisor = ConnectionSupervisor::start(
+        manager.clone(),
+        peer_management.peer_db.clone(),
+        SupervisorConfig {
+            interval: Duration::from_millis(200),
+            connect_timeout: Duration::from_secs(1),
+            retry_after: Duration::from_secs(60),
+            target_out_connections: HashMap::default(),
+            default_target_out_connections: 1,
+            rotation: None,
+            backoff: None,
+        },
+    )
+    .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(manager
+        .lock()
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id));
+    supervisor.stop();
+    listener.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn address_book() {
+    let id = DefaultPeerId::generate();
+    let mut address_book = AddressBook::default().set_max_addresses_per_peer(2);
+    let start = Instant::now();
+    let addrs: Vec<SocketAddr> = (1..=3)
+        .map(|port| format!("127.0.0.1:{port}").parse().unwrap())
+        .collect();
+    address_book.insert(id.clone(), TransportType::Tcp, addrs[0], start);
+    address_book.record_connected(
+        id.clone(),
+        TransportType::Tcp,
+        addrs[1],
+        start + Duration::from_secs(1),
+    );
+    // the stalest address makes room for the new one
+    address_book.insert_listeners(
+        id.clone(),
+        &HashMap::from([(addrs[2], TransportType::Quic)]),
+        start + Duration::from_secs(2),
+    );
+    // the connected address first
+    assert_eq!(
+        address_book.addresses(&id),
+        vec![
+            (TransportType::Tcp, addrs[1]),
+            (TransportType::Quic, addrs[2])
+        ]
+    );
+    assert_eq!(address_book.peer_of(&addrs[2]), Some(&id));
+    assert_eq!(address_book.peer_of(&addrs[0]), None);
+
+    assert_eq!(
+        address_book.prune(Duration::from_secs(2), start + Duration::from_secs(3)),
+        1
+    );
+    assert_eq!(
+        address_book.addresses(&id),
+        vec![(TransportType::Quic, addrs[2])]
+    );
+    address_book.remove_address(&id, &addrs[2]);
+    assert!(address_book.is_empty());
+}
+
+#[test]
+fn kad_find_node() {
+    let (mut manager, peer_management, kad) = peer_management_manager();
+    let (mut manager2, peer_management2, kad2) = peer_management_manager();
+
+    // the first peer knows a third one, the second one looks for it
+    let third_id = DefaultPeerId::generate();
+    let third_hooks = TestHooks {
+        our_id: third_id.clone(),
+    };
+    let third_announcement = Announcement::new(
+        HashMap::from([("127.0.0.3:8080".parse().unwrap(), TransportType::Tcp)]),
+        &third_hooks,
+    )
+    .unwrap();
+    peer_management
+        .peer_db
+        .write()
+        .insert_announcement(third_id.clone(), third_announcement.clone());
+    assert!(kad.add_peer(&third_id).unwrap());
+
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    connect_tcp(&mut manager2, format!("127.0.0.1:{port}").parse().unwrap());
+
+    let (id, send_channels) = {
+        let connections = manager2.active_connections.read();
+        let (id, connection) = connections.connections.iter().next().unwrap();
+        (id.clone(), connection.send_channels.clone())
+    };
+    let found = kad2
+        .find_node(
+            &id,
+            &send_channels,
+            kad_key(&third_id, &third_hooks).unwrap(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+    assert_eq!(found, vec![third_id.clone()]);
+    assert_eq!(
+        peer_management2.peer_db.read().peers[&third_id].announcement,
+        third_announcement
+    );
+    // the second peer is in the routing table of the first one since its query
+    assert_eq!(kad.routing_table.read().len(), 2);
+    assert_eq!(
+        kad2.routing_table
+            .read()
+            .closest(&kad_key(&third_id, &third_hooks).unwrap(), 1),
+        vec![third_id]
+    );
+
+    // an answer of no pending query, e.g. arriving after the timeout, is ignored
+    let send_channels = {
+        let connections = manager.active_connections.read();
+        connections
+            .connections
+            .values()
+            .next()
+            .unwrap()
+            .send_channels
+            .clone()
+    };
+    send_channels
+        .send(
+            &kad.serializer(),
+            KadMessage::Nodes {
+                request_id: 0,
+                nodes: Vec::new(),
+            },
+            false,
+        )
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(manager2.active_connections.read().connections.len(), 1);
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn relayed_connection() {
+    let (mut relay_manager_a, _, _) = relay_manager(Some(RelayConfig {
+        max_reservations: 10,
+        max_circuits: 10,
+        max_hops: 1,
+        max_circuit_bandwidth: 1_000,
+    }));
+    let (mut manager_b, relay_b, received_b) = relay_manager(None);
+    let (mut manager_c, relay_c, _) = relay_manager(None);
+    let id_a = relay_manager_a.config.context.our_id.clone();
+    let id_b = manager_b.config.context.our_id.clone();
+    let id_c = manager_c.config.context.our_id.clone();
+
+    let port = get_tcp_port(10000..u16::MAX);
+    relay_manager_a
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    for manager in [&mut manager_b, &mut manager_c] {
+        manager
+            .try_connect(
+                TransportType::Tcp,
+                format!("127.0.0.1:{port}").parse().unwrap(),
+                Duration::from_secs(3),
+            )
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(1));
+
+    // B can only be reached through A once it has a reservation on it
+    let send_channels = manager_b.active_connections.read().connections[&id_a]
+        .send_channels
+        .clone();
+    assert!(manager_c
+        .try_connect_relayed(&relay_c, &id_a, id_b.clone(), Duration::from_secs(3))
+        .unwrap()
+        .join()
+        .unwrap()
+        .is_err());
+    relay_b
+        .reserve(&id_a, &send_channels, Duration::from_secs(3))
+        .unwrap();
+    manager_b.accept_relayed_connections(&relay_b).unwrap();
+    manager_c
+        .try_connect_relayed(&relay_c, &id_a, id_b.clone(), Duration::from_secs(3))
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(manager_b
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_c));
+
+    let send_channels = manager_c.active_connections.read().connections[&id_b]
+        .send_channels
+        .clone();
+    let serializer = RoutedSerializer {
+        handler_id: FORWARD_HANDLER_ID,
+        serializer: RawSerializer,
+    };
+    send_channels
+        .send(&serializer, b"through the relay".to_vec(), false)
+        .unwrap();
+    let (sender, data) = received_b.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(sender, id_c);
+    assert_eq!(&data[..], b"through the relay");
+
+    // the relay closes the circuits going over their bandwidth
+    send_channels
+        .send(&serializer, vec![0; 2_000], false)
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(received_b.try_recv().is_err());
+    assert!(!manager_b
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_c));
+    assert!(!manager_c
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_b));
+
+    relay_manager_a
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn relayed_connection_closed_with_relay() {
+    let (mut relay_manager_a, relay_a, _) = relay_manager(Some(RelayConfig {
+        max_reservations: 10,
+        max_circuits: 10,
+        max_hops: 1,
+        max_circuit_bandwidth: 1_000_000,
+    }));
+    relay_manager_a.watch_relay(&relay_a);
+    let (mut manager_b, relay_b, _) = relay_manager(None);
+    let (mut manager_c, relay_c, _) = relay_manager(None);
+    let id_a = relay_manager_a.config.context.our_id.clone();
+    let id_b = manager_b.config.context.our_id.clone();
+    let id_c = manager_c.config.context.our_id.clone();
+
+    let port = get_tcp_port(10000..u16::MAX);
+    relay_manager_a
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+    for manager in [&mut manager_b, &mut manager_c] {
+        manager
+            .try_connect(
+                TransportType::Tcp,
+                format!("127.0.0.1:{port}").parse().unwrap(),
+                Duration::from_secs(3),
+            )
+            .unwrap();
+    }
+    std::thread::sleep(Duration::from_secs(1));
+    let send_channels = manager_b.active_connections.read().connections[&id_a]
+        .send_channels
+        .clone();
+    relay_b
+        .reserve(&id_a, &send_channels, Duration::from_secs(3))
+        .unwrap();
+    manager_b.accept_relayed_connections(&relay_b).unwrap();
+    manager_c
+        .try_connect_relayed(&relay_c, &id_a, id_b.clone(), Duration::from_secs(3))
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(manager_b
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_c));
+
+    // B leaves the relay: its circuit is closed on both ends
+    manager_b
+        .active_connections
+        .write()
+        .remove_connection(&id_a);
+    std::thread::sleep(Duration::from_secs(1));
+    assert!(!manager_b
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_c));
+    assert!(!manager_c
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id_b));
+
+    relay_manager_a
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn connect_expecting_peer_id() {
+    let (mut manager, _, _) = peer_management_manager();
+    let (mut manager2, _, _) = peer_management_manager();
+    let id = manager.config.context.our_id.clone();
+
+    let port = get_tcp_port(10000..u16::MAX);
+    manager
+        .start_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+
+    // another peer answers at the address
+    let err = manager2
+        .try_connect_expecting(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            DefaultPeerId::generate(),
+            Duration::from_secs(3),
+        )
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::PeerIdMismatch);
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(manager2.active_connections.read().connections.is_empty());
+
+    manager2
+        .try_connect_expecting(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            id.clone(),
+            Duration::from_secs(3),
+        )
+        .unwrap()
+        .join()
+        .unwrap()
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(manager2
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&id));
+
+    manager
+        .stop_listener(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+        )
+        .unwrap();
+}
+
+#[test]
+fn peer_list_snapshot() {
+    let (manager, _, _) = peer_management_manager();
+    let (manager2, _, _) = peer_management_manager();
+    let id = DefaultPeerId::generate();
+    let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    {
+        let mut active_connections = manager.active_connections.write();
+        let now = active_connections.clock.now();
+        active_connections
+            .address_book
+            .record_connected(id.clone(), TransportType::Tcp, addr, now);
+        active_connections.address_book.set_score(id.clone(), 0.5);
+    }
+    let snapshot = manager.export_peer_list();
+    assert_eq!(snapshot.version, PEER_LIST_VERSION);
+    assert_eq!(snapshot.peers.len(), 1);
+    assert!(snapshot.peers[0].addresses[0].last_connected.is_some());
+
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let imported: PeerListSnapshot<DefaultPeerId> = serde_json::from_str(&json).unwrap();
+    assert_eq!(imported, snapshot);
+    assert_eq!(manager2.import_peer_list(&imported).unwrap(), 1);
+    {
+        let active_connections = manager2.active_connections.read();
+        assert_eq!(
+            active_connections.address_book.addresses(&id),
+            vec![(TransportType::Tcp, addr)]
+        );
+        assert!(active_connections.address_book.entries(&id)[0]
+            .last_connected
+            .is_some());
+        assert_eq!(active_connections.address_book.score(&id), Some(0.5));
+    }
+
+    // the other versions are refused
+    let mut future = imported;
+    future.version = PEER_LIST_VERSION + 1;
+    let err = manager2.import_peer_list(&future).unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::PeerListVersion);
+}
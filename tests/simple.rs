@@ -1,36 +1,38 @@
 mod util;
 use std::collections::HashMap;
-use std::{thread::sleep, time::Duration};
+use std::sync::Arc;
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
-use peernet::config::PeerNetCategoryInfo;
+use parking_lot::Mutex;
+use peernet::audit::{AuditOutcome, AuditRecord, AuditSink, SharedAuditSink};
+use peernet::bandwidth::{Bandwidth, BandwidthRates, BandwidthSnapshot};
+use peernet::config::{
+    ConfigError, DisconnectFlush, MessageCoalescing, PeerIdRules, PeerMetadata,
+    PeerNetCategoryInfo, PeerNetSettings, PortMapping, QuicSettings, SniRoute, TcpKeepalive,
+    TcpSettings, ThreadsConfig, TrustedPeer, TrustedPeers, MAX_SMALL_MESSAGE_SIZE, REDACTED,
+};
+use peernet::error::PeerNetError;
+use peernet::history::{ConnectionEventKind, DisconnectReason};
+use peernet::peer::PeerConnectionType;
 use peernet::peer_id::PeerId;
+use peernet::port_mapping::{PortMappingEvent, PortMappingProtocol};
+use peernet::state::CategoryStats;
 use peernet::{
     config::{PeerNetConfiguration, PeerNetFeatures},
     network_manager::PeerNetManager,
-    peer::InitConnectionHandler,
-    transports::TransportType,
+    transports::{endpoint::Endpoint, TransportType},
 };
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use util::{create_clients, DefaultMessagesHandler};
-
-use crate::util::{get_tcp_port, DefaultContext, DefaultPeerId};
-
-#[derive(Clone)]
-pub struct DefaultInitConnection;
-impl InitConnectionHandler<DefaultPeerId, DefaultContext, DefaultMessagesHandler>
-    for DefaultInitConnection
-{
-    fn perform_handshake(
-        &mut self,
-        _keypair: &DefaultContext,
-        _endpoint: &mut peernet::transports::endpoint::Endpoint,
-        _listeners: &std::collections::HashMap<std::net::SocketAddr, TransportType>,
-        _messages_handler: DefaultMessagesHandler,
-    ) -> peernet::error::PeerNetResult<DefaultPeerId> {
-        Ok(DefaultPeerId::generate())
-    }
-}
+use util::{create_clients, DefaultMessagesHandler, RawSerializer};
+
+use crate::util::{
+    connect_tcp, default_config, default_manager, eventually, get_tcp_port, listen_tcp, wait_until,
+    DefaultContext, DefaultInitConnection, DefaultManager, DefaultPeerId,
+};
 
 #[test]
 fn simple() {
@@ -40,24 +42,35 @@ fn simple() {
 
     let config = PeerNetConfiguration {
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection,
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         send_data_channel_size: 1000,
-        max_message_size: 10000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        tcp: TcpSettings {
+            max_message_size: Some(10000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 10,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(10),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
     };
 
     let mut manager: PeerNetManager<
@@ -65,7 +78,7 @@ fn simple() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -91,6 +104,256 @@ fn simple() {
         .unwrap();
 }
 
+#[test]
+fn configuration_builder() {
+    let builder = default_config;
+    assert!(builder()
+        .set_tcp_settings(TcpSettings {
+            max_message_size: Some(0),
+            ..Default::default()
+        })
+        .build()
+        .is_err());
+    assert!(builder()
+        .set_tcp_settings(TcpSettings {
+            rate_time_window: Duration::ZERO,
+            ..Default::default()
+        })
+        .build()
+        .is_err());
+    assert!(builder()
+        .set_tcp_settings(TcpSettings {
+            listener_poll_interval: Duration::ZERO,
+            ..Default::default()
+        })
+        .build()
+        .is_err());
+
+    let mut manager = default_manager(
+        builder()
+            .set_max_in_connections(Some(2))
+            .set_default_category_info(PeerNetCategoryInfo {
+                max_in_connections: Some(2),
+                max_in_connections_per_ip: Some(2),
+                max_out_connections: Some(2),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
+            }),
+    );
+    let addr = listen_tcp(&mut manager);
+    let _ = create_clients(3, &addr.to_string());
+    assert!(eventually(|| manager.nb_in_connections() == 2));
+    // the third client stays refused
+    sleep(Duration::from_millis(200));
+    assert_eq!(manager.nb_in_connections(), 2);
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn settings_from_json() {
+    let settings: PeerNetSettings = serde_json::from_str(
+        r#"{
+            "max_in_connections": 50,
+            "tcp": {
+                "rate_time_window": 500
+            },
+            "quic": {
+                "local_addr": "127.0.0.1:9000"
+            },
+            "peers_categories": {
+                "bootstrap": [["127.0.0.1"], {
+                    "max_in_connections": 5,
+                    "max_in_connections_per_ip": 1,
+                    "max_out_connections": 5
+                }]
+            },
+            "trusted_peers": {
+                "ips": ["10.0.0.1"]
+            }
+        }"#,
+    )
+    .unwrap();
+    // the trusted ids can't be in the settings, they are kept
+    let trusted_id = DefaultPeerId::generate();
+    let config = default_config()
+        .set_trusted_peers(TrustedPeers {
+            peers: vec![
+                TrustedPeer::Id(trusted_id.clone()),
+                TrustedPeer::Ip(IpAddr::from_str("10.0.0.2").unwrap()),
+            ],
+            evict_untrusted: true,
+        })
+        .set_settings(settings)
+        .build()
+        .unwrap();
+    assert_eq!(config.max_in_connections, Some(50));
+    assert_eq!(config.tcp.rate_time_window, Duration::from_millis(500));
+    assert_eq!(
+        config.quic.local_addr,
+        SocketAddr::from_str("127.0.0.1:9000").unwrap()
+    );
+    assert_eq!(
+        config.peers_categories["bootstrap"].0,
+        vec![IpAddr::from_str("127.0.0.1").unwrap()]
+    );
+    assert_eq!(
+        config.trusted_peers.peers,
+        vec![
+            TrustedPeer::Id(trusted_id),
+            TrustedPeer::Ip(IpAddr::from_str("10.0.0.1").unwrap())
+        ]
+    );
+    assert!(!config.trusted_peers.evict_untrusted);
+    // the missing fields have their default value
+    let default_settings = PeerNetSettings::default();
+    assert_eq!(config.tcp.read_timeout, default_settings.tcp.read_timeout);
+    assert_eq!(
+        config.tcp.max_message_size,
+        default_settings.tcp.max_message_size
+    );
+
+    let settings = config.settings();
+    let json = serde_json::to_string(&settings).unwrap();
+    let settings2: PeerNetSettings = serde_json::from_str(&json).unwrap();
+    assert_eq!(
+        settings2.tcp.rate_time_window,
+        settings.tcp.rate_time_window
+    );
+    assert_eq!(settings2.quic.local_addr, settings.quic.local_addr);
+    assert_eq!(settings2.peers_categories.len(), 1);
+    assert_eq!(
+        settings2.trusted_peers.ips,
+        vec![IpAddr::from_str("10.0.0.1").unwrap()]
+    );
+}
+
+#[test]
+fn configuration_describe() {
+    let config = default_config()
+        .set_optional_features(
+            PeerNetFeatures::default()
+                .set_protocols(vec!["blocks".to_string()])
+                .set_metadata(PeerMetadata {
+                    values: [("chain".to_string(), "private-chain".to_string())].into(),
+                    max_size: 1024,
+                })
+                .set_peer_timings(true),
+        )
+        .build()
+        .unwrap();
+    let description = config.describe();
+    assert!(description.contains("send_data_channel_size: 10000"));
+    assert!(description.contains(r#"protocols: ["blocks"]"#));
+    assert!(description.contains(r#"metadata: { keys: ["chain"], max_size: 1024 }"#));
+    assert!(description.contains("peer_timings"));
+    assert!(!description.contains("private-chain"));
+    // the context and the handlers are not formatted
+    let debug = format!("{:?}", config);
+    assert!(debug.contains(REDACTED));
+    assert!(!debug.contains(&format!("{:?}", config.context.our_id)));
+
+    let manager: PeerNetManager<_, _, _, _> = PeerNetManager::new(config).unwrap();
+    assert_eq!(manager.dump_state().config, description);
+}
+
+#[test]
+fn configuration_validation() {
+    let config = || default_config().build().unwrap();
+    assert_eq!(config().validate(), Ok(()));
+
+    let mut invalid = config();
+    invalid.tcp.rate_bucket_size = 1024;
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::RateBucketTooSmall {
+            rate_bucket_size: 1024
+        })
+    );
+    let mut invalid = config();
+    invalid.tcp.max_message_size = Some(0);
+    assert_eq!(invalid.validate(), Err(ConfigError::ZeroMaxMessageSize));
+    let mut invalid = config();
+    invalid.tcp.read_timeout = Duration::ZERO;
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::ZeroDuration("tcp.read_timeout"))
+    );
+    let mut invalid = config();
+    invalid.optional_features = invalid
+        .optional_features
+        .set_handshake_timeout(Duration::ZERO);
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::ZeroDuration(
+            "optional_features.handshake_timeout"
+        ))
+    );
+    let mut invalid = config();
+    invalid.peers_categories.insert(
+        "local".to_string(),
+        (
+            vec![IpAddr::from_str("127.0.0.1").unwrap()],
+            PeerNetCategoryInfo {
+                max_in_connections: Some(2),
+                max_in_connections_per_ip: Some(3),
+                max_out_connections: Some(2),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
+            },
+        ),
+    );
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::PerIpLimitAboveCategoryLimit {
+            category: Some("local".to_string()),
+            max_in_connections_per_ip: 3,
+            max_in_connections: 2,
+        })
+    );
+    let mut invalid = config();
+    invalid.quic.sni_routes.push(SniRoute {
+        server_name: "partners.example.com".to_string(),
+        certificate: None,
+        category: Some("partners".to_string()),
+    });
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::UnknownSniCategory {
+            server_name: "partners.example.com".to_string(),
+            category: "partners".to_string(),
+        })
+    );
+    let mut invalid = config();
+    invalid.optional_features = invalid
+        .optional_features
+        .set_small_message_size(MAX_SMALL_MESSAGE_SIZE + 1);
+    assert_eq!(
+        invalid.validate(),
+        Err(ConfigError::SmallMessageSizeTooBig(
+            MAX_SMALL_MESSAGE_SIZE + 1
+        ))
+    );
+    // the manager refuses it
+    let err = PeerNetManager::new(invalid).err().unwrap();
+    assert_eq!(
+        err.error_type(),
+        &PeerNetError::ConfigError(ConfigError::SmallMessageSizeTooBig(
+            MAX_SMALL_MESSAGE_SIZE + 1
+        ))
+    );
+    assert_eq!(err.code(), 304);
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["code"], 304);
+    assert_eq!(json["location"], err.location());
+    assert_eq!(
+        json["error_type"]["ConfigError"]["SmallMessageSizeTooBig"],
+        MAX_SMALL_MESSAGE_SIZE + 1
+    );
+}
+
 #[test]
 fn simple_no_place() {
     let context = DefaultContext {
@@ -99,31 +362,42 @@ fn simple_no_place() {
 
     let config = PeerNetConfiguration {
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection,
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         send_data_channel_size: 1000,
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 0,
-            max_in_connections_per_ip: 1,
-            max_out_connections: 1,
+            max_in_connections: Some(0),
+            max_in_connections_per_ip: Some(0),
+            max_out_connections: Some(1),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
     };
     let mut manager: PeerNetManager<
         DefaultPeerId,
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -156,31 +430,42 @@ fn simple_no_place_after_handshake() {
 
     let config = PeerNetConfiguration {
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection,
         optional_features: PeerNetFeatures::default(),
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         send_data_channel_size: 1000,
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 0,
-            max_in_connections_per_ip: 1,
-            max_out_connections: 1,
+            max_in_connections: Some(0),
+            max_in_connections_per_ip: Some(0),
+            max_out_connections: Some(1),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
     };
     let mut manager: PeerNetManager<
         DefaultPeerId,
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -214,9 +499,12 @@ fn simple_with_category() {
         (
             vec![IpAddr::from_str("127.0.0.1").unwrap()],
             PeerNetCategoryInfo {
-                max_in_connections: 10,
-                max_in_connections_per_ip: 10,
-                max_out_connections: 10,
+                max_in_connections: Some(10),
+                max_in_connections_per_ip: Some(10),
+                max_out_connections: Some(10),
+                max_out_connections_per_ip: None,
+                read_timeout: None,
+                write_timeout: None,
             },
         ),
     );
@@ -225,24 +513,35 @@ fn simple_with_category() {
     };
 
     let config = PeerNetConfiguration {
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection,
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
         send_data_channel_size: 1000,
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
         peers_categories,
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 0,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(0),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
     };
 
@@ -251,7 +550,7 @@ fn simple_with_category() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -284,24 +583,35 @@ fn two_peers_tcp() {
 
     let config = PeerNetConfiguration {
         context,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
         message_handler: DefaultMessagesHandler {},
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         send_data_channel_size: 1000,
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
     };
 
     let mut manager: PeerNetManager<
@@ -309,7 +619,7 @@ fn two_peers_tcp() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
 
     let port = get_tcp_port(10000..u16::MAX);
     manager
@@ -325,24 +635,35 @@ fn two_peers_tcp() {
 
     let config = PeerNetConfiguration {
         context: context2,
-        max_in_connections: 10,
+        max_in_connections: Some(10),
         init_connection_handler: DefaultInitConnection {},
         optional_features: PeerNetFeatures::default(),
-        max_message_size: 1048576000,
-        rate_bucket_size: 60 * 1024,
-        rate_limit: 10000,
-        rate_time_window: Duration::from_secs(1),
+        tcp: TcpSettings {
+            max_message_size: Some(1048576000),
+            rate_limit: 10000,
+            rate_time_window: Duration::from_secs(1),
+            rate_bucket_size: 60 * 1024,
+            read_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            listener_poll_interval: Duration::from_secs(1),
+            keepalive: None,
+            linger: None,
+        },
+        quic: QuicSettings::default(),
         send_data_channel_size: 1000,
         message_handler: DefaultMessagesHandler {},
         peers_categories: HashMap::default(),
         default_category_info: PeerNetCategoryInfo {
-            max_in_connections: 10,
-            max_in_connections_per_ip: 2,
-            max_out_connections: 10,
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
         },
+        trusted_peers: TrustedPeers::default(),
+        peer_id_rules: PeerIdRules::default(),
         _phantom: std::marker::PhantomData,
-        read_timeout: Duration::from_secs(10),
-        write_timeout: Duration::from_secs(10),
     };
 
     let mut manager2: PeerNetManager<
@@ -350,7 +671,7 @@ fn two_peers_tcp() {
         DefaultContext,
         DefaultInitConnection,
         DefaultMessagesHandler,
-    > = PeerNetManager::new(config);
+    > = PeerNetManager::new(config).unwrap();
     std::thread::sleep(std::time::Duration::from_secs(1));
 
     manager2
@@ -374,28 +695,28 @@ fn two_peers_tcp() {
 // fn two_peers_quic() {
 //     let keypair1 = KeyPair::generate();
 //     let config = PeerNetConfiguration {
-//         max_in_connections: 10,
-//         max_out_connections: 20,
+//         max_in_connections: Some(10),
+//         max_out_connections: Some(20),
 //         self_keypair: keypair1.clone(),
 //         init_connection_handler: DefaultInitConnection {},
 //         message_handler: DefaultMessagesHandler {},
 //         optional_features: PeerNetFeatures::default().set_reject_same_ip_addr(false),
 //     };
-//     let mut manager = PeerNetManager::new(config);
+//     let mut manager = PeerNetManager::new(config).unwrap();
 //     manager
 //         .start_listener(TransportType::Quic, "127.0.0.1:8082".parse().unwrap())
 //         .unwrap();
 
 //     let keypair2 = KeyPair::generate();
 //     let config = PeerNetConfiguration {
-//         max_in_connections: 10,
-//         max_out_connections: 20,
+//         max_in_connections: Some(10),
+//         max_out_connections: Some(20),
 //         self_keypair: keypair2,
 //         init_connection_handler: DefaultInitConnection {},
 //         optional_features: PeerNetFeatures::default().set_reject_same_ip_addr(false),
 //         message_handler: DefaultMessagesHandler {},
 //     };
-//     let mut manager2 = PeerNetManager::new(config);
+//     let mut manager2 = PeerNetManager::new(config).unwrap();
 //     sleep(Duration::from_secs(3));
 //     manager2
 //         .try_connect(
@@ -413,3 +734,911 @@ fn two_peers_tcp() {
 //         .stop_listener(TransportType::Quic, "127.0.0.1:8082".parse().unwrap())
 //         .unwrap();
 // }
+
+#[test]
+fn two_peers_tcp_expired_message() {
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        // deadline already reached when the write thread picks the message
+        connection
+            .send_channels
+            .send_with_deadline(&RawSerializer, vec![1, 2, 3], false, Instant::now())
+            .unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1, 2, 3], false)
+            .unwrap();
+    }
+    // only the second message is sent
+    assert!(eventually(|| manager.get_total_bytes_received() == 3));
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.send_channels.nb_expired_messages(), 1);
+    }
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn two_peers_tcp_coalescing() {
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config().set_optional_features(
+        PeerNetFeatures::default().set_message_coalescing(MessageCoalescing {
+            max_batch_size: 1024,
+            max_delay: Duration::from_millis(50),
+        }),
+    ));
+    connect_tcp(&mut manager2, addr);
+    let before = manager2.get_bandwidth();
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        for _ in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![1, 2, 3], false)
+                .unwrap();
+        }
+    }
+    // every message of the batches is received
+    assert!(eventually(|| manager.get_total_bytes_received() == 30));
+    let bandwidth = manager2.get_bandwidth();
+    assert_eq!(bandwidth.since(&before).bytes_sent, 30);
+    {
+        // the only connection sent everything
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert_eq!(connection.endpoint.get_bandwidth(), bandwidth);
+    }
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn bandwidth_delta_and_reset() {
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    // the handshake is left out of the next deltas
+    manager2.get_bandwidth_delta();
+    let (peer_id, frames_sent) = {
+        let active_connections = manager2.active_connections.read();
+        let (peer_id, connection) = active_connections.connections.iter().next().unwrap();
+        connection.endpoint.get_bandwidth_delta();
+        let frames_sent = connection.endpoint.get_bandwidth().frames_sent;
+        for _ in 0..5 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![1, 2, 3, 4], false)
+                .unwrap();
+        }
+        (peer_id.clone(), frames_sent)
+    };
+    assert!(eventually(|| {
+        manager2.active_connections.read().connections[&peer_id]
+            .endpoint
+            .get_bandwidth()
+            .frames_sent
+            == frames_sent + 5
+    }));
+    {
+        let active_connections = manager2.active_connections.read();
+        let endpoint = &active_connections.connections[&peer_id].endpoint;
+        let delta = endpoint.get_bandwidth_delta();
+        assert_eq!(delta.bytes_sent, 20);
+        assert_eq!(delta.frames_sent, 5);
+        assert_eq!(endpoint.get_bandwidth_delta(), BandwidthSnapshot::default());
+    }
+    assert_eq!(manager2.get_bandwidth_delta().frames_sent, 5);
+
+    // the counts before the reset stay in the stats of the category
+    let stats = manager2.category_stats(None);
+    let counted = manager2.reset_bandwidth(&peer_id).unwrap();
+    assert_eq!(counted, stats.bandwidth);
+    assert_eq!(
+        manager2.active_connections.read().connections[&peer_id]
+            .endpoint
+            .get_bandwidth(),
+        BandwidthSnapshot::default()
+    );
+    assert_eq!(manager2.category_stats(None), stats);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn two_peers_tcp_small_messages() {
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(
+        default_config()
+            .set_optional_features(PeerNetFeatures::default().set_small_message_size(256)),
+    );
+    connect_tcp(&mut manager2, addr);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        // small, at the limit and above it: the frames are the same on both paths
+        for size in [10, 256, 257, 10_000] {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![1; size], false)
+                .unwrap();
+        }
+    }
+    let expected = 10 + 256 + 257 + 10_000;
+    assert!(eventually(|| manager.get_total_bytes_received() == expected));
+    assert_eq!(manager2.get_bandwidth().bytes_sent, expected);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn connection_history() {
+    let config = |connection_history: usize| {
+        default_config().set_optional_features(
+            PeerNetFeatures::default().set_connection_history(connection_history),
+        )
+    };
+    // only keeps the 2 last events
+    let mut manager = default_manager(config(2));
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(config(10));
+    connect_tcp(&mut manager2, addr);
+    let peer_id = manager2
+        .active_connections
+        .read()
+        .connections
+        .keys()
+        .next()
+        .unwrap()
+        .clone();
+    manager2
+        .active_connections
+        .write()
+        .remove_connection(&peer_id);
+    let disconnected = |manager: &DefaultManager| {
+        manager.recent_events(1).first().map_or(false, |event| {
+            matches!(event.kind, ConnectionEventKind::Disconnected { .. })
+        })
+    };
+    assert!(eventually(
+        || disconnected(&manager) && disconnected(&manager2)
+    ));
+
+    let events: Vec<_> = manager2
+        .recent_events(10)
+        .into_iter()
+        .map(|event| (event.addr, event.kind))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (
+                addr,
+                ConnectionEventKind::Disconnected {
+                    peer_id: peer_id.clone(),
+                    reason: DisconnectReason::Closed,
+                    dropped_messages: 0,
+                }
+            ),
+            (
+                addr,
+                ConnectionEventKind::HandshakeSucceeded {
+                    peer_id,
+                    direction: PeerConnectionType::OUT
+                }
+            ),
+        ]
+    );
+    assert_eq!(manager2.recent_events(1).len(), 1);
+
+    // the accept is already dropped
+    let events = manager.recent_events(10);
+    assert_eq!(events.len(), 2);
+    assert!(matches!(
+        events[0].kind,
+        ConnectionEventKind::Disconnected {
+            reason: DisconnectReason::ClosedByPeer,
+            ..
+        }
+    ));
+    assert!(matches!(
+        events[1].kind,
+        ConnectionEventKind::HandshakeSucceeded {
+            direction: PeerConnectionType::IN,
+            ..
+        }
+    ));
+    assert!(events[0].time >= events[1].time);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[derive(Default)]
+struct CollectingAuditSink(Mutex<Vec<AuditRecord>>);
+
+impl AuditSink for CollectingAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        self.0.lock().push(record.clone());
+    }
+}
+
+#[test]
+fn audit_log() {
+    let config = |audit: SharedAuditSink| {
+        default_config().set_optional_features(PeerNetFeatures::default().set_audit(audit))
+    };
+    let listener_audit = Arc::new(CollectingAuditSink::default());
+    let mut manager = default_manager(config(listener_audit.clone()));
+    let addr = listen_tcp(&mut manager);
+
+    let dialer_audit = Arc::new(CollectingAuditSink::default());
+    let mut manager2 = default_manager(config(dialer_audit.clone()));
+    connect_tcp(&mut manager2, addr);
+    // nothing listens on this one
+    let closed: SocketAddr = format!("127.0.0.1:{}", get_tcp_port(10000..u16::MAX))
+        .parse()
+        .unwrap();
+    manager2
+        .try_connect(TransportType::Tcp, closed, Duration::from_millis(300))
+        .unwrap();
+
+    let outcomes = |audit: &CollectingAuditSink| {
+        audit
+            .0
+            .lock()
+            .iter()
+            .map(|record| (record.addr, record.direction, record.outcome))
+            .collect::<Vec<_>>()
+    };
+    assert!(eventually(|| {
+        outcomes(&dialer_audit).contains(&(
+            closed,
+            PeerConnectionType::OUT,
+            AuditOutcome::DialFailed,
+        )) && outcomes(&listener_audit).len() == 2
+    }));
+    let dialer = outcomes(&dialer_audit);
+    assert!(dialer.contains(&(addr, PeerConnectionType::OUT, AuditOutcome::Dialing)));
+    assert!(dialer.contains(&(addr, PeerConnectionType::OUT, AuditOutcome::Connected)));
+    assert!(dialer.contains(&(closed, PeerConnectionType::OUT, AuditOutcome::Dialing)));
+    let listener: Vec<_> = outcomes(&listener_audit)
+        .into_iter()
+        .map(|(_, direction, outcome)| (direction, outcome))
+        .collect();
+    assert_eq!(
+        listener,
+        vec![
+            (PeerConnectionType::IN, AuditOutcome::Accepted),
+            (PeerConnectionType::IN, AuditOutcome::Connected)
+        ]
+    );
+    assert!(listener_audit.0.lock()[1].peer_id.is_some());
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn disconnect_flush() {
+    let config = |optional_features: PeerNetFeatures| {
+        default_config().set_optional_features(optional_features.set_connection_history(10))
+    };
+    let mut manager = default_manager(config(PeerNetFeatures::default()));
+    let addr = listen_tcp(&mut manager);
+
+    // the rate limit keeps the messages in the queue when the connection is removed
+    let mut manager2 = default_manager(config(PeerNetFeatures::default().set_disconnect_flush(
+        DisconnectFlush {
+            max_duration: Duration::from_secs(10),
+            max_bytes: 25_000,
+        },
+    )));
+    connect_tcp(&mut manager2, addr);
+    {
+        let mut active_connections = manager2.active_connections.write();
+        let peer_id = active_connections
+            .connections
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        let connection = &active_connections.connections[&peer_id];
+        for _ in 0..10 {
+            connection
+                .send_channels
+                .send(&RawSerializer, vec![0; 5000], false)
+                .unwrap();
+        }
+        active_connections.remove_connection(&peer_id);
+        assert_eq!(active_connections.nb_out_connections, 0);
+    }
+
+    // recorded once the flush is over
+    let dropped_messages = || match manager2.recent_events(1).first().map(|event| &event.kind) {
+        Some(ConnectionEventKind::Disconnected {
+            reason: DisconnectReason::Closed,
+            dropped_messages,
+            ..
+        }) => Some(*dropped_messages),
+        _ => None,
+    };
+    assert!(wait_until(Duration::from_secs(10), || dropped_messages().is_some()));
+    let dropped_messages = dropped_messages().unwrap();
+    // the messages beyond max_bytes are dropped, the ones before are all received
+    assert!(dropped_messages > 0 && dropped_messages <= 5);
+    assert!(eventually(
+        || manager.get_total_bytes_received() == (10 - dropped_messages as u64) * 5000
+    ));
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn dump_state() {
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    let state = manager.dump_state();
+    assert!(!state.locked);
+    assert_eq!(state.listeners, vec![(addr, TransportType::Tcp)]);
+    assert_eq!(state.nb_in_connections, 1);
+    assert!(state.in_connection_queue.is_empty());
+    assert_eq!(state.peers.len(), 1);
+    assert_eq!(state.peers[0].transport, "tcp");
+    assert_eq!(state.peers[0].direction, PeerConnectionType::IN);
+    assert_eq!(state.peers[0].queued_low_priority, 0);
+    // the peers of no category
+    assert_eq!(state.categories.len(), 1);
+    assert_eq!(state.categories[0].name, None);
+    assert_eq!(state.categories[0].stats.nb_in_connections, 1);
+    let json = serde_json::to_value(&state).unwrap();
+    assert_eq!(json["peers"][0]["direction"], "IN");
+    assert_eq!(json["nb_in_connections"], 1);
+
+    let state = manager2.dump_state();
+    assert_eq!(state.peers[0].addr, addr);
+    assert_eq!(state.categories[0].stats.nb_out_connections, 1);
+
+    let snapshot = manager2.connections_snapshot();
+    assert_eq!(snapshot.nb_out_connections, 1);
+    assert_eq!(snapshot.nb_out_handshakes, 0);
+    assert_eq!(snapshot.connections.len(), 1);
+    assert_eq!(snapshot.connections[0].addr, addr);
+    assert_eq!(snapshot.connections[0].direction, PeerConnectionType::OUT);
+    assert!(manager2
+        .active_connections
+        .read()
+        .connections
+        .contains_key(&snapshot.connections[0].peer_id));
+
+    // doesn't wait for a lock that is never released
+    let active_connections = manager.active_connections.write();
+    let state = manager.dump_state();
+    assert!(state.locked);
+    assert!(state.peers.is_empty());
+    drop(active_connections);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn category_stats() {
+    let limits = PeerNetCategoryInfo {
+        max_in_connections: Some(10),
+        max_in_connections_per_ip: Some(10),
+        max_out_connections: Some(10),
+        max_out_connections_per_ip: None,
+        read_timeout: None,
+        write_timeout: None,
+    };
+    let mut categories = HashMap::new();
+    categories.insert(
+        "local".to_string(),
+        (vec![IpAddr::from_str("127.0.0.1").unwrap()], limits),
+    );
+    let mut manager = default_manager(default_config().set_peers_categories(categories));
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+    let stats = manager.category_stats(Some("local"));
+    assert_eq!(stats.nb_in_connections, 1);
+    assert_eq!(stats.nb_out_connections, 0);
+    assert_eq!(manager.category_stats(None).nb_in_connections, 0);
+
+    let peer_id = {
+        let active_connections = manager2.active_connections.read();
+        let (peer_id, connection) = active_connections.connections.iter().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1; 100], false)
+            .unwrap();
+        peer_id.clone()
+    };
+    assert!(eventually(|| {
+        manager
+            .category_stats(Some("local"))
+            .bandwidth
+            .bytes_received
+            == 100
+    }));
+    // the bytes of the peers of no category, on the other side
+    assert_eq!(manager2.category_stats(None).bandwidth.bytes_sent, 100);
+
+    // still counted once the peer is gone
+    manager2
+        .active_connections
+        .write()
+        .remove_connection(&peer_id);
+    assert!(eventually(|| manager.nb_in_connections() == 0));
+    let stats = manager.category_stats(Some("local"));
+    assert_eq!(stats.nb_in_connections, 0);
+    assert_eq!(stats.bandwidth.bytes_received, 100);
+    assert_eq!(manager2.category_stats(None).bandwidth.bytes_sent, 100);
+    assert_eq!(
+        manager.category_stats(Some("unknown")),
+        CategoryStats::default()
+    );
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn bandwidth_rates() {
+    let bandwidth = Bandwidth::new_shared();
+    assert_eq!(bandwidth.rates(), BandwidthRates::default());
+    sleep(Duration::from_millis(200));
+    bandwidth.add_sent(1000);
+    let rates = bandwidth.rates();
+    // the average weights the last 200ms against the (empty) past
+    assert!(rates.bytes_sent_per_sec > 0.0 && rates.bytes_sent_per_sec < 5000.0);
+    assert_eq!(rates.bytes_received_per_sec, 0.0);
+    // nothing more sent, the rate decays
+    sleep(Duration::from_millis(200));
+    let later = bandwidth.rates();
+    assert!(later.bytes_sent_per_sec < rates.bytes_sent_per_sec);
+
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1; 10_000], false)
+            .unwrap();
+    }
+    assert!(eventually(|| manager.get_total_bytes_received() == 10_000));
+    assert!(manager.get_rates().bytes_received_per_sec > 0.0);
+    let rates = manager2.get_rates();
+    assert!(rates.bytes_sent_per_sec > 0.0);
+    assert_eq!(rates.bytes_received_per_sec, 0.0);
+    {
+        let active_connections = manager2.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        assert!(connection.endpoint.get_rates().bytes_sent_per_sec > 0.0);
+    }
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn background_errors() {
+    let (errors_tx, errors_rx) = crossbeam::channel::unbounded();
+    let mut manager = default_manager(
+        default_config().set_optional_features(
+            PeerNetFeatures::default()
+                .set_on_error(move |err| errors_tx.send((err.code(), err.location())).unwrap()),
+        ),
+    );
+
+    // nothing listens on the port
+    let port = get_tcp_port(10000..u16::MAX);
+    let handle = manager
+        .try_connect(
+            TransportType::Tcp,
+            format!("127.0.0.1:{port}").parse().unwrap(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+    assert!(handle.join().unwrap().is_err());
+    let (code, location) = errors_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(code, 101);
+    assert_eq!(location, "try_connect stream connect");
+
+    // the listeners bind before starting their thread, the error is returned
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    assert!(manager.start_listener(TransportType::Tcp, addr).is_err());
+    let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    assert!(manager.start_listener(TransportType::Quic, addr).is_err());
+    assert!(manager.active_connections.read().listeners.is_empty());
+    assert!(errors_rx.try_recv().is_err());
+}
+
+#[test]
+fn stop_listener_peers() {
+    // both clients connect from 127.0.0.1
+    let config = || {
+        default_config().set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        })
+    };
+    let mut manager = default_manager(config());
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    let mut clients = Vec::new();
+    for _ in 0..2 {
+        let addr = listen_tcp(&mut manager);
+        let mut client = default_manager(config());
+        connect_tcp(&mut client, addr);
+        addrs.push(addr);
+        clients.push(client);
+    }
+    assert!(eventually(|| manager.nb_in_connections() == 2));
+
+    // only the peer accepted by the stopped listener is disconnected
+    manager.stop_listener(TransportType::Tcp, addrs[0]).unwrap();
+    assert!(eventually(|| {
+        clients[0].active_connections.read().connections.is_empty()
+    }));
+    assert_eq!(manager.nb_in_connections(), 1);
+    assert_eq!(clients[1].active_connections.read().connections.len(), 1);
+    {
+        let active_connections = clients[1].active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        connection
+            .send_channels
+            .send(&RawSerializer, vec![1; 10], false)
+            .unwrap();
+    }
+    assert!(eventually(|| manager.get_total_bytes_received() == 10));
+    assert_eq!(manager.nb_in_connections(), 1);
+    manager.stop_listener(TransportType::Tcp, addrs[1]).unwrap();
+}
+
+#[test]
+fn stop_listener_poll_interval() {
+    let mut manager = default_manager(default_config().set_tcp_settings(TcpSettings {
+        listener_poll_interval: Duration::from_millis(50),
+        ..Default::default()
+    }));
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    // stopped while idle, between polls that time out
+    for _ in 0..3 {
+        manager.start_listener(TransportType::Tcp, addr).unwrap();
+        sleep(Duration::from_millis(120));
+        let start = Instant::now();
+        manager.stop_listener(TransportType::Tcp, addr).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    // the socket of the stopped listener is closed
+    assert!(std::net::TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn category_timeouts() {
+    let settings: PeerNetSettings = serde_json::from_str(
+        r#"{
+            "peers_categories": {
+                "local": [["127.0.0.1"], {
+                    "max_in_connections": 5,
+                    "max_in_connections_per_ip": 5,
+                    "max_out_connections": 5,
+                    "read_timeout": 2000,
+                    "write_timeout": 3000
+                }]
+            }
+        }"#,
+    )
+    .unwrap();
+    let mut manager = default_manager(default_config().set_settings(settings));
+    let addr = listen_tcp(&mut manager);
+    // the other side is in no category
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    let timeouts = |manager: &DefaultManager| {
+        let active_connections = manager.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        let Endpoint::Tcp(endpoint) = &connection.endpoint else {
+            panic!("not a TCP endpoint");
+        };
+        (endpoint.config.read_timeout, endpoint.config.write_timeout)
+    };
+    assert_eq!(
+        timeouts(&manager),
+        (Duration::from_secs(2), Duration::from_secs(3))
+    );
+    let default_settings = TcpSettings::default();
+    assert_eq!(
+        timeouts(&manager2),
+        (
+            default_settings.read_timeout,
+            default_settings.write_timeout
+        )
+    );
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn stop_missing_listener() {
+    let mut manager = default_manager(default_config());
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    let other_addr: SocketAddr = format!("127.0.0.1:{}", port + 1).parse().unwrap();
+    let err = manager.stop_listener(TransportType::Tcp, addr).unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::TransportNotStarted);
+
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+    let err = manager
+        .stop_listener(TransportType::Tcp, other_addr)
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::ListenerNotFound);
+    let err = manager
+        .rebind_listener(TransportType::Tcp, other_addr, addr)
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::ListenerNotFound);
+    let err = manager
+        .stop_listener(TransportType::Quic, addr)
+        .unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::TransportNotStarted);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    let err = manager.stop_listener(TransportType::Tcp, addr).unwrap_err();
+    assert_eq!(err.error_type(), &PeerNetError::ListenerNotFound);
+}
+
+#[test]
+fn tcp_keepalive() {
+    let settings: PeerNetSettings = serde_json::from_str(
+        r#"{
+            "tcp": {
+                "keepalive": { "idle": 30000, "interval": 5000, "count": 4 }
+            }
+        }"#,
+    )
+    .unwrap();
+    let keepalive = settings.tcp.keepalive.unwrap();
+    assert_eq!(
+        keepalive,
+        TcpKeepalive {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+            count: 4,
+        }
+    );
+    let config = || default_config().set_settings(settings.clone());
+    let mut manager = default_manager(config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    // set on the incoming and the outgoing connections
+    for manager in [&manager, &manager2] {
+        let active_connections = manager.active_connections.read();
+        let connection = active_connections.connections.values().next().unwrap();
+        let Endpoint::Tcp(endpoint) = &connection.endpoint else {
+            panic!("not a TCP endpoint");
+        };
+        let socket = socket2::SockRef::from(&endpoint.stream_limiter.stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), keepalive.idle);
+            assert_eq!(socket.keepalive_interval().unwrap(), keepalive.interval);
+            assert_eq!(socket.keepalive_retries().unwrap(), keepalive.count);
+        }
+    }
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+/// Answers the NAT-PMP requests like a gateway of external address 1.2.3.4, forwards them to
+/// `requests`
+fn fake_nat_pmp_gateway(requests: crossbeam::channel::Sender<Vec<u8>>) -> std::net::SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    std::thread::spawn(move || loop {
+        let mut request = [0u8; 12];
+        let (len, from) = socket.recv_from(&mut request).unwrap();
+        let mut response = vec![0, 128 + request[1], 0, 0, 0, 0, 0, 1];
+        if request[1] == 0 {
+            response.extend_from_slice(&[1, 2, 3, 4]);
+        } else {
+            // internal port, external port (internal + 1) and lifetime
+            response.extend_from_slice(&request[4..6]);
+            let port = u16::from_be_bytes([request[4], request[5]]);
+            response.extend_from_slice(&port.wrapping_add(1).to_be_bytes());
+            response.extend_from_slice(&request[8..12]);
+        }
+        if requests.send(request[..len].to_vec()).is_err() {
+            return;
+        }
+        socket.send_to(&response, from).unwrap();
+    });
+    addr
+}
+
+#[test]
+fn port_mapping_nat_pmp() {
+    let (requests_tx, requests_rx) = crossbeam::channel::unbounded();
+    let (events_tx, events_rx) = crossbeam::channel::unbounded();
+    let mut manager = default_manager(default_config().set_optional_features(
+        PeerNetFeatures::default().set_port_mapping(PortMapping {
+            lease_duration: Duration::from_secs(3600),
+            nat_pmp_gateway: Some(fake_nat_pmp_gateway(requests_tx)),
+            timeout: Duration::from_secs(1),
+            events: events_tx,
+        }),
+    ));
+
+    let addr = listen_tcp(&mut manager);
+    assert_eq!(
+        events_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        PortMappingEvent::Mapped {
+            local_addr: addr,
+            external_addr: format!("1.2.3.4:{}", addr.port() + 1).parse().unwrap(),
+            protocol: PortMappingProtocol::NatPmp,
+        }
+    );
+
+    // the mapping is removed with the listener
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+    let requests: Vec<Vec<u8>> = requests_rx.try_iter().collect();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[2][1], 2);
+    assert_eq!(requests[2][6..], [0, 0, 0, 0, 0, 0]);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn threads_config() {
+    // names of the threads of the process, truncated to 15 bytes by the kernel
+    fn thread_names() -> Vec<String> {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.unwrap().path().join("comm")).ok())
+            .map(|name| name.trim_end().to_string())
+            .collect()
+    }
+
+    let builder = |name_prefix: &str| {
+        default_config().set_optional_features(PeerNetFeatures::default().set_threads(
+            ThreadsConfig {
+                stack_size: Some(256 * 1024),
+                name_prefix: Some(name_prefix.to_string()),
+            },
+        ))
+    };
+    let mut manager = default_manager(builder("tc1_"));
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(builder("tc2_"));
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    // the threads of each side start after its handshake
+    for name in [
+        "tc1_tcp_listene",
+        "tc1_peer_thread",
+        "tc1_peer_write_",
+        "tc2_peer_thread",
+        "tc2_peer_write_",
+    ] {
+        assert!(
+            eventually(|| thread_names().iter().any(|thread| thread == name)),
+            "{name} not found"
+        );
+    }
+
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
+
+#[test]
+fn peer_threads_joined() {
+    fn thread_names() -> Vec<String> {
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.unwrap().path().join("comm")).ok())
+            .map(|name| name.trim_end().to_string())
+            .collect()
+    }
+
+    let builder = |name_prefix: &str| {
+        default_config().set_optional_features(PeerNetFeatures::default().set_threads(
+            ThreadsConfig {
+                stack_size: None,
+                name_prefix: Some(name_prefix.to_string()),
+            },
+        ))
+    };
+    let mut manager = default_manager(builder("pt1_"));
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(builder("pt2_"));
+    for _ in 0..2 {
+        connect_tcp(&mut manager2, addr);
+        assert!(eventually(|| manager.active_thread_count() == 1));
+        assert_eq!(manager2.active_thread_count(), 1);
+
+        // the threads of both sides end with the connection
+        let peer_id = manager2
+            .active_connections
+            .read()
+            .connections
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        manager2
+            .active_connections
+            .write()
+            .remove_connection(&peer_id);
+        assert!(eventually(|| {
+            manager.active_thread_count() == 0 && manager2.active_thread_count() == 0
+        }));
+    }
+
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+    // the threads are joined when the managers are dropped
+    drop(manager);
+    drop(manager2);
+    let names = thread_names();
+    assert!(
+        !names
+            .iter()
+            .any(|name| name.starts_with("pt1_") || name.starts_with("pt2_")),
+        "threads still running: {names:?}"
+    );
+}
+
+#[test]
+fn rebind_listener() {
+    let config = || {
+        default_config().set_default_category_info(PeerNetCategoryInfo {
+            max_in_connections: Some(10),
+            max_in_connections_per_ip: Some(2),
+            max_out_connections: Some(10),
+            max_out_connections_per_ip: None,
+            read_timeout: None,
+            write_timeout: None,
+        })
+    };
+    let mut manager = default_manager(config());
+    let old_addr = listen_tcp(&mut manager);
+    let mut clients = vec![default_manager(config()), default_manager(config())];
+    connect_tcp(&mut clients[0], old_addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    let port = get_tcp_port(10000..u16::MAX);
+    let new_addr: SocketAddr = format!("127.0.0.1:{port}").parse().unwrap();
+    // the old listener must exist
+    assert!(manager
+        .rebind_listener(TransportType::Tcp, new_addr, new_addr)
+        .is_err());
+    manager
+        .rebind_listener(TransportType::Tcp, old_addr, new_addr)
+        .unwrap();
+    assert_eq!(
+        manager.dump_state().listeners,
+        vec![(new_addr, TransportType::Tcp)]
+    );
+    assert!(std::net::TcpStream::connect(old_addr).is_err());
+    connect_tcp(&mut clients[1], new_addr);
+    // the peer of the old listener is still connected
+    assert!(eventually(|| manager.nb_in_connections() == 2));
+
+    // and stopped with the new one
+    manager.stop_listener(TransportType::Tcp, new_addr).unwrap();
+    assert!(eventually(|| manager.nb_in_connections() == 0));
+}
@@ -0,0 +1,213 @@
+// Networks of managers linked in memory by the simulator
+#![cfg(feature = "testing")]
+mod util;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use peernet::config::{PeerNetConfiguration, PeerNetConfigurationBuilder};
+use peernet::error::{PeerNetError, PeerNetResult};
+use peernet::messages::{Bytes, MessagesHandler};
+use peernet::peer::InitConnectionHandler;
+use peernet::simulator::{LinkConditions, Simulator};
+use peernet::transports::{endpoint::Endpoint, TransportType};
+use util::{DefaultContext, DefaultPeerId, RawSerializer};
+
+/// Each side sends its id
+#[derive(Clone)]
+struct IdInitConnection;
+
+impl InitConnectionHandler<DefaultPeerId, DefaultContext, RecordingHandler> for IdInitConnection {
+    fn perform_handshake(
+        &mut self,
+        context: &DefaultContext,
+        endpoint: &mut Endpoint,
+        _listeners: &HashMap<SocketAddr, TransportType>,
+        _messages_handler: RecordingHandler,
+    ) -> PeerNetResult<DefaultPeerId> {
+        endpoint.send::<DefaultPeerId>(&context.our_id.id.to_be_bytes())?;
+        let id = endpoint.receive::<DefaultPeerId>()?;
+        let id = id.as_ref().try_into().map_err(|_| {
+            PeerNetError::HandshakeError.error("test", Some("invalid id".to_string()))
+        })?;
+        Ok(DefaultPeerId {
+            id: u64::from_be_bytes(id),
+        })
+    }
+}
+
+/// Forwards the messages received by the node `node` to `received`
+#[derive(Clone)]
+struct RecordingHandler {
+    node: usize,
+    received: Sender<(usize, Bytes)>,
+}
+
+impl MessagesHandler<DefaultPeerId> for RecordingHandler {
+    fn handle(&self, data: Bytes, _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
+        self.received
+            .send((self.node, data))
+            .map_err(|err| PeerNetError::HandlerError.error("test", Some(err.to_string())))
+    }
+}
+
+type TestSimulator = Simulator<DefaultPeerId, DefaultContext, IdInitConnection, RecordingHandler>;
+
+/// `nb_nodes` nodes of ids 0, 1..., their messages go to the receiver
+fn simulator(
+    seed: u64,
+    conditions: LinkConditions,
+    nb_nodes: usize,
+) -> (TestSimulator, Receiver<(usize, Bytes)>) {
+    let (received_tx, received) = unbounded();
+    let mut simulator = Simulator::new(seed, conditions);
+    for node in 0..nb_nodes {
+        let config: PeerNetConfiguration<_, _, _, _> = PeerNetConfigurationBuilder::new(
+            DefaultContext {
+                our_id: DefaultPeerId { id: node as u64 },
+            },
+            IdInitConnection,
+            RecordingHandler {
+                node,
+                received: received_tx.clone(),
+            },
+        )
+        .build()
+        .unwrap();
+        assert_eq!(simulator.add_node(config).unwrap(), node);
+    }
+    (simulator, received)
+}
+
+fn send(simulator: &TestSimulator, from: usize, to: usize, data: Vec<u8>) {
+    let active_connections = simulator.node(from).active_connections.read();
+    active_connections.connections[&DefaultPeerId { id: to as u64 }]
+        .send_channels
+        .send(&RawSerializer, data, false)
+        .unwrap();
+}
+
+fn nb_connections(simulator: &TestSimulator, node: usize) -> usize {
+    simulator
+        .node(node)
+        .active_connections
+        .read()
+        .connections
+        .len()
+}
+
+#[test]
+fn simulated_latency() {
+    let conditions = LinkConditions {
+        latency: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let (mut simulator, received) = simulator(0, conditions, 4);
+    let start = Instant::now();
+    simulator.connect_ring(1).unwrap();
+    assert!(simulator.wait_until(Duration::from_secs(5), |node| {
+        node.active_connections.read().connections.len() == 2
+    }));
+    // each side waits for the id of the other
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    {
+        let active_connections = simulator.node(0).active_connections.read();
+        let mut addrs: Vec<SocketAddr> = active_connections
+            .connections
+            .values()
+            .map(|connection| *connection.endpoint.get_target_addr())
+            .collect();
+        addrs.sort();
+        // dialed 1, dialed by 3
+        assert_eq!(addrs[0], simulator.addr(1));
+        assert_eq!(addrs[1].ip(), simulator.addr(3).ip());
+    }
+
+    let start = Instant::now();
+    send(&simulator, 0, 1, vec![1, 2, 3]);
+    let (node, data) = received.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(node, 1);
+    assert_eq!(&data[..], &[1, 2, 3]);
+    // a node isn't linked to itself
+    assert!(simulator.connect(0, 0).is_err());
+    assert_eq!(nb_connections(&simulator, 2), 2);
+}
+
+#[test]
+fn simulated_loss_is_deterministic() {
+    let run = |seed| {
+        let (mut simulator, received) = simulator(seed, LinkConditions::default(), 2);
+        simulator.connect(0, 1).unwrap();
+        assert!(simulator.wait_until(Duration::from_secs(5), |node| {
+            node.active_connections.read().connections.len() == 1
+        }));
+        simulator.set_link(
+            0,
+            1,
+            LinkConditions {
+                latency: Duration::from_millis(5),
+                jitter: Duration::from_millis(20),
+                loss: 0.5,
+            },
+        );
+        for i in 0..50 {
+            send(&simulator, 0, 1, vec![i]);
+        }
+        std::thread::sleep(Duration::from_millis(500));
+        received
+            .try_iter()
+            .map(|(_, data)| data[0])
+            .collect::<Vec<u8>>()
+    };
+    let received = run(42);
+    assert!(!received.is_empty() && received.len() < 50);
+    // in order despite the jitter
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(run(42), received);
+}
+
+#[test]
+fn simulated_partition() {
+    let (mut simulator, received) = simulator(1, LinkConditions::default(), 3);
+    simulator.connect_ring(1).unwrap();
+    assert!(simulator.wait_until(Duration::from_secs(5), |node| {
+        node.active_connections.read().connections.len() == 2
+    }));
+
+    let partitioned = LinkConditions {
+        loss: 1.0,
+        ..Default::default()
+    };
+    simulator.set_link(0, 1, partitioned);
+    assert_eq!(simulator.link_conditions(1, 0), partitioned);
+    send(&simulator, 0, 1, vec![1]);
+    send(&simulator, 0, 2, vec![2]);
+    send(&simulator, 1, 0, vec![3]);
+    std::thread::sleep(Duration::from_millis(200));
+    let delivered: Vec<(usize, u8)> = received
+        .try_iter()
+        .map(|(node, data)| (node, data[0]))
+        .collect();
+    assert_eq!(delivered, vec![(2, 2)]);
+
+    // healed
+    simulator.set_link(0, 1, LinkConditions::default());
+    send(&simulator, 0, 1, vec![4]);
+    assert_eq!(
+        received.recv_timeout(Duration::from_secs(1)).unwrap(),
+        (1, Bytes::from(vec![4]))
+    );
+
+    // the connection is closed on both sides
+    simulator
+        .node(0)
+        .active_connections
+        .write()
+        .remove_connection(&DefaultPeerId { id: 1 });
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(nb_connections(&simulator, 0), 1);
+    assert_eq!(nb_connections(&simulator, 1), 1);
+    assert_eq!(nb_connections(&simulator, 2), 2);
+}
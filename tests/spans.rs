@@ -0,0 +1,100 @@
+mod util;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use peernet::transports::TransportType;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use crate::util::{connect_tcp, default_config, default_manager, eventually, listen_tcp};
+
+type Fields = HashMap<String, String>;
+
+/// Keeps the name and the fields of every span
+#[derive(Clone, Default)]
+struct SpansRecorder {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<HashMap<u64, (String, Fields)>>>,
+}
+
+struct FieldsVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for SpansRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut fields = HashMap::new();
+        span.record(&mut FieldsVisitor(&mut fields));
+        self.spans
+            .lock()
+            .insert(id, (span.metadata().name().to_string(), fields));
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some((_, fields)) = self.spans.lock().get_mut(&span.into_u64()) {
+            values.record(&mut FieldsVisitor(fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn peer_spans() {
+    let recorder = SpansRecorder::default();
+    // the peers run in threads of their own
+    tracing::subscriber::set_global_default(recorder.clone()).unwrap();
+
+    let mut manager = default_manager(default_config());
+    let addr = listen_tcp(&mut manager);
+    let mut manager2 = default_manager(default_config());
+    connect_tcp(&mut manager2, addr);
+    assert!(eventually(|| manager.nb_in_connections() == 1));
+
+    let spans = recorder.spans.lock();
+    let peers: Vec<&Fields> = spans
+        .values()
+        .filter(|(name, _)| name == "peer")
+        .map(|(_, fields)| fields)
+        .collect();
+    assert_eq!(peers.len(), 2);
+    for direction in ["IN", "OUT"] {
+        let fields = peers
+            .iter()
+            .find(|fields| fields["direction"] == direction)
+            .unwrap();
+        assert_eq!(fields["transport"], "tcp");
+        // recorded once the handshake is done
+        assert!(fields.contains_key("peer_id"));
+        if direction == "OUT" {
+            assert_eq!(fields["addr"], addr.to_string());
+        }
+    }
+    drop(spans);
+    manager.stop_listener(TransportType::Tcp, addr).unwrap();
+}
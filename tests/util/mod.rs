@@ -1,46 +1,24 @@
 #![allow(dead_code)]
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener};
 use std::ops::Range;
 use std::thread::{sleep, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use peernet::{context::Context, error::PeerNetResult, messages::MessagesHandler, peer_id::PeerId};
+use peernet::config::PeerNetConfigurationBuilder;
+use peernet::context::Context;
+use peernet::messages::MessagesHandler;
+use peernet::network_manager::PeerNetManager;
+use peernet::peer::InitConnectionHandler;
+use peernet::peer_id::PeerId;
+use peernet::transports::TransportType;
 use rand::Rng;
 
-pub mod paramtests;
-
-#[derive(Clone)]
-pub struct DefaultContext {
-    pub our_id: DefaultPeerId,
-}
-
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct DefaultPeerId {
-    pub id: u64,
-}
-
-impl PeerId for DefaultPeerId {
-    fn generate() -> Self {
-        let mut rng = rand::thread_rng();
-        let random_number: u64 = rng.gen();
-        DefaultPeerId { id: random_number }
-    }
-}
+pub use peernet::defaults::{
+    DefaultContext, DefaultInitConnection, DefaultMessagesHandler, DefaultPeerId,
+};
+pub use peernet::messages::RawSerializer;
 
-impl Context<DefaultPeerId> for DefaultContext {
-    fn get_peer_id(&self) -> DefaultPeerId {
-        self.our_id.clone()
-    }
-}
-
-#[derive(Clone)]
-pub struct DefaultMessagesHandler {}
-
-impl MessagesHandler<DefaultPeerId> for DefaultMessagesHandler {
-    fn handle(&self, _data: &[u8], _peer_id: &DefaultPeerId) -> PeerNetResult<()> {
-        Ok(())
-    }
-}
+pub mod paramtests;
 
 pub fn create_clients(nb_clients: usize, to_ip: &str) -> Vec<JoinHandle<()>> {
     let mut clients = Vec::new();
@@ -60,6 +38,88 @@ pub fn create_clients(nb_clients: usize, to_ip: &str) -> Vec<JoinHandle<()>> {
     clients
 }
 
+pub type DefaultManager =
+    PeerNetManager<DefaultPeerId, DefaultContext, DefaultInitConnection, DefaultMessagesHandler>;
+
+/// Builder of the configuration of a peer with a new id and the default handlers
+pub fn default_config() -> PeerNetConfigurationBuilder<
+    DefaultPeerId,
+    DefaultContext,
+    DefaultInitConnection,
+    DefaultMessagesHandler,
+> {
+    PeerNetConfigurationBuilder::new(
+        DefaultContext {
+            our_id: DefaultPeerId::generate(),
+        },
+        DefaultInitConnection,
+        DefaultMessagesHandler {},
+    )
+}
+
+/// Manager of the configuration of `builder`
+pub fn default_manager(
+    builder: PeerNetConfigurationBuilder<
+        DefaultPeerId,
+        DefaultContext,
+        DefaultInitConnection,
+        DefaultMessagesHandler,
+    >,
+) -> DefaultManager {
+    PeerNetManager::new(builder.build().unwrap()).unwrap()
+}
+
+/// Start a TCP listener of `manager` on a free port of 127.0.0.1, returns its address
+pub fn listen_tcp<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+>(
+    manager: &mut PeerNetManager<Id, Ctx, I, M>,
+) -> SocketAddr {
+    let port = get_tcp_port(10000..u16::MAX);
+    let addr = format!("127.0.0.1:{port}").parse().unwrap();
+    manager.start_listener(TransportType::Tcp, addr).unwrap();
+    addr
+}
+
+/// Connect `manager` to the TCP listener `addr` and wait for the end of the handshake
+pub fn connect_tcp<
+    Id: PeerId,
+    Ctx: Context<Id>,
+    I: InitConnectionHandler<Id, Ctx, M>,
+    M: MessagesHandler<Id>,
+>(
+    manager: &mut PeerNetManager<Id, Ctx, I, M>,
+    addr: SocketAddr,
+) {
+    let nb_connections = manager.active_connections.read().connections.len();
+    manager
+        .try_connect(TransportType::Tcp, addr, Duration::from_secs(3))
+        .unwrap();
+    assert!(wait_until(Duration::from_secs(3), || {
+        manager.active_connections.read().connections.len() > nb_connections
+    }));
+}
+
+/// Poll `condition` for at most a second of real time
+pub fn eventually(condition: impl Fn() -> bool) -> bool {
+    wait_until(Duration::from_secs(1), condition)
+}
+
+/// Poll `condition` for at most `timeout` of real time
+pub fn wait_until(timeout: Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(5));
+    }
+    true
+}
+
 pub fn get_tcp_port(range: Range<u16>) -> u16 {
     let mut rng = rand::thread_rng();
     loop {